@@ -0,0 +1,420 @@
+//! A chat service that uses all interaction patterns
+//!
+//! - rpc: [Login] to get a session
+//! - server streaming: [Feed] for the messages of a room, history first
+//! - client streaming: [UploadHistory] to add many messages to a room at once
+//! - bidi streaming: [Typing] to tell others when you type, and see when they do
+//!
+//! This is shared by the chat example and the chat test, so the example keeps working.
+#![allow(dead_code)]
+use async_stream::stream;
+use derive_more::{From, TryInto};
+use futures::{SinkExt, Stream, StreamExt};
+use quic_rpc::{
+    declare_bidi_streaming, declare_client_streaming, declare_rpc, declare_server_streaming,
+    server::RpcServerError, RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Identifies a logged in user
+pub type SessionId = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub from: String,
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Login {
+    pub user: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginResponse(pub Result<SessionId, String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Feed {
+    pub session: SessionId,
+    pub room: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FeedItem(pub ChatMessage);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadHistory {
+    pub session: SessionId,
+    pub room: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadUpdate {
+    pub text: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadResponse(pub Result<usize, String>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Typing {
+    pub session: SessionId,
+    pub room: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypingUpdate(pub bool);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub user: String,
+    pub typing: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum ChatRequest {
+    Login(Login),
+    Feed(Feed),
+    UploadHistory(UploadHistory),
+    UploadUpdate(UploadUpdate),
+    Typing(Typing),
+    TypingUpdate(TypingUpdate),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+pub enum ChatResponse {
+    LoginResponse(LoginResponse),
+    FeedItem(FeedItem),
+    UploadResponse(UploadResponse),
+    TypingEvent(TypingEvent),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatService;
+
+impl Service for ChatService {
+    type Req = ChatRequest;
+    type Res = ChatResponse;
+}
+
+declare_rpc!(ChatService, Login, LoginResponse);
+declare_server_streaming!(ChatService, Feed, FeedItem);
+declare_client_streaming!(ChatService, UploadHistory, UploadUpdate, UploadResponse);
+declare_bidi_streaming!(ChatService, Typing, TypingUpdate, TypingEvent);
+
+struct Room {
+    history: Vec<ChatMessage>,
+    messages: broadcast::Sender<ChatMessage>,
+    typing: BTreeSet<String>,
+    typing_events: broadcast::Sender<TypingEvent>,
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        Self {
+            history: Vec::new(),
+            messages: broadcast::channel(64).0,
+            typing: BTreeSet::new(),
+            typing_events: broadcast::channel(64).0,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    sessions: HashMap<SessionId, String>,
+    next_session: SessionId,
+    rooms: HashMap<String, Room>,
+}
+
+/// The chat server
+#[derive(Clone, Default)]
+pub struct Chat(Arc<Mutex<State>>);
+
+impl Chat {
+    fn user(&self, session: SessionId) -> Option<String> {
+        self.0.lock().unwrap().sessions.get(&session).cloned()
+    }
+
+    /// Set if `user` is typing in `room`, and tell the others if that changed
+    fn set_typing(&self, room: &str, user: &str, typing: bool) {
+        let mut state = self.0.lock().unwrap();
+        let room = state.rooms.entry(room.to_string()).or_default();
+        let changed = if typing {
+            room.typing.insert(user.to_string())
+        } else {
+            room.typing.remove(user)
+        };
+        if changed {
+            let event = TypingEvent {
+                user: user.to_string(),
+                typing,
+            };
+            room.typing_events.send(event).ok();
+        }
+    }
+
+    async fn login(self, req: Login) -> LoginResponse {
+        if req.user.is_empty() {
+            return LoginResponse(Err("user name must not be empty".into()));
+        }
+        let mut state = self.0.lock().unwrap();
+        let session = state.next_session;
+        state.next_session += 1;
+        state.sessions.insert(session, req.user);
+        LoginResponse(Ok(session))
+    }
+
+    fn feed(self, req: Feed) -> impl Stream<Item = FeedItem> + Send + 'static {
+        let subscription = self.user(req.session).map(|_| {
+            let mut state = self.0.lock().unwrap();
+            let room = state.rooms.entry(req.room).or_default();
+            // under the same lock as appending, so no message is missed or seen twice
+            (room.history.clone(), room.messages.subscribe())
+        });
+        stream! {
+            let (history, mut live) = match subscription {
+                Some(subscription) => subscription,
+                None => return,
+            };
+            for message in history {
+                yield FeedItem(message);
+            }
+            loop {
+                match live.recv().await {
+                    Ok(message) => yield FeedItem(message),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    async fn upload_history(
+        self,
+        req: UploadHistory,
+        updates: impl Stream<Item = UploadUpdate>,
+    ) -> UploadResponse {
+        let from = match self.user(req.session) {
+            Some(user) => user,
+            None => return UploadResponse(Err("unknown session".into())),
+        };
+        tokio::pin!(updates);
+        let mut count = 0;
+        while let Some(UploadUpdate { text }) = updates.next().await {
+            let message = ChatMessage {
+                from: from.clone(),
+                text,
+            };
+            let mut state = self.0.lock().unwrap();
+            let room = state.rooms.entry(req.room.clone()).or_default();
+            room.history.push(message.clone());
+            room.messages.send(message).ok();
+            count += 1;
+        }
+        UploadResponse(Ok(count))
+    }
+
+    fn typing(
+        self,
+        req: Typing,
+        updates: impl Stream<Item = TypingUpdate> + Send + 'static,
+    ) -> impl Stream<Item = TypingEvent> + Send + 'static {
+        let user = self.user(req.session);
+        let subscription = user.as_ref().map(|user| {
+            let mut state = self.0.lock().unwrap();
+            let room = state.rooms.entry(req.room.clone()).or_default();
+            // who is typing already, and everything that changes after that
+            let typing = room
+                .typing
+                .iter()
+                .filter(|other| *other != user)
+                .map(|other| TypingEvent {
+                    user: other.clone(),
+                    typing: true,
+                })
+                .collect::<Vec<_>>();
+            (typing, room.typing_events.subscribe())
+        });
+        stream! {
+            let (user, (typing, mut events)) = match user.zip(subscription) {
+                Some(subscribed) => subscribed,
+                None => return,
+            };
+            for event in typing {
+                yield event;
+            }
+            let mut forward = tokio::spawn({
+                let (chat, room, user) = (self.clone(), req.room.clone(), user.clone());
+                async move {
+                    tokio::pin!(updates);
+                    while let Some(TypingUpdate(typing)) = updates.next().await {
+                        chat.set_typing(&room, &user, typing);
+                    }
+                    chat.set_typing(&room, &user, false);
+                }
+            });
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    // the client is done
+                    _ = &mut forward => break,
+                };
+                match event {
+                    Ok(event) if event.user != user => yield event,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    /// Serve chat calls until the endpoint is closed
+    pub async fn serve<C: ServiceEndpoint<ChatService>>(
+        self,
+        server: RpcServer<ChatService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            let chat = self.clone();
+            tokio::spawn(async move {
+                use ChatRequest::*;
+                #[rustfmt::skip]
+                let res = match req {
+                    Login(msg) => chan.rpc(msg, chat, Chat::login).await,
+                    Feed(msg) => chan.server_streaming(msg, chat, Chat::feed).await,
+                    UploadHistory(msg) => chan.client_streaming(msg, chat, Chat::upload_history).await,
+                    Typing(msg) => chan.bidi_streaming(msg, chat, Chat::typing).await,
+                    UploadUpdate(_) | TypingUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+                };
+                match res {
+                    // a streaming call that the client stopped, e.g. by dropping the feed
+                    Ok(()) | Err(RpcServerError::UnexpectedUpdateMessage) => {}
+                    Err(cause) => eprintln!("chat: call failed: {cause:?}"),
+                }
+            });
+        }
+    }
+}
+
+/// Log in, failing on errors of the service
+async fn login<C: ServiceConnection<ChatService>>(
+    client: &RpcClient<ChatService, C>,
+    user: &str,
+) -> anyhow::Result<SessionId> {
+    let LoginResponse(res) = client.rpc(Login { user: user.into() }).await?;
+    res.map_err(anyhow::Error::msg)
+}
+
+/// Alice and bob chat in a room, using every interaction pattern of the service
+pub async fn conversation<C: ServiceConnection<ChatService>>(
+    client: RpcClient<ChatService, C>,
+) -> anyhow::Result<()> {
+    let room = "rust".to_string();
+
+    // rpc
+    let alice = login(&client, "alice").await?;
+    let bob = login(&client, "bob").await?;
+    let LoginResponse(res) = client.rpc(Login { user: "".into() }).await?;
+    anyhow::ensure!(res.is_err(), "empty user names are rejected");
+    println!("alice and bob logged in");
+
+    // server streaming
+    let mut feed = client
+        .server_streaming(Feed {
+            session: bob,
+            room: room.clone(),
+        })
+        .await?;
+
+    // client streaming
+    let (mut upload, response) = client
+        .client_streaming(UploadHistory {
+            session: alice,
+            room: room.clone(),
+        })
+        .await?;
+    let texts = [
+        "hi bob",
+        "did you see the new release?",
+        "it has a chat example",
+    ];
+    for text in texts {
+        upload
+            .send(UploadUpdate { text: text.into() })
+            .await
+            .map_err(anyhow::Error::msg)?;
+    }
+    drop(upload);
+    let UploadResponse(count) = response.await?;
+    anyhow::ensure!(count == Ok(texts.len()), "uploaded {count:?}");
+    println!("alice uploaded {} messages", texts.len());
+
+    for text in texts {
+        let FeedItem(message) = feed.next().await.expect("feed ended")?;
+        anyhow::ensure!(message.from == "alice" && message.text == text);
+        println!("bob got: {}: {}", message.from, message.text);
+    }
+    drop(feed);
+
+    // bidi streaming
+    let (mut alice_typing, mut alice_sees) = client
+        .bidi(Typing {
+            session: alice,
+            room: room.clone(),
+        })
+        .await?;
+    alice_typing
+        .send(TypingUpdate(true))
+        .await
+        .map_err(anyhow::Error::msg)?;
+    let (mut bob_typing, mut bob_sees) = client
+        .bidi(Typing {
+            session: bob,
+            room: room.clone(),
+        })
+        .await?;
+    let event = bob_sees.next().await.expect("typing ended")?;
+    anyhow::ensure!(event.user == "alice" && event.typing);
+    println!("bob sees alice typing");
+    bob_typing
+        .send(TypingUpdate(true))
+        .await
+        .map_err(anyhow::Error::msg)?;
+    let event = alice_sees.next().await.expect("typing ended")?;
+    anyhow::ensure!(event.user == "bob" && event.typing);
+    println!("alice sees bob typing");
+
+    // alice leaves, so she is no longer typing
+    drop(alice_typing);
+    let event = bob_sees.next().await.expect("typing ended")?;
+    anyhow::ensure!(event.user == "alice" && !event.typing);
+    println!("bob sees alice stop typing");
+    anyhow::ensure!(alice_sees.next().await.is_none());
+    drop(bob_typing);
+    anyhow::ensure!(bob_sees.next().await.is_none());
+    Ok(())
+}
+
+/// A quinn server endpoint on localhost with a self signed certificate, and a
+/// client endpoint that trusts it
+pub fn quinn_endpoints() -> anyhow::Result<(quinn::Endpoint, quinn::Endpoint, SocketAddr)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let server_config =
+        quinn::ServerConfig::with_single_cert(vec![rustls::Certificate(cert_der.clone())], key)?;
+    let server = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse()?)?;
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(cert_der))?;
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse()?)?;
+    client.set_default_client_config(quinn::ClientConfig::with_root_certificates(roots));
+    let addr = server.local_addr()?;
+    Ok((server, client, addr))
+}
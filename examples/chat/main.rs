@@ -0,0 +1,32 @@
+//! A chat service, used over the in memory and the quinn transport
+//!
+//! Run with `cargo run --example chat --features flume-transport,quinn-transport,macros`
+use quic_rpc::{
+    transport::{
+        flume,
+        quinn::{QuinnConnection, QuinnServerEndpoint},
+    },
+    RpcClient, RpcServer,
+};
+
+mod chat;
+use chat::*;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    println!("in memory");
+    let (server, client) = flume::connection::<ChatRequest, ChatResponse>(1);
+    let server = tokio::spawn(Chat::default().serve(RpcServer::new(server)));
+    conversation(RpcClient::<ChatService, _>::new(client)).await?;
+    server.abort();
+
+    println!("quinn");
+    let (server, client, addr) = quinn_endpoints()?;
+    let server = QuinnServerEndpoint::<ChatRequest, ChatResponse>::new(server)?;
+    let server = tokio::spawn(Chat::default().serve(RpcServer::new(server)));
+    let client =
+        QuinnConnection::<ChatResponse, ChatRequest>::new(client, addr, "localhost".into());
+    conversation(RpcClient::<ChatService, _>::new(client)).await?;
+    server.abort();
+    Ok(())
+}
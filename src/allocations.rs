@@ -0,0 +1,143 @@
+//! Counting allocations, to keep the steady state of calls free of them
+//!
+//! Once a channel is open, receiving messages on the flume transport does not
+//! allocate, and neither does sending them while the channel has room: the
+//! buffers of the channel are reused, and the sinks and streams of a call are
+//! created when it starts. A sender that has to wait for room allocates its
+//! place in the queue of waiters. Opening a channel does allocate, so every
+//! rpc call allocates a few times, as do messages on transports that
+//! serialize them.
+//!
+//! [CountingAllocator] wraps a global allocator and counts the allocations of
+//! the whole process and of every thread, so tests can check that messages
+//! continue to be sent and received without allocations:
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static ALLOCATOR: CountingAllocator = CountingAllocator::system();
+//!
+//! let before = AllocationStats::thread();
+//! send.send(Update(1)).await?;
+//! recv.next().await;
+//! assert_eq!((AllocationStats::thread() - before).allocations, 0);
+//! ```
+//!
+//! With the `openmetrics` feature, the totals of the process are also part of
+//! the [metrics](crate::metrics), as `process_allocations` and
+//! `process_allocated_bytes`, once the allocator is installed.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    ops::Sub,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static THREAD: Cell<AllocationStats> = const {
+        Cell::new(AllocationStats { allocations: 0, bytes: 0 })
+    };
+}
+
+/// A global allocator that counts the allocations it passes on to another one
+///
+/// Counting costs an atomic increment per allocation, so this is meant for
+/// tests and for processes that watch their allocations in production.
+#[derive(Debug, Default)]
+pub struct CountingAllocator<A = System> {
+    inner: A,
+}
+
+impl CountingAllocator<System> {
+    /// Count the allocations of the system allocator
+    pub const fn system() -> Self {
+        Self { inner: System }
+    }
+}
+
+impl<A> CountingAllocator<A> {
+    /// Count the allocations of `inner`
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+fn count(bytes: usize) {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    BYTES.fetch_add(bytes as u64, Ordering::Relaxed);
+    // the thread local is gone while the thread shuts down
+    THREAD
+        .try_with(|stats| {
+            let mut current = stats.get();
+            current.allocations += 1;
+            current.bytes += bytes as u64;
+            stats.set(current);
+        })
+        .ok();
+}
+
+// SAFETY: all allocations are done by the inner allocator
+unsafe impl<A: GlobalAlloc> GlobalAlloc for CountingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// Numbers of allocations counted by a [CountingAllocator]
+///
+/// Reallocations are counted as allocations of their new size. Subtracting
+/// two snapshots gives the allocations between them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocationStats {
+    /// The number of allocations
+    pub allocations: u64,
+    /// The number of bytes allocated
+    pub bytes: u64,
+}
+
+impl AllocationStats {
+    /// The allocations of the whole process so far
+    ///
+    /// These are all zero if no [CountingAllocator] is installed.
+    pub fn total() -> Self {
+        Self {
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            bytes: BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The allocations of the current thread so far
+    ///
+    /// These are all zero if no [CountingAllocator] is installed.
+    pub fn thread() -> Self {
+        THREAD.with(|stats| stats.get())
+    }
+}
+
+impl Sub for AllocationStats {
+    type Output = Self;
+
+    fn sub(self, earlier: Self) -> Self {
+        Self {
+            allocations: self.allocations - earlier.allocations,
+            bytes: self.bytes - earlier.bytes,
+        }
+    }
+}
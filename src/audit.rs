@@ -0,0 +1,256 @@
+//! Audit records of finished server calls
+//!
+//! An [Auditor] gets an [AuditRecord] for every call of a server that was
+//! configured with [RpcServer::with_auditor](crate::RpcServer::with_auditor),
+//! once the call finished. Unlike the telemetry of calls, which is meant for
+//! debugging and dashboards, records are meant to be written to external
+//! systems, e.g. an audit log or a database:
+//!
+//! ```ignore
+//! let auditor = Auditor::new(AuditConfig::default(), |records| async move {
+//!     audit_log.append(records).await;
+//! })
+//! .with_summary(|req: &StoreRequest| req.key().to_string());
+//! let server = RpcServer::new(endpoint).with_auditor(auditor);
+//! ```
+//!
+//! Records are written in batches by a background task, so a slow external
+//! system never holds up calls. Records are buffered up to
+//! [AuditConfig::capacity]. If the writer falls behind further than that,
+//! records are dropped and counted in [Auditor::dropped].
+use crate::{telemetry::method_name, Service};
+use std::{
+    any::type_name,
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::mpsc;
+
+/// How a call ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The handler finished and the response was sent
+    Completed,
+    /// The call failed, with the message of the error
+    Failed(String),
+    /// The call was dropped before it finished, e.g. because the task running
+    /// it was aborted or the handler panicked
+    Aborted,
+}
+
+/// A finished server call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Type name of the service
+    pub service: &'static str,
+    /// Name of the message type of the request
+    pub method: &'static str,
+    /// The summary of the first request, see [Auditor::with_summary]
+    pub request: Option<String>,
+    /// How the call ended
+    pub outcome: Outcome,
+    /// When the handler was started
+    pub started: SystemTime,
+    /// How long the call took, from the start of the handler until it ended
+    pub duration: Duration,
+    /// The address of the client, if the transport knows it, see
+    /// [ConnectionCommon::peer_addr](crate::transport::ConnectionCommon::peer_addr)
+    pub peer: Option<SocketAddr>,
+}
+
+/// Batching of audit records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditConfig {
+    /// Maximum number of records that are buffered while the writer is busy
+    pub capacity: usize,
+    /// Maximum number of records in a batch
+    pub max_batch: usize,
+    /// Maximum time the first record of a batch waits for more records
+    pub max_delay: Duration,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            max_batch: 100,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+type Summary<S> = dyn Fn(&<S as Service>::Req) -> String + Send + Sync;
+
+/// Receives the audit records of the calls of a server
+///
+/// Clones share the writer. The writer finishes once the last clone and all
+/// calls that are in flight are dropped, after writing the remaining records.
+pub struct Auditor<S: Service> {
+    sender: mpsc::Sender<AuditRecord>,
+    summary: Option<Arc<Summary<S>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<S: Service> Auditor<S> {
+    /// Create an auditor that writes batches of records with `write`
+    ///
+    /// `write` is awaited before the next batch is collected. This spawns the
+    /// writer, so it must be called from within a tokio runtime.
+    pub fn new<F, Fut>(config: AuditConfig, write: F) -> Self
+    where
+        F: FnMut(Vec<AuditRecord>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (sender, records) = mpsc::channel(config.capacity.max(1));
+        tokio::spawn(write_batches(records, config, write));
+        Self {
+            sender,
+            summary: None,
+            dropped: Default::default(),
+        }
+    }
+
+    /// Summarize the first request of every call with `f`
+    ///
+    /// Without a summary, records have no [AuditRecord::request], so requests
+    /// do not end up in the audit log unless they are meant to.
+    pub fn with_summary<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&S::Req) -> String + Send + Sync + 'static,
+    {
+        self.summary = Some(Arc::new(f));
+        self
+    }
+
+    /// The number of records that were dropped because the buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Start the audit of a call with the first request `request`
+    pub(crate) fn accepted(&self, request: &S::Req, peer: Option<SocketAddr>) -> PendingAudit {
+        PendingAudit {
+            sender: self.sender.clone(),
+            dropped: self.dropped.clone(),
+            request: self.summary.as_ref().map(|summary| summary(request)),
+            peer,
+        }
+    }
+}
+
+impl<S: Service> Clone for Auditor<S> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            summary: self.summary.clone(),
+            dropped: self.dropped.clone(),
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for Auditor<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auditor")
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+async fn write_batches<F, Fut>(
+    mut records: mpsc::Receiver<AuditRecord>,
+    config: AuditConfig,
+    mut write: F,
+) where
+    F: FnMut(Vec<AuditRecord>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    while let Some(first) = records.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(config.max_delay);
+        tokio::pin!(deadline);
+        while batch.len() < config.max_batch {
+            tokio::select! {
+                record = records.recv() => match record {
+                    Some(record) => batch.push(record),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        write(batch).await;
+    }
+}
+
+/// The audit of an accepted call whose handler did not start yet
+#[derive(Debug)]
+pub(crate) struct PendingAudit {
+    sender: mpsc::Sender<AuditRecord>,
+    dropped: Arc<AtomicU64>,
+    request: Option<String>,
+    peer: Option<SocketAddr>,
+}
+
+impl PendingAudit {
+    /// Start the audit of a call of message type `M` on service `S`
+    pub(crate) fn start<S: 'static, M: 'static>(self) -> Audit {
+        Audit {
+            pending: Some(self),
+            service: type_name::<S>(),
+            method: method_name::<M>(),
+            started: SystemTime::now(),
+            start: Instant::now(),
+            outcome: Outcome::Aborted,
+        }
+    }
+}
+
+/// The audit of a running call
+///
+/// The record is sent when this is dropped. Unless [Audit::finish] was called
+/// before, the call was aborted.
+#[derive(Debug)]
+pub(crate) struct Audit {
+    pending: Option<PendingAudit>,
+    service: &'static str,
+    method: &'static str,
+    started: SystemTime,
+    start: Instant,
+    outcome: Outcome,
+}
+
+impl Audit {
+    /// Record the result of the call
+    pub(crate) fn finish<E: fmt::Display>(mut self, result: &Result<(), E>) {
+        self.outcome = match result {
+            Ok(()) => Outcome::Completed,
+            Err(cause) => Outcome::Failed(cause.to_string()),
+        };
+    }
+}
+
+impl Drop for Audit {
+    fn drop(&mut self) {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        let record = AuditRecord {
+            service: self.service,
+            method: self.method,
+            request: pending.request,
+            outcome: std::mem::replace(&mut self.outcome, Outcome::Aborted),
+            started: self.started,
+            duration: self.start.elapsed(),
+            peer: pending.peer,
+        };
+        if pending.sender.try_send(record).is_err() {
+            pending.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
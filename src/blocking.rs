@@ -0,0 +1,116 @@
+//! Calling a service from synchronous code
+//!
+//! Handlers often hand work that blocks to [tokio::task::spawn_blocking] or
+//! to threads of their own. Code on these threads can not await the futures
+//! of an [RpcClient], and calling [Runtime::block_on](tokio::runtime::Runtime::block_on)
+//! or [Handle::block_on] there panics if the thread is part of a runtime.
+//!
+//! A [BlockingClient] runs every call as a task on the runtime it was created
+//! on, and waits for the task without entering the runtime itself:
+//!
+//! ```ignore
+//! let client = BlockingClient::new(client);
+//! tokio::task::spawn_blocking(move || {
+//!     let decoded = expensive_decode(data);
+//!     client.rpc(Store(decoded))
+//! });
+//! ```
+//!
+//! Waiting blocks the calling thread, so a blocking client must not be used
+//! from async code. On a worker thread, it holds up all other tasks of that
+//! worker, and on a current thread runtime, it waits forever.
+use crate::{
+    channels,
+    client::{RpcClientError, StreamingResponseError, StreamingResponseItemError},
+    message::{RpcMsg, ServerStreamingMsg},
+    RpcClient, Service, ServiceConnection,
+};
+use futures::Future;
+use std::{panic, result, sync::mpsc};
+use tokio::runtime::Handle;
+
+/// The receiver of the responses of [BlockingClient::server_streaming]
+pub type BlockingResponses<T, C> = mpsc::Receiver<result::Result<T, StreamingResponseItemError<C>>>;
+
+/// An [RpcClient] for synchronous code
+#[derive(Debug)]
+pub struct BlockingClient<S, C> {
+    client: RpcClient<S, C>,
+    handle: Handle,
+}
+
+impl<S, C: Clone> Clone for BlockingClient<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            handle: self.handle.clone(),
+        }
+    }
+}
+
+impl<S: Service, C: ServiceConnection<S>> BlockingClient<S, C> {
+    /// Run the calls of `client` on the current runtime
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn new(client: RpcClient<S, C>) -> Self {
+        Self::with_handle(client, Handle::current())
+    }
+
+    /// Run the calls of `client` on the runtime of `handle`
+    pub fn with_handle(client: RpcClient<S, C>, handle: Handle) -> Self {
+        Self { client, handle }
+    }
+
+    /// The async client
+    pub fn client(&self) -> &RpcClient<S, C> {
+        &self.client
+    }
+
+    /// Run `f` on the runtime and wait for its output
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` panics, or if the runtime shuts down before `f` is done.
+    pub fn block_on<F>(&self, f: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let task = self.handle.spawn(f);
+        match futures::executor::block_on(task) {
+            Ok(output) => output,
+            Err(cause) if cause.is_panic() => panic::resume_unwind(cause.into_panic()),
+            Err(_) => panic!("the runtime shut down before the call was done"),
+        }
+    }
+
+    /// Rpc call to the server, see [RpcClient::rpc]
+    pub fn rpc<M>(&self, msg: M) -> result::Result<M::Response, RpcClientError<C>>
+    where
+        M: RpcMsg<S>,
+    {
+        let client = self.client.clone();
+        self.block_on(async move { client.rpc(msg).await })
+    }
+
+    /// Server streaming call, see [RpcClient::server_streaming]
+    ///
+    /// The responses are read on the runtime and buffered until they are
+    /// received. Dropping the receiver ends the call once the next response
+    /// arrives.
+    pub fn server_streaming<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<BlockingResponses<M::Response, C>, StreamingResponseError<C>>
+    where
+        M: ServerStreamingMsg<S>,
+    {
+        let client = self.client.clone();
+        self.block_on(async move {
+            let responses = client.server_streaming(msg).await?;
+            Ok(channels::std_receiver(responses))
+        })
+    }
+}
@@ -0,0 +1,626 @@
+//! Bulk transfers striped across multiple streams
+//!
+//! Every call uses its own stream, and the throughput of a stream is limited
+//! by its flow control window. On paths with a high bandwidth delay product a
+//! large transfer over a single stream can therefore not use the whole link.
+//! [send] splits the data of a transfer into [Segment]s and stripes them
+//! across several client streaming calls, one per stream, and a
+//! [BulkReceiver] on the server reassembles them in order.
+//!
+//! The stripe message needs to be part of the service, as a client streaming
+//! call:
+//!
+//! ```ignore
+//! declare_client_streaming!(BulkService, Stripe, Segment, StripeReceived);
+//! ```
+//!
+//! The server handles every stripe with [BulkReceiver::handle], and gets the
+//! reassembled transfers from [BulkTransfers::accept]:
+//!
+//! ```ignore
+//! let (receiver, mut transfers) = BulkReceiver::new(DEFAULT_MAX_PENDING);
+//! // in the dispatch loop
+//! Request::Stripe(msg) => chan.client_streaming(msg, receiver.clone(), BulkReceiver::handle).await,
+//! // somewhere else
+//! while let Some(mut transfer) = transfers.accept().await {
+//!     while let Some(data) = transfer.recv().await {
+//!         file.write_all(&data?).await?;
+//!     }
+//! }
+//! ```
+//!
+//! A transfer only ends once all of its stripes arrived and ended, so a client
+//! that fails before opening all stripes leaves the transfer waiting.
+//!
+//! To serve a file, [send_file] reads a [FileRegion] directly into the
+//! segments, at their position in the file, instead of reading it into chunks
+//! that then need to be split and copied into segments.
+use crate::{
+    client::{ClientStreamingError, ClientStreamingItemError},
+    message::ClientStreamingMsg,
+    transport::ConnectionErrors,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{future, stream, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error, fmt,
+    fs::File,
+    io,
+    path::Path,
+    result,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{mpsc, Notify};
+
+/// Default number of streams of a bulk transfer
+pub const DEFAULT_STRIPES: usize = 4;
+
+/// Default size of the segments of a bulk transfer, in bytes
+pub const DEFAULT_SEGMENT_SIZE: usize = 64 * 1024;
+
+/// Default number of segments a [BulkReceiver] buffers per transfer while
+/// waiting for an earlier segment
+pub const DEFAULT_MAX_PENDING: usize = 64;
+
+/// Request that opens one stripe of a bulk transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stripe {
+    /// The id of the transfer, which is the same for all stripes
+    pub id: String,
+    /// The index of this stripe
+    pub index: u32,
+    /// The number of stripes of the transfer
+    pub stripes: u32,
+}
+
+/// A segment of the data of a bulk transfer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Segment {
+    /// The position of the segment in the transfer, over all stripes
+    pub seq: u64,
+    /// The data
+    pub data: Vec<u8>,
+}
+
+/// The number of segments the server received on a stripe
+///
+/// This is 0 for stripes the server rejected, e.g. because another stripe of
+/// the transfer already used the same index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StripeReceived(pub u64);
+
+/// Options of a single bulk transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkOptions {
+    /// The number of streams the data is striped across
+    pub stripes: usize,
+    /// The size of the segments the data is split into, in bytes
+    pub segment_size: usize,
+}
+
+impl Default for BulkOptions {
+    fn default() -> Self {
+        Self {
+            stripes: DEFAULT_STRIPES,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+        }
+    }
+}
+
+/// Send the data produced by `data` as bulk transfer `id`
+///
+/// The data is split into segments of `options.segment_size` bytes, which are
+/// sent round robin on `options.stripes` client streaming calls. Returns the
+/// number of bytes sent, once the server confirmed it received every segment.
+pub async fn send<S, C, St>(
+    client: &RpcClient<S, C>,
+    id: impl Into<String>,
+    data: St,
+    options: BulkOptions,
+) -> result::Result<u64, BulkError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    Stripe: ClientStreamingMsg<S, Update = Segment, Response = StripeReceived>,
+    Segment: Into<S::Req>,
+    St: Stream<Item = Vec<u8>>,
+{
+    let segment_size = options.segment_size.max(1);
+    let state = (Box::pin(data), Vec::new(), false);
+    let segments = stream::unfold(state, move |(mut data, mut buffer, mut done)| async move {
+        while !done && buffer.len() < segment_size {
+            match data.next().await {
+                Some(item) => buffer.extend(item),
+                None => done = true,
+            }
+        }
+        if buffer.is_empty() {
+            return None;
+        }
+        let rest = buffer.split_off(segment_size.min(buffer.len()));
+        let segment = std::mem::replace(&mut buffer, rest);
+        Some((Ok(segment), (data, buffer, done)))
+    });
+    send_segments(client, id.into(), segments, options.stripes).await
+}
+
+/// Send the data of a file region as bulk transfer `id`
+///
+/// Like [send], but every segment is read from the file directly into the
+/// buffer that is sent. Reading happens on the blocking thread pool, ahead of
+/// the stripes by a few segments.
+pub async fn send_file<S, C>(
+    client: &RpcClient<S, C>,
+    id: impl Into<String>,
+    region: FileRegion,
+    options: BulkOptions,
+) -> result::Result<u64, BulkError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    Stripe: ClientStreamingMsg<S, Update = Segment, Response = StripeReceived>,
+    Segment: Into<S::Req>,
+{
+    let segments = region
+        .segments(options.segment_size.max(1))
+        .map(|segment| segment.map_err(BulkError::Read));
+    send_segments(client, id.into(), segments, options.stripes).await
+}
+
+/// Stripe already split segments across `stripes` client streaming calls
+async fn send_segments<S, C, St>(
+    client: &RpcClient<S, C>,
+    id: String,
+    segments: St,
+    stripes: usize,
+) -> result::Result<u64, BulkError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    Stripe: ClientStreamingMsg<S, Update = Segment, Response = StripeReceived>,
+    Segment: Into<S::Req>,
+    St: Stream<Item = result::Result<Vec<u8>, BulkError<C>>>,
+{
+    let stripes = stripes.max(1);
+    let mut senders = Vec::with_capacity(stripes);
+    let mut forwards = Vec::with_capacity(stripes);
+    for index in 0..stripes {
+        let msg = Stripe {
+            id: id.clone(),
+            index: index as u32,
+            stripes: stripes as u32,
+        };
+        let (mut sink, response) = client
+            .client_streaming(msg)
+            .await
+            .map_err(BulkError::Open)?;
+        // a small queue per stripe, so a stripe with a full window does not
+        // hold up the others
+        let (sender, mut segments) = mpsc::channel::<Segment>(2);
+        senders.push(sender);
+        forwards.push(async move {
+            let mut sent = 0;
+            while let Some(segment) = segments.recv().await {
+                sink.send(segment).await.map_err(BulkError::Send)?;
+                sent += 1;
+            }
+            sink.close().await.map_err(BulkError::Send)?;
+            // some transports only end the update stream once the sink is dropped
+            drop(sink);
+            let StripeReceived(received) = response.await.map_err(BulkError::Recv)?;
+            if received != sent {
+                return Err(BulkError::Incomplete {
+                    stripe: index as u32,
+                    sent,
+                    received,
+                });
+            }
+            Ok(())
+        });
+    }
+    let split = async move {
+        tokio::pin!(segments);
+        let mut seq = 0u64;
+        let mut bytes = 0u64;
+        while let Some(data) = segments.next().await {
+            let data = data?;
+            bytes += data.len() as u64;
+            let sender = &senders[(seq % stripes as u64) as usize];
+            if sender.send(Segment { seq, data }).await.is_err() {
+                // the stripe failed, its error is returned by the forward
+                break;
+            }
+            seq += 1;
+        }
+        // dropping the senders ends the stripes
+        Ok::<_, BulkError<C>>(bytes)
+    };
+    let (bytes, _) = future::try_join(split, future::try_join_all(forwards)).await?;
+    Ok(bytes)
+}
+
+/// A region of a file, to be sent with [send_file]
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    file: Arc<File>,
+    offset: u64,
+    len: u64,
+}
+
+impl FileRegion {
+    /// The `len` bytes of `file` starting at `offset`
+    pub fn new(file: File, offset: u64, len: u64) -> Self {
+        Self {
+            file: Arc::new(file),
+            offset,
+            len,
+        }
+    }
+
+    /// The whole file, as long as it is now
+    pub fn whole(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self::new(file, 0, len))
+    }
+
+    /// Open the file at `path`, as a region of the whole file
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        tokio::task::spawn_blocking(move || Self::whole(File::open(path)?))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+    }
+
+    /// The offset of the region in the file
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The length of the region, in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// True if the region is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The region, read as segments of `segment_size` bytes
+    ///
+    /// Fails with [io::ErrorKind::UnexpectedEof] if the file is shorter than
+    /// the region.
+    fn segments(self, segment_size: usize) -> impl Stream<Item = io::Result<Vec<u8>>> {
+        let end = self.offset.saturating_add(self.len);
+        stream::unfold(Some(self.offset), move |pos| {
+            let file = self.file.clone();
+            async move {
+                let pos = pos.filter(|pos| *pos < end)?;
+                let len = (end - pos).min(segment_size as u64) as usize;
+                let read = tokio::task::spawn_blocking(move || {
+                    let mut data = vec![0; len];
+                    read_at(&file, pos, &mut data)?;
+                    Ok(data)
+                })
+                .await
+                .unwrap_or_else(|e| Err(io::Error::new(io::ErrorKind::Other, e)));
+                // stop after the first error
+                let next = read.is_ok().then(|| pos + len as u64);
+                Some((read, next))
+            }
+        })
+    }
+}
+
+/// Fill `buf` with the data of `file` at `pos`
+///
+/// Clones of a [FileRegion] share the file, so this does not use the cursor
+/// of the file where the platform allows it.
+#[cfg(unix)]
+fn read_at(file: &File, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, pos)
+}
+
+#[cfg(not(unix))]
+fn read_at(file: &File, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = file;
+    file.seek(SeekFrom::Start(pos))?;
+    file.read_exact(buf)
+}
+
+/// Error for [send] and [send_file]
+#[derive(Debug)]
+pub enum BulkError<C: ConnectionErrors> {
+    /// Unable to open a stripe
+    Open(ClientStreamingError<C>),
+    /// Unable to send a segment
+    Send(C::SendError),
+    /// Unable to receive the response to a stripe
+    Recv(ClientStreamingItemError<C>),
+    /// Unable to read the data of a [FileRegion]
+    Read(io::Error),
+    /// The server did not receive all segments of a stripe
+    Incomplete {
+        /// The index of the stripe
+        stripe: u32,
+        /// The number of segments sent on the stripe
+        sent: u64,
+        /// The number of segments the server received on the stripe
+        received: u64,
+    },
+}
+
+impl<C: ConnectionErrors> fmt::Display for BulkError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for BulkError<C> {}
+
+/// Reassembles the bulk transfers a server receives
+///
+/// Clones share the same transfers.
+#[derive(Debug, Clone)]
+pub struct BulkReceiver {
+    transfers: Arc<Mutex<HashMap<String, Arc<Transfer>>>>,
+    accepted: mpsc::UnboundedSender<BulkTransfer>,
+    max_pending: usize,
+}
+
+/// The bulk transfers of a [BulkReceiver], see [BulkReceiver::new]
+#[derive(Debug)]
+pub struct BulkTransfers(mpsc::UnboundedReceiver<BulkTransfer>);
+
+impl BulkTransfers {
+    /// Wait for the first stripe of the next transfer
+    ///
+    /// Returns `None` once all clones of the [BulkReceiver] are dropped.
+    pub async fn accept(&mut self) -> Option<BulkTransfer> {
+        self.0.recv().await
+    }
+}
+
+impl BulkReceiver {
+    /// Create a new receiver, and the transfers it receives
+    ///
+    /// Up to `max_pending` segments are buffered per transfer while waiting for
+    /// an earlier segment. Stripes that are further ahead wait, so a slow
+    /// stripe limits how far the others can get.
+    pub fn new(max_pending: usize) -> (Self, BulkTransfers) {
+        let (accepted, transfers) = mpsc::unbounded_channel();
+        let receiver = Self {
+            transfers: Default::default(),
+            accepted,
+            max_pending: max_pending.max(1),
+        };
+        (receiver, BulkTransfers(transfers))
+    }
+
+    /// Handler for [Stripe]s, to be used with [RpcChannel::client_streaming](crate::server::RpcChannel::client_streaming)
+    pub async fn handle<St>(self, req: Stripe, segments: St) -> StripeReceived
+    where
+        St: Stream<Item = Segment>,
+    {
+        let transfer = match self.join(&req) {
+            Some(transfer) => transfer,
+            None => {
+                tracing::debug!("rejected stripe {} of transfer {}", req.index, req.id);
+                return StripeReceived(0);
+            }
+        };
+        // the handler is dropped if reading the updates fails
+        let mut guard = StripeGuard {
+            receiver: &self,
+            id: &req.id,
+            transfer: &transfer,
+            ended: false,
+        };
+        tokio::pin!(segments);
+        let mut received = 0;
+        while let Some(segment) = segments.next().await {
+            transfer.insert(segment, self.max_pending).await;
+            received += 1;
+        }
+        guard.ended = true;
+        StripeReceived(received)
+    }
+
+    /// Join a stripe to its transfer, creating the transfer for the first stripe
+    fn join(&self, req: &Stripe) -> Option<Arc<Transfer>> {
+        if req.index >= req.stripes {
+            return None;
+        }
+        let mut transfers = self.transfers.lock().unwrap();
+        let transfer = match transfers.get(&req.id) {
+            Some(transfer) => transfer.clone(),
+            None => {
+                let transfer = Arc::new(Transfer::new(req.stripes));
+                let accepted = BulkTransfer {
+                    id: req.id.clone(),
+                    transfer: transfer.clone(),
+                };
+                if self.accepted.send(accepted).is_err() {
+                    return None;
+                }
+                transfers.insert(req.id.clone(), transfer.clone());
+                transfer
+            }
+        };
+        let mut state = transfer.state.lock().unwrap();
+        let index = req.index as usize;
+        if req.stripes as usize != state.joined.len() || state.joined.get(index) != Some(&false) {
+            return None;
+        }
+        state.joined[index] = true;
+        drop(state);
+        Some(transfer)
+    }
+}
+
+/// Ends a stripe, failing the transfer if the stripe did not end normally
+struct StripeGuard<'a> {
+    receiver: &'a BulkReceiver,
+    id: &'a str,
+    transfer: &'a Arc<Transfer>,
+    ended: bool,
+}
+
+impl<'a> Drop for StripeGuard<'a> {
+    fn drop(&mut self) {
+        let mut state = self.transfer.state.lock().unwrap();
+        state.ended += 1;
+        state.failed |= !self.ended;
+        if state.failed || state.ended == state.joined.len() {
+            // the id can be used for a new transfer
+            let mut transfers = self.receiver.transfers.lock().unwrap();
+            if let Some(transfer) = transfers.get(self.id) {
+                if Arc::ptr_eq(transfer, self.transfer) {
+                    transfers.remove(self.id);
+                }
+            }
+        }
+        drop(state);
+        self.transfer.changed.notify_waiters();
+    }
+}
+
+#[derive(Debug)]
+struct Transfer {
+    state: Mutex<TransferState>,
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct TransferState {
+    /// Which stripes joined, by index
+    joined: Vec<bool>,
+    /// The number of stripes that ended
+    ended: usize,
+    /// A stripe did not end normally
+    failed: bool,
+    /// The consumer of the transfer was dropped
+    dropped: bool,
+    /// The seq of the next segment of the consumer
+    next: u64,
+    /// Segments that arrived before an earlier one, by seq
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Transfer {
+    fn new(stripes: u32) -> Self {
+        Self {
+            state: Mutex::new(TransferState {
+                joined: vec![false; stripes as usize],
+                ended: 0,
+                failed: false,
+                dropped: false,
+                next: 0,
+                pending: BTreeMap::new(),
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Add a segment, waiting while it is too far ahead of the consumer
+    async fn insert(&self, segment: Segment, max_pending: usize) {
+        loop {
+            let changed = self.changed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.dropped || state.failed || segment.seq < state.next {
+                    // nobody is interested, or a duplicate
+                    return;
+                }
+                if segment.seq < state.next + max_pending as u64 {
+                    state.pending.insert(segment.seq, segment.data);
+                    drop(state);
+                    self.changed.notify_waiters();
+                    return;
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+/// A bulk transfer received by a [BulkReceiver]
+#[derive(Debug)]
+pub struct BulkTransfer {
+    id: String,
+    transfer: Arc<Transfer>,
+}
+
+impl BulkTransfer {
+    /// The id of the transfer
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The data of the next segment, in the order it was sent
+    ///
+    /// Returns `None` once all stripes ended and all segments were returned,
+    /// or after returning an error.
+    pub async fn recv(&mut self) -> Option<result::Result<Vec<u8>, BulkFailed>> {
+        loop {
+            let changed = self.transfer.changed.notified();
+            {
+                let mut state = self.transfer.state.lock().unwrap();
+                let next = state.next;
+                if let Some(data) = state.pending.remove(&next) {
+                    state.next += 1;
+                    drop(state);
+                    self.transfer.changed.notify_waiters();
+                    return Some(Ok(data));
+                }
+                if state.dropped {
+                    return None;
+                }
+                let complete = state.ended == state.joined.len();
+                if state.failed || (complete && !state.pending.is_empty()) {
+                    // only report the failure once
+                    state.dropped = true;
+                    state.pending.clear();
+                    return Some(Err(BulkFailed));
+                }
+                if complete {
+                    return None;
+                }
+            }
+            changed.await;
+        }
+    }
+
+    /// Turn the transfer into a stream of the data of its segments
+    pub fn into_stream(self) -> impl Stream<Item = result::Result<Vec<u8>, BulkFailed>> {
+        futures::stream::unfold(self, |mut transfer| async move {
+            let item = transfer.recv().await?;
+            Some((item, transfer))
+        })
+    }
+}
+
+impl Drop for BulkTransfer {
+    fn drop(&mut self) {
+        let mut state = self.transfer.state.lock().unwrap();
+        state.dropped = true;
+        state.pending.clear();
+        drop(state);
+        self.transfer.changed.notify_waiters();
+    }
+}
+
+/// A stripe of a bulk transfer failed, so the transfer is incomplete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulkFailed;
+
+impl fmt::Display for BulkFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bulk transfer failed")
+    }
+}
+
+impl error::Error for BulkFailed {}
@@ -0,0 +1,216 @@
+//! Server side caching of rpc responses
+//!
+//! Expensive rpc methods whose response only depends on the request can be
+//! marked with [RpcMsg::CACHEABLE]. With a [ResponseCache] configured using
+//! [RpcServer::with_response_cache](crate::RpcServer::with_response_cache),
+//! responses of these methods are cached, keyed by the serialized request, and
+//! repeated identical requests are answered without calling the handler.
+//!
+//! Entries expire after a time to live, which can be set per method. Entries
+//! can also be invalidated explicitly, e.g. from a hook that sees every
+//! accepted request and invalidates the methods a write affects:
+//!
+//! ```ignore
+//! let cache = ResponseCache::<StoreService>::new(Duration::from_secs(60))
+//!     .with_invalidation(|req, cache| {
+//!         if let StoreRequest::Put(_) = req {
+//!             cache.invalidate::<Get>();
+//!         }
+//!     });
+//! ```
+//!
+//! Concurrent identical requests that miss the cache are all handled, and the
+//! last response is cached.
+use crate::{message::RpcMsg, Service};
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Default maximum number of cached responses
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+type Hook<S> = Arc<dyn Fn(&<S as Service>::Req, &ResponseCache<S>) + Send + Sync>;
+
+/// The key of a cached response, the method and the serialized request
+type Key = (TypeId, Vec<u8>);
+
+struct Entry {
+    response: Vec<u8>,
+    expires: Instant,
+}
+
+/// A cache of responses of [cacheable](RpcMsg::CACHEABLE) rpc methods
+///
+/// Clones share the cached responses.
+pub struct ResponseCache<S: Service> {
+    entries: Arc<Mutex<HashMap<Key, Entry>>>,
+    ttl: Duration,
+    methods: HashMap<TypeId, Duration>,
+    capacity: usize,
+    hooks: Vec<Hook<S>>,
+}
+
+impl<S: Service> Clone for ResponseCache<S> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            ttl: self.ttl,
+            methods: self.methods.clone(),
+            capacity: self.capacity,
+            hooks: self.hooks.clone(),
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for ResponseCache<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl<S: Service> ResponseCache<S> {
+    /// Create a cache whose entries expire after `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            ttl,
+            methods: HashMap::new(),
+            capacity: DEFAULT_CAPACITY,
+            hooks: Vec::new(),
+        }
+    }
+
+    /// Set the maximum number of cached responses
+    ///
+    /// When the cache is full, expired entries are removed first, then the
+    /// entries that expire soonest.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Set the time to live of cached responses of message type `M`
+    pub fn with_method_ttl<M: RpcMsg<S>>(mut self, ttl: Duration) -> Self {
+        self.methods.insert(TypeId::of::<M>(), ttl);
+        self
+    }
+
+    /// Call `f` with every request accepted by the server, before it is handled
+    ///
+    /// This allows invalidating cached responses that a request makes stale.
+    pub fn with_invalidation<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&S::Req, &ResponseCache<S>) + Send + Sync + 'static,
+    {
+        self.hooks.push(Arc::new(f));
+        self
+    }
+
+    /// Remove all cached responses of message type `M`
+    pub fn invalidate<M: RpcMsg<S>>(&self) {
+        let id = TypeId::of::<M>();
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(method, _), _| *method != id);
+    }
+
+    /// Remove all cached responses
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// The number of cached responses, including expired ones that were not removed yet
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Whether no responses are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run the invalidation hooks for an accepted request
+    pub(crate) fn accepted(&self, req: &S::Req) {
+        for hook in &self.hooks {
+            hook(req, self);
+        }
+    }
+
+    /// Look up the response for a request
+    ///
+    /// Returns `None` if the request can not be converted back, which does not
+    /// happen for messages declared with the `declare_*` macros.
+    pub(crate) fn lookup<M: RpcMsg<S>>(&self, req: M) -> Option<Lookup<S, M>> {
+        let req: S::Req = req.into();
+        let key = bincode::serialize(&req)
+            .ok()
+            .map(|bytes| (TypeId::of::<M>(), bytes));
+        if let Some(key) = &key {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(key) {
+                Some(entry) if entry.expires > Instant::now() => {
+                    if let Ok(res) = bincode::deserialize(&entry.response) {
+                        return Some(Lookup::Hit(res));
+                    }
+                }
+                Some(_) => {
+                    entries.remove(key);
+                }
+                None => {}
+            }
+        }
+        let req = M::try_from(req).ok()?;
+        Some(Lookup::Miss(req, CacheKey(key)))
+    }
+
+    /// Cache the response for a request that missed the cache
+    pub(crate) fn insert(&self, key: CacheKey, res: &S::Res) {
+        let (key, response) = match (key.0, bincode::serialize(res)) {
+            (Some(key), Ok(response)) => (key, response),
+            _ => return,
+        };
+        if self.capacity == 0 {
+            return;
+        }
+        let ttl = self.methods.get(&key.0).copied().unwrap_or(self.ttl);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        while entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            match soonest {
+                Some(soonest) => entries.remove(&soonest),
+                None => break,
+            };
+        }
+        let expires = now + ttl;
+        entries.insert(key, Entry { response, expires });
+    }
+}
+
+/// Where to cache the response of a request that missed the cache
+///
+/// `None` if the request could not be serialized, so it is not cached.
+pub(crate) struct CacheKey(Option<Key>);
+
+/// The result of looking up a request in the cache
+pub(crate) enum Lookup<S: Service, M> {
+    /// The cached response
+    Hit(S::Res),
+    /// The request, which needs to be handled
+    Miss(M, CacheKey),
+}
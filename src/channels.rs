@@ -0,0 +1,465 @@
+//! Adapters between rpc streams and sinks and plain channels
+//!
+//! Code that is structured around channels can be plugged into rpc calls
+//! without rewriting it. Response streams and [UpdateStream]s can be turned
+//! into channel receivers, and [UpdateSink]s into channel senders:
+//!
+//! ```ignore
+//! let (updates, responses) = client.bidi(Multiply(2)).await?;
+//! let (tx, forward) = channels::tokio_sender(updates, 16);
+//! let rx = channels::tokio_receiver(responses, 16);
+//! run_existing_worker(tx, rx).await;
+//! ```
+//!
+//! The other way around, channel receivers can be turned into streams, e.g. to
+//! return them as the response stream of a handler, and channel senders into
+//! sinks. The receivers of [futures::channel::mpsc] already are streams and
+//! its senders are sinks, so they can be used directly.
+//!
+//! Adapters to channel endpoints forward items on a spawned task, so they must
+//! be called from within a tokio runtime. Dropping the channel endpoint stops
+//! the forwarding and drops the rpc stream or sink, which ends the call.
+//!
+//! A response stream can also be split into several consumers with [tee],
+//! e.g. when both the UI and persistence need the events of a subscription,
+//! without making the call twice.
+//!
+//! A [Pausable] response stream stops reading while it is paused, e.g. while
+//! a heavy feed is hidden in a UI, without ending the call:
+//!
+//! ```ignore
+//! let feed = Pausable::new(client.server_streaming(Feed).await?);
+//! let handle = feed.handle();
+//! tokio::spawn(render(feed));
+//! // later, from the UI
+//! handle.pause();
+//! ```
+//!
+//! [UpdateStream]: crate::server::UpdateStream
+//! [UpdateSink]: crate::client::UpdateSink
+use futures::{
+    channel::mpsc, sink, stream, stream::BoxStream, task::AtomicWaker, Sink, SinkExt, Stream,
+    StreamExt,
+};
+use pin_project::pin_project;
+use std::{
+    error, fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc as std_mpsc, Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{sync::mpsc as tokio_mpsc, task::JoinHandle};
+
+/// A boxed sink, as returned by [tokio_sink] and [std_sink]
+pub type BoxSink<T, E> = Pin<Box<dyn Sink<T, Error = E> + Send>>;
+
+/// Forward the items of `stream` into a [futures::channel::mpsc] channel
+/// with the given buffer size
+///
+/// If the receiver is dropped, forwarding stops once the next item arrives.
+pub fn futures_receiver<St>(stream: St, buffer: usize) -> mpsc::Receiver<St::Item>
+where
+    St: Stream + Send + 'static,
+    St::Item: Send,
+{
+    let (mut tx, rx) = mpsc::channel(buffer);
+    tokio::spawn(async move {
+        tokio::pin!(stream);
+        while let Some(item) = stream.next().await {
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Forward the items of `stream` into a tokio mpsc channel with the given
+/// buffer size
+///
+/// If the receiver is dropped, forwarding stops right away.
+pub fn tokio_receiver<St>(stream: St, buffer: usize) -> tokio_mpsc::Receiver<St::Item>
+where
+    St: Stream + Send + 'static,
+    St::Item: Send,
+{
+    let (tx, rx) = tokio_mpsc::channel(buffer);
+    tokio::spawn(async move {
+        tokio::pin!(stream);
+        loop {
+            let item = tokio::select! {
+                item = stream.next() => item,
+                // stop waiting for the next item once the receiver is gone
+                _ = tx.closed() => None,
+            };
+            let item = match item {
+                Some(item) => item,
+                None => break,
+            };
+            if tx.send(item).await.is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Forward the items of `stream` into an unbounded [std::sync::mpsc] channel
+///
+/// This allows consuming an rpc stream from a thread that is not part of the
+/// runtime. If the receiver is dropped, forwarding stops once the next item
+/// arrives.
+pub fn std_receiver<St>(stream: St) -> std_mpsc::Receiver<St::Item>
+where
+    St: Stream + Send + 'static,
+    St::Item: Send,
+{
+    let (tx, rx) = std_mpsc::channel();
+    tokio::spawn(async move {
+        tokio::pin!(stream);
+        while let Some(item) = stream.next().await {
+            if tx.send(item).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Forward the items sent on a [futures::channel::mpsc] channel with the given
+/// buffer size into `sink`
+///
+/// The task completes once all senders are dropped and the sink is closed, or
+/// with the first error of the sink.
+pub fn futures_sender<Si, T>(
+    sink: Si,
+    buffer: usize,
+) -> (mpsc::Sender<T>, JoinHandle<Result<(), Si::Error>>)
+where
+    Si: Sink<T> + Send + 'static,
+    Si::Error: Send,
+    T: Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(buffer);
+    let task = tokio::spawn(rx.map(Ok).forward(sink));
+    (tx, task)
+}
+
+/// Forward the items sent on a tokio mpsc channel with the given buffer size
+/// into `sink`
+///
+/// See [futures_sender] for when the task completes.
+pub fn tokio_sender<Si, T>(
+    sink: Si,
+    buffer: usize,
+) -> (tokio_mpsc::Sender<T>, JoinHandle<Result<(), Si::Error>>)
+where
+    Si: Sink<T> + Send + 'static,
+    Si::Error: Send,
+    T: Send + 'static,
+{
+    let (tx, rx) = tokio_mpsc::channel(buffer);
+    let task = tokio::spawn(tokio_stream(rx).map(Ok).forward(sink));
+    (tx, task)
+}
+
+/// Forward the items sent on an unbounded [std::sync::mpsc] channel into `sink`
+///
+/// This allows feeding an rpc sink from a thread that is not part of the
+/// runtime. Receiving from the channel blocks, so it is done on a blocking
+/// thread of the runtime. See [futures_sender] for when the task completes.
+pub fn std_sender<Si, T>(sink: Si) -> (std_mpsc::Sender<T>, JoinHandle<Result<(), Si::Error>>)
+where
+    Si: Sink<T> + Send + 'static,
+    Si::Error: Send,
+    T: Send + 'static,
+{
+    let (tx, rx) = std_mpsc::channel();
+    let task = tokio::spawn(std_stream(rx).map(Ok).forward(sink));
+    (tx, task)
+}
+
+/// Turn a tokio mpsc receiver into a stream
+pub fn tokio_stream<T: Send + 'static>(rx: tokio_mpsc::Receiver<T>) -> BoxStream<'static, T> {
+    stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((item, rx))
+    })
+    .boxed()
+}
+
+/// Turn a tokio mpsc sender into a sink
+///
+/// The sink fails with [tokio_mpsc::error::SendError] once the receiver is
+/// dropped.
+pub fn tokio_sink<T: Send + 'static>(
+    tx: tokio_mpsc::Sender<T>,
+) -> BoxSink<T, tokio_mpsc::error::SendError<T>> {
+    Box::pin(sink::unfold(tx, |tx, item| async move {
+        tx.send(item).await?;
+        Ok(tx)
+    }))
+}
+
+/// Turn a [std::sync::mpsc] receiver into a stream
+///
+/// Receiving blocks, so it is done on a blocking thread of the runtime, which
+/// is kept busy until all senders are dropped or the stream is dropped and
+/// another item arrives.
+pub fn std_stream<T: Send + 'static>(rx: std_mpsc::Receiver<T>) -> mpsc::Receiver<T> {
+    let (mut tx, items) = mpsc::channel(1);
+    tokio::task::spawn_blocking(move || {
+        let handle = tokio::runtime::Handle::current();
+        while let Ok(item) = rx.recv() {
+            if handle.block_on(tx.send(item)).is_err() {
+                break;
+            }
+        }
+    });
+    items
+}
+
+/// Turn a [std::sync::mpsc] sender into a sink
+///
+/// Sending on an unbounded channel never blocks. The sink fails with
+/// [std_mpsc::SendError] once the receiver is dropped.
+pub fn std_sink<T: Send + 'static>(tx: std_mpsc::Sender<T>) -> BoxSink<T, std_mpsc::SendError<T>> {
+    Box::pin(sink::unfold(tx, |tx, item| async move {
+        tx.send(item)?;
+        Ok(tx)
+    }))
+}
+
+/// What [tee] does when a consumer falls behind
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// Wait until the consumer has room, so every consumer gets every item
+    ///
+    /// The slowest consumer limits how fast the response stream is read.
+    #[default]
+    Wait,
+    /// Skip items for a consumer whose buffer is full
+    ///
+    /// The consumer gets a [TeeError::Lagged] with the number of skipped
+    /// items before the next item it receives, or at the end of the stream.
+    Skip,
+}
+
+/// Error item of a stream returned by [tee]
+#[derive(Debug)]
+pub enum TeeError<E> {
+    /// The response stream failed, the error is shared by all consumers
+    Inner(Arc<E>),
+    /// The consumer fell behind and missed this many items, see [LagPolicy::Skip]
+    Lagged(u64),
+}
+
+impl<E> Clone for TeeError<E> {
+    fn clone(&self) -> Self {
+        match self {
+            TeeError::Inner(cause) => TeeError::Inner(cause.clone()),
+            TeeError::Lagged(n) => TeeError::Lagged(*n),
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for TeeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for TeeError<E> {}
+
+/// A consumer of [tee], with the number of items it missed
+struct Consumer<T, E> {
+    tx: tokio_mpsc::Sender<Result<T, TeeError<E>>>,
+    missed: u64,
+}
+
+/// Split a response stream into `consumers` streams that all get its items
+///
+/// Every consumer buffers up to `buffer` items, and `policy` decides what
+/// happens when a buffer is full. Errors of the response stream are shared
+/// with all consumers, so the items only need to be [Clone].
+///
+/// The items are forwarded on a spawned task. Consumers can be dropped at any
+/// time; once all of them are dropped, forwarding stops when the next item
+/// arrives, and the response stream is dropped, which ends the call.
+pub fn tee<St, T, E>(
+    stream: St,
+    consumers: usize,
+    buffer: usize,
+    policy: LagPolicy,
+) -> Vec<BoxStream<'static, Result<T, TeeError<E>>>>
+where
+    St: Stream<Item = Result<T, E>> + Send + 'static,
+    T: Clone + Send + 'static,
+    E: Send + Sync + 'static,
+{
+    let buffer = buffer.max(1);
+    let mut streams = Vec::with_capacity(consumers);
+    let mut targets = Vec::with_capacity(consumers);
+    for _ in 0..consumers {
+        // one slot more than the buffer, so a skipping consumer always has
+        // room to be told about items it missed at the end of the stream
+        let (tx, rx) = tokio_mpsc::channel(buffer + 1);
+        targets.push(Consumer { tx, missed: 0 });
+        streams.push(tokio_stream(rx));
+    }
+    tokio::spawn(async move {
+        tokio::pin!(stream);
+        while let Some(item) = stream.next().await {
+            let item = item.map_err(|cause| TeeError::Inner(Arc::new(cause)));
+            let mut open = Vec::with_capacity(targets.len());
+            for mut target in targets {
+                let delivered = match policy {
+                    LagPolicy::Wait => target.tx.send(item.clone()).await.is_ok(),
+                    LagPolicy::Skip => target.skip_send(&item, buffer),
+                };
+                if delivered {
+                    open.push(target);
+                }
+            }
+            targets = open;
+            if targets.is_empty() {
+                break;
+            }
+        }
+        for target in targets {
+            if target.missed > 0 {
+                target
+                    .tx
+                    .try_send(Err(TeeError::Lagged(target.missed)))
+                    .ok();
+            }
+        }
+    });
+    streams
+}
+
+impl<T: Clone, E> Consumer<T, E> {
+    /// Send an item if there is room in the buffer, otherwise skip it
+    ///
+    /// Returns false if the consumer is gone.
+    fn skip_send(&mut self, item: &Result<T, TeeError<E>>, buffer: usize) -> bool {
+        if self.tx.is_closed() {
+            return false;
+        }
+        // sent items and lag reports only use `buffer` of the slots
+        let mut used = buffer + 1 - self.tx.capacity();
+        if self.missed > 0 && used < buffer {
+            self.tx.try_send(Err(TeeError::Lagged(self.missed))).ok();
+            self.missed = 0;
+            used += 1;
+        }
+        if used >= buffer {
+            self.missed += 1;
+            return true;
+        }
+        self.tx.try_send(item.clone()).ok();
+        true
+    }
+}
+
+/// The state shared by a [Pausable] and its [PauseHandle]s
+#[derive(Debug, Default)]
+struct PauseState {
+    paused: AtomicBool,
+    /// The task that polled the stream while it was paused
+    waker: AtomicWaker,
+}
+
+/// A stream that can be paused and resumed without ending the call
+///
+/// While paused, the inner stream is not polled at all, so responses are
+/// left to the flow control of the transport: the quinn transport stops
+/// extending the receive window of the stream once its buffer is full, and
+/// bounded channels like the flume transport stop accepting responses, so
+/// the handler of the server waits until the stream is resumed.
+///
+/// This only stops reading. [Timeouts](crate::message::MethodPolicy::timeout)
+/// of the call keep running while it is paused.
+#[pin_project]
+#[derive(Debug)]
+pub struct Pausable<St> {
+    #[pin]
+    inner: St,
+    state: Arc<PauseState>,
+}
+
+impl<St: Stream> Pausable<St> {
+    /// Wrap `inner`, which starts out running
+    pub fn new(inner: St) -> Self {
+        Self {
+            inner,
+            state: Default::default(),
+        }
+    }
+
+    /// A handle to pause and resume the stream from elsewhere, e.g. a UI task
+    pub fn handle(&self) -> PauseHandle {
+        PauseHandle(self.state.clone())
+    }
+
+    /// Stop reading the inner stream until [Pausable::resume] is called
+    pub fn pause(&self) {
+        self.handle().pause()
+    }
+
+    /// Continue reading the inner stream
+    pub fn resume(&self) {
+        self.handle().resume()
+    }
+
+    /// Whether the stream is paused
+    pub fn is_paused(&self) -> bool {
+        self.state.paused.load(Ordering::Acquire)
+    }
+
+    /// The inner stream
+    pub fn into_inner(self) -> St {
+        self.inner
+    }
+}
+
+impl<St: Stream> Stream for Pausable<St> {
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if this.state.paused.load(Ordering::Acquire) {
+            this.state.waker.register(cx.waker());
+            // resumed between the check and registering the waker
+            if this.state.paused.load(Ordering::Acquire) {
+                return Poll::Pending;
+            }
+        }
+        this.inner.poll_next(cx)
+    }
+}
+
+/// Pauses and resumes a [Pausable] stream
+#[derive(Debug, Clone)]
+pub struct PauseHandle(Arc<PauseState>);
+
+impl PauseHandle {
+    /// Stop reading the stream until [PauseHandle::resume] is called
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Release);
+    }
+
+    /// Continue reading the stream
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Release);
+        self.0.waker.wake();
+    }
+
+    /// Whether the stream is paused
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Acquire)
+    }
+}
@@ -1,9 +1,14 @@
 //! Client side api
 //!
 //! The main entry point is [RpcClient].
+#[cfg(feature = "stream-limits")]
+use crate::limits::{Limited, MethodLimits, StreamDirection, StreamLimitExceeded, StreamLimits};
 use crate::{
-    message::{BidiStreamingMsg, ClientStreamingMsg, RpcMsg, ServerStreamingMsg},
-    transport::ConnectionErrors,
+    context::{self, Bounded, CallCancelled, CallContext},
+    coop::{Cooperative, DEFAULT_YIELD_BUDGET},
+    message::{BidiStreamingMsg, ClientStreamingMsg, Msg, RpcMsg, ServerStreamingMsg},
+    telemetry::{Call, Payloads, Side},
+    transport::{ConnectionErrors, DatagramConnection},
     Service, ServiceConnection,
 };
 use futures::{
@@ -11,13 +16,27 @@ use futures::{
 };
 use pin_project::pin_project;
 use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
     error,
     fmt::{self, Debug},
     marker::PhantomData,
     pin::Pin,
     result,
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
+use tracing::Instrument;
+
+/// Default number of attempts of [RpcClient::rpc_retry]
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// Default delay between attempts of [RpcClient::rpc_retry]
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Advances a request past a response, see [RpcClient::server_streaming_collect_resumable]
+type Advance<'a, M, R> = &'a mut (dyn FnMut(&mut M, &R) + Send);
 
 /// A client for a specific service
 ///
@@ -26,6 +45,17 @@ use std::{
 #[derive(Debug)]
 pub struct RpcClient<S, C> {
     source: C,
+    /// Number of response items received in a row before yielding
+    yield_budget: usize,
+    /// Stream middlewares, keyed by the type id of the message type
+    middlewares: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+    /// Whether calls are bounded by the current [CallContext](crate::context::CallContext)
+    propagate_context: bool,
+    /// Number of attempts and delay between them for retried calls
+    retries: (usize, Duration),
+    /// Limits for the response streams of calls
+    #[cfg(feature = "stream-limits")]
+    limits: Arc<MethodLimits>,
     p: PhantomData<S>,
 }
 
@@ -33,11 +63,20 @@ impl<S, C: Clone> Clone for RpcClient<S, C> {
     fn clone(&self) -> Self {
         Self {
             source: self.source.clone(),
+            yield_budget: self.yield_budget,
+            middlewares: self.middlewares.clone(),
+            propagate_context: self.propagate_context,
+            retries: self.retries,
+            #[cfg(feature = "stream-limits")]
+            limits: self.limits.clone(),
             p: PhantomData,
         }
     }
 }
 
+/// Per call factory for an item transformation of a response stream
+type ItemMapFactory<T> = Arc<dyn Fn() -> Box<dyn FnMut(T) -> T + Send> + Send + Sync>;
+
 /// Sink that can be used to send updates to the server for the two interaction patterns
 /// that support it, [crate::message::ClientStreaming] and [crate::message::BidiStreaming].
 #[pin_project]
@@ -68,6 +107,61 @@ impl<S: Service, C: ServiceConnection<S>, T: Into<S::Req>> Sink<T> for UpdateSin
     }
 }
 
+/// Accounting of a single rpc call, see [RpcClient::rpc_detailed]
+#[cfg(feature = "call-stats")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallStats {
+    /// Bytes of the bincode encoded requests of all attempts that were sent
+    pub bytes_sent: u64,
+    /// Bytes of the bincode encoded response
+    pub bytes_received: u64,
+    /// Time from sending the request of the last attempt until its response arrived
+    pub rtt: Duration,
+    /// Number of attempts that failed before the call succeeded
+    pub retries: usize,
+    /// Time all attempts waited for a substream, e.g. in the queue of a
+    /// [PriorityConnection](crate::transport::priority::PriorityConnection)
+    pub queue_time: Duration,
+}
+
+/// Records what happens during an rpc call, `()` records nothing
+trait Recorder<S: Service>: Send {
+    fn opened(&mut self, _queue_time: Duration) {}
+    /// The size of a request, measured before it is sent and passed to `sent` after
+    fn request_size(&self, _request: &S::Req) -> u64 {
+        0
+    }
+    fn sent(&mut self, _bytes: u64) {}
+    fn received(&mut self, _response: &S::Res, _rtt: Duration) {}
+    fn retried(&mut self) {}
+}
+
+impl<S: Service> Recorder<S> for () {}
+
+#[cfg(feature = "call-stats")]
+impl<S: Service> Recorder<S> for CallStats {
+    fn opened(&mut self, queue_time: Duration) {
+        self.queue_time += queue_time;
+    }
+
+    fn request_size(&self, request: &S::Req) -> u64 {
+        bincode::serialized_size(request).unwrap_or_default()
+    }
+
+    fn sent(&mut self, bytes: u64) {
+        self.bytes_sent += bytes;
+    }
+
+    fn received(&mut self, response: &S::Res, rtt: Duration) {
+        self.bytes_received += bincode::serialized_size(response).unwrap_or_default();
+        self.rtt = rtt;
+    }
+
+    fn retried(&mut self) {
+        self.retries += 1;
+    }
+}
+
 impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     /// Create a new rpc client for a specific [Service] given a compatible
     /// [ServiceConnection].
@@ -76,10 +170,128 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     pub fn new(source: C) -> Self {
         Self {
             source,
+            yield_budget: DEFAULT_YIELD_BUDGET,
+            middlewares: Default::default(),
+            propagate_context: false,
+            retries: (DEFAULT_MAX_ATTEMPTS, DEFAULT_RETRY_DELAY),
+            #[cfg(feature = "stream-limits")]
+            limits: Default::default(),
             p: PhantomData,
         }
     }
 
+    /// Set the number of items that can be received from a response stream
+    /// in a row before yielding to the runtime.
+    ///
+    /// The default is [DEFAULT_YIELD_BUDGET]. A budget of 0 disables cooperative yielding.
+    pub fn with_yield_budget(mut self, budget: usize) -> Self {
+        self.yield_budget = budget;
+        self
+    }
+
+    /// Bound calls by the [CallContext] they are made in
+    ///
+    /// When enabled, calls made while a context is current, e.g. from within a
+    /// handler of a server call, fail with [CallCancelled] once the deadline of
+    /// the context passes or it is cancelled. The default is disabled.
+    pub fn with_context_propagation(mut self, propagate: bool) -> Self {
+        self.propagate_context = propagate;
+        self
+    }
+
+    /// Set how often [RpcClient::rpc_retry] tries a call, and how long it
+    /// waits between attempts.
+    ///
+    /// The defaults are [DEFAULT_MAX_ATTEMPTS] and [DEFAULT_RETRY_DELAY]. If
+    /// the server pushed back an attempt, the delay it asked for is used instead.
+    pub fn with_retries(mut self, max_attempts: usize, delay: Duration) -> Self {
+        self.retries = (max_attempts.max(1), delay);
+        self
+    }
+
+    /// Install a middleware for the response stream of a server streaming message type `M`.
+    ///
+    /// `f` is called once per call to create the item transformation for that call,
+    /// so the transformation can keep per call state, e.g. to log every nth item.
+    /// The transformation is applied to every successfully received item.
+    ///
+    /// Installing a middleware for the same message type again replaces the previous one.
+    pub fn with_server_streaming_middleware<M, F, G>(self, f: F) -> Self
+    where
+        M: ServerStreamingMsg<S>,
+        F: Fn() -> G + Send + Sync + 'static,
+        G: FnMut(M::Response) -> M::Response + Send + 'static,
+    {
+        self.with_middleware::<M, M::Response, F, G>(f)
+    }
+
+    /// Install a middleware for the response stream of a bidi streaming message type `M`.
+    ///
+    /// See [RpcClient::with_server_streaming_middleware] for details.
+    pub fn with_bidi_middleware<M, F, G>(self, f: F) -> Self
+    where
+        M: BidiStreamingMsg<S>,
+        F: Fn() -> G + Send + Sync + 'static,
+        G: FnMut(M::Response) -> M::Response + Send + 'static,
+    {
+        self.with_middleware::<M, M::Response, F, G>(f)
+    }
+
+    fn with_middleware<M, T, F, G>(mut self, f: F) -> Self
+    where
+        M: 'static,
+        T: 'static,
+        F: Fn() -> G + Send + Sync + 'static,
+        G: FnMut(T) -> T + Send + 'static,
+    {
+        let factory: ItemMapFactory<T> = Arc::new(move || Box::new(f()));
+        Arc::make_mut(&mut self.middlewares).insert(TypeId::of::<M>(), Arc::new(factory));
+        self
+    }
+
+    /// Set the limits for the response streams of all calls.
+    ///
+    /// Once a response stream exceeds the limits, it ends with an error item.
+    /// Limits set for a specific message type using
+    /// [RpcClient::with_method_stream_limits] take precedence.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_stream_limits(mut self, limits: StreamLimits) -> Self {
+        Arc::make_mut(&mut self.limits).default = limits;
+        self
+    }
+
+    /// Set the limits for the response streams of calls of message type `M`.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_method_stream_limits<M: Msg<S>>(mut self, limits: StreamLimits) -> Self {
+        let methods = &mut Arc::make_mut(&mut self.limits).methods;
+        methods.insert(TypeId::of::<M>(), limits);
+        self
+    }
+
+    /// The context calls of message type `M` are bounded by, if any
+    ///
+    /// This is the current context if propagation is enabled, with the
+    /// timeout of the policy of `M`.
+    fn call_context<M: Msg<S>>(&self) -> Option<CallContext> {
+        let ctx = context::outbound(self.propagate_context);
+        match M::POLICY.timeout {
+            Some(timeout) => Some(ctx.unwrap_or_default().with_timeout(timeout)),
+            None => ctx,
+        }
+    }
+
+    /// Create the item transformation for a single call of message type `M`, if any
+    fn item_map<M: 'static, T: 'static>(&self) -> Option<Box<dyn FnMut(T) -> T + Send>> {
+        let factory = self.middlewares.get(&TypeId::of::<M>())?;
+        let factory = factory.downcast_ref::<ItemMapFactory<T>>()?;
+        Some(factory())
+    }
+
+    /// Get a reference to the underlying connection
+    pub fn inner(&self) -> &C {
+        &self.source
+    }
+
     /// Get the underlying connection
     pub fn into_inner(self) -> C {
         self.source
@@ -90,17 +302,121 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: RpcMsg<S>,
     {
-        let msg = msg.into();
-        let (mut send, mut recv) = self.source.open_bi().await.map_err(RpcClientError::Open)?;
-        send.send(msg).await.map_err(RpcClientError::<C>::Send)?;
-        let res = recv
-            .next()
-            .await
-            .ok_or(RpcClientError::<C>::EarlyClose)?
-            .map_err(RpcClientError::<C>::RecvError)?;
-        // keep send alive until we have the answer
-        drop(send);
-        M::Response::try_from(res).map_err(|_| RpcClientError::DowncastError)
+        self.rpc_recorded(msg, &mut ()).await
+    }
+
+    /// RPC call to the server that also returns the [CallStats] of the call
+    ///
+    /// Like [RpcClient::rpc], this makes a single attempt.
+    #[cfg(feature = "call-stats")]
+    pub async fn rpc_detailed<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<(M::Response, CallStats), RpcClientError<C>>
+    where
+        M: RpcMsg<S>,
+    {
+        let mut stats = CallStats::default();
+        let res = self.rpc_recorded(msg, &mut stats).await?;
+        Ok((res, stats))
+    }
+
+    async fn rpc_recorded<M, R>(
+        &self,
+        msg: M,
+        recorder: &mut R,
+    ) -> result::Result<M::Response, RpcClientError<C>>
+    where
+        M: RpcMsg<S>,
+        R: Recorder<S>,
+    {
+        let call = Call::start::<S, M>(Side::Client);
+        let ctx = self.call_context::<M>();
+        let res = context::bounded(ctx.as_ref(), async {
+            let msg = msg.into();
+            let payloads = Payloads::start::<S, M>(&msg);
+            let opening = Instant::now();
+            let (mut send, mut recv) = self.source.open_bi().await.map_err(RpcClientError::Open)?;
+            recorder.opened(opening.elapsed());
+            let size = recorder.request_size(&msg);
+            let sent = Instant::now();
+            send.send(msg).await.map_err(RpcClientError::<C>::Send)?;
+            recorder.sent(size);
+            let res = recv
+                .next()
+                .await
+                .ok_or(RpcClientError::<C>::EarlyClose)?
+                .map_err(RpcClientError::<C>::RecvError)?;
+            recorder.received(&res, sent.elapsed());
+            payloads.response(&res);
+            // keep send alive until we have the answer
+            drop(send);
+            M::Response::try_from(res).map_err(|_| RpcClientError::DowncastError)
+        })
+        .instrument(call.span().clone())
+        .await;
+        res.unwrap_or_else(|cause| Err(RpcClientError::Cancelled(cause)))
+    }
+
+    /// RPC call to the server that retries failed attempts, if the message is
+    /// [idempotent](crate::message::MethodPolicy::idempotent)
+    ///
+    /// Attempts that fail because the substream could not be opened, or the
+    /// request or response got lost, are retried as configured with
+    /// [RpcClient::with_retries]. Calls of other messages are only attempted
+    /// once, since the server might have handled a failed attempt. Fails with
+    /// the error of the last attempt.
+    pub async fn rpc_retry<M>(&self, msg: M) -> result::Result<M::Response, RpcClientError<C>>
+    where
+        M: RpcMsg<S> + Clone,
+    {
+        self.rpc_retry_recorded(msg, &mut ()).await
+    }
+
+    /// RPC call to the server that retries failed attempts, and also returns
+    /// the [CallStats] of all attempts
+    ///
+    /// See [RpcClient::rpc_retry] for which attempts are retried.
+    #[cfg(feature = "call-stats")]
+    pub async fn rpc_retry_detailed<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<(M::Response, CallStats), RpcClientError<C>>
+    where
+        M: RpcMsg<S> + Clone,
+    {
+        let mut stats = CallStats::default();
+        let res = self.rpc_retry_recorded(msg, &mut stats).await?;
+        Ok((res, stats))
+    }
+
+    async fn rpc_retry_recorded<M, R>(
+        &self,
+        msg: M,
+        recorder: &mut R,
+    ) -> result::Result<M::Response, RpcClientError<C>>
+    where
+        M: RpcMsg<S> + Clone,
+        R: Recorder<S>,
+    {
+        let (max_attempts, delay) = self.retries;
+        let max_attempts = if M::POLICY.idempotent {
+            max_attempts
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        loop {
+            match self.rpc_recorded(msg.clone(), recorder).await {
+                Err(cause) if attempt < max_attempts && cause.is_retryable() => {
+                    tracing::debug!("attempt {} of call failed: {}", attempt, cause);
+                    attempt += 1;
+                    recorder.retried();
+                    tokio::time::sleep(cause.retry_after().unwrap_or(delay)).await;
+                }
+                res => return res,
+            }
+        }
     }
 
     /// Bidi call to the server, request opens a stream, response is a stream
@@ -114,26 +430,137 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: ServerStreamingMsg<S>,
     {
+        let call = Call::start::<S, M>(Side::Client);
         let msg = msg.into();
-        let (mut send, recv) = self
-            .source
-            .open_bi()
-            .await
-            .map_err(StreamingResponseError::Open)?;
-        send.send(msg)
-            .map_err(StreamingResponseError::<C>::Send)
-            .await?;
-        let recv = recv.map(move |x| match x {
+        let payloads = Payloads::start::<S, M>(&msg);
+        let ctx = self.call_context::<M>();
+        let (send, recv) = context::bounded(ctx.as_ref(), async {
+            let (mut send, recv) = self
+                .source
+                .open_bi()
+                .await
+                .map_err(StreamingResponseError::Open)?;
+            send.send(msg)
+                .map_err(StreamingResponseError::<C>::Send)
+                .await?;
+            Ok((send, recv))
+        })
+        .instrument(call.span().clone())
+        .await
+        .unwrap_or_else(|cause| Err(StreamingResponseError::Cancelled(cause)))?;
+        let mut item_map = self.item_map::<M, M::Response>();
+        let f = move |x| match x {
             Ok(x) => {
-                M::Response::try_from(x).map_err(|_| StreamingResponseItemError::DowncastError)
+                payloads.response(&x);
+                M::Response::try_from(x)
+                    .map(|x| match item_map.as_mut() {
+                        Some(f) => f(x),
+                        None => x,
+                    })
+                    .map_err(|_| StreamingResponseItemError::DowncastError)
             }
             Err(e) => Err(StreamingResponseItemError::RecvError(e)),
+        };
+        #[cfg(feature = "stream-limits")]
+        let recv = Limited::new(
+            recv,
+            self.limits.tracker::<S, M>(StreamDirection::Responses),
+            f,
+            |cause| Err(StreamingResponseItemError::LimitExceeded(cause)),
+        );
+        #[cfg(not(feature = "stream-limits"))]
+        let recv = recv.map(f);
+        let recv = Bounded::new(recv, ctx, |cause| {
+            Err(StreamingResponseItemError::Cancelled(cause))
         });
-        // keep send alive so the request on the server side does not get cancelled
-        let recv = DeferDrop(recv, send).boxed();
+        let recv = Cooperative::new(recv, self.yield_budget);
+        // keep send alive so the request on the server side does not get cancelled,
+        // and the call alive so telemetry covers the entire stream
+        let recv = DeferDrop(recv, (send, call)).boxed();
         Ok(recv)
     }
 
+    /// Server streaming call that collects all responses
+    ///
+    /// If the call fails part way, the error is [Interrupted], with the
+    /// responses that were received until then. Without a way to continue the
+    /// stream, it is never [resumable](Interrupted::resumable), see
+    /// [RpcClient::server_streaming_collect_resumable].
+    pub async fn server_streaming_collect<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<Vec<M::Response>, Interrupted<M, M::Response, C>>
+    where
+        M: ServerStreamingMsg<S>,
+    {
+        self.collect_responses(msg, None).await
+    }
+
+    /// Server streaming call that collects all responses, and can be resumed
+    /// if it fails part way
+    ///
+    /// `advance` advances a request past a response, so that sending the
+    /// advanced request continues the stream right after that response, like
+    /// `Resumable::advance` of subscriptions. If the call fails on the way to
+    /// the server, the [Interrupted] error contains the request advanced past
+    /// all responses that were received, to resume the call with. Errors that
+    /// would happen again when resuming, e.g. responses that exceed the limits
+    /// of the stream, are not resumable.
+    pub async fn server_streaming_collect_resumable<M, F>(
+        &self,
+        msg: M,
+        mut advance: F,
+    ) -> result::Result<Vec<M::Response>, Interrupted<M, M::Response, C>>
+    where
+        M: ServerStreamingMsg<S> + Clone,
+        F: FnMut(&mut M, &M::Response) + Send,
+    {
+        let resume = msg.clone();
+        self.collect_responses(msg, Some((resume, &mut advance)))
+            .await
+    }
+
+    async fn collect_responses<M>(
+        &self,
+        msg: M,
+        mut resume: Option<(M, Advance<'_, M, M::Response>)>,
+    ) -> result::Result<Vec<M::Response>, Interrupted<M, M::Response, C>>
+    where
+        M: ServerStreamingMsg<S>,
+    {
+        let mut received = Vec::new();
+        let mut responses = match self.server_streaming(msg).await {
+            Ok(responses) => responses,
+            Err(cause) => {
+                let resume = resume.map(|(msg, _)| msg);
+                return Err(Interrupted::new(
+                    received,
+                    resume,
+                    InterruptedCause::Start(cause),
+                ));
+            }
+        };
+        while let Some(res) = responses.next().await {
+            match res {
+                Ok(res) => {
+                    if let Some((msg, advance)) = resume.as_mut() {
+                        advance(msg, &res);
+                    }
+                    received.push(res);
+                }
+                Err(cause) => {
+                    let resume = resume.map(|(msg, _)| msg);
+                    return Err(Interrupted::new(
+                        received,
+                        resume,
+                        InterruptedCause::Item(cause),
+                    ));
+                }
+            }
+        }
+        Ok(received)
+    }
+
     /// Call to the server that allows the client to stream, single response
     pub async fn client_streaming<M>(
         &self,
@@ -148,27 +575,41 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: ClientStreamingMsg<S>,
     {
+        let call = Call::start::<S, M>(Side::Client);
         let msg = msg.into();
-        let (mut send, mut recv) = self
-            .source
-            .open_bi()
-            .await
-            .map_err(ClientStreamingError::Open)?;
-        send.send(msg).map_err(ClientStreamingError::Send).await?;
+        let payloads = Payloads::start::<S, M>(&msg);
+        let ctx = self.call_context::<M>();
+        let (send, mut recv) = context::bounded(ctx.as_ref(), async {
+            let (mut send, recv) = self
+                .source
+                .open_bi()
+                .await
+                .map_err(ClientStreamingError::Open)?;
+            send.send(msg).map_err(ClientStreamingError::Send).await?;
+            Ok((send, recv))
+        })
+        .instrument(call.span().clone())
+        .await
+        .unwrap_or_else(|cause| Err(ClientStreamingError::Cancelled(cause)))?;
         let send = UpdateSink::<S, C, M::Update>(send, PhantomData);
+        let span = call.span().clone();
         let recv = async move {
-            let item = recv
-                .next()
+            // keep the call alive until we have the response
+            let _call = call;
+            let item = context::bounded(ctx.as_ref(), recv.next())
                 .await
+                .map_err(ClientStreamingItemError::Cancelled)?
                 .ok_or(ClientStreamingItemError::EarlyClose)?;
 
             match item {
                 Ok(x) => {
+                    payloads.response(&x);
                     M::Response::try_from(x).map_err(|_| ClientStreamingItemError::DowncastError)
                 }
                 Err(e) => Err(ClientStreamingItemError::RecvError(e)),
             }
         }
+        .instrument(span)
         .boxed();
         Ok((send, recv))
     }
@@ -187,20 +628,68 @@ impl<S: Service, C: ServiceConnection<S>> RpcClient<S, C> {
     where
         M: BidiStreamingMsg<S>,
     {
+        let call = Call::start::<S, M>(Side::Client);
         let msg = msg.into();
-        let (mut send, recv) = self.source.open_bi().await.map_err(BidiError::Open)?;
-        send.send(msg).await.map_err(BidiError::<C>::Send)?;
+        let payloads = Payloads::start::<S, M>(&msg);
+        let ctx = self.call_context::<M>();
+        let (send, recv) = context::bounded(ctx.as_ref(), async {
+            let (mut send, recv) = self.source.open_bi().await.map_err(BidiError::Open)?;
+            send.send(msg).await.map_err(BidiError::<C>::Send)?;
+            Ok((send, recv))
+        })
+        .instrument(call.span().clone())
+        .await
+        .unwrap_or_else(|cause| Err(BidiError::Cancelled(cause)))?;
         let send = UpdateSink(send, PhantomData);
-        let recv = recv
-            .map(|x| match x {
-                Ok(x) => M::Response::try_from(x).map_err(|_| BidiItemError::DowncastError),
-                Err(e) => Err(BidiItemError::RecvError(e)),
-            })
-            .boxed();
+        let mut item_map = self.item_map::<M, M::Response>();
+        let f = move |x| match x {
+            Ok(x) => {
+                payloads.response(&x);
+                M::Response::try_from(x)
+                    .map(|x| match item_map.as_mut() {
+                        Some(f) => f(x),
+                        None => x,
+                    })
+                    .map_err(|_| BidiItemError::DowncastError)
+            }
+            Err(e) => Err(BidiItemError::RecvError(e)),
+        };
+        #[cfg(feature = "stream-limits")]
+        let recv = Limited::new(
+            recv,
+            self.limits.tracker::<S, M>(StreamDirection::Responses),
+            f,
+            |cause| Err(BidiItemError::LimitExceeded(cause)),
+        );
+        #[cfg(not(feature = "stream-limits"))]
+        let recv = recv.map(f);
+        let recv = Bounded::new(recv, ctx, |cause| Err(BidiItemError::Cancelled(cause)));
+        let recv = Cooperative::new(recv, self.yield_budget);
+        // keep the call alive so telemetry covers the entire stream
+        let recv = DeferDrop(recv, call).boxed();
         Ok((send, recv))
     }
 }
 
+impl<S, C> RpcClient<S, C>
+where
+    S: Service,
+    C: ServiceConnection<S> + DatagramConnection<S::Res, S::Req>,
+{
+    /// Send a message to the server as an unreliable datagram
+    ///
+    /// There is no response, and the message can get lost on the way, see
+    /// [DatagramConnection]. The server receives it with
+    /// [RpcServer::accept_unreliable](crate::RpcServer::accept_unreliable).
+    /// Returns once the message is queued for sending.
+    pub fn notify_unreliable<M>(&self, msg: M) -> result::Result<(), C::SendError>
+    where
+        M: Into<S::Req>,
+    {
+        self.source.send_datagram(msg.into())
+    }
+}
+
 impl<S: Service, C: ServiceConnection<S>> AsRef<C> for RpcClient<S, C> {
     fn as_ref(&self) -> &C {
         &self.source
@@ -220,6 +709,9 @@ pub enum RpcClientError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<C: ConnectionErrors> fmt::Display for RpcClientError<C> {
@@ -230,6 +722,25 @@ impl<C: ConnectionErrors> fmt::Display for RpcClientError<C> {
 
 impl<C: ConnectionErrors> error::Error for RpcClientError<C> {}
 
+impl<C: ConnectionErrors> RpcClientError<C> {
+    /// How long the server asked to wait before retrying the call, if it
+    /// pushed back the call, see [pushback](crate::transport::pushback)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RecvError(cause) => C::retry_after(cause),
+            _ => None,
+        }
+    }
+
+    /// Whether the call failed on the way, so a retry might succeed
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Open(_) | Self::Send(_) | Self::EarlyClose | Self::RecvError(_)
+        )
+    }
+}
+
 /// Server error when accepting a bidi request
 #[derive(Debug)]
 pub enum BidiError<C: ConnectionErrors> {
@@ -237,6 +748,9 @@ pub enum BidiError<C: ConnectionErrors> {
     Open(C::OpenError),
     /// Unable to send the request to the server
     Send(C::SendError),
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<C: ConnectionErrors> fmt::Display for BidiError<C> {
@@ -254,6 +768,12 @@ pub enum BidiItemError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The response stream exceeded its limits, see [StreamLimits]
+    #[cfg(feature = "stream-limits")]
+    LimitExceeded(StreamLimitExceeded),
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<C: ConnectionErrors> fmt::Display for BidiItemError<C> {
@@ -264,6 +784,17 @@ impl<C: ConnectionErrors> fmt::Display for BidiItemError<C> {
 
 impl<C: ConnectionErrors> error::Error for BidiItemError<C> {}
 
+impl<C: ConnectionErrors> BidiItemError<C> {
+    /// How long the server asked to wait before retrying the call, if it
+    /// pushed back the call, see [pushback](crate::transport::pushback)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RecvError(cause) => C::retry_after(cause),
+            _ => None,
+        }
+    }
+}
+
 /// Server error when accepting a client streaming request
 #[derive(Debug)]
 pub enum ClientStreamingError<C: ConnectionErrors> {
@@ -271,6 +802,9 @@ pub enum ClientStreamingError<C: ConnectionErrors> {
     Open(C::OpenError),
     /// Unable to send the request to the server
     Send(C::SendError),
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<C: ConnectionErrors> fmt::Display for ClientStreamingError<C> {
@@ -290,6 +824,9 @@ pub enum ClientStreamingItemError<C: ConnectionErrors> {
     RecvError(C::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<C: ConnectionErrors> fmt::Display for ClientStreamingItemError<C> {
@@ -300,6 +837,17 @@ impl<C: ConnectionErrors> fmt::Display for ClientStreamingItemError<C> {
 
 impl<C: ConnectionErrors> error::Error for ClientStreamingItemError<C> {}
 
+impl<C: ConnectionErrors> ClientStreamingItemError<C> {
+    /// How long the server asked to wait before retrying the call, if it
+    /// pushed back the call, see [pushback](crate::transport::pushback)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RecvError(cause) => C::retry_after(cause),
+            _ => None,
+        }
+    }
+}
+
 /// Server error when accepting a server streaming request
 #[derive(Debug)]
 pub enum StreamingResponseError<C: ConnectionErrors> {
@@ -307,6 +855,9 @@ pub enum StreamingResponseError<C: ConnectionErrors> {
     Open(C::OpenError),
     /// Unable to send the request to the server
     Send(C::SendError),
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<S: ConnectionErrors> fmt::Display for StreamingResponseError<S> {
@@ -324,6 +875,12 @@ pub enum StreamingResponseItemError<S: ConnectionErrors> {
     RecvError(S::RecvError),
     /// Unexpected response from the server
     DowncastError,
+    /// The response stream exceeded its limits, see [StreamLimits]
+    #[cfg(feature = "stream-limits")]
+    LimitExceeded(StreamLimitExceeded),
+    /// The context the call was made in is done, see [RpcClient::with_context_propagation],
+    /// or the [timeout](crate::message::MethodPolicy::timeout) of the message has passed
+    Cancelled(CallCancelled),
 }
 
 impl<S: ConnectionErrors> fmt::Display for StreamingResponseItemError<S> {
@@ -334,6 +891,85 @@ impl<S: ConnectionErrors> fmt::Display for StreamingResponseItemError<S> {
 
 impl<S: ConnectionErrors> error::Error for StreamingResponseItemError<S> {}
 
+impl<S: ConnectionErrors> StreamingResponseItemError<S> {
+    /// How long the server asked to wait before retrying the call, if it
+    /// pushed back the call, see [pushback](crate::transport::pushback)
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::RecvError(cause) => S::retry_after(cause),
+            _ => None,
+        }
+    }
+}
+
+/// A server streaming call that failed part way, see [RpcClient::server_streaming_collect]
+///
+/// Only errors end a call early. Transports that can not tell a lost
+/// connection from the end of a stream, like the flume transport, end the
+/// stream instead, so the responses look complete.
+#[derive(Debug)]
+pub struct Interrupted<M, R, C: ConnectionErrors> {
+    /// The responses received before the call failed
+    pub received: Vec<R>,
+    /// The request that continues the call after the received responses, if
+    /// resuming it can succeed
+    pub resumable: Option<M>,
+    /// Why the call failed
+    pub cause: InterruptedCause<C>,
+}
+
+impl<M, R, C: ConnectionErrors> Interrupted<M, R, C> {
+    fn new(received: Vec<R>, resume: Option<M>, cause: InterruptedCause<C>) -> Self {
+        let resumable = resume.filter(|_| cause.is_transient());
+        Self {
+            received,
+            resumable,
+            cause,
+        }
+    }
+}
+
+impl<M, R, C: ConnectionErrors> fmt::Display for Interrupted<M, R, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "call interrupted after {} responses: {}",
+            self.received.len(),
+            self.cause
+        )
+    }
+}
+
+impl<M: Debug, R: Debug, C: ConnectionErrors> error::Error for Interrupted<M, R, C> {}
+
+/// Why a server streaming call was [Interrupted]
+#[derive(Debug)]
+pub enum InterruptedCause<C: ConnectionErrors> {
+    /// The call could not be started
+    Start(StreamingResponseError<C>),
+    /// Receiving a response failed
+    Item(StreamingResponseItemError<C>),
+}
+
+impl<C: ConnectionErrors> InterruptedCause<C> {
+    /// Whether the error is on the way to the server, so trying again can succeed
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Start(StreamingResponseError::Open(_) | StreamingResponseError::Send(_))
+                | Self::Item(StreamingResponseItemError::RecvError(_))
+        )
+    }
+}
+
+impl<C: ConnectionErrors> fmt::Display for InterruptedCause<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for InterruptedCause<C> {}
+
 /// Wrap a stream with an additional item that is kept alive until the stream is dropped
 #[pin_project]
 struct DeferDrop<S: Stream, X>(#[pin] S, X);
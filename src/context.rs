@@ -0,0 +1,310 @@
+//! Deadlines and cancellation of calls, propagated to nested calls
+//!
+//! The server runs every handler in a [CallContext]. The context has the
+//! deadline of [StreamingTimeouts::max_duration](crate::server::StreamingTimeouts::max_duration),
+//! if one applies to the call, and is cancelled once the call ends, because
+//! the handler finished, the client went away or the call timed out.
+//!
+//! Handlers that make further calls to other services can bound these calls by
+//! the inbound call. Calls of an [RpcClient](crate::RpcClient) created with
+//! [with_context_propagation](crate::RpcClient::with_context_propagation)
+//! that are made while a context is current fail with [CallCancelled] once the
+//! context is done, and close their substream so the downstream server stops
+//! working on them.
+//!
+//! The context is task local, so work that is spawned on another task needs to
+//! take it along. [CallContext::spawn] does this, and also ties the task to the
+//! call: the task is cancelled once the call ends, so background work started
+//! by a handler does not outlive the request, e.g. after the client stopped
+//! reading a response stream.
+//!
+//! ```ignore
+//! let ctx = CallContext::current().unwrap_or_default();
+//! let lookup = ctx.spawn(async move { downstream.rpc(Lookup(key)).await });
+//! ```
+//!
+//! Tasks that should outlive the call can still be spawned with
+//! [tokio::spawn], optionally in the context with [CallContext::scope].
+//!
+//! On transports with connections, the context also carries the
+//! [Extensions] of the connection the call came in on, see
+//! [CallContext::extensions].
+//!
+//! A context can also be started by hand, e.g. to bound all calls made on
+//! behalf of a single request of some other protocol:
+//!
+//! ```ignore
+//! let ctx = CallContext::new().with_timeout(Duration::from_secs(1));
+//! ctx.scope(handle(request)).await
+//! ```
+use crate::extensions::Extensions;
+use futures::{future::BoxFuture, FutureExt, Stream};
+use pin_project::pin_project;
+use std::{
+    error, fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Notify, task::JoinHandle};
+
+tokio::task_local! {
+    static CURRENT: CallContext;
+}
+
+/// The deadline and cancellation state of a call
+///
+/// Clones share the cancellation state, so cancelling a clone cancels the
+/// original and vice versa.
+#[derive(Debug, Clone, Default)]
+pub struct CallContext {
+    deadline: Option<Instant>,
+    cancel: Arc<Cancel>,
+    extensions: Option<Extensions>,
+}
+
+#[derive(Debug, Default)]
+struct Cancel {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CallContext {
+    /// Create a context without a deadline
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The context of the current task, if it has one
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(Clone::clone).ok()
+    }
+
+    /// Set the deadline, unless the context already has an earlier one
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(match self.deadline {
+            Some(existing) => existing.min(deadline),
+            None => deadline,
+        });
+        self
+    }
+
+    /// Set the deadline to `timeout` from now, unless the context already has
+    /// an earlier one
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_deadline(Instant::now() + timeout)
+    }
+
+    /// Set the extensions of the connection the call came in on
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// The deadline of the context
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The time until the deadline, zero if it passed
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// The extensions of the connection the call came in on
+    ///
+    /// This is `None` for contexts that were started by hand, and for calls on
+    /// transports without connections.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.extensions.as_ref()
+    }
+
+    /// Cancel the context
+    pub fn cancel(&self) {
+        self.cancel.cancelled.store(true, Ordering::SeqCst);
+        self.cancel.notify.notify_waiters();
+    }
+
+    /// Whether the context was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fail if the context was cancelled or its deadline passed
+    pub fn check(&self) -> Result<(), CallCancelled> {
+        if self.is_cancelled() {
+            return Err(CallCancelled::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if deadline <= Instant::now() => Err(CallCancelled::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until the context is cancelled or its deadline passes
+    pub async fn done(&self) -> CallCancelled {
+        let cancelled = async {
+            loop {
+                // register before checking, so a concurrent cancel is not missed
+                let notified = self.cancel.notify.notified();
+                if self.is_cancelled() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        match self.deadline {
+            Some(deadline) => tokio::select! {
+                biased;
+                _ = cancelled => CallCancelled::Cancelled,
+                _ = tokio::time::sleep_until(deadline.into()) => CallCancelled::DeadlineExceeded,
+            },
+            None => {
+                cancelled.await;
+                CallCancelled::Cancelled
+            }
+        }
+    }
+
+    /// Run `f` with this context as the current context
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    /// Spawn a task that runs in this context, and is cancelled once the
+    /// context is done
+    ///
+    /// The task yields [CallCancelled] if it was cancelled before it finished.
+    pub fn spawn<F>(&self, f: F) -> JoinHandle<Result<F::Output, CallCancelled>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let ctx = self.clone();
+        tokio::spawn(async move {
+            let work = ctx.clone().scope(f);
+            bounded(Some(&ctx), work).await
+        })
+    }
+}
+
+/// Cancels a context when dropped
+struct CancelOnDrop(CallContext);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Run a server call in a new context, which is cancelled once the call ends
+pub(crate) async fn serve<F: Future>(
+    timeout: Option<Duration>,
+    extensions: Option<Extensions>,
+    f: F,
+) -> F::Output {
+    let ctx = CallContext {
+        extensions,
+        ..CallContext::new()
+    };
+    let ctx = match timeout {
+        Some(timeout) => ctx.with_timeout(timeout),
+        None => ctx,
+    };
+    let _guard = CancelOnDrop(ctx.clone());
+    ctx.scope(f).await
+}
+
+/// The context that client calls are bounded by, if propagation is enabled
+pub(crate) fn outbound(propagate: bool) -> Option<CallContext> {
+    if propagate {
+        CallContext::current()
+    } else {
+        None
+    }
+}
+
+/// Run `f`, unless the context is done first
+pub(crate) async fn bounded<F: Future>(
+    ctx: Option<&CallContext>,
+    f: F,
+) -> Result<F::Output, CallCancelled> {
+    let ctx = match ctx {
+        Some(ctx) => ctx,
+        None => return Ok(f.await),
+    };
+    ctx.check()?;
+    tokio::select! {
+        biased;
+        cause = ctx.done() => Err(cause),
+        res = f => Ok(res),
+    }
+}
+
+/// A response stream that ends with an error item once the context is done
+#[pin_project]
+pub(crate) struct Bounded<St, E> {
+    #[pin]
+    inner: St,
+    done: Option<BoxFuture<'static, CallCancelled>>,
+    cancelled: Option<E>,
+}
+
+impl<St, E> Bounded<St, E> {
+    pub(crate) fn new(inner: St, ctx: Option<CallContext>, cancelled: E) -> Self {
+        let done = ctx.map(|ctx| async move { ctx.done().await }.boxed());
+        Self {
+            inner,
+            done,
+            cancelled: Some(cancelled),
+        }
+    }
+}
+
+impl<St, E> Stream for Bounded<St, E>
+where
+    St: Stream,
+    E: FnOnce(CallCancelled) -> St::Item,
+{
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(done) = this.done.as_mut() {
+            if let Poll::Ready(cause) = done.poll_unpin(cx) {
+                *this.done = None;
+                return Poll::Ready(this.cancelled.take().map(|f| f(cause)));
+            }
+        }
+        if this.cancelled.is_none() {
+            return Poll::Ready(None);
+        }
+        this.inner.poll_next(cx)
+    }
+}
+
+/// A call was aborted because the context it was made in is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallCancelled {
+    /// The deadline of the context passed
+    DeadlineExceeded,
+    /// The context was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for CallCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallCancelled::DeadlineExceeded => write!(f, "deadline of the call exceeded"),
+            CallCancelled::Cancelled => write!(f, "call cancelled"),
+        }
+    }
+}
+
+impl error::Error for CallCancelled {}
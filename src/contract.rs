@@ -0,0 +1,491 @@
+//! Contracts between clients and servers, checked against either side
+//!
+//! A [Contract] is a script of interactions: the request of a call, the
+//! updates the client sends and the responses the server sends back. The same
+//! contract can be checked against a real server with [Contract::verify], and
+//! against a client with a [ContractMock], so teams that develop a client and a
+//! server separately can both check their side against a shared script.
+//!
+//! Contracts can be serialized, so they can be checked in as JSON or YAML
+//! files and loaded with any serde format, for example:
+//!
+//! ```ignore
+//! let contract: Contract<StoreService> = serde_json::from_str(r#"{
+//!     "interactions": [{
+//!         "name": "get a known key",
+//!         "pattern": "rpc",
+//!         "request": { "Get": "a" },
+//!         "responses": [{ "Value": 1 }]
+//!     }]
+//! }"#)?;
+//! // on the server side
+//! contract.verify(&connection).await?;
+//! // on the client side
+//! let mock = ContractMock::new(contract);
+//! let client = RpcClient::new(mock.connection());
+//! // ... use the client ...
+//! mock.verify().await?;
+//! ```
+//!
+//! Messages are compared with [PartialEq], so they have to match exactly.
+use crate::{
+    offline::{Fixtures, OfflineConnection, Responses, Updates},
+    Service, ServiceConnection,
+};
+use futures::{
+    channel::oneshot,
+    future::{self, join},
+    stream, SinkExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    error, fmt, mem,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinHandle;
+
+/// The interaction pattern of a call in a [Contract]
+///
+/// This decides when the client closes its side of the call: after the
+/// updates for client and bidi streaming, and once all responses are received
+/// otherwise, since closing an rpc or server streaming call cancels it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern {
+    /// A single request and a single response
+    Rpc,
+    /// A single request and a stream of responses
+    ServerStreaming,
+    /// A stream of updates and a single response
+    ClientStreaming,
+    /// A stream of updates and a stream of responses
+    BidiStreaming,
+}
+
+impl Pattern {
+    fn closes_updates(self) -> bool {
+        matches!(self, Self::ClientStreaming | Self::BidiStreaming)
+    }
+}
+
+/// A single call of a [Contract]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Interaction<S: Service> {
+    /// The name of the interaction, used in reports
+    pub name: String,
+    /// The interaction pattern of the call
+    pub pattern: Pattern,
+    /// The first request of the call
+    pub request: S::Req,
+    /// The updates the client sends after the first request
+    #[serde(default)]
+    pub updates: Vec<S::Req>,
+    /// The responses the server sends, before it closes the call
+    #[serde(default)]
+    pub responses: Vec<S::Res>,
+}
+
+impl<S: Service> Interaction<S> {
+    /// Create a new interaction without updates or responses
+    pub fn new(name: impl Into<String>, pattern: Pattern, request: impl Into<S::Req>) -> Self {
+        Self {
+            name: name.into(),
+            pattern,
+            request: request.into(),
+            updates: Vec::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    /// Add an update the client sends
+    pub fn update(mut self, update: impl Into<S::Req>) -> Self {
+        self.updates.push(update.into());
+        self
+    }
+
+    /// Add a response the server sends
+    pub fn response(mut self, response: impl Into<S::Res>) -> Self {
+        self.responses.push(response.into());
+        self
+    }
+}
+
+/// A script of interactions between a client and a server
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Contract<S: Service> {
+    /// The interactions, in the order they are verified against a server
+    pub interactions: Vec<Interaction<S>>,
+}
+
+impl<S: Service> Default for Contract<S> {
+    fn default() -> Self {
+        Self {
+            interactions: Vec::new(),
+        }
+    }
+}
+
+impl<S: Service> Contract<S> {
+    /// Create a new contract from its interactions
+    pub fn new(interactions: impl IntoIterator<Item = Interaction<S>>) -> Self {
+        Self {
+            interactions: interactions.into_iter().collect(),
+        }
+    }
+
+    /// Add an interaction
+    pub fn interaction(mut self, interaction: Interaction<S>) -> Self {
+        self.interactions.push(interaction);
+        self
+    }
+}
+
+impl<S> Contract<S>
+where
+    S: Service,
+    S::Req: Clone,
+    S::Res: PartialEq + Clone,
+{
+    /// Make the calls of all interactions through `connection`, in order, and
+    /// check the responses of the server
+    ///
+    /// All interactions are made, even if some of them fail. A server that
+    /// neither responds nor closes a call keeps this waiting, so wrap it in a
+    /// timeout when that is a concern.
+    pub async fn verify<C: ServiceConnection<S>>(
+        &self,
+        connection: &C,
+    ) -> Result<(), Violations<S>> {
+        let mut violations = Vec::new();
+        for interaction in &self.interactions {
+            if let Err(violation) = call(interaction, connection).await {
+                violations.push(violation);
+            }
+        }
+        Violations::check(violations)
+    }
+}
+
+/// Make the call of an interaction and compare its responses
+async fn call<S, C>(interaction: &Interaction<S>, connection: &C) -> Result<(), Violation<S>>
+where
+    S: Service,
+    S::Req: Clone,
+    S::Res: PartialEq + Clone,
+    C: ServiceConnection<S>,
+{
+    let failed = |cause: String| Violation::Failed {
+        interaction: interaction.name.clone(),
+        cause,
+    };
+    let (mut send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|cause| failed(format!("open: {cause:?}")))?;
+    let send = async move {
+        send.send(interaction.request.clone()).await?;
+        for update in &interaction.updates {
+            send.send(update.clone()).await?;
+        }
+        if interaction.pattern.closes_updates() {
+            // not all sinks end the stream of the server when closed
+            send.close().await?;
+            return Ok(None);
+        }
+        Ok::<_, C::SendError>(Some(send))
+    };
+    // read responses while sending, so a server that answers every update
+    // can not get stuck on a full channel
+    let (send, responses) = join(send, recv.collect::<Vec<_>>()).await;
+    // rpc and server streaming calls were kept open until all responses were in
+    let send = send.map_err(|cause| failed(format!("send: {cause:?}")))?;
+    drop(send);
+    let responses = responses
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|cause| failed(format!("receive: {cause:?}")))?;
+    match Diff::first(&interaction.responses, responses) {
+        Some(diff) => Err(Violation::Responses {
+            interaction: interaction.name.clone(),
+            diff,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Answers calls as scripted by a [Contract], and checks the calls of a client
+///
+/// Calls are answered by the first interaction with an equal request that was
+/// not called yet, or the first interaction with an equal request if all of
+/// these were called. Calls with other requests are answered without any
+/// responses, so the client sees them as closed early.
+///
+/// Cloning the mock gives another handle to the same calls.
+pub struct ContractMock<S: Service> {
+    inner: Arc<MockInner<S>>,
+}
+
+struct MockInner<S: Service> {
+    contract: Contract<S>,
+    called: Mutex<Vec<bool>>,
+    violations: Mutex<Vec<Violation<S>>>,
+    checks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl<S: Service> ContractMock<S> {
+    /// Create a new mock that answers calls as scripted by `contract`
+    pub fn new(contract: Contract<S>) -> Self {
+        let called = vec![false; contract.interactions.len()];
+        Self {
+            inner: Arc::new(MockInner {
+                contract,
+                called: Mutex::new(called),
+                violations: Default::default(),
+                checks: Default::default(),
+            }),
+        }
+    }
+
+    /// The contract the calls are checked against
+    pub fn contract(&self) -> &Contract<S> {
+        &self.inner.contract
+    }
+}
+
+impl<S> ContractMock<S>
+where
+    S: Service,
+    S::Req: PartialEq + Clone,
+    S::Res: Clone,
+{
+    /// A connection for a client, with calls answered by this mock
+    pub fn connection(&self) -> OfflineConnection<S> {
+        OfflineConnection::new(self.clone())
+    }
+
+    /// Check the calls made so far, and that every interaction was called
+    ///
+    /// This waits until the client closed its side of every call, to compare
+    /// all updates. The calls checked so far are forgotten, so the mock can be
+    /// checked again after more calls.
+    pub async fn verify(&self) -> Result<(), Violations<S>> {
+        let checks = mem::take(&mut *self.inner.checks.lock().unwrap());
+        for check in checks {
+            check.await.ok();
+        }
+        let mut violations = mem::take(&mut *self.inner.violations.lock().unwrap());
+        let called = mem::replace(
+            &mut *self.inner.called.lock().unwrap(),
+            vec![false; self.inner.contract.interactions.len()],
+        );
+        for (interaction, called) in self.inner.contract.interactions.iter().zip(called) {
+            if !called {
+                violations.push(Violation::NotCalled {
+                    interaction: interaction.name.clone(),
+                });
+            }
+        }
+        Violations::check(violations)
+    }
+}
+
+impl<S: Service> MockInner<S>
+where
+    S::Req: PartialEq,
+{
+    /// Find the interaction for a call and mark it as called
+    fn find(&self, req: &S::Req) -> Option<usize> {
+        let interactions = &self.contract.interactions;
+        let mut called = self.called.lock().unwrap();
+        let matching = |index: &usize| interactions[*index].request == *req;
+        let index = (0..interactions.len())
+            .filter(matching)
+            .find(|index| !called[*index])
+            .or_else(|| (0..interactions.len()).find(matching))?;
+        called[index] = true;
+        Some(index)
+    }
+}
+
+impl<S: Service> Clone for ContractMock<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for ContractMock<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContractMock")
+            .field("interactions", &self.inner.contract.interactions.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Fixtures<S> for ContractMock<S>
+where
+    S: Service,
+    S::Req: PartialEq + Clone,
+    S::Res: Clone,
+{
+    fn call(&self, req: S::Req, updates: Updates<S>) -> Responses<S> {
+        let index = match self.inner.find(&req) {
+            Some(index) => index,
+            None => {
+                self.inner
+                    .violations
+                    .lock()
+                    .unwrap()
+                    .push(Violation::UnknownRequest(req));
+                // keep accepting updates, so the client does not fail sending them
+                tokio::spawn(updates.for_each(|_| future::ready(())));
+                return stream::empty().boxed();
+            }
+        };
+        let inner = self.inner.clone();
+        let (updates_done, done) = oneshot::channel();
+        let check = tokio::spawn(async move {
+            let updates = updates.collect::<Vec<_>>().await;
+            let interaction = &inner.contract.interactions[index];
+            if let Some(diff) = Diff::first(&interaction.updates, updates) {
+                inner.violations.lock().unwrap().push(Violation::Updates {
+                    interaction: interaction.name.clone(),
+                    diff,
+                });
+            }
+            updates_done.send(()).ok();
+        });
+        self.inner.checks.lock().unwrap().push(check);
+        let interaction = &self.inner.contract.interactions[index];
+        let responses = stream::iter(interaction.responses.clone());
+        if interaction.pattern == Pattern::ClientStreaming {
+            // the single response comes once the client is done sending
+            stream::once(done)
+                .flat_map(move |_| responses.clone())
+                .boxed()
+        } else {
+            responses.boxed()
+        }
+    }
+}
+
+/// The first difference between the expected and the actual messages of a call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diff<T> {
+    /// The position of the message in the call
+    pub index: usize,
+    /// The expected message, or `None` if there are more messages than expected
+    pub expected: Option<T>,
+    /// The actual message, or `None` if there are fewer messages than expected
+    pub actual: Option<T>,
+}
+
+impl<T: PartialEq + Clone> Diff<T> {
+    fn first(expected: &[T], actual: Vec<T>) -> Option<Self> {
+        let len = expected.len().max(actual.len());
+        let mut actual = actual.into_iter();
+        (0..len).find_map(|index| {
+            let expected = expected.get(index);
+            let actual = actual.next();
+            if expected == actual.as_ref() {
+                return None;
+            }
+            Some(Self {
+                index,
+                expected: expected.cloned(),
+                actual,
+            })
+        })
+    }
+}
+
+impl<T: fmt::Debug> fmt::Display for Diff<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.expected, &self.actual) {
+            (Some(expected), Some(actual)) => write!(
+                f,
+                "message {} is {actual:?}, expected {expected:?}",
+                self.index
+            ),
+            (Some(expected), None) => write!(f, "message {} is missing: {expected:?}", self.index),
+            (None, Some(actual)) => write!(f, "message {} is unexpected: {actual:?}", self.index),
+            (None, None) => write!(f, "message {} matches", self.index),
+        }
+    }
+}
+
+/// A way in which a client or a server does not keep a [Contract]
+#[derive(Debug)]
+pub enum Violation<S: Service> {
+    /// A call with a request that is not part of the contract
+    UnknownRequest(S::Req),
+    /// An interaction that was never called
+    NotCalled {
+        /// The name of the interaction
+        interaction: String,
+    },
+    /// The call of an interaction failed
+    Failed {
+        /// The name of the interaction
+        interaction: String,
+        /// A description of the error
+        cause: String,
+    },
+    /// The client sent other updates than scripted
+    Updates {
+        /// The name of the interaction
+        interaction: String,
+        /// The first update that differs
+        diff: Diff<S::Req>,
+    },
+    /// The server sent other responses than scripted
+    Responses {
+        /// The name of the interaction
+        interaction: String,
+        /// The first response that differs
+        diff: Diff<S::Res>,
+    },
+}
+
+impl<S: Service> fmt::Display for Violation<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownRequest(req) => write!(f, "unknown request {req:?}"),
+            Self::NotCalled { interaction } => write!(f, "{interaction}: not called"),
+            Self::Failed { interaction, cause } => write!(f, "{interaction}: failed to {cause}"),
+            Self::Updates { interaction, diff } => write!(f, "{interaction}: update {diff}"),
+            Self::Responses { interaction, diff } => write!(f, "{interaction}: response {diff}"),
+        }
+    }
+}
+
+/// All the ways in which a client or a server does not keep a [Contract]
+///
+/// This is never empty.
+#[derive(Debug)]
+pub struct Violations<S: Service>(pub Vec<Violation<S>>);
+
+impl<S: Service> Violations<S> {
+    fn check(violations: Vec<Violation<S>>) -> Result<(), Self> {
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Self(violations))
+        }
+    }
+}
+
+impl<S: Service> fmt::Display for Violations<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} contract violations", self.0.len())?;
+        for violation in &self.0 {
+            write!(f, "\n  {violation}")?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Service> error::Error for Violations<S> {}
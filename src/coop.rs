@@ -0,0 +1,94 @@
+//! Cooperative yielding for hot streaming loops
+//!
+//! Flume channels and most of the streams we forward do not participate in the
+//! tokio coop budget, so a stream that is always ready can keep a task busy
+//! forever. [Budget] counts consecutive ready items and forces a yield to the
+//! runtime once the budget is used up.
+use futures::{future::poll_fn, Stream};
+use pin_project::pin_project;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Default number of items that are processed before yielding to the runtime.
+///
+/// This is the same value tokio uses for its own coop budget.
+pub const DEFAULT_YIELD_BUDGET: usize = 128;
+
+/// Number of items that can be processed without yielding
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Budget {
+    initial: usize,
+    remaining: usize,
+}
+
+impl Budget {
+    /// Create a new budget. A budget of 0 disables yielding.
+    pub(crate) fn new(initial: usize) -> Self {
+        Self {
+            initial,
+            remaining: initial,
+        }
+    }
+
+    /// Consume one unit of budget, returning pending once it is exhausted.
+    ///
+    /// When returning pending, the budget is reset and the task is woken
+    /// immediately, so other tasks get a chance to run before we continue.
+    pub(crate) fn poll_proceed(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.initial == 0 {
+            return Poll::Ready(());
+        }
+        if self.remaining == 0 {
+            self.remaining = self.initial;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.remaining -= 1;
+        Poll::Ready(())
+    }
+
+    /// Reset the budget, e.g. after the task yielded for other reasons
+    pub(crate) fn reset(&mut self) {
+        self.remaining = self.initial;
+    }
+
+    /// Async version of [Budget::poll_proceed]
+    pub(crate) async fn proceed(&mut self) {
+        poll_fn(|cx| self.poll_proceed(cx)).await
+    }
+}
+
+/// A stream wrapper that yields to the runtime after a number of ready items
+#[pin_project]
+#[derive(Debug)]
+pub(crate) struct Cooperative<S> {
+    #[pin]
+    inner: S,
+    budget: Budget,
+}
+
+impl<S> Cooperative<S> {
+    pub(crate) fn new(inner: S, budget: usize) -> Self {
+        Self {
+            inner,
+            budget: Budget::new(budget),
+        }
+    }
+}
+
+impl<S: Stream> Stream for Cooperative<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        futures::ready!(this.budget.poll_proceed(cx));
+        let res = this.inner.poll_next(cx);
+        if res.is_pending() {
+            // we are yielding anyway, so start with a fresh budget next time
+            this.budget.reset();
+        }
+        res
+    }
+}
@@ -0,0 +1,144 @@
+//! Discovery of the endpoints of a service
+//!
+//! Client side features that talk to more than one endpoint, like the
+//! [ShardedClient](crate::sharded::ShardedClient) or a reconnecting quinn
+//! connection, get their endpoints from a [Discovery]. This keeps endpoint
+//! management the same for all of them, whether the endpoints are a static
+//! list, come from DNS or from a service registry like etcd or consul.
+//!
+//! [StaticDiscovery] is a list of endpoints that can be changed at runtime, and
+//! [DnsDiscovery] polls the addresses a hostname resolves to. Other sources,
+//! including DNS SRV records, can be plugged in by implementing [Discovery].
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use std::{fmt::Debug, hash::Hash, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+/// A source of the endpoints of a service
+pub trait Discovery: Debug + Send + Sync + 'static {
+    /// The address of an endpoint, e.g. a [SocketAddr]
+    type Endpoint: Debug + Clone + Eq + Hash + Send + Sync + 'static;
+
+    /// The current endpoints
+    fn endpoints(&self) -> BoxFuture<'static, io::Result<Vec<Self::Endpoint>>>;
+
+    /// Watch the endpoints for changes
+    ///
+    /// The stream yields the current endpoints first, and then the complete new
+    /// set of endpoints every time it changes. Errors are yielded as they happen
+    /// and do not end the stream.
+    fn watch(&self) -> BoxStream<'static, io::Result<Vec<Self::Endpoint>>>;
+}
+
+/// A [Discovery] for a list of endpoints that is maintained by hand
+///
+/// Clones share the same list, so the list can be updated with
+/// [StaticDiscovery::set] after the discovery was handed out.
+#[derive(Debug, Clone)]
+pub struct StaticDiscovery<E>(Arc<watch::Sender<Vec<E>>>);
+
+impl<E> StaticDiscovery<E> {
+    /// Create a discovery for the given endpoints
+    pub fn new(endpoints: Vec<E>) -> Self {
+        Self(Arc::new(watch::channel(endpoints).0))
+    }
+
+    /// Replace the endpoints, notifying all watchers
+    pub fn set(&self, endpoints: Vec<E>) {
+        self.0.send_replace(endpoints);
+    }
+}
+
+impl<E: Debug + Clone + Eq + Hash + Send + Sync + 'static> Discovery for StaticDiscovery<E> {
+    type Endpoint = E;
+
+    fn endpoints(&self) -> BoxFuture<'static, io::Result<Vec<E>>> {
+        futures::future::ok(self.0.borrow().clone()).boxed()
+    }
+
+    fn watch(&self) -> BoxStream<'static, io::Result<Vec<E>>> {
+        let rx = self.0.subscribe();
+        stream::unfold((rx, true), |(mut rx, first)| async move {
+            // the stream ends once the last clone of the discovery is dropped
+            if !first {
+                rx.changed().await.ok()?;
+            }
+            let endpoints = rx.borrow_and_update().clone();
+            Some((Ok(endpoints), (rx, false)))
+        })
+        .boxed()
+    }
+}
+
+/// A [Discovery] for the addresses a hostname resolves to
+///
+/// Uses the resolver of the operating system, and polls it at a fixed
+/// interval to watch for changes.
+#[derive(Debug, Clone)]
+pub struct DnsDiscovery {
+    host: String,
+    port: u16,
+    interval: Duration,
+}
+
+/// Default interval at which [DnsDiscovery] resolves the hostname again
+pub const DEFAULT_DNS_INTERVAL: Duration = Duration::from_secs(30);
+
+impl DnsDiscovery {
+    /// Create a discovery for the addresses `host` resolves to, with `port`
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            interval: DEFAULT_DNS_INTERVAL,
+        }
+    }
+
+    /// Set the interval at which the hostname is resolved while watching
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+async fn lookup(host: String, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = tokio::net::lookup_host((host.as_str(), port))
+        .await?
+        .collect::<Vec<_>>();
+    // the resolver may return the addresses in any order
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+impl Discovery for DnsDiscovery {
+    type Endpoint = SocketAddr;
+
+    fn endpoints(&self) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        lookup(self.host.clone(), self.port).boxed()
+    }
+
+    fn watch(&self) -> BoxStream<'static, io::Result<Vec<SocketAddr>>> {
+        let this = self.clone();
+        stream::unfold((this, None, true), |(this, mut last, first)| async move {
+            if !first {
+                tokio::time::sleep(this.interval).await;
+            }
+            loop {
+                match lookup(this.host.clone(), this.port).await {
+                    Ok(addrs) if last.as_ref() == Some(&addrs) => {}
+                    Ok(addrs) => {
+                        last = Some(addrs.clone());
+                        return Some((Ok(addrs), (this, last, false)));
+                    }
+                    Err(cause) => return Some((Err(cause), (this, last, false))),
+                }
+                tokio::time::sleep(this.interval).await;
+            }
+        })
+        .boxed()
+    }
+}
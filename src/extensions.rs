@@ -0,0 +1,128 @@
+//! State of an application that is attached to a connection
+//!
+//! Transports that accept connections give every connection its own
+//! [Extensions], a map with at most one value of every type. It is shared by
+//! all calls on the connection, so a check that looks at the first request of
+//! a call can leave its results for the handlers of later calls, e.g. the
+//! principal of an authenticated client, or the counters of a rate limiter:
+//!
+//! ```ignore
+//! let pending = server.accept_pending().await?;
+//! if let Some(extensions) = pending.extensions() {
+//!     if let Request::Login(login) = pending.request() {
+//!         extensions.insert(Principal(login.user.clone()));
+//!     }
+//! }
+//! let (req, chan) = pending.accept();
+//! // later, in a handler of another call on the same connection
+//! let principal = CallContext::current()
+//!     .and_then(|ctx| ctx.extensions()?.get::<Principal>());
+//! ```
+//!
+//! Values are looked up by their type, so applications should use their own
+//! types instead of e.g. a plain `String`. The extensions of a connection are
+//! dropped once the connection and all of its calls are gone.
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+type Map = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// A map of values keyed by their type, shared by all calls of a connection
+///
+/// Clones share the values, so a value inserted into a clone can be read from
+/// the original and vice versa.
+#[derive(Clone, Default)]
+pub struct Extensions(Arc<Mutex<Map>>);
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Extensions {
+    /// Create a new, empty map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Insert a value, and return the previous value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(downcast)
+    }
+
+    /// A clone of the value of type `T`, if there is one
+    ///
+    /// Values that are expensive to clone or that are changed by several calls
+    /// can be stored in an [Arc], or changed in place with [Extensions::update].
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// A clone of the value of type `T`, inserting the result of `f` first if
+    /// there is none
+    pub fn get_or_insert_with<T, F>(&self, f: F) -> T
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce() -> T,
+    {
+        let mut map = self.0.lock().unwrap();
+        let value = map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()));
+        value
+            .downcast_ref::<T>()
+            .expect("values are keyed by their type")
+            .clone()
+    }
+
+    /// Change the value of type `T` in place, if there is one, and return the
+    /// result of `f`
+    ///
+    /// The map is locked while `f` runs, so `f` must not use the map itself.
+    pub fn update<T, F, R>(&self, f: F) -> Option<R>
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        self.0
+            .lock()
+            .unwrap()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut::<T>())
+            .map(f)
+    }
+
+    /// Remove the value of type `T`, and return it
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(downcast)
+    }
+
+    /// Whether there is a value of type `T`
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.0.lock().unwrap().contains_key(&TypeId::of::<T>())
+    }
+}
+
+fn downcast<T: 'static>(value: Box<dyn Any + Send + Sync>) -> Option<T> {
+    value.downcast::<T>().ok().map(|value| *value)
+}
@@ -10,6 +10,24 @@
 //!
 //! See the [README](https://github.com/n0-computer/quic-rpc/blob/main/README.md)
 //!
+//! # Telemetry
+//!
+//! All calls are instrumented with [tracing] spans named `rpc` that carry the
+//! OpenTelemetry semantic convention fields `rpc.system`, `rpc.service` and
+//! `rpc.method`, so they can be exported via OTLP using `tracing-opentelemetry`.
+//!
+//! With the `opentelemetry-metrics` feature, call durations are recorded in the
+//! `rpc.client.duration` and `rpc.server.duration` histograms of the global
+//! OpenTelemetry meter provider.
+//!
+//! With the `openmetrics` feature, the same metrics are collected in an
+//! in-process registry and can be rendered in the OpenMetrics text format, see
+//! the `metrics` module. The hyper transport can serve them on `/metrics`.
+//!
+//! With the `payload-sampling` feature, the payloads of a configurable fraction
+//! of client calls are recorded for debugging, see the `sampling` module. The
+//! hyper transport can serve them on `/debug/samples`.
+//!
 //! # Example
 //! ```
 //! # async fn example() -> anyhow::Result<()> {
@@ -94,15 +112,61 @@
 use serde::{de::DeserializeOwned, Serialize};
 use std::fmt::{Debug, Display};
 use transport::{Connection, ServerEndpoint};
+#[cfg(feature = "alloc-counters")]
+pub mod allocations;
+pub mod audit;
+pub mod blocking;
+pub mod bulk;
+#[cfg(feature = "response-cache")]
+pub mod cache;
+pub mod channels;
 pub mod client;
+pub mod context;
+#[cfg(feature = "contract-testing")]
+pub mod contract;
+mod coop;
+pub mod discovery;
+pub mod extensions;
+#[cfg(feature = "stream-limits")]
+pub mod limits;
 pub mod message;
+#[cfg(feature = "openmetrics")]
+pub mod metrics;
+#[cfg(feature = "offline")]
+pub mod offline;
+pub mod push;
+pub mod query;
+#[cfg(feature = "payload-sampling")]
+pub mod sampling;
 pub mod server;
+pub mod sharded;
+pub mod strict;
+#[cfg(feature = "session-persistence")]
+pub mod subscription;
+mod telemetry;
+pub mod testing;
+pub mod topics;
 pub mod transport;
+pub mod upload;
+pub mod validation;
+pub mod versions;
 pub use client::RpcClient;
+pub use coop::DEFAULT_YIELD_BUDGET;
 pub use server::RpcServer;
 #[cfg(feature = "macros")]
 mod macros;
 
+/// Support items for the code generated by the macros, not a public API
+#[cfg(feature = "macros")]
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::macros::{
+        check_pinned, doc_text, manifest, parse_duration, parse_size, tag, ConvertHandlerDropped,
+        HandlerDroppedProbe, IntoInner, NoHandlerDropped, TagSeed, Variant, VariantRepr,
+        DEFAULT_MAX_VARIANT_SIZE,
+    };
+}
+
 /// Requirements for a RPC message
 ///
 /// Even when just using the mem transport, we require messages to be Serializable and Deserializable.
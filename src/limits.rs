@@ -0,0 +1,242 @@
+//! Limits on the length of streams
+//!
+//! A streaming call can produce items for as long as the producer wants, e.g.
+//! a server streaming call that computes an unbounded sequence. [StreamLimits]
+//! bound the number of items, the number of bytes and the duration of the
+//! streams of a call, separately for the updates sent by the client and the
+//! responses sent by the server.
+//!
+//! Limits are configured per message type, with a default for all others,
+//! using [RpcServer::with_method_stream_limits](crate::RpcServer::with_method_stream_limits)
+//! and [RpcClient::with_method_stream_limits](crate::RpcClient::with_method_stream_limits).
+//! The server enforces them on the updates it receives and the responses it
+//! sends, failing the call with
+//! [RpcServerError::StreamLimit](crate::server::RpcServerError::StreamLimit).
+//! The client enforces them on the responses it receives, ending the response
+//! stream with an error item.
+//!
+//! Limits are checked whenever an item is sent or received, so
+//! [StreamLimits::max_duration] ends streams that keep producing items, but
+//! not streams that stall. Use
+//! [StreamingTimeouts](crate::server::StreamingTimeouts) for those.
+use crate::{message::Msg, telemetry::method_name, Service};
+use futures::Stream;
+use pin_project::pin_project;
+use serde::Serialize;
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    error, fmt,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Limits for a single stream of a call
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamLimits {
+    /// Maximum number of items
+    pub max_items: Option<u64>,
+    /// Maximum total size of the items, in bytes of their bincode encoding
+    pub max_bytes: Option<u64>,
+    /// Maximum time from the start of the call until the last item
+    pub max_duration: Option<Duration>,
+}
+
+impl StreamLimits {
+    fn is_unlimited(&self) -> bool {
+        self.max_items.is_none() && self.max_bytes.is_none() && self.max_duration.is_none()
+    }
+}
+
+/// The stream of a call that exceeded a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamDirection {
+    /// The updates sent by the client
+    Updates,
+    /// The responses sent by the server
+    Responses,
+}
+
+/// The limit of [StreamLimits] that a stream exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// [StreamLimits::max_items] was exceeded
+    Items,
+    /// [StreamLimits::max_bytes] was exceeded
+    Bytes,
+    /// [StreamLimits::max_duration] was exceeded
+    Duration,
+}
+
+/// Information about a stream that exceeded its limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamLimitExceeded {
+    /// Type name of the service
+    pub service: &'static str,
+    /// Name of the message type of the request
+    pub method: &'static str,
+    /// The stream that exceeded the limit
+    pub direction: StreamDirection,
+    /// The limit that was exceeded
+    pub kind: LimitKind,
+}
+
+impl fmt::Display for StreamLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            StreamDirection::Updates => "updates",
+            StreamDirection::Responses => "responses",
+        };
+        let limit = match self.kind {
+            LimitKind::Items => "maximum number of items",
+            LimitKind::Bytes => "maximum number of bytes",
+            LimitKind::Duration => "maximum duration",
+        };
+        write!(f, "{} of {} exceeded the {}", direction, self.method, limit)
+    }
+}
+
+impl error::Error for StreamLimitExceeded {}
+
+/// Stream limits, by message type
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MethodLimits {
+    pub(crate) default: StreamLimits,
+    pub(crate) methods: HashMap<TypeId, StreamLimits>,
+}
+
+impl MethodLimits {
+    /// Start tracking a stream of a call of message type `M` on service `S`
+    ///
+    /// Returns `None` if the stream is not limited.
+    ///
+    /// Without limits for `M`, the [max_size](crate::message::MethodPolicy::max_size)
+    /// of the policy of `M` takes precedence over the default maximum number of
+    /// bytes.
+    pub(crate) fn tracker<S: Service, M: Msg<S>>(
+        &self,
+        direction: StreamDirection,
+    ) -> Option<Tracker> {
+        let limits = self
+            .methods
+            .get(&TypeId::of::<M>())
+            .copied()
+            .unwrap_or(StreamLimits {
+                max_bytes: M::POLICY.max_size.or(self.default.max_bytes),
+                ..self.default
+            });
+        if limits.is_unlimited() {
+            return None;
+        }
+        Some(Tracker {
+            limits,
+            started: Instant::now(),
+            items: 0,
+            bytes: 0,
+            exceeded: StreamLimitExceeded {
+                service: type_name::<S>(),
+                method: method_name::<M>(),
+                direction,
+                kind: LimitKind::Items,
+            },
+        })
+    }
+}
+
+/// Tracks the items of a single limited stream
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    limits: StreamLimits,
+    started: Instant,
+    items: u64,
+    bytes: u64,
+    exceeded: StreamLimitExceeded,
+}
+
+impl Tracker {
+    /// Account for an item of the stream, failing if it exceeds a limit
+    pub(crate) fn item(&mut self, item: &impl Serialize) -> Result<(), StreamLimitExceeded> {
+        self.items += 1;
+        if matches!(self.limits.max_items, Some(max) if self.items > max) {
+            return Err(self.exceeded(LimitKind::Items));
+        }
+        if let Some(max) = self.limits.max_bytes {
+            // items that can not be serialized will fail to be sent anyway
+            self.bytes += bincode::serialized_size(item).unwrap_or_default();
+            if self.bytes > max {
+                return Err(self.exceeded(LimitKind::Bytes));
+            }
+        }
+        if matches!(self.limits.max_duration, Some(max) if self.started.elapsed() > max) {
+            return Err(self.exceeded(LimitKind::Duration));
+        }
+        Ok(())
+    }
+
+    fn exceeded(&self, kind: LimitKind) -> StreamLimitExceeded {
+        StreamLimitExceeded {
+            kind,
+            ..self.exceeded
+        }
+    }
+}
+
+/// A received stream, mapped with `f`, that ends with an error item once it
+/// exceeds its limits
+#[pin_project]
+pub(crate) struct Limited<St, F, E> {
+    #[pin]
+    inner: St,
+    tracker: Option<Tracker>,
+    f: F,
+    exceeded: Option<E>,
+    done: bool,
+}
+
+impl<St, F, E> Limited<St, F, E> {
+    pub(crate) fn new(inner: St, tracker: Option<Tracker>, f: F, exceeded: E) -> Self {
+        Self {
+            inner,
+            tracker,
+            f,
+            exceeded: Some(exceeded),
+            done: false,
+        }
+    }
+}
+
+impl<St, F, E, T, R, O> Stream for Limited<St, F, E>
+where
+    St: Stream<Item = Result<T, R>>,
+    T: Serialize,
+    F: FnMut(Result<T, R>) -> O,
+    E: FnOnce(StreamLimitExceeded) -> O,
+{
+    type Item = O;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        let item = match futures::ready!(this.inner.poll_next(cx)) {
+            Some(item) => item,
+            None => return Poll::Ready(None),
+        };
+        if let (Ok(msg), Some(tracker)) = (&item, this.tracker.as_mut()) {
+            if let Err(cause) = tracker.item(msg) {
+                tracing::debug!(
+                    rpc.service = cause.service,
+                    rpc.method = cause.method,
+                    "{}",
+                    cause
+                );
+                *this.done = true;
+                let exceeded = this.exceeded.take().expect("limited stream exceeded twice");
+                return Poll::Ready(Some(exceeded(cause)));
+            }
+        }
+        Poll::Ready(Some((this.f)(item)))
+    }
+}
@@ -1,4 +1,284 @@
 //! Macros to reduce boilerplate for RPC implementations.
+//!
+//! This module only contains support items for the generated code.
+use crate::server::{FromHandlerDropped, HandlerDropped};
+use serde::de::{self, DeserializeSeed, Deserializer, Visitor};
+use std::{fmt, marker::PhantomData, time::Duration};
+
+/// Messages larger than this many bytes are boxed in the generated enums,
+/// unless configured otherwise with `MaxVariantSize` in [rpc_service](crate::rpc_service).
+pub const DEFAULT_MAX_VARIANT_SIZE: usize = 128;
+
+/// Selects how a message is stored in a generated enum
+pub struct Variant<T, const BOXED: bool>(PhantomData<T>);
+
+/// The type used to store a message in a generated enum
+pub trait VariantRepr {
+    /// Either the message itself, or a box of it
+    type Repr;
+}
+
+impl<T> VariantRepr for Variant<T, false> {
+    type Repr = T;
+}
+
+impl<T> VariantRepr for Variant<T, true> {
+    type Repr = Box<T>;
+}
+
+/// Get a message out of its representation in a generated enum
+pub trait IntoInner<T> {
+    /// Unbox the message, if it is boxed
+    fn into_inner(self) -> T;
+}
+
+impl<T> IntoInner<T> for T {
+    fn into_inner(self) -> T {
+        self
+    }
+}
+
+impl<T> IntoInner<T> for Box<T> {
+    fn into_inner(self) -> T {
+        *self
+    }
+}
+
+/// Selects the response of generated messages for dropped handlers
+///
+/// `(&HandlerDroppedProbe::<R>(PhantomData)).handler_dropped(info)` resolves to
+/// [ConvertHandlerDropped] if `R` implements [FromHandlerDropped], and to
+/// [NoHandlerDropped] otherwise.
+pub struct HandlerDroppedProbe<R>(pub PhantomData<R>);
+
+/// Response for a dropped handler of a response type that can carry it
+pub trait ConvertHandlerDropped<R> {
+    /// The response converted from `info`
+    fn handler_dropped(&self, info: &HandlerDropped) -> Option<R>;
+}
+
+impl<R: FromHandlerDropped> ConvertHandlerDropped<R> for HandlerDroppedProbe<R> {
+    fn handler_dropped(&self, info: &HandlerDropped) -> Option<R> {
+        Some(R::from_handler_dropped(*info))
+    }
+}
+
+/// No response for a dropped handler of any other response type
+pub trait NoHandlerDropped<R> {
+    /// Always `None`
+    fn handler_dropped(&self, info: &HandlerDropped) -> Option<R>;
+}
+
+impl<R> NoHandlerDropped<R> for &HandlerDroppedProbe<R> {
+    fn handler_dropped(&self, _info: &HandlerDropped) -> Option<R> {
+        None
+    }
+}
+
+/// The tag of a message in a generated enum
+///
+/// This is the pinned tag from `tags`, if there is one for `name`, and the
+/// 32 bit FNV-1a hash of `name` otherwise, so it does not depend on the order
+/// in which the messages are declared.
+pub const fn tag(name: &str, pinned: &[(&str, u32)]) -> u32 {
+    let mut i = 0;
+    while i < pinned.len() {
+        if str_eq(pinned[i].0, name) {
+            return pinned[i].1;
+        }
+        i += 1;
+    }
+    let bytes = name.as_bytes();
+    let mut hash: u32 = 0x811c_9dc5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Sort the tags of a generated enum by tag, failing to compile on duplicates
+pub const fn manifest<const N: usize>(
+    mut tags: [(&'static str, u32); N],
+) -> [(&'static str, u32); N] {
+    let mut i = 1;
+    while i < N {
+        let mut j = i;
+        while j > 0 && tags[j - 1].1 > tags[j].1 {
+            let t = tags[j - 1];
+            tags[j - 1] = tags[j];
+            tags[j] = t;
+            j -= 1;
+        }
+        if j > 0 && tags[j - 1].1 == tags[j].1 {
+            panic!("two messages have the same tag, pin one of them with #[tags(..)]");
+        }
+        i += 1;
+    }
+    tags
+}
+
+/// Fail to compile if a pinned tag does not belong to any message of the service
+pub const fn check_pinned(pinned: &[(&str, u32)], names: &[&str]) {
+    let mut i = 0;
+    while i < pinned.len() {
+        let mut j = 0;
+        while j < names.len() && !str_eq(pinned[i].0, names[j]) {
+            j += 1;
+        }
+        if j == names.len() {
+            panic!("#[tags(..)] pins a tag for a message that is not part of the service");
+        }
+        i += 1;
+    }
+}
+
+/// Parse a duration of the `rpc` attribute, like `"500ms"` or `"5s"`
+///
+/// The units are `ms`, `s`, `m` and `h`. Anything else fails to compile.
+pub const fn parse_duration(text: &str) -> Duration {
+    let (value, start) = split_unit(text);
+    let millis = if unit_eq(text, start, "ms") {
+        1
+    } else if unit_eq(text, start, "s") {
+        1_000
+    } else if unit_eq(text, start, "m") {
+        60_000
+    } else if unit_eq(text, start, "h") {
+        3_600_000
+    } else {
+        panic!("#[rpc(timeout = ..)] needs a unit of ms, s, m or h");
+    };
+    Duration::from_millis(value * millis)
+}
+
+/// Parse a size of the `rpc` attribute, like `"64KiB"` or `"1MB"`
+///
+/// The units are `B`, `KB`, `MB` and `GB`, and `KiB`, `MiB` and `GiB` for
+/// powers of two. A number without a unit is in bytes. Anything else fails to
+/// compile.
+pub const fn parse_size(text: &str) -> u64 {
+    let (value, start) = split_unit(text);
+    let bytes = if start == text.len() || unit_eq(text, start, "B") {
+        1
+    } else if unit_eq(text, start, "KB") {
+        1_000
+    } else if unit_eq(text, start, "MB") {
+        1_000_000
+    } else if unit_eq(text, start, "GB") {
+        1_000_000_000
+    } else if unit_eq(text, start, "KiB") {
+        1 << 10
+    } else if unit_eq(text, start, "MiB") {
+        1 << 20
+    } else if unit_eq(text, start, "GiB") {
+        1 << 30
+    } else {
+        panic!("#[rpc(max_size = ..)] needs a unit of B, KB, MB, GB, KiB, MiB or GiB");
+    };
+    value * bytes
+}
+
+/// Split a number with a unit into the number and the start of the unit
+const fn split_unit(text: &str) -> (u64, usize) {
+    let bytes = text.as_bytes();
+    let mut value: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        value = value * 10 + (bytes[i] - b'0') as u64;
+        i += 1;
+    }
+    if i == 0 {
+        panic!("#[rpc(..)] options need to start with a number");
+    }
+    (value, i)
+}
+
+/// Whether `text` from `start` on is `unit`
+const fn unit_eq(text: &str, start: usize, unit: &str) -> bool {
+    let (text, unit) = (text.as_bytes(), unit.as_bytes());
+    if text.len() - start != unit.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < unit.len() {
+        if text[start + i] != unit[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The text of a doc comment from the `DOCS` of a generated request enum
+///
+/// Removes the space after the `///` of every line, and joins the lines.
+pub fn doc_text(lines: &[&str]) -> String {
+    lines
+        .iter()
+        .map(|line| line.strip_prefix(' ').unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Deserializes the variant identifier of a generated enum to its tag
+///
+/// Formats like bincode identify variants by their index, which is the tag.
+/// Self describing formats identify them by name, which is looked up in the
+/// manifest.
+pub struct TagSeed(pub &'static [(&'static str, u32)]);
+
+impl<'de> DeserializeSeed<'de> for TagSeed {
+    type Value = u32;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<u32, D::Error> {
+        deserializer.deserialize_identifier(self)
+    }
+}
+
+impl<'de> Visitor<'de> for TagSeed {
+    type Value = u32;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a message tag or name")
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<u32, E> {
+        u32::try_from(v).map_err(|_| E::custom(format!("unknown message tag {v}")))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<u32, E> {
+        self.0
+            .iter()
+            .find(|(name, _)| *name == v)
+            .map(|(_, tag)| *tag)
+            .ok_or_else(|| E::unknown_variant(v, &[]))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<u32, E> {
+        match std::str::from_utf8(v) {
+            Ok(v) => self.visit_str(v),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(v), &self)),
+        }
+    }
+}
 
 /// Derive a set of RPC types and message implementation from a declaration.
 ///
@@ -114,6 +394,117 @@
 ///
 /// ```
 ///
+/// # Large messages
+///
+/// Variants of the generated enums are as large as their largest message, so a
+/// single large message makes every request or response expensive to move
+/// around. Messages larger than 128 bytes are therefore stored boxed. The threshold can be configured with an optional
+/// `MaxVariantSize` line after `CreateDispatch`:
+///
+/// ```ignore
+/// rpc_service! {
+///     Request = MyRequest;
+///     Response = MyResponse;
+///     Service = MyService;
+///     CreateDispatch = _;
+///     // box messages larger than 64 bytes, use usize::MAX to never box
+///     MaxVariantSize = 64;
+///
+///     Rpc add = Add, _ -> Sum;
+/// }
+/// ```
+///
+/// Boxing does not change the wire format, and `From` and `TryFrom`
+/// conversions between messages and the enums work with the unboxed message
+/// types either way. Only code that matches on the enum variants directly sees
+/// the boxed messages.
+///
+/// # Wire tags
+///
+/// Every message is identified on the wire by a tag, the hash of its name, so
+/// reordering the method lines does not change the protocol. Renaming a
+/// message does change its tag. To keep the old tag, or to assign tags
+/// explicitly, pin them with a `tags` attribute on any method line:
+///
+/// ```ignore
+/// rpc_service! {
+///     Request = MyRequest;
+///     Response = MyResponse;
+///     Service = MyService;
+///     CreateDispatch = _;
+///
+///     #[tags(Add = 1, Sum = 2)]
+///     Rpc add = Add, _ -> Sum;
+/// }
+/// ```
+///
+/// Pinning a tag for a message that is not part of the service, or two
+/// messages of an enum with the same tag, fails to compile. The generated enums
+/// have a `MANIFEST` constant with the names and tags of their messages,
+/// sorted by tag, which can be compared against a checked in copy in a test to
+/// catch accidental protocol changes.
+///
+/// This is a breaking change of the wire format in version 0.6. Before, a
+/// message was identified by its position in the enum: the requests of all
+/// method lines in order, followed by their updates, and the responses in
+/// order. Peers built with an older version can only talk to services that pin
+/// every message to that position, starting at 0. Tags are 32 bit hashes,
+/// which take 5 bytes with variable length integer encodings such as the one of
+/// postcard, while positions usually took 1. Pinning small tags keeps messages
+/// as short as before.
+///
+/// # Method docs
+///
+/// Doc comments on the method lines, before any attributes, describe the
+/// methods for generated clients and tools. The generated request enum has a
+/// `DOCS` constant with the lines of the comment of every method, by the name
+/// of the message that starts the call:
+///
+/// ```ignore
+/// rpc_service! {
+///     Request = MyRequest;
+///     Response = MyResponse;
+///     Service = MyService;
+///     CreateDispatch = _;
+///
+///     /// Add two numbers
+///     Rpc add = Add, _ -> Sum;
+/// }
+///
+/// assert_eq!(MyRequest::DOCS, [("Add", [" Add two numbers"].as_slice())]);
+/// assert_eq!(MyRequest::doc("Add").as_deref(), Some("Add two numbers"));
+/// ```
+///
+/// The `doc` function of the enum joins the lines of the comment of a method
+/// into its text.
+///
+/// Doc comments on the message types themselves are not visible to the macro.
+///
+/// # Method policies
+///
+/// The [policy](crate::message::MethodPolicy) of a message can be declared with
+/// an `rpc` attribute on its method line, after the `tags` attribute if there
+/// is one:
+///
+/// ```ignore
+/// rpc_service! {
+///     Request = MyRequest;
+///     Response = MyResponse;
+///     Service = MyService;
+///     CreateDispatch = _;
+///
+///     #[rpc(timeout = "5s", idempotent, max_size = "1MB", cacheable)]
+///     Rpc add = Add, _ -> Sum;
+///     #[rpc(timeout = "1m")]
+///     ClientStreaming upload = Upload, Chunk -> Uploaded;
+/// }
+/// ```
+///
+/// `timeout` is a duration in `ms`, `s`, `m` or `h`, `max_size` a size in
+/// bytes, with an optional unit like `KB` or `KiB`, and `cacheable` sets
+/// [RpcMsg::CACHEABLE](crate::message::RpcMsg::CACHEABLE), so it is only
+/// allowed for rpc methods. Unknown options and invalid values fail to compile.
+///
 /// The generation of the macros in `CreateDispatch` and `CreateClient`
 /// is optional. If you don't need them, pass `_` instead:
 ///
@@ -138,27 +529,47 @@ macro_rules! rpc_service {
         Response = $response:ident;
         Service = $service:ident;
         CreateDispatch = $create_dispatch:tt;
+        MaxVariantSize = $max:expr;
 
-        $($m_pattern:ident $m_name:ident = $m_input:ident, $m_update:tt -> $m_output:ident);+$(;)?
+        $($(#[doc = $doc:literal])* $(#[tags($($pin:ident = $pin_tag:expr),* $(,)?)])? $(#[rpc($($opt:tt)*)])? $m_pattern:ident $m_name:ident = $m_input:ident, $m_update:tt -> $m_output:ident);+$(;)?
     ) => {
 
         $crate::__request_enum! {
             $service,
+            $max,
+            [$($($((stringify!($pin), $pin_tag),)*)?)*],
             $request {
                 $($m_input,)*
                 $($m_update,)*
             }
         }
 
-        #[doc=concat!("Response messages for ", stringify!($service))]
-        #[allow(clippy::enum_variant_names)]
-        #[derive(::std::fmt::Debug, ::derive_more::From, ::derive_more::TryInto, ::serde::Serialize, ::serde::Deserialize)]
-        pub enum $response {
-            $($m_output($m_output),)*
+        $crate::__message_enum!($service, "Response", $response, $max, [$($($((stringify!($pin), $pin_tag),)*)?)*], [$($m_output)*]);
+
+        impl $request {
+            /// The doc comments of the method lines, by the name of the message that starts the call
+            ///
+            /// Every line of a comment is an entry, see [Self::doc] for the text.
+            pub const DOCS: &'static [(&'static str, &'static [&'static str])] = &[
+                $((stringify!($m_input), &[$($doc),*]),)*
+            ];
+
+            /// The doc comment of the method that is started by the message `name`
+            pub fn doc(name: &str) -> ::std::option::Option<::std::string::String> {
+                Self::DOCS
+                    .iter()
+                    .find(|(message, _)| *message == name)
+                    .map(|(_, lines)| $crate::__private::doc_text(lines))
+            }
         }
 
+        const _: () = $crate::__private::check_pinned(
+            &[$($($((stringify!($pin), $pin_tag),)*)?)*],
+            &[$(stringify!($m_input), stringify!($m_update), stringify!($m_output),)*],
+        );
+
         $(
-            $crate::__rpc_message!($service, $m_pattern, $m_input, $m_update, $m_output);
+            $crate::__rpc_message!($service, $m_pattern, $m_input, $m_update, $m_output, [$($($opt)*)?]);
         )*
 
         #[doc=concat!("RPC service ", stringify!($service))]
@@ -177,6 +588,24 @@ macro_rules! rpc_service {
             [ $($m_pattern $m_name = $m_input, $m_update -> $m_output);+ ]
         );
     };
+    (
+        Request = $request:ident;
+        Response = $response:ident;
+        Service = $service:ident;
+        CreateDispatch = $create_dispatch:tt;
+
+        $($(#[doc = $doc:literal])* $(#[tags($($pin:ident = $pin_tag:expr),* $(,)?)])? $(#[rpc($($opt:tt)*)])? $m_pattern:ident $m_name:ident = $m_input:ident, $m_update:tt -> $m_output:ident);+$(;)?
+    ) => {
+        $crate::rpc_service! {
+            Request = $request;
+            Response = $response;
+            Service = $service;
+            CreateDispatch = $create_dispatch;
+            MaxVariantSize = $crate::__private::DEFAULT_MAX_VARIANT_SIZE;
+
+            $($(#[doc = $doc])* $(#[tags($($pin = $pin_tag),*)])? $(#[rpc($($opt)*)])? $m_pattern $m_name = $m_input, $m_update -> $m_output);+
+        }
+    };
 }
 
 #[doc(hidden)]
@@ -205,7 +634,10 @@ macro_rules! __derive_create_dispatch {
                 ) -> Result<(), $crate::server::RpcServerError<C>> {
                     let res = match msg {
                         $(
-                            $request::$m_input(msg) => { $crate::__rpc_invoke!($m_pattern, $m_name, $target, msg, chan, target) },
+                            $request::$m_input(msg) => {
+                                let msg: $m_input = $crate::__private::IntoInner::into_inner(msg);
+                                $crate::__rpc_invoke!($m_pattern, $m_name, $target, msg, chan, target)
+                            },
                         )*
                         _ => Err($crate::server::RpcServerError::<C>::UnexpectedStartMessage),
                     };
@@ -221,28 +653,119 @@ macro_rules! __derive_create_dispatch {
 #[macro_export]
 macro_rules! __request_enum {
     // User entry points.
-    ($service:ident, $enum_name:ident { $variant_name:ident $($tt:tt)* }) => {
-        $crate::__request_enum!(@ {[$service $enum_name] [$variant_name]} $($tt)*);
+    ($service:ident, $max:tt, $pins:tt, $enum_name:ident { $variant_name:ident $($tt:tt)* }) => {
+        $crate::__request_enum!(@ {[$service $max $pins $enum_name] [$variant_name]} $($tt)*);
     };
 
     // Internal rules to categorize each value
     // This also filters out _ placeholders from non-streaming methods.
-    (@ {[$service:ident $enum_name:ident] [$($agg:ident)*]} $(,)? $(_$(,)?)* $variant_name:ident $($tt:tt)*) => {
-        $crate::__request_enum!(@ {[$service $enum_name] [$($agg)* $variant_name]} $($tt)*);
+    (@ {[$service:ident $max:tt $pins:tt $enum_name:ident] [$($agg:ident)*]} $(,)? $(_$(,)?)* $variant_name:ident $($tt:tt)*) => {
+        $crate::__request_enum!(@ {[$service $max $pins $enum_name] [$($agg)* $variant_name]} $($tt)*);
     };
 
     // Internal rules to categorize each value
-    (@ {[$service:ident $enum_name:ident] [$($agg:ident)*]} $(,)? $variant_name:ident $($tt:tt)*) => {
-        $crate::__request_enum!(@ {[$service $enum_name] [$($agg)* $variant_name]} $($tt)*);
+    (@ {[$service:ident $max:tt $pins:tt $enum_name:ident] [$($agg:ident)*]} $(,)? $variant_name:ident $($tt:tt)*) => {
+        $crate::__request_enum!(@ {[$service $max $pins $enum_name] [$($agg)* $variant_name]} $($tt)*);
     };
 
     // Final internal rule that generates the enum from the categorized input
-    (@ {[$service:ident $enum_name:ident] [$($n:ident)*]} $(,)? $(_$(,)?)*) => {
-        #[doc=concat!("Request messages for ", stringify!($service))]
-        #[derive(::std::fmt::Debug, ::derive_more::From, ::derive_more::TryInto, ::serde::Serialize, ::serde::Deserialize)]
+    (@ {[$service:ident $max:tt $pins:tt $enum_name:ident] [$($n:ident)*]} $(,)? $(_$(,)?)*) => {
+        $crate::__message_enum!($service, "Request", $enum_name, $max, $pins, [$($n)*]);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __message_enum {
+    ($service:ident, $kind:literal, $enum_name:ident, $max:tt, $pins:tt, [$($n:ident)*]) => {
+        #[doc=concat!($kind, " messages for ", stringify!($service))]
+        #[allow(clippy::enum_variant_names)]
+        #[derive(::std::fmt::Debug)]
         pub enum $enum_name {
-            $($n($n),)*
+            $($n($crate::__variant_repr!($n, $max)),)*
+        }
+
+        impl $enum_name {
+            /// The names of the messages and the tags that identify them on the wire, sorted by tag
+            pub const MANIFEST: &'static [(&'static str, u32)] = &$crate::__private::manifest([
+                $((stringify!($n), $crate::__private::tag(stringify!($n), &$pins)),)*
+            ]);
         }
+
+        // evaluate the manifest, to reject duplicate tags at compile time
+        const _: &[(&str, u32)] = $enum_name::MANIFEST;
+
+        impl ::serde::Serialize for $enum_name {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+                match self {
+                    $(
+                        Self::$n(msg) => {
+                            const TAG: u32 = $crate::__private::tag(stringify!($n), &$pins);
+                            serializer.serialize_newtype_variant(stringify!($enum_name), TAG, stringify!($n), msg)
+                        }
+                    )*
+                }
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $enum_name {
+            fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+                struct EnumVisitor;
+
+                impl<'de> ::serde::de::Visitor<'de> for EnumVisitor {
+                    type Value = $enum_name;
+
+                    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        f.write_str(concat!("enum ", stringify!($enum_name)))
+                    }
+
+                    fn visit_enum<A: ::serde::de::EnumAccess<'de>>(self, data: A) -> ::std::result::Result<Self::Value, A::Error> {
+                        let (tag, variant) = data.variant_seed($crate::__private::TagSeed($enum_name::MANIFEST))?;
+                        match tag {
+                            $(
+                                tag if tag == {
+                                    const TAG: u32 = $crate::__private::tag(stringify!($n), &$pins);
+                                    TAG
+                                } => ::serde::de::VariantAccess::newtype_variant::<$crate::__variant_repr!($n, $max)>(variant)
+                                    .map($enum_name::$n),
+                            )*
+                            tag => Err(::serde::de::Error::custom(format_args!("unknown {} tag {}", stringify!($enum_name), tag))),
+                        }
+                    }
+                }
+
+                // the names are for describing variants, tags are not indices into them
+                deserializer.deserialize_enum(stringify!($enum_name), &[$(stringify!($n)),*], EnumVisitor)
+            }
+        }
+
+        $(
+            impl ::std::convert::From<$n> for $enum_name {
+                fn from(value: $n) -> Self {
+                    Self::$n(::std::convert::From::from(value))
+                }
+            }
+
+            impl ::std::convert::TryFrom<$enum_name> for $n {
+                type Error = &'static str;
+
+                fn try_from(value: $enum_name) -> ::std::result::Result<Self, Self::Error> {
+                    #[allow(unreachable_patterns)]
+                    match value {
+                        $enum_name::$n(msg) => Ok($crate::__private::IntoInner::into_inner(msg)),
+                        _ => Err(concat!("Only ", stringify!($enum_name), "::", stringify!($n), " can be converted to ", stringify!($n))),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __variant_repr {
+    ($n:ident, $max:tt) => {
+        <$crate::__private::Variant<$n, { ::std::mem::size_of::<$n>() > $max }> as $crate::__private::VariantRepr>::Repr
     };
 }
 
@@ -264,6 +787,14 @@ macro_rules! declare_rpc {
     ($service:ty, $m_input:ty, $m_output:ty) => {
         impl $crate::message::RpcMsg<$service> for $m_input {
             type Response = $m_output;
+            fn handler_dropped(
+                info: &$crate::server::HandlerDropped,
+            ) -> ::std::option::Option<$m_output> {
+                #[allow(unused_imports)]
+                use $crate::__private::{ConvertHandlerDropped as _, NoHandlerDropped as _};
+                (&$crate::__private::HandlerDroppedProbe::<$m_output>(::std::marker::PhantomData))
+                    .handler_dropped(info)
+            }
         }
     };
 }
@@ -323,6 +854,14 @@ macro_rules! declare_client_streaming {
         impl $crate::message::ClientStreamingMsg<$service> for $m_input {
             type Update = $m_update;
             type Response = $m_output;
+            fn handler_dropped(
+                info: &$crate::server::HandlerDropped,
+            ) -> ::std::option::Option<$m_output> {
+                #[allow(unused_imports)]
+                use $crate::__private::{ConvertHandlerDropped as _, NoHandlerDropped as _};
+                (&$crate::__private::HandlerDroppedProbe::<$m_output>(::std::marker::PhantomData))
+                    .handler_dropped(info)
+            }
         }
     };
 }
@@ -361,31 +900,56 @@ macro_rules! declare_bidi_streaming {
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __rpc_message {
-    ($service:ident, Rpc, $m_input:ident, _, $m_output:ident) => {
+    ($service:ident, Rpc, $m_input:ident, _, $m_output:ident, [$($opt:tt)*]) => {
         impl $crate::message::RpcMsg<$service> for $m_input {
             type Response = $m_output;
+            const CACHEABLE: bool = $crate::__method_cacheable!($($opt)*);
+            const RPC_POLICY: $crate::message::MethodPolicy =
+                $crate::__method_policy!(@rpc $crate::message::MethodPolicy::DEFAULT; $($opt)*);
+            fn handler_dropped(
+                info: &$crate::server::HandlerDropped,
+            ) -> ::std::option::Option<$m_output> {
+                #[allow(unused_imports)]
+                use $crate::__private::{ConvertHandlerDropped as _, NoHandlerDropped as _};
+                (&$crate::__private::HandlerDroppedProbe::<$m_output>(::std::marker::PhantomData))
+                    .handler_dropped(info)
+            }
         }
     };
-    ($service:ident, ServerStreaming, $m_input:ident, _, $m_output:ident) => {
+    ($service:ident, ServerStreaming, $m_input:ident, _, $m_output:ident, [$($opt:tt)*]) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ServerStreaming;
+            const POLICY: $crate::message::MethodPolicy =
+                $crate::__method_policy!(@stream $crate::message::MethodPolicy::DEFAULT; $($opt)*);
         }
         impl $crate::message::ServerStreamingMsg<$service> for $m_input {
             type Response = $m_output;
         }
     };
-    ($service:ident, ClientStreaming, $m_input:ident, $m_update:ident, $m_output:ident) => {
+    ($service:ident, ClientStreaming, $m_input:ident, $m_update:ident, $m_output:ident, [$($opt:tt)*]) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::ClientStreaming;
+            const POLICY: $crate::message::MethodPolicy =
+                $crate::__method_policy!(@stream $crate::message::MethodPolicy::DEFAULT; $($opt)*);
         }
         impl $crate::message::ClientStreamingMsg<$service> for $m_input {
             type Response = $m_output;
             type Update = $m_update;
+            fn handler_dropped(
+                info: &$crate::server::HandlerDropped,
+            ) -> ::std::option::Option<$m_output> {
+                #[allow(unused_imports)]
+                use $crate::__private::{ConvertHandlerDropped as _, NoHandlerDropped as _};
+                (&$crate::__private::HandlerDroppedProbe::<$m_output>(::std::marker::PhantomData))
+                    .handler_dropped(info)
+            }
         }
     };
-    ($service:ident, BidiStreaming, $m_input:ident, $m_update:ident, $m_output:ident) => {
+    ($service:ident, BidiStreaming, $m_input:ident, $m_update:ident, $m_output:ident, [$($opt:tt)*]) => {
         impl $crate::message::Msg<$service> for $m_input {
             type Pattern = $crate::message::BidiStreaming;
+            const POLICY: $crate::message::MethodPolicy =
+                $crate::__method_policy!(@stream $crate::message::MethodPolicy::DEFAULT; $($opt)*);
         }
         impl $crate::message::BidiStreamingMsg<$service> for $m_input {
             type Response = $m_output;
@@ -394,6 +958,56 @@ macro_rules! __rpc_message {
     };
 }
 
+/// The policy of the options of an `rpc` attribute
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __method_policy {
+    (@$kind:ident $policy:expr;) => {
+        $policy
+    };
+    (@$kind:ident $policy:expr; , $($rest:tt)*) => {
+        $crate::__method_policy!(@$kind $policy; $($rest)*)
+    };
+    (@$kind:ident $policy:expr; timeout = $timeout:literal $($rest:tt)*) => {
+        $crate::__method_policy!(
+            @$kind $policy.with_timeout($crate::__private::parse_duration($timeout)); $($rest)*
+        )
+    };
+    (@$kind:ident $policy:expr; idempotent $($rest:tt)*) => {
+        $crate::__method_policy!(@$kind $policy.with_idempotent(true); $($rest)*)
+    };
+    (@$kind:ident $policy:expr; max_size = $size:literal $($rest:tt)*) => {
+        $crate::__method_policy!(
+            @$kind $policy.with_max_size($crate::__private::parse_size($size)); $($rest)*
+        )
+    };
+    // cacheable is not part of the policy, see __method_cacheable
+    (@rpc $policy:expr; cacheable $($rest:tt)*) => {
+        $crate::__method_policy!(@rpc $policy; $($rest)*)
+    };
+    (@stream $policy:expr; cacheable $($rest:tt)*) => {
+        compile_error!("#[rpc(cacheable)] is only allowed for rpc methods")
+    };
+    (@$kind:ident $policy:expr; $option:tt $($rest:tt)*) => {
+        compile_error!(concat!("unknown #[rpc(..)] option ", stringify!($option)))
+    };
+}
+
+/// Whether the options of an `rpc` attribute contain `cacheable`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __method_cacheable {
+    () => {
+        false
+    };
+    (cacheable $($rest:tt)*) => {
+        true
+    };
+    ($option:tt $($rest:tt)*) => {
+        $crate::__method_cacheable!($($rest)*)
+    };
+}
+
 #[doc(hidden)]
 #[macro_export]
 macro_rules! __rpc_invoke {
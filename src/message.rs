@@ -1,8 +1,8 @@
 //! Service definition
 //!
 //! Traits to define the behaviour of messages for services
-use crate::Service;
-use std::fmt::Debug;
+use crate::{server::HandlerDropped, Service};
+use std::{fmt::Debug, time::Duration};
 
 /// Declares the interaction pattern for a message and a service.
 ///
@@ -10,6 +10,78 @@ use std::fmt::Debug;
 pub trait Msg<S: Service>: Into<S::Req> + TryFrom<S::Req> + Send + 'static {
     /// The interaction pattern for this message with this service.
     type Pattern: InteractionPattern;
+
+    /// The policy for calls of this message
+    ///
+    /// For rpc messages, this is [RpcMsg::RPC_POLICY].
+    const POLICY: MethodPolicy = MethodPolicy::DEFAULT;
+}
+
+/// Policy for the calls of a message
+///
+/// This is usually declared next to the message with the `rpc` attribute of
+/// [rpc_service](crate::rpc_service), and applied by the client, the server
+/// and the stream limits, so there is no need to configure it for every
+/// client and server. Configuration for a specific message type on a client
+/// or server takes precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodPolicy {
+    /// Time limit for calls
+    ///
+    /// Clients fail calls that take longer with
+    /// [CallCancelled::DeadlineExceeded](crate::context::CallCancelled::DeadlineExceeded).
+    /// Servers use this as the deadline of the
+    /// [CallContext](crate::context::CallContext) of the handler, and fail rpc
+    /// and client streaming calls that take longer with
+    /// [RpcServerError::Timeout](crate::server::RpcServerError::Timeout).
+    pub timeout: Option<Duration>,
+    /// Whether calls can be repeated without changing the outcome
+    ///
+    /// Only calls of idempotent messages are retried by
+    /// [RpcClient::rpc_retry](crate::RpcClient::rpc_retry).
+    pub idempotent: bool,
+    /// Maximum size of each stream of a call, in bytes of its bincode encoding
+    ///
+    /// With the `stream-limits` feature, this is the default for
+    /// [StreamLimits::max_bytes](crate::limits::StreamLimits::max_bytes) of the
+    /// updates and responses of calls.
+    pub max_size: Option<u64>,
+}
+
+impl MethodPolicy {
+    /// No time limit, not idempotent and no size limit
+    pub const DEFAULT: Self = Self {
+        timeout: None,
+        idempotent: false,
+        max_size: None,
+    };
+
+    /// Set the time limit for calls
+    pub const fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Mark calls as idempotent
+    pub const fn with_idempotent(self, idempotent: bool) -> Self {
+        Self { idempotent, ..self }
+    }
+
+    /// Set the maximum size of each stream of a call
+    pub const fn with_max_size(self, max_size: u64) -> Self {
+        Self {
+            max_size: Some(max_size),
+            ..self
+        }
+    }
+}
+
+impl Default for MethodPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
 }
 
 /// Defines the response type for a rpc message.
@@ -22,11 +94,34 @@ pub trait RpcMsg<S: Service>: Msg<S, Pattern = Rpc> {
     ///
     /// For requests that can produce errors, this can be set to [Result<T, E>](std::result::Result).
     type Response: Into<S::Res> + TryFrom<S::Res> + Send + 'static;
+
+    /// Whether the response only depends on the request, so it can be cached
+    ///
+    /// Responses of cacheable messages are cached by servers that have a response
+    /// cache, see the `cache` module. The default is `false`.
+    const CACHEABLE: bool = false;
+
+    /// The policy for calls of this message, see [Msg::POLICY]
+    ///
+    /// [Msg] is implemented for rpc messages automatically, so their policy is
+    /// set here.
+    const RPC_POLICY: MethodPolicy = MethodPolicy::DEFAULT;
+
+    /// The response for a request whose handler was dropped before it responded
+    ///
+    /// The macros that declare messages return a
+    /// [FromHandlerDropped](crate::server::FromHandlerDropped) response if the
+    /// response type implements it. The default is `None`, so the client sees
+    /// the call end early.
+    fn handler_dropped(_info: &HandlerDropped) -> Option<Self::Response> {
+        None
+    }
 }
 
 /// We can only do this for one trait, so we do it for RpcMsg since it is the most common
 impl<T: RpcMsg<S>, S: Service> Msg<S> for T {
     type Pattern = Rpc;
+    const POLICY: MethodPolicy = T::RPC_POLICY;
 }
 
 /// Defines update type and response type for a client streaming message.
@@ -41,6 +136,12 @@ pub trait ClientStreamingMsg<S: Service>: Msg<S, Pattern = ClientStreaming> {
     ///
     /// For requests that can produce errors, this can be set to [Result<T, E>](std::result::Result).
     type Response: Into<S::Res> + TryFrom<S::Res> + Send + 'static;
+
+    /// The response for a request whose handler was dropped before it
+    /// responded, see [RpcMsg::handler_dropped]
+    fn handler_dropped(_info: &HandlerDropped) -> Option<Self::Response> {
+        None
+    }
 }
 
 /// Defines response type for a server streaming message.
@@ -0,0 +1,531 @@
+//! Metrics in the OpenMetrics text format
+//!
+//! With the `openmetrics` feature, the telemetry of all calls is also collected
+//! in an in-process registry, so metrics can be scraped without setting up an
+//! OpenTelemetry pipeline. [encode] renders the registry in the
+//! [OpenMetrics text format], and the hyper transport can serve it on
+//! `/metrics` from the same listener as the rpc calls.
+//!
+//! The registry contains
+//! - `rpc_client_duration_seconds` and `rpc_server_duration_seconds`, histograms
+//!   of call durations per service and method,
+//! - `rpc_server_in_flight`, the calls in flight on all connections,
+//! - `rpc_server_connection_in_flight`, the calls in flight per connection,
+//!   for endpoints that opted in,
+//! - `quic_connection_rtt_seconds`, `quic_connection_cwnd_bytes`,
+//!   `quic_connection_lost_packets` and `quic_connection_congestion_events`,
+//!   the path statistics of connections, if the transport samples them,
+//! - `rpc_compression_input_bytes`, `rpc_compression_output_bytes` and
+//!   `rpc_compression_skipped_frames`, the
+//!   [compression statistics](crate::transport::compression::CompressionStats)
+//!   per message type, with the `zstd-compression` feature,
+//! - `process_allocations` and `process_allocated_bytes`, with the
+//!   `alloc-counters` feature and a
+//!   [CountingAllocator](crate::allocations::CountingAllocator) installed.
+//!
+//! Metrics of a connection are only kept while the connection is open.
+//!
+//! The duration of server calls can also be labelled with values from their
+//! first request, e.g. the tenant a call is made for, using [MetricLabels]
+//! with [RpcServer::with_metric_labels](crate::RpcServer::with_metric_labels):
+//!
+//! ```ignore
+//! let labels = MetricLabels::new().with_label("tenant", 100, |req: &StoreRequest| {
+//!     req.tenant().map(|tenant| tenant.to_string())
+//! });
+//! let server = RpcServer::new(endpoint).with_metric_labels(labels);
+//! ```
+//!
+//! [OpenMetrics text format]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+use crate::{
+    telemetry::{Side, RPC_SYSTEM},
+    Service,
+};
+use once_cell::sync::Lazy;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// The content type of the output of [encode]
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Upper bounds of the duration histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(Default::default);
+
+/// Render all metrics in the OpenMetrics text format
+pub fn encode() -> String {
+    let mut out = String::new();
+    REGISTRY
+        .lock()
+        .unwrap()
+        .encode(&mut out)
+        .expect("writing to a string does not fail");
+    out
+}
+
+/// The value recorded for a label once it reached its limit of distinct values
+pub const OVERFLOW_VALUE: &str = "other";
+
+/// Names of the labels that are always set, which extracted labels can not use
+const RESERVED_LABELS: [&str; 4] = ["rpc_system", "rpc_service", "rpc_method", "le"];
+
+/// Label names and values of a call, in the order the labels were added
+pub(crate) type Labels = Vec<(&'static str, String)>;
+
+type Extract<S> = dyn Fn(&<S as Service>::Req) -> Option<String> + Send + Sync;
+
+struct Label<S: Service> {
+    name: &'static str,
+    limit: usize,
+    extract: Box<Extract<S>>,
+    /// The distinct values recorded so far, at most `limit`
+    values: Mutex<BTreeSet<String>>,
+}
+
+/// Labels for the duration of server calls, extracted from their first request
+///
+/// Every label has a limit of distinct values, so a misbehaving client can not
+/// blow up the number of series in the registry. Clones share the values that
+/// were recorded so far.
+pub struct MetricLabels<S: Service> {
+    labels: Vec<Arc<Label<S>>>,
+}
+
+impl<S: Service> MetricLabels<S> {
+    /// No labels
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a label named `name`, whose value is extracted from the first
+    /// request of every call by `extract`
+    ///
+    /// Calls for which `extract` returns `None` are recorded without this
+    /// label. At most `limit` distinct values are recorded, calls with other
+    /// values after that are recorded with [OVERFLOW_VALUE].
+    ///
+    /// Panics if `name` is not a valid label name, or one of the labels that
+    /// are always set.
+    pub fn with_label<F>(mut self, name: &'static str, limit: usize, extract: F) -> Self
+    where
+        F: Fn(&S::Req) -> Option<String> + Send + Sync + 'static,
+    {
+        let valid = name
+            .chars()
+            .enumerate()
+            .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        assert!(
+            !name.is_empty() && !name.starts_with("__") && valid,
+            "invalid label name {name:?}"
+        );
+        assert!(
+            !RESERVED_LABELS.contains(&name) && self.labels.iter().all(|l| l.name != name),
+            "label {name:?} is already set"
+        );
+        self.labels.push(Arc::new(Label {
+            name,
+            limit,
+            extract: Box::new(extract),
+            values: Default::default(),
+        }));
+        self
+    }
+
+    /// The labels for a call with the first request `request`
+    pub(crate) fn extract(&self, request: &S::Req) -> Labels {
+        let mut labels = Labels::new();
+        for label in &self.labels {
+            let value = match (label.extract)(request) {
+                Some(value) => value,
+                None => continue,
+            };
+            let mut values = label.values.lock().unwrap();
+            let value = if values.contains(&value) {
+                value
+            } else if values.len() < label.limit {
+                values.insert(value.clone());
+                value
+            } else {
+                OVERFLOW_VALUE.to_string()
+            };
+            labels.push((label.name, value));
+        }
+        labels
+    }
+}
+
+impl<S: Service> Default for MetricLabels<S> {
+    fn default() -> Self {
+        Self { labels: Vec::new() }
+    }
+}
+
+impl<S: Service> Clone for MetricLabels<S> {
+    fn clone(&self) -> Self {
+        Self {
+            labels: self.labels.clone(),
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for MetricLabels<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.labels.iter().map(|label| label.name))
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn record(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    in_flight: Option<i64>,
+    rtt: Option<Duration>,
+    cwnd: Option<u64>,
+    lost_packets: u64,
+    congestion_events: u64,
+}
+
+type Method = (&'static str, &'static str);
+
+/// Compression counters of a message type
+#[derive(Debug, Default)]
+struct Compression {
+    input_bytes: u64,
+    output_bytes: u64,
+    skipped_frames: u64,
+}
+
+/// A counter of [Compression]: name, help, unit and value
+type CompressionCounter = (
+    &'static str,
+    &'static str,
+    Option<&'static str>,
+    fn(&Compression) -> u64,
+);
+
+#[derive(Debug, Default)]
+struct Registry {
+    client_duration: BTreeMap<(Method, Labels), Histogram>,
+    server_duration: BTreeMap<(Method, Labels), Histogram>,
+    server_in_flight: i64,
+    connections: BTreeMap<SocketAddr, Connection>,
+    compression: BTreeMap<&'static str, Compression>,
+}
+
+impl Registry {
+    fn encode(&self, out: &mut String) -> fmt::Result {
+        let durations = [
+            (
+                "rpc_client_duration_seconds",
+                "Duration of outbound rpc calls",
+                &self.client_duration,
+            ),
+            (
+                "rpc_server_duration_seconds",
+                "Duration of inbound rpc calls",
+                &self.server_duration,
+            ),
+        ];
+        for (name, help, histograms) in durations {
+            header(out, name, "histogram", help, Some("seconds"))?;
+            for (((service, method), extracted), histogram) in histograms {
+                let mut labels = format!(
+                    "rpc_system=\"{}\",rpc_service=\"{}\",rpc_method=\"{}\"",
+                    RPC_SYSTEM,
+                    escape(service),
+                    escape(method)
+                );
+                for (name, value) in extracted {
+                    write!(labels, ",{name}=\"{}\"", escape(value))?;
+                }
+                let mut cumulative = 0;
+                for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}")?;
+                }
+                writeln!(
+                    out,
+                    "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                    histogram.count
+                )?;
+                writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum)?;
+                writeln!(out, "{name}_count{{{labels}}} {}", histogram.count)?;
+            }
+        }
+
+        header(
+            out,
+            "rpc_server_in_flight",
+            "gauge",
+            "Number of inbound rpc calls in flight",
+            None,
+        )?;
+        writeln!(
+            out,
+            "rpc_server_in_flight{{rpc_system=\"{RPC_SYSTEM}\"}} {}",
+            self.server_in_flight
+        )?;
+        let connections = &self.connections;
+        header(
+            out,
+            "rpc_server_connection_in_flight",
+            "gauge",
+            "Number of inbound rpc calls in flight per connection",
+            None,
+        )?;
+        for (peer, connection) in connections {
+            if let Some(in_flight) = connection.in_flight {
+                writeln!(
+                    out,
+                    "rpc_server_connection_in_flight{{{}}} {}",
+                    peer_labels(peer),
+                    in_flight
+                )?;
+            }
+        }
+        header(
+            out,
+            "quic_connection_rtt_seconds",
+            "gauge",
+            "Round trip time estimate of connections",
+            Some("seconds"),
+        )?;
+        for (peer, connection) in connections {
+            if let Some(rtt) = connection.rtt {
+                writeln!(
+                    out,
+                    "quic_connection_rtt_seconds{{{}}} {}",
+                    peer_labels(peer),
+                    rtt.as_secs_f64()
+                )?;
+            }
+        }
+        header(
+            out,
+            "quic_connection_cwnd_bytes",
+            "gauge",
+            "Congestion window of connections",
+            Some("bytes"),
+        )?;
+        for (peer, connection) in connections {
+            if let Some(cwnd) = connection.cwnd {
+                writeln!(
+                    out,
+                    "quic_connection_cwnd_bytes{{{}}} {}",
+                    peer_labels(peer),
+                    cwnd
+                )?;
+            }
+        }
+        header(
+            out,
+            "quic_connection_lost_packets",
+            "counter",
+            "Packets lost on connections",
+            None,
+        )?;
+        for (peer, connection) in connections.iter().filter(|(_, c)| c.rtt.is_some()) {
+            writeln!(
+                out,
+                "quic_connection_lost_packets_total{{{}}} {}",
+                peer_labels(peer),
+                connection.lost_packets
+            )?;
+        }
+        header(
+            out,
+            "quic_connection_congestion_events",
+            "counter",
+            "Congestion events on connections, due to loss or ECN-CE",
+            None,
+        )?;
+        for (peer, connection) in connections.iter().filter(|(_, c)| c.rtt.is_some()) {
+            writeln!(
+                out,
+                "quic_connection_congestion_events_total{{{}}} {}",
+                peer_labels(peer),
+                connection.congestion_events
+            )?;
+        }
+        if !self.compression.is_empty() {
+            let counters: [CompressionCounter; 3] = [
+                (
+                    "rpc_compression_input_bytes",
+                    "Bytes of compressed frames before compression",
+                    Some("bytes"),
+                    |c| c.input_bytes,
+                ),
+                (
+                    "rpc_compression_output_bytes",
+                    "Bytes of compressed frames after compression",
+                    Some("bytes"),
+                    |c| c.output_bytes,
+                ),
+                (
+                    "rpc_compression_skipped_frames",
+                    "Frames sent uncompressed since their message type does not compress",
+                    None,
+                    |c| c.skipped_frames,
+                ),
+            ];
+            for (name, help, unit, value) in counters {
+                header(out, name, "counter", help, unit)?;
+                for (message, compression) in &self.compression {
+                    writeln!(
+                        out,
+                        "{name}_total{{rpc_system=\"{RPC_SYSTEM}\",rpc_message=\"{}\"}} {}",
+                        escape(message),
+                        value(compression)
+                    )?;
+                }
+            }
+        }
+        #[cfg(feature = "alloc-counters")]
+        {
+            let allocations = crate::allocations::AllocationStats::total();
+            if allocations.allocations > 0 {
+                header(
+                    out,
+                    "process_allocations",
+                    "counter",
+                    "Heap allocations of the process",
+                    None,
+                )?;
+                writeln!(out, "process_allocations_total {}", allocations.allocations)?;
+                header(
+                    out,
+                    "process_allocated_bytes",
+                    "counter",
+                    "Bytes allocated on the heap by the process",
+                    Some("bytes"),
+                )?;
+                writeln!(out, "process_allocated_bytes_total {}", allocations.bytes)?;
+            }
+        }
+        writeln!(out, "# EOF")
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str, unit: Option<&str>) -> fmt::Result {
+    writeln!(out, "# TYPE {name} {kind}")?;
+    if let Some(unit) = unit {
+        writeln!(out, "# UNIT {name} {unit}")?;
+    }
+    writeln!(out, "# HELP {name} {help}.")
+}
+
+fn peer_labels(peer: &SocketAddr) -> String {
+    format!("rpc_system=\"{RPC_SYSTEM}\",net_sock_peer_addr=\"{peer}\"")
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Record the duration of a call
+pub(crate) fn call_duration(
+    side: Side,
+    service: &'static str,
+    method: &'static str,
+    labels: Labels,
+    elapsed: Duration,
+) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let histograms = match side {
+        Side::Client => &mut registry.client_duration,
+        Side::Server => &mut registry.server_duration,
+    };
+    histograms
+        .entry(((service, method), labels))
+        .or_default()
+        .record(elapsed.as_secs_f64());
+}
+
+/// Start recording the metrics of a new connection
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_opened(peer: SocketAddr) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.connections.insert(peer, Connection::default());
+}
+
+/// Remove the metrics of a closed connection
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_closed(peer: SocketAddr) {
+    REGISTRY.lock().unwrap().connections.remove(&peer);
+}
+
+/// Record a change of the number of calls in flight on a server
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn server_in_flight(delta: i64) {
+    REGISTRY.lock().unwrap().server_in_flight += delta;
+}
+
+/// Record a change of the number of calls in flight on a server connection
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_in_flight(peer: SocketAddr, delta: i64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some(connection) = registry.connections.get_mut(&peer) {
+        *connection.in_flight.get_or_insert(0) += delta;
+    }
+}
+
+/// Record a sample of the path statistics of a connection
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_stats(
+    peer: SocketAddr,
+    rtt: Duration,
+    cwnd: u64,
+    lost_packets: u64,
+    congestion_events: u64,
+) {
+    let mut registry = REGISTRY.lock().unwrap();
+    if let Some(connection) = registry.connections.get_mut(&peer) {
+        connection.rtt = Some(rtt);
+        connection.cwnd = Some(cwnd);
+        connection.lost_packets += lost_packets;
+        connection.congestion_events += congestion_events;
+    }
+}
+
+/// Record a frame of `message` that was compressed from `input` to `output` bytes, or skipped
+#[cfg(feature = "zstd-compression")]
+pub(crate) fn compression(message: &'static str, input: u64, output: Option<u64>) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let compression = registry.compression.entry(message).or_default();
+    match output {
+        Some(output) => {
+            compression.input_bytes += input;
+            compression.output_bytes += output;
+        }
+        None => compression.skipped_frames += 1,
+    }
+}
@@ -0,0 +1,435 @@
+//! Offline connections that answer calls with canned responses
+//!
+//! With the `offline` feature, an [RpcClient](crate::RpcClient) can be created
+//! with an [OfflineConnection] instead of a transport, so code that uses the
+//! client can be developed and tried out before the server exists. The client
+//! has the same type as with any other connection, apart from the connection
+//! type parameter.
+//!
+//! Calls are answered by [Fixtures]. These can be closures that look at the
+//! request and return the responses, for example:
+//!
+//! ```ignore
+//! let connection = OfflineConnection::<StoreService>::new(|req: StoreRequest, _updates| match req {
+//!     StoreRequest::Get(Get(key)) => offline::respond(GetResponse(Some(key))),
+//!     // no responses, so the call fails with an early close
+//!     _ => stream::empty().boxed(),
+//! });
+//! let client = RpcClient::new(connection);
+//! ```
+//!
+//! Fixtures can also be recorded from the calls to a real server, using a
+//! [RecordingConnection], and replayed later as a [Session]. Sessions can be
+//! serialized, so they can be checked in next to the code that uses them.
+use crate::{
+    transport::{Capabilities, Connection, ConnectionCommon, ConnectionErrors},
+    Service, ServiceConnection,
+};
+use futures::{
+    channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, Sink, Stream, StreamExt,
+};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    fmt, mem,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The updates of a call, after its first request
+pub type Updates<S> = BoxStream<'static, <S as Service>::Req>;
+
+/// The responses to a call
+pub type Responses<S> = BoxStream<'static, <S as Service>::Res>;
+
+/// Provides the responses to calls of an [OfflineConnection]
+///
+/// This is implemented for closures that take the first request and the
+/// updates of a call, and for recorded [Session]s.
+pub trait Fixtures<S: Service>: Send + Sync + 'static {
+    /// Answer a call
+    ///
+    /// The call ends when the responses end. Without any responses, the client
+    /// sees the call as closed early. Updates that are not consumed are
+    /// dropped, which fails sending further updates.
+    fn call(&self, req: S::Req, updates: Updates<S>) -> Responses<S>;
+}
+
+impl<S, F> Fixtures<S> for F
+where
+    S: Service,
+    F: Fn(S::Req, Updates<S>) -> Responses<S> + Send + Sync + 'static,
+{
+    fn call(&self, req: S::Req, updates: Updates<S>) -> Responses<S> {
+        self(req, updates)
+    }
+}
+
+/// Answer a call with a single response
+///
+/// The response type is usually inferred as the response type of the service.
+pub fn respond<T: Send + 'static>(res: impl Into<T>) -> BoxStream<'static, T> {
+    stream::once(future::ready(res.into())).boxed()
+}
+
+/// Answer a call with a sequence of responses
+pub fn respond_all<T, I>(res: I) -> BoxStream<'static, T>
+where
+    T: Send + 'static,
+    I: IntoIterator,
+    I::Item: Into<T> + 'static,
+    I::IntoIter: Send + 'static,
+{
+    stream::iter(res).map(Into::into).boxed()
+}
+
+/// A connection that answers all calls with [Fixtures], without a server
+///
+/// Every channel is served by a new tokio task, so this needs to be used from
+/// within a tokio runtime.
+pub struct OfflineConnection<S: Service> {
+    fixtures: Arc<dyn Fixtures<S>>,
+    latency: Duration,
+}
+
+impl<S: Service> OfflineConnection<S> {
+    /// Create a new offline connection, answering calls with `fixtures`
+    pub fn new(fixtures: impl Fixtures<S>) -> Self {
+        Self {
+            fixtures: Arc::new(fixtures),
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// Delay the responses to each call, to simulate a remote server
+    ///
+    /// The default is no delay.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+impl<S: Service> Clone for OfflineConnection<S> {
+    fn clone(&self) -> Self {
+        Self {
+            fixtures: self.fixtures.clone(),
+            latency: self.latency,
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for OfflineConnection<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OfflineConnection")
+            .field("latency", &self.latency)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S: Service> ConnectionErrors for OfflineConnection<S> {
+    type OpenError = Infallible;
+    type SendError = SendError;
+    type RecvError = Infallible;
+}
+
+impl<S: Service> ConnectionCommon<S::Res, S::Req> for OfflineConnection<S> {
+    type RecvStream = RecvStream<S::Res>;
+    type SendSink = UnboundedSender<S::Req>;
+    // nothing leaves the process
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<S: Service> Connection<S::Res, S::Req> for OfflineConnection<S> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), Infallible>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let (send, mut requests) = mpsc::unbounded();
+        let (responses_tx, responses) = mpsc::unbounded();
+        let fixtures = self.fixtures.clone();
+        let latency = self.latency;
+        tokio::spawn(async move {
+            if let Some(req) = requests.next().await {
+                let mut responses = fixtures.call(req, requests.boxed());
+                if !latency.is_zero() {
+                    tokio::time::sleep(latency).await;
+                }
+                while let Some(res) = responses.next().await {
+                    if responses_tx.unbounded_send(res).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        future::ok((send, RecvStream(responses)))
+    }
+}
+
+/// Receive stream of an [OfflineConnection]
+#[derive(Debug)]
+pub struct RecvStream<T>(UnboundedReceiver<T>);
+
+impl<T> Stream for RecvStream<T> {
+    type Item = result::Result<T, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx).map(|x| x.map(Ok))
+    }
+}
+
+/// A call recorded by a [RecordingConnection]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct RecordedCall<S: Service> {
+    /// The first request of the call
+    pub request: S::Req,
+    /// The updates the client sent after the first request
+    pub updates: Vec<S::Req>,
+    /// The responses the server sent
+    pub responses: Vec<S::Res>,
+}
+
+/// Calls recorded by a [RecordingConnection], to be replayed as [Fixtures]
+///
+/// Calls are answered with the responses of the first recorded call with an
+/// equal first request. Updates are ignored. Calls without a recorded call are
+/// answered without any responses.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Session<S: Service> {
+    /// The recorded calls, in the order they ended
+    pub calls: Vec<RecordedCall<S>>,
+}
+
+impl<S: Service> Default for Session<S> {
+    fn default() -> Self {
+        Self { calls: Vec::new() }
+    }
+}
+
+impl<S> Fixtures<S> for Session<S>
+where
+    S: Service,
+    S::Req: PartialEq,
+    S::Res: Clone,
+{
+    fn call(&self, req: S::Req, updates: Updates<S>) -> Responses<S> {
+        let responses = match self.calls.iter().find(|call| call.request == req) {
+            Some(call) => call.responses.clone(),
+            None => {
+                tracing::warn!("no recorded call for {:?}", req);
+                Vec::new()
+            }
+        };
+        // keep accepting updates, so the client does not fail sending them
+        tokio::spawn(updates.for_each(|_| future::ready(())));
+        stream::iter(responses).boxed()
+    }
+}
+
+/// A connection that records the calls made through another connection
+///
+/// The recorded calls can be taken out as a [Session] at any time. Calls are
+/// added once both the send and the receive side of their channel are dropped.
+pub struct RecordingConnection<S: Service, C> {
+    inner: C,
+    calls: Arc<Mutex<Vec<RecordedCall<S>>>>,
+}
+
+impl<S: Service, C> RecordingConnection<S, C> {
+    /// Record the calls made through `inner`
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            calls: Default::default(),
+        }
+    }
+
+    /// Take the calls recorded so far
+    pub fn take_session(&self) -> Session<S> {
+        Session {
+            calls: mem::take(&mut *self.calls.lock().unwrap()),
+        }
+    }
+}
+
+impl<S: Service, C: Clone> Clone for RecordingConnection<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            calls: self.calls.clone(),
+        }
+    }
+}
+
+impl<S: Service, C: fmt::Debug> fmt::Debug for RecordingConnection<S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingConnection")
+            .field("inner", &self.inner)
+            .field("calls", &self.calls.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl<S: Service, C: ConnectionErrors> ConnectionErrors for RecordingConnection<S, C> {
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<S, C> ConnectionCommon<S::Res, S::Req> for RecordingConnection<S, C>
+where
+    S: Service,
+    S::Req: Clone,
+    S::Res: Clone,
+    C: ServiceConnection<S>,
+{
+    type RecvStream = RecordingStream<S, C::RecvStream>;
+    type SendSink = RecordingSink<S, C::SendSink>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<S, C> Connection<S::Res, S::Req> for RecordingConnection<S, C>
+where
+    S: Service,
+    S::Req: Clone,
+    S::Res: Clone,
+    C: ServiceConnection<S>,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), C::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let calls = self.calls.clone();
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let call = Arc::new(PendingCall {
+                call: Mutex::new((None, Vec::new(), Vec::new())),
+                calls,
+            });
+            Ok((
+                RecordingSink {
+                    inner: send,
+                    call: call.clone(),
+                },
+                RecordingStream { inner: recv, call },
+            ))
+        }
+        .boxed()
+    }
+}
+
+/// A call that is still going on, added to the recorded calls when dropped
+struct PendingCall<S: Service> {
+    #[allow(clippy::type_complexity)]
+    call: Mutex<(Option<S::Req>, Vec<S::Req>, Vec<S::Res>)>,
+    calls: Arc<Mutex<Vec<RecordedCall<S>>>>,
+}
+
+impl<S: Service> Drop for PendingCall<S> {
+    fn drop(&mut self) {
+        let (request, updates, responses) = mem::take(&mut *self.call.lock().unwrap());
+        if let Some(request) = request {
+            self.calls.lock().unwrap().push(RecordedCall {
+                request,
+                updates,
+                responses,
+            });
+        }
+    }
+}
+
+/// Send sink of a [RecordingConnection]
+#[pin_project]
+pub struct RecordingSink<S: Service, T> {
+    #[pin]
+    inner: T,
+    call: Arc<PendingCall<S>>,
+}
+
+impl<S: Service, T: fmt::Debug> fmt::Debug for RecordingSink<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingSink")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T> Sink<S::Req> for RecordingSink<S, T>
+where
+    S: Service,
+    S::Req: Clone,
+    T: Sink<S::Req>,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: S::Req) -> Result<(), Self::Error> {
+        let this = self.project();
+        {
+            let mut call = this.call.call.lock().unwrap();
+            match call.0 {
+                None => call.0 = Some(item.clone()),
+                Some(_) => call.1.push(item.clone()),
+            }
+        }
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Receive stream of a [RecordingConnection]
+#[pin_project]
+pub struct RecordingStream<S: Service, T> {
+    #[pin]
+    inner: T,
+    call: Arc<PendingCall<S>>,
+}
+
+impl<S: Service, T: fmt::Debug> fmt::Debug for RecordingStream<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordingStream")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T, E> Stream for RecordingStream<S, T>
+where
+    S: Service,
+    S::Res: Clone,
+    T: Stream<Item = result::Result<S::Res, E>>,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = this.inner.poll_next(cx);
+        if let Poll::Ready(Some(Ok(res))) = &item {
+            this.call.call.lock().unwrap().2.push(res.clone());
+        }
+        item
+    }
+}
@@ -0,0 +1,465 @@
+//! Streams opened by the server
+//!
+//! Calls are started by clients, so a server can only answer. To push state
+//! to a connected client on its own, e.g. to sync incremental state, the client
+//! starts a single long running push call with [listen], and the server opens
+//! any number of typed streams toward the client on it. Every stream starts
+//! with a header, followed by its items.
+//!
+//! The push call is declared on the service with
+//! [declare_server_initiated](crate::declare_server_initiated), with the message
+//! that starts it, the header and the item type of the streams. The request and
+//! response enums of the service need variants for [PushControl] and
+//! [Pushed] respectively:
+//!
+//! ```ignore
+//! declare_server_initiated!(SyncService, Listen, SyncStart, SyncItem);
+//! ```
+//!
+//! On the server side, [serve] turns the updates of the call into the response
+//! stream of the handler, and returns a [Pusher] to open streams with:
+//!
+//! ```ignore
+//! Request::Listen(msg) => {
+//!     chan.bidi_streaming(msg, clients, |clients, _req, updates| {
+//!         let (pusher, responses) = push::serve(updates);
+//!         clients.add(pusher);
+//!         responses
+//!     })
+//!     .await
+//! }
+//! ```
+//!
+//! [Pusher::open] returns a [PushSink] for the items of the new stream, and
+//! the client gets the header and a [PushedStream] of the items from
+//! [Incoming::accept].
+//!
+//! The server closes a stream by dropping its sink. The client closes it by
+//! dropping its stream, after which sending to the sink fails. The server
+//! learns about this with the next item it sends. The push call ends once the
+//! client drops [Incoming] and all its streams, or the server drops all
+//! [Pusher]s and sinks.
+use crate::{
+    client::{BidiError, BidiItemError},
+    message::BidiStreamingMsg,
+    transport::ConnectionErrors,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{
+    channel::mpsc,
+    future,
+    stream::{self, AbortHandle, BoxStream, SelectAll},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error,
+    fmt::{self, Debug},
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc as tokio_mpsc;
+
+/// Default number of items that are buffered for each pushed stream
+pub const DEFAULT_BUFFER: usize = 16;
+
+/// Update of a push call
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PushControl {
+    /// The client dropped the stream with this id
+    Close(u64),
+}
+
+/// Response of a push call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pushed<H, T> {
+    /// The server opened a stream with this id and header
+    Open(u64, H),
+    /// An item of an open stream
+    Item(u64, T),
+    /// The stream with this id ended
+    Closed(u64),
+}
+
+/// Serve a push call
+///
+/// Returns the [Pusher] to open streams with, and the stream of responses for
+/// the handler of the call.
+pub fn serve<U, H, T>(updates: U) -> (Pusher<H, T>, PushStream<U, H, T>)
+where
+    U: Stream<Item = PushControl>,
+    H: Send + 'static,
+    T: Send + 'static,
+{
+    let (opened_tx, opened) = mpsc::unbounded();
+    let pusher = Pusher {
+        opened: opened_tx,
+        next_id: Arc::new(AtomicU64::new(0)),
+    };
+    let responses = PushStream {
+        updates: Box::pin(updates),
+        opened: Some(opened),
+        streams: SelectAll::new(),
+        open: HashMap::new(),
+    };
+    (pusher, responses)
+}
+
+/// A stream that was opened but not yet sent to the client
+type Opened<H, T> = (u64, H, mpsc::Receiver<T>);
+
+/// Opens streams toward the client of a push call, created by [serve]
+///
+/// Clones open streams on the same call.
+pub struct Pusher<H, T> {
+    opened: mpsc::UnboundedSender<Opened<H, T>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<H, T> Clone for Pusher<H, T> {
+    fn clone(&self) -> Self {
+        Self {
+            opened: self.opened.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<H, T> Debug for Pusher<H, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pusher")
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl<H, T> Pusher<H, T> {
+    /// Open a stream with `header`, buffering [DEFAULT_BUFFER] items
+    pub fn open(&self, header: H) -> result::Result<PushSink<T>, PushClosed> {
+        self.open_with_buffer(header, DEFAULT_BUFFER)
+    }
+
+    /// Open a stream with `header`, buffering `buffer` items
+    ///
+    /// Sending to the sink waits while the buffer is full.
+    pub fn open_with_buffer(
+        &self,
+        header: H,
+        buffer: usize,
+    ) -> result::Result<PushSink<T>, PushClosed> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (send, recv) = mpsc::channel(buffer);
+        self.opened
+            .unbounded_send((id, header, recv))
+            .map_err(|_| PushClosed)?;
+        Ok(PushSink { id, send })
+    }
+
+    /// Whether the push call has ended, so no more streams can be opened
+    pub fn is_closed(&self) -> bool {
+        self.opened.is_closed()
+    }
+}
+
+/// Sink for the items of a stream opened by a [Pusher]
+///
+/// Dropping the sink ends the stream.
+#[pin_project]
+pub struct PushSink<T> {
+    id: u64,
+    #[pin]
+    send: mpsc::Sender<T>,
+}
+
+impl<T> Debug for PushSink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushSink").field("id", &self.id).finish()
+    }
+}
+
+impl<T> PushSink<T> {
+    /// The id of the stream
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<T> Sink<T> for PushSink<T> {
+    type Error = PushClosed;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().send.poll_ready(cx).map_err(|_| PushClosed)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.project().send.start_send(item).map_err(|_| PushClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().send.poll_flush(cx).map_err(|_| PushClosed)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().send.poll_close(cx).map_err(|_| PushClosed)
+    }
+}
+
+/// Stream of responses of a push call, created by [serve]
+#[pin_project]
+pub struct PushStream<U, H, T> {
+    updates: Pin<Box<U>>,
+    opened: Option<mpsc::UnboundedReceiver<Opened<H, T>>>,
+    streams: SelectAll<BoxStream<'static, (u64, Option<T>)>>,
+    open: HashMap<u64, AbortHandle>,
+}
+
+impl<U, H, T> Debug for PushStream<U, H, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushStream")
+            .field("open", &self.open.keys().collect::<Vec<_>>())
+            .field("pushers_closed", &self.opened.is_none())
+            .finish()
+    }
+}
+
+impl<U, H, T> Stream for PushStream<U, H, T>
+where
+    U: Stream<Item = PushControl>,
+    H: Send + 'static,
+    T: Send + 'static,
+{
+    type Item = Pushed<H, T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        loop {
+            match this.updates.as_mut().poll_next(cx) {
+                Poll::Ready(Some(PushControl::Close(id))) => {
+                    if let Some(handle) = this.open.remove(&id) {
+                        handle.abort();
+                    }
+                }
+                // the client is gone, which drops all streams
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+        if let Some(opened) = this.opened.as_mut() {
+            match opened.poll_next_unpin(cx) {
+                Poll::Ready(Some((id, header, items))) => {
+                    let (items, handle) = stream::abortable(items);
+                    this.open.insert(id, handle);
+                    this.streams.push(
+                        items
+                            .map(move |item| (id, Some(item)))
+                            .chain(stream::once(future::ready((id, None))))
+                            .boxed(),
+                    );
+                    return Poll::Ready(Some(Pushed::Open(id, header)));
+                }
+                Poll::Ready(None) => *this.opened = None,
+                Poll::Pending => {}
+            }
+        }
+        match this.streams.poll_next_unpin(cx) {
+            Poll::Ready(Some((id, Some(item)))) => Poll::Ready(Some(Pushed::Item(id, item))),
+            Poll::Ready(Some((id, None))) => {
+                this.open.remove(&id);
+                Poll::Ready(Some(Pushed::Closed(id)))
+            }
+            // no streams are open, so we are waiting for new ones
+            Poll::Ready(None) if this.opened.is_some() => Poll::Pending,
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Error when the client closed a pushed stream, or the whole push call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushClosed;
+
+impl fmt::Display for PushClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for PushClosed {}
+
+/// Start a push call
+///
+/// The responses are distributed to the streams on a spawned task, so this
+/// must be called from within a tokio runtime.
+pub async fn listen<S, C, M, H, T>(
+    client: &RpcClient<S, C>,
+    msg: M,
+) -> result::Result<Incoming<C, H, T>, BidiError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    M: BidiStreamingMsg<S, Update = PushControl, Response = Pushed<H, T>>,
+    PushControl: Into<S::Req>,
+    H: Send + 'static,
+    T: Send + 'static,
+{
+    let (send, recv) = client.bidi(msg).await?;
+    let (accepted_tx, accepted) = tokio_mpsc::unbounded_channel();
+    tokio::spawn(distribute(send, recv, accepted_tx));
+    Ok(Incoming(accepted))
+}
+
+/// A stream the server opened, with its header
+type Accepted<C, H, T> = result::Result<(H, PushedStream<T>), BidiItemError<C>>;
+
+/// Distribute the responses of a push call to its streams
+async fn distribute<Si, St, C, H, T>(
+    mut send: Si,
+    mut recv: St,
+    accepted: tokio_mpsc::UnboundedSender<Accepted<C, H, T>>,
+) where
+    Si: Sink<PushControl> + Unpin,
+    St: Stream<Item = result::Result<Pushed<H, T>, BidiItemError<C>>> + Unpin,
+    C: ConnectionErrors,
+{
+    let mut streams = HashMap::<u64, tokio_mpsc::UnboundedSender<T>>::new();
+    let mut listening = true;
+    loop {
+        let res = if listening {
+            tokio::select! {
+                res = recv.next() => res,
+                _ = accepted.closed() => {
+                    listening = false;
+                    None
+                }
+            }
+        } else {
+            recv.next().await
+        };
+        match res {
+            Some(Ok(Pushed::Open(id, header))) => {
+                let (items_tx, items) = tokio_mpsc::unbounded_channel();
+                if accepted
+                    .send(Ok((header, PushedStream { id, items })))
+                    .is_ok()
+                {
+                    streams.insert(id, items_tx);
+                } else {
+                    send.send(PushControl::Close(id)).await.ok();
+                }
+            }
+            Some(Ok(Pushed::Item(id, item))) => {
+                if let Some(items) = streams.get(&id) {
+                    if items.send(item).is_err() {
+                        streams.remove(&id);
+                        send.send(PushControl::Close(id)).await.ok();
+                    }
+                }
+            }
+            Some(Ok(Pushed::Closed(id))) => {
+                streams.remove(&id);
+            }
+            Some(Err(cause)) => {
+                accepted.send(Err(cause)).ok();
+                break;
+            }
+            None if listening => break,
+            None => {}
+        }
+        if !listening {
+            // nobody is interested in new streams, so end the call after the
+            // open ones are dropped
+            streams.retain(|_, items| !items.is_closed());
+            if streams.is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// Streams the server opens on a push call, created by [listen]
+pub struct Incoming<C: ConnectionErrors, H, T>(tokio_mpsc::UnboundedReceiver<Accepted<C, H, T>>);
+
+impl<C: ConnectionErrors, H, T> Debug for Incoming<C, H, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming").finish()
+    }
+}
+
+impl<C: ConnectionErrors, H, T> Incoming<C, H, T> {
+    /// Wait for the next stream the server opens
+    ///
+    /// Returns `None` once the push call has ended.
+    pub async fn accept(&mut self) -> Option<Accepted<C, H, T>> {
+        self.0.recv().await
+    }
+}
+
+/// The items of a stream opened by the server
+///
+/// Dropping the stream closes it.
+pub struct PushedStream<T> {
+    id: u64,
+    items: tokio_mpsc::UnboundedReceiver<T>,
+}
+
+impl<T> Debug for PushedStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushedStream")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<T> PushedStream<T> {
+    /// The id of the stream
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<T> Stream for PushedStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.items.poll_recv(cx)
+    }
+}
+
+/// Declare a message to start a push call of a service
+///
+/// Example:
+/// ```ignore
+/// declare_server_initiated!(SyncService, Listen, SyncStart, SyncItem);
+/// ```
+///
+/// This is equivalent to:
+/// ```ignore
+/// impl Msg<SyncService> for Listen {
+///     type Pattern = BidiStreaming;
+/// }
+///
+/// impl BidiStreamingMsg<SyncService> for Listen {
+///     type Update = PushControl;
+///     type Response = Pushed<SyncStart, SyncItem>;
+/// }
+/// ```
+#[macro_export]
+macro_rules! declare_server_initiated {
+    ($service:ty, $m_input:ty, $m_header:ty, $m_item:ty) => {
+        impl $crate::message::Msg<$service> for $m_input {
+            type Pattern = $crate::message::BidiStreaming;
+        }
+        impl $crate::message::BidiStreamingMsg<$service> for $m_input {
+            type Update = $crate::push::PushControl;
+            type Response = $crate::push::Pushed<$m_header, $m_item>;
+        }
+    };
+}
@@ -0,0 +1,342 @@
+//! Server streaming calls that carry a filter for the server to apply
+//!
+//! Many services have "subscribe with filter" endpoints, where the client only
+//! wants the items that match some condition. Filtering on the client wastes
+//! bandwidth, and every service inventing its own filter type makes them hard
+//! to use consistently. A [Filter] is a serializable predicate over the named
+//! fields of the items, that is sent as part of the request:
+//!
+//! ```
+//! use quic_rpc::query::{field, Filter};
+//!
+//! let filter: Filter = field("status").eq("open").and(field("priority").ge(2));
+//! ```
+//!
+//! Requests that carry a filter implement [QueryMsg]. On the server side,
+//! [handler] takes the filter out of the request and passes it to the handler
+//! as a separate argument, so the handler can push it down to wherever the
+//! items come from, e.g. an index lookup for [Filter::required] values or a
+//! database query:
+//!
+//! ```ignore
+//! Request::Search(msg) => chan.server_streaming(msg, handler, query::handler(Handler::search)).await,
+//! ```
+//!
+//! Parts of a filter that can not be pushed down can be applied to the
+//! resulting items with [filtered], for items that implement [Fields].
+#[cfg(doc)]
+use crate::message::ServerStreamingMsg;
+use futures::{future, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+/// The value of a field, as used in a [Filter]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Value {
+    /// No value
+    Null,
+    /// A boolean
+    Bool(bool),
+    /// A signed integer
+    Int(i64),
+    /// An unsigned integer
+    UInt(u64),
+    /// A floating point number
+    Float(f64),
+    /// A string
+    Str(String),
+    /// Binary data
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    /// Compare two values
+    ///
+    /// Numbers compare by value, no matter if they are signed, unsigned or
+    /// floating point. Strings and binary data compare lexicographically.
+    /// Values of other kinds, or of different kinds, are only equal to
+    /// themselves and have no order.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        use Value::*;
+        match (self, other) {
+            (Int(a), Int(b)) => Some(a.cmp(b)),
+            (UInt(a), UInt(b)) => Some(a.cmp(b)),
+            (Int(a), UInt(b)) => Some(compare_signed(*a, *b)),
+            (UInt(a), Int(b)) => Some(compare_signed(*b, *a).reverse()),
+            (Float(a), Float(b)) => a.partial_cmp(b),
+            (Float(a), Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Float(a), UInt(b)) => a.partial_cmp(&(*b as f64)),
+            (Int(a), Float(b)) => (*a as f64).partial_cmp(b),
+            (UInt(a), Float(b)) => (*a as f64).partial_cmp(b),
+            (Str(a), Str(b)) => Some(a.cmp(b)),
+            (Bytes(a), Bytes(b)) => Some(a.cmp(b)),
+            _ if self == other => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+/// Compare a signed with an unsigned integer
+fn compare_signed(a: i64, b: u64) -> Ordering {
+    if a < 0 {
+        Ordering::Less
+    } else {
+        (a as u64).cmp(&b)
+    }
+}
+
+macro_rules! value_from {
+    ($($ty:ty => $variant:ident),*) => {
+        $(
+            impl From<$ty> for Value {
+                fn from(value: $ty) -> Self {
+                    Value::$variant(value.into())
+                }
+            }
+        )*
+    };
+}
+
+value_from!(
+    bool => Bool,
+    i8 => Int, i16 => Int, i32 => Int, i64 => Int,
+    u8 => UInt, u16 => UInt, u32 => UInt, u64 => UInt,
+    f32 => Float, f64 => Float,
+    String => Str, &str => Str,
+    Vec<u8> => Bytes
+);
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+
+/// A serializable predicate over the named fields of an item
+///
+/// A condition on a field that the item does not have is false.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum Filter {
+    /// Matches all items
+    #[default]
+    All,
+    /// The field is equal to the value
+    Eq(String, Value),
+    /// The field is not equal to the value
+    Ne(String, Value),
+    /// The field is less than the value
+    Lt(String, Value),
+    /// The field is less than or equal to the value
+    Le(String, Value),
+    /// The field is greater than the value
+    Gt(String, Value),
+    /// The field is greater than or equal to the value
+    Ge(String, Value),
+    /// The field is equal to one of the values
+    In(String, Vec<Value>),
+    /// The field is a string that starts with the prefix
+    Prefix(String, String),
+    /// All of the filters match
+    And(Vec<Filter>),
+    /// Any of the filters match
+    Or(Vec<Filter>),
+    /// The filter does not match
+    Not(Box<Filter>),
+}
+
+/// Start a condition on the field `name`, see [Field]
+pub fn field(name: impl Into<String>) -> Field {
+    Field(name.into())
+}
+
+/// A field of an item, to build conditions on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field(String);
+
+impl Field {
+    /// The field is equal to `value`
+    pub fn eq(self, value: impl Into<Value>) -> Filter {
+        Filter::Eq(self.0, value.into())
+    }
+
+    /// The field is not equal to `value`
+    pub fn ne(self, value: impl Into<Value>) -> Filter {
+        Filter::Ne(self.0, value.into())
+    }
+
+    /// The field is less than `value`
+    pub fn lt(self, value: impl Into<Value>) -> Filter {
+        Filter::Lt(self.0, value.into())
+    }
+
+    /// The field is less than or equal to `value`
+    pub fn le(self, value: impl Into<Value>) -> Filter {
+        Filter::Le(self.0, value.into())
+    }
+
+    /// The field is greater than `value`
+    pub fn gt(self, value: impl Into<Value>) -> Filter {
+        Filter::Gt(self.0, value.into())
+    }
+
+    /// The field is greater than or equal to `value`
+    pub fn ge(self, value: impl Into<Value>) -> Filter {
+        Filter::Ge(self.0, value.into())
+    }
+
+    /// The field is equal to one of `values`
+    pub fn one_of<V: Into<Value>>(self, values: impl IntoIterator<Item = V>) -> Filter {
+        Filter::In(self.0, values.into_iter().map(Into::into).collect())
+    }
+
+    /// The field is a string that starts with `prefix`
+    pub fn starts_with(self, prefix: impl Into<String>) -> Filter {
+        Filter::Prefix(self.0, prefix.into())
+    }
+}
+
+impl Filter {
+    /// Both this and `other` match
+    pub fn and(self, other: Filter) -> Filter {
+        match (self, other) {
+            (Filter::All, filter) | (filter, Filter::All) => filter,
+            (Filter::And(mut filters), Filter::And(other)) => {
+                filters.extend(other);
+                Filter::And(filters)
+            }
+            (Filter::And(mut filters), filter) => {
+                filters.push(filter);
+                Filter::And(filters)
+            }
+            (filter, other) => Filter::And(vec![filter, other]),
+        }
+    }
+
+    /// This or `other` matches
+    pub fn or(self, other: Filter) -> Filter {
+        match (self, other) {
+            (Filter::Or(mut filters), filter) => {
+                filters.push(filter);
+                Filter::Or(filters)
+            }
+            (filter, other) => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    /// This filter does not match
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> Filter {
+        match self {
+            Filter::Not(filter) => *filter,
+            filter => Filter::Not(Box::new(filter)),
+        }
+    }
+
+    /// The value `name` must be equal to for the filter to match, if any
+    ///
+    /// This is useful to push down the filter to an index: if the filter
+    /// requires a key, only the items with that key need to be considered.
+    pub fn required(&self, name: &str) -> Option<&Value> {
+        match self {
+            Filter::Eq(field, value) if field == name => Some(value),
+            Filter::And(filters) => filters.iter().find_map(|filter| filter.required(name)),
+            _ => None,
+        }
+    }
+
+    /// The names of all fields the filter refers to
+    pub fn fields(&self) -> Vec<&str> {
+        let mut fields = Vec::new();
+        self.collect_fields(&mut fields);
+        fields.sort_unstable();
+        fields.dedup();
+        fields
+    }
+
+    fn collect_fields<'a>(&'a self, fields: &mut Vec<&'a str>) {
+        match self {
+            Filter::All => {}
+            Filter::Eq(field, _)
+            | Filter::Ne(field, _)
+            | Filter::Lt(field, _)
+            | Filter::Le(field, _)
+            | Filter::Gt(field, _)
+            | Filter::Ge(field, _)
+            | Filter::In(field, _)
+            | Filter::Prefix(field, _) => fields.push(field),
+            Filter::And(filters) | Filter::Or(filters) => {
+                for filter in filters {
+                    filter.collect_fields(fields);
+                }
+            }
+            Filter::Not(filter) => filter.collect_fields(fields),
+        }
+    }
+
+    /// Check if `item` matches the filter
+    pub fn matches<I: Fields + ?Sized>(&self, item: &I) -> bool {
+        let compare = |name: &str, value: &Value, accept: fn(Ordering) -> bool| {
+            item.field(name)
+                .and_then(|field| field.compare(value))
+                .map_or(false, accept)
+        };
+        match self {
+            Filter::All => true,
+            Filter::Eq(name, value) => compare(name, value, Ordering::is_eq),
+            Filter::Ne(name, value) => compare(name, value, Ordering::is_ne),
+            Filter::Lt(name, value) => compare(name, value, Ordering::is_lt),
+            Filter::Le(name, value) => compare(name, value, Ordering::is_le),
+            Filter::Gt(name, value) => compare(name, value, Ordering::is_gt),
+            Filter::Ge(name, value) => compare(name, value, Ordering::is_ge),
+            Filter::In(name, values) => item.field(name).map_or(false, |field| {
+                values
+                    .iter()
+                    .any(|value| field.compare(value) == Some(Ordering::Equal))
+            }),
+            Filter::Prefix(name, prefix) => match item.field(name) {
+                Some(Value::Str(value)) => value.starts_with(prefix.as_str()),
+                _ => false,
+            },
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(item)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(item)),
+            Filter::Not(filter) => !filter.matches(item),
+        }
+    }
+}
+
+/// Items whose fields can be checked by a [Filter]
+pub trait Fields {
+    /// The value of the field `name`, or `None` if there is no such field
+    fn field(&self, name: &str) -> Option<Value>;
+}
+
+/// A request that carries a [Filter], usually a [ServerStreamingMsg]
+pub trait QueryMsg {
+    /// Take the filter out of the request, leaving [Filter::All]
+    fn take_filter(&mut self) -> Filter;
+}
+
+/// Wrap a handler that gets the filter of a [QueryMsg] as a separate argument
+///
+/// The returned function can be passed to
+/// [RpcChannel::server_streaming](crate::server::RpcChannel::server_streaming).
+/// The request the handler gets no longer contains the filter.
+pub fn handler<T, M, F, St>(f: F) -> impl FnOnce(T, M) -> St + Send + 'static
+where
+    M: QueryMsg,
+    F: FnOnce(T, M, Filter) -> St + Send + 'static,
+{
+    move |target, mut req| {
+        let filter = req.take_filter();
+        f(target, req, filter)
+    }
+}
+
+/// Only the items of `items` that match `filter`
+pub fn filtered<St>(items: St, filter: Filter) -> impl Stream<Item = St::Item>
+where
+    St: Stream,
+    St::Item: Fields,
+{
+    items.filter(move |item| future::ready(filter.matches(item)))
+}
@@ -0,0 +1,217 @@
+//! Sampled capture of client call payloads
+//!
+//! With the `payload-sampling` feature, the serialized request and responses
+//! of a configurable fraction of client calls are recorded in an in-process
+//! ring buffer. This is useful to debug issues that only happen rarely, where
+//! logging all payloads would be too expensive.
+//!
+//! Sampling is off by default, enable it with [configure]. Samples can be
+//! retrieved with [samples], or rendered with [encode]. The hyper transport
+//! can serve them on `/debug/samples` from the same listener as the rpc calls.
+//!
+//! Payloads are the bincode encoding of the request and response enums of the
+//! service. Updates of client streaming and bidi calls are not recorded.
+use crate::telemetry::method_name;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    any::type_name,
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// The content type of the output of [encode]
+pub const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Default number of samples that are kept
+pub const DEFAULT_CAPACITY: usize = 128;
+
+static SAMPLER: Lazy<Mutex<Sampler>> = Lazy::new(|| {
+    Mutex::new(Sampler {
+        rate: 0.0,
+        capacity: DEFAULT_CAPACITY,
+        calls: 0,
+        samples: VecDeque::new(),
+    })
+});
+
+/// A recorded call
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// The service of the call
+    pub service: &'static str,
+    /// The method of the call, e.g. `Sqr`
+    pub method: &'static str,
+    /// When the call was started
+    pub started: SystemTime,
+    /// How long the call took, until the response or the response stream was dropped
+    pub duration: Duration,
+    /// The serialized request
+    pub request: Vec<u8>,
+    /// The serialized responses, in the order they were received
+    pub responses: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Sampler {
+    rate: f64,
+    capacity: usize,
+    calls: u64,
+    samples: VecDeque<Sample>,
+}
+
+impl Sampler {
+    /// Decide whether to record the next call
+    ///
+    /// This records exactly the configured fraction of calls, evenly spread.
+    fn sample(&mut self) -> bool {
+        if self.rate <= 0.0 {
+            return false;
+        }
+        let before = (self.calls as f64 * self.rate).floor();
+        self.calls += 1;
+        (self.calls as f64 * self.rate).floor() > before
+    }
+
+    fn push(&mut self, sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+/// Record the payloads of a fraction of client calls
+///
+/// `rate` is clamped to `0.0..=1.0`, `0.0` disables sampling. At most `capacity`
+/// samples are kept, older samples are dropped first.
+pub fn configure(rate: f64, capacity: usize) {
+    let mut sampler = SAMPLER.lock().unwrap();
+    sampler.rate = if rate.is_nan() {
+        0.0
+    } else {
+        rate.clamp(0.0, 1.0)
+    };
+    sampler.capacity = capacity;
+    while sampler.samples.len() > capacity {
+        sampler.samples.pop_front();
+    }
+}
+
+/// Get the recorded samples, oldest first
+pub fn samples() -> Vec<Sample> {
+    SAMPLER.lock().unwrap().samples.iter().cloned().collect()
+}
+
+/// Remove all recorded samples
+pub fn clear() {
+    SAMPLER.lock().unwrap().samples.clear();
+}
+
+/// Render the recorded samples as text
+///
+/// Every sample starts with a line
+/// `sample <service> <method> <started unix ms> <duration us>`,
+/// followed by a `request <hex>` line and a `response <hex>` line per response.
+pub fn encode() -> String {
+    let mut out = String::new();
+    for sample in SAMPLER.lock().unwrap().samples.iter() {
+        sample
+            .encode(&mut out)
+            .expect("writing to a string does not fail");
+    }
+    out
+}
+
+impl Sample {
+    fn encode(&self, out: &mut impl Write) -> fmt::Result {
+        let started = self
+            .started
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        writeln!(
+            out,
+            "sample {} {} {} {}",
+            self.service,
+            self.method,
+            started,
+            self.duration.as_micros()
+        )?;
+        write_hex(out, "request", &self.request)?;
+        for response in &self.responses {
+            write_hex(out, "response", response)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_hex(out: &mut impl Write, label: &str, bytes: &[u8]) -> fmt::Result {
+    write!(out, "{label} ")?;
+    for byte in bytes {
+        write!(out, "{byte:02x}")?;
+    }
+    writeln!(out)
+}
+
+/// Recorder for a single sampled call
+///
+/// This is shared between the parts of a call that see responses. The sample
+/// is stored once the last clone is dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct Recorder(Arc<Mutex<Pending>>);
+
+#[derive(Debug)]
+struct Pending {
+    sample: Sample,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start recording a call of message type `M` on service `S`, if it is sampled
+    pub(crate) fn start<S: 'static, M: 'static>(request: &impl Serialize) -> Option<Self> {
+        if !SAMPLER.lock().unwrap().sample() {
+            return None;
+        }
+        let sample = Sample {
+            service: type_name::<S>(),
+            method: method_name::<M>(),
+            started: SystemTime::now(),
+            duration: Duration::ZERO,
+            request: serialize(request),
+            responses: Vec::new(),
+        };
+        let start = Instant::now();
+        Some(Self(Arc::new(Mutex::new(Pending { sample, start }))))
+    }
+
+    /// Record a response
+    pub(crate) fn response(&self, response: &impl Serialize) {
+        let response = serialize(response);
+        self.0.lock().unwrap().sample.responses.push(response);
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let sample = Sample {
+            service: self.sample.service,
+            method: self.sample.method,
+            started: self.sample.started,
+            duration: self.start.elapsed(),
+            request: std::mem::take(&mut self.sample.request),
+            responses: std::mem::take(&mut self.sample.responses),
+        };
+        SAMPLER.lock().unwrap().push(sample);
+    }
+}
+
+fn serialize(value: &impl Serialize) -> Vec<u8> {
+    // payloads that can not be serialized are recorded as empty
+    bincode::serialize(value).unwrap_or_default()
+}
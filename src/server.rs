@@ -1,14 +1,40 @@
 //! Server side api
 //!
 //! The main entry point is [RpcServer]
+#[cfg(feature = "response-cache")]
+use crate::cache::{Lookup, ResponseCache};
+#[cfg(feature = "stream-limits")]
+use crate::limits::{MethodLimits, StreamDirection, StreamLimitExceeded, StreamLimits, Tracker};
+#[cfg(feature = "openmetrics")]
+use crate::metrics::MetricLabels;
 use crate::{
-    message::{BidiStreamingMsg, ClientStreamingMsg, RpcMsg, ServerStreamingMsg},
-    transport::ConnectionErrors,
+    audit::{Auditor, PendingAudit},
+    context,
+    coop::{Budget, DEFAULT_YIELD_BUDGET},
+    extensions::Extensions,
+    message::{BidiStreamingMsg, ClientStreamingMsg, Msg, RpcMsg, ServerStreamingMsg},
+    telemetry::{method_name, Call, Phase, Received, Side},
+    transport::{ConnectionCommon, ConnectionErrors, DatagramEndpoint},
+    validation::{
+        FromInvalidArgument, InvalidArgument, PatternResponse, ResponseOf, Validate, Validations,
+    },
+    versions::{CanonicalResponse, Versioned},
     Service, ServiceEndpoint,
 };
 use futures::{channel::oneshot, task, task::Poll, Future, FutureExt, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
-use std::{error, fmt, fmt::Debug, marker::PhantomData, pin::Pin, result};
+use std::{
+    any::{type_name, TypeId},
+    collections::HashMap,
+    error, fmt,
+    fmt::Debug,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
 
 /// A server channel for a specific service.
 ///
@@ -21,6 +47,13 @@ pub struct RpcServer<S, C> {
     /// Each new request is a receiver and channel pair on which messages for this request
     /// are received and responses sent.
     source: C,
+    /// Number of items processed in streaming loops before yielding
+    yield_budget: usize,
+    /// Limits for the streams of calls
+    #[cfg(feature = "stream-limits")]
+    limits: Arc<MethodLimits>,
+    /// Configuration that depends on the message types of the service
+    settings: Box<dyn ServiceSettings<S>>,
     p: PhantomData<S>,
 }
 
@@ -28,11 +61,254 @@ impl<S, C: Clone> Clone for RpcServer<S, C> {
     fn clone(&self) -> Self {
         Self {
             source: self.source.clone(),
+            yield_budget: self.yield_budget,
+            #[cfg(feature = "stream-limits")]
+            limits: self.limits.clone(),
+            settings: self.settings.boxed_clone(),
             p: PhantomData,
         }
     }
 }
 
+/// The configuration of a [RpcServer] that depends on the message types of the service
+#[derive(Debug, Clone)]
+struct Settings<S: Service> {
+    /// Response to send for requests whose handler was dropped
+    handler_dropped: Option<HandlerDroppedResponse<S>>,
+    /// Time limits for client streaming calls
+    timeouts: Arc<Timeouts<S>>,
+    /// Cache for responses of cacheable rpc calls
+    #[cfg(feature = "response-cache")]
+    cache: Option<ResponseCache<S>>,
+    /// Labels for the metrics of calls
+    #[cfg(feature = "openmetrics")]
+    metric_labels: Option<MetricLabels<S>>,
+    /// Receiver of the audit records of calls
+    auditor: Option<Auditor<S>>,
+    /// Checks of requests, by message type
+    validations: Arc<Validations<S>>,
+}
+
+impl<S: Service> Default for Settings<S> {
+    fn default() -> Self {
+        Self {
+            handler_dropped: None,
+            timeouts: Default::default(),
+            #[cfg(feature = "response-cache")]
+            cache: None,
+            #[cfg(feature = "openmetrics")]
+            metric_labels: None,
+            auditor: None,
+            validations: Default::default(),
+        }
+    }
+}
+
+/// Access to the [Settings] of a server
+///
+/// This keeps the `S: Service` bound off [RpcServer] itself, so it is only
+/// required by the impls that use the message types.
+trait ServiceSettings<S>: fmt::Debug + Send + Sync {
+    fn get(&self) -> &Settings<S>
+    where
+        S: Service;
+
+    fn get_mut(&mut self) -> &mut Settings<S>
+    where
+        S: Service;
+
+    fn boxed_clone(&self) -> Box<dyn ServiceSettings<S>>;
+}
+
+impl<S: Service> ServiceSettings<S> for Settings<S> {
+    fn get(&self) -> &Settings<S> {
+        self
+    }
+
+    fn get_mut(&mut self) -> &mut Settings<S> {
+        self
+    }
+
+    fn boxed_clone(&self) -> Box<dyn ServiceSettings<S>> {
+        Box::new(self.clone())
+    }
+}
+
+/// Information about a request whose handler was dropped before sending a response
+///
+/// This happens e.g. when the handler panics or the task running it is aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerDropped {
+    /// Type name of the service
+    pub service: &'static str,
+    /// Name of the message type of the request
+    pub method: &'static str,
+}
+
+impl fmt::Display for HandlerDropped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handler for {} dropped without a response", self.method)
+    }
+}
+
+impl error::Error for HandlerDropped {}
+
+/// A response that can carry a [HandlerDropped] to the client
+///
+/// Messages declared with the macros of this crate send this response when
+/// their handler is dropped, unless a response is configured with
+/// [RpcServer::with_handler_dropped_response].
+pub trait FromHandlerDropped {
+    /// The response for a request whose handler was dropped
+    fn from_handler_dropped(info: HandlerDropped) -> Self;
+}
+
+impl<T, E: From<HandlerDropped>> FromHandlerDropped for result::Result<T, E> {
+    fn from_handler_dropped(info: HandlerDropped) -> Self {
+        Err(info.into())
+    }
+}
+
+type HandlerDroppedFn<R> = dyn Fn(&HandlerDropped) -> Option<R> + Send + Sync;
+
+/// Produces the response to send for a request whose handler was dropped
+struct HandlerDroppedResponse<S: Service>(Arc<HandlerDroppedFn<S::Res>>);
+
+impl<S: Service> Clone for HandlerDroppedResponse<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: Service> fmt::Debug for HandlerDroppedResponse<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HandlerDroppedResponse").finish()
+    }
+}
+
+/// Time limits for client streaming calls
+///
+/// Calls that exceed a limit are aborted with [RpcServerError::Timeout]. The
+/// client gets the response configured with [RpcServer::with_timeout_response],
+/// or just sees the stream close if there is none.
+///
+/// Without limits for a specific message type, the
+/// [timeout](crate::message::MethodPolicy::timeout) of the policy of the
+/// message is used as the maximum duration. Rpc calls are limited by the
+/// timeout of their policy as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamingTimeouts {
+    /// Maximum duration of a call, from the start of the handler until the
+    /// response is sent
+    pub max_duration: Option<Duration>,
+    /// Maximum time the handler waits for the next update
+    ///
+    /// Only time spent waiting for the client counts. Time the handler spends
+    /// between reading updates does not.
+    pub max_update_gap: Option<Duration>,
+}
+
+/// The limit of [StreamingTimeouts] that a call exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// [StreamingTimeouts::max_duration] was exceeded
+    Duration,
+    /// [StreamingTimeouts::max_update_gap] was exceeded
+    UpdateGap,
+}
+
+/// Information about a call that timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallTimeout {
+    /// Type name of the service
+    pub service: &'static str,
+    /// Name of the message type of the request
+    pub method: &'static str,
+    /// The limit that was exceeded
+    pub kind: TimeoutKind,
+}
+
+impl fmt::Display for CallTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = match self.kind {
+            TimeoutKind::Duration => "maximum duration",
+            TimeoutKind::UpdateGap => "maximum gap between updates",
+        };
+        write!(f, "{} exceeded the {}", self.method, limit)
+    }
+}
+
+impl error::Error for CallTimeout {}
+
+type TimeoutResponseFn<R> = dyn Fn(&CallTimeout) -> Option<R> + Send + Sync;
+
+/// Time limits for client streaming calls, by message type
+struct Timeouts<S: Service> {
+    default: StreamingTimeouts,
+    methods: HashMap<TypeId, StreamingTimeouts>,
+    response: Option<Arc<TimeoutResponseFn<S::Res>>>,
+}
+
+impl<S: Service> Timeouts<S> {
+    fn get<M: Msg<S>>(&self) -> StreamingTimeouts {
+        let id = TypeId::of::<M>();
+        self.methods.get(&id).copied().unwrap_or(StreamingTimeouts {
+            max_duration: M::POLICY.timeout.or(self.default.max_duration),
+            ..self.default
+        })
+    }
+
+    /// Log a call that timed out, and send the timeout response, unless the
+    /// handler already started sending its own response
+    async fn timed_out<C: ServiceEndpoint<S>>(
+        &self,
+        info: &CallTimeout,
+        guard: &mut ResponseGuard<S, C>,
+    ) {
+        tracing::debug!(
+            rpc.service = info.service,
+            rpc.method = info.method,
+            "{}",
+            info
+        );
+        let response = self.response.as_ref().and_then(|f| f(info));
+        if let (Some(response), Some(mut send)) = (response, guard.send.take()) {
+            if let Err(cause) = send.send(response).await {
+                tracing::debug!("error sending timeout response: {}", cause);
+            }
+        }
+    }
+}
+
+impl<S: Service> Default for Timeouts<S> {
+    fn default() -> Self {
+        Self {
+            default: Default::default(),
+            methods: Default::default(),
+            response: None,
+        }
+    }
+}
+
+impl<S: Service> Clone for Timeouts<S> {
+    fn clone(&self) -> Self {
+        Self {
+            default: self.default,
+            methods: self.methods.clone(),
+            response: self.response.clone(),
+        }
+    }
+}
+
+impl<S: Service> fmt::Debug for Timeouts<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timeouts")
+            .field("default", &self.default)
+            .field("methods", &self.methods.len())
+            .finish()
+    }
+}
+
 impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     /// Create a new rpc server for a specific service for a [Service] given a compatible
     /// [ServiceEndpoint].
@@ -41,9 +317,154 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     pub fn new(source: C) -> Self {
         Self {
             source,
+            yield_budget: DEFAULT_YIELD_BUDGET,
+            #[cfg(feature = "stream-limits")]
+            limits: Default::default(),
+            settings: Box::new(Settings::<S>::default()),
             p: PhantomData,
         }
     }
+
+    /// Set the number of items that are sent or received in a streaming loop
+    /// before yielding to the runtime.
+    ///
+    /// This is inherited by all channels accepted by this server. The default
+    /// is [DEFAULT_YIELD_BUDGET]. A budget of 0 disables cooperative yielding.
+    pub fn with_yield_budget(mut self, budget: usize) -> Self {
+        self.yield_budget = budget;
+        self
+    }
+
+    /// Set the response that is sent when the handler for a request is dropped
+    /// before it sent a response.
+    ///
+    /// This is inherited by all channels accepted by this server. See
+    /// [RpcChannel::with_handler_dropped_response] for details.
+    pub fn with_handler_dropped_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HandlerDropped) -> Option<S::Res> + Send + Sync + 'static,
+    {
+        self.settings.get_mut().handler_dropped = Some(HandlerDroppedResponse(Arc::new(f)));
+        self
+    }
+
+    /// Set the time limits for all client streaming calls.
+    ///
+    /// This is inherited by all channels accepted by this server. Limits set
+    /// for a specific message type using [RpcServer::with_method_timeouts] take
+    /// precedence.
+    pub fn with_streaming_timeouts(mut self, timeouts: StreamingTimeouts) -> Self {
+        Arc::make_mut(&mut self.settings.get_mut().timeouts).default = timeouts;
+        self
+    }
+
+    /// Set the time limits for client streaming calls of message type `M`.
+    ///
+    /// These take precedence over the timeout of the policy of `M`.
+    ///
+    /// This is inherited by all channels accepted by this server.
+    pub fn with_method_timeouts<M: ClientStreamingMsg<S>>(
+        mut self,
+        timeouts: StreamingTimeouts,
+    ) -> Self {
+        let methods = &mut Arc::make_mut(&mut self.settings.get_mut().timeouts).methods;
+        methods.insert(TypeId::of::<M>(), timeouts);
+        self
+    }
+
+    /// Set the response that is sent when a client streaming call times out.
+    ///
+    /// This is inherited by all channels accepted by this server. See
+    /// [RpcChannel::with_timeout_response] for details.
+    pub fn with_timeout_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&CallTimeout) -> Option<S::Res> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.settings.get_mut().timeouts).response = Some(Arc::new(f));
+        self
+    }
+
+    /// Cache the responses of [cacheable](crate::message::RpcMsg::CACHEABLE) rpc calls.
+    ///
+    /// This is inherited by all channels accepted by this server. The
+    /// invalidation hooks of the cache are called with every accepted request.
+    #[cfg(feature = "response-cache")]
+    pub fn with_response_cache(mut self, cache: ResponseCache<S>) -> Self {
+        self.settings.get_mut().cache = Some(cache);
+        self
+    }
+
+    /// Set the limits for the streams of all calls.
+    ///
+    /// This is inherited by all channels accepted by this server. Limits set
+    /// for a specific message type using [RpcServer::with_method_stream_limits]
+    /// take precedence.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_stream_limits(mut self, limits: StreamLimits) -> Self {
+        Arc::make_mut(&mut self.limits).default = limits;
+        self
+    }
+
+    /// Set the limits for the streams of calls of message type `M`.
+    ///
+    /// This is inherited by all channels accepted by this server.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_method_stream_limits<M: Msg<S>>(mut self, limits: StreamLimits) -> Self {
+        let methods = &mut Arc::make_mut(&mut self.limits).methods;
+        methods.insert(TypeId::of::<M>(), limits);
+        self
+    }
+
+    /// Label the duration of calls with values extracted from their first request.
+    ///
+    /// The labels are extracted when a request is accepted, and recorded in the
+    /// [metrics](crate::metrics) registry once the call ends.
+    #[cfg(feature = "openmetrics")]
+    pub fn with_metric_labels(mut self, labels: MetricLabels<S>) -> Self {
+        self.settings.get_mut().metric_labels = Some(labels);
+        self
+    }
+
+    /// Send an [audit record](crate::audit::AuditRecord) to `auditor` after every call.
+    ///
+    /// The summary of the request and the address of the client are captured
+    /// when a request is accepted. Requests that are
+    /// [rejected](PendingRequest::reject), and channels that are dropped before
+    /// a handler method like [RpcChannel::rpc] runs, are not audited.
+    pub fn with_auditor(mut self, auditor: Auditor<S>) -> Self {
+        self.settings.get_mut().auditor = Some(auditor);
+        self
+    }
+
+    /// Check requests of type `M` with [Validate] before they are passed to a
+    /// handler
+    ///
+    /// A request that is not valid is answered with its [InvalidArgument],
+    /// and the handler method like [RpcChannel::rpc] returns
+    /// [RpcServerError::InvalidArgument] without calling the handler. See
+    /// [crate::validation].
+    pub fn with_validation<M>(self) -> Self
+    where
+        M: Msg<S> + Validate + PatternResponse<S, M::Pattern>,
+        ResponseOf<S, M>: FromInvalidArgument,
+    {
+        self.with_validator(M::validate)
+    }
+
+    /// Check requests of type `M` with `f` before they are passed to a handler
+    ///
+    /// This works like [RpcServer::with_validation], for checks that are not
+    /// a [Validate] implementation of the message, e.g. from another library.
+    /// A later check for the same message type replaces an earlier one.
+    pub fn with_validator<M, F>(mut self, f: F) -> Self
+    where
+        M: Msg<S> + PatternResponse<S, M::Pattern>,
+        ResponseOf<S, M>: FromInvalidArgument,
+        F: Fn(&M) -> result::Result<(), InvalidArgument> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.settings.get_mut().validations).insert(f);
+        self
+    }
 }
 
 /// A channel for requests and responses for a specific service.
@@ -59,6 +480,26 @@ pub struct RpcChannel<S: Service, C: ServiceEndpoint<S>> {
     pub send: C::SendSink,
     /// Stream to receive requests from the client.
     pub recv: C::RecvStream,
+    /// Number of items processed in streaming loops before yielding
+    yield_budget: usize,
+    /// Response to send for requests whose handler was dropped
+    handler_dropped: Option<HandlerDroppedResponse<S>>,
+    /// Time limits for client streaming calls
+    timeouts: Arc<Timeouts<S>>,
+    /// Cache for responses of cacheable rpc calls
+    #[cfg(feature = "response-cache")]
+    cache: Option<ResponseCache<S>>,
+    /// Limits for the streams of calls
+    #[cfg(feature = "stream-limits")]
+    limits: Arc<MethodLimits>,
+    /// When the first request was read, if it was accepted by a [RpcServer]
+    received: Option<Received>,
+    /// Audit of the call, if it was accepted by a [RpcServer] with an auditor
+    audit: Option<PendingAudit>,
+    /// Extensions of the connection the call came in on
+    extensions: Option<Extensions>,
+    /// Checks of the request, by message type
+    validations: Arc<Validations<S>>,
     /// Phantom data to make the type parameter `S` non-instantiable.
     p: PhantomData<S>,
 }
@@ -69,14 +510,197 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         Self {
             send,
             recv,
+            yield_budget: DEFAULT_YIELD_BUDGET,
+            handler_dropped: None,
+            timeouts: Default::default(),
+            #[cfg(feature = "response-cache")]
+            cache: None,
+            #[cfg(feature = "stream-limits")]
+            limits: Default::default(),
+            received: None,
+            audit: None,
+            extensions: None,
+            validations: Default::default(),
             p: PhantomData,
         }
     }
 
+    /// Set the number of items that are sent or received in a streaming loop
+    /// before yielding to the runtime.
+    ///
+    /// A budget of 0 disables cooperative yielding.
+    pub fn with_yield_budget(mut self, budget: usize) -> Self {
+        self.yield_budget = budget;
+        self
+    }
+
+    /// Set the extensions of the connection the call came in on
+    ///
+    /// Channels accepted by a [RpcServer] get these from the transport.
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    /// The extensions of the connection the call came in on
+    ///
+    /// Handlers get these from their [CallContext](crate::context::CallContext).
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.extensions.as_ref()
+    }
+
+    /// Set the response that is sent when the handler for a request is dropped
+    /// before it sent a response.
+    ///
+    /// This applies to the single response of [RpcChannel::rpc] and
+    /// [RpcChannel::client_streaming]. Dropped handlers are always logged. If `f`
+    /// returns a response, it is sent to the client, so the client gets a typed
+    /// error instead of just seeing the stream close. Since the response has to
+    /// match the message type, `f` can use [HandlerDropped::method] to pick it.
+    ///
+    /// If `f` returns `None`, the response of the message type is sent, see
+    /// [RpcMsg::handler_dropped]. For messages declared with the macros of
+    /// this crate, that is a [FromHandlerDropped] error if the response can
+    /// carry one.
+    pub fn with_handler_dropped_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&HandlerDropped) -> Option<S::Res> + Send + Sync + 'static,
+    {
+        self.handler_dropped = Some(HandlerDroppedResponse(Arc::new(f)));
+        self
+    }
+
+    /// Set the time limits for all client streaming calls.
+    ///
+    /// Limits set for a specific message type using
+    /// [RpcChannel::with_method_timeouts] take precedence.
+    pub fn with_streaming_timeouts(mut self, timeouts: StreamingTimeouts) -> Self {
+        Arc::make_mut(&mut self.timeouts).default = timeouts;
+        self
+    }
+
+    /// Set the time limits for client streaming calls of message type `M`.
+    ///
+    /// These take precedence over the timeout of the policy of `M`.
+    pub fn with_method_timeouts<M: ClientStreamingMsg<S>>(
+        mut self,
+        timeouts: StreamingTimeouts,
+    ) -> Self {
+        let methods = &mut Arc::make_mut(&mut self.timeouts).methods;
+        methods.insert(TypeId::of::<M>(), timeouts);
+        self
+    }
+
+    /// Set the response that is sent when a client streaming call times out.
+    ///
+    /// Timeouts are always returned as [RpcServerError::Timeout] by
+    /// [RpcChannel::client_streaming]. If `f` returns a response, it is also sent
+    /// to the client, so the client gets a typed error instead of just seeing
+    /// the stream close. No response is sent if the handler already started
+    /// sending its own response.
+    pub fn with_timeout_response<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&CallTimeout) -> Option<S::Res> + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.timeouts).response = Some(Arc::new(f));
+        self
+    }
+
+    /// Cache the responses of [cacheable](crate::message::RpcMsg::CACHEABLE) rpc calls.
+    ///
+    /// [RpcChannel::rpc] answers requests that are in the cache without calling
+    /// the handler, and caches the responses of the handler otherwise.
+    #[cfg(feature = "response-cache")]
+    pub fn with_response_cache(mut self, cache: ResponseCache<S>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Set the limits for the streams of all calls.
+    ///
+    /// The updates received and the responses sent by streaming calls are
+    /// checked against the limits, and the call fails with
+    /// [RpcServerError::StreamLimit] once a stream exceeds them. Limits set for
+    /// a specific message type using [RpcChannel::with_method_stream_limits]
+    /// take precedence.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_stream_limits(mut self, limits: StreamLimits) -> Self {
+        Arc::make_mut(&mut self.limits).default = limits;
+        self
+    }
+
+    /// Set the limits for the streams of calls of message type `M`.
+    #[cfg(feature = "stream-limits")]
+    pub fn with_method_stream_limits<M: Msg<S>>(mut self, limits: StreamLimits) -> Self {
+        let methods = &mut Arc::make_mut(&mut self.limits).methods;
+        methods.insert(TypeId::of::<M>(), limits);
+        self
+    }
+
+    /// handle version `V` of a logical message using a handler for the
+    /// canonical version
+    ///
+    /// The request is converted to the canonical version before it is passed
+    /// to `f`, and the response back to version `V`. See [crate::versions].
+    pub async fn rpc_versioned<V, F, Fut, T>(
+        self,
+        req: V,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        V: Versioned<S>,
+        F: FnOnce(T, V::Canonical) -> Fut,
+        Fut: Future<Output = CanonicalResponse<S, V>>,
+        T: Send + 'static,
+    {
+        self.rpc(req, target, move |target, req| {
+            f(target, req.upgrade()).map(V::downgrade_response)
+        })
+        .await
+    }
+
     /// handle the message of type `M` using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
     pub async fn rpc<M, F, Fut, T>(
+        mut self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: RpcMsg<S>,
+        F: FnOnce(T, M) -> Fut,
+        Fut: Future<Output = M::Response>,
+        T: Send + 'static,
+    {
+        let audit = self.audit.take().map(PendingAudit::start::<S, M>);
+        let res = match self.validate(&req).await {
+            Ok(()) => self.rpc_inner(req, target, f).await,
+            Err(cause) => Err(cause),
+        };
+        if let Some(audit) = audit {
+            audit.finish(&res);
+        }
+        res
+    }
+
+    /// Answer a request that is not valid, see [RpcServer::with_validation]
+    async fn validate<M: Msg<S>>(&mut self, req: &M) -> result::Result<(), RpcServerError<C>> {
+        let (invalid, response) = match self.validations.check(req) {
+            Some(rejected) => rejected,
+            None => return Ok(()),
+        };
+        tracing::debug!(rpc.method = method_name::<M>(), "{}", invalid);
+        self.send
+            .send(response)
+            .await
+            .map_err(RpcServerError::SendError)?;
+        Err(RpcServerError::InvalidArgument(invalid))
+    }
+
+    async fn rpc_inner<M, F, Fut, T>(
         self,
         req: M,
         target: T,
@@ -88,29 +712,103 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         Fut: Future<Output = M::Response>,
         T: Send + 'static,
     {
+        let call = Call::start::<S, M>(Side::Server).received(self.received);
         let Self {
-            mut send, mut recv, ..
+            send,
+            mut recv,
+            handler_dropped,
+            timeouts,
+            #[cfg(feature = "response-cache")]
+            cache,
+            extensions,
+            ..
         } = self;
+        #[cfg(feature = "response-cache")]
+        let (req, cache) = match cache.filter(|_| M::CACHEABLE) {
+            Some(cache) => match cache.lookup::<M>(req) {
+                Some(Lookup::Hit(res)) => {
+                    tracing::debug!(parent: call.span(), "response served from cache");
+                    let mut send = send;
+                    return send_timed(&call, &mut send, res)
+                        .instrument(call.span().clone())
+                        .await;
+                }
+                Some(Lookup::Miss(req, key)) => (req, Some((cache, key))),
+                None => return Err(RpcServerError::UnexpectedStartMessage),
+            },
+            None => (req, None),
+        };
+        let mut guard = ResponseGuard::<S, C>::new::<M>(send, handler_dropped, |info| {
+            M::handler_dropped(info).map(Into::into)
+        });
         // cancel if we get an update, no matter what it is
         let cancel = recv
             .next()
             .map(|_| RpcServerError::UnexpectedUpdateMessage::<C>);
         // race the computation and the cancellation
-        race2(cancel.map(Err), async move {
+        let work = race2(cancel.map(Err), async {
             // get the response
-            let res = f(target, req).await;
+            let res = call.phase(Phase::Handler, f(target, req)).await;
             // turn into a S::Res so we can send it
             let res: S::Res = res.into();
+            #[cfg(feature = "response-cache")]
+            if let Some((cache, key)) = cache {
+                cache.insert(key, &res);
+            }
             // send it and return the error if any
-            send.send(res).await.map_err(RpcServerError::SendError)
-        })
-        .await
+            let mut send = guard.defuse();
+            send_timed(&call, &mut send, res).await
+        });
+        let timeout = M::POLICY.timeout;
+        let work = async {
+            match timeout {
+                Some(duration) => match tokio::time::timeout(duration, work).await {
+                    Ok(res) => res,
+                    Err(_) => Err(RpcServerError::Timeout(CallTimeout {
+                        service: type_name::<S>(),
+                        method: method_name::<M>(),
+                        kind: TimeoutKind::Duration,
+                    })),
+                },
+                None => work.await,
+            }
+        };
+        let res = context::serve(timeout, extensions, work.instrument(call.span().clone())).await;
+        if let Err(RpcServerError::Timeout(info)) = &res {
+            timeouts.timed_out(info, &mut guard).await;
+        }
+        // the request was answered, or the client is no longer interested
+        guard.defuse_if_armed();
+        res
     }
 
     /// handle the message M using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
     pub async fn client_streaming<M, F, Fut, T>(
+        mut self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: ClientStreamingMsg<S>,
+        F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Fut + Send + 'static,
+        Fut: Future<Output = M::Response> + Send + 'static,
+        T: Send + 'static,
+    {
+        let audit = self.audit.take().map(PendingAudit::start::<S, M>);
+        let res = match self.validate(&req).await {
+            Ok(()) => self.client_streaming_inner(req, target, f).await,
+            Err(cause) => Err(cause),
+        };
+        if let Some(audit) = audit {
+            audit.finish(&res);
+        }
+        res
+    }
+
+    async fn client_streaming_inner<M, F, Fut, T>(
         self,
         req: M,
         target: T,
@@ -122,23 +820,94 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         Fut: Future<Output = M::Response> + Send + 'static,
         T: Send + 'static,
     {
-        let Self { mut send, recv, .. } = self;
-        let (updates, read_error) = UpdateStream::new(recv);
-        race2(read_error.map(Err), async move {
-            // get the response
-            let res = f(target, req, updates).await;
-            // turn into a S::Res so we can send it
-            let res: S::Res = res.into();
-            // send it and return the error if any
-            send.send(res).await.map_err(RpcServerError::SendError)
-        })
-        .await
+        let call = Call::start::<S, M>(Side::Server).received(self.received);
+        #[cfg(feature = "stream-limits")]
+        let tracker = self.limits.tracker::<S, M>(StreamDirection::Updates);
+        let Self {
+            send,
+            recv,
+            yield_budget,
+            handler_dropped,
+            timeouts,
+            extensions,
+            ..
+        } = self;
+        let limits = timeouts.get::<M>();
+        let timeout = |kind| CallTimeout {
+            service: type_name::<S>(),
+            method: method_name::<M>(),
+            kind,
+        };
+        let mut guard = ResponseGuard::<S, C>::new::<M>(send, handler_dropped, |info| {
+            M::handler_dropped(info).map(Into::into)
+        });
+        let (mut updates, read_error) = UpdateStream::new(recv, yield_budget);
+        if let Some(gap) = limits.max_update_gap {
+            updates.2 = Some(GapTimer::new(gap, timeout(TimeoutKind::UpdateGap)));
+        }
+        #[cfg(feature = "stream-limits")]
+        {
+            updates.5 = tracker;
+        }
+        let res = async {
+            let work = race2(read_error.map(Err), async {
+                // get the response
+                let res = call.phase(Phase::Handler, f(target, req, updates)).await;
+                // turn into a S::Res so we can send it
+                let res: S::Res = res.into();
+                // send it and return the error if any
+                let mut send = guard.defuse();
+                send_timed(&call, &mut send, res).await
+            });
+            match limits.max_duration {
+                Some(duration) => match tokio::time::timeout(duration, work).await {
+                    Ok(res) => res,
+                    Err(_) => Err(RpcServerError::Timeout(timeout(TimeoutKind::Duration))),
+                },
+                None => work.await,
+            }
+        };
+        let res = context::serve(
+            limits.max_duration,
+            extensions,
+            res.instrument(call.span().clone()),
+        )
+        .await;
+        if let Err(RpcServerError::Timeout(info)) = &res {
+            timeouts.timed_out(info, &mut guard).await;
+        }
+        // the request was answered, or reading updates failed
+        guard.defuse_if_armed();
+        res
     }
 
     /// handle the message M using the given function on the target object
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
     pub async fn bidi_streaming<M, F, Str, T>(
+        mut self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: BidiStreamingMsg<S>,
+        F: FnOnce(T, M, UpdateStream<S, C, M::Update>) -> Str + Send + 'static,
+        Str: Stream<Item = M::Response> + Send + 'static,
+        T: Send + 'static,
+    {
+        let audit = self.audit.take().map(PendingAudit::start::<S, M>);
+        let res = match self.validate(&req).await {
+            Ok(()) => self.bidi_streaming_inner(req, target, f).await,
+            Err(cause) => Err(cause),
+        };
+        if let Some(audit) = audit {
+            audit.finish(&res);
+        }
+        res
+    }
+
+    async fn bidi_streaming_inner<M, F, Str, T>(
         self,
         req: M,
         target: T,
@@ -150,23 +919,48 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         Str: Stream<Item = M::Response> + Send + 'static,
         T: Send + 'static,
     {
-        let Self { mut send, recv, .. } = self;
+        let call = Call::start::<S, M>(Side::Server).received(self.received);
+        let Self {
+            mut send,
+            recv,
+            yield_budget,
+            #[cfg(feature = "stream-limits")]
+            limits,
+            extensions,
+            ..
+        } = self;
         // downcast the updates
-        let (updates, read_error) = UpdateStream::new(recv);
-        // get the response
-        let responses = f(target, req, updates);
-        race2(read_error.map(Err), async move {
+        #[allow(unused_mut)]
+        let (mut updates, read_error) = UpdateStream::new(recv, yield_budget);
+        #[cfg(feature = "stream-limits")]
+        let mut tracker = {
+            updates.5 = limits.tracker::<S, M>(StreamDirection::Updates);
+            limits.tracker::<S, M>(StreamDirection::Responses)
+        };
+        let work = race2(read_error.map(Err), async move {
+            // get the response
+            let responses = f(target, req, updates);
             tokio::pin!(responses);
+            let mut budget = Budget::new(yield_budget);
             while let Some(response) = responses.next().await {
+                // give other tasks a chance to run if the stream is always ready
+                budget.proceed().await;
                 // turn into a S::Res so we can send it
                 let response: S::Res = response.into();
+                #[cfg(feature = "stream-limits")]
+                check_limits(&mut tracker, &response)?;
                 // send it and return the error if any
                 send.send(response)
                     .await
                     .map_err(RpcServerError::SendError)?;
             }
             Ok(())
-        })
+        });
+        context::serve(
+            M::POLICY.timeout,
+            extensions,
+            work.instrument(call.span().clone()),
+        )
         .await
     }
 
@@ -174,6 +968,29 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
     ///
     /// If you want to support concurrent requests, you need to spawn this on a tokio task yourself.
     pub async fn server_streaming<M, F, Str, T>(
+        mut self,
+        req: M,
+        target: T,
+        f: F,
+    ) -> result::Result<(), RpcServerError<C>>
+    where
+        M: ServerStreamingMsg<S>,
+        F: FnOnce(T, M) -> Str + Send + 'static,
+        Str: Stream<Item = M::Response> + Send + 'static,
+        T: Send + 'static,
+    {
+        let audit = self.audit.take().map(PendingAudit::start::<S, M>);
+        let res = match self.validate(&req).await {
+            Ok(()) => self.server_streaming_inner(req, target, f).await,
+            Err(cause) => Err(cause),
+        };
+        if let Some(audit) = audit {
+            audit.finish(&res);
+        }
+        res
+    }
+
+    async fn server_streaming_inner<M, F, Str, T>(
         self,
         req: M,
         target: T,
@@ -185,28 +1002,47 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcChannel<S, C> {
         Str: Stream<Item = M::Response> + Send + 'static,
         T: Send + 'static,
     {
+        let call = Call::start::<S, M>(Side::Server).received(self.received);
         let Self {
-            mut send, mut recv, ..
+            mut send,
+            mut recv,
+            yield_budget,
+            #[cfg(feature = "stream-limits")]
+            limits,
+            extensions,
+            ..
         } = self;
+        #[cfg(feature = "stream-limits")]
+        let mut tracker = limits.tracker::<S, M>(StreamDirection::Responses);
         // cancel if we get an update, no matter what it is
         let cancel = recv
             .next()
             .map(|_| RpcServerError::UnexpectedUpdateMessage::<C>);
         // race the computation and the cancellation
-        race2(cancel.map(Err), async move {
+        let work = race2(cancel.map(Err), async move {
             // get the response
             let responses = f(target, req);
             tokio::pin!(responses);
+            let mut budget = Budget::new(yield_budget);
             while let Some(response) = responses.next().await {
+                // give other tasks a chance to run if the stream is always ready
+                budget.proceed().await;
                 // turn into a S::Res so we can send it
                 let response: S::Res = response.into();
+                #[cfg(feature = "stream-limits")]
+                check_limits(&mut tracker, &response)?;
                 // send it and return the error if any
                 send.send(response)
                     .await
                     .map_err(RpcServerError::SendError)?;
             }
             Ok(())
-        })
+        });
+        context::serve(
+            M::POLICY.timeout,
+            extensions,
+            work.instrument(call.span().clone()),
+        )
         .await
     }
 
@@ -249,11 +1085,36 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     /// Often sink and stream will wrap an an underlying byte stream. In this case you can
     /// call into_inner() on them to get it back to perform byte level reads and writes.
     pub async fn accept(&self) -> result::Result<(S::Req, RpcChannel<S, C>), RpcServerError<C>> {
+        Ok(self.accept_pending().await?.accept())
+    }
+
+    /// Accepts a new channel from a client and reads the first request, without
+    /// committing to handle it.
+    ///
+    /// The returned [PendingRequest] allows inspecting the request and the
+    /// receive side of the channel, e.g. for metadata of the transport, and then
+    /// either [accepting](PendingRequest::accept) it, which is what
+    /// [RpcServer::accept] does, or [rejecting](PendingRequest::reject) it before
+    /// any handler or per call state is set up. This is useful for cheap
+    /// authorization and load shedding:
+    ///
+    /// ```ignore
+    /// let pending = server.accept_pending().await?;
+    /// if overloaded() {
+    ///     pending.reject_with(MyResponse::Overloaded).await?;
+    ///     continue;
+    /// }
+    /// let (req, chan) = pending.accept();
+    /// ```
+    pub async fn accept_pending(
+        &self,
+    ) -> result::Result<PendingRequest<'_, S, C>, RpcServerError<C>> {
         let (send, mut recv) = self
             .source
             .accept_bi()
             .await
             .map_err(RpcServerError::Accept)?;
+        let accepted = Instant::now();
 
         // get the first message from the client. This will tell us what it wants to do.
         let request: S::Req = recv
@@ -263,7 +1124,58 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
             .ok_or(RpcServerError::EarlyClose)?
             // recv error
             .map_err(RpcServerError::RecvError)?;
-        Ok((request, RpcChannel::new(send, recv)))
+        let received = Received {
+            accepted,
+            decoded: Instant::now(),
+            peer: <C as ConnectionCommon<S::Req, S::Res>>::peer_addr(&recv),
+            #[cfg(feature = "openmetrics")]
+            labels: self
+                .settings
+                .get()
+                .metric_labels
+                .as_ref()
+                .map(|labels| labels.extract(&request))
+                .unwrap_or_default(),
+        };
+        Ok(PendingRequest {
+            request,
+            send,
+            recv,
+            received,
+            server: self,
+        })
+    }
+
+    /// Turn the server into a stream of accepted requests
+    ///
+    /// Every item is the result of an [RpcServer::accept]. Errors of individual
+    /// channels are yielded as items, the stream ends after the first
+    /// [RpcServerError::Accept] error, since the endpoint will not accept any
+    /// more channels after that.
+    ///
+    /// This allows using stream combinators instead of a loop, e.g. to handle a
+    /// bounded number of requests concurrently:
+    ///
+    /// ```ignore
+    /// server
+    ///     .into_stream()
+    ///     .for_each_concurrent(16, |item| async move {
+    ///         if let Ok((req, chan)) = item {
+    ///             let _ = dispatch(req, chan).await;
+    ///         }
+    ///     })
+    ///     .await;
+    /// ```
+    pub fn into_stream(self) -> impl Stream<Item = AcceptResult<S, C>> {
+        futures::stream::unfold(Some(self), |server| async move {
+            let server = server?;
+            let item = server.accept().await;
+            let next = match item {
+                Err(RpcServerError::Accept(_)) => None,
+                _ => Some(server),
+            };
+            Some((item, next))
+        })
     }
 
     /// Get the underlying service endpoint
@@ -272,12 +1184,201 @@ impl<S: Service, C: ServiceEndpoint<S>> RpcServer<S, C> {
     }
 }
 
+impl<S, C> RpcServer<S, C>
+where
+    S: Service,
+    C: ServiceEndpoint<S> + DatagramEndpoint<S::Req, S::Res>,
+{
+    /// Receive the next message a client sent with
+    /// [RpcClient::notify_unreliable](crate::RpcClient::notify_unreliable)
+    ///
+    /// Messages from all connections arrive here, independent of the channels
+    /// accepted with [RpcServer::accept]. They can be lost or arrive out of
+    /// order, and there is no way to respond to them.
+    pub async fn accept_unreliable(&self) -> result::Result<S::Req, RpcServerError<C>> {
+        self.source
+            .recv_datagram()
+            .await
+            .map_err(RpcServerError::RecvError)
+    }
+}
+
+/// Item of [RpcServer::into_stream], the result of accepting a request
+pub type AcceptResult<S, C> =
+    result::Result<(<S as Service>::Req, RpcChannel<S, C>), RpcServerError<C>>;
+
+/// A request that was received, but not yet accepted, see [RpcServer::accept_pending]
+pub struct PendingRequest<'a, S: Service, C: ServiceEndpoint<S>> {
+    request: S::Req,
+    send: C::SendSink,
+    recv: C::RecvStream,
+    received: Received,
+    server: &'a RpcServer<S, C>,
+}
+
+impl<'a, S: Service, C: ServiceEndpoint<S>> fmt::Debug for PendingRequest<'a, S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingRequest")
+            .field("request", &self.request)
+            .finish()
+    }
+}
+
+impl<'a, S: Service, C: ServiceEndpoint<S>> PendingRequest<'a, S, C> {
+    /// The first message of the request
+    pub fn request(&self) -> &S::Req {
+        &self.request
+    }
+
+    /// The receive side of the channel, to get metadata of the transport
+    pub fn recv(&self) -> &C::RecvStream {
+        &self.recv
+    }
+
+    /// The extensions of the connection the request came in on, if the
+    /// transport has connections
+    ///
+    /// Values inserted here are visible to the handlers of this and all later
+    /// calls on the connection.
+    pub fn extensions(&self) -> Option<Extensions> {
+        <C as ConnectionCommon<S::Req, S::Res>>::extensions(&self.recv)
+    }
+
+    /// Accept the request, to handle it with the returned channel
+    ///
+    /// The channel inherits the configuration of the server.
+    pub fn accept(self) -> (S::Req, RpcChannel<S, C>) {
+        let Self {
+            request,
+            send,
+            recv,
+            received,
+            server,
+        } = self;
+        let peer = <C as ConnectionCommon<S::Req, S::Res>>::peer_addr(&recv);
+        let mut channel = RpcChannel::new(send, recv).with_yield_budget(server.yield_budget);
+        channel.received = Some(received);
+        let settings = server.settings.get();
+        channel.audit = settings
+            .auditor
+            .as_ref()
+            .map(|auditor| auditor.accepted(&request, peer));
+        channel.handler_dropped = settings.handler_dropped.clone();
+        channel.timeouts = settings.timeouts.clone();
+        channel.validations = settings.validations.clone();
+        channel.extensions = <C as ConnectionCommon<S::Req, S::Res>>::extensions(&channel.recv);
+        #[cfg(feature = "stream-limits")]
+        {
+            channel.limits = server.limits.clone();
+        }
+        #[cfg(feature = "response-cache")]
+        if let Some(cache) = &settings.cache {
+            cache.accepted(&request);
+            channel.cache = Some(cache.clone());
+        }
+        (request, channel)
+    }
+
+    /// Reject the request without a response
+    ///
+    /// The channel is closed, so the client sees the call end early.
+    pub fn reject(self) {
+        tracing::debug!("rejected request {:?}", self.request);
+    }
+
+    /// Reject the request, sending `response` to the client before closing the
+    /// channel
+    ///
+    /// Since the response has to match what the client expects for the
+    /// request, `response` is usually picked based on [PendingRequest::request].
+    pub async fn reject_with(
+        self,
+        response: impl Into<S::Res>,
+    ) -> result::Result<(), RpcServerError<C>> {
+        tracing::debug!("rejected request {:?}", self.request);
+        let mut send = self.send;
+        send.send(response.into())
+            .await
+            .map_err(RpcServerError::SendError)
+    }
+}
+
 impl<S: Service, C: ServiceEndpoint<S>> AsRef<C> for RpcServer<S, C> {
     fn as_ref(&self) -> &C {
         &self.source
     }
 }
 
+/// Guard for the send side of a request with a single response
+///
+/// If this is dropped while still armed, the handler was dropped before it
+/// sent a response. This is logged, and the configured response is sent, or
+/// the response of the message type for dropped handlers if there is none.
+struct ResponseGuard<S: Service, C: ServiceEndpoint<S>> {
+    send: Option<C::SendSink>,
+    info: HandlerDropped,
+    response: Option<HandlerDroppedResponse<S>>,
+    fallback: fn(&HandlerDropped) -> Option<S::Res>,
+}
+
+impl<S: Service, C: ServiceEndpoint<S>> ResponseGuard<S, C> {
+    fn new<M: 'static>(
+        send: C::SendSink,
+        response: Option<HandlerDroppedResponse<S>>,
+        fallback: fn(&HandlerDropped) -> Option<S::Res>,
+    ) -> Self {
+        Self {
+            send: Some(send),
+            info: HandlerDropped {
+                service: type_name::<S>(),
+                method: method_name::<M>(),
+            },
+            response,
+            fallback,
+        }
+    }
+
+    /// Take the send side to send the response
+    fn defuse(&mut self) -> C::SendSink {
+        self.send.take().expect("response guard defused twice")
+    }
+
+    /// Drop the send side without sending a response
+    fn defuse_if_armed(&mut self) {
+        self.send = None;
+    }
+}
+
+impl<S: Service, C: ServiceEndpoint<S>> Drop for ResponseGuard<S, C> {
+    fn drop(&mut self) {
+        let mut send = match self.send.take() {
+            Some(send) => send,
+            None => return,
+        };
+        tracing::warn!(
+            rpc.service = self.info.service,
+            rpc.method = self.info.method,
+            "handler dropped without sending a response"
+        );
+        let response = self.response.as_ref().and_then(|f| (f.0)(&self.info));
+        let response = match response.or_else(|| (self.fallback)(&self.info)) {
+            Some(response) => response,
+            None => return,
+        };
+        // we can not send from drop, so we need a task for it
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(async move {
+                    if let Err(cause) = send.send(response).await {
+                        tracing::debug!("error sending handler dropped response: {}", cause);
+                    }
+                });
+            }
+            Err(_) => tracing::debug!("no runtime to send handler dropped response"),
+        }
+    }
+}
+
 /// A stream of updates
 ///
 /// If there is any error with receiving or with decoding the updates, the stream will stall and the error will
@@ -287,14 +1388,51 @@ impl<S: Service, C: ServiceEndpoint<S>> AsRef<C> for RpcServer<S, C> {
 pub struct UpdateStream<S: Service, C: ServiceEndpoint<S>, T>(
     #[pin] C::RecvStream,
     Option<oneshot::Sender<RpcServerError<C>>>,
+    Option<GapTimer>,
+    Budget,
     PhantomData<T>,
+    #[cfg(feature = "stream-limits")] Option<Tracker>,
 );
 
+/// Fails a client streaming call when the client does not send updates in time
+#[derive(Debug)]
+struct GapTimer {
+    gap: Duration,
+    timeout: CallTimeout,
+    sleep: Pin<Box<tokio::time::Sleep>>,
+}
+
+impl GapTimer {
+    fn new(gap: Duration, timeout: CallTimeout) -> Self {
+        Self {
+            gap,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(gap)),
+        }
+    }
+
+    fn reset(&mut self) {
+        let deadline = tokio::time::Instant::now() + self.gap;
+        self.sleep.as_mut().reset(deadline);
+    }
+}
+
 impl<S: Service, C: ServiceEndpoint<S>, T> UpdateStream<S, C, T> {
-    fn new(recv: C::RecvStream) -> (Self, UnwrapToPending<RpcServerError<C>>) {
+    fn new(recv: C::RecvStream, yield_budget: usize) -> (Self, UnwrapToPending<RpcServerError<C>>) {
         let (error_send, error_recv) = oneshot::channel();
         let error_recv = UnwrapToPending(error_recv);
-        (Self(recv, Some(error_send), PhantomData), error_recv)
+        (
+            Self(
+                recv,
+                Some(error_send),
+                None,
+                Budget::new(yield_budget),
+                PhantomData,
+                #[cfg(feature = "stream-limits")]
+                None,
+            ),
+            error_recv,
+        )
     }
 }
 
@@ -306,10 +1444,18 @@ where
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
         let mut this = self.project();
+        futures::ready!(this.3.poll_proceed(cx));
         match this.0.poll_next_unpin(cx) {
             Poll::Ready(Some(msg)) => match msg {
+                #[cfg(feature = "stream-limits")]
+                Ok(msg) if exceeds_limits(this.5, &msg, this.1) => Poll::Pending,
                 Ok(msg) => match T::try_from(msg) {
-                    Ok(msg) => Poll::Ready(Some(msg)),
+                    Ok(msg) => {
+                        if let Some(timer) = this.2.as_mut() {
+                            timer.reset();
+                        }
+                        Poll::Ready(Some(msg))
+                    }
                     Err(_cause) => {
                         // we were unable to downcast, so we need to send an error
                         if let Some(tx) = this.1.take() {
@@ -326,8 +1472,23 @@ where
                     Poll::Pending
                 }
             },
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+            Poll::Ready(None) => {
+                // the client is done, so there is no gap to wait for
+                *this.2 = None;
+                Poll::Ready(None)
+            }
+            Poll::Pending => {
+                this.3.reset();
+                if let Some(timer) = this.2.as_mut() {
+                    if timer.sleep.poll_unpin(cx).is_ready() {
+                        if let Some(tx) = this.1.take() {
+                            let _ = tx.send(RpcServerError::Timeout(timer.timeout));
+                        }
+                        *this.2 = None;
+                    }
+                }
+                Poll::Pending
+            }
         }
     }
 }
@@ -346,6 +1507,13 @@ pub enum RpcServerError<C: ConnectionErrors> {
     SendError(C::SendError),
     /// Got an unexpected update message, e.g. a request message or a non-matching update message
     UnexpectedUpdateMessage,
+    /// A call exceeded its time limits, see [StreamingTimeouts]
+    Timeout(CallTimeout),
+    /// The first request of a call was not valid, see [RpcServer::with_validation]
+    InvalidArgument(InvalidArgument),
+    /// A stream of a call exceeded its limits, see [StreamLimits]
+    #[cfg(feature = "stream-limits")]
+    StreamLimit(StreamLimitExceeded),
 }
 
 impl<C: ConnectionErrors> fmt::Debug for RpcServerError<C> {
@@ -357,6 +1525,10 @@ impl<C: ConnectionErrors> fmt::Debug for RpcServerError<C> {
             Self::SendError(arg0) => f.debug_tuple("SendError").field(arg0).finish(),
             Self::UnexpectedStartMessage => f.debug_tuple("UnexpectedStartMessage").finish(),
             Self::UnexpectedUpdateMessage => f.debug_tuple("UnexpectedStartMessage").finish(),
+            Self::Timeout(arg0) => f.debug_tuple("Timeout").field(arg0).finish(),
+            Self::InvalidArgument(arg0) => f.debug_tuple("InvalidArgument").field(arg0).finish(),
+            #[cfg(feature = "stream-limits")]
+            Self::StreamLimit(arg0) => f.debug_tuple("StreamLimit").field(arg0).finish(),
         }
     }
 }
@@ -369,6 +1541,51 @@ impl<C: ConnectionErrors> fmt::Display for RpcServerError<C> {
 
 impl<C: ConnectionErrors> error::Error for RpcServerError<C> {}
 
+/// Account for a response sent by a limited stream
+#[cfg(feature = "stream-limits")]
+fn check_limits<C: ConnectionErrors>(
+    tracker: &mut Option<Tracker>,
+    response: &impl serde::Serialize,
+) -> result::Result<(), RpcServerError<C>> {
+    match tracker {
+        Some(tracker) => tracker.item(response).map_err(|cause| {
+            tracing::debug!(
+                rpc.service = cause.service,
+                rpc.method = cause.method,
+                "{}",
+                cause
+            );
+            RpcServerError::StreamLimit(cause)
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Account for an update received by a limited stream
+///
+/// If the update exceeds the limits, the error is sent to fail the call.
+#[cfg(feature = "stream-limits")]
+fn exceeds_limits<C: ConnectionErrors>(
+    tracker: &mut Option<Tracker>,
+    update: &impl serde::Serialize,
+    tx: &mut Option<oneshot::Sender<RpcServerError<C>>>,
+) -> bool {
+    let cause = match tracker.as_mut().map(|tracker| tracker.item(update)) {
+        Some(Err(cause)) => cause,
+        _ => return false,
+    };
+    tracing::debug!(
+        rpc.service = cause.service,
+        rpc.method = cause.method,
+        "{}",
+        cause
+    );
+    if let Some(tx) = tx.take() {
+        let _ = tx.send(RpcServerError::StreamLimit(cause));
+    }
+    true
+}
+
 /// Take an oneshot receiver and just return Pending the underlying future returns `Err(oneshot::Canceled)`
 struct UnwrapToPending<T>(oneshot::Receiver<T>);
 
@@ -384,6 +1601,20 @@ impl<T> Future for UnwrapToPending<T> {
     }
 }
 
+/// Send the single response of a call, timing encoding and flushing separately
+async fn send_timed<S: Service, C: ServiceEndpoint<S>>(
+    call: &Call,
+    send: &mut C::SendSink,
+    res: S::Res,
+) -> result::Result<(), RpcServerError<C>> {
+    call.phase(Phase::Encode, send.feed(res))
+        .await
+        .map_err(RpcServerError::SendError)?;
+    call.phase(Phase::Send, send.flush())
+        .await
+        .map_err(RpcServerError::SendError)
+}
+
 async fn race2<T, A: Future<Output = T>, B: Future<Output = T>>(f1: A, f2: B) -> T {
     tokio::select! {
         x = f1 => x,
@@ -0,0 +1,397 @@
+//! Client that routes calls to shards by key
+//!
+//! The main entry point is [ShardedClient]. Each call extracts a key from the
+//! request and is routed to the shard that owns the key. Ownership is decided
+//! by rendezvous hashing, so adding or removing a shard only moves the keys
+//! owned by that shard. The shards can be kept in sync with a
+//! [Discovery] using [ShardedClient::discover].
+//!
+//! Hashing uses 64 bit FNV-1a over the [Hash] of the shard id and the key,
+//! with integers written in little endian, so clients built with different
+//! toolchains and for different platforms agree on key ownership, as long as
+//! the [Hash] impls of the id and key types do not change.
+use crate::{
+    client::{
+        BidiError, BidiItemError, ClientStreamingError, ClientStreamingItemError, RpcClientError,
+        StreamingResponseError, StreamingResponseItemError, UpdateSink,
+    },
+    discovery::Discovery,
+    message::{BidiStreamingMsg, ClientStreamingMsg, Msg, RpcMsg, ServerStreamingMsg},
+    transport::breaker::BreakerConnection,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt};
+use std::{
+    collections::HashSet,
+    error,
+    fmt::{self, Debug},
+    hash::{Hash, Hasher},
+    result,
+    sync::{Arc, RwLock},
+};
+use tokio::task::JoinHandle;
+
+/// A change to the set of shards of a [ShardedClient]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rebalance<I> {
+    /// A shard was added and now owns some of the keys
+    Added(I),
+    /// A shard was removed and its keys are now owned by the remaining shards
+    Removed(I),
+}
+
+type KeyFn<S, K> = Arc<dyn Fn(&<S as Service>::Req) -> K + Send + Sync>;
+type Hook<I> = Arc<dyn Fn(&Rebalance<I>) + Send + Sync>;
+type Shards<S, C, I> = Arc<RwLock<Vec<(I, RpcClient<S, C>)>>>;
+type Available<S, C> = fn(&RpcClient<S, C>) -> bool;
+
+/// A client for a service that is partitioned across multiple endpoints
+///
+/// `S` is the service type, `C` is the substream source of each shard, `K` is the
+/// key extracted from requests and `I` is the type used to identify shards.
+///
+/// Clones share the same set of shards.
+pub struct ShardedClient<S: Service, C, K, I> {
+    shards: Shards<S, C, I>,
+    key: KeyFn<S, K>,
+    hooks: Arc<RwLock<Vec<Hook<I>>>>,
+    available: Option<Available<S, C>>,
+}
+
+impl<S: Service, C, K, I> Clone for ShardedClient<S, C, K, I> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            key: self.key.clone(),
+            hooks: self.hooks.clone(),
+            available: self.available,
+        }
+    }
+}
+
+impl<S: Service, C, K, I: Debug> Debug for ShardedClient<S, C, K, I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shards = self.shards.read().unwrap();
+        f.debug_struct("ShardedClient")
+            .field(
+                "shards",
+                &shards.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<S, C, K, I> ShardedClient<S, C, K, I>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    K: Hash,
+    I: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Create a new sharded client without any shards
+    ///
+    /// `key` is called for the initial request of every call to determine the
+    /// shard to route the call to.
+    pub fn new(key: impl Fn(&S::Req) -> K + Send + Sync + 'static) -> Self {
+        Self {
+            shards: Default::default(),
+            key: Arc::new(key),
+            hooks: Default::default(),
+            available: None,
+        }
+    }
+
+    /// Add a shard, builder style
+    pub fn with_shard(self, id: I, client: RpcClient<S, C>) -> Self {
+        self.add_shard(id, client);
+        self
+    }
+
+    /// Register a hook that is called whenever the set of shards changes
+    ///
+    /// Hooks are called after the change took effect, so [ShardedClient::owner]
+    /// already reflects the new set of shards. This can be used to migrate or
+    /// invalidate state for keys that changed owner.
+    pub fn on_rebalance(&self, hook: impl Fn(&Rebalance<I>) + Send + Sync + 'static) {
+        self.hooks.write().unwrap().push(Arc::new(hook));
+    }
+
+    /// Add a shard, replacing the client if a shard with the same id exists
+    pub fn add_shard(&self, id: I, client: RpcClient<S, C>) {
+        {
+            let mut shards = self.shards.write().unwrap();
+            match shards.iter_mut().find(|(x, _)| *x == id) {
+                Some((_, existing)) => {
+                    // same id means same keys, so this is not a rebalance
+                    *existing = client;
+                    return;
+                }
+                None => shards.push((id.clone(), client)),
+            }
+        }
+        self.notify(&Rebalance::Added(id));
+    }
+
+    /// Remove a shard, returning its client if it existed
+    pub fn remove_shard(&self, id: &I) -> Option<RpcClient<S, C>> {
+        let removed = {
+            let mut shards = self.shards.write().unwrap();
+            let index = shards.iter().position(|(x, _)| x == id)?;
+            shards.remove(index)
+        };
+        self.notify(&Rebalance::Removed(removed.0));
+        Some(removed.1)
+    }
+
+    /// Keep the shards in sync with the endpoints of `discovery`
+    ///
+    /// This spawns a task that adds a shard, with a client created by
+    /// `connect`, for every new endpoint, and removes the shards of endpoints
+    /// that went away, calling the hooks registered with
+    /// [ShardedClient::on_rebalance]. Shards added by hand are left alone. The
+    /// task runs until the discovery stops reporting changes, or until it is
+    /// aborted.
+    pub fn discover<D, F>(&self, discovery: &D, connect: F) -> JoinHandle<()>
+    where
+        D: Discovery<Endpoint = I>,
+        F: Fn(&I) -> RpcClient<S, C> + Send + 'static,
+        K: 'static,
+        I: Debug,
+    {
+        let this = self.clone();
+        let mut changes = discovery.watch();
+        tokio::spawn(async move {
+            let mut known = HashSet::new();
+            while let Some(endpoints) = changes.next().await {
+                let endpoints = match endpoints {
+                    Ok(endpoints) => endpoints.into_iter().collect::<HashSet<_>>(),
+                    Err(cause) => {
+                        // keep the shards we have until discovery works again
+                        tracing::debug!("Discovery failed: {}", cause);
+                        continue;
+                    }
+                };
+                for id in known.difference(&endpoints) {
+                    this.remove_shard(id);
+                }
+                for id in endpoints.difference(&known) {
+                    tracing::debug!("Discovered shard {:?}", id);
+                    this.add_shard(id.clone(), connect(id));
+                }
+                known = endpoints;
+            }
+        })
+    }
+
+    /// The ids of all shards
+    pub fn shards(&self) -> Vec<I> {
+        let shards = self.shards.read().unwrap();
+        shards.iter().map(|(id, _)| id.clone()).collect()
+    }
+
+    /// The id of the shard that owns `key`, or `None` if there are no shards
+    pub fn owner(&self, key: &K) -> Option<I> {
+        let shards = self.shards.read().unwrap();
+        pick(&shards, key).map(|(id, _)| id.clone())
+    }
+
+    /// The client of the shard that owns `key`, or `None` if there are no shards
+    ///
+    /// With [ShardedClient::with_circuit_breakers], shards whose breaker is
+    /// open are skipped, unless all of them are.
+    pub fn client_for_key(&self, key: &K) -> Option<RpcClient<S, C>> {
+        let shards = self.shards.read().unwrap();
+        if let Some(available) = self.available {
+            let available = shards
+                .iter()
+                .filter(|(_, client)| available(client))
+                .map(|(id, client)| (id, client))
+                .collect::<Vec<_>>();
+            if let Some((_, client)) = pick(&available, key) {
+                return Some((*client).clone());
+            }
+        }
+        pick(&shards, key).map(|(_, client)| client.clone())
+    }
+
+    /// Route a message to its shard, giving it back together with the client
+    fn route<M: Msg<S>, E>(&self, msg: M) -> result::Result<(RpcClient<S, C>, M), ShardError<E>> {
+        let req: S::Req = msg.into();
+        let key = (self.key)(&req);
+        let client = self.client_for_key(&key).ok_or(ShardError::NoShards)?;
+        match M::try_from(req) {
+            Ok(msg) => Ok((client, msg)),
+            // the request was just created from a M
+            Err(_) => unreachable!("request does not convert back to message"),
+        }
+    }
+
+    /// RPC call to the shard owning the key of the request
+    ///
+    /// See [RpcClient::rpc].
+    pub async fn rpc<M>(&self, msg: M) -> result::Result<M::Response, ShardError<RpcClientError<C>>>
+    where
+        M: RpcMsg<S>,
+    {
+        let (client, msg) = self.route(msg)?;
+        client.rpc(msg).await.map_err(ShardError::Call)
+    }
+
+    /// Server streaming call to the shard owning the key of the request
+    ///
+    /// See [RpcClient::server_streaming].
+    pub async fn server_streaming<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<
+        BoxStream<'static, result::Result<M::Response, StreamingResponseItemError<C>>>,
+        ShardError<StreamingResponseError<C>>,
+    >
+    where
+        M: ServerStreamingMsg<S>,
+    {
+        let (client, msg) = self.route(msg)?;
+        client.server_streaming(msg).await.map_err(ShardError::Call)
+    }
+
+    /// Client streaming call to the shard owning the key of the request
+    ///
+    /// See [RpcClient::client_streaming].
+    pub async fn client_streaming<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<
+        (
+            UpdateSink<S, C, M::Update>,
+            BoxFuture<'static, result::Result<M::Response, ClientStreamingItemError<C>>>,
+        ),
+        ShardError<ClientStreamingError<C>>,
+    >
+    where
+        M: ClientStreamingMsg<S>,
+    {
+        let (client, msg) = self.route(msg)?;
+        client.client_streaming(msg).await.map_err(ShardError::Call)
+    }
+
+    /// Bidi streaming call to the shard owning the key of the request
+    ///
+    /// See [RpcClient::bidi].
+    pub async fn bidi<M>(
+        &self,
+        msg: M,
+    ) -> result::Result<
+        (
+            UpdateSink<S, C, M::Update>,
+            BoxStream<'static, result::Result<M::Response, BidiItemError<C>>>,
+        ),
+        ShardError<BidiError<C>>,
+    >
+    where
+        M: BidiStreamingMsg<S>,
+    {
+        let (client, msg) = self.route(msg)?;
+        client.bidi(msg).await.map_err(ShardError::Call)
+    }
+
+    fn notify(&self, event: &Rebalance<I>) {
+        let hooks = self.hooks.read().unwrap().clone();
+        for hook in hooks {
+            hook(event);
+        }
+    }
+}
+
+impl<S, C, K, I> ShardedClient<S, BreakerConnection<C, S::Res, S::Req>, K, I>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    K: Hash,
+    I: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// Route calls around shards whose [CircuitBreaker](crate::transport::breaker::CircuitBreaker) is open
+    ///
+    /// The keys of a shard with an open breaker are owned by the remaining
+    /// shards until the breaker lets calls through again, so broken shards are
+    /// ejected and readmitted automatically. This is not a rebalance, so the
+    /// hooks registered with [ShardedClient::on_rebalance] are not called. If
+    /// the breakers of all shards are open, calls fail fast with the error of
+    /// the breaker.
+    pub fn with_circuit_breakers(mut self) -> Self {
+        self.available = Some(|client| client.inner().breaker().is_available());
+        self
+    }
+}
+
+/// Rendezvous hashing: the shard with the highest score for the key wins
+fn pick<'a, I: Hash, K: Hash, T>(shards: &'a [(I, T)], key: &K) -> Option<&'a (I, T)> {
+    shards.iter().max_by_key(|(id, _)| {
+        let mut hasher = Fnv1a::default();
+        id.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish()
+    })
+}
+
+/// 64 bit FNV-1a, which unlike the hasher of the standard library is specified
+/// and the same for every build
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // integers are hashed as native endian bytes by default
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        // lengths of slices and strings, the same on 32 and 64 bit platforms
+        self.write_u64(i as u64)
+    }
+}
+
+/// Error for calls on a [ShardedClient]
+#[derive(Debug)]
+pub enum ShardError<E> {
+    /// There are no shards to route the call to
+    NoShards,
+    /// The call on the shard failed
+    Call(E),
+}
+
+impl<E: Debug> fmt::Display for ShardError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: Debug> error::Error for ShardError<E> {}
@@ -0,0 +1,169 @@
+//! Refusing to talk over a connection that is weaker than required
+//!
+//! By default, a client uses whatever the connection and the server offer: a
+//! transport without encryption, frames without compression, or an old
+//! version of a message when the server has not been upgraded yet. In
+//! compliance sensitive deployments, silently falling back like this is
+//! worse than failing.
+//!
+//! [Requirements] describe the weakest connection a client accepts. They can
+//! be checked for a connection with [Requirements::check] before it is used:
+//!
+//! ```ignore
+//! let requirements = Requirements::new()
+//!     .with_capabilities(Capabilities { encrypted: true, ..Capabilities::NONE })
+//!     .with_compression();
+//! requirements.check(&connection)?;
+//! let client = RpcClient::<StoreService, _>::new(connection);
+//! ```
+//!
+//! A [VersionedClient] with [VersionedClient::with_requirements] also checks
+//! the versions it negotiates with the server, see
+//! [Requirements::with_min_version], and refuses to send calls if anything
+//! is weaker than required.
+#[cfg(doc)]
+use crate::versions::VersionedClient;
+use crate::{
+    telemetry::method_name,
+    transport::{Capabilities, ConnectionCommon},
+    versions::Versioned,
+    Service,
+};
+use std::{collections::BTreeMap, error, fmt};
+
+/// The weakest connection a client accepts
+#[derive(Debug, Clone)]
+pub struct Requirements {
+    capabilities: Capabilities,
+    compression: bool,
+    min_versions: BTreeMap<&'static str, u32>,
+}
+
+impl Default for Requirements {
+    fn default() -> Self {
+        Self {
+            capabilities: Capabilities::NONE,
+            compression: false,
+            min_versions: BTreeMap::new(),
+        }
+    }
+}
+
+impl Requirements {
+    /// No requirements at all
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require the transport to give all guarantees of `required`
+    pub fn with_capabilities(mut self, required: Capabilities) -> Self {
+        self.capabilities = required;
+        self
+    }
+
+    /// Require frames to be compressed, see [ConnectionCommon::compressed]
+    pub fn with_compression(mut self) -> Self {
+        self.compression = true;
+        self
+    }
+
+    /// Require calls of the logical message of `V` to be sent as `V` or a newer version
+    pub fn with_min_version<S: Service, V: Versioned<S>>(mut self) -> Self {
+        self.min_versions
+            .insert(method_name::<V::Canonical>(), V::VERSION);
+        self
+    }
+
+    /// Check that `connection` meets the requirements for the transport
+    ///
+    /// Versions are only known once they are negotiated with the server, so
+    /// they are not checked here.
+    pub fn check<In, Out, C: ConnectionCommon<In, Out>>(
+        &self,
+        connection: &C,
+    ) -> Result<(), Downgrade> {
+        let missing = C::CAPABILITIES.missing(self.capabilities);
+        if missing != Capabilities::NONE {
+            return Err(Downgrade::Capabilities { missing });
+        }
+        if self.compression && !connection.compressed() {
+            return Err(Downgrade::Compression);
+        }
+        Ok(())
+    }
+
+    /// Check that `version` is recent enough for the logical message named `method`
+    pub(crate) fn check_version(
+        &self,
+        method: &'static str,
+        version: u32,
+    ) -> Result<(), Downgrade> {
+        match self.min_versions.get(method) {
+            Some(&required) if version < required => Err(Downgrade::Version {
+                method,
+                required,
+                negotiated: version,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A connection that is weaker than the [Requirements]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Downgrade {
+    /// The transport does not give these required guarantees
+    Capabilities {
+        /// The required guarantees the transport does not give
+        missing: Capabilities,
+    },
+    /// Frames are not compressed
+    Compression,
+    /// The newest version both sides support is older than required
+    Version {
+        /// The name of the canonical message
+        method: &'static str,
+        /// The oldest version that is allowed
+        required: u32,
+        /// The version that would have been used
+        negotiated: u32,
+    },
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Downgrade::Capabilities { missing } => {
+                let names = [
+                    (missing.ordered, "ordered"),
+                    (missing.reliable, "reliable"),
+                    (missing.encrypted, "encrypted"),
+                    (missing.multiplexed, "multiplexed"),
+                ];
+                let names = names
+                    .iter()
+                    .filter(|(missing, _)| *missing)
+                    .map(|(_, name)| *name)
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "the transport is not {}, which is required",
+                    names.join(", ")
+                )
+            }
+            Downgrade::Compression => {
+                write!(f, "frames are not compressed, which is required")
+            }
+            Downgrade::Version {
+                method,
+                required,
+                negotiated,
+            } => write!(
+                f,
+                "{method} would be sent as version {negotiated}, but at least version {required} is required"
+            ),
+        }
+    }
+}
+
+impl error::Error for Downgrade {}
@@ -0,0 +1,655 @@
+//! Subscriptions that survive process restarts
+//!
+//! A subscription is a server streaming call that is expected to run for a long
+//! time. [SubscriptionClient] persists the request of every subscription in a
+//! [SessionStore], and updates it after each response using [Resumable::advance].
+//! After a restart, [SubscriptionClient::resume] re-establishes all persisted
+//! subscriptions of a message type, continuing after the last response that was
+//! received.
+//!
+//! Subscription state can contain resume tokens, so [EncryptedStore] can be
+//! used to encrypt it before it is written to disk by e.g. a [FileStore].
+//!
+//! The same mechanism moves subscriptions between server instances. A server
+//! that is about to shut down starts a [Drain], which ends its subscription
+//! streams with a response carrying a [Redirect] to another instance. A
+//! subscription client configured with [SubscriptionClient::with_redirects]
+//! connects to that instance and resumes the subscriptions there, without the
+//! redirect showing up in the stream.
+use crate::{
+    client::{StreamingResponseError, StreamingResponseItemError},
+    message::ServerStreamingMsg,
+    transport::ConnectionErrors,
+    RpcClient, Service, ServiceConnection,
+};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    Future, FutureExt, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error,
+    fmt::{self, Debug},
+    io,
+    path::PathBuf,
+    result,
+    sync::{Arc, Mutex},
+};
+
+/// A server streaming request that can be resumed
+pub trait Resumable<S: Service>: ServerStreamingMsg<S> + Clone {
+    /// Advance the request past `response`
+    ///
+    /// Sending the advanced request must continue the stream right after
+    /// `response`, e.g. by storing the offset or resume token of the response.
+    fn advance(&mut self, response: &Self::Response);
+
+    /// The redirect in `response`, if the server that sent it is draining
+    ///
+    /// With [SubscriptionClient::with_redirects], the subscription is resumed
+    /// on the endpoint of the redirect instead of returning the response. The
+    /// default is to never redirect.
+    fn redirect(_response: &Self::Response) -> Option<Redirect> {
+        None
+    }
+
+    /// Prepare the request for being resumed on another server, with the token
+    /// of a [Redirect]
+    ///
+    /// The default does nothing, since the request has already been advanced
+    /// past the last response before the redirect.
+    fn redirected(&mut self, _token: &[u8]) {}
+}
+
+/// Hint from a draining server to resume a subscription on another instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redirect {
+    /// The instance to resume on, as understood by the connect function of
+    /// [SubscriptionClient::with_redirects]
+    pub endpoint: String,
+    /// Opaque token for the new instance, see [Resumable::redirected]
+    pub token: Vec<u8>,
+}
+
+/// Ends the subscriptions of a server with a [Redirect], before it shuts down
+///
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Drain(Arc<tokio::sync::watch::Sender<Option<String>>>);
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self(Arc::new(tokio::sync::watch::channel(None).0))
+    }
+}
+
+impl Drain {
+    /// Create a new drain, that has not started yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start draining, redirecting all subscriptions to `endpoint`
+    pub fn start(&self, endpoint: impl Into<String>) {
+        self.0.send_replace(Some(endpoint.into()));
+    }
+
+    /// The endpoint subscriptions are redirected to, once draining has started
+    pub fn endpoint(&self) -> Option<String> {
+        self.0.borrow().clone()
+    }
+
+    /// End `responses` with a redirect once draining starts
+    ///
+    /// `redirect` creates the response that carries the [Redirect], given the
+    /// endpoint passed to [Drain::start]. Subscriptions that start while
+    /// draining are redirected right away.
+    pub fn wrap<St, F>(&self, responses: St, redirect: F) -> BoxStream<'static, St::Item>
+    where
+        St: Stream + Send + 'static,
+        St::Item: Send + 'static,
+        F: FnOnce(String) -> St::Item + Send + 'static,
+    {
+        let state = Some((responses.boxed(), Some(self.0.subscribe()), redirect));
+        stream::unfold(state, |state| async move {
+            let (mut responses, mut draining, redirect) = state?;
+            loop {
+                let rx = match draining.as_mut() {
+                    Some(rx) => rx,
+                    // all drains are gone, so this will never be redirected
+                    None => {
+                        let item = responses.next().await?;
+                        return Some((item, Some((responses, None, redirect))));
+                    }
+                };
+                let endpoint = rx.borrow_and_update().clone();
+                if let Some(endpoint) = endpoint {
+                    return Some((redirect(endpoint), None));
+                }
+                tokio::select! {
+                    item = responses.next() => {
+                        let item = item?;
+                        return Some((item, Some((responses, draining, redirect))));
+                    }
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            draining = None;
+                        }
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+/// Connects to the endpoint of a [Redirect]
+type Connector<S, C> =
+    Arc<dyn Fn(String) -> BoxFuture<'static, io::Result<RpcClient<S, C>>> + Send + Sync>;
+
+/// The instance subscriptions were last redirected to, with its endpoint
+type Redirected<S, C> = Arc<tokio::sync::Mutex<Option<(String, RpcClient<S, C>)>>>;
+
+/// Persisted subscription state, as pairs of subscription id and state
+pub type Sessions = Vec<(String, Vec<u8>)>;
+
+/// Storage for subscription state
+///
+/// State is opaque bytes, keyed by subscription id.
+pub trait SessionStore: Debug + Send + Sync + 'static {
+    /// Load the state of all subscriptions
+    fn load(&self) -> BoxFuture<'_, io::Result<Sessions>>;
+
+    /// Store the state of a subscription, replacing the previous state
+    fn save<'a>(&'a self, id: &'a str, state: Vec<u8>) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Remove the state of a subscription
+    ///
+    /// Removing a subscription that does not exist is not an error.
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// A [SessionStore] that keeps state in memory
+///
+/// Clones share the same state. This does not survive restarts, but is useful
+/// for tests and as the inner store of an [EncryptedStore] in tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemStore(Arc<Mutex<BTreeMap<String, Vec<u8>>>>);
+
+impl MemStore {
+    /// Create a new, empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SessionStore for MemStore {
+    fn load(&self) -> BoxFuture<'_, io::Result<Sessions>> {
+        let state = self.0.lock().unwrap();
+        let res = state.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        futures::future::ok(res).boxed()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        self.0.lock().unwrap().insert(id.to_string(), state);
+        futures::future::ok(()).boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.0.lock().unwrap().remove(id);
+        futures::future::ok(()).boxed()
+    }
+}
+
+/// A [SessionStore] that keeps the state of each subscription in a file
+///
+/// Files are named after the hex encoded subscription id and are replaced
+/// atomically, so a crash while saving leaves the previous state intact.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+const FILE_EXTENSION: &str = "sub";
+
+impl FileStore {
+    /// Create a store in `dir`, which is created on the first save
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        let name: String = id.bytes().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name).with_extension(FILE_EXTENSION)
+    }
+}
+
+fn decode_hex(name: &str) -> Option<String> {
+    if name.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl SessionStore for FileStore {
+    fn load(&self) -> BoxFuture<'_, io::Result<Sessions>> {
+        async move {
+            let mut res = Vec::new();
+            let mut entries = match tokio::fs::read_dir(&self.dir).await {
+                Ok(entries) => entries,
+                Err(cause) if cause.kind() == io::ErrorKind::NotFound => return Ok(res),
+                Err(cause) => return Err(cause),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if path.extension().and_then(|x| x.to_str()) != Some(FILE_EXTENSION) {
+                    continue;
+                }
+                // skip files that were not written by us
+                let id = match path
+                    .file_stem()
+                    .and_then(|x| x.to_str())
+                    .and_then(decode_hex)
+                {
+                    Some(id) => id,
+                    None => continue,
+                };
+                res.push((id, tokio::fs::read(&path).await?));
+            }
+            res.sort();
+            Ok(res)
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            let path = self.path(id);
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(&tmp, state).await?;
+            tokio::fs::rename(&tmp, &path).await
+        }
+        .boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            match tokio::fs::remove_file(self.path(id)).await {
+                Err(cause) if cause.kind() == io::ErrorKind::NotFound => Ok(()),
+                res => res,
+            }
+        }
+        .boxed()
+    }
+}
+
+/// A [SessionStore] that encrypts state before passing it to an inner store
+///
+/// State is encrypted with ChaCha20-Poly1305 and a random nonce. The
+/// subscription id is authenticated as well, so state can not be moved to a
+/// different subscription. Loading state that was not encrypted with the same
+/// key fails with [io::ErrorKind::InvalidData].
+pub struct EncryptedStore<St> {
+    inner: St,
+    cipher: ChaCha20Poly1305,
+}
+
+impl<St: Debug> Debug for EncryptedStore<St> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedStore")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+const NONCE_LEN: usize = 12;
+
+impl<St: SessionStore> EncryptedStore<St> {
+    /// Wrap a store, encrypting state with a 256 bit key
+    pub fn new(inner: St, key: [u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Get back the inner store
+    pub fn into_inner(self) -> St {
+        self.inner
+    }
+
+    fn decrypt(&self, id: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "encrypted state too short",
+            ));
+        }
+        let (nonce, msg) = data.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg,
+            aad: id.as_bytes(),
+        };
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unable to decrypt state"))
+    }
+}
+
+impl<St: SessionStore> SessionStore for EncryptedStore<St> {
+    fn load(&self) -> BoxFuture<'_, io::Result<Sessions>> {
+        async move {
+            let res = self.inner.load().await?;
+            res.into_iter()
+                .map(|(id, data)| {
+                    let state = self.decrypt(&id, &data)?;
+                    Ok((id, state))
+                })
+                .collect()
+        }
+        .boxed()
+    }
+
+    fn save<'a>(&'a self, id: &'a str, state: Vec<u8>) -> BoxFuture<'a, io::Result<()>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &state,
+            aad: id.as_bytes(),
+        };
+        let data = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map(|encrypted| [nonce.as_slice(), &encrypted].concat())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "unable to encrypt state"));
+        async move { self.inner.save(id, data?).await }.boxed()
+    }
+
+    fn remove<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.inner.remove(id)
+    }
+}
+
+/// Stream of responses of a subscription
+pub type SubscriptionStream<R, C> = BoxStream<'static, result::Result<R, SubscriptionError<C>>>;
+
+/// A client that persists subscriptions so they can be resumed after a restart
+///
+/// A subscription stays persisted until the server ends the stream, or until it
+/// is removed using [SubscriptionClient::unsubscribe]. Errors, including the
+/// process going away, leave the state in place so the subscription is resumed.
+pub struct SubscriptionClient<S, C, St> {
+    client: RpcClient<S, C>,
+    store: Arc<St>,
+    connect: Option<Connector<S, C>>,
+    /// The client of the instance the last redirect pointed to, with its endpoint
+    redirected: Redirected<S, C>,
+}
+
+impl<S, C: Clone, St> Clone for SubscriptionClient<S, C, St> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            store: self.store.clone(),
+            connect: self.connect.clone(),
+            redirected: self.redirected.clone(),
+        }
+    }
+}
+
+impl<S, C, St: Debug> Debug for SubscriptionClient<S, C, St> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionClient")
+            .field("store", &self.store)
+            .field("redirects", &self.connect.is_some())
+            .finish()
+    }
+}
+
+impl<S, C, St> SubscriptionClient<S, C, St>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    St: SessionStore,
+{
+    /// Create a new subscription client
+    pub fn new(client: RpcClient<S, C>, store: St) -> Self {
+        Self {
+            client,
+            store: Arc::new(store),
+            connect: None,
+            redirected: Default::default(),
+        }
+    }
+
+    /// Follow the [Redirect]s of draining servers
+    ///
+    /// When a response of a subscription is a redirect, see
+    /// [Resumable::redirect], `connect` is called with its endpoint and the
+    /// subscription is resumed on the returned client. Subscriptions that are
+    /// started or resumed later use that client as well, and redirects to the
+    /// same endpoint share it.
+    pub fn with_redirects<F, Fut>(mut self, connect: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<RpcClient<S, C>>> + Send + 'static,
+    {
+        self.connect = Some(Arc::new(move |endpoint| connect(endpoint).boxed()));
+        self
+    }
+
+    /// The store used to persist subscriptions
+    pub fn store(&self) -> &St {
+        &self.store
+    }
+
+    /// Start a new subscription
+    ///
+    /// The request is persisted before the call is made. If a subscription with
+    /// the same id exists, its state is replaced.
+    pub async fn subscribe<M>(
+        &self,
+        id: impl Into<String>,
+        msg: M,
+    ) -> result::Result<SubscriptionStream<M::Response, C>, SubscriptionError<C>>
+    where
+        M: Resumable<S>,
+    {
+        let id = id.into();
+        save::<S, C, St>(&self.store, &id, msg.clone().into()).await?;
+        self.open(id, msg).await
+    }
+
+    /// Resume all persisted subscriptions of message type `M`
+    ///
+    /// Subscriptions of other message types are left alone, so they can be
+    /// resumed by another call with the matching type. Subscriptions whose state
+    /// can not be decoded are skipped and left in the store.
+    pub async fn resume<M>(
+        &self,
+    ) -> result::Result<Vec<(String, SubscriptionStream<M::Response, C>)>, SubscriptionError<C>>
+    where
+        M: Resumable<S>,
+    {
+        let mut res = Vec::new();
+        for (id, state) in self.store.load().await.map_err(SubscriptionError::Store)? {
+            let req: S::Req = match bincode::deserialize(&state) {
+                Ok(req) => req,
+                Err(cause) => {
+                    tracing::warn!("skipping subscription {}: {}", id, cause);
+                    continue;
+                }
+            };
+            if let Ok(msg) = M::try_from(req) {
+                let stream = self.open(id.clone(), msg).await?;
+                res.push((id, stream));
+            }
+        }
+        Ok(res)
+    }
+
+    /// The ids of all persisted subscriptions
+    pub async fn subscriptions(&self) -> result::Result<Vec<String>, SubscriptionError<C>> {
+        let res = self.store.load().await.map_err(SubscriptionError::Store)?;
+        Ok(res.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Remove a subscription, so it is no longer resumed
+    ///
+    /// This does not close a stream that is currently open, but that stream no
+    /// longer updates the persisted state once it is dropped.
+    pub async fn unsubscribe(&self, id: &str) -> result::Result<(), SubscriptionError<C>> {
+        self.store
+            .remove(id)
+            .await
+            .map_err(SubscriptionError::Store)
+    }
+
+    async fn open<M>(
+        &self,
+        id: String,
+        msg: M,
+    ) -> result::Result<SubscriptionStream<M::Response, C>, SubscriptionError<C>>
+    where
+        M: Resumable<S>,
+    {
+        let responses = self
+            .current()
+            .await
+            .server_streaming(msg.clone())
+            .await
+            .map_err(SubscriptionError::Open)?;
+        let state = Some((responses, msg, self.clone(), id));
+        let stream = stream::unfold(state, |state| async move {
+            let (mut responses, mut msg, this, id) = state?;
+            loop {
+                return match responses.next().await {
+                    Some(Ok(response)) => {
+                        if let Some(redirect) = this.connect.as_ref().and(M::redirect(&response)) {
+                            tracing::debug!(
+                                "subscription {} redirected to {}",
+                                id,
+                                redirect.endpoint
+                            );
+                            msg.redirected(&redirect.token);
+                            match this.follow(&id, msg.clone(), redirect.endpoint).await {
+                                Ok(redirected) => {
+                                    responses = redirected;
+                                    continue;
+                                }
+                                // the state is kept, so the subscription can be resumed
+                                Err(cause) => Some((Err(cause), None)),
+                            }
+                        } else {
+                            msg.advance(&response);
+                            let res = save::<S, C, St>(&this.store, &id, msg.clone().into())
+                                .await
+                                .map(|_| response);
+                            Some((res, Some((responses, msg, this, id))))
+                        }
+                    }
+                    // the state is kept, so the subscription can be resumed
+                    Some(Err(cause)) => Some((Err(SubscriptionError::Recv(cause)), None)),
+                    None => match this.store.remove(&id).await {
+                        // the server ended the subscription
+                        Ok(()) => None,
+                        Err(cause) => Some((Err(SubscriptionError::Store(cause)), None)),
+                    },
+                };
+            }
+        });
+        Ok(stream.boxed())
+    }
+
+    /// The client of the instance subscriptions are currently made on
+    async fn current(&self) -> RpcClient<S, C> {
+        match &*self.redirected.lock().await {
+            Some((_, client)) => client.clone(),
+            None => self.client.clone(),
+        }
+    }
+
+    /// Resume a redirected subscription on the instance at `endpoint`
+    async fn follow<M>(
+        &self,
+        id: &str,
+        msg: M,
+        endpoint: String,
+    ) -> result::Result<
+        BoxStream<'static, result::Result<M::Response, StreamingResponseItemError<C>>>,
+        SubscriptionError<C>,
+    >
+    where
+        M: Resumable<S>,
+    {
+        save::<S, C, St>(&self.store, id, msg.clone().into()).await?;
+        let client = {
+            let mut redirected = self.redirected.lock().await;
+            match &*redirected {
+                Some((current, client)) if *current == endpoint => client.clone(),
+                _ => {
+                    let connect = self.connect.as_ref().expect("redirects are followed");
+                    let client = connect(endpoint.clone())
+                        .await
+                        .map_err(SubscriptionError::Connect)?;
+                    *redirected = Some((endpoint, client.clone()));
+                    client
+                }
+            }
+        };
+        client
+            .server_streaming(msg)
+            .await
+            .map_err(SubscriptionError::Open)
+    }
+}
+
+async fn save<S, C, St>(
+    store: &St,
+    id: &str,
+    req: S::Req,
+) -> result::Result<(), SubscriptionError<C>>
+where
+    S: Service,
+    C: ConnectionErrors,
+    St: SessionStore,
+{
+    let state = bincode::serialize(&req).map_err(SubscriptionError::Codec)?;
+    store
+        .save(id, state)
+        .await
+        .map_err(SubscriptionError::Store)
+}
+
+/// Error for a [SubscriptionClient]
+#[derive(Debug)]
+pub enum SubscriptionError<C: ConnectionErrors> {
+    /// Unable to load or store subscription state
+    Store(io::Error),
+    /// Unable to encode or decode subscription state
+    Codec(bincode::Error),
+    /// Unable to start the streaming call
+    Open(StreamingResponseError<C>),
+    /// Unable to receive a response
+    Recv(StreamingResponseItemError<C>),
+    /// Unable to connect to the instance a subscription was redirected to
+    Connect(io::Error),
+}
+
+impl<C: ConnectionErrors> fmt::Display for SubscriptionError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for SubscriptionError<C> {}
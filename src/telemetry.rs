@@ -0,0 +1,469 @@
+//! Telemetry for rpc calls
+//!
+//! Every call on the client and the server side is wrapped in a [tracing] span
+//! that uses the field names of the OpenTelemetry semantic conventions for rpc
+//! systems (`rpc.system`, `rpc.service`, `rpc.method`). These spans can be
+//! exported via OTLP using `tracing-opentelemetry`.
+//!
+//! With the `opentelemetry-metrics` feature, the duration of each call is also
+//! recorded in the `rpc.client.duration` and `rpc.server.duration` histograms
+//! of the global OpenTelemetry meter provider. Transports that track calls in
+//! flight report them in the `rpc.server.in_flight` counter, and optionally
+//! per connection in the `rpc.server.connection.in_flight` counter,
+//! and transports that sample path statistics report them in the
+//! `quic.connection.*` instruments. With the `openmetrics` feature, the same
+//! measurements are also collected in the registry of [crate::metrics].
+//!
+//! Server calls also record how long each phase of the call took, in
+//! microseconds, in the fields of the call span:
+//!
+//! - `rpc.decode_us`: reading and decoding the first request, after the
+//!   channel was accepted
+//! - `rpc.queue_us`: waiting for a handler, between reading the request and
+//!   the start of the call
+//! - `rpc.handler_us`: running the handler
+//! - `rpc.encode_us`: waiting for room in the send sink and encoding the
+//!   response
+//! - `rpc.send_us`: flushing the response to the transport
+//!
+//! The phases of the handler and the response of rpc and client streaming
+//! calls run in the child spans `rpc.handler`, `rpc.encode` and `rpc.send`,
+//! so tools that build flamegraphs from span timings, like `tracing-flame`,
+//! can attribute the time spent within a call.
+use futures::Future;
+use std::{
+    any::type_name,
+    time::{Duration, Instant},
+};
+use tracing::Instrument;
+
+/// Value of the `rpc.system` attribute
+pub(crate) const RPC_SYSTEM: &str = "quic-rpc";
+
+/// The side of a call, used for the span kind and to pick the metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    fn kind(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Server => "server",
+        }
+    }
+}
+
+/// Telemetry for a single call
+///
+/// The span is closed and the duration is recorded when this is dropped, so
+/// for streaming calls this should be kept alive as long as the call.
+#[derive(Debug)]
+pub(crate) struct Call {
+    span: tracing::Span,
+    #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+    start: Instant,
+    #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+    side: Side,
+    #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+    service: &'static str,
+    #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+    method: &'static str,
+    #[cfg(feature = "openmetrics")]
+    labels: crate::metrics::Labels,
+}
+
+impl Call {
+    /// Start telemetry for a call of message type `M` on service `S`
+    pub(crate) fn start<S: 'static, M: 'static>(side: Side) -> Self {
+        let service = type_name::<S>();
+        let method = method_name::<M>();
+        let span = tracing::debug_span!(
+            "rpc",
+            rpc.system = RPC_SYSTEM,
+            rpc.service = service,
+            rpc.method = method,
+            otel.kind = side.kind(),
+            net.sock.peer.addr = tracing::field::Empty,
+            rpc.decode_us = tracing::field::Empty,
+            rpc.queue_us = tracing::field::Empty,
+            rpc.handler_us = tracing::field::Empty,
+            rpc.encode_us = tracing::field::Empty,
+            rpc.send_us = tracing::field::Empty,
+        );
+        Self {
+            span,
+            #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+            start: Instant::now(),
+            #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+            side,
+            #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+            service,
+            #[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+            method,
+            #[cfg(feature = "openmetrics")]
+            labels: Vec::new(),
+        }
+    }
+
+    /// The span for this call
+    pub(crate) fn span(&self) -> &tracing::Span {
+        &self.span
+    }
+
+    /// Record how long reading the first request and waiting for the call took,
+    /// and the labels extracted from the request
+    pub(crate) fn received(self, received: Option<Received>) -> Self {
+        #[allow(unused_mut)]
+        let mut call = self;
+        if let Some(received) = received {
+            if let Some(peer) = received.peer {
+                call.span.record("net.sock.peer.addr", peer.to_string());
+            }
+            let decode = received.decoded - received.accepted;
+            call.span.record("rpc.decode_us", micros(decode));
+            call.span
+                .record("rpc.queue_us", micros(received.decoded.elapsed()));
+            #[cfg(feature = "openmetrics")]
+            {
+                call.labels = received.labels;
+            }
+        }
+        call
+    }
+
+    /// Run a phase of the call in a child span, and record how long it took
+    pub(crate) async fn phase<F: Future>(&self, phase: Phase, f: F) -> F::Output {
+        let span = match phase {
+            Phase::Handler => tracing::debug_span!(parent: &self.span, "rpc.handler"),
+            Phase::Encode => tracing::debug_span!(parent: &self.span, "rpc.encode"),
+            Phase::Send => tracing::debug_span!(parent: &self.span, "rpc.send"),
+        };
+        let start = Instant::now();
+        let res = f.instrument(span).await;
+        self.span.record(phase.field(), micros(start.elapsed()));
+        res
+    }
+}
+
+/// When the first request of a server call was read
+///
+/// Taken when the request is accepted, and passed on to the call.
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+    /// The channel was accepted
+    pub accepted: Instant,
+    /// The first request was read and decoded
+    pub decoded: Instant,
+    /// The address of the client, if the transport knows it
+    pub peer: Option<std::net::SocketAddr>,
+    /// The metric labels extracted from the first request
+    #[cfg(feature = "openmetrics")]
+    pub labels: crate::metrics::Labels,
+}
+
+/// A phase of a server call that runs in its own span
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    Handler,
+    Encode,
+    Send,
+}
+
+impl Phase {
+    fn field(self) -> &'static str {
+        match self {
+            Phase::Handler => "rpc.handler_us",
+            Phase::Encode => "rpc.encode_us",
+            Phase::Send => "rpc.send_us",
+        }
+    }
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(any(feature = "opentelemetry-metrics", feature = "openmetrics"))]
+impl Drop for Call {
+    fn drop(&mut self) {
+        #[cfg(feature = "openmetrics")]
+        crate::metrics::call_duration(
+            self.side,
+            self.service,
+            self.method,
+            std::mem::take(&mut self.labels),
+            self.start.elapsed(),
+        );
+        #[cfg(feature = "opentelemetry-metrics")]
+        {
+            use opentelemetry::{Context, KeyValue};
+            let elapsed = self.start.elapsed().as_secs_f64() * 1000.0;
+            let histogram = match self.side {
+                Side::Client => &metrics::INSTRUMENTS.client_duration,
+                Side::Server => &metrics::INSTRUMENTS.server_duration,
+            };
+            histogram.record(
+                &Context::current(),
+                elapsed,
+                &[
+                    KeyValue::new("rpc.system", RPC_SYSTEM),
+                    KeyValue::new("rpc.service", self.service),
+                    KeyValue::new("rpc.method", self.method),
+                ],
+            );
+        }
+    }
+}
+
+#[cfg(feature = "opentelemetry-metrics")]
+mod metrics {
+    use once_cell::sync::Lazy;
+    use opentelemetry::metrics::{Counter, Histogram, Unit, UpDownCounter};
+
+    pub(super) struct Instruments {
+        pub client_duration: Histogram<f64>,
+        pub server_duration: Histogram<f64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub server_in_flight: UpDownCounter<i64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub connection_in_flight: UpDownCounter<i64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub connection_rtt: Histogram<f64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub connection_cwnd: Histogram<u64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub connection_lost_packets: Counter<u64>,
+        #[cfg_attr(not(feature = "quinn-transport"), allow(dead_code))]
+        pub connection_congestion_events: Counter<u64>,
+        #[cfg_attr(not(feature = "zstd-compression"), allow(dead_code))]
+        pub compression_input: Counter<u64>,
+        #[cfg_attr(not(feature = "zstd-compression"), allow(dead_code))]
+        pub compression_output: Counter<u64>,
+        #[cfg_attr(not(feature = "zstd-compression"), allow(dead_code))]
+        pub compression_skipped: Counter<u64>,
+    }
+
+    pub(super) static INSTRUMENTS: Lazy<Instruments> = Lazy::new(|| {
+        let meter = opentelemetry::global::meter("quic-rpc");
+        Instruments {
+            client_duration: meter
+                .f64_histogram("rpc.client.duration")
+                .with_description("Duration of outbound rpc calls")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            server_duration: meter
+                .f64_histogram("rpc.server.duration")
+                .with_description("Duration of inbound rpc calls")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            server_in_flight: meter
+                .i64_up_down_counter("rpc.server.in_flight")
+                .with_description("Number of inbound rpc calls in flight")
+                .init(),
+            connection_in_flight: meter
+                .i64_up_down_counter("rpc.server.connection.in_flight")
+                .with_description("Number of inbound rpc calls in flight per connection")
+                .init(),
+            connection_rtt: meter
+                .f64_histogram("quic.connection.rtt")
+                .with_description("Round trip time estimate of connections")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            connection_cwnd: meter
+                .u64_histogram("quic.connection.cwnd")
+                .with_description("Congestion window of connections")
+                .with_unit(Unit::new("By"))
+                .init(),
+            connection_lost_packets: meter
+                .u64_counter("quic.connection.lost_packets")
+                .with_description("Packets lost on connections")
+                .init(),
+            connection_congestion_events: meter
+                .u64_counter("quic.connection.congestion_events")
+                .with_description("Congestion events on connections, due to loss or ECN-CE")
+                .init(),
+            compression_input: meter
+                .u64_counter("rpc.compression.input")
+                .with_description("Bytes of compressed frames before compression")
+                .with_unit(Unit::new("By"))
+                .init(),
+            compression_output: meter
+                .u64_counter("rpc.compression.output")
+                .with_description("Bytes of compressed frames after compression")
+                .with_unit(Unit::new("By"))
+                .init(),
+            compression_skipped: meter
+                .u64_counter("rpc.compression.skipped")
+                .with_description(
+                    "Frames sent uncompressed since their message type does not compress",
+                )
+                .init(),
+        }
+    });
+}
+
+/// Record a change of the number of calls in flight on a server
+///
+/// The counter is not labelled with the peer address, since every client
+/// would add a time series. The address is recorded on the call spans instead,
+/// and endpoints can opt into a series per connection, see [connection_in_flight].
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn server_in_flight(delta: i64) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::server_in_flight(delta);
+    #[cfg(feature = "opentelemetry-metrics")]
+    metrics::INSTRUMENTS.server_in_flight.add(
+        &opentelemetry::Context::current(),
+        delta,
+        &[opentelemetry::KeyValue::new("rpc.system", RPC_SYSTEM)],
+    );
+    #[cfg(not(any(feature = "openmetrics", feature = "opentelemetry-metrics")))]
+    let _ = delta;
+}
+
+/// Record a change of the number of calls in flight on a server connection
+///
+/// Only called for connections of endpoints that opted in, see
+/// [ServerEndpointConfig::connection_metrics](crate::transport::quinn::ServerEndpointConfig::connection_metrics).
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_in_flight(peer: std::net::SocketAddr, delta: i64) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::connection_in_flight(peer, delta);
+    #[cfg(feature = "opentelemetry-metrics")]
+    metrics::INSTRUMENTS.connection_in_flight.add(
+        &opentelemetry::Context::current(),
+        delta,
+        &[
+            opentelemetry::KeyValue::new("rpc.system", RPC_SYSTEM),
+            opentelemetry::KeyValue::new("net.sock.peer.addr", peer.to_string()),
+        ],
+    );
+    #[cfg(not(any(feature = "openmetrics", feature = "opentelemetry-metrics")))]
+    let _ = (peer, delta);
+}
+
+/// Record a sample of the path statistics of a connection
+///
+/// `lost_packets` and `congestion_events` are the increments since the last sample.
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_stats(
+    peer: std::net::SocketAddr,
+    rtt: std::time::Duration,
+    cwnd: u64,
+    lost_packets: u64,
+    congestion_events: u64,
+) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::connection_stats(peer, rtt, cwnd, lost_packets, congestion_events);
+    #[cfg(feature = "opentelemetry-metrics")]
+    {
+        use opentelemetry::{Context, KeyValue};
+        let cx = Context::current();
+        let attributes = [
+            KeyValue::new("rpc.system", RPC_SYSTEM),
+            KeyValue::new("net.sock.peer.addr", peer.to_string()),
+        ];
+        let instruments = &metrics::INSTRUMENTS;
+        let rtt = rtt.as_secs_f64() * 1000.0;
+        instruments.connection_rtt.record(&cx, rtt, &attributes);
+        instruments.connection_cwnd.record(&cx, cwnd, &attributes);
+        instruments
+            .connection_lost_packets
+            .add(&cx, lost_packets, &attributes);
+        instruments
+            .connection_congestion_events
+            .add(&cx, congestion_events, &attributes);
+    }
+    #[cfg(not(feature = "opentelemetry-metrics"))]
+    let _ = (peer, rtt, cwnd, lost_packets, congestion_events);
+}
+
+/// Record a frame of `message` that was compressed from `input` to `output` bytes
+///
+/// `output` is `None` if the frame was sent without trying to compress it.
+#[cfg(feature = "zstd-compression")]
+pub(crate) fn compression(message: &'static str, input: u64, output: Option<u64>) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::compression(message, input, output);
+    #[cfg(feature = "opentelemetry-metrics")]
+    {
+        use opentelemetry::{Context, KeyValue};
+        let cx = Context::current();
+        let attributes = [
+            KeyValue::new("rpc.system", RPC_SYSTEM),
+            KeyValue::new("rpc.message", message),
+        ];
+        let instruments = &metrics::INSTRUMENTS;
+        match output {
+            Some(output) => {
+                instruments.compression_input.add(&cx, input, &attributes);
+                instruments.compression_output.add(&cx, output, &attributes);
+            }
+            None => instruments.compression_skipped.add(&cx, 1, &attributes),
+        }
+    }
+    #[cfg(not(any(feature = "openmetrics", feature = "opentelemetry-metrics")))]
+    let _ = (message, input, output);
+}
+
+/// Record that a server connection was opened
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_opened(peer: std::net::SocketAddr) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::connection_opened(peer);
+    #[cfg(not(feature = "openmetrics"))]
+    let _ = peer;
+}
+
+/// Record that a server connection was closed
+#[cfg(feature = "quinn-transport")]
+pub(crate) fn connection_closed(peer: std::net::SocketAddr) {
+    #[cfg(feature = "openmetrics")]
+    crate::metrics::connection_closed(peer);
+    #[cfg(not(feature = "openmetrics"))]
+    let _ = peer;
+}
+
+/// Payload capture of a client call, if it is sampled
+///
+/// See [crate::sampling]. Without the `payload-sampling` feature this does nothing.
+#[derive(Debug, Clone)]
+pub(crate) struct Payloads(#[cfg(feature = "payload-sampling")] Option<crate::sampling::Recorder>);
+
+impl Payloads {
+    /// Decide whether to sample a call of message type `M` on service `S`
+    #[cfg_attr(
+        not(feature = "payload-sampling"),
+        allow(clippy::extra_unused_type_parameters)
+    )]
+    pub(crate) fn start<S: 'static, M: 'static>(request: &impl serde::Serialize) -> Self {
+        #[cfg(feature = "payload-sampling")]
+        return Self(crate::sampling::Recorder::start::<S, M>(request));
+        #[cfg(not(feature = "payload-sampling"))]
+        {
+            let _ = request;
+            Self()
+        }
+    }
+
+    /// Record a response of the call
+    pub(crate) fn response(&self, response: &impl serde::Serialize) {
+        #[cfg(feature = "payload-sampling")]
+        if let Some(recorder) = &self.0 {
+            recorder.response(response);
+        }
+        #[cfg(not(feature = "payload-sampling"))]
+        let _ = response;
+    }
+}
+
+/// The unqualified name of a message type, e.g. `Sqr` for `math::Sqr`
+pub(crate) fn method_name<M>() -> &'static str {
+    let name = type_name::<M>();
+    // strip generic arguments before taking the last path segment
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
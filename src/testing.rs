@@ -0,0 +1,353 @@
+//! Harness for testing handlers without a transport
+//!
+//! [TestHarness] calls a dispatch function directly with typed requests. The
+//! handler gets an [RpcChannel] backed by in-memory channels, so there is no
+//! serialization and no connection setup. Updates can be scripted up front or
+//! sent one by one, and responses are read back as typed values.
+//!
+//! The dispatch function has the same signature as the ones used with
+//! [run_server_loop](crate::server::run_server_loop), so the function generated
+//! by the `create_dispatch` macro of [rpc_service](crate::rpc_service) can be
+//! used directly.
+use crate::{
+    message::{BidiStreamingMsg, ClientStreamingMsg, RpcMsg, ServerStreamingMsg},
+    server::{RpcChannel, RpcServerError},
+    transport::{Capabilities, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint},
+    RpcMessage, Service,
+};
+use futures::{
+    channel::mpsc::{self, SendError, UnboundedReceiver, UnboundedSender},
+    future, Future, Stream, StreamExt,
+};
+use std::{
+    convert::Infallible,
+    error,
+    fmt::{self, Debug},
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+
+/// The endpoint type of channels created by a [TestHarness]
+///
+/// This never accepts any channels by itself. It only exists to name the
+/// sink and stream types of the in-memory channels.
+#[derive(Debug, Clone, Default)]
+pub struct TestEndpoint;
+
+impl ConnectionErrors for TestEndpoint {
+    type OpenError = Infallible;
+    type SendError = SendError;
+    type RecvError = Infallible;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for TestEndpoint {
+    type RecvStream = RecvStream<In>;
+    type SendSink = UnboundedSender<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for TestEndpoint {
+    type AcceptBiFut = future::Pending<Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        future::pending()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &[LocalAddr::Mem]
+    }
+}
+
+/// Receive stream of a [TestEndpoint]
+#[derive(Debug)]
+pub struct RecvStream<T>(UnboundedReceiver<T>);
+
+impl<T> Stream for RecvStream<T> {
+    type Item = result::Result<T, Infallible>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx).map(|x| x.map(Ok))
+    }
+}
+
+/// Calls a dispatch function with typed requests
+///
+/// Every call spawns the dispatch function on a new tokio task with a clone of
+/// the target, so this needs to be used from within a tokio runtime.
+pub struct TestHarness<S, T, H> {
+    target: T,
+    handler: H,
+    _s: PhantomData<S>,
+}
+
+impl<S, T: Debug, H> Debug for TestHarness<S, T, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestHarness")
+            .field("target", &self.target)
+            .finish()
+    }
+}
+
+impl<S, T, H, Fut> TestHarness<S, T, H>
+where
+    S: Service,
+    T: Clone + Send + 'static,
+    H: Fn(RpcChannel<S, TestEndpoint>, S::Req, T) -> Fut,
+    Fut: Future<Output = result::Result<(), RpcServerError<TestEndpoint>>> + Send + 'static,
+{
+    /// Create a new harness for the given target and dispatch function
+    pub fn new(target: T, handler: H) -> Self {
+        Self {
+            target,
+            handler,
+            _s: PhantomData,
+        }
+    }
+
+    /// Start a call with an untyped request
+    ///
+    /// Updates and responses are untyped as well. This is useful to test how a
+    /// handler deals with unexpected messages.
+    pub fn start(&self, req: S::Req) -> TestCall<S, S::Req, S::Res> {
+        self.start_typed(req)
+    }
+
+    /// Start a call with a typed request
+    fn start_typed<U, R>(&self, req: impl Into<S::Req>) -> TestCall<S, U, R> {
+        let (update_tx, update_rx) = mpsc::unbounded();
+        let (response_tx, response_rx) = mpsc::unbounded();
+        let chan = RpcChannel::<S, TestEndpoint>::new(response_tx, RecvStream(update_rx));
+        let task = tokio::spawn((self.handler)(chan, req.into(), self.target.clone()));
+        TestCall {
+            updates: Some(update_tx),
+            responses: response_rx,
+            task: Some(task),
+            _p: PhantomData,
+        }
+    }
+
+    /// Call a rpc handler and return its response
+    pub async fn rpc<M>(&self, msg: M) -> result::Result<M::Response, HarnessError>
+    where
+        M: RpcMsg<S>,
+    {
+        self.start_typed::<Infallible, M::Response>(msg)
+            .response()
+            .await
+    }
+
+    /// Call a server streaming handler
+    ///
+    /// Use the returned call to read the responses.
+    pub fn server_streaming<M>(&self, msg: M) -> TestCall<S, Infallible, M::Response>
+    where
+        M: ServerStreamingMsg<S>,
+    {
+        self.start_typed(msg)
+    }
+
+    /// Call a client streaming handler with a scripted sequence of updates
+    ///
+    /// The update stream is closed after the last update.
+    pub async fn client_streaming<M>(
+        &self,
+        msg: M,
+        updates: impl IntoIterator<Item = M::Update>,
+    ) -> result::Result<M::Response, HarnessError>
+    where
+        M: ClientStreamingMsg<S>,
+    {
+        let mut call = self.start_typed::<M::Update, M::Response>(msg);
+        call.send_all(updates)?;
+        call.response().await
+    }
+
+    /// Call a client streaming handler, sending updates manually
+    ///
+    /// The handler only responds once the update stream is closed, using
+    /// [TestCall::send_all] or [TestCall::close_updates].
+    pub fn client_streaming_call<M>(&self, msg: M) -> TestCall<S, M::Update, M::Response>
+    where
+        M: ClientStreamingMsg<S>,
+    {
+        self.start_typed(msg)
+    }
+
+    /// Call a bidi streaming handler
+    ///
+    /// Use the returned call to send updates and read responses.
+    pub fn bidi<M>(&self, msg: M) -> TestCall<S, M::Update, M::Response>
+    where
+        M: BidiStreamingMsg<S>,
+    {
+        self.start_typed(msg)
+    }
+}
+
+/// A call in progress, started by a [TestHarness]
+///
+/// `U` is the update type and `R` is the response type. Dropping the call aborts
+/// the handler.
+pub struct TestCall<S: Service, U, R> {
+    updates: Option<UnboundedSender<S::Req>>,
+    responses: UnboundedReceiver<S::Res>,
+    task: Option<JoinHandle<result::Result<(), RpcServerError<TestEndpoint>>>>,
+    _p: PhantomData<fn(U) -> R>,
+}
+
+impl<S: Service, U, R> Debug for TestCall<S, U, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TestCall")
+            .field("updates_closed", &self.updates.is_none())
+            .finish()
+    }
+}
+
+impl<S: Service, U, R> Drop for TestCall<S, U, R> {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl<S: Service, U, R> TestCall<S, U, R> {
+    /// Close the update stream, like a client that is done sending
+    ///
+    /// For rpc and server streaming calls, this is how a client cancels the call.
+    pub fn close_updates(&mut self) {
+        self.updates = None;
+    }
+
+    /// Wait for the handler to finish and return its result
+    ///
+    /// Responses that were not read yet are discarded. The update stream stays
+    /// open, so use [TestCall::close_updates] first for handlers that only finish
+    /// once there are no more updates.
+    pub async fn finish(&mut self) -> result::Result<(), HarnessError> {
+        let task = match self.task.take() {
+            Some(task) => task,
+            None => return Ok(()),
+        };
+        match task.await {
+            Ok(res) => res.map_err(HarnessError::Handler),
+            Err(_) => Err(HarnessError::Panicked),
+        }
+    }
+}
+
+impl<S: Service, U: Into<S::Req>, R> TestCall<S, U, R> {
+    /// Send an update to the handler
+    pub fn send(&mut self, update: U) -> result::Result<(), HarnessError> {
+        let updates = self.updates.as_ref().ok_or(HarnessError::UpdatesClosed)?;
+        updates
+            .unbounded_send(update.into())
+            .map_err(|_| HarnessError::UpdatesClosed)
+    }
+
+    /// Send a sequence of updates, then close the update stream
+    pub fn send_all(
+        &mut self,
+        updates: impl IntoIterator<Item = U>,
+    ) -> result::Result<(), HarnessError> {
+        for update in updates {
+            self.send(update)?;
+        }
+        self.close_updates();
+        Ok(())
+    }
+}
+
+impl<S: Service, U, R: TryFrom<S::Res>> TestCall<S, U, R> {
+    /// Receive the next response, or `None` if the handler closed the channel
+    pub async fn next(&mut self) -> result::Result<Option<R>, HarnessError> {
+        match self.responses.next().await {
+            Some(res) => R::try_from(res)
+                .map(Some)
+                .map_err(|_| HarnessError::UnexpectedResponse),
+            None => {
+                self.finish().await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Receive the single response of a rpc or client streaming call
+    ///
+    /// Fails if the handler sends more than one response.
+    pub async fn response(mut self) -> result::Result<R, HarnessError> {
+        let res = self.next().await?.ok_or(HarnessError::NoResponse)?;
+        match self.next().await? {
+            Some(_) => Err(HarnessError::UnexpectedResponse),
+            None => Ok(res),
+        }
+    }
+
+    /// Collect all remaining responses until the handler closes the channel
+    pub async fn collect(mut self) -> result::Result<Vec<R>, HarnessError> {
+        let mut res = Vec::new();
+        while let Some(item) = self.next().await? {
+            res.push(item);
+        }
+        Ok(res)
+    }
+}
+
+impl<S, U, R> TestCall<S, U, R>
+where
+    S: Service,
+    R: TryFrom<S::Res> + PartialEq + Debug,
+{
+    /// Assert that the next response is `expected`
+    ///
+    /// # Panics
+    ///
+    /// If the next response differs, the channel is closed or the handler failed.
+    pub async fn assert_next(&mut self, expected: R) {
+        match self.next().await {
+            Ok(Some(res)) => assert_eq!(res, expected, "unexpected response"),
+            Ok(None) => panic!("expected {expected:?}, but the handler closed the channel"),
+            Err(cause) => panic!("expected {expected:?}, but got {cause}"),
+        }
+    }
+
+    /// Assert that the handler closed the channel without further responses
+    /// and finished successfully
+    ///
+    /// # Panics
+    ///
+    /// If there is another response or the handler failed.
+    pub async fn assert_done(&mut self) {
+        match self.next().await {
+            Ok(None) => {}
+            Ok(Some(res)) => panic!("expected the channel to be closed, but got {res:?}"),
+            Err(cause) => panic!("expected the handler to finish, but got {cause}"),
+        }
+    }
+}
+
+/// Error for calls made with a [TestHarness]
+#[derive(Debug)]
+pub enum HarnessError {
+    /// The handler returned an error
+    Handler(RpcServerError<TestEndpoint>),
+    /// The handler panicked
+    Panicked,
+    /// The handler finished without sending a response
+    NoResponse,
+    /// The handler sent a response of the wrong type, or too many responses
+    UnexpectedResponse,
+    /// The update stream was closed, or the handler stopped reading updates
+    UpdatesClosed,
+}
+
+impl fmt::Display for HarnessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for HarnessError {}
@@ -0,0 +1,238 @@
+//! Many topics on a single streaming call
+//!
+//! Clients that watch hundreds of topics would need hundreds of server
+//! streaming calls. Instead, a topic call is a single bidi call whose updates
+//! are [Control] messages that attach and detach topics, and whose responses
+//! are [TopicEvent]s tagged with the topic they belong to.
+//!
+//! On the server side, [multiplex] turns the updates into a stream of events,
+//! given a function that opens the stream of a single topic:
+//!
+//! ```ignore
+//! impl Handler {
+//!     fn watch(
+//!         self,
+//!         _req: Watch,
+//!         updates: impl Stream<Item = Control<String>> + Send + 'static,
+//!     ) -> impl Stream<Item = TopicEvent<String, Event>> {
+//!         topics::multiplex(updates, move |topic| self.events(topic))
+//!     }
+//! }
+//! ```
+//!
+//! On the client side, [open] starts a topic call and returns a [TopicSender]
+//! to attach and detach topics while events are being received.
+//!
+//! Every attach of a topic is followed by exactly one [TopicEvent::Ended] for
+//! that topic, once it is detached or its stream ends. Attaching a topic that
+//! is already attached has no effect. The call ends when the client closes the
+//! update stream, after all attached topics have ended.
+use crate::{
+    client::{BidiError, BidiItemError, UpdateSink},
+    message::BidiStreamingMsg,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{
+    future,
+    stream::{self, AbortHandle, Abortable, BoxStream, SelectAll},
+    SinkExt, Stream, StreamExt,
+};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    hash::Hash,
+    pin::Pin,
+    result,
+    task::{Context, Poll},
+};
+
+/// Update of a topic call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Control<K> {
+    /// Start receiving events of a topic
+    Attach(K),
+    /// Stop receiving events of a topic
+    Detach(K),
+}
+
+/// Response of a topic call
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TopicEvent<K, T> {
+    /// An item of an attached topic
+    Item(K, T),
+    /// A topic ended, because it was detached or because its stream ended
+    Ended(K),
+}
+
+/// Stream of events for a set of topics, created by [multiplex]
+#[pin_project]
+pub struct Multiplex<U, F, K, T> {
+    updates: Option<Pin<Box<U>>>,
+    open: F,
+    topics: SelectAll<BoxStream<'static, (u64, TopicEvent<K, T>)>>,
+    attached: HashMap<K, (u64, AbortHandle)>,
+    next_id: u64,
+}
+
+impl<U, F, K: Debug, T> Debug for Multiplex<U, F, K, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multiplex")
+            .field("attached", &self.attached.keys().collect::<Vec<_>>())
+            .field("updates_closed", &self.updates.is_none())
+            .finish()
+    }
+}
+
+/// Multiplex the streams of many topics onto a single stream
+///
+/// `open` is called with the topic for every [Control::Attach] of a topic that
+/// is not attached yet.
+pub fn multiplex<U, F, St, K, T>(updates: U, open: F) -> Multiplex<U, F, K, T>
+where
+    U: Stream<Item = Control<K>>,
+    F: FnMut(&K) -> St,
+    St: Stream<Item = T> + Send + 'static,
+    K: Clone + Eq + Hash + Send + 'static,
+    T: Send + 'static,
+{
+    Multiplex {
+        updates: Some(Box::pin(updates)),
+        open,
+        topics: SelectAll::new(),
+        attached: HashMap::new(),
+        next_id: 0,
+    }
+}
+
+impl<U, F, St, K, T> Stream for Multiplex<U, F, K, T>
+where
+    U: Stream<Item = Control<K>>,
+    F: FnMut(&K) -> St,
+    St: Stream<Item = T> + Send + 'static,
+    K: Clone + Eq + Hash + Send + 'static,
+    T: Send + 'static,
+{
+    type Item = TopicEvent<K, T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        while let Some(updates) = this.updates.as_mut() {
+            match updates.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Control::Attach(topic))) => {
+                    if this.attached.contains_key(&topic) {
+                        continue;
+                    }
+                    let id = *this.next_id;
+                    *this.next_id += 1;
+                    let (items, handle) = stream::abortable((this.open)(&topic));
+                    this.attached.insert(topic.clone(), (id, handle));
+                    this.topics.push(topic_stream(id, topic, items));
+                }
+                Poll::Ready(Some(Control::Detach(topic))) => {
+                    if let Some((_, handle)) = this.attached.remove(&topic) {
+                        handle.abort();
+                    }
+                }
+                Poll::Ready(None) => {
+                    // the client is done, so detach all topics
+                    for (_, (_, handle)) in this.attached.drain() {
+                        handle.abort();
+                    }
+                    *this.updates = None;
+                }
+                Poll::Pending => break,
+            }
+        }
+        match this.topics.poll_next_unpin(cx) {
+            Poll::Ready(Some((id, event))) => {
+                if let TopicEvent::Ended(topic) = &event {
+                    // the topic might have been detached and attached again
+                    if matches!(this.attached.get(topic), Some((current, _)) if *current == id) {
+                        this.attached.remove(topic);
+                    }
+                }
+                Poll::Ready(Some(event))
+            }
+            // no topics are attached, so we are waiting for updates
+            Poll::Ready(None) if this.updates.is_some() => Poll::Pending,
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Tag the items of a topic, and end it with [TopicEvent::Ended]
+fn topic_stream<K, T, St>(
+    id: u64,
+    topic: K,
+    items: Abortable<St>,
+) -> BoxStream<'static, (u64, TopicEvent<K, T>)>
+where
+    K: Clone + Send + 'static,
+    T: Send + 'static,
+    St: Stream<Item = T> + Send + 'static,
+{
+    let ended = TopicEvent::Ended(topic.clone());
+    items
+        .map(move |item| (id, TopicEvent::Item(topic.clone(), item)))
+        .chain(stream::once(future::ready((id, ended))))
+        .boxed()
+}
+
+/// Stream of events of a topic call
+pub type TopicStream<C, K, T> =
+    BoxStream<'static, result::Result<TopicEvent<K, T>, BidiItemError<C>>>;
+
+/// Start a topic call
+///
+/// No topics are attached initially. Dropping the sender ends the call once
+/// all attached topics have ended.
+pub async fn open<S, C, M, K, T>(
+    client: &RpcClient<S, C>,
+    msg: M,
+) -> result::Result<(TopicSender<S, C, K>, TopicStream<C, K, T>), BidiError<C>>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    M: BidiStreamingMsg<S, Update = Control<K>, Response = TopicEvent<K, T>>,
+    Control<K>: Into<S::Req>,
+{
+    let (send, recv) = client.bidi(msg).await?;
+    Ok((TopicSender(send), recv))
+}
+
+/// Attaches and detaches topics of a topic call, created by [open]
+pub struct TopicSender<S: Service, C: ServiceConnection<S>, K>(UpdateSink<S, C, Control<K>>)
+where
+    Control<K>: Into<S::Req>;
+
+impl<S: Service, C: ServiceConnection<S>, K> Debug for TopicSender<S, C, K>
+where
+    Control<K>: Into<S::Req>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TopicSender").finish()
+    }
+}
+
+impl<S: Service, C: ServiceConnection<S>, K> TopicSender<S, C, K>
+where
+    Control<K>: Into<S::Req>,
+{
+    /// Start receiving events of a topic
+    pub async fn attach(&mut self, topic: K) -> result::Result<(), C::SendError> {
+        self.0.send(Control::Attach(topic)).await
+    }
+
+    /// Stop receiving events of a topic
+    pub async fn detach(&mut self, topic: K) -> result::Result<(), C::SendError> {
+        self.0.send(Control::Detach(topic)).await
+    }
+
+    /// Get back the update sink of the call
+    pub fn into_inner(self) -> UpdateSink<S, C, Control<K>> {
+        self.0
+    }
+}
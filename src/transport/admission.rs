@@ -0,0 +1,584 @@
+//! Server side admission of calls by priority
+//!
+//! [PriorityConnection](super::priority::PriorityConnection) only orders the
+//! calls of a single client. To keep health checks and control calls
+//! responsive while the server is busy with bulk calls from many clients, the
+//! server needs to know the priority of every call.
+//!
+//! [PrioritizedConnection] tags the first message of every call with a
+//! [Priority]. [AdmissionServerEndpoint] limits the number of calls that are
+//! in flight at the same time, and admits waiting calls according to their
+//! priority and the [AdmissionPolicy]. Calls that are queued are already
+//! accepted from the underlying transport, so the queue can grow as deep as
+//! clients keep calling, while the number of calls being handled is bounded.
+//!
+//! Calls that start with a [LatencyCritical] message are not queued at all,
+//! see [AdmissionServerEndpoint::with_latency_critical].
+//!
+//! Both sides must use the wrappers, since they change the wire format of requests.
+use super::{
+    latency::LatencyCritical, priority::Priority, Capabilities, Connection, ConnectionCommon,
+    ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Sink, Stream, StreamExt};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// Wire format of requests on a prioritized connection
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Prioritized<T> {
+    /// The first message of a call
+    Start {
+        /// The priority of the call
+        priority: Priority,
+        /// The message
+        msg: T,
+    },
+    /// Any other message
+    Msg(T),
+}
+
+impl<T> Prioritized<T> {
+    /// Get the message, discarding the priority
+    pub fn into_msg(self) -> T {
+        match self {
+            Prioritized::Start { msg, .. } => msg,
+            Prioritized::Msg(msg) => msg,
+        }
+    }
+}
+
+/// A connection that tags all calls with a priority
+///
+/// Use [PrioritizedConnection::with_priority] to create clones for calls with
+/// a different priority, e.g. one for health checks and one for bulk calls.
+pub struct PrioritizedConnection<C, In, Out> {
+    inner: C,
+    priority: Priority,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C, In, Out> PrioritizedConnection<C, In, Out>
+where
+    C: Connection<In, Prioritized<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a connection, using normal priority for calls
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            priority: Priority::default(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Create a clone of this connection that tags calls with the given priority
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            priority,
+            _p: PhantomData,
+        }
+    }
+
+    /// The priority calls on this connection are tagged with
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for PrioritizedConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            priority: self.priority,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for PrioritizedConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrioritizedConnection")
+            .field("inner", &self.inner)
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for PrioritizedConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C, In, Out> ConnectionCommon<In, Out> for PrioritizedConnection<C, In, Out>
+where
+    C: Connection<In, Prioritized<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = C::RecvStream;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C, In, Out> Connection<In, Out> for PrioritizedConnection<C, In, Out>
+where
+    C: Connection<In, Prioritized<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let priority = self.priority;
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let send = SendSink {
+                inner: send,
+                priority: Some(priority),
+                _p: PhantomData,
+            };
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+/// Send sink for prioritized connections
+///
+/// Tags the first message with the priority of the call.
+#[pin_project]
+pub struct SendSink<C: ConnectionCommon<In, Prioritized<Out>>, In, Out> {
+    #[pin]
+    inner: C::SendSink,
+    priority: Option<Priority>,
+    _p: PhantomData<Out>,
+}
+
+impl<C: ConnectionCommon<In, Prioritized<Out>>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Prioritized<Out>>, In, Out> Sink<Out> for SendSink<C, In, Out> {
+    type Error = C::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Out) -> Result<(), Self::Error> {
+        let this = self.project();
+        let item = match this.priority.take() {
+            Some(priority) => Prioritized::Start { priority, msg },
+            None => Prioritized::Msg(msg),
+        };
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// The order in which an [AdmissionServerEndpoint] admits waiting calls
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdmissionPolicy {
+    /// Always admit the waiting call with the highest priority
+    ///
+    /// Low priority calls can starve as long as higher priority calls keep
+    /// arriving.
+    #[default]
+    Strict,
+    /// Admit waiting calls of every priority in proportion to their weights
+    ///
+    /// With weights of 4, 2 and 1, out of every 7 admitted calls 4 are high, 2
+    /// are normal and 1 is low priority, as long as calls of all priorities are
+    /// waiting. A weight of 0 is treated as 1.
+    Weighted {
+        /// Weight of [Priority::High] calls
+        high: u32,
+        /// Weight of [Priority::Normal] calls
+        normal: u32,
+        /// Weight of [Priority::Low] calls
+        low: u32,
+    },
+}
+
+/// Configuration for an [AdmissionServerEndpoint]
+#[derive(Debug, Clone)]
+pub struct AdmissionConfig {
+    /// The maximum number of calls that can be in flight at the same time
+    pub max_concurrent: usize,
+    /// The order in which waiting calls are admitted
+    pub policy: AdmissionPolicy,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 64,
+            policy: AdmissionPolicy::default(),
+        }
+    }
+}
+
+/// The priorities, from highest to lowest
+const PRIORITIES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+/// Calls waiting to be admitted, by priority
+struct Queues<T> {
+    /// Queues, in the order of [PRIORITIES]
+    queues: [VecDeque<T>; 3],
+    /// Current weights for smooth weighted round robin
+    current: [i64; 3],
+}
+
+impl<T> Queues<T> {
+    fn new() -> Self {
+        Self {
+            queues: Default::default(),
+            current: [0; 3],
+        }
+    }
+
+    fn push(&mut self, priority: Priority, item: T) {
+        let index = PRIORITIES.iter().position(|p| *p == priority).unwrap();
+        self.queues[index].push_back(item);
+    }
+
+    fn len(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn pop(&mut self, policy: AdmissionPolicy) -> Option<T> {
+        let index = match policy {
+            AdmissionPolicy::Strict => self.queues.iter().position(|q| !q.is_empty())?,
+            AdmissionPolicy::Weighted { high, normal, low } => {
+                let weights = [high, normal, low].map(|w| w.max(1) as i64);
+                let mut total = 0;
+                let mut best: Option<usize> = None;
+                for (i, weight) in weights.iter().enumerate() {
+                    if self.queues[i].is_empty() {
+                        continue;
+                    }
+                    self.current[i] += weight;
+                    total += weight;
+                    if best.map_or(true, |b| self.current[i] > self.current[b]) {
+                        best = Some(i);
+                    }
+                }
+                let best = best?;
+                self.current[best] -= total;
+                best
+            }
+        };
+        self.queues[index].pop_front()
+    }
+}
+
+/// Number of calls in flight, shared with the permits of the calls
+#[derive(Debug, Default)]
+struct InFlight {
+    count: AtomicUsize,
+    released: Notify,
+}
+
+/// A permit for one call, released when dropped
+#[derive(Debug)]
+struct Permit(Arc<InFlight>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+        self.0.released.notify_waiters();
+    }
+}
+
+type Waiting<E, In, Out> = (
+    <E as ConnectionCommon<Prioritized<In>, Out>>::SendSink,
+    In,
+    <E as ConnectionCommon<Prioritized<In>, Out>>::RecvStream,
+);
+
+/// A server endpoint that admits calls by priority
+///
+/// All clones share the same limit and queues.
+pub struct AdmissionServerEndpoint<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> {
+    inner: E,
+    config: AdmissionConfig,
+    latency_critical: LatencyCritical,
+    in_flight: Arc<InFlight>,
+    queues: Arc<tokio::sync::Mutex<Queues<Waiting<E, In, Out>>>>,
+}
+
+impl<E, In, Out> AdmissionServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Prioritized<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a server endpoint
+    pub fn new(inner: E, config: AdmissionConfig) -> Self {
+        Self {
+            inner,
+            config,
+            latency_critical: LatencyCritical::default(),
+            in_flight: Default::default(),
+            queues: Arc::new(tokio::sync::Mutex::new(Queues::new())),
+        }
+    }
+
+    /// Hand out calls that start with a latency critical message right away
+    ///
+    /// These calls skip the queues, and do not count against
+    /// [AdmissionConfig::max_concurrent], so they are served even while the
+    /// server is saturated with other calls.
+    pub fn with_latency_critical(mut self, latency_critical: LatencyCritical) -> Self {
+        self.latency_critical = latency_critical;
+        self
+    }
+
+    /// The number of calls that are currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count.load(Ordering::SeqCst)
+    }
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> Clone
+    for AdmissionServerEndpoint<E, In, Out>
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            latency_critical: self.latency_critical.clone(),
+            in_flight: self.in_flight.clone(),
+            queues: self.queues.clone(),
+        }
+    }
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> fmt::Debug
+    for AdmissionServerEndpoint<E, In, Out>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdmissionServerEndpoint")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .field("latency_critical", &self.latency_critical)
+            .field("in_flight", &self.in_flight.count)
+            .finish()
+    }
+}
+
+impl<E, In, Out> ConnectionErrors for AdmissionServerEndpoint<E, In, Out>
+where
+    E: ConnectionCommon<Prioritized<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenError = E::OpenError;
+    type SendError = E::SendError;
+    type RecvError = E::RecvError;
+}
+
+impl<E, In, Out> ConnectionCommon<In, Out> for AdmissionServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Prioritized<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::ServerSendSink<E, In, Out>;
+    type RecvStream = self::RecvStream<E, In, Out>;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+}
+
+impl<E, In, Out> ServerEndpoint<In, Out> for AdmissionServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Prioritized<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        let latency_critical = self.latency_critical.clone();
+        let in_flight = self.in_flight.clone();
+        let queues = self.queues.clone();
+        async move {
+            let mut queues = queues.lock().await;
+            loop {
+                // register before checking, so a release in between is not missed
+                let released = in_flight.released.notified();
+                if in_flight.count.load(Ordering::SeqCst) < config.max_concurrent {
+                    if let Some((send, first, recv)) = queues.pop(config.policy) {
+                        in_flight.count.fetch_add(1, Ordering::SeqCst);
+                        let permit = Arc::new(Permit(in_flight.clone()));
+                        return Ok(admitted(send, first, recv, Some(permit)));
+                    }
+                }
+                let accepted = tokio::select! {
+                    res = inner.accept_bi() => Some(res),
+                    _ = released => None,
+                };
+                let (send, mut recv) = match accepted {
+                    Some(res) => res?,
+                    None => continue,
+                };
+                let (priority, first) = match recv.next().await {
+                    Some(Ok(Prioritized::Start { priority, msg })) => (priority, msg),
+                    Some(Ok(Prioritized::Msg(msg))) => (Priority::default(), msg),
+                    Some(Err(cause)) => {
+                        tracing::debug!("error reading first message: {}", cause);
+                        continue;
+                    }
+                    None => {
+                        tracing::debug!("substream closed before the first message");
+                        continue;
+                    }
+                };
+                if latency_critical.contains(&first) {
+                    return Ok(admitted(send, first, recv, None));
+                }
+                queues.push(priority, (send, first, recv));
+                tracing::trace!("{} calls waiting for admission", queues.len());
+            }
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// The channels of an admitted call, with the permit of the call unless it is latency critical
+fn admitted<E, In, Out>(
+    send: E::SendSink,
+    first: In,
+    recv: E::RecvStream,
+    permit: Option<Arc<Permit>>,
+) -> (ServerSendSink<E, In, Out>, RecvStream<E, In, Out>)
+where
+    E: ConnectionCommon<Prioritized<In>, Out>,
+{
+    let send = ServerSendSink {
+        inner: send,
+        _permit: permit.clone(),
+    };
+    let recv = RecvStream {
+        first: Some(first),
+        inner: recv,
+        _permit: permit,
+    };
+    (send, recv)
+}
+
+/// Send sink for admission server endpoints
+///
+/// Keeps the permit of the call alive until both sink and stream are dropped.
+#[pin_project]
+pub struct ServerSendSink<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> {
+    #[pin]
+    inner: E::SendSink,
+    _permit: Option<Arc<Permit>>,
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> fmt::Debug for ServerSendSink<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerSendSink").finish()
+    }
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> Sink<Out> for ServerSendSink<E, In, Out> {
+    type Error = E::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Receive stream for admission server endpoints
+///
+/// Yields the first message, which was already read to get the priority,
+/// followed by the remaining messages. Keeps the permit of the call alive
+/// until both sink and stream are dropped.
+#[pin_project]
+pub struct RecvStream<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> {
+    first: Option<In>,
+    #[pin]
+    inner: E::RecvStream,
+    _permit: Option<Arc<Permit>>,
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In, Out> fmt::Debug for RecvStream<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<E: ConnectionCommon<Prioritized<In>, Out>, In: Unpin, Out> Stream for RecvStream<E, In, Out> {
+    type Item = result::Result<In, E::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(first) = this.first.take() {
+            return Poll::Ready(Some(Ok(first)));
+        }
+        this.inner
+            .poll_next(cx)
+            .map(|item| item.map(|res| res.map(Prioritized::into_msg)))
+    }
+}
@@ -0,0 +1,342 @@
+//! Transports whose type is chosen at runtime
+//!
+//! [RpcClient] and [RpcServer] are generic over their transport, which spreads
+//! into every function that takes one. A [BoxedConnection] or
+//! [BoxedServerEndpoint] wraps any transport behind a single type, so the
+//! transport can be picked at runtime, e.g. from a command line flag, and code
+//! that uses the client or server only names the service:
+//!
+//! ```ignore
+//! let connection = match transport {
+//!     Transport::Mem => BoxedConnection::new(mem_connection),
+//!     Transport::Quinn => BoxedConnection::new(QuinnConnection::new(endpoint, addr, name)),
+//! };
+//! let client: BoxedClient<ComputeService> = RpcClient::new(connection);
+//! ```
+//!
+//! The channels and errors of the transport are boxed. Since the capabilities
+//! of the transport are only known at runtime, the boxed transports have the
+//! default [CAPABILITIES](ConnectionCommon::CAPABILITIES) of no guarantees,
+//! and `capabilities` returns the guarantees of the transport inside.
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::{extensions::Extensions, RpcClient, RpcError, RpcMessage, RpcServer, Service};
+use futures::{
+    future::BoxFuture, stream::BoxStream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamExt,
+};
+use std::{
+    error, fmt,
+    net::SocketAddr,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// A client for `S` over a [BoxedConnection]
+pub type BoxedClient<S> = RpcClient<S, BoxedConnection<<S as Service>::Res, <S as Service>::Req>>;
+
+/// A server for `S` on a [BoxedServerEndpoint]
+pub type BoxedServer<S> =
+    RpcServer<S, BoxedServerEndpoint<<S as Service>::Req, <S as Service>::Res>>;
+
+/// A pair of boxed channels to send and receive messages on a single stream.
+type Socket<In, Out> = (BoxedSendSink<Out>, BoxedRecvStream<In>);
+
+/// A [Connection] with boxed channels and errors
+trait ErasedConnection<In, Out>: Send + Sync + 'static {
+    fn open_bi(&self) -> OpenBiFuture<In, Out>;
+    fn compressed(&self) -> bool;
+}
+
+impl<In: RpcMessage, Out: RpcMessage, C: Connection<In, Out>> ErasedConnection<In, Out> for C {
+    fn open_bi(&self) -> OpenBiFuture<In, Out> {
+        Connection::open_bi(self)
+            .map(|res| {
+                let (send, recv) = res.map_err(BoxedError::new)?;
+                Ok(boxed::<C, _, _>(send, recv))
+            })
+            .boxed()
+    }
+
+    fn compressed(&self) -> bool {
+        ConnectionCommon::compressed(self)
+    }
+}
+
+/// A [ServerEndpoint] with boxed channels and errors
+trait ErasedEndpoint<In, Out>: Send + Sync + 'static {
+    fn accept_bi(&self) -> AcceptBiFuture<In, Out>;
+    fn local_addr(&self) -> &[LocalAddr];
+    fn compressed(&self) -> bool;
+}
+
+impl<In: RpcMessage, Out: RpcMessage, E: ServerEndpoint<In, Out>> ErasedEndpoint<In, Out> for E {
+    fn accept_bi(&self) -> AcceptBiFuture<In, Out> {
+        ServerEndpoint::accept_bi(self)
+            .map(|res| {
+                let (send, recv) = res.map_err(BoxedError::new)?;
+                Ok(boxed::<E, _, _>(send, recv))
+            })
+            .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        ServerEndpoint::local_addr(self)
+    }
+
+    fn compressed(&self) -> bool {
+        ConnectionCommon::compressed(self)
+    }
+}
+
+/// Box the channels of `C`
+fn boxed<C, In, Out>(send: C::SendSink, recv: C::RecvStream) -> Socket<In, Out>
+where
+    C: ConnectionCommon<In, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    let recv = BoxedRecvStream {
+        peer_addr: C::peer_addr(&recv),
+        extensions: C::extensions(&recv),
+        inner: recv.map_err(BoxedError::recv::<C>).boxed(),
+    };
+    let send = BoxedSendSink(Box::pin(send.sink_map_err(BoxedError::new)));
+    (send, recv)
+}
+
+/// A connection of any transport
+pub struct BoxedConnection<In: RpcMessage, Out: RpcMessage> {
+    inner: Arc<dyn ErasedConnection<In, Out>>,
+    capabilities: Capabilities,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> BoxedConnection<In, Out> {
+    /// Box `connection`
+    pub fn new<C: Connection<In, Out>>(connection: C) -> Self {
+        Self {
+            inner: Arc::new(connection),
+            capabilities: C::CAPABILITIES,
+        }
+    }
+
+    /// The guarantees of the transport inside
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Clone for BoxedConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            capabilities: self.capabilities,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for BoxedConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedConnection")
+            .field("capabilities", &self.capabilities)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A server endpoint of any transport
+pub struct BoxedServerEndpoint<In: RpcMessage, Out: RpcMessage> {
+    inner: Arc<dyn ErasedEndpoint<In, Out>>,
+    capabilities: Capabilities,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> BoxedServerEndpoint<In, Out> {
+    /// Box `endpoint`
+    pub fn new<E: ServerEndpoint<In, Out>>(endpoint: E) -> Self {
+        Self {
+            inner: Arc::new(endpoint),
+            capabilities: E::CAPABILITIES,
+        }
+    }
+
+    /// The guarantees of the transport inside
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Clone for BoxedServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            capabilities: self.capabilities,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for BoxedServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BoxedServerEndpoint")
+            .field("capabilities", &self.capabilities)
+            .field("local_addr", &self.inner.local_addr())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Send sink for boxed channels
+pub struct BoxedSendSink<Out>(Pin<Box<dyn Sink<Out, Error = BoxedError> + Send + 'static>>);
+
+impl<Out> Sink<Out> for BoxedSendSink<Out> {
+    type Error = BoxedError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        self.0.as_mut().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.as_mut().poll_close(cx)
+    }
+}
+
+/// RecvStream for boxed channels
+///
+/// Keeps the peer address and the extensions of the channel.
+pub struct BoxedRecvStream<In> {
+    inner: BoxStream<'static, result::Result<In, BoxedError>>,
+    peer_addr: Option<SocketAddr>,
+    extensions: Option<Extensions>,
+}
+
+impl<In> Stream for BoxedRecvStream<In> {
+    type Item = result::Result<In, BoxedError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+/// Error of a boxed transport, the error of the transport inside
+#[derive(Debug)]
+pub struct BoxedError {
+    cause: Box<dyn RpcError>,
+    retry_after: Option<Duration>,
+}
+
+impl BoxedError {
+    fn new(cause: impl RpcError) -> Self {
+        Self {
+            cause: Box::new(cause),
+            retry_after: None,
+        }
+    }
+
+    fn recv<C: ConnectionErrors>(cause: C::RecvError) -> Self {
+        Self {
+            retry_after: C::retry_after(&cause),
+            cause: Box::new(cause),
+        }
+    }
+
+    /// The error of the transport inside
+    pub fn cause(&self) -> &dyn RpcError {
+        self.cause.as_ref()
+    }
+}
+
+impl fmt::Display for BoxedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for BoxedError {}
+
+/// Future returned by open_bi of a [BoxedConnection]
+pub type OpenBiFuture<In, Out> = BoxFuture<'static, result::Result<Socket<In, Out>, BoxedError>>;
+
+/// Future returned by accept_bi of a [BoxedServerEndpoint]
+pub type AcceptBiFuture<In, Out> = BoxFuture<'static, result::Result<Socket<In, Out>, BoxedError>>;
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for BoxedConnection<In, Out> {
+    type SendError = BoxedError;
+    type RecvError = BoxedError;
+    type OpenError = BoxedError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        error.retry_after
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for BoxedConnection<In, Out> {
+    type RecvStream = BoxedRecvStream<In>;
+    type SendSink = BoxedSendSink<Out>;
+
+    fn compressed(&self) -> bool {
+        self.inner.compressed()
+    }
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        recv.peer_addr
+    }
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions.clone()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for BoxedConnection<In, Out> {
+    type OpenBiFut = OpenBiFuture<In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        self.inner.open_bi()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for BoxedServerEndpoint<In, Out> {
+    type SendError = BoxedError;
+    type RecvError = BoxedError;
+    type OpenError = BoxedError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        error.retry_after
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for BoxedServerEndpoint<In, Out> {
+    type RecvStream = BoxedRecvStream<In>;
+    type SendSink = BoxedSendSink<Out>;
+
+    fn compressed(&self) -> bool {
+        self.inner.compressed()
+    }
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        recv.peer_addr
+    }
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions.clone()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for BoxedServerEndpoint<In, Out> {
+    type AcceptBiFut = AcceptBiFuture<In, Out>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        self.inner.accept_bi()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
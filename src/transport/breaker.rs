@@ -0,0 +1,422 @@
+//! Transport wrapper that stops calling a failing endpoint
+//!
+//! [BreakerConnection] records the outcome of every call in a
+//! [CircuitBreaker]. A call fails if opening the substream fails, if receiving
+//! fails, or if the first response takes longer than
+//! [BreakerConfig::slow_call]. Once the share of failed calls among the last
+//! [BreakerConfig::window] calls reaches [BreakerConfig::failure_rate], the
+//! breaker opens and calls fail right away with [OpenError::Open], without
+//! touching the endpoint.
+//!
+//! After [BreakerConfig::open_for] the breaker is half open and lets a single
+//! probe call through. If the probe succeeds the breaker closes again,
+//! otherwise it stays open for another [BreakerConfig::open_for].
+//!
+//! A [ShardedClient](crate::sharded::ShardedClient) of breaker connections can
+//! route around shards whose breaker is open, see
+//! [ShardedClient::with_circuit_breakers](crate::sharded::ShardedClient::with_circuit_breakers).
+use super::{Capabilities, Connection, ConnectionCommon, ConnectionErrors};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Stream};
+use pin_project::pin_project;
+use std::{
+    collections::VecDeque,
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Configuration for a [CircuitBreaker]
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// The number of most recent calls the failure rate is computed over
+    pub window: usize,
+    /// The minimum number of calls in the window before the breaker can open
+    pub min_calls: usize,
+    /// The share of failed calls, between 0 and 1, at which the breaker opens
+    pub failure_rate: f64,
+    /// Calls that take longer than this to get their first response count as failed
+    pub slow_call: Option<Duration>,
+    /// How long the breaker stays open before letting a probe call through
+    pub open_for: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            min_calls: 10,
+            failure_rate: 0.5,
+            slow_call: None,
+            open_for: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The state of a [CircuitBreaker]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls go through, and their outcomes are recorded
+    Closed,
+    /// Calls fail right away
+    Open,
+    /// A single probe call goes through, which decides whether the breaker closes
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { probing: bool },
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: BreakerConfig,
+    state: State,
+    /// Outcomes of the most recent calls, `true` for failed calls
+    outcomes: VecDeque<bool>,
+}
+
+impl Inner {
+    /// Move from open to half open once the open period is over
+    fn update(&mut self, now: Instant) {
+        if let State::Open { until } = self.state {
+            if until <= now {
+                tracing::debug!("circuit breaker half open");
+                self.state = State::HalfOpen { probing: false };
+            }
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        tracing::debug!("circuit breaker open");
+        self.state = State::Open {
+            until: now + self.config.open_for,
+        };
+        self.outcomes.clear();
+    }
+
+    fn record(&mut self, probe: bool, failed: bool, now: Instant) {
+        if probe {
+            if failed {
+                self.open(now);
+            } else {
+                tracing::debug!("circuit breaker closed");
+                self.state = State::Closed;
+            }
+            return;
+        }
+        // calls that were started before the breaker opened do not count
+        if !matches!(self.state, State::Closed) {
+            return;
+        }
+        self.outcomes.push_back(failed);
+        while self.outcomes.len() > self.config.window.max(1) {
+            self.outcomes.pop_front();
+        }
+        let failures = self.outcomes.iter().filter(|failed| **failed).count();
+        let total = self.outcomes.len();
+        if total >= self.config.min_calls.max(1)
+            && failures as f64 >= self.config.failure_rate * total as f64
+        {
+            self.open(now);
+        }
+    }
+}
+
+/// Tracks the outcomes of calls to an endpoint, and decides whether calls go through
+///
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker(Arc<Mutex<Inner>>);
+
+impl CircuitBreaker {
+    /// Create a new, closed breaker
+    pub fn new(config: BreakerConfig) -> Self {
+        Self(Arc::new(Mutex::new(Inner {
+            config,
+            state: State::Closed,
+            outcomes: VecDeque::new(),
+        })))
+    }
+
+    /// The current state of the breaker
+    pub fn state(&self) -> BreakerState {
+        let mut inner = self.0.lock().unwrap();
+        inner.update(Instant::now());
+        match inner.state {
+            State::Closed => BreakerState::Closed,
+            State::Open { .. } => BreakerState::Open,
+            State::HalfOpen { .. } => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether the next call would go through
+    pub fn is_available(&self) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        inner.update(Instant::now());
+        match inner.state {
+            State::Closed => true,
+            State::Open { .. } => false,
+            State::HalfOpen { probing } => !probing,
+        }
+    }
+
+    /// Admit a call, returning the ticket it has to report its outcome on
+    fn admit(&self) -> Option<Ticket> {
+        let now = Instant::now();
+        let mut inner = self.0.lock().unwrap();
+        inner.update(now);
+        let probe = match &mut inner.state {
+            State::Closed => false,
+            State::Open { .. } => return None,
+            State::HalfOpen { probing: true } => return None,
+            State::HalfOpen { probing } => {
+                *probing = true;
+                true
+            }
+        };
+        Some(Ticket {
+            breaker: self.clone(),
+            probe,
+            started: now,
+            slow_call: inner.config.slow_call,
+            done: false,
+        })
+    }
+}
+
+/// An admitted call, that records its outcome in the breaker
+///
+/// A ticket that is dropped without an outcome, e.g. because the call was
+/// abandoned, only counts as failed if it was slow. Otherwise it is not
+/// recorded at all, and an abandoned probe lets the next call probe instead.
+#[derive(Debug)]
+struct Ticket {
+    breaker: CircuitBreaker,
+    probe: bool,
+    started: Instant,
+    slow_call: Option<Duration>,
+    done: bool,
+}
+
+impl Ticket {
+    fn is_slow(&self, now: Instant) -> bool {
+        match self.slow_call {
+            Some(slow_call) => now.duration_since(self.started) > slow_call,
+            None => false,
+        }
+    }
+
+    fn record(&mut self, failed: bool) {
+        let now = Instant::now();
+        let failed = failed || self.is_slow(now);
+        self.done = true;
+        self.breaker
+            .0
+            .lock()
+            .unwrap()
+            .record(self.probe, failed, now);
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let now = Instant::now();
+        if self.is_slow(now) {
+            return self.record(true);
+        }
+        if self.probe {
+            let mut inner = self.breaker.0.lock().unwrap();
+            if let State::HalfOpen { probing } = &mut inner.state {
+                *probing = false;
+            }
+        }
+    }
+}
+
+/// A connection that stops opening substreams while its breaker is open
+///
+/// All clones of a connection share the same breaker.
+pub struct BreakerConnection<C, In, Out> {
+    inner: C,
+    breaker: CircuitBreaker,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> BreakerConnection<C, In, Out> {
+    /// Wrap a connection with a new breaker
+    pub fn new(inner: C, config: BreakerConfig) -> Self {
+        Self::with_breaker(inner, CircuitBreaker::new(config))
+    }
+
+    /// Wrap a connection with an existing breaker
+    ///
+    /// This can be used to share a breaker between several connections to the
+    /// same endpoint.
+    pub fn with_breaker(inner: C, breaker: CircuitBreaker) -> Self {
+        Self {
+            inner,
+            breaker,
+            _p: PhantomData,
+        }
+    }
+
+    /// The breaker of this connection
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for BreakerConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            breaker: self.breaker.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for BreakerConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreakerConnection")
+            .field("inner", &self.inner)
+            .field("breaker", &self.breaker)
+            .finish()
+    }
+}
+
+/// Receive stream for breaker connections
+///
+/// Records the outcome of the call once the first item is received.
+#[pin_project]
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out> {
+    #[pin]
+    inner: C::RecvStream,
+    ticket: Option<Ticket>,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> RecvStream<C, In, Out> {
+    /// Get the underlying stream of the wrapped connection
+    ///
+    /// The outcome of the call is no longer recorded.
+    pub fn into_inner(self) -> C::RecvStream {
+        self.inner
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, C::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = futures::ready!(this.inner.poll_next(cx));
+        if let Some(mut ticket) = this.ticket.take() {
+            match &item {
+                Some(Ok(_)) => ticket.record(false),
+                Some(Err(_)) => ticket.record(true),
+                // a call may legitimately end without a response
+                None => {}
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// Future returned by open_bi
+pub type OpenBiFuture<C, In, Out> = BoxFuture<
+    'static,
+    result::Result<
+        (
+            <C as ConnectionCommon<In, Out>>::SendSink,
+            RecvStream<C, In, Out>,
+        ),
+        OpenError<<C as ConnectionErrors>::OpenError>,
+    >,
+>;
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for BreakerConnection<C, In, Out>
+{
+    type OpenError = OpenError<C::OpenError>;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for BreakerConnection<C, In, Out>
+{
+    type SendSink = C::SendSink;
+    type RecvStream = self::RecvStream<C, In, Out>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for BreakerConnection<C, In, Out>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let ticket = self.breaker.admit();
+        async move {
+            let mut ticket = ticket.ok_or(OpenError::Open)?;
+            match inner.open_bi().await {
+                Ok((send, recv)) => Ok((
+                    send,
+                    RecvStream {
+                        inner: recv,
+                        ticket: Some(ticket),
+                    },
+                )),
+                Err(cause) => {
+                    ticket.record(true);
+                    Err(OpenError::Inner(cause))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Error when opening a substream on a [BreakerConnection]
+#[derive(Debug, Clone)]
+pub enum OpenError<E> {
+    /// The breaker is open, so the substream was not opened
+    Open,
+    /// Opening the substream on the inner connection failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for OpenError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for OpenError<E> {}
@@ -0,0 +1,141 @@
+//! Bridges between transports
+//!
+//! Code that is written against the [flume] transport, like tests
+//! or embedded components, can be pointed at a remote service without changes:
+//! [connection] returns a flume connection whose calls are made on a remote
+//! connection, and [server_endpoint] returns a flume server endpoint that gets
+//! the calls of remote clients.
+//!
+//! ```ignore
+//! let remote = QuinnConnection::new(endpoint, addr, "localhost".into());
+//! let (connection, _bridge) = bridge::connection::<ComputeRequest, ComputeResponse, _>(remote, 1);
+//! // the same client as with flume::connection
+//! let client = RpcClient::<ComputeService, _>::new(connection);
+//! ```
+//!
+//! Both are built on [forward], which forwards the channels of any server
+//! endpoint to any connection.
+use super::{
+    flume::{self, FlumeConnection, FlumeServerEndpoint},
+    Connection, ConnectionErrors, ServerEndpoint,
+};
+use crate::RpcMessage;
+use futures::{future, SinkExt, Stream, StreamExt};
+use std::{error, fmt};
+use tokio::task::JoinHandle;
+
+/// Error that ends [forward]
+#[derive(Debug)]
+pub enum ForwardError<E: ConnectionErrors, C: ConnectionErrors> {
+    /// Accepting a channel on the endpoint failed, e.g. because all its clients are gone
+    Accept(E::OpenError),
+    /// Opening a channel on the connection failed
+    Open(C::OpenError),
+}
+
+impl<E: ConnectionErrors, C: ConnectionErrors> fmt::Display for ForwardError<E, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: ConnectionErrors, C: ConnectionErrors> error::Error for ForwardError<E, C> {}
+
+/// The task of a bridge, which ends with the error that ended it
+pub type Bridge<E, C> = JoinHandle<ForwardError<E, C>>;
+
+/// Forward every channel accepted on `endpoint` to a channel opened on `connection`
+///
+/// Messages are forwarded in both directions until the side they come from
+/// ends, so ending the requests of a call, e.g. of a client streaming call,
+/// ends them on the other side as well. Messages that fail to arrive end the
+/// direction they were sent in.
+///
+/// Runs until accepting or opening a channel fails.
+pub async fn forward<In, Out, E, C>(endpoint: E, connection: C) -> ForwardError<E, C>
+where
+    In: RpcMessage,
+    Out: RpcMessage,
+    E: ServerEndpoint<In, Out>,
+    C: Connection<Out, In>,
+{
+    loop {
+        let (send, recv) = match endpoint.accept_bi().await {
+            Ok(channel) => channel,
+            Err(cause) => return ForwardError::Accept(cause),
+        };
+        let (remote_send, remote_recv) = match connection.open_bi().await {
+            Ok(channel) => channel,
+            Err(cause) => return ForwardError::Open(cause),
+        };
+        tokio::spawn(future::join(
+            pump(recv, remote_send),
+            pump(remote_recv, send),
+        ));
+    }
+}
+
+/// Sends the messages of `recv` to `send`, until either of them ends
+async fn pump<T, E, S>(mut recv: impl Stream<Item = Result<T, E>> + Unpin, mut send: S)
+where
+    E: fmt::Display,
+    S: futures::Sink<T> + Unpin,
+    S::Error: fmt::Display,
+{
+    while let Some(item) = recv.next().await {
+        let item = match item {
+            Ok(item) => item,
+            Err(cause) => {
+                tracing::debug!("receiving a forwarded message failed: {}", cause);
+                break;
+            }
+        };
+        if let Err(cause) = send.send(item).await {
+            tracing::debug!("forwarding a message failed: {}", cause);
+            break;
+        }
+    }
+    send.close().await.ok();
+}
+
+/// A flume connection whose calls are made on `remote`
+///
+/// `buffer` is the buffer of the flume channels, see [flume::connection]. The
+/// bridge runs until all clones of the connection are dropped, or opening a
+/// channel on `remote` fails. Calls that are made after that fail.
+pub fn connection<Req, Res, C>(
+    remote: C,
+    buffer: usize,
+) -> (
+    FlumeConnection<Res, Req>,
+    Bridge<FlumeServerEndpoint<Req, Res>, C>,
+)
+where
+    Req: RpcMessage,
+    Res: RpcMessage,
+    C: Connection<Res, Req>,
+{
+    let (endpoint, connection) = flume::connection::<Req, Res>(buffer);
+    (connection, tokio::spawn(forward(endpoint, remote)))
+}
+
+/// A flume server endpoint that gets the calls of the clients of `remote`
+///
+/// `buffer` is the buffer of the flume channels, see [flume::connection]. The
+/// bridge runs until the endpoint is dropped, or accepting a channel on
+/// `remote` fails.
+pub fn server_endpoint<Req, Res, E>(
+    remote: E,
+    buffer: usize,
+) -> (
+    FlumeServerEndpoint<Req, Res>,
+    Bridge<E, FlumeConnection<Res, Req>>,
+)
+where
+    Req: RpcMessage,
+    Res: RpcMessage,
+    E: ServerEndpoint<Req, Res>,
+{
+    let (endpoint, connection) = flume::connection::<Req, Res>(buffer);
+    (endpoint, tokio::spawn(forward(remote, connection)))
+}
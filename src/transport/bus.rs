@@ -0,0 +1,662 @@
+//! Transport over a publish/subscribe message broker such as NATS or MQTT
+//!
+//! Where clients can not connect to servers directly, existing broker
+//! infrastructure can carry the calls instead. The broker is abstracted by the
+//! [Broker] trait, which only needs to publish payloads on a subject and
+//! subscribe to a subject. Implement it for the client of your broker, or use
+//! [MemoryBroker] for in-process use and tests.
+//!
+//! A [BusServerEndpoint] subscribes to a request subject. A [BusConnection]
+//! subscribes to its own reply subject and publishes the messages of every
+//! channel to the request subject, tagged with the reply subject and a
+//! correlation id. The server publishes the responses of a channel to the
+//! reply subject, tagged with the same correlation id. Closing a send sink
+//! publishes an end marker, so all interaction patterns work, including calls
+//! without a response.
+//!
+//! Messages of a channel must arrive in the order they were published, which
+//! brokers guarantee per publisher and subject. Brokers with at-most-once
+//! delivery can lose messages, in which case the call never completes, so use
+//! timeouts on top of this transport.
+use crate::{
+    transport::{
+        decode::{decode, DecodeError, Direction},
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcError, RpcMessage,
+};
+use bincode::Options;
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, Sink, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap,
+    },
+    convert::Infallible,
+    error, fmt,
+    hash::{BuildHasher, Hasher},
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+
+/// A publish/subscribe message broker
+pub trait Broker: Clone + Send + Sync + 'static {
+    /// Error when publishing or subscribing
+    type Error: RpcError;
+    /// Stream of the payloads published on a subject
+    type Subscription: Stream<Item = Vec<u8>> + Send + Unpin + 'static;
+
+    /// The guarantees of the broker, which are the capabilities of the transport
+    ///
+    /// Defaults to ordered, multiplexed delivery that can lose messages and
+    /// is not encrypted. Override this for brokers that give more guarantees.
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: false,
+        encrypted: false,
+        multiplexed: true,
+    };
+
+    /// Publish a payload on a subject
+    fn publish(
+        &self,
+        subject: &str,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'static, result::Result<(), Self::Error>>;
+
+    /// Subscribe to a subject
+    fn subscribe(
+        &self,
+        subject: &str,
+    ) -> BoxFuture<'static, result::Result<Self::Subscription, Self::Error>>;
+}
+
+/// A message of a channel, as published on the broker
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    /// The reply subject of the client, only set for messages to the server
+    reply: Option<String>,
+    /// The correlation id of the channel, unique per reply subject
+    id: u64,
+    frame: Frame,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    /// The first message of a new channel
+    Open(Vec<u8>),
+    /// A message of an existing channel
+    Data(Vec<u8>),
+    /// The sender of the channel is done
+    End,
+}
+
+/// The options of [bincode::serialize]
+fn options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+/// A random value, to make reply subjects unique
+fn random() -> u64 {
+    let mut hasher: DefaultHasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+/// Error when opening or accepting a channel
+#[derive(Debug)]
+pub enum OpenError {
+    /// The subscription to the broker ended
+    Closed,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for OpenError {}
+
+/// Error when sending a message
+#[derive(Debug)]
+pub enum SendError<E> {
+    /// The message could not be serialized
+    Serialize(bincode::Error),
+    /// The broker failed to publish the message
+    Publish(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for SendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for SendError<E> {}
+
+/// Error when receiving a message
+#[derive(Debug)]
+pub enum RecvError {
+    /// The message could not be deserialized
+    Deserialize(DecodeError),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for RecvError {}
+
+/// Send side of a channel, publishes messages on the broker
+pub struct SendSink<B: Broker, Out> {
+    broker: B,
+    subject: String,
+    reply: Option<String>,
+    id: u64,
+    /// The next message opens the channel
+    open: bool,
+    /// The end marker was published
+    ended: bool,
+    pending: Option<BoxFuture<'static, result::Result<(), B::Error>>>,
+    _p: PhantomData<Out>,
+}
+
+// nothing is pinned, the pending publish is boxed
+impl<B: Broker, Out> Unpin for SendSink<B, Out> {}
+
+impl<B: Broker, Out> fmt::Debug for SendSink<B, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("subject", &self.subject)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<B: Broker, Out> SendSink<B, Out> {
+    fn new(broker: B, subject: String, reply: Option<String>, id: u64) -> Self {
+        Self {
+            broker,
+            subject,
+            // only clients open channels, and they always send a reply subject
+            open: reply.is_some(),
+            reply,
+            id,
+            ended: false,
+            pending: None,
+            _p: PhantomData,
+        }
+    }
+
+    fn publish(
+        &self,
+        frame: Frame,
+    ) -> result::Result<BoxFuture<'static, result::Result<(), B::Error>>, bincode::Error> {
+        let envelope = Envelope {
+            reply: self.reply.clone(),
+            id: self.id,
+            frame,
+        };
+        let payload = options().serialize(&envelope)?;
+        Ok(self.broker.publish(&self.subject, payload))
+    }
+
+    fn poll_pending(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), SendError<B::Error>>> {
+        if let Some(pending) = self.pending.as_mut() {
+            let res = futures::ready!(pending.poll_unpin(cx));
+            self.pending = None;
+            res.map_err(SendError::Publish)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<B: Broker, Out: Serialize> Sink<Out> for SendSink<B, Out> {
+    type Error = SendError<B::Error>;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> result::Result<(), Self::Error> {
+        let this = self.get_mut();
+        let payload = options().serialize(&item).map_err(SendError::Serialize)?;
+        let frame = if this.open {
+            Frame::Open(payload)
+        } else {
+            Frame::Data(payload)
+        };
+        this.pending = Some(this.publish(frame).map_err(SendError::Serialize)?);
+        this.open = false;
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.get_mut().poll_pending(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_pending(cx))?;
+        if !this.ended {
+            this.ended = true;
+            if !this.open {
+                this.pending = Some(this.publish(Frame::End).map_err(SendError::Serialize)?);
+                futures::ready!(this.poll_pending(cx))?;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<B: Broker, Out> Drop for SendSink<B, Out> {
+    fn drop(&mut self) {
+        // a channel that was never opened does not need to be ended
+        if self.ended || self.open {
+            return;
+        }
+        let pending = self.pending.take();
+        let end = match self.publish(Frame::End) {
+            Ok(end) => end,
+            Err(_) => return,
+        };
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if let Some(pending) = pending {
+                    pending.await.ok();
+                }
+                end.await.ok();
+            });
+        }
+    }
+}
+
+/// Receive side of a channel
+pub struct RecvStream<In> {
+    inner: flume::r#async::RecvStream<'static, Vec<u8>>,
+    direction: Direction,
+    _p: PhantomData<In>,
+}
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    fn new(inner: flume::Receiver<Vec<u8>>, direction: Direction) -> Self {
+        Self {
+            inner: inner.into_stream(),
+            direction,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In: RpcMessage> Stream for RecvStream<In> {
+    type Item = result::Result<In, RecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let direction = self.direction;
+        self.inner.poll_next_unpin(cx).map(|payload| {
+            payload.map(|payload| {
+                decode(options(), &payload, direction).map_err(RecvError::Deserialize)
+            })
+        })
+    }
+}
+
+/// Channels of a client, by correlation id, or `None` once the subscription ended
+type Channels = Arc<Mutex<Option<HashMap<u64, flume::Sender<Vec<u8>>>>>>;
+
+struct ClientInner<B> {
+    broker: B,
+    subject: String,
+    reply: String,
+    next_id: AtomicU64,
+    channels: Channels,
+    router: JoinHandle<()>,
+}
+
+impl<B> Drop for ClientInner<B> {
+    fn drop(&mut self) {
+        self.router.abort();
+    }
+}
+
+/// A connection to a server via a broker
+pub struct BusConnection<B: Broker, In, Out> {
+    inner: Arc<ClientInner<B>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<B: Broker, In, Out> Clone for BusConnection<B, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<B: Broker, In, Out> fmt::Debug for BusConnection<B, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BusConnection")
+            .field("subject", &self.inner.subject)
+            .field("reply", &self.inner.reply)
+            .finish()
+    }
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> BusConnection<B, In, Out> {
+    /// Connect to the server that serves on `subject`
+    ///
+    /// Responses are received on the reply subject `<subject>.reply.<random id>`.
+    pub async fn connect(broker: B, subject: impl Into<String>) -> result::Result<Self, B::Error> {
+        let subject = subject.into();
+        let reply = format!("{}.reply.{:016x}", subject, random());
+        Self::connect_with_reply(broker, subject, reply).await
+    }
+
+    /// Connect to the server that serves on `subject`, with a custom reply subject
+    ///
+    /// The reply subject must not be used by any other connection. This is useful
+    /// for brokers whose subjects don't use `.` as a separator, such as MQTT.
+    pub async fn connect_with_reply(
+        broker: B,
+        subject: impl Into<String>,
+        reply: impl Into<String>,
+    ) -> result::Result<Self, B::Error> {
+        let subject = subject.into();
+        let reply = reply.into();
+        let mut subscription = broker.subscribe(&reply).await?;
+        let channels: Channels = Arc::new(Mutex::new(Some(HashMap::new())));
+        let router = tokio::spawn({
+            let channels = channels.clone();
+            async move {
+                while let Some(payload) = subscription.next().await {
+                    let envelope = match options().deserialize::<Envelope>(&payload) {
+                        Ok(envelope) => envelope,
+                        Err(cause) => {
+                            tracing::warn!("bus: dropping malformed response: {}", cause);
+                            continue;
+                        }
+                    };
+                    let mut channels = channels.lock().unwrap();
+                    let channels = channels.as_mut().expect("only cleared by the router");
+                    match envelope.frame {
+                        Frame::Data(payload) => {
+                            let sent = channels
+                                .get(&envelope.id)
+                                .map(|tx| tx.send(payload).is_ok());
+                            if sent == Some(false) {
+                                channels.remove(&envelope.id);
+                            }
+                        }
+                        Frame::End => {
+                            channels.remove(&envelope.id);
+                        }
+                        Frame::Open(_) => {
+                            tracing::warn!("bus: dropping response that opens a channel");
+                        }
+                    }
+                }
+                // the subscription ended, so all calls end
+                channels.lock().unwrap().take();
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ClientInner {
+                broker,
+                subject,
+                reply,
+                next_id: AtomicU64::new(0),
+                channels,
+                router,
+            }),
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> ConnectionErrors for BusConnection<B, In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError<B::Error>;
+    type RecvError = RecvError;
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for BusConnection<B, In, Out>
+{
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<B, Out>;
+    const CAPABILITIES: Capabilities = B::CAPABILITIES;
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> Connection<In, Out> for BusConnection<B, In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = &self.inner;
+        let id = inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = flume::unbounded();
+        // register the channel before anything is published, so no response is missed
+        match inner.channels.lock().unwrap().as_mut() {
+            Some(channels) => channels.insert(id, tx),
+            None => return future::ready(Err(OpenError::Closed)),
+        };
+        let send = SendSink::new(
+            inner.broker.clone(),
+            inner.subject.clone(),
+            Some(inner.reply.clone()),
+            id,
+        );
+        let recv = RecvStream::new(rx, Direction::Response);
+        future::ready(Ok((send, recv)))
+    }
+}
+
+/// A channel accepted by the router of a server
+type Accepted = (String, u64, flume::Receiver<Vec<u8>>);
+
+struct ServerInner<B> {
+    broker: B,
+    router: JoinHandle<()>,
+}
+
+impl<B> Drop for ServerInner<B> {
+    fn drop(&mut self) {
+        self.router.abort();
+    }
+}
+
+/// A server endpoint that serves calls published on a subject of a broker
+pub struct BusServerEndpoint<B: Broker, In, Out> {
+    inner: Arc<ServerInner<B>>,
+    accept: flume::Receiver<Accepted>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<B: Broker, In, Out> Clone for BusServerEndpoint<B, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<B: Broker, In, Out> fmt::Debug for BusServerEndpoint<B, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BusServerEndpoint").finish()
+    }
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> BusServerEndpoint<B, In, Out> {
+    /// Serve calls that are published on `subject`
+    pub async fn serve(broker: B, subject: impl Into<String>) -> result::Result<Self, B::Error> {
+        let subject = subject.into();
+        let mut subscription = broker.subscribe(&subject).await?;
+        let (accept_tx, accept) = flume::bounded::<Accepted>(32);
+        let router = tokio::spawn(async move {
+            let mut channels = HashMap::<(String, u64), flume::Sender<Vec<u8>>>::new();
+            while let Some(payload) = subscription.next().await {
+                let envelope = match options().deserialize::<Envelope>(&payload) {
+                    Ok(envelope) => envelope,
+                    Err(cause) => {
+                        tracing::warn!("bus: dropping malformed request: {}", cause);
+                        continue;
+                    }
+                };
+                let reply = match envelope.reply {
+                    Some(reply) => reply,
+                    None => {
+                        tracing::warn!("bus: dropping request without reply subject");
+                        continue;
+                    }
+                };
+                let key = (reply, envelope.id);
+                match envelope.frame {
+                    Frame::Open(payload) => {
+                        let (tx, rx) = flume::unbounded();
+                        tx.send(payload).ok();
+                        channels.insert(key.clone(), tx);
+                        if accept_tx.send_async((key.0, key.1, rx)).await.is_err() {
+                            // all server endpoints were dropped
+                            break;
+                        }
+                    }
+                    Frame::Data(payload) => {
+                        let sent = channels.get(&key).map(|tx| tx.send(payload).is_ok());
+                        if sent == Some(false) {
+                            channels.remove(&key);
+                        }
+                    }
+                    Frame::End => {
+                        channels.remove(&key);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner { broker, router }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for BusServerEndpoint<B, In, Out>
+{
+    type OpenError = OpenError;
+    type SendError = SendError<B::Error>;
+    type RecvError = RecvError;
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for BusServerEndpoint<B, In, Out>
+{
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<B, Out>;
+    const CAPABILITIES: Capabilities = B::CAPABILITIES;
+}
+
+impl<B: Broker, In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out>
+    for BusServerEndpoint<B, In, Out>
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        let broker = self.inner.broker.clone();
+        async move {
+            let (reply, id, rx) = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let send = SendSink::new(broker, reply, None, id);
+            let recv = RecvStream::new(rx, Direction::Request);
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    /// Broker subjects are not local addresses, so this is empty
+    fn local_addr(&self) -> &[LocalAddr] {
+        &[]
+    }
+}
+
+/// An in-process [Broker]
+///
+/// Payloads are delivered to all current subscribers of exactly the subject
+/// they are published on.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBroker(Arc<Mutex<Subscribers>>);
+
+/// Subscribers of a [MemoryBroker], by subject
+type Subscribers = HashMap<String, Vec<flume::Sender<Vec<u8>>>>;
+
+impl MemoryBroker {
+    /// Create a new broker without subscribers
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Broker for MemoryBroker {
+    type Error = Infallible;
+    type Subscription = flume::r#async::RecvStream<'static, Vec<u8>>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+
+    fn publish(
+        &self,
+        subject: &str,
+        payload: Vec<u8>,
+    ) -> BoxFuture<'static, result::Result<(), Self::Error>> {
+        let mut subjects = self.0.lock().unwrap();
+        if let Some(subscribers) = subjects.get_mut(subject) {
+            subscribers.retain(|tx| tx.send(payload.clone()).is_ok());
+        }
+        future::ok(()).boxed()
+    }
+
+    fn subscribe(
+        &self,
+        subject: &str,
+    ) -> BoxFuture<'static, result::Result<Self::Subscription, Self::Error>> {
+        let (tx, rx) = flume::unbounded();
+        let mut subjects = self.0.lock().unwrap();
+        subjects.entry(subject.to_string()).or_default().push(tx);
+        future::ok(rx.into_stream()).boxed()
+    }
+}
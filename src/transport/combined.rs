@@ -1,6 +1,14 @@
-//! Transport that combines two other transports
-use super::{Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint};
-use crate::RpcMessage;
+//! Transports that combine other transports
+//!
+//! A [CombinedConnection] or [CombinedServerEndpoint] combines two transports
+//! and keeps their types. A [MultiServerEndpoint] serves any number of
+//! endpoints at the same time, e.g. quinn, mem and tcp, at the cost of boxing
+//! their channels and errors.
+use super::{
+    boxed::{self, BoxedError, BoxedRecvStream, BoxedSendSink, BoxedServerEndpoint},
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::{extensions::Extensions, RpcMessage};
 use futures::{
     future::{self, BoxFuture},
     FutureExt, Sink, Stream, TryFutureExt,
@@ -10,9 +18,11 @@ use std::{
     error, fmt,
     fmt::Debug,
     marker::PhantomData,
+    net::SocketAddr,
     pin::Pin,
     result,
     task::{Context, Poll},
+    time::Duration,
 };
 
 /// A connection that combines two other connections
@@ -21,6 +31,8 @@ pub struct CombinedConnection<A, B, In: RpcMessage, Out: RpcMessage> {
     pub a: Option<A>,
     /// Second connection
     pub b: Option<B>,
+    /// Whether to fall back to the second connection
+    fallback: bool,
     /// Phantom data so we can have `In` and `Out` as type parameters
     _p: PhantomData<(In, Out)>,
 }
@@ -35,9 +47,20 @@ impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMes
         Self {
             a,
             b,
+            fallback: false,
             _p: PhantomData,
         }
     }
+
+    /// Open substreams on the second connection when opening them on the first fails
+    ///
+    /// Since a combined connection is a connection itself, fallbacks can be
+    /// chained, e.g. quinn, then tcp, then HTTP/1.1 long polling
+    /// when nothing else gets through.
+    pub fn with_fallback(mut self, fallback: bool) -> Self {
+        self.fallback = fallback;
+        self
+    }
 }
 impl<A: Clone, B: Clone, In: RpcMessage, Out: RpcMessage> Clone
     for CombinedConnection<A, B, In, Out>
@@ -46,6 +69,7 @@ impl<A: Clone, B: Clone, In: RpcMessage, Out: RpcMessage> Clone
         Self {
             a: self.a.clone(),
             b: self.b.clone(),
+            fallback: self.fallback,
             _p: PhantomData,
         }
     }
@@ -58,6 +82,7 @@ impl<A: Debug, B: Debug, In: RpcMessage, Out: RpcMessage> Debug
         f.debug_struct("CombinedConnection")
             .field("a", &self.a)
             .field("b", &self.b)
+            .field("fallback", &self.fallback)
             .finish()
     }
 }
@@ -301,6 +326,13 @@ impl<A: ConnectionErrors, B: ConnectionErrors, In: RpcMessage, Out: RpcMessage>
     type SendError = self::SendError<A, B>;
     type RecvError = self::RecvError<A, B>;
     type OpenError = self::OpenBiError<A, B>;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        match error {
+            RecvError::A(cause) => A::retry_after(cause),
+            RecvError::B(cause) => B::retry_after(cause),
+        }
+    }
 }
 
 impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMessage>
@@ -308,6 +340,8 @@ impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMes
 {
     type RecvStream = self::RecvStream<A, B, In, Out>;
     type SendSink = self::SendSink<A, B, In, Out>;
+    // channels can go over either transport
+    const CAPABILITIES: Capabilities = A::CAPABILITIES.and(B::CAPABILITIES);
 }
 
 impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMessage>
@@ -315,12 +349,18 @@ impl<A: Connection<In, Out>, B: Connection<In, Out>, In: RpcMessage, Out: RpcMes
 {
     fn open_bi(&self) -> OpenBiFuture<A, B, In, Out> {
         let this = self.clone();
-        async {
+        async move {
             // try a first, then b
             if let Some(a) = this.a {
-                let (send, recv) = a.open_bi().await.map_err(OpenBiError::A)?;
-                Ok((SendSink::A(send), RecvStream::A(recv)))
-            } else if let Some(b) = this.b {
+                match a.open_bi().await {
+                    Ok((send, recv)) => return Ok((SendSink::A(send), RecvStream::A(recv))),
+                    Err(cause) if this.fallback && this.b.is_some() => {
+                        tracing::debug!("falling back, opening a substream failed: {}", cause);
+                    }
+                    Err(cause) => return Err(OpenBiError::A(cause)),
+                }
+            }
+            if let Some(b) = this.b {
                 let (send, recv) = b.open_bi().await.map_err(OpenBiError::B)?;
                 Ok((SendSink::B(send), RecvStream::B(recv)))
             } else {
@@ -346,6 +386,7 @@ impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out
 {
     type RecvStream = self::RecvStream<A, B, In, Out>;
     type SendSink = self::SendSink<A, B, In, Out>;
+    const CAPABILITIES: Capabilities = A::CAPABILITIES.and(B::CAPABILITIES);
 }
 
 impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage>
@@ -394,6 +435,111 @@ impl<A: ServerEndpoint<In, Out>, B: ServerEndpoint<In, Out>, In: RpcMessage, Out
     }
 }
 
+/// An endpoint that serves any number of other endpoints
+///
+/// Like [CombinedServerEndpoint], [accept_bi](ServerEndpoint::accept_bi) waits
+/// for a channel on all endpoints, and returns the first one, or the first
+/// error. Without any endpoints, it waits forever.
+///
+/// The endpoints can have different types, so they are
+/// [boxed](super::boxed::BoxedServerEndpoint). Since the capabilities of the
+/// endpoints are only known at runtime, [CAPABILITIES](ConnectionCommon::CAPABILITIES)
+/// are [Capabilities::NONE], and [MultiServerEndpoint::capabilities] returns the
+/// guarantees all endpoints give.
+pub struct MultiServerEndpoint<In: RpcMessage, Out: RpcMessage> {
+    endpoints: Vec<BoxedServerEndpoint<In, Out>>,
+    local_addr: Vec<LocalAddr>,
+    capabilities: Capabilities,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> MultiServerEndpoint<In, Out> {
+    /// Create a multi endpoint without any endpoints
+    pub fn new() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            local_addr: Vec::new(),
+            capabilities: Capabilities::ALL,
+        }
+    }
+
+    /// Also serve `endpoint`
+    pub fn with_endpoint<E: ServerEndpoint<In, Out>>(mut self, endpoint: E) -> Self {
+        self.local_addr
+            .extend(endpoint.local_addr().iter().cloned());
+        self.capabilities = self.capabilities.and(E::CAPABILITIES);
+        self.endpoints.push(BoxedServerEndpoint::new(endpoint));
+        self
+    }
+
+    /// The guarantees that all endpoints give
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Default for MultiServerEndpoint<In, Out> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Clone for MultiServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            endpoints: self.endpoints.clone(),
+            local_addr: self.local_addr.clone(),
+            capabilities: self.capabilities,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Debug for MultiServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultiServerEndpoint")
+            .field("endpoints", &self.endpoints)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for MultiServerEndpoint<In, Out> {
+    type SendError = BoxedError;
+    type RecvError = BoxedError;
+    type OpenError = BoxedError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        BoxedServerEndpoint::<In, Out>::retry_after(error)
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for MultiServerEndpoint<In, Out> {
+    type RecvStream = BoxedRecvStream<In>;
+    type SendSink = BoxedSendSink<Out>;
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        BoxedServerEndpoint::<In, Out>::peer_addr(recv)
+    }
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        BoxedServerEndpoint::<In, Out>::extensions(recv)
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for MultiServerEndpoint<In, Out> {
+    fn accept_bi(&self) -> boxed::AcceptBiFuture<In, Out> {
+        if self.endpoints.is_empty() {
+            return future::pending().boxed();
+        }
+        let accepts = self.endpoints.iter().map(|endpoint| endpoint.accept_bi());
+        future::select_all(accepts).map(|(res, _, _)| res).boxed()
+    }
+
+    type AcceptBiFut = boxed::AcceptBiFuture<In, Out>;
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.local_addr
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -401,7 +547,7 @@ mod tests {
             combined::{self, OpenBiError},
             flume,
         },
-        Connection,
+        Connection, ServerEndpoint,
     };
 
     #[tokio::test]
@@ -415,4 +561,20 @@ mod tests {
         let res = channel.open_bi().await;
         assert!(matches!(res, Err(OpenBiError::NoChannel)));
     }
+
+    #[tokio::test]
+    async fn open_fallback_channel() {
+        let (server, a) = flume::connection::<(), ()>(1);
+        drop(server);
+        let (server, b) = flume::connection::<(), ()>(1);
+        let channel = combined::CombinedConnection::new(Some(a), Some(b));
+        let res = channel.open_bi().await;
+        assert!(matches!(res, Err(OpenBiError::A(_))));
+
+        let channel = channel.with_fallback(true);
+        let accept = tokio::spawn(async move { server.accept_bi().await.is_ok() });
+        let res = channel.open_bi().await;
+        assert!(matches!(res, Ok((combined::SendSink::B(_), _))));
+        assert!(accept.await.unwrap());
+    }
 }
@@ -0,0 +1,310 @@
+//! Frame compression using zstd dictionaries
+//!
+//! Small structured messages compress badly on their own, since there is not
+//! enough data to learn from. A dictionary trained on typical messages, e.g.
+//! using the `zstd --train` command line tool, fixes this and can shrink small
+//! payloads considerably.
+//!
+//! Both sides of a connection must be configured with the same dictionary.
+//! Compressed frames carry the id of a dictionary in the zstd format, so a
+//! frame that was compressed with a different dictionary is rejected instead
+//! of decompressing to garbage. Raw content dictionaries have no id, so frames
+//! compressed with them can not be checked. With compression enabled, every frame starts with a flag byte, so frames
+//! that would not get smaller are sent uncompressed.
+//!
+//! Some messages never get smaller, e.g. ones that carry blobs that are
+//! already compressed. The dictionary keeps [CompressionStats] per message
+//! type of all the connections that use it, and with [Adaptive] compression,
+//! which is the default, stops trying to compress message types that save too
+//! little. The statistics are also recorded in the metrics.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Read},
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// Default zstd compression level
+pub const DEFAULT_LEVEL: i32 = 3;
+
+const FLAG_RAW: u8 = 0;
+const FLAG_ZSTD: u8 = 1;
+
+/// When to stop compressing a message type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adaptive {
+    /// The share of bytes compression has to save, e.g. `0.05` for 5%
+    pub min_saving: f64,
+    /// The number of frames of a message type to compress before deciding
+    pub samples: u64,
+    /// Every this many skipped frames, one is compressed again, to notice
+    /// when the messages of a type start to compress
+    pub probe_interval: u64,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Self {
+            min_saving: 0.05,
+            samples: 16,
+            probe_interval: 64,
+        }
+    }
+}
+
+/// Compression statistics of a message type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionStats {
+    /// Frames that were compressed, or tried to
+    pub compressed_frames: u64,
+    /// Frames that were sent uncompressed without trying
+    pub skipped_frames: u64,
+    /// Bytes of the compressed frames, before compression
+    pub input_bytes: u64,
+    /// Bytes of the compressed frames, after compression
+    pub output_bytes: u64,
+}
+
+impl CompressionStats {
+    /// The size of the compressed frames after compression, relative to before
+    pub fn ratio(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 1.0;
+        }
+        self.output_bytes as f64 / self.input_bytes as f64
+    }
+}
+
+/// The statistics of a message type, and what adaptive compression decides on
+#[derive(Debug, Default)]
+struct Tracked {
+    stats: CompressionStats,
+    /// Moving average of the ratio of the recently compressed frames
+    recent: f64,
+    /// Frames skipped since the last probe
+    since_probe: u64,
+}
+
+/// A zstd dictionary, prepared for compression and decompression
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    inner: Arc<Inner>,
+    adaptive: Option<Adaptive>,
+    stats: Arc<Mutex<HashMap<&'static str, Tracked>>>,
+}
+
+struct Inner {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+    len: usize,
+    id: Option<u32>,
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("len", &self.inner.len)
+            .field("id", &self.inner.id)
+            .field("adaptive", &self.adaptive)
+            .finish()
+    }
+}
+
+impl ZstdDictionary {
+    /// Prepare a dictionary with [DEFAULT_LEVEL]
+    pub fn new(dictionary: &[u8]) -> Self {
+        Self::with_level(dictionary, DEFAULT_LEVEL)
+    }
+
+    /// Prepare a dictionary with a custom compression level
+    pub fn with_level(dictionary: &[u8], level: i32) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                encoder: EncoderDictionary::copy(dictionary, level),
+                decoder: DecoderDictionary::copy(dictionary),
+                len: dictionary.len(),
+                id: zstd::zstd_safe::get_dict_id_from_dict(dictionary).map(NonZeroU32::get),
+            }),
+            adaptive: Some(Adaptive::default()),
+            stats: Default::default(),
+        }
+    }
+
+    /// The id of the dictionary, if it is in the zstd format, e.g. made by `zstd --train`
+    pub fn id(&self) -> Option<u32> {
+        self.inner.id
+    }
+
+    /// Set when to stop compressing a message type, or `None` to always compress
+    pub fn with_adaptive(mut self, value: Option<Adaptive>) -> Self {
+        self.adaptive = value;
+        self
+    }
+
+    /// The compression statistics of the connections that use this dictionary, by message type
+    ///
+    /// Message types are the names of the variants of the generated enums.
+    pub fn stats(&self) -> BTreeMap<&'static str, CompressionStats> {
+        let stats = self.stats.lock().unwrap();
+        stats
+            .iter()
+            .map(|(message, tracked)| (*message, tracked.stats))
+            .collect()
+    }
+
+    /// Compress a frame of `message`, prefixing it with the flag byte
+    ///
+    /// Frames of message types that do not compress are not compressed at all
+    /// with adaptive compression.
+    pub(crate) fn compress(
+        &self,
+        frame: &[u8],
+        message: Option<&'static str>,
+    ) -> io::Result<Vec<u8>> {
+        if let Some(message) = message {
+            if self.skip(message) {
+                crate::telemetry::compression(message, frame.len() as u64, None);
+                return Ok(framed(FLAG_RAW, frame));
+            }
+        }
+        let mut compressor = zstd::bulk::Compressor::with_prepared_dictionary(&self.inner.encoder)?;
+        let compressed = compressor.compress(frame)?;
+        let (flag, data) = if compressed.len() < frame.len() {
+            (FLAG_ZSTD, compressed.as_slice())
+        } else {
+            (FLAG_RAW, frame)
+        };
+        if let Some(message) = message {
+            self.record(message, frame.len() as u64, data.len() as u64);
+            crate::telemetry::compression(message, frame.len() as u64, Some(data.len() as u64));
+        }
+        Ok(framed(flag, data))
+    }
+
+    /// Frame `frame` without compressing it, and without recording it in the statistics
+    pub(crate) fn uncompressed(&self, frame: &[u8]) -> Vec<u8> {
+        framed(FLAG_RAW, frame)
+    }
+
+    /// Whether to send the next frame of `message` without trying to compress it
+    fn skip(&self, message: &'static str) -> bool {
+        let adaptive = match &self.adaptive {
+            Some(adaptive) => adaptive,
+            None => return false,
+        };
+        let mut stats = self.stats.lock().unwrap();
+        let tracked = stats.entry(message).or_default();
+        if tracked.stats.compressed_frames < adaptive.samples
+            || tracked.recent <= 1.0 - adaptive.min_saving
+        {
+            return false;
+        }
+        tracked.since_probe += 1;
+        if tracked.since_probe >= adaptive.probe_interval {
+            tracked.since_probe = 0;
+            return false;
+        }
+        tracked.stats.skipped_frames += 1;
+        true
+    }
+
+    /// Record a frame of `message` that was compressed from `input` to `output` bytes
+    fn record(&self, message: &'static str, input: u64, output: u64) {
+        let ratio = if input == 0 {
+            1.0
+        } else {
+            output as f64 / input as f64
+        };
+        let weight = 1.0 / self.adaptive.map_or(1, |adaptive| adaptive.samples.max(1)) as f64;
+        let mut stats = self.stats.lock().unwrap();
+        let tracked = stats.entry(message).or_default();
+        tracked.recent = if tracked.stats.compressed_frames == 0 {
+            ratio
+        } else {
+            tracked.recent + (ratio - tracked.recent) * weight
+        };
+        tracked.stats.compressed_frames += 1;
+        tracked.stats.input_bytes += input;
+        tracked.stats.output_bytes += output;
+    }
+
+    /// Decompress a frame that was produced by [ZstdDictionary::compress]
+    ///
+    /// Fails if the decompressed frame would be larger than `max_len`.
+    pub(crate) fn decompress(&self, frame: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        let (flag, data) = frame
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty frame"))?;
+        match *flag {
+            FLAG_RAW => Ok(data.to_vec()),
+            FLAG_ZSTD => {
+                let id = zstd::zstd_safe::get_dict_id_from_frame(data).map(NonZeroU32::get);
+                if id != self.inner.id {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "frame compressed with dictionary {:?}, but {:?} is configured",
+                            id, self.inner.id
+                        ),
+                    ));
+                }
+                let decoder = zstd::stream::read::Decoder::with_prepared_dictionary(
+                    data,
+                    &self.inner.decoder,
+                )?;
+                let mut res = Vec::new();
+                decoder.take(max_len as u64 + 1).read_to_end(&mut res)?;
+                if res.len() > max_len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "decompressed frame too large",
+                    ));
+                }
+                Ok(res)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown compression flag",
+            )),
+        }
+    }
+}
+
+/// `data` with the flag byte in front
+fn framed(flag: u8, data: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(data.len() + 1);
+    res.push(flag);
+    res.extend_from_slice(data);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ZstdDictionary;
+
+    /// a dictionary in the zstd format, trained on messages of `kind`
+    fn trained(kind: &str) -> Vec<u8> {
+        let samples = (0..1000)
+            .map(|i| format!("{{\"{kind}\":{i},\"name\":\"{kind} number {i}\"}}").into_bytes())
+            .collect::<Vec<_>>();
+        zstd::dict::from_samples(&samples, 1024).unwrap()
+    }
+
+    #[test]
+    fn dictionary_id_mismatch() {
+        let a = ZstdDictionary::new(&trained("user")).with_adaptive(None);
+        let b = ZstdDictionary::new(&trained("order")).with_adaptive(None);
+        assert!(a.id().is_some());
+        assert_ne!(a.id(), b.id());
+        let frame = br#"{"user":7,"name":"user number 7"}"#.repeat(4);
+        let compressed = a.compress(&frame, None).unwrap();
+        assert_eq!(compressed[0], super::FLAG_ZSTD);
+        assert_eq!(a.decompress(&compressed, 1024).unwrap(), frame);
+        let err = b.decompress(&compressed, 1024).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // raw content dictionaries have no id
+        assert_eq!(ZstdDictionary::new(&[0u8; 64]).id(), None);
+    }
+}
@@ -0,0 +1,455 @@
+//! Context for errors when decoding messages
+use bincode::Options;
+use serde::de::{self, DeserializeOwned, Visitor};
+use std::{any::type_name, error, fmt, io, io::Read};
+
+/// Maximum number of bytes in [DecodeError::snippet]
+const SNIPPET_LEN: usize = 32;
+
+/// The direction of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A message from the client to the server
+    Request,
+    /// A message from the server to the client
+    Response,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Request => f.write_str("request"),
+            Direction::Response => f.write_str("response"),
+        }
+    }
+}
+
+/// Error when decoding a message, with information about where it failed
+///
+/// Transports that report errors as [io::Error] wrap this, use
+/// [DecodeError::from_io] to get it back.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// The direction of the message
+    pub direction: Direction,
+    /// The type of the message, e.g. `my_crate::MyRequest`
+    pub type_name: &'static str,
+    /// The enum variant of the message, if the message type is an enum and
+    /// its tag could be decoded
+    pub variant: Option<&'static str>,
+    /// The offset in the message at which decoding failed
+    pub offset: usize,
+    /// The length of the message
+    pub len: usize,
+    /// The start offset and the bytes of the message around `offset`
+    ///
+    /// This is only captured in debug builds, since messages can contain
+    /// sensitive data.
+    pub snippet: Option<(usize, Vec<u8>)>,
+    /// The error of the codec
+    pub cause: bincode::Error,
+}
+
+impl DecodeError {
+    /// Get the decode error of an [io::Error], if it was caused by one
+    pub fn from_io(error: &io::Error) -> Option<&DecodeError> {
+        error.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    fn new<T: DeserializeOwned, O: Options + Copy>(
+        options: O,
+        bytes: &[u8],
+        direction: Direction,
+        cause: bincode::Error,
+    ) -> Self {
+        // decode again, counting the bytes that were consumed before the failure
+        let mut reader = CountingReader { bytes, read: 0 };
+        let _ = options.deserialize_from::<_, T>(&mut reader);
+        let offset = reader.read;
+        #[cfg(debug_assertions)]
+        let snippet = {
+            let start = offset.saturating_sub(SNIPPET_LEN / 2);
+            let end = bytes.len().min(start + SNIPPET_LEN);
+            Some((start, bytes[start..end].to_vec()))
+        };
+        #[cfg(not(debug_assertions))]
+        let snippet = None;
+        Self {
+            direction,
+            type_name: type_name::<T>(),
+            variant: variant::<T, O>(options, bytes),
+            offset,
+            len: bytes.len(),
+            snippet,
+            cause,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decode {} {}", self.direction, self.type_name)?;
+        if let Some(variant) = self.variant {
+            write!(f, "::{variant}")?;
+        }
+        write!(
+            f,
+            " at byte {} of {}: {}",
+            self.offset, self.len, self.cause
+        )?;
+        if let Some((start, bytes)) = &self.snippet {
+            write!(f, " [{}..{}:", start, start + bytes.len())?;
+            for byte in bytes {
+                write!(f, " {byte:02x}")?;
+            }
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl From<DecodeError> for io::Error {
+    fn from(error: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Decode a message with bincode, adding context on failure
+pub(crate) fn decode<T: DeserializeOwned, O: Options + Copy>(
+    options: O,
+    bytes: &[u8],
+    direction: Direction,
+) -> Result<T, DecodeError> {
+    options
+        .deserialize(bytes)
+        .map_err(|cause| DecodeError::new::<T, O>(options, bytes, direction, cause))
+}
+
+struct CountingReader<'a> {
+    bytes: &'a [u8],
+    read: usize,
+}
+
+impl<'a> Read for CountingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.bytes.read(buf)?;
+        self.read += n;
+        Ok(n)
+    }
+}
+
+/// The name of the enum variant of a message
+///
+/// bincode encodes the tag of the variant as a u32 in front of the variant,
+/// using the int encoding of the options. The tag is the variant index for
+/// derived enums, but not for enums with their own tags, like the ones of
+/// [rpc_service](crate::rpc_service). So the tag is resolved by the enum
+/// itself: the variant it picks for the tag is the one it picks for the name.
+fn variant<T: DeserializeOwned, O: Options>(options: O, bytes: &[u8]) -> Option<&'static str> {
+    let variants = match T::deserialize(Probe::Variants) {
+        Err(ProbeError::Variants(variants)) => variants,
+        _ => return None,
+    };
+    let tag: u32 = options.allow_trailing_bytes().deserialize(bytes).ok()?;
+    let tagged = arm::<T>(Ident::Tag(tag))?;
+    let mut matching = variants
+        .iter()
+        .filter(|name| arm::<T>(Ident::Name(name)).as_ref() == Some(&tagged));
+    // variants of derived enums can look the same, but their tag is the index
+    match variants.get(tag as usize) {
+        Some(name) if matching.clone().any(|n| n == name) => Some(name),
+        _ => match (matching.next(), matching.next()) {
+            (Some(name), None) => Some(name),
+            _ => None,
+        },
+    }
+}
+
+/// How an enum deserializes the variant identified by `ident`, if it knows it
+fn arm<T: DeserializeOwned>(ident: Ident) -> Option<Arm> {
+    match T::deserialize(Probe::Arm(ident)) {
+        Err(ProbeError::Arm(arm)) => Some(arm),
+        _ => None,
+    }
+}
+
+/// A deserializer that only finds out the variants of an enum
+enum Probe {
+    /// The names of the variants
+    Variants,
+    /// How the variant with the identifier is deserialized
+    Arm(Ident),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Ident {
+    Tag(u32),
+    Name(&'static str),
+}
+
+/// What an enum deserializes a variant as
+#[derive(Debug, PartialEq, Eq)]
+enum Arm {
+    Unit,
+    Newtype(&'static str),
+    Tuple(usize, &'static str),
+    Struct(&'static [&'static str], &'static str),
+}
+
+#[derive(Debug)]
+enum ProbeError {
+    Variants(&'static [&'static str]),
+    Arm(Arm),
+    Other,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for ProbeError {}
+
+impl de::Error for ProbeError {
+    fn custom<M: fmt::Display>(_msg: M) -> Self {
+        ProbeError::Other
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Probe {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(ProbeError::Other)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Probe::Variants => Err(ProbeError::Variants(variants)),
+            Probe::Arm(ident) => visitor.visit_enum(ident),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> de::EnumAccess<'de> for Ident {
+    type Error = ProbeError;
+    type Variant = Self;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), Self::Error> {
+        Ok((seed.deserialize(self)?, self))
+    }
+}
+
+/// Records how the enum deserializes the variant, instead of deserializing it
+impl<'de> de::VariantAccess<'de> for Ident {
+    type Error = ProbeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(ProbeError::Arm(Arm::Unit))
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        _seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        Err(ProbeError::Arm(Arm::Newtype(type_name::<T::Value>())))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        Err(ProbeError::Arm(Arm::Tuple(len, type_name::<V::Value>())))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        Err(ProbeError::Arm(Arm::Struct(
+            fields,
+            type_name::<V::Value>(),
+        )))
+    }
+}
+
+/// The identifier of the variant, by tag or by name
+impl<'de> de::Deserializer<'de> for Ident {
+    type Error = ProbeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Ident::Tag(tag) => visitor.visit_u64(tag.into()),
+            Ident::Name(name) => visitor.visit_borrowed_str(name),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::variant;
+    use bincode::Options;
+    use serde::{de, Deserialize, Deserializer};
+    use std::fmt;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    enum Derived {
+        A,
+        B,
+        C(u8),
+    }
+
+    /// An enum whose tags are not the indices of its variants
+    #[allow(dead_code)]
+    enum Tagged {
+        A(u8),
+        B(u16),
+    }
+
+    impl<'de> Deserialize<'de> for Tagged {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct TagVisitor;
+
+            impl<'de> de::Visitor<'de> for TagVisitor {
+                type Value = u32;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("a tag")
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<u32, E> {
+                    Ok(v as u32)
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<u32, E> {
+                    match v {
+                        "A" => Ok(7),
+                        "B" => Ok(1),
+                        _ => Err(E::unknown_variant(v, &["A", "B"])),
+                    }
+                }
+            }
+
+            impl<'de> de::DeserializeSeed<'de> for TagVisitor {
+                type Value = u32;
+
+                fn deserialize<D: Deserializer<'de>>(self, d: D) -> Result<u32, D::Error> {
+                    d.deserialize_identifier(self)
+                }
+            }
+
+            struct EnumVisitor;
+
+            impl<'de> de::Visitor<'de> for EnumVisitor {
+                type Value = Tagged;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("enum Tagged")
+                }
+
+                fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<Tagged, A::Error> {
+                    let (tag, variant) = data.variant_seed(TagVisitor)?;
+                    match tag {
+                        7 => de::VariantAccess::newtype_variant(variant).map(Tagged::A),
+                        1 => de::VariantAccess::newtype_variant(variant).map(Tagged::B),
+                        tag => Err(de::Error::custom(format_args!("unknown tag {tag}"))),
+                    }
+                }
+            }
+
+            deserializer.deserialize_enum("Tagged", &["A", "B"], EnumVisitor)
+        }
+    }
+
+    fn variant_of<T: de::DeserializeOwned>(tag: u32) -> Option<&'static str> {
+        let options = bincode::DefaultOptions::new().with_fixint_encoding();
+        variant::<T, _>(options, &options.serialize(&tag).unwrap())
+    }
+
+    #[test]
+    fn variant_by_index() {
+        assert_eq!(variant_of::<Derived>(0), Some("A"));
+        assert_eq!(variant_of::<Derived>(1), Some("B"));
+        assert_eq!(variant_of::<Derived>(2), Some("C"));
+        assert_eq!(variant_of::<Derived>(3), None);
+    }
+
+    #[test]
+    fn variant_by_tag() {
+        assert_eq!(variant_of::<Tagged>(7), Some("A"));
+        // not the variant at index 1
+        assert_eq!(variant_of::<Tagged>(1), Some("B"));
+        assert_eq!(variant_of::<Tagged>(0), None);
+        assert_eq!(variant_of::<Tagged>(2), None);
+    }
+
+    #[cfg(feature = "macros")]
+    mod macros {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Get(pub u64);
+
+        #[derive(Debug, Serialize, Deserialize)]
+        pub struct Put(pub u64, pub u64);
+
+        crate::rpc_service! {
+            Request = StoreRequest;
+            Response = StoreResponse;
+            Service = StoreService;
+            CreateDispatch = _;
+
+            Rpc get = Get, _ -> Get;
+            #[tags(Put = 1)]
+            Rpc put = Put, _ -> Put;
+        }
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn variant_of_macro_enum() {
+        use super::{decode, Direction};
+        use macros::*;
+
+        let options = bincode::DefaultOptions::new().with_fixint_encoding();
+        for (req, name) in [
+            (StoreRequest::from(Get(1)), "Get"),
+            (StoreRequest::from(Put(1, 2)), "Put"),
+        ] {
+            let bytes = options.serialize(&req).unwrap();
+            let truncated = &bytes[..bytes.len() - 1];
+            let e = decode::<StoreRequest, _>(options, truncated, Direction::Request).unwrap_err();
+            assert_eq!(e.variant, Some(name));
+        }
+    }
+}
@@ -0,0 +1,609 @@
+//! ChaCha20-Poly1305 encryption for every message, with key rotation
+//!
+//! [EncryptedConnection] and [EncryptedServerEndpoint] wrap a transport so that
+//! every message is serialized and encrypted with a key shared by both sides,
+//! independent of any encryption provided by the transport. Like the [signed]
+//! wrappers, this is useful when messages cross hops that terminate the
+//! transport encryption.
+//!
+//! Each direction of a channel derives its own key from the shared key and a
+//! random salt that is sent with its first message. Messages are numbered
+//! within a channel, so replayed, reordered or dropped messages fail to
+//! decrypt. The inner transport must be ordered and reliable.
+//!
+//! # Key rotation
+//!
+//! For channels that live for a long time, e.g. streams lasting hours, the
+//! sender can rotate the key of its direction after a number of messages or
+//! bytes, see [EncryptionConfig::rotate_after_messages] and
+//! [EncryptionConfig::rotate_after_bytes]. Rotation is opt-in and needs no
+//! configuration on the receiving side: every message carries the epoch of
+//! its key, and the receiver follows when the epoch advances.
+//!
+//! The key of the next epoch is derived from the key of the current epoch and
+//! the shared key, and the old key is dropped. So a key of an epoch that leaks
+//! only exposes the messages of that epoch: earlier keys can not be computed
+//! from it, and later keys can not be computed without the shared key.
+//! Leaking the shared key itself exposes everything.
+//!
+//! [signed]: super::signed
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::{future::BoxFuture, FutureExt, Sink, Stream};
+use hkdf::Hkdf;
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Key derivation context of requests
+const REQUEST_CONTEXT: &[u8] = b"quic-rpc encrypted request";
+/// Key derivation context of responses
+const RESPONSE_CONTEXT: &[u8] = b"quic-rpc encrypted response";
+/// Key derivation context of the next epoch
+const RATCHET_CONTEXT: &[u8] = b"quic-rpc encrypted ratchet";
+
+/// Wire format of messages on an encrypted connection
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Encrypted<T> {
+    /// The salt of the key of the direction, set on the first message only
+    salt: Option<[u8; 32]>,
+    epoch: u32,
+    ciphertext: Vec<u8>,
+    #[serde(skip)]
+    _p: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encrypted")
+            .field("len", &self.ciphertext.len())
+            .field("epoch", &self.epoch)
+            .finish()
+    }
+}
+
+/// The key of one direction of a channel in the current epoch
+struct Ratchet {
+    key: [u8; 32],
+    cipher: ChaCha20Poly1305,
+    epoch: u32,
+    /// Messages encrypted with the key so far
+    messages: u64,
+    /// Bytes encrypted with the key so far
+    bytes: u64,
+}
+
+impl Ratchet {
+    fn new(shared: &[u8; 32], salt: &[u8; 32], context: &[u8]) -> Self {
+        Self::derive(salt, shared, context, 0)
+    }
+
+    fn derive(salt: &[u8; 32], ikm: &[u8; 32], context: &[u8], epoch: u32) -> Self {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(Some(salt), ikm)
+            .expand(context, &mut key)
+            .expect("32 bytes is a valid output length");
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            key,
+            epoch,
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    /// The key of the next epoch
+    fn next(&self, shared: &[u8; 32]) -> Self {
+        Self::derive(
+            &self.key,
+            shared,
+            RATCHET_CONTEXT,
+            self.epoch.wrapping_add(1),
+        )
+    }
+
+    /// Messages are numbered within an epoch, so the number is a unique nonce
+    fn nonce(&self) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&self.messages.to_le_bytes());
+        *Nonce::from_slice(&nonce)
+    }
+
+    fn seal(&mut self, plaintext: &[u8]) -> result::Result<Vec<u8>, chacha20poly1305::Error> {
+        let ciphertext = self.cipher.encrypt(&self.nonce(), plaintext)?;
+        self.messages += 1;
+        self.bytes += plaintext.len() as u64;
+        Ok(ciphertext)
+    }
+
+    fn open(&mut self, ciphertext: &[u8]) -> result::Result<Vec<u8>, chacha20poly1305::Error> {
+        let plaintext = self.cipher.decrypt(&self.nonce(), ciphertext)?;
+        self.messages += 1;
+        self.bytes += plaintext.len() as u64;
+        Ok(plaintext)
+    }
+}
+
+/// When the sender rotates the key of its direction of a channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Rotation {
+    messages: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl Rotation {
+    fn due(&self, ratchet: &Ratchet) -> bool {
+        self.messages.map_or(false, |max| ratchet.messages >= max)
+            || self.bytes.map_or(false, |max| ratchet.bytes >= max)
+    }
+}
+
+/// Keys used by an [EncryptedConnection] or [EncryptedServerEndpoint]
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    key: Arc<[u8; 32]>,
+    rotation: Rotation,
+}
+
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("rotation", &self.rotation)
+            .finish()
+    }
+}
+
+impl EncryptionConfig {
+    /// Encrypt messages with keys derived from the 256 bit shared `key`
+    ///
+    /// Keys are not rotated within a channel unless configured.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key: Arc::new(key),
+            rotation: Rotation::default(),
+        }
+    }
+
+    /// Rotate the key of a direction of a channel after sending `messages` messages with it
+    pub fn rotate_after_messages(mut self, messages: u64) -> Self {
+        self.rotation.messages = Some(messages.max(1));
+        self
+    }
+
+    /// Rotate the key of a direction of a channel after encrypting `bytes` bytes with it
+    pub fn rotate_after_bytes(mut self, bytes: u64) -> Self {
+        self.rotation.bytes = Some(bytes.max(1));
+        self
+    }
+}
+
+/// A connection that encrypts requests and decrypts responses
+pub struct EncryptedConnection<C, In, Out> {
+    inner: C,
+    config: EncryptionConfig,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C, In, Out> EncryptedConnection<C, In, Out>
+where
+    C: Connection<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a connection
+    pub fn new(inner: C, config: EncryptionConfig) -> Self {
+        Self {
+            inner,
+            config,
+            _p: PhantomData,
+        }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for EncryptedConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for EncryptedConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedConnection")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for EncryptedConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = SendError<C::SendError>;
+    type RecvError = RecvError<C::RecvError>;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        match error {
+            RecvError::Inner(cause) => C::retry_after(cause),
+            _ => None,
+        }
+    }
+}
+
+impl<C, In, Out> ConnectionCommon<In, Out> for EncryptedConnection<C, In, Out>
+where
+    C: Connection<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<C::SendSink, Out>;
+    type RecvStream = self::RecvStream<C::RecvStream, In>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        encrypted: true,
+        ..C::CAPABILITIES
+    };
+}
+
+impl<C, In, Out> Connection<In, Out> for EncryptedConnection<C, In, Out>
+where
+    C: Connection<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let send = SendSink::new(send, config.clone(), REQUEST_CONTEXT);
+            let recv = RecvStream::new(recv, config, RESPONSE_CONTEXT);
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+/// A server endpoint that decrypts requests and encrypts responses
+pub struct EncryptedServerEndpoint<E, In, Out> {
+    inner: E,
+    config: EncryptionConfig,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<E, In, Out> EncryptedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a server endpoint
+    pub fn new(inner: E, config: EncryptionConfig) -> Self {
+        Self {
+            inner,
+            config,
+            _p: PhantomData,
+        }
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Clone, In, Out> Clone for EncryptedServerEndpoint<E, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<E: fmt::Debug, In, Out> fmt::Debug for EncryptedServerEndpoint<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedServerEndpoint")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<E: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for EncryptedServerEndpoint<E, In, Out>
+{
+    type OpenError = E::OpenError;
+    type SendError = SendError<E::SendError>;
+    type RecvError = RecvError<E::RecvError>;
+}
+
+impl<E, In, Out> ConnectionCommon<In, Out> for EncryptedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<E::SendSink, Out>;
+    type RecvStream = self::RecvStream<E::RecvStream, In>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        encrypted: true,
+        ..E::CAPABILITIES
+    };
+}
+
+impl<E, In, Out> ServerEndpoint<In, Out> for EncryptedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Encrypted<In>, Encrypted<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        async move {
+            let (send, recv) = inner.accept_bi().await?;
+            let send = SendSink::new(send, config.clone(), RESPONSE_CONTEXT);
+            let recv = RecvStream::new(recv, config, REQUEST_CONTEXT);
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Send sink for encrypted channels
+///
+/// Serializes and encrypts every message before passing it to the inner sink,
+/// and rotates the key when it is due.
+#[pin_project]
+pub struct SendSink<S, T> {
+    #[pin]
+    inner: S,
+    config: EncryptionConfig,
+    context: &'static [u8],
+    ratchet: Option<Ratchet>,
+    _p: PhantomData<fn(T)>,
+}
+
+impl<S, T> SendSink<S, T> {
+    fn new(inner: S, config: EncryptionConfig, context: &'static [u8]) -> Self {
+        Self {
+            inner,
+            config,
+            context,
+            ratchet: None,
+            _p: PhantomData,
+        }
+    }
+
+    /// The epoch of the key of the last message sent on this channel
+    pub fn epoch(&self) -> u32 {
+        self.ratchet.as_ref().map_or(0, |ratchet| ratchet.epoch)
+    }
+}
+
+impl<S, T> fmt::Debug for SendSink<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("epoch", &self.epoch())
+            .finish()
+    }
+}
+
+impl<S: Sink<Encrypted<T>>, T: Serialize> Sink<T> for SendSink<S, T> {
+    type Error = SendError<S::Error>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_ready(cx)
+            .map_err(SendError::Inner)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: T) -> Result<(), Self::Error> {
+        let this = self.project();
+        let payload = bincode::serialize(&msg).map_err(SendError::Serialize)?;
+        let mut salt = None;
+        let ratchet = match this.ratchet {
+            Some(ratchet) => ratchet,
+            None => {
+                let mut bytes = [0; 32];
+                OsRng.fill_bytes(&mut bytes);
+                salt = Some(bytes);
+                this.ratchet
+                    .insert(Ratchet::new(&this.config.key, &bytes, this.context))
+            }
+        };
+        if this.config.rotation.due(ratchet) {
+            *ratchet = ratchet.next(&this.config.key);
+        }
+        let ciphertext = ratchet.seal(&payload).map_err(|_| SendError::Encrypt)?;
+        let item = Encrypted {
+            salt,
+            epoch: ratchet.epoch,
+            ciphertext,
+            _p: PhantomData,
+        };
+        this.inner.start_send(item).map_err(SendError::Inner)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_flush(cx)
+            .map_err(SendError::Inner)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_close(cx)
+            .map_err(SendError::Inner)
+    }
+}
+
+/// Receive stream for encrypted channels
+///
+/// Decrypts every message, and follows the key rotations of the sender.
+#[pin_project]
+pub struct RecvStream<R, T> {
+    #[pin]
+    inner: R,
+    config: EncryptionConfig,
+    context: &'static [u8],
+    ratchet: Option<Ratchet>,
+    _p: PhantomData<fn() -> T>,
+}
+
+impl<R, T> RecvStream<R, T> {
+    fn new(inner: R, config: EncryptionConfig, context: &'static [u8]) -> Self {
+        Self {
+            inner,
+            config,
+            context,
+            ratchet: None,
+            _p: PhantomData,
+        }
+    }
+
+    /// The epoch of the key of the last message received on this channel
+    pub fn epoch(&self) -> u32 {
+        self.ratchet.as_ref().map_or(0, |ratchet| ratchet.epoch)
+    }
+}
+
+impl<R, T> fmt::Debug for RecvStream<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("epoch", &self.epoch())
+            .finish()
+    }
+}
+
+impl<R, T, E> Stream for RecvStream<R, T>
+where
+    R: Stream<Item = result::Result<Encrypted<T>, E>>,
+    T: DeserializeOwned,
+{
+    type Item = result::Result<T, RecvError<E>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = match this.inner.poll_next(cx) {
+            Poll::Ready(Some(Ok(item))) => item,
+            Poll::Ready(Some(Err(cause))) => {
+                return Poll::Ready(Some(Err(RecvError::Inner(cause))))
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        let current = match (this.ratchet.as_mut(), item.salt) {
+            (None, Some(salt)) => {
+                this.ratchet
+                    .insert(Ratchet::new(&this.config.key, &salt, this.context))
+            }
+            (Some(ratchet), None) => ratchet,
+            // a salt on any but the first message would start over with the
+            // first key, and no salt on the first message leaves no key
+            _ => return Poll::Ready(Some(Err(RecvError::Salt))),
+        };
+        let payload = if item.epoch == current.epoch {
+            current.open(&item.ciphertext)
+        } else if item.epoch == current.epoch.wrapping_add(1) {
+            // only switch to the next key once it is known to be used
+            let mut next = current.next(&this.config.key);
+            let res = next.open(&item.ciphertext);
+            if res.is_ok() {
+                *current = next;
+            }
+            res
+        } else {
+            return Poll::Ready(Some(Err(RecvError::Epoch(item.epoch))));
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(_) => return Poll::Ready(Some(Err(RecvError::Decrypt))),
+        };
+        let msg = bincode::deserialize(&payload).map_err(RecvError::Deserialize);
+        Poll::Ready(Some(msg))
+    }
+}
+
+/// Send error for encrypted channels
+#[derive(Debug)]
+pub enum SendError<E> {
+    /// The message could not be serialized
+    Serialize(bincode::Error),
+    /// The message could not be encrypted
+    Encrypt,
+    /// The inner sink failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for SendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for SendError<E> {}
+
+/// Receive error for encrypted channels
+#[derive(Debug)]
+pub enum RecvError<E> {
+    /// The first message of the channel has no salt, or a later one has
+    Salt,
+    /// The message is encrypted with the key of an epoch that is neither the
+    /// current nor the next one
+    Epoch(u32),
+    /// The message was encrypted with a different key, or was modified,
+    /// replayed or reordered
+    Decrypt,
+    /// The message could not be deserialized
+    Deserialize(bincode::Error),
+    /// The inner stream failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for RecvError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for RecvError<E> {}
@@ -0,0 +1,391 @@
+//! Different methods of a service on different listeners
+//!
+//! A server often has listeners with different audiences, e.g. a unix socket
+//! for operators and a quinn endpoint for the public. Instead of splitting the
+//! service into two, every listener can be wrapped in an
+//! [ExposedServerEndpoint] with the [Exposure] of the methods it serves, and
+//! all of them served by one server loop:
+//!
+//! ```ignore
+//! let public = Exposure::<ComputeService>::none().with::<Sqr>().with::<Fibonacci>();
+//! let endpoint = MultiServerEndpoint::new()
+//!     .with_endpoint(uds)
+//!     .with_endpoint(ExposedServerEndpoint::new(quinn, public));
+//! let server = RpcServer::<ComputeService, _>::new(endpoint);
+//! ```
+//!
+//! The endpoint reads the first message of every call, and closes the channel
+//! of calls to methods it does not expose before the server sees them, so the
+//! client sees the call end early. Methods are matched by the name of the enum
+//! variant of the request, which is the name of the message for the enums of
+//! `rpc_service!`, and by convention for hand written enums.
+use super::{Capabilities, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint};
+use crate::{message::Msg, telemetry::method_name, Service};
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use pin_project::pin_project;
+use serde::{ser, Serialize};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The methods of `S` that a listener serves
+pub struct Exposure<S> {
+    /// Whether methods that are not listed are served
+    default: bool,
+    /// Methods that are listed, by message name
+    methods: HashMap<&'static str, bool>,
+    _p: PhantomData<S>,
+}
+
+impl<S: Service> Exposure<S> {
+    /// Serve all methods, except the ones removed with [Exposure::without]
+    pub fn all() -> Self {
+        Self {
+            default: true,
+            methods: HashMap::new(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Serve no methods, except the ones added with [Exposure::with]
+    pub fn none() -> Self {
+        Self {
+            default: false,
+            methods: HashMap::new(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Also serve calls that start with `M`
+    pub fn with<M: Msg<S>>(mut self) -> Self {
+        self.methods.insert(method_name::<M>(), true);
+        self
+    }
+
+    /// Do not serve calls that start with `M`
+    pub fn without<M: Msg<S>>(mut self) -> Self {
+        self.methods.insert(method_name::<M>(), false);
+        self
+    }
+
+    /// Whether a call that starts with `req` is served
+    ///
+    /// Requests that are not an enum are only served if all methods are.
+    pub fn allows(&self, req: &S::Req) -> bool {
+        variant(req)
+            .and_then(|name| self.methods.get(name).copied())
+            .unwrap_or(self.default)
+    }
+}
+
+impl<S> Clone for Exposure<S> {
+    fn clone(&self) -> Self {
+        Self {
+            default: self.default,
+            methods: self.methods.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<S> fmt::Debug for Exposure<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Exposure")
+            .field("default", &self.default)
+            .field("methods", &self.methods)
+            .finish()
+    }
+}
+
+/// A server endpoint that only serves the methods of an [Exposure]
+pub struct ExposedServerEndpoint<E, S> {
+    inner: E,
+    exposure: Arc<Exposure<S>>,
+}
+
+impl<S, E> ExposedServerEndpoint<E, S>
+where
+    S: Service,
+    E: ServerEndpoint<S::Req, S::Res>,
+{
+    /// Wrap a server endpoint, serving the methods of `exposure`
+    pub fn new(inner: E, exposure: Exposure<S>) -> Self {
+        Self {
+            inner,
+            exposure: Arc::new(exposure),
+        }
+    }
+
+    /// The methods this endpoint serves
+    pub fn exposure(&self) -> &Exposure<S> {
+        &self.exposure
+    }
+
+    /// Get back the inner endpoint
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Clone, S> Clone for ExposedServerEndpoint<E, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            exposure: self.exposure.clone(),
+        }
+    }
+}
+
+impl<E: fmt::Debug, S> fmt::Debug for ExposedServerEndpoint<E, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExposedServerEndpoint")
+            .field("inner", &self.inner)
+            .field("exposure", &self.exposure)
+            .finish()
+    }
+}
+
+impl<S, E> ConnectionErrors for ExposedServerEndpoint<E, S>
+where
+    S: Service,
+    E: ConnectionErrors,
+{
+    type OpenError = E::OpenError;
+    type SendError = E::SendError;
+    type RecvError = E::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        E::retry_after(error)
+    }
+}
+
+impl<S, E> ConnectionCommon<S::Req, S::Res> for ExposedServerEndpoint<E, S>
+where
+    S: Service,
+    E: ServerEndpoint<S::Req, S::Res>,
+{
+    type SendSink = E::SendSink;
+    type RecvStream = self::RecvStream<E, S>;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+
+    fn compressed(&self) -> bool {
+        self.inner.compressed()
+    }
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<std::net::SocketAddr> {
+        E::peer_addr(&recv.inner)
+    }
+
+    fn extensions(recv: &Self::RecvStream) -> Option<crate::extensions::Extensions> {
+        E::extensions(&recv.inner)
+    }
+}
+
+impl<S, E> ServerEndpoint<S::Req, S::Res> for ExposedServerEndpoint<E, S>
+where
+    S: Service,
+    E: ServerEndpoint<S::Req, S::Res>,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let exposure = self.exposure.clone();
+        async move {
+            loop {
+                let (send, mut recv) = inner.accept_bi().await?;
+                let first = match recv.next().await {
+                    Some(Ok(first)) => first,
+                    Some(Err(cause)) => {
+                        tracing::debug!("error reading first message: {}", cause);
+                        continue;
+                    }
+                    None => {
+                        tracing::debug!("substream closed before the first message");
+                        continue;
+                    }
+                };
+                if !exposure.allows(&first) {
+                    tracing::debug!("rejected request {:?}, not exposed here", first);
+                    continue;
+                }
+                let recv = RecvStream {
+                    first: Some(first),
+                    inner: recv,
+                };
+                return Ok((send, recv));
+            }
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Receive stream for exposed server endpoints
+///
+/// Yields the first message, which was already read to check it, followed by
+/// the remaining messages.
+#[pin_project]
+pub struct RecvStream<E: ConnectionCommon<S::Req, S::Res>, S: Service> {
+    first: Option<S::Req>,
+    #[pin]
+    inner: E::RecvStream,
+}
+
+impl<E: ConnectionCommon<S::Req, S::Res>, S: Service> fmt::Debug for RecvStream<E, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<E: ConnectionCommon<S::Req, S::Res>, S: Service> Stream for RecvStream<E, S> {
+    type Item = result::Result<S::Req, E::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(first) = this.first.take() {
+            return Poll::Ready(Some(Ok(first)));
+        }
+        this.inner.poll_next(cx)
+    }
+}
+
+/// The name of the enum variant of `value`, without serializing its content
+pub(crate) fn variant<T: Serialize>(value: &T) -> Option<&'static str> {
+    match value.serialize(Probe) {
+        Err(ProbeError(name)) => name,
+        Ok(()) => None,
+    }
+}
+
+/// A serializer that only finds out the variant name of an enum
+struct Probe;
+
+#[derive(Debug)]
+struct ProbeError(Option<&'static str>);
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for ProbeError {}
+
+impl ser::Error for ProbeError {
+    fn custom<M: fmt::Display>(_msg: M) -> Self {
+        ProbeError(None)
+    }
+}
+
+type Impossible = ser::Impossible<(), ProbeError>;
+
+/// Serializing anything but an enum finds no variant
+macro_rules! not_an_enum {
+    ($($method:ident($($ty:ty),*) -> $ok:ty;)*) => {
+        $(
+            fn $method(self, $(_: $ty),*) -> result::Result<$ok, ProbeError> {
+                Err(ProbeError(None))
+            }
+        )*
+    };
+}
+
+impl ser::Serializer for Probe {
+    type Ok = ();
+    type Error = ProbeError;
+    type SerializeSeq = Impossible;
+    type SerializeTuple = Impossible;
+    type SerializeTupleStruct = Impossible;
+    type SerializeTupleVariant = Impossible;
+    type SerializeMap = Impossible;
+    type SerializeStruct = Impossible;
+    type SerializeStructVariant = Impossible;
+
+    not_an_enum! {
+        serialize_bool(bool) -> ();
+        serialize_i8(i8) -> ();
+        serialize_i16(i16) -> ();
+        serialize_i32(i32) -> ();
+        serialize_i64(i64) -> ();
+        serialize_u8(u8) -> ();
+        serialize_u16(u16) -> ();
+        serialize_u32(u32) -> ();
+        serialize_u64(u64) -> ();
+        serialize_f32(f32) -> ();
+        serialize_f64(f64) -> ();
+        serialize_char(char) -> ();
+        serialize_str(&str) -> ();
+        serialize_bytes(&[u8]) -> ();
+        serialize_none() -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(&'static str) -> ();
+        serialize_seq(Option<usize>) -> Impossible;
+        serialize_tuple(usize) -> Impossible;
+        serialize_tuple_struct(&'static str, usize) -> Impossible;
+        serialize_map(Option<usize>) -> Impossible;
+        serialize_struct(&'static str, usize) -> Impossible;
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> result::Result<(), ProbeError> {
+        Err(ProbeError(None))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _value: &T,
+    ) -> result::Result<(), ProbeError> {
+        Err(ProbeError(None))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> result::Result<(), ProbeError> {
+        Err(ProbeError(Some(variant)))
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _value: &T,
+    ) -> result::Result<(), ProbeError> {
+        Err(ProbeError(Some(variant)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> result::Result<Impossible, ProbeError> {
+        Err(ProbeError(Some(variant)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> result::Result<Impossible, ProbeError> {
+        Err(ProbeError(Some(variant)))
+    }
+}
@@ -0,0 +1,511 @@
+//! Transport wrappers that share the bandwidth of a connection between services by weight
+//!
+//! Services that share a connection, e.g. several [QuinnConnection](crate::transport::quinn::QuinnConnection)s
+//! created from one quinn connection, compete for the same bandwidth. Without
+//! scheduling, a service that sends large messages, like file transfers, can
+//! delay the messages of a control service for as long as the transfer takes.
+//!
+//! A [FairShare] is the bandwidth of the connection. Every [FairConnection] and
+//! [FairServerEndpoint] created with it is a class with a weight, and messages
+//! are sent in the order of weighted fair queuing: while several classes have
+//! messages waiting, each of them gets a part of the bandwidth proportional to
+//! its weight. Bandwidth that a class does not use goes to the others, so a
+//! class that is alone gets all of it.
+//!
+//! ```ignore
+//! let share = FairShare::new(FairConfig { rate: 10_000_000, ..Default::default() });
+//! let control = FairConnection::new(QuinnConnection::from_connection(conn.clone()), &share, 8);
+//! let files = FairConnection::new(QuinnConnection::from_connection(conn), &share, 1);
+//! ```
+//!
+//! The size of a message is the size of its bincode encoding. Since the
+//! scheduler can not see the actual capacity of the path, [FairConfig::rate]
+//! should be set to a little below it, otherwise the messages are not queued
+//! here but in the buffers of the transport, where they are not scheduled.
+//! Only the send path is scheduled, so both sides of a connection need a
+//! [FairShare] if both send a lot.
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Sink, TryFutureExt};
+use pin_project::pin_project;
+use serde::Serialize;
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+
+/// Configuration for a [FairShare]
+#[derive(Debug, Clone)]
+pub struct FairConfig {
+    /// The bandwidth that is shared, in bytes per second
+    pub rate: u64,
+    /// The number of bytes that can be sent at once after the connection was idle
+    pub burst: u64,
+}
+
+impl Default for FairConfig {
+    fn default() -> Self {
+        Self {
+            rate: 12_500_000,
+            burst: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    ticket: u64,
+    start: f64,
+    finish: f64,
+    cost: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Bytes that can be sent right away, negative after a message larger than the burst
+    tokens: f64,
+    refilled: Instant,
+    /// The start tag of the message that was sent last
+    virtual_time: f64,
+    /// The finish tag of the last message of every class
+    finish: Vec<f64>,
+    waiters: Vec<Waiter>,
+    next_ticket: u64,
+}
+
+#[derive(Debug)]
+struct Scheduler {
+    config: FairConfig,
+    state: Mutex<State>,
+    /// Notified whenever the first waiter may have changed
+    changed: Notify,
+}
+
+impl Scheduler {
+    fn rate(&self) -> f64 {
+        self.config.rate.max(1) as f64
+    }
+
+    fn refill(&self, state: &mut State) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate()).min(self.config.burst as f64);
+        state.refilled = now;
+    }
+
+    /// The tokens needed to send a message, messages larger than the burst go
+    /// into debt
+    fn needed(&self, cost: u64) -> f64 {
+        cost.min(self.config.burst) as f64
+    }
+
+    fn register(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let virtual_time = state.virtual_time;
+        state.finish.push(virtual_time);
+        state.finish.len() - 1
+    }
+
+    /// Wait until a message of `cost` bytes of `class` can be sent
+    async fn acquire(self: Arc<Self>, class: usize, weight: u32, cost: u64) {
+        let ticket = {
+            let mut state = self.state.lock().unwrap();
+            self.refill(&mut state);
+            let start = state.virtual_time.max(state.finish[class]);
+            let finish = start + cost as f64 / f64::from(weight.max(1));
+            state.finish[class] = finish;
+            if state.waiters.is_empty() && state.tokens >= self.needed(cost) {
+                state.tokens -= cost as f64;
+                state.virtual_time = start;
+                return;
+            }
+            let ticket = state.next_ticket;
+            state.next_ticket += 1;
+            state.waiters.push(Waiter {
+                ticket,
+                start,
+                finish,
+                cost,
+            });
+            ticket
+        };
+        let mut queued = Queued {
+            scheduler: &self,
+            ticket: Some(ticket),
+        };
+        loop {
+            let changed = self.changed.notified();
+            let delay = {
+                let mut state = self.state.lock().unwrap();
+                self.refill(&mut state);
+                let (index, first) = state
+                    .waiters
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| {
+                        a.finish.total_cmp(&b.finish).then(a.ticket.cmp(&b.ticket))
+                    })
+                    .expect("the waiter is queued");
+                if first.ticket != ticket {
+                    None
+                } else if state.tokens >= self.needed(first.cost) {
+                    let waiter = state.waiters.swap_remove(index);
+                    state.tokens -= waiter.cost as f64;
+                    state.virtual_time = waiter.start;
+                    drop(state);
+                    queued.ticket = None;
+                    self.changed.notify_waiters();
+                    return;
+                } else {
+                    let missing = self.needed(first.cost) - state.tokens;
+                    Some(Duration::from_secs_f64(missing / self.rate()))
+                }
+            };
+            match delay {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => changed.await,
+            }
+        }
+    }
+}
+
+/// Removes a waiter that gave up waiting
+struct Queued<'a> {
+    scheduler: &'a Scheduler,
+    ticket: Option<u64>,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket {
+            let mut state = self.scheduler.state.lock().unwrap();
+            state.waiters.retain(|waiter| waiter.ticket != ticket);
+            drop(state);
+            self.scheduler.changed.notify_waiters();
+        }
+    }
+}
+
+/// The bandwidth of a connection, shared by the classes created with it
+///
+/// Clones share the bandwidth.
+#[derive(Debug, Clone)]
+pub struct FairShare(Arc<Scheduler>);
+
+impl FairShare {
+    /// Create a new share of the given bandwidth
+    pub fn new(config: FairConfig) -> Self {
+        let tokens = config.burst as f64;
+        Self(Arc::new(Scheduler {
+            config,
+            state: Mutex::new(State {
+                tokens,
+                refilled: Instant::now(),
+                virtual_time: 0.0,
+                finish: Vec::new(),
+                waiters: Vec::new(),
+                next_ticket: 0,
+            }),
+            changed: Notify::new(),
+        }))
+    }
+
+    /// The configuration of this share
+    pub fn config(&self) -> &FairConfig {
+        &self.0.config
+    }
+
+    fn class(&self, weight: u32) -> Class {
+        Class {
+            scheduler: self.0.clone(),
+            id: self.0.register(),
+            weight: weight.max(1),
+        }
+    }
+}
+
+/// A class of messages of a [FairShare]
+#[derive(Debug, Clone)]
+struct Class {
+    scheduler: Arc<Scheduler>,
+    id: usize,
+    weight: u32,
+}
+
+/// A connection whose messages are sent with a weight of a [FairShare]
+///
+/// All clones are the same class, with the same weight.
+pub struct FairConnection<C, In, Out> {
+    inner: C,
+    class: Class,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> FairConnection<C, In, Out> {
+    /// Wrap a connection as a new class of `share`
+    ///
+    /// A weight of 0 counts as 1.
+    pub fn new(inner: C, share: &FairShare, weight: u32) -> Self {
+        Self {
+            inner,
+            class: share.class(weight),
+            _p: PhantomData,
+        }
+    }
+
+    /// The weight of the messages sent on this connection
+    pub fn weight(&self) -> u32 {
+        self.class.weight
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for FairConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            class: self.class.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for FairConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FairConnection")
+            .field("inner", &self.inner)
+            .field("weight", &self.class.weight)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for FairConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for FairConnection<C, In, Out>
+{
+    type SendSink = self::SendSink<C::SendSink, Out>;
+    type RecvStream = C::RecvStream;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+/// Future returned by open_bi and accept_bi
+pub type OpenBiFuture<C, In, Out> = BoxFuture<
+    'static,
+    result::Result<
+        (
+            SendSink<<C as ConnectionCommon<In, Out>>::SendSink, Out>,
+            <C as ConnectionCommon<In, Out>>::RecvStream,
+        ),
+        <C as ConnectionErrors>::OpenError,
+    >,
+>;
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for FairConnection<C, In, Out>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let class = self.class.clone();
+        self.inner
+            .open_bi()
+            .map_ok(move |(send, recv)| (SendSink::new(send, class), recv))
+            .boxed()
+    }
+}
+
+/// A server endpoint whose responses are sent with a weight of a [FairShare]
+pub struct FairServerEndpoint<E, In, Out> {
+    inner: E,
+    class: Class,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<E: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> FairServerEndpoint<E, In, Out> {
+    /// Wrap a server endpoint as a new class of `share`
+    ///
+    /// A weight of 0 counts as 1.
+    pub fn new(inner: E, share: &FairShare, weight: u32) -> Self {
+        Self {
+            inner,
+            class: share.class(weight),
+            _p: PhantomData,
+        }
+    }
+
+    /// The weight of the messages sent on this endpoint
+    pub fn weight(&self) -> u32 {
+        self.class.weight
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Clone, In, Out> Clone for FairServerEndpoint<E, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            class: self.class.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<E: fmt::Debug, In, Out> fmt::Debug for FairServerEndpoint<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FairServerEndpoint")
+            .field("inner", &self.inner)
+            .field("weight", &self.class.weight)
+            .finish()
+    }
+}
+
+impl<E: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for FairServerEndpoint<E, In, Out>
+{
+    type OpenError = E::OpenError;
+    type SendError = E::SendError;
+    type RecvError = E::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        E::retry_after(error)
+    }
+}
+
+impl<E: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for FairServerEndpoint<E, In, Out>
+{
+    type SendSink = self::SendSink<E::SendSink, Out>;
+    type RecvStream = E::RecvStream;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<crate::extensions::Extensions> {
+        E::extensions(recv)
+    }
+}
+
+impl<E: ServerEndpoint<In, Out>, In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out>
+    for FairServerEndpoint<E, In, Out>
+{
+    type AcceptBiFut = OpenBiFuture<E, In, Out>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let class = self.class.clone();
+        self.inner
+            .accept_bi()
+            .map_ok(move |(send, recv)| (SendSink::new(send, class), recv))
+            .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Send sink that waits for the turn of its class before passing a message on
+#[pin_project]
+pub struct SendSink<T, Out> {
+    #[pin]
+    inner: T,
+    class: Class,
+    /// A message that waits for its turn
+    queued: Option<(Out, BoxFuture<'static, ()>)>,
+}
+
+impl<T, Out> fmt::Debug for SendSink<T, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("weight", &self.class.weight)
+            .field("queued", &self.queued.is_some())
+            .finish()
+    }
+}
+
+impl<T, Out> SendSink<T, Out> {
+    fn new(inner: T, class: Class) -> Self {
+        Self {
+            inner,
+            class,
+            queued: None,
+        }
+    }
+
+    /// Get the underlying sink of the wrapped connection
+    ///
+    /// A message that is still waiting for its turn is dropped.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Sink<Out>, Out> SendSink<T, Out> {
+    /// Pass the queued message on once it is its turn
+    fn poll_queued(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        let mut this = self.project();
+        if let Some((_, turn)) = this.queued.as_mut() {
+            futures::ready!(turn.poll_unpin(cx));
+            futures::ready!(this.inner.as_mut().poll_ready(cx))?;
+            let (item, _) = this.queued.take().expect("checked above");
+            this.inner.start_send(item)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Sink<Out>, Out: Serialize> Sink<Out> for SendSink<T, Out> {
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_queued(cx))?;
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        let this = self.project();
+        let cost = bincode::serialized_size(&item).unwrap_or_default();
+        let class = &*this.class;
+        let turn = class
+            .scheduler
+            .clone()
+            .acquire(class.id, class.weight, cost)
+            .boxed();
+        *this.queued = Some((item, turn));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_queued(cx))?;
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_queued(cx))?;
+        self.project().inner.poll_close(cx)
+    }
+}
@@ -1,15 +1,32 @@
 //! Memory transport implementation using [flume]
 //!
+//! Connections created with [connection_with_latency] delay every message by a
+//! configurable [Latency], to emulate a network link in benchmarks and tests
+//! while keeping everything in process.
+//!
 //! [flume]: https://docs.rs/flume/
 use crate::{
     transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
     RpcMessage,
 };
 use core::fmt;
-use futures::{Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
-use std::{error, fmt::Display, marker::PhantomData, pin::Pin, result, task::Poll};
+use futures::{Future, FutureExt, Sink, SinkExt, Stream};
+use std::{
+    error,
+    fmt::Display,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
+};
+use tokio::time::Instant;
 
-use super::ConnectionCommon;
+use super::{Capabilities, ConnectionCommon};
 
 /// Error when receiving from a channel
 ///
@@ -23,8 +40,82 @@ impl fmt::Display for RecvError {
     }
 }
 
+/// Simulated latency of a connection created with [connection_with_latency]
+///
+/// Every message is delayed by `delay` plus a random jitter of up to `jitter`.
+/// The jitter is drawn from a generator seeded with `seed`, so runs with the
+/// same seed and the same sequence of calls see the same delays. Messages of a
+/// channel are never reordered, a message is delivered no earlier than the one
+/// sent before it.
+///
+/// Delays use the tokio clock, so tests can run with paused time to avoid
+/// actually waiting.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// The fixed delay of every message
+    pub delay: Duration,
+    /// The maximum additional random delay of a message
+    pub jitter: Duration,
+    /// Seed of the jitter
+    pub seed: u64,
+}
+
+/// Latency shared by all channels of a connection
+#[derive(Debug)]
+struct LatencyModel {
+    latency: Latency,
+    /// Number of channel directions created so far, to seed their jitter
+    streams: AtomicU64,
+}
+
+impl LatencyModel {
+    fn timer(&self) -> Timer {
+        let stream = self.streams.fetch_add(1, Ordering::Relaxed);
+        Timer {
+            delay: self.latency.delay,
+            jitter: self.latency.jitter,
+            state: self.latency.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15),
+            last: None,
+        }
+    }
+}
+
+/// Computes the delivery times of the messages of one channel direction
+#[derive(Debug)]
+struct Timer {
+    delay: Duration,
+    jitter: Duration,
+    state: u64,
+    last: Option<Instant>,
+}
+
+impl Timer {
+    /// splitmix64, which is good enough for jitter and needs no dependency
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn deliver_at(&mut self) -> Instant {
+        let jitter = match self.jitter.as_nanos() as u64 {
+            0 => 0,
+            max => self.next_u64() % (max + 1),
+        };
+        let at = Instant::now() + self.delay + Duration::from_nanos(jitter);
+        let at = self.last.map_or(at, |last| last.max(at));
+        self.last = Some(at);
+        at
+    }
+}
+
+/// A message, with the time at which it is delivered if the connection has latency
+type Timed<T> = (Option<Instant>, T);
+
 /// Sink for memory channels
-pub struct SendSink<T: RpcMessage>(flume::r#async::SendSink<'static, T>);
+pub struct SendSink<T: RpcMessage>(flume::r#async::SendSink<'static, Timed<T>>, Option<Timer>);
 
 impl<T: RpcMessage> fmt::Debug for SendSink<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -45,8 +136,9 @@ impl<T: RpcMessage> Sink<T> for SendSink<T> {
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let at = self.1.as_mut().map(Timer::deliver_at);
         self.0
-            .start_send_unpin(item)
+            .start_send_unpin((at, item))
             .map_err(|_| SendError::ReceiverDropped)
     }
 
@@ -70,7 +162,20 @@ impl<T: RpcMessage> Sink<T> for SendSink<T> {
 }
 
 /// Stream for memory channels
-pub struct RecvStream<T: RpcMessage>(flume::r#async::RecvStream<'static, T>);
+///
+/// This polls a single receive future for all messages, instead of using the
+/// stream of flume, which registers a new waiter with the channel every time
+/// it has to wait. So once a channel is open, receiving does not allocate.
+pub struct RecvStream<T: RpcMessage>(
+    flume::r#async::RecvFut<'static, Timed<T>>,
+    Option<(Pin<Box<tokio::time::Sleep>>, T)>,
+);
+
+impl<T: RpcMessage> RecvStream<T> {
+    fn new(inner: flume::Receiver<Timed<T>>) -> Self {
+        Self(inner.into_recv_async(), None)
+    }
+}
 
 impl<T: RpcMessage> fmt::Debug for RecvStream<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -85,11 +190,22 @@ impl<T: RpcMessage> Stream for RecvStream<T> {
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Option<Self::Item>> {
-        match self.0.poll_next_unpin(cx) {
-            Poll::Ready(Some(v)) => Poll::Ready(Some(Ok(v))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
+        if self.1.is_none() {
+            // the future can be polled again after it is ready, and keeps its waiter
+            match self.0.poll_unpin(cx) {
+                Poll::Ready(Ok((Some(at), v))) if at > Instant::now() => {
+                    let sleep = Box::pin(tokio::time::sleep_until(at));
+                    self.1 = Some((sleep, v));
+                }
+                Poll::Ready(Ok((_, v))) => return Poll::Ready(Some(Ok(v))),
+                Poll::Ready(Err(_)) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
         }
+        let (sleep, _) = self.1.as_mut().unwrap();
+        futures::ready!(sleep.as_mut().poll(cx));
+        let (_, v) = self.1.take().unwrap();
+        Poll::Ready(Some(Ok(v)))
     }
 }
 
@@ -195,6 +311,8 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for FlumeServerEndpoint<In, Out> {
     type SendSink = SendSink<Out>;
     type RecvStream = RecvStream<In>;
+    // channels are in memory, so messages never leave the process
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for FlumeServerEndpoint<In, Out> {
@@ -223,21 +341,23 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for FlumeConnection<In, O
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for FlumeConnection<In, Out> {
     type SendSink = SendSink<Out>;
     type RecvStream = RecvStream<In>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for FlumeConnection<In, Out> {
     type OpenBiFut = OpenBiFuture<In, Out>;
 
     fn open_bi(&self) -> Self::OpenBiFut {
-        let (local_send, remote_recv) = flume::bounded::<Out>(128);
-        let (remote_send, local_recv) = flume::bounded::<In>(128);
+        let (local_send, remote_recv) = flume::bounded::<Timed<Out>>(128);
+        let (remote_send, local_recv) = flume::bounded::<Timed<In>>(128);
+        let timer = || self.latency.as_ref().map(|latency| latency.timer());
         let remote_chan = (
-            SendSink(remote_send.into_sink()),
-            RecvStream(remote_recv.into_stream()),
+            SendSink(remote_send.into_sink(), timer()),
+            RecvStream::new(remote_recv),
         );
         let local_chan = (
-            SendSink(local_send.into_sink()),
-            RecvStream(local_recv.into_stream()),
+            SendSink(local_send.into_sink(), timer()),
+            RecvStream::new(local_recv),
         );
         OpenBiFuture::new(self.sink.clone().into_send_async(remote_chan), local_chan)
     }
@@ -248,12 +368,14 @@ impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for FlumeConnection<In
 /// Created using [connection].
 pub struct FlumeConnection<In: RpcMessage, Out: RpcMessage> {
     sink: flume::Sender<(SendSink<In>, RecvStream<Out>)>,
+    latency: Option<Arc<LatencyModel>>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Clone for FlumeConnection<In, Out> {
     fn clone(&self) -> Self {
         Self {
             sink: self.sink.clone(),
+            latency: self.latency.clone(),
         }
     }
 }
@@ -262,6 +384,7 @@ impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for FlumeConnection<In, Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("FlumeClientChannel")
             .field("sink", &self.sink)
+            .field("latency", &self.latency.as_ref().map(|l| l.latency))
             .finish()
     }
 }
@@ -337,5 +460,26 @@ pub fn connection<Req: RpcMessage, Res: RpcMessage>(
     buffer: usize,
 ) -> (FlumeServerEndpoint<Req, Res>, FlumeConnection<Res, Req>) {
     let (sink, stream) = flume::bounded(buffer);
-    (FlumeServerEndpoint { stream }, FlumeConnection { sink })
+    let connection = FlumeConnection {
+        sink,
+        latency: None,
+    };
+    (FlumeServerEndpoint { stream }, connection)
+}
+
+/// Create a flume server endpoint and a connected flume client channel, where
+/// every message is delayed by `latency`
+///
+/// See [connection] for `buffer`. The latency applies to both requests and
+/// responses, so a call takes at least twice the delay.
+pub fn connection_with_latency<Req: RpcMessage, Res: RpcMessage>(
+    buffer: usize,
+    latency: Latency,
+) -> (FlumeServerEndpoint<Req, Res>, FlumeConnection<Res, Req>) {
+    let (server, mut client) = connection(buffer);
+    client.latency = Some(Arc::new(LatencyModel {
+        latency,
+        streams: AtomicU64::new(0),
+    }));
+    (server, client)
 }
@@ -0,0 +1,486 @@
+//! Channels multiplexed on a single byte stream, shared by the stream transports
+//!
+//! Messages are encoded with bincode and sent as length delimited frames,
+//! tagged with the id of their channel. The first message of a channel opens
+//! it on the server, and closing a send sink sends an end marker, so all
+//! interaction patterns work, including calls without a response.
+//!
+//! All channels share the byte stream, so a large message delays the messages
+//! of all other channels until it is sent. Messages that were received but not
+//! yet read are buffered per channel.
+use crate::{
+    extensions::Extensions,
+    transport::decode::{decode, DecodeError, Direction},
+    RpcMessage,
+};
+use bincode::Options;
+use bytes::Bytes;
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinHandle,
+};
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+/// Maximum length of a frame, including the channel id
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+/// Number of frames that can be queued for writing per connection
+const WRITE_BUFFER: usize = 32;
+
+/// A message of a channel, as sent on the connection
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    /// The id of the channel, unique per connection
+    id: u64,
+    frame: Frame,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    /// The first message of a new channel
+    Open(Vec<u8>),
+    /// A message of an existing channel
+    Data(Vec<u8>),
+    /// The sender of the channel is done
+    End,
+}
+
+/// The options of [bincode::serialize]
+fn options() -> impl Options + Copy {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+fn codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .max_frame_length(MAX_FRAME_LENGTH)
+        .new_codec()
+}
+
+/// Error when opening or accepting a channel
+#[derive(Debug)]
+pub enum OpenError {
+    /// The connection or the listener was closed
+    Closed,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for OpenError {}
+
+/// Error when sending a message
+#[derive(Debug)]
+pub enum SendError {
+    /// The message could not be serialized
+    Serialize(bincode::Error),
+    /// The connection was closed
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for SendError {}
+
+/// Error when receiving a message
+#[derive(Debug)]
+pub enum RecvError {
+    /// The message could not be deserialized
+    Deserialize(DecodeError),
+    /// The connection closed before the other side ended the channel
+    Closed,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for RecvError {}
+
+/// Send side of a channel, queues messages for the writer of the connection
+pub struct SendSink<Out> {
+    sink: flume::r#async::SendSink<'static, Vec<u8>>,
+    id: u64,
+    /// The next message opens the channel
+    open: bool,
+    /// The end marker was queued
+    ended: bool,
+    _p: PhantomData<Out>,
+}
+
+// nothing is pinned, the sink of the writer is unpin
+impl<Out> Unpin for SendSink<Out> {}
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").field("id", &self.id).finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    fn new(writer: flume::Sender<Vec<u8>>, id: u64, open: bool) -> Self {
+        Self {
+            sink: writer.into_sink(),
+            id,
+            open,
+            ended: false,
+            _p: PhantomData,
+        }
+    }
+
+    fn envelope(&self, frame: Frame) -> result::Result<Vec<u8>, bincode::Error> {
+        options().serialize(&Envelope { id: self.id, frame })
+    }
+}
+
+impl<Out: Serialize> Sink<Out> for SendSink<Out> {
+    type Error = SendError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.get_mut()
+            .sink
+            .poll_ready_unpin(cx)
+            .map_err(|_| SendError::Closed)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> result::Result<(), Self::Error> {
+        let this = self.get_mut();
+        let payload = options().serialize(&item).map_err(SendError::Serialize)?;
+        let frame = if this.open {
+            Frame::Open(payload)
+        } else {
+            Frame::Data(payload)
+        };
+        let envelope = this.envelope(frame).map_err(SendError::Serialize)?;
+        this.sink
+            .start_send_unpin(envelope)
+            .map_err(|_| SendError::Closed)?;
+        this.open = false;
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.get_mut()
+            .sink
+            .poll_flush_unpin(cx)
+            .map_err(|_| SendError::Closed)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if !this.ended && !this.open {
+            futures::ready!(this.sink.poll_ready_unpin(cx)).map_err(|_| SendError::Closed)?;
+            let end = this.envelope(Frame::End).map_err(SendError::Serialize)?;
+            this.sink
+                .start_send_unpin(end)
+                .map_err(|_| SendError::Closed)?;
+        }
+        this.ended = true;
+        this.sink
+            .poll_flush_unpin(cx)
+            .map_err(|_| SendError::Closed)
+    }
+}
+
+impl<Out> Drop for SendSink<Out> {
+    fn drop(&mut self) {
+        // a channel that was never opened does not need to be ended
+        if self.ended || self.open {
+            return;
+        }
+        let end = match self.envelope(Frame::End) {
+            Ok(end) => end,
+            Err(_) => return,
+        };
+        let writer = self.sink.sender().clone();
+        if let Err(flume::TrySendError::Full(end)) = writer.try_send(end) {
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                handle.spawn(async move { writer.send_async(end).await.ok() });
+            }
+        }
+    }
+}
+
+/// A payload for a channel, or `None` if the connection closed before the channel ended
+type Delivery = Option<Vec<u8>>;
+
+/// Receive side of a channel
+pub struct RecvStream<In> {
+    inner: flume::r#async::RecvStream<'static, Delivery>,
+    direction: Direction,
+    extensions: Option<Extensions>,
+    _p: PhantomData<In>,
+}
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    fn new(
+        inner: flume::Receiver<Delivery>,
+        direction: Direction,
+        extensions: Option<Extensions>,
+    ) -> Self {
+        Self {
+            inner: inner.into_stream(),
+            direction,
+            extensions,
+            _p: PhantomData,
+        }
+    }
+
+    /// The extensions of the connection the channel was accepted on
+    ///
+    /// This is `None` on the client side.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.extensions.as_ref()
+    }
+}
+
+impl<In: RpcMessage> Stream for RecvStream<In> {
+    type Item = result::Result<In, RecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let direction = self.direction;
+        self.inner.poll_next_unpin(cx).map(|delivery| {
+            delivery.map(|payload| match payload {
+                Some(payload) => {
+                    decode(options(), &payload, direction).map_err(RecvError::Deserialize)
+                }
+                None => Err(RecvError::Closed),
+            })
+        })
+    }
+}
+
+/// Channels of a connection, by id, or `None` once the connection closed
+type Channels = Arc<Mutex<Option<HashMap<u64, flume::Sender<Delivery>>>>>;
+
+/// Write the queued frames to the connection, until all senders are gone
+async fn write_frames<W>(write: W, frames: flume::Receiver<Vec<u8>>)
+where
+    W: AsyncWrite + Send + Unpin + 'static,
+{
+    let mut framed = FramedWrite::new(write, codec());
+    while let Ok(frame) = frames.recv_async().await {
+        if let Err(cause) = framed.send(Bytes::from(frame)).await {
+            tracing::debug!("framed: error writing frame: {}", cause);
+            break;
+        }
+    }
+}
+
+/// The envelopes read from the connection, until it is closed or fails
+fn read_envelopes<R>(read: R) -> impl Stream<Item = Envelope> + Send
+where
+    R: AsyncRead + Send + Unpin + 'static,
+{
+    FramedRead::new(read, codec())
+        .take_while(|frame| {
+            if let Err(cause) = frame {
+                tracing::debug!("framed: error reading frame: {}", cause);
+            }
+            future::ready(frame.is_ok())
+        })
+        .filter_map(|frame| async move {
+            let frame = frame.ok()?;
+            match options().deserialize::<Envelope>(&frame) {
+                Ok(envelope) => Some(envelope),
+                Err(cause) => {
+                    tracing::warn!("framed: dropping malformed frame: {}", cause);
+                    None
+                }
+            }
+        })
+}
+
+/// Forward a message to its channel, removing channels that are done
+fn route(channels: &mut HashMap<u64, flume::Sender<Delivery>>, id: u64, payload: Vec<u8>) {
+    let sent = channels.get(&id).map(|tx| tx.send(Some(payload)).is_ok());
+    if sent == Some(false) {
+        channels.remove(&id);
+    }
+}
+
+/// Tell the channels that were not ended that the connection closed
+fn interrupt(channels: HashMap<u64, flume::Sender<Delivery>>) {
+    for tx in channels.into_values() {
+        tx.send(None).ok();
+    }
+}
+
+/// The client side of a connection
+pub(crate) struct Client {
+    writer: flume::Sender<Vec<u8>>,
+    next_id: AtomicU64,
+    channels: Channels,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Client {
+    /// Open channels on the connection with the read and write half `read` and `write`
+    ///
+    /// Once the connection is closed, opening channels fails and all open
+    /// channels end.
+    pub(crate) fn new<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (writer, frames) = flume::bounded(WRITE_BUFFER);
+        let channels: Channels = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader = tokio::spawn({
+            let channels = channels.clone();
+            async move {
+                let envelopes = read_envelopes(read);
+                tokio::pin!(envelopes);
+                while let Some(envelope) = envelopes.next().await {
+                    let mut channels = channels.lock().unwrap();
+                    let channels = channels.as_mut().expect("only cleared by the reader");
+                    match envelope.frame {
+                        Frame::Data(payload) => route(channels, envelope.id, payload),
+                        Frame::End => {
+                            channels.remove(&envelope.id);
+                        }
+                        Frame::Open(_) => {
+                            tracing::warn!("framed: dropping response that opens a channel");
+                        }
+                    }
+                }
+                // the connection closed, so all calls end
+                let open = channels.lock().unwrap().take();
+                interrupt(open.expect("only cleared by the reader"));
+            }
+        });
+        let writer_task = tokio::spawn(write_frames(write, frames));
+        Self {
+            writer,
+            next_id: AtomicU64::new(0),
+            channels,
+            tasks: [reader, writer_task],
+        }
+    }
+
+    /// Open a new channel
+    pub(crate) fn open_bi<In, Out>(
+        &self,
+    ) -> result::Result<(SendSink<Out>, RecvStream<In>), OpenError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = flume::unbounded();
+        // register the channel before anything is sent, so no response is missed
+        match self.channels.lock().unwrap().as_mut() {
+            Some(channels) => channels.insert(id, tx),
+            None => return Err(OpenError::Closed),
+        };
+        let send = SendSink::new(self.writer.clone(), id, true);
+        let recv = RecvStream::new(rx, Direction::Response, None);
+        Ok((send, recv))
+    }
+}
+
+/// A channel accepted on one of the connections of a server
+///
+/// `I` is what the server knows about the connection.
+pub(crate) struct Accepted<I> {
+    writer: flume::Sender<Vec<u8>>,
+    id: u64,
+    rx: flume::Receiver<Delivery>,
+    extensions: Extensions,
+    info: I,
+}
+
+impl<I> Accepted<I> {
+    /// The send and receive side of the channel, and the info of its connection
+    pub(crate) fn into_parts<In, Out>(self) -> (SendSink<Out>, RecvStream<In>, I) {
+        let send = SendSink::new(self.writer, self.id, false);
+        let recv = RecvStream::new(self.rx, Direction::Request, Some(self.extensions));
+        (send, recv, self.info)
+    }
+}
+
+/// Routes the messages of an accepted connection to its channels, until it closes
+///
+/// New channels are sent to `accept`, together with a clone of `info`.
+pub(crate) async fn serve<R, W, I>(read: R, write: W, info: I, accept: flume::Sender<Accepted<I>>)
+where
+    R: AsyncRead + Send + Unpin + 'static,
+    W: AsyncWrite + Send + Unpin + 'static,
+    I: Clone + Send + 'static,
+{
+    let (writer, frames) = flume::bounded(WRITE_BUFFER);
+    // ends once the reader and all send sinks of the connection are dropped
+    tokio::spawn(write_frames(write, frames));
+    let mut channels = HashMap::<u64, flume::Sender<Delivery>>::new();
+    let extensions = Extensions::new();
+    let envelopes = read_envelopes(read);
+    tokio::pin!(envelopes);
+    while let Some(envelope) = envelopes.next().await {
+        match envelope.frame {
+            Frame::Open(payload) => {
+                let (tx, rx) = flume::unbounded();
+                tx.send(Some(payload)).ok();
+                channels.insert(envelope.id, tx);
+                let accepted = Accepted {
+                    writer: writer.clone(),
+                    id: envelope.id,
+                    rx,
+                    extensions: extensions.clone(),
+                    info: info.clone(),
+                };
+                if accept.send_async(accepted).await.is_err() {
+                    // all server endpoints were dropped
+                    break;
+                }
+            }
+            Frame::Data(payload) => route(&mut channels, envelope.id, payload),
+            Frame::End => {
+                channels.remove(&envelope.id);
+            }
+        }
+    }
+    interrupt(channels);
+}
@@ -0,0 +1,682 @@
+//! HTTP/1.1 long-poll transport using [hyper], for networks that let nothing else through
+//!
+//! Every substream is a session on the server. The client opens it with a `POST`
+//! without a query, which the server answers with the id of the new session. The
+//! client then sends its messages as the chunked bodies of `POST ?session=<id>`
+//! requests, and polls for the messages of the server with `GET ?session=<id>`,
+//! which the server holds until it has messages or the poll timeout elapsed. Both
+//! bodies use the framing of the [hyper](super::hyper) transport for the codec of
+//! the [LongPollConfig], and the send sinks and receive streams are the ones of
+//! that transport.
+//!
+//! The client ends its side of a substream with `POST ?session=<id>&fin`, the
+//! server ends its side by answering a poll with `204 No Content`. The server
+//! drops sessions whose client has not made a request for the idle timeout.
+//!
+//! This is meant as the last fallback of a
+//! [CombinedConnection](super::combined::CombinedConnection), since every message
+//! costs at least one request.
+//!
+//! [hyper]: https://crates.io/crates/hyper/
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    convert::Infallible,
+    error, fmt,
+    hash::{BuildHasher, Hasher},
+    io, iter,
+    marker::PhantomData,
+    net::SocketAddr,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint};
+use crate::RpcMessage;
+use bytes::Bytes;
+use flume::{Receiver, Sender};
+use futures::{future::BoxFuture, stream, FutureExt};
+use hyper::{
+    client::{connect::Connect, HttpConnector},
+    server::conn::{AddrIncoming, AddrStream},
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server, StatusCode, Uri,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, event, Level};
+
+pub use super::hyper::{AcceptBiError, RecvError, RecvStream, SendError, SendSink};
+use super::{
+    decode::Direction,
+    hyper::{forward_body, ChannelConfig, Requester},
+    Capabilities, Codec, ConnectionCommon,
+};
+
+/// Capabilities of the http1 transport
+///
+/// Like the hyper transport, the connection may be plain http.
+const HTTP1_CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    encrypted: false,
+    multiplexed: true,
+};
+
+/// Configuration of the long polls
+#[derive(Debug, Clone)]
+pub struct LongPollConfig {
+    /// How long the server holds a poll when it has no messages
+    ///
+    /// This should be below the timeouts of the proxies in between.
+    pub poll_timeout: Duration,
+    /// How long the server keeps a session whose client made no request
+    ///
+    /// This must be above the poll timeout.
+    pub idle_timeout: Duration,
+    /// The maximum number of messages in a request or response body
+    pub max_batch: usize,
+    /// The codec of the messages, see [ChannelConfig::codec]
+    pub codec: Codec,
+}
+
+impl Default for LongPollConfig {
+    fn default() -> Self {
+        Self {
+            poll_timeout: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(60),
+            max_batch: 64,
+            codec: Codec::default(),
+        }
+    }
+}
+
+/// A pair of channels to send and receive messages on a single stream.
+type Socket<In, Out> = (self::SendSink<Out>, self::RecvStream<In>);
+
+/// A flume sender and receiver tuple.
+type InternalChannel<In> = (
+    Receiver<result::Result<In, RecvError>>,
+    Sender<io::Result<Bytes>>,
+);
+
+struct Http1ConnectionInner {
+    client: Box<dyn Requester>,
+    uri: Uri,
+    config: LongPollConfig,
+    channel_config: Arc<ChannelConfig>,
+}
+
+/// HTTP/1.1 long-poll connection to a [Http1ServerEndpoint]
+pub struct Http1Connection<In: RpcMessage, Out: RpcMessage> {
+    inner: Arc<Http1ConnectionInner>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Http1Connection<In, Out> {
+    /// create a client given an uri and the default configuration
+    pub fn new(uri: Uri) -> Self {
+        Self::with_config(uri, LongPollConfig::default())
+    }
+
+    /// create a client given an uri and a custom configuration
+    pub fn with_config(uri: Uri, config: LongPollConfig) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_nodelay(true);
+        Self::with_connector(connector, uri, config)
+    }
+
+    /// create a client given a connector, e.g. for TLS, an uri and a custom configuration
+    pub fn with_connector<C: Connect + Clone + Send + Sync + 'static>(
+        connector: C,
+        uri: Uri,
+        config: LongPollConfig,
+    ) -> Self {
+        let client = Client::builder().build::<_, Body>(connector);
+        let channel_config = ChannelConfig::default().codec(config.codec.clone());
+        Self {
+            inner: Arc::new(Http1ConnectionInner {
+                client: Box::new(client),
+                uri,
+                config,
+                channel_config: Arc::new(channel_config),
+            }),
+            _p: PhantomData,
+        }
+    }
+
+    async fn open(
+        inner: Arc<Http1ConnectionInner>,
+    ) -> result::Result<Socket<In, Out>, OpenBiError> {
+        event!(Level::TRACE, "open_bi {}", inner.uri);
+        let req = Request::post(&inner.uri)
+            .body(Body::empty())
+            .map_err(OpenBiError::HyperHttp)?;
+        let res = inner
+            .client
+            .request(req)
+            .await
+            .map_err(OpenBiError::Hyper)?;
+        if res.status() != StatusCode::OK {
+            return Err(OpenBiError::Status(res.status()));
+        }
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(OpenBiError::Hyper)?;
+        let session = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|id| u64::from_str_radix(id, 16).ok())
+            .ok_or(OpenBiError::InvalidSession)?;
+        let uri = with_query(&inner.uri, &format!("session={session:x}"))
+            .map_err(OpenBiError::HyperHttp)?;
+        let fin_uri = with_query(&inner.uri, &format!("session={session:x}&fin"))
+            .map_err(OpenBiError::HyperHttp)?;
+
+        let (out_tx, out_rx) = flume::bounded::<io::Result<Bytes>>(32);
+        let (in_tx, in_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
+        let send = SendSink::new(out_tx, inner.channel_config.clone());
+        tokio::spawn(send_loop(inner.clone(), uri.clone(), fin_uri, out_rx));
+        tokio::spawn(poll_loop(inner, uri, in_tx));
+        Ok((send, RecvStream::new(in_rx)))
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for Http1Connection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Http1Connection")
+            .field("uri", &self.inner.uri)
+            .field("config", &self.inner.config)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Clone for Http1Connection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+/// `uri` with its query replaced by `query`
+fn with_query(uri: &Uri, query: &str) -> result::Result<Uri, hyper::http::Error> {
+    let mut parts = uri.clone().into_parts();
+    let path_and_query = format!(
+        "{}?{}",
+        parts
+            .path_and_query
+            .as_ref()
+            .map_or("/", |path| path.path()),
+        query
+    );
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+/// Posts the messages of a substream in batches, then ends the client side of it.
+///
+/// Returning early drops `out_rx`, which makes sending on the sink fail.
+async fn send_loop(
+    inner: Arc<Http1ConnectionInner>,
+    uri: Uri,
+    fin_uri: Uri,
+    out_rx: Receiver<io::Result<Bytes>>,
+) {
+    while let Ok(first) = out_rx.recv_async().await {
+        // messages that could not be serialized were already reported by the sink
+        let frames = iter::once(first)
+            .chain(
+                out_rx
+                    .try_iter()
+                    .take(inner.config.max_batch.saturating_sub(1)),
+            )
+            .filter(|frame| frame.is_ok())
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            continue;
+        }
+        if !post(&inner, &uri, Body::wrap_stream(stream::iter(frames))).await {
+            return;
+        }
+    }
+    // the sink was closed or dropped
+    post(&inner, &fin_uri, Body::empty()).await;
+}
+
+/// Posts `body` to `uri`, and returns whether the server took it
+async fn post(inner: &Http1ConnectionInner, uri: &Uri, body: Body) -> bool {
+    let req = Request::post(uri).body(body).expect("valid request");
+    match inner.client.request(req).await {
+        Ok(res) if res.status().is_success() => true,
+        Ok(res) => {
+            debug!("Sending failed with status {}", res.status());
+            false
+        }
+        Err(cause) => {
+            debug!("Network error: {}", cause);
+            false
+        }
+    }
+}
+
+/// Polls for the messages of a substream until the server ends its side, or the
+/// receive stream is dropped.
+async fn poll_loop<In: RpcMessage>(
+    inner: Arc<Http1ConnectionInner>,
+    uri: Uri,
+    in_tx: Sender<result::Result<In, RecvError>>,
+) {
+    while !in_tx.is_disconnected() {
+        let req = Request::get(&uri)
+            .body(Body::empty())
+            .expect("valid request");
+        let res = match inner.client.request(req).await {
+            Ok(res) => res,
+            Err(cause) => {
+                in_tx
+                    .send_async(Err(RecvError::NetworkError(cause)))
+                    .await
+                    .ok();
+                return;
+            }
+        };
+        match res.status() {
+            StatusCode::OK => {
+                if forward_body(
+                    res.into_body(),
+                    &in_tx,
+                    &inner.config.codec,
+                    Direction::Response,
+                )
+                .await
+                .is_err()
+                {
+                    return;
+                }
+            }
+            StatusCode::NO_CONTENT => return,
+            status => {
+                debug!("Polling failed with status {}", status);
+                return;
+            }
+        }
+    }
+}
+
+/// The state of a substream on the server
+struct Session<In: RpcMessage> {
+    /// Sender for the messages of the client, `None` once the client ended its side
+    req_tx: Mutex<Option<Sender<result::Result<In, RecvError>>>>,
+    /// Receiver for the messages of the server
+    res_rx: Receiver<io::Result<Bytes>>,
+    /// When the client made its last request
+    last_seen: Mutex<Instant>,
+}
+
+impl<In: RpcMessage> Session<In> {
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Whether the client ended its side
+    fn client_done(&self) -> bool {
+        self.req_tx.lock().unwrap().is_none()
+    }
+
+    /// Whether the server ended its side, and the client got all of its messages
+    fn server_done(&self) -> bool {
+        self.res_rx.is_disconnected() && self.res_rx.is_empty()
+    }
+
+    /// Waits for messages of the server, `None` once the server ended its side
+    async fn poll(&self, config: &LongPollConfig) -> Option<Vec<Bytes>> {
+        let first = match tokio::time::timeout(config.poll_timeout, self.res_rx.recv_async()).await
+        {
+            Err(_elapsed) => return Some(Vec::new()),
+            Ok(Err(_disconnected)) => return None,
+            Ok(Ok(first)) => first,
+        };
+        // messages that could not be serialized were already reported by the sink
+        let frames = iter::once(first)
+            .chain(
+                self.res_rx
+                    .try_iter()
+                    .take(config.max_batch.saturating_sub(1)),
+            )
+            .filter_map(Result::ok)
+            .collect();
+        Some(frames)
+    }
+}
+
+/// The sessions of a server endpoint
+struct Sessions<In: RpcMessage> {
+    map: Mutex<HashMap<u64, Arc<Session<In>>>>,
+    /// Randomly keyed hasher of `next`, so session ids can not be guessed
+    ids: RandomState,
+    next: AtomicU64,
+    config: LongPollConfig,
+}
+
+impl<In: RpcMessage> Sessions<In> {
+    fn insert(&self, session: Session<In>) -> u64 {
+        let mut map = self.map.lock().unwrap();
+        loop {
+            let mut hasher = self.ids.build_hasher();
+            hasher.write_u64(self.next.fetch_add(1, Ordering::Relaxed));
+            let id = hasher.finish();
+            if let std::collections::hash_map::Entry::Vacant(entry) = map.entry(id) {
+                entry.insert(Arc::new(session));
+                return id;
+            }
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<Session<In>>> {
+        self.map.lock().unwrap().get(&id).cloned()
+    }
+
+    fn remove(&self, id: u64) {
+        self.map.lock().unwrap().remove(&id);
+    }
+
+    /// Drops the sessions whose client made no request for the idle timeout
+    fn reap(&self) {
+        let idle_timeout = self.config.idle_timeout;
+        self.map
+            .lock()
+            .unwrap()
+            .retain(|_, session| session.last_seen.lock().unwrap().elapsed() < idle_timeout);
+    }
+}
+
+/// A server endpoint that accepts HTTP/1.1 long-poll sessions
+///
+/// Each session opened by a client yields a `(send, recv)` pair, like the
+/// [HyperServerEndpoint](super::hyper::HyperServerEndpoint).
+///
+/// Creating this spawns a tokio task which runs the server, once dropped this task is shut
+/// down: no new connections will be accepted and existing channels will stop.
+#[derive(Debug)]
+pub struct Http1ServerEndpoint<In: RpcMessage, Out: RpcMessage> {
+    /// The channel.
+    channel: Receiver<InternalChannel<In>>,
+    /// The configuration of the send sinks.
+    channel_config: Arc<ChannelConfig>,
+    /// The sender to stop the server.
+    ///
+    /// Simply dropping it makes the receiver complete and will shut down the hyper server.
+    stop_tx: mpsc::Sender<()>,
+    /// The local address this server is bound to.
+    local_addr: [LocalAddr; 1],
+    /// Phantom data for in and out
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Http1ServerEndpoint<In, Out> {
+    /// Creates a server listening on the [`SocketAddr`], with the default configuration.
+    pub fn serve(addr: &SocketAddr) -> hyper::Result<Self> {
+        Self::serve_with_config(addr, Default::default())
+    }
+
+    /// Creates a server listening on the [`SocketAddr`] with a custom configuration.
+    pub fn serve_with_config(addr: &SocketAddr, config: LongPollConfig) -> hyper::Result<Self> {
+        let (accept_tx, accept_rx) = flume::bounded(32);
+        let channel_config = ChannelConfig::default().codec(config.codec.clone());
+        let sessions = Arc::new(Sessions {
+            map: Default::default(),
+            ids: RandomState::new(),
+            next: AtomicU64::new(0),
+            config,
+        });
+
+        let reaper = Arc::downgrade(&sessions);
+        let service = make_service_fn(move |socket: &AddrStream| {
+            event!(Level::TRACE, "Connection from {:?}", socket.remote_addr());
+            let accept_tx = accept_tx.clone();
+            let sessions = sessions.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let accept_tx = accept_tx.clone();
+                    let sessions = sessions.clone();
+                    async move { Ok::<_, Infallible>(Self::handle(req, &sessions, accept_tx).await) }
+                }))
+            }
+        });
+
+        let mut incoming = AddrIncoming::bind(addr)?;
+        incoming.set_nodelay(true);
+        let server = Server::builder(incoming).http1_only(true).serve(service);
+        let local_addr = server.local_addr();
+
+        let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
+        let server = server.with_graceful_shutdown(async move {
+            // If the sender is dropped this will also gracefully terminate the server.
+            stop_rx.recv().await;
+        });
+        tokio::spawn(server);
+        tokio::spawn(async move {
+            let period = reaper
+                .upgrade()
+                .map_or(Duration::MAX, |sessions| sessions.config.idle_timeout / 2);
+            let mut interval = tokio::time::interval(period.max(Duration::from_millis(1)));
+            loop {
+                interval.tick().await;
+                // the sessions are dropped once the server has shut down
+                match reaper.upgrade() {
+                    Some(sessions) => sessions.reap(),
+                    None => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            channel: accept_rx,
+            channel_config: Arc::new(channel_config),
+            stop_tx,
+            local_addr: [LocalAddr::Socket(local_addr)],
+            _p: PhantomData,
+        })
+    }
+
+    /// Handles a single request of a client.
+    async fn handle(
+        req: Request<Body>,
+        sessions: &Sessions<In>,
+        accept_tx: Sender<InternalChannel<In>>,
+    ) -> Response<Body> {
+        let mut id = None;
+        let mut fin = false;
+        for param in req.uri().query().unwrap_or_default().split('&') {
+            match param.split_once('=') {
+                Some(("session", value)) => id = Some(u64::from_str_radix(value, 16).ok()),
+                None if param == "fin" => fin = true,
+                _ => {}
+            }
+        }
+        let id = match (req.method(), id) {
+            (&Method::POST, None) => return Self::open(sessions, accept_tx).await,
+            (_, None) => return status(StatusCode::BAD_REQUEST),
+            (_, Some(None)) => return status(StatusCode::NOT_FOUND),
+            (_, Some(Some(id))) => id,
+        };
+        let session = match sessions.get(id) {
+            Some(session) => session,
+            None => return status(StatusCode::NOT_FOUND),
+        };
+        session.touch();
+        match *req.method() {
+            Method::POST => {
+                let req_tx = session.req_tx.lock().unwrap().clone();
+                let req_tx = match req_tx {
+                    Some(req_tx) => req_tx,
+                    None => return status(StatusCode::GONE),
+                };
+                if forward_body(
+                    req.into_body(),
+                    &req_tx,
+                    &sessions.config.codec,
+                    Direction::Request,
+                )
+                .await
+                .is_err()
+                {
+                    // the server dropped the receive stream
+                    return status(StatusCode::GONE);
+                }
+                if fin {
+                    session.req_tx.lock().unwrap().take();
+                    if session.server_done() {
+                        sessions.remove(id);
+                    }
+                }
+                status(StatusCode::NO_CONTENT)
+            }
+            Method::GET => {
+                let frames = session.poll(&sessions.config).await;
+                session.touch();
+                match frames {
+                    Some(frames) => Response::new(Body::wrap_stream(stream::iter(
+                        frames.into_iter().map(Ok::<_, Infallible>),
+                    ))),
+                    None => {
+                        if session.client_done() {
+                            sessions.remove(id);
+                        }
+                        status(StatusCode::NO_CONTENT)
+                    }
+                }
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    /// Opens a session and hands it to [ServerEndpoint::accept_bi].
+    async fn open(
+        sessions: &Sessions<In>,
+        accept_tx: Sender<InternalChannel<In>>,
+    ) -> Response<Body> {
+        let (req_tx, req_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
+        let (res_tx, res_rx) = flume::bounded::<io::Result<Bytes>>(32);
+        if accept_tx.send_async((req_rx, res_tx)).await.is_err() {
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let id = sessions.insert(Session {
+            req_tx: Mutex::new(Some(req_tx)),
+            res_rx,
+            last_seen: Mutex::new(Instant::now()),
+        });
+        Response::new(Body::from(format!("{id:x}")))
+    }
+}
+
+/// An empty response with `status`
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .expect("valid response")
+}
+
+// This does not want or need RpcMessage to be clone but still want to clone the
+// ServerChannel and it's containing channels itself.
+impl<In: RpcMessage, Out: RpcMessage> Clone for Http1ServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            channel: self.channel.clone(),
+            channel_config: self.channel_config.clone(),
+            stop_tx: self.stop_tx.clone(),
+            local_addr: self.local_addr.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+/// OpenBiError for http1 channels.
+#[derive(Debug)]
+pub enum OpenBiError {
+    /// Hyper http error
+    HyperHttp(hyper::http::Error),
+    /// Generic hyper error
+    Hyper(hyper::Error),
+    /// The server did not open a session, e.g. because a proxy could not reach it
+    Status(StatusCode),
+    /// The server answered with something that is not a session id
+    InvalidSession,
+}
+
+impl fmt::Display for OpenBiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl error::Error for OpenBiError {}
+
+/// Future returned by [open_bi](crate::transport::Connection::open_bi).
+pub type OpenBiFuture<In, Out> = BoxFuture<'static, result::Result<Socket<In, Out>, OpenBiError>>;
+
+/// Future returned by [accept_bi](crate::transport::ServerEndpoint::accept_bi).
+pub type AcceptBiFuture<In, Out> =
+    BoxFuture<'static, result::Result<Socket<In, Out>, AcceptBiError>>;
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Http1Connection<In, Out> {
+    type SendError = self::SendError;
+
+    type RecvError = self::RecvError;
+
+    type OpenError = OpenBiError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Http1Connection<In, Out> {
+    type RecvStream = self::RecvStream<In>;
+
+    type SendSink = self::SendSink<Out>;
+    const CAPABILITIES: Capabilities = HTTP1_CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for Http1Connection<In, Out> {
+    type OpenBiFut = OpenBiFuture<In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        Self::open(self.inner.clone()).boxed()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Http1ServerEndpoint<In, Out> {
+    type SendError = self::SendError;
+
+    type RecvError = self::RecvError;
+
+    type OpenError = AcceptBiError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Http1ServerEndpoint<In, Out> {
+    type RecvStream = self::RecvStream<In>;
+    type SendSink = self::SendSink<Out>;
+    const CAPABILITIES: Capabilities = HTTP1_CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for Http1ServerEndpoint<In, Out> {
+    type AcceptBiFut = AcceptBiFuture<In, Out>;
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.local_addr
+    }
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let channel = self.channel.clone();
+        let config = self.channel_config.clone();
+        async move {
+            let (recv, send) = channel
+                .recv_async()
+                .await
+                .map_err(|_| AcceptBiError::RemoteDropped)?;
+            Ok((SendSink::new(send, config), RecvStream::new(recv)))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,617 @@
+//! Transport over HTTP/3 requests, for servers behind HTTP/3 proxies and CDNs
+//!
+//! Proxies that terminate HTTP/3 do not forward QUIC connections with other
+//! application protocols. So every call is made as a `POST` request with the
+//! content type [CONTENT_TYPE], and the messages of the call are the bodies of
+//! the request and the response. The bodies contain the messages framed like
+//! on a native quinn connection, so proxies may split and merge their DATA
+//! frames as they see fit.
+//!
+//! The server answers with status 200 when the call starts, before the
+//! handler sends anything. Requests with another method or content type are
+//! answered with status 405 and 415, and every path is accepted:
+//!
+//! ```ignore
+//! let server = Http3ServerEndpoint::<ComputeRequest, ComputeResponse>::new(endpoint)?;
+//! let connection = endpoint.connect(addr, "localhost")?.await?;
+//! let client = Http3Connection::<ComputeResponse, ComputeRequest>::connect(connection, "localhost", "/rpc").await?;
+//! ```
+//!
+//! Both endpoints must negotiate [H3_ALPN]. HTTP/3 itself is implemented by
+//! the [h3](https://crates.io/crates/h3) crate.
+use crate::{
+    transport::{
+        quinn::MAX_FRAME_LENGTH,
+        util::{FramedBincodeRead, FramedBincodeWrite, Framing},
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, Direction, LocalAddr,
+        ServerEndpoint,
+    },
+    RpcMessage,
+};
+use bytes::{Buf, Bytes};
+use futures::{
+    future::BoxFuture, stream::BoxStream, FutureExt, Sink, SinkExt, Stream, StreamExt, TryFutureExt,
+};
+use http::{header, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::{io::AsyncWrite, task::JoinHandle};
+use tokio_util::io::StreamReader;
+
+mod quic;
+
+#[cfg(doc)]
+use crate::transport::quinn::H3_ALPN;
+
+/// The content type of the requests and responses of calls
+pub const CONTENT_TYPE: &str = "application/x-quic-rpc";
+
+type ClientRequest<S> = h3::client::RequestStream<S, Bytes>;
+type ServerRequest<S> = h3::server::RequestStream<S, Bytes>;
+
+fn h3_error(error: h3::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+/// The sending half of a request, on the client or on the server
+enum SendHalf {
+    Client(ClientRequest<h3_quinn::SendStream<Bytes>>),
+    Server(ServerRequest<h3_quinn::SendStream<Bytes>>),
+}
+
+impl SendHalf {
+    async fn send_data(&mut self, data: Bytes) -> result::Result<(), h3::Error> {
+        match self {
+            Self::Client(send) => send.send_data(data).await,
+            Self::Server(send) => send.send_data(data).await,
+        }
+    }
+
+    async fn finish(&mut self) -> result::Result<(), h3::Error> {
+        match self {
+            Self::Client(send) => send.finish().await,
+            Self::Server(send) => send.finish().await,
+        }
+    }
+}
+
+/// The receiving half of a request, on the client or on the server
+enum RecvHalf {
+    Client(ClientRequest<quic::RecvStream>),
+    Server(ServerRequest<quic::RecvStream>),
+}
+
+impl RecvHalf {
+    async fn recv_data(&mut self) -> result::Result<Option<Bytes>, h3::Error> {
+        fn to_bytes(mut data: impl Buf) -> Bytes {
+            data.copy_to_bytes(data.remaining())
+        }
+        Ok(match self {
+            Self::Client(recv) => recv.recv_data().await?.map(to_bytes),
+            Self::Server(recv) => recv.recv_data().await?.map(to_bytes),
+        })
+    }
+}
+
+/// The payloads of the DATA frames of a request or response body
+fn body(recv: RecvHalf) -> BoxStream<'static, io::Result<Bytes>> {
+    futures::stream::try_unfold(recv, |mut recv| async move {
+        let data = recv.recv_data().await.map_err(h3_error)?;
+        Ok(data.map(|data| (data, recv)))
+    })
+    .boxed()
+}
+
+type BodyRead = StreamReader<BoxStream<'static, io::Result<Bytes>>, Bytes>;
+
+type Pending = BoxFuture<'static, (Box<SendHalf>, result::Result<(), h3::Error>)>;
+
+enum WriteState {
+    Ready(Box<SendHalf>),
+    /// Sending a DATA frame that was already accepted
+    Sending(Pending),
+    Finishing(Pending),
+    Finished,
+}
+
+/// Writes everything as DATA frames of a request or response body
+struct BodyWrite(WriteState);
+
+impl BodyWrite {
+    /// Wait until the DATA frame that is being sent, if any, is sent
+    fn poll_sent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let WriteState::Sending(pending) = &mut self.0 {
+            let (send, res) = futures::ready!(pending.poll_unpin(cx));
+            self.0 = WriteState::Ready(send);
+            res.map_err(h3_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for BodyWrite {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_sent(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut send = match mem::replace(&mut this.0, WriteState::Finished) {
+            WriteState::Ready(send) => send,
+            state => {
+                this.0 = state;
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+        };
+        let data = Bytes::copy_from_slice(buf);
+        this.0 = WriteState::Sending(
+            async move {
+                let res = send.send_data(data).await;
+                (send, res)
+            }
+            .boxed(),
+        );
+        // the frame is accepted as a whole, and sent by the next calls if
+        // the stream is blocked
+        if let Poll::Ready(Err(cause)) = this.poll_sent(cx) {
+            return Poll::Ready(Err(cause));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_sent(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_sent(cx))?;
+        loop {
+            match mem::replace(&mut this.0, WriteState::Finished) {
+                WriteState::Ready(mut send) => {
+                    this.0 = WriteState::Finishing(
+                        async move {
+                            let res = send.finish().await;
+                            (send, res)
+                        }
+                        .boxed(),
+                    );
+                }
+                WriteState::Finishing(mut pending) => {
+                    return match pending.poll_unpin(cx) {
+                        Poll::Ready((_, res)) => Poll::Ready(res.map_err(h3_error)),
+                        Poll::Pending => {
+                            this.0 = WriteState::Finishing(pending);
+                            Poll::Pending
+                        }
+                    };
+                }
+                WriteState::Sending(_) => unreachable!("waited for above"),
+                WriteState::Finished => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// A sink that sends messages as the body of an HTTP/3 request or response
+#[pin_project]
+pub struct SendSink<Out>(#[pin] FramedBincodeWrite<BodyWrite, Out>);
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<Out: Serialize> SendSink<Out> {
+    fn new(send: SendHalf, framing: Framing) -> Self {
+        Self(FramedBincodeWrite::new(
+            BodyWrite(WriteState::Ready(Box::new(send))),
+            framing,
+        ))
+    }
+}
+
+impl<Out: Serialize + Send + 'static> Sink<Out> for SendSink<Out> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        self.project().0.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().0.poll_close_unpin(cx)
+    }
+}
+
+/// A stream of the messages in the body of an HTTP/3 request or response
+///
+/// On the client side, a response with a status other than 200 is reported as
+/// an error of kind [io::ErrorKind::ConnectionRefused].
+#[pin_project]
+pub struct RecvStream<In>(#[pin] FramedBincodeRead<BodyRead, In>);
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<In: DeserializeOwned> RecvStream<In> {
+    fn new(
+        body: BoxStream<'static, io::Result<Bytes>>,
+        framing: Framing,
+        direction: Direction,
+    ) -> Self {
+        Self(FramedBincodeRead::new(
+            StreamReader::new(body),
+            framing,
+            direction,
+        ))
+    }
+}
+
+impl<In: DeserializeOwned + Send + 'static> Stream for RecvStream<In> {
+    type Item = result::Result<In, io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_next_unpin(cx)
+    }
+}
+
+/// Wait for the response to a call
+async fn read_response(recv: &mut ClientRequest<quic::RecvStream>) -> io::Result<()> {
+    let response = recv.recv_response().await.map_err(h3_error)?;
+    if response.status() != StatusCode::OK {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!(
+                "the server refused the call with status {}",
+                response.status().as_u16()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The HTTP/3 connection of a client, and where its requests go
+struct ClientInner {
+    // only cloned, but it is not Sync
+    requests: Mutex<h3::client::SendRequest<quic::OpenStreams, Bytes>>,
+    remote_address: SocketAddr,
+    uri: Uri,
+    /// Drives the connection
+    task: JoinHandle<()>,
+}
+
+impl Drop for ClientInner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A connection that makes every call as an HTTP/3 request
+///
+/// All clones share the HTTP/3 connection, which stays open until the last
+/// clone is dropped.
+pub struct Http3Connection<In, Out> {
+    inner: Arc<ClientInner>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Http3Connection<In, Out> {
+    /// Make calls on an established HTTP/3 connection
+    ///
+    /// The connection must have negotiated [H3_ALPN]. `authority` and `path`
+    /// are sent with every request, e.g. for a proxy to route the calls.
+    pub async fn connect(
+        connection: quinn::Connection,
+        authority: &str,
+        path: &str,
+    ) -> io::Result<Self> {
+        let uri = Uri::builder()
+            .scheme("https")
+            .authority(authority)
+            .path_and_query(path)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let remote_address = connection.remote_address();
+        let (mut driver, requests) = h3::client::new(quic::Connection::new(connection))
+            .await
+            .map_err(h3_error)?;
+        let task = tokio::spawn(async move {
+            if let Err(e) = futures::future::poll_fn(|cx| driver.poll_close(cx)).await {
+                tracing::debug!("HTTP/3 connection to {} closed: {}", remote_address, e);
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ClientInner {
+                requests: Mutex::new(requests),
+                remote_address,
+                uri,
+                task,
+            }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In, Out> fmt::Debug for Http3Connection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Http3Connection")
+            .field("remote_address", &self.inner.remote_address)
+            .field("uri", &self.inner.uri)
+            .finish()
+    }
+}
+
+impl<In, Out> Clone for Http3Connection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Http3Connection<In, Out> {
+    type SendError = io::Error;
+
+    type RecvError = io::Error;
+
+    type OpenError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Http3Connection<In, Out> {
+    type SendSink = SendSink<Out>;
+    type RecvStream = RecvStream<In>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+/// Future returned by [Http3Connection::open_bi]
+pub type OpenBiFuture<In, Out> = BoxFuture<'static, io::Result<(SendSink<Out>, RecvStream<In>)>>;
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for Http3Connection<In, Out> {
+    type OpenBiFut = OpenBiFuture<In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let mut requests = self.inner.requests.lock().unwrap().clone();
+        let uri = self.inner.uri.clone();
+        let framing = self.framing.clone();
+        async move {
+            let mut request = Request::new(());
+            *request.method_mut() = Method::POST;
+            *request.uri_mut() = uri;
+            request
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+            let stream = requests.send_request(request).await.map_err(h3_error)?;
+            let (send, mut recv) = stream.split();
+            let send = SendSink::new(SendHalf::Client(send), framing.clone());
+            let response = async move {
+                read_response(&mut recv).await?;
+                Ok(body(RecvHalf::Client(recv)))
+            };
+            let recv = RecvStream::new(
+                response.try_flatten_stream().boxed(),
+                framing,
+                Direction::Response,
+            );
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
+
+/// Answer a request, returning its halves if it is a call
+async fn accept_call(
+    request: Request<()>,
+    mut stream: ServerRequest<quic::BidiStream>,
+) -> result::Result<Option<(SendHalf, RecvHalf)>, h3::Error> {
+    let content_type = HeaderValue::from_static(CONTENT_TYPE);
+    let mut response = Response::new(());
+    if request.method() != Method::POST {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else if request.headers().get(header::CONTENT_TYPE) != Some(&content_type) {
+        *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    } else {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+        stream.send_response(response).await?;
+        let (send, recv) = stream.split();
+        return Ok(Some((SendHalf::Server(send), RecvHalf::Server(recv))));
+    }
+    stream.send_response(response).await?;
+    stream.finish().await?;
+    Ok(None)
+}
+
+/// Pass the calls of an HTTP/3 connection to `sender`
+async fn serve_connection<In: RpcMessage, Out: RpcMessage>(
+    connection: quinn::Connection,
+    sender: flume::Sender<Socket<In, Out>>,
+    framing: Framing,
+) -> result::Result<(), h3::Error> {
+    // dropping the HTTP/3 connection closes it
+    let mut connection =
+        h3::server::Connection::<_, Bytes>::new(quic::Connection::new(connection)).await?;
+    while let Some((request, stream)) = connection.accept().await? {
+        let sender = sender.clone();
+        let framing = framing.clone();
+        tokio::spawn(async move {
+            let (send, recv) = match accept_call(request, stream).await {
+                Ok(Some(call)) => call,
+                Ok(None) => return,
+                Err(e) => {
+                    tracing::debug!("HTTP/3 request failed: {}", e);
+                    return;
+                }
+            };
+            let send = SendSink::new(send, framing.clone());
+            let recv = RecvStream::new(body(recv), framing, Direction::Request);
+            sender.send_async((send, recv)).await.ok();
+        });
+    }
+    Ok(())
+}
+
+struct ServerInner {
+    tasks: Vec<JoinHandle<()>>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// A server endpoint that serves calls made as HTTP/3 requests
+pub struct Http3ServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Socket<In, Out>>,
+}
+
+impl<In, Out> Clone for Http3ServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for Http3ServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Http3ServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Http3ServerEndpoint<In, Out> {
+    /// Serve calls on the connections of a quinn server endpoint
+    ///
+    /// Dropping all clones of the endpoint stops accepting connections.
+    /// Connections that were already accepted are served until they close.
+    pub fn new(endpoint: quinn::Endpoint) -> io::Result<Self> {
+        let local_addr = endpoint.local_addr()?;
+        let (incoming_tx, incoming) = flume::bounded(16);
+        let accept = tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                let connection = match connecting.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        tracing::warn!("Error accepting HTTP/3 connection: {}", e);
+                        continue;
+                    }
+                };
+                if incoming_tx.send_async(connection).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self::serve(incoming, local_addr, vec![accept]))
+    }
+
+    /// Serve calls on the HTTP/3 connections from `incoming`
+    ///
+    /// This is useful if the quinn endpoint is managed elsewhere, e.g. because
+    /// it is shared with other protocols.
+    pub fn handle_connections(
+        incoming: flume::Receiver<quinn::Connection>,
+        local_addr: SocketAddr,
+    ) -> Self {
+        Self::serve(incoming, local_addr, Vec::new())
+    }
+
+    fn serve(
+        incoming: flume::Receiver<quinn::Connection>,
+        local_addr: SocketAddr,
+        mut tasks: Vec<JoinHandle<()>>,
+    ) -> Self {
+        let (sender, accept) = flume::bounded(16);
+        let framing = Framing::new(MAX_FRAME_LENGTH);
+        tasks.push(tokio::spawn(async move {
+            while let Ok(connection) = incoming.recv_async().await {
+                let remote_address = connection.remote_address();
+                let serve = serve_connection(connection, sender.clone(), framing.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = serve.await {
+                        tracing::debug!("HTTP/3 connection from {} failed: {}", remote_address, e);
+                    }
+                });
+            }
+        }));
+        Self {
+            inner: Arc::new(ServerInner {
+                tasks,
+                local_addr: [LocalAddr::Socket(local_addr)],
+            }),
+            accept,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Http3ServerEndpoint<In, Out> {
+    type SendError = io::Error;
+
+    type RecvError = io::Error;
+
+    type OpenError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Http3ServerEndpoint<In, Out> {
+    type SendSink = SendSink<Out>;
+    type RecvStream = RecvStream<In>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for Http3ServerEndpoint<In, Out> {
+    type AcceptBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            accept
+                .recv_async()
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "the endpoint is closed"))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
@@ -0,0 +1,240 @@
+//! The QUIC streams that h3 reads HTTP/3 frames from
+//!
+//! These are the streams of [h3_quinn], except that the request streams hand
+//! the header and the payload of every DATA frame to h3 as separate chunks.
+//! `FrameStream::poll_data` of h3 0.0.2 only looks at the payload it already
+//! has after receiving another chunk, so a payload that arrived together with
+//! the frame header would not be read until the peer sends more. That is
+//! fixed in h3 0.0.4, which needs quinn 0.10.
+use bytes::{Buf, Bytes, BytesMut};
+use futures::ready;
+use h3::quic::{self, StreamId, WriteBuf};
+use std::task::{Context, Poll};
+
+/// Length of the QUIC variable-length integer at the start of `buf`, and its value
+fn varint(buf: &[u8]) -> Option<(usize, u64)> {
+    let first = *buf.first()?;
+    let len = 1 << (first >> 6);
+    let bytes = buf.get(..len)?;
+    let value = bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |value, byte| {
+            value << 8 | u64::from(*byte)
+        });
+    Some((len, value))
+}
+
+/// Length of the HTTP/3 frame header at the start of `buf`, and the length of its payload
+fn frame_header(buf: &[u8]) -> Option<(usize, u64)> {
+    let (type_len, _) = varint(buf)?;
+    let (len_len, payload) = varint(&buf[type_len..])?;
+    Some((type_len + len_len, payload))
+}
+
+/// A QUIC connection for h3, see the [module docs](self)
+pub(super) struct Connection(h3_quinn::Connection);
+
+impl Connection {
+    pub(super) fn new(connection: quinn::Connection) -> Self {
+        Self(h3_quinn::Connection::new(connection))
+    }
+}
+
+impl quic::Connection<Bytes> for Connection {
+    type BidiStream = BidiStream;
+    type SendStream = h3_quinn::SendStream<Bytes>;
+    type RecvStream = h3_quinn::RecvStream;
+    type OpenStreams = OpenStreams;
+    type Error = h3_quinn::ConnectionError;
+
+    fn poll_accept_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::RecvStream>, Self::Error>> {
+        quic::Connection::<Bytes>::poll_accept_recv(&mut self.0, cx)
+    }
+
+    fn poll_accept_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::BidiStream>, Self::Error>> {
+        let stream = ready!(self.0.poll_accept_bidi(cx))?;
+        Poll::Ready(Ok(stream.map(BidiStream::new)))
+    }
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, Self::Error>> {
+        let stream = ready!(self.0.poll_open_bidi(cx))?;
+        Poll::Ready(Ok(BidiStream::new(stream)))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, Self::Error>> {
+        self.0.poll_open_send(cx)
+    }
+
+    fn opener(&self) -> Self::OpenStreams {
+        OpenStreams(quic::Connection::<Bytes>::opener(&self.0))
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        quic::Connection::<Bytes>::close(&mut self.0, code, reason)
+    }
+}
+
+/// Opens the request streams of a client
+pub(super) struct OpenStreams(h3_quinn::OpenStreams);
+
+impl Clone for OpenStreams {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl quic::OpenStreams<Bytes> for OpenStreams {
+    type BidiStream = BidiStream;
+    type SendStream = h3_quinn::SendStream<Bytes>;
+    type RecvStream = RecvStream;
+    type Error = h3_quinn::ConnectionError;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, Self::Error>> {
+        let stream = ready!(self.0.poll_open_bidi(cx))?;
+        Poll::Ready(Ok(BidiStream::new(stream)))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, Self::Error>> {
+        self.0.poll_open_send(cx)
+    }
+
+    fn close(&mut self, code: h3::error::Code, reason: &[u8]) {
+        quic::OpenStreams::<Bytes>::close(&mut self.0, code, reason)
+    }
+}
+
+/// A request stream
+pub(super) struct BidiStream {
+    send: h3_quinn::SendStream<Bytes>,
+    recv: RecvStream,
+}
+
+impl BidiStream {
+    fn new(stream: h3_quinn::BidiStream<Bytes>) -> Self {
+        let (send, recv) = quic::BidiStream::split(stream);
+        Self {
+            send,
+            recv: RecvStream::new(recv),
+        }
+    }
+}
+
+impl quic::BidiStream<Bytes> for BidiStream {
+    type SendStream = h3_quinn::SendStream<Bytes>;
+    type RecvStream = RecvStream;
+
+    fn split(self) -> (Self::SendStream, Self::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl quic::SendStream<Bytes> for BidiStream {
+    type Error = h3_quinn::SendStreamError;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send.poll_ready(cx)
+    }
+
+    fn send_data<T: Into<WriteBuf<Bytes>>>(&mut self, data: T) -> Result<(), Self::Error> {
+        self.send.send_data(data)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.send.poll_finish(cx)
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        self.send.reset(reset_code)
+    }
+
+    fn id(&self) -> StreamId {
+        self.send.id()
+    }
+}
+
+impl quic::RecvStream for BidiStream {
+    type Buf = Bytes;
+    type Error = h3_quinn::ReadError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, Self::Error>> {
+        self.recv.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.recv.stop_sending(error_code)
+    }
+}
+
+/// The receiving half of a request stream, split at the HTTP/3 frames
+pub(super) struct RecvStream {
+    inner: h3_quinn::RecvStream,
+    /// Received, but not yet passed on
+    buf: Bytes,
+    /// Payload of the current frame that is not yet passed on
+    payload: u64,
+}
+
+impl RecvStream {
+    fn new(inner: h3_quinn::RecvStream) -> Self {
+        Self {
+            inner,
+            buf: Bytes::new(),
+            payload: 0,
+        }
+    }
+}
+
+impl quic::RecvStream for RecvStream {
+    type Buf = Bytes;
+    type Error = h3_quinn::ReadError;
+
+    fn poll_data(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Bytes>, Self::Error>> {
+        loop {
+            if self.payload > 0 && !self.buf.is_empty() {
+                let len = self.payload.min(self.buf.len() as u64);
+                self.payload -= len;
+                return Poll::Ready(Ok(Some(self.buf.split_to(len as usize))));
+            }
+            if self.payload == 0 {
+                if let Some((len, payload)) = frame_header(&self.buf) {
+                    self.payload = payload;
+                    return Poll::Ready(Ok(Some(self.buf.split_to(len))));
+                }
+            }
+            match ready!(self.inner.poll_data(cx))? {
+                Some(data) if self.buf.is_empty() => self.buf = data,
+                Some(data) => {
+                    // only the start of a frame header is left over
+                    let mut buf = BytesMut::from(&self.buf[..]);
+                    buf.extend_from_slice(data.chunk());
+                    self.buf = buf.freeze();
+                }
+                // h3 reports the truncated frame
+                None if self.buf.is_empty() => return Poll::Ready(Ok(None)),
+                None => return Poll::Ready(Ok(Some(std::mem::take(&mut self.buf)))),
+            }
+        }
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.inner.stop_sending(error_code)
+    }
+}
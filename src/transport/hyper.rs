@@ -8,6 +8,7 @@ use std::{
 
 use crate::transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint};
 use crate::RpcMessage;
+use bincode::Options;
 use bytes::Bytes;
 use flume::{r#async::RecvFut, Receiver, Sender};
 use futures::{future::FusedFuture, Future, FutureExt, Sink, SinkExt, StreamExt};
@@ -22,7 +23,20 @@ use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tracing::{debug, event, trace, Level};
 
-use super::ConnectionCommon;
+use super::{
+    decode::{decode, DecodeError, Direction},
+    Capabilities, Codec, ConnectionCommon,
+};
+
+/// Capabilities of the hyper transport
+///
+/// The connection may be plain http2, so messages are not assumed to be encrypted.
+const HYPER_CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    encrypted: false,
+    multiplexed: true,
+};
 
 struct HyperConnectionInner {
     client: Box<dyn Requester>,
@@ -37,7 +51,7 @@ pub struct HyperConnection<In: RpcMessage, Out: RpcMessage> {
 }
 
 /// Trait so we don't have to drag around the hyper internals
-trait Requester: Send + Sync + 'static {
+pub(super) trait Requester: Send + Sync + 'static {
     fn request(&self, req: Request<Body>) -> ResponseFuture;
 }
 
@@ -139,6 +153,11 @@ pub struct ChannelConfig {
     /// The maximum frame size to use.
     max_frame_size: u32,
     max_payload_size: usize,
+    codec: Codec,
+    #[cfg(feature = "openmetrics")]
+    serve_metrics: bool,
+    #[cfg(feature = "payload-sampling")]
+    serve_samples: bool,
 }
 
 impl ChannelConfig {
@@ -159,6 +178,39 @@ impl ChannelConfig {
         self.max_payload_size = value;
         Ok(self)
     }
+
+    /// Set the codec of the messages.
+    ///
+    /// Both sides of a channel have to use the same codec. Messages of a codec
+    /// that produces text, like `Codec::Json`, are separated by newlines instead
+    /// of being length prefixed, so the bodies can be written and read by hand.
+    /// Like for any client, the request body has to stay open until the response
+    /// arrived, since the server takes the end of the requests as a cancellation.
+    pub fn codec(mut self, value: Codec) -> Self {
+        self.codec = value;
+        self
+    }
+
+    /// Serve the [metrics](crate::metrics) on `GET /metrics`.
+    ///
+    /// This only affects server channels. Since metrics scrapers use HTTP/1.1, the
+    /// server accepts both HTTP/1.1 and HTTP/2 connections when this is enabled.
+    /// Rpc calls are still only accepted over HTTP/2.
+    #[cfg(feature = "openmetrics")]
+    pub fn serve_metrics(mut self, value: bool) -> Self {
+        self.serve_metrics = value;
+        self
+    }
+
+    /// Serve the [sampled payloads](crate::sampling) on `GET /debug/samples`.
+    ///
+    /// This only affects server channels. Like [ChannelConfig::serve_metrics], this
+    /// makes the server accept HTTP/1.1 connections as well.
+    #[cfg(feature = "payload-sampling")]
+    pub fn serve_samples(mut self, value: bool) -> Self {
+        self.serve_samples = value;
+        self
+    }
 }
 
 impl Default for ChannelConfig {
@@ -166,6 +218,11 @@ impl Default for ChannelConfig {
         Self {
             max_frame_size: 0xFFFFFF,
             max_payload_size: 0xFFFFFF,
+            codec: Codec::default(),
+            #[cfg(feature = "openmetrics")]
+            serve_metrics: false,
+            #[cfg(feature = "payload-sampling")]
+            serve_samples: false,
         }
     }
 }
@@ -207,6 +264,15 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
     /// Creates a server listening on the [`SocketAddr`] with a custom configuration.
     pub fn serve_with_config(addr: &SocketAddr, config: ChannelConfig) -> hyper::Result<Self> {
         let (accept_tx, accept_rx) = flume::bounded(32);
+        #[cfg(feature = "openmetrics")]
+        let serve_metrics = config.serve_metrics;
+        #[cfg(not(feature = "openmetrics"))]
+        let serve_metrics = false;
+        #[cfg(feature = "payload-sampling")]
+        let serve_samples = config.serve_samples;
+        #[cfg(not(feature = "payload-sampling"))]
+        let serve_samples = false;
+        let codec = config.codec.clone();
 
         // The hyper "MakeService" which is called for each connection that is made to the
         // server.  It creates another Service which handles a single request.
@@ -216,10 +282,37 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
 
             // Need a new accept_tx to move to the future on every call of this FnMut.
             let accept_tx = accept_tx.clone();
+            let codec = codec.clone();
             async move {
                 let one_req_service = service_fn(move |req: Request<Body>| {
                     // This closure is an FnMut as well, so clone accept_tx once more.
-                    Self::handle_one_http2_request(req, accept_tx.clone())
+                    let accept_tx = accept_tx.clone();
+                    let codec = codec.clone();
+                    async move {
+                        #[cfg(feature = "openmetrics")]
+                        if serve_metrics && req.uri().path() == "/metrics" {
+                            return Ok(get_response(
+                                &req,
+                                crate::metrics::CONTENT_TYPE,
+                                crate::metrics::encode,
+                            ));
+                        }
+                        #[cfg(feature = "payload-sampling")]
+                        if serve_samples && req.uri().path() == "/debug/samples" {
+                            return Ok(get_response(
+                                &req,
+                                crate::sampling::CONTENT_TYPE,
+                                crate::sampling::encode,
+                            ));
+                        }
+                        if req.version() != hyper::Version::HTTP_2 {
+                            return Ok(Response::builder()
+                                .status(StatusCode::HTTP_VERSION_NOT_SUPPORTED)
+                                .body(Body::empty())
+                                .expect("valid response"));
+                        }
+                        Self::handle_one_http2_request(req, accept_tx, codec).await
+                    }
                 });
                 Ok::<_, Infallible>(one_req_service)
             }
@@ -228,7 +321,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
         let mut incoming = AddrIncoming::bind(addr)?;
         incoming.set_nodelay(true);
         let server = Server::builder(incoming)
-            .http2_only(true)
+            .http2_only(!(serve_metrics || serve_samples))
             .http2_initial_connection_window_size(Some(config.max_frame_size))
             .http2_initial_stream_window_size(Some(config.max_frame_size))
             .http2_max_frame_size(Some(config.max_frame_size))
@@ -259,6 +352,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
     async fn handle_one_http2_request(
         req: Request<Body>,
         accept_tx: Sender<InternalChannel<In>>,
+        codec: Codec,
     ) -> Result<Response<Body>, String> {
         let (req_tx, req_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
         let (res_tx, res_rx) = flume::bounded::<io::Result<Bytes>>(32);
@@ -267,7 +361,7 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
             .await
             .map_err(|_e| "unable to send")?;
 
-        spawn_recv_forwarder(req.into_body(), req_tx);
+        spawn_recv_forwarder(req.into_body(), req_tx, codec, Direction::Request);
         // Create a response with the response body channel as the response body
         let response = Response::builder()
             .status(StatusCode::OK)
@@ -277,6 +371,25 @@ impl<In: RpcMessage, Out: RpcMessage> HyperServerEndpoint<In, Out> {
     }
 }
 
+/// Respond to a request for a text endpoint like `/metrics`, rendered by `render`
+#[cfg(any(feature = "openmetrics", feature = "payload-sampling"))]
+fn get_response(
+    req: &Request<Body>,
+    content_type: &'static str,
+    render: fn() -> String,
+) -> Response<Body> {
+    let response = if req.method() == hyper::Method::GET {
+        Response::builder()
+            .header(hyper::header::CONTENT_TYPE, content_type)
+            .body(Body::from(render()))
+    } else {
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::empty())
+    };
+    response.expect("valid response")
+}
+
 fn try_get_length_prefixed(buf: &[u8]) -> Option<&[u8]> {
     if buf.len() < 4 {
         return None;
@@ -288,6 +401,16 @@ fn try_get_length_prefixed(buf: &[u8]) -> Option<&[u8]> {
     Some(&buf[4..4 + len])
 }
 
+/// Try to get the next frame of the buffer, and the number of bytes it takes up.
+fn try_get_frame<'a>(buf: &'a [u8], codec: &Codec) -> Option<(&'a [u8], usize)> {
+    if codec.is_line_delimited() {
+        let end = buf.iter().position(|b| *b == b'\n')?;
+        Some((&buf[..end], end + 1))
+    } else {
+        try_get_length_prefixed(buf).map(|msg| (msg, msg.len() + 4))
+    }
+}
+
 /// Try forward all frames as deserialized messages from the buffer to the sender.
 ///
 /// On success, returns the number of forwarded bytes.
@@ -299,11 +422,24 @@ fn try_get_length_prefixed(buf: &[u8]) -> Option<&[u8]> {
 async fn try_forward_all<In: RpcMessage>(
     buffer: &[u8],
     req_tx: &Sender<Result<In, RecvError>>,
+    codec: &Codec,
+    direction: Direction,
 ) -> result::Result<usize, ()> {
+    // the options of bincode::deserialize
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes();
     let mut sent = 0;
-    while let Some(msg) = try_get_length_prefixed(&buffer[sent..]) {
-        sent += msg.len() + 4;
-        let item = bincode::deserialize::<In>(msg).map_err(RecvError::DeserializeError);
+    while let Some((msg, len)) = try_get_frame(&buffer[sent..], codec) {
+        sent += len;
+        let item = match codec {
+            Codec::Bincode => {
+                decode::<In, _>(options, msg, direction).map_err(RecvError::DeserializeError)
+            }
+            // blank lines between messages typed by hand
+            _ if codec.is_line_delimited() && msg.iter().all(u8::is_ascii_whitespace) => continue,
+            _ => codec.decode(msg, direction).map_err(RecvError::CodecError),
+        };
         if let Err(_cause) = req_tx.send_async(item).await {
             // The receiver is gone, so we can't send any more data.
             //
@@ -320,8 +456,8 @@ async fn try_forward_all<In: RpcMessage>(
 
 /// Spawns a task which forwards requests from the network to a flume channel.
 ///
-/// This task will read chunks from the network, split them into length prefixed
-/// frames, deserialize those frames, and send the result to the flume channel.
+/// This task will read chunks from the network, split them into frames, deserialize
+/// those frames with `codec`, and send the result to the flume channel.
 ///
 /// If there is a network error or the flume channel closes or the request
 /// stream is simply ended this task will terminate.
@@ -329,43 +465,56 @@ async fn try_forward_all<In: RpcMessage>(
 /// So it is fine to ignore the returned [`JoinHandle`].
 ///
 /// The HTTP2 request comes from *req* and the data is sent to `req_tx`.
+/// `direction` is the direction of the messages, for error reporting.
 fn spawn_recv_forwarder<In: RpcMessage>(
     req: Body,
     req_tx: Sender<result::Result<In, RecvError>>,
+    codec: Codec,
+    direction: Direction,
 ) -> JoinHandle<result::Result<(), ()>> {
-    tokio::spawn(async move {
-        let mut stream = req;
-        let mut buf = Vec::new();
-
-        while let Some(chunk) = stream.next().await {
-            match chunk.as_ref() {
-                Ok(chunk) => {
-                    event!(Level::TRACE, "Server got {} bytes", chunk.len());
-                    if buf.is_empty() {
-                        // try to forward directly from buffer
-                        let sent = try_forward_all(chunk, &req_tx).await?;
-                        // add just the rest, if any
-                        buf.extend_from_slice(&chunk[sent..]);
-                    } else {
-                        // no choice but to add it all
-                        buf.extend_from_slice(chunk);
-                    }
-                }
-                Err(cause) => {
-                    // Indicates that the connection has been closed on the client side.
-                    // This is a normal occurrence, e.g. when the client has raced the RPC
-                    // call with something else and has droppped the future.
-                    debug!("Network error: {}", cause);
-                    break;
+    tokio::spawn(async move { forward_body(req, &req_tx, &codec, direction).await })
+}
+
+/// Forwards the frames of `body` as messages deserialized with `codec` to `req_tx`.
+///
+/// Ends without an error when the body ends or fails, and with the unit error when
+/// `req_tx` has no receiver anymore.
+pub(super) async fn forward_body<In: RpcMessage>(
+    mut stream: Body,
+    req_tx: &Sender<result::Result<In, RecvError>>,
+    codec: &Codec,
+    direction: Direction,
+) -> result::Result<(), ()> {
+    let mut buf = Vec::new();
+
+    while let Some(chunk) = stream.next().await {
+        match chunk.as_ref() {
+            Ok(chunk) => {
+                event!(Level::TRACE, "Server got {} bytes", chunk.len());
+                if buf.is_empty() {
+                    // try to forward directly from buffer
+                    let sent = try_forward_all(chunk, req_tx, codec, direction).await?;
+                    // add just the rest, if any
+                    buf.extend_from_slice(&chunk[sent..]);
+                } else {
+                    // no choice but to add it all
+                    buf.extend_from_slice(chunk);
                 }
-            };
-            let sent = try_forward_all(&buf, &req_tx).await?;
-            // remove the forwarded bytes.
-            // Frequently this will be the entire buffer, so no memcpy but just set the size to 0
-            buf.drain(..sent);
-        }
-        Ok(())
-    })
+            }
+            Err(cause) => {
+                // Indicates that the connection has been closed on the client side.
+                // This is a normal occurrence, e.g. when the client has raced the RPC
+                // call with something else and has droppped the future.
+                debug!("Network error: {}", cause);
+                break;
+            }
+        };
+        let sent = try_forward_all(&buf, req_tx, codec, direction).await?;
+        // remove the forwarded bytes.
+        // Frequently this will be the entire buffer, so no memcpy but just set the size to 0
+        buf.drain(..sent);
+    }
+    Ok(())
 }
 
 // This does not want or need RpcMessage to be clone but still want to clone the
@@ -430,7 +579,10 @@ pub struct SendSink<Out: RpcMessage> {
 }
 
 impl<Out: RpcMessage> SendSink<Out> {
-    fn new(sender: flume::Sender<io::Result<Bytes>>, config: Arc<ChannelConfig>) -> Self {
+    pub(super) fn new(
+        sender: flume::Sender<io::Result<Bytes>>,
+        config: Arc<ChannelConfig>,
+    ) -> Self {
         Self {
             sink: sender.into_sink(),
             config,
@@ -438,9 +590,23 @@ impl<Out: RpcMessage> SendSink<Out> {
         }
     }
     fn serialize(&self, item: Out) -> Result<Bytes, SendError> {
+        let codec = &self.config.codec;
+        if codec.is_line_delimited() {
+            let mut data = Vec::from(codec.encode(&item).map_err(SendError::CodecError)?);
+            if data.len() > self.config.max_payload_size {
+                return Err(SendError::SizeError(data.len()));
+            }
+            data.push(b'\n');
+            return Ok(data.into());
+        }
         let mut data = Vec::with_capacity(1024);
         data.extend_from_slice(&[0u8; 4]);
-        bincode::serialize_into(&mut data, &item).map_err(SendError::SerializeError)?;
+        match codec {
+            Codec::Bincode => {
+                bincode::serialize_into(&mut data, &item).map_err(SendError::SerializeError)?
+            }
+            _ => data.extend_from_slice(&codec.encode(&item).map_err(SendError::CodecError)?),
+        }
         let len = data.len() - 4;
         if len > self.config.max_payload_size {
             return Err(SendError::SizeError(len));
@@ -511,6 +677,8 @@ impl<Out: RpcMessage> Sink<Out> for SendSink<Out> {
 pub enum SendError {
     /// Error when bincode serializing the message.
     SerializeError(bincode::Error),
+    /// Error when serializing the message with a codec other than bincode.
+    CodecError(io::Error),
     /// The message is too large to be sent.
     SizeError(usize),
     /// The connection has been closed.
@@ -529,7 +697,9 @@ impl error::Error for SendError {}
 #[derive(Debug)]
 pub enum RecvError {
     /// Error when bincode deserializing the message.
-    DeserializeError(bincode::Error),
+    DeserializeError(DecodeError),
+    /// Error when deserializing the message with a codec other than bincode.
+    CodecError(io::Error),
     /// Hyper network error.
     NetworkError(hyper::Error),
 }
@@ -611,7 +781,8 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
                     event!(Level::TRACE, "OpenBiFuture got response");
                     let (_, out_tx, config) = this.chan.take().unwrap().unwrap();
                     let (in_tx, in_rx) = flume::bounded::<result::Result<In, RecvError>>(32);
-                    spawn_recv_forwarder(res.into_body(), in_tx);
+                    let codec = config.codec.clone();
+                    spawn_recv_forwarder(res.into_body(), in_tx, codec, Direction::Response);
 
                     let out_tx = self::SendSink::new(out_tx, config);
                     let in_rx = self::RecvStream::new(in_rx);
@@ -768,6 +939,7 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for HyperConnect
     type RecvStream = self::RecvStream<In>;
 
     type SendSink = self::SendSink<Out>;
+    const CAPABILITIES: Capabilities = HYPER_CAPABILITIES;
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for HyperConnection<In, Out> {
@@ -789,6 +961,7 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for HyperServerEndpoint<I
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for HyperServerEndpoint<In, Out> {
     type RecvStream = self::RecvStream<In>;
     type SendSink = self::SendSink<Out>;
+    const CAPABILITIES: Capabilities = HYPER_CAPABILITIES;
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for HyperServerEndpoint<In, Out> {
@@ -0,0 +1,399 @@
+//! QUIC transport implementation based on [iroh-net](https://crates.io/crates/iroh-net)
+//!
+//! [IrohConnection] and [IrohServerEndpoint] mirror the connection and server
+//! endpoint of the quinn transport, but address nodes by their [NodeAddr]
+//! instead of a socket address, so iroh can hole punch or relay the
+//! connection. Every channel is a new bidirectional stream of a connection
+//! that uses the ALPN of the service.
+//!
+//! ```ignore
+//! const ALPN: &[u8] = b"compute/1";
+//!
+//! let endpoint = Endpoint::builder().alpns(vec![ALPN.to_vec()]).bind().await?;
+//! let server = IrohServerEndpoint::<ComputeRequest, ComputeResponse>::new(endpoint);
+//!
+//! let endpoint = Endpoint::builder().bind().await?;
+//! let client = IrohConnection::<ComputeResponse, ComputeRequest>::new(endpoint, node_addr, ALPN.to_vec());
+//! ```
+//!
+//! Connections of an iroh endpoint are authenticated with the key of the node,
+//! so the capabilities claim encryption.
+//!
+//! iroh-net needs Rust 1.76, a newer compiler than the rest of the crate.
+// iroh-net 0.28.2 deprecates everything in favor of the renamed iroh crate
+#![allow(deprecated)]
+use super::{
+    util::{Codec, FramedBincodeRead, FramedBincodeWrite, Framing},
+    Capabilities, ConnectionCommon, Direction,
+};
+use crate::{
+    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    RpcMessage,
+};
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use iroh_net::{
+    endpoint::{get_remote_node_id, RecvStream as IrohRecvStream, SendStream as IrohSendStream},
+    Endpoint, NodeAddr, NodeId,
+};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Maximum length of a single frame
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
+
+/// Wrap the two sides of a bidirectional stream into the two sides of a channel
+fn socket<In: RpcMessage, Out: RpcMessage>(
+    (send, recv): (IrohSendStream, IrohRecvStream),
+    peer: NodeId,
+    framing: Framing,
+    direction: Direction,
+) -> Socket<In, Out> {
+    let send = SendSink(FramedBincodeWrite::new(send, framing.clone()));
+    let recv = RecvStream(FramedBincodeRead::new(recv, framing, direction), peer);
+    (send, recv)
+}
+
+fn other(cause: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, cause)
+}
+
+#[derive(Debug)]
+struct ClientInner {
+    endpoint: Endpoint,
+    addr: NodeAddr,
+    alpn: Vec<u8>,
+    /// The current connection, established by the first call
+    connection: tokio::sync::Mutex<Option<iroh_net::endpoint::Connection>>,
+}
+
+impl ClientInner {
+    /// The current connection, connecting if there is none or it is closed
+    async fn connection(&self) -> io::Result<iroh_net::endpoint::Connection> {
+        let mut current = self.connection.lock().await;
+        if let Some(connection) = current.as_ref() {
+            if connection.close_reason().is_none() {
+                return Ok(connection.clone());
+            }
+        }
+        tracing::debug!("Connecting to {}", self.addr.node_id);
+        let connection = self
+            .endpoint
+            .connect(self.addr.clone(), &self.alpn)
+            .await
+            .map_err(other)?;
+        *current = Some(connection.clone());
+        Ok(connection)
+    }
+}
+
+/// A connection to a node that serves calls with an [IrohServerEndpoint]
+///
+/// The QUIC connection is established when the first channel is opened, and
+/// established again once it is closed.
+pub struct IrohConnection<In, Out> {
+    inner: Arc<ClientInner>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for IrohConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for IrohConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrohConnection")
+            .field("addr", &self.inner.addr)
+            .field("alpn", &String::from_utf8_lossy(&self.inner.alpn))
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> IrohConnection<In, Out> {
+    /// Call the node at `addr` on connections with ALPN `alpn`, connecting with `endpoint`
+    pub fn new(endpoint: Endpoint, addr: impl Into<NodeAddr>, alpn: Vec<u8>) -> Self {
+        Self {
+            inner: Arc::new(ClientInner {
+                endpoint,
+                addr: addr.into(),
+                alpn,
+                connection: Default::default(),
+            }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        }
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// The server endpoint must use the same codec, see
+    /// [IrohServerEndpoint::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+
+    /// The node that is called
+    pub fn node_id(&self) -> NodeId {
+        self.inner.addr.node_id
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for IrohConnection<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for IrohConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for IrohConnection<In, Out> {
+    type OpenBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let framing = self.framing.clone();
+        async move {
+            let connection = inner.connection().await?;
+            let stream = connection.open_bi().await?;
+            Ok(socket(
+                stream,
+                inner.addr.node_id,
+                framing,
+                Direction::Response,
+            ))
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct ServerInner {
+    task: JoinHandle<()>,
+    local_addr: Vec<LocalAddr>,
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A server endpoint that serves the calls on all connections of an iroh [Endpoint]
+///
+/// The endpoint should only accept the ALPN of the service, see
+/// [iroh_net::endpoint::Builder::alpns].
+pub struct IrohServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<((IrohSendStream, IrohRecvStream), NodeId)>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for IrohServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for IrohServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrohServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> IrohServerEndpoint<In, Out> {
+    /// Serve the calls on the connections accepted by `endpoint`
+    ///
+    /// Connections are accepted until the endpoint and all its clones are
+    /// dropped, which also closes the accepted connections.
+    pub fn new(endpoint: Endpoint) -> Self {
+        let (v4, v6) = endpoint.bound_sockets();
+        let local_addr = std::iter::once(v4)
+            .chain(v6)
+            .map(LocalAddr::Socket)
+            .collect();
+        let (sender, accept) = flume::bounded(16);
+        let task = tokio::spawn(async move {
+            // dropped with the task, which aborts the tasks of all connections
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    incoming = endpoint.accept() => {
+                        let incoming = match incoming {
+                            Some(incoming) => incoming,
+                            None => break,
+                        };
+                        let sender = sender.clone();
+                        connections.spawn(async move {
+                            let connection = match incoming.await {
+                                Ok(connection) => connection,
+                                Err(cause) => {
+                                    tracing::debug!("failed to accept connection: {}", cause);
+                                    return;
+                                }
+                            };
+                            let peer = match get_remote_node_id(&connection) {
+                                Ok(peer) => peer,
+                                Err(cause) => {
+                                    tracing::debug!("connection without node id: {}", cause);
+                                    return;
+                                }
+                            };
+                            while let Ok(stream) = connection.accept_bi().await {
+                                if sender.send_async((stream, peer)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        });
+        Self {
+            inner: Arc::new(ServerInner { task, local_addr }),
+            accept,
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        }
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// Clients must use the same codec, see [IrohConnection::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for IrohServerEndpoint<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for IrohServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for IrohServerEndpoint<In, Out> {
+    type AcceptBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        let framing = self.framing.clone();
+        async move {
+            let (stream, peer) = accept.recv_async().await.map_err(|_| {
+                io::Error::new(io::ErrorKind::NotConnected, "no longer accepting streams")
+            })?;
+            Ok(socket(stream, peer, framing, Direction::Request))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
+
+/// A sink that wraps an iroh [SendStream](IrohSendStream) with length delimiting and bincode
+#[pin_project]
+pub struct SendSink<Out>(#[pin] FramedBincodeWrite<IrohSendStream, Out>);
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    /// Get the underlying [SendStream](IrohSendStream), which implements
+    /// [tokio::io::AsyncWrite] and can be used to send bytes directly.
+    pub fn into_inner(self) -> IrohSendStream {
+        self.0.into_inner()
+    }
+}
+
+impl<Out: Serialize + Send + 'static> Sink<Out> for SendSink<Out> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> io::Result<()> {
+        self.project().0.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_close_unpin(cx)
+    }
+}
+
+/// A stream that wraps an iroh [RecvStream](IrohRecvStream) with length delimiting and bincode
+#[pin_project]
+pub struct RecvStream<In>(#[pin] FramedBincodeRead<IrohRecvStream, In>, NodeId);
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").field("peer", &self.1).finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// The node on the other end of the channel
+    ///
+    /// On the server, this is the authenticated identity of the client.
+    pub fn peer(&self) -> NodeId {
+        self.1
+    }
+
+    /// Get the underlying [RecvStream](IrohRecvStream), which implements
+    /// [tokio::io::AsyncRead] and can be used to receive bytes directly.
+    pub fn into_inner(self) -> IrohRecvStream {
+        self.0.into_inner()
+    }
+}
+
+impl<In: DeserializeOwned + Send + 'static> Stream for RecvStream<In> {
+    type Item = io::Result<In>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_next_unpin(cx)
+    }
+}
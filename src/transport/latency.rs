@@ -0,0 +1,61 @@
+//! Message types that take the shortest path through a transport
+//!
+//! Heartbeats and consensus messages that share a connection with bulk
+//! traffic must not wait behind it. A [LatencyCritical] set marks their
+//! message types, and the layers that trade latency for throughput let them
+//! through untouched:
+//!
+//! - an [AdmissionServerEndpoint](super::admission::AdmissionServerEndpoint)
+//!   hands out calls that start with a marked message right away, without
+//!   queueing them or counting them against the limit of calls in flight
+//! - the quinn transport sends marked messages without compressing them, and
+//!   never encodes them on the blocking thread pool
+//!
+//! ```ignore
+//! let urgent = LatencyCritical::new().with::<Heartbeat>().with::<HeartbeatResponse>();
+//! let endpoint = AdmissionServerEndpoint::new(endpoint, config).with_latency_critical(urgent.clone());
+//! let connection = QuinnConnection::new(endpoint, addr, name).with_latency_critical(urgent);
+//! ```
+//!
+//! Message types are matched by the name of the enum variant of the message,
+//! like in an [Exposure](super::exposure::Exposure), so the requests and the
+//! responses of a method are marked separately. The batches of the http1
+//! transport only take messages that are already waiting, so they never hold
+//! a message back. The call limit of the quinn server endpoint applies before
+//! the first message of a call is read, so it can not tell calls apart.
+use super::exposure::variant;
+use crate::telemetry::method_name;
+use serde::Serialize;
+use std::{collections::HashSet, sync::Arc};
+
+/// Message types that bypass queueing, compression and offloading
+#[derive(Debug, Clone, Default)]
+pub struct LatencyCritical {
+    /// Marked messages, by message name
+    names: Arc<HashSet<&'static str>>,
+}
+
+impl LatencyCritical {
+    /// No latency critical messages
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also mark messages of type `M`
+    pub fn with<M>(mut self) -> Self {
+        Arc::make_mut(&mut self.names).insert(method_name::<M>());
+        self
+    }
+
+    /// Whether no message types are marked
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Whether `msg` is of a marked type
+    ///
+    /// Messages that are not an enum are never latency critical.
+    pub fn contains<T: Serialize>(&self, msg: &T) -> bool {
+        !self.is_empty() && variant(msg).map_or(false, |name| self.names.contains(name))
+    }
+}
@@ -0,0 +1,399 @@
+//! Transport that exposes services as a protocol of a libp2p swarm
+//!
+//! [Libp2pConnection] and [Libp2pServerEndpoint] open and accept streams of a
+//! [StreamProtocol] through the [Control] of a `libp2p-stream` behaviour, so
+//! services can reuse the connections of an existing swarm instead of opening
+//! a separate QUIC endpoint. Every channel is a new stream of the protocol,
+//! multiplexed by the muxer of the swarm, like every channel of the quinn
+//! transport is a new QUIC stream.
+//!
+//! ```ignore
+//! const PROTOCOL: StreamProtocol = StreamProtocol::new("/compute/1");
+//!
+//! let behaviour = libp2p_stream::Behaviour::new();
+//! let control = behaviour.new_control();
+//! // build a swarm with the behaviour and keep polling it
+//!
+//! let server = Libp2pServerEndpoint::<ComputeRequest, ComputeResponse>::new(control.clone(), PROTOCOL)?;
+//! let client = Libp2pConnection::<ComputeResponse, ComputeRequest>::new(control, peer, PROTOCOL);
+//! ```
+//!
+//! The swarm has to be polled for streams to be opened and accepted, and it
+//! has to know an address of the peer, e.g. because it is already connected.
+//! Connections of a swarm are always secured by its security protocol, so the
+//! capabilities claim encryption.
+//!
+//! The feature only enables the parts of libp2p the transport needs. The
+//! transports, security protocols and muxers of the swarm are up to the
+//! application. libp2p-stream needs Rust 1.75, a newer compiler than the rest
+//! of the crate.
+use super::{
+    util::{Codec, FramedBincodeRead, FramedBincodeWrite, Framing},
+    Capabilities, ConnectionCommon, Direction,
+};
+use crate::{
+    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    RpcMessage,
+};
+use futures::{
+    future::BoxFuture,
+    io::{ReadHalf, WriteHalf},
+    AsyncReadExt, AsyncWrite, AsyncWriteExt, FutureExt, Sink, SinkExt, Stream, StreamExt,
+};
+use libp2p::{PeerId, StreamProtocol};
+use libp2p_stream::{AlreadyRegistered, Control};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::task::JoinHandle;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+
+/// Maximum length of a single frame
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
+
+/// Split a stream of the protocol into the two sides of a channel
+fn socket<In: RpcMessage, Out: RpcMessage>(
+    stream: libp2p::Stream,
+    peer: PeerId,
+    framing: Framing,
+    direction: Direction,
+) -> Socket<In, Out> {
+    let (read, write) = stream.split();
+    let send = SendSink(FramedBincodeWrite::new(
+        CloseOnDrop(Some(write)).compat_write(),
+        framing.clone(),
+    ));
+    let recv = RecvStream(
+        FramedBincodeRead::new(read.compat(), framing, direction),
+        peer,
+    );
+    (send, recv)
+}
+
+/// The write half of a stream, closed once it is dropped
+///
+/// Unlike a QUIC stream, a stream of a libp2p muxer is only closed for writing
+/// by closing it explicitly. Channels are closed for writing by dropping their
+/// send side, so without this the remote would never see the end of e.g. the
+/// updates of a client streaming call. Closing needs to flush, so it is done
+/// on a task.
+struct CloseOnDrop(Option<WriteHalf<libp2p::Stream>>);
+
+impl CloseOnDrop {
+    fn get(&mut self) -> &mut WriteHalf<libp2p::Stream> {
+        self.0.as_mut().expect("only taken on drop")
+    }
+}
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        if let (Some(mut write), Ok(runtime)) =
+            (self.0.take(), tokio::runtime::Handle::try_current())
+        {
+            runtime.spawn(async move {
+                write.close().await.ok();
+            });
+        }
+    }
+}
+
+impl AsyncWrite for CloseOnDrop {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(self.get()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get()).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(self.get()).poll_close(cx)
+    }
+}
+
+/// A connection to a peer that serves a protocol with a [Libp2pServerEndpoint]
+pub struct Libp2pConnection<In, Out> {
+    control: Control,
+    peer: PeerId,
+    protocol: StreamProtocol,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for Libp2pConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            control: self.control.clone(),
+            peer: self.peer,
+            protocol: self.protocol.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for Libp2pConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Libp2pConnection")
+            .field("peer", &self.peer)
+            .field("protocol", &self.protocol)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Libp2pConnection<In, Out> {
+    /// Call `peer` on streams of `protocol`, opened with `control`
+    pub fn new(control: Control, peer: PeerId, protocol: StreamProtocol) -> Self {
+        Self {
+            control,
+            peer,
+            protocol,
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        }
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// The server endpoint must use the same codec, see
+    /// [Libp2pServerEndpoint::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+
+    /// The peer that is called
+    pub fn peer(&self) -> PeerId {
+        self.peer
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Libp2pConnection<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Libp2pConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for Libp2pConnection<In, Out> {
+    type OpenBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let mut control = self.control.clone();
+        let peer = self.peer;
+        let protocol = self.protocol.clone();
+        let framing = self.framing.clone();
+        async move {
+            let stream = control
+                .open_stream(peer, protocol)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(socket(stream, peer, framing, Direction::Response))
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct ServerInner {
+    task: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A server endpoint that serves the calls of all peers on streams of a protocol
+pub struct Libp2pServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<(PeerId, libp2p::Stream)>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for Libp2pServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for Libp2pServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Libp2pServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Libp2pServerEndpoint<In, Out> {
+    /// Serve the calls on streams of `protocol`, accepted with `control`
+    ///
+    /// Fails if streams of the protocol are already accepted by another
+    /// endpoint of the same behaviour.
+    pub fn new(
+        mut control: Control,
+        protocol: StreamProtocol,
+    ) -> result::Result<Self, AlreadyRegistered> {
+        let mut incoming = control.accept(protocol.clone())?;
+        let (sender, accept) = flume::bounded(16);
+        let task = tokio::spawn(async move {
+            while let Some(stream) = incoming.next().await {
+                if sender.send_async(stream).await.is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                task,
+                local_addr: [LocalAddr::Protocol(protocol.to_string())],
+            }),
+            accept,
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        })
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// Clients must use the same codec, see [Libp2pConnection::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for Libp2pServerEndpoint<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for Libp2pServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for Libp2pServerEndpoint<In, Out> {
+    type AcceptBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        let framing = self.framing.clone();
+        async move {
+            let (peer, stream) = accept.recv_async().await.map_err(|_| {
+                io::Error::new(io::ErrorKind::NotConnected, "no longer accepting streams")
+            })?;
+            Ok(socket(stream, peer, framing, Direction::Request))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
+
+/// A sink that wraps the write side of a libp2p stream with length delimiting and bincode
+#[pin_project]
+pub struct SendSink<Out>(#[pin] FramedBincodeWrite<Compat<CloseOnDrop>, Out>);
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    /// Get the write half of the underlying [libp2p::Stream], which implements
+    /// [futures::AsyncWrite] and can be used to send bytes directly.
+    ///
+    /// Unlike the send side, it is not closed when it is dropped.
+    pub fn into_inner(self) -> WriteHalf<libp2p::Stream> {
+        let mut write = self.0.into_inner().into_inner();
+        write.0.take().expect("only taken on drop")
+    }
+}
+
+impl<Out: Serialize + Send + 'static> Sink<Out> for SendSink<Out> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> io::Result<()> {
+        self.project().0.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_close_unpin(cx)
+    }
+}
+
+/// A stream that wraps the read side of a libp2p stream with length delimiting and bincode
+#[pin_project]
+pub struct RecvStream<In>(
+    #[pin] FramedBincodeRead<Compat<ReadHalf<libp2p::Stream>>, In>,
+    PeerId,
+);
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").field("peer", &self.1).finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// The peer on the other end of the channel
+    ///
+    /// On the server, this is the authenticated identity of the client.
+    pub fn peer(&self) -> PeerId {
+        self.1
+    }
+
+    /// Get the read half of the underlying [libp2p::Stream], which implements
+    /// [futures::AsyncRead] and can be used to receive bytes directly.
+    pub fn into_inner(self) -> ReadHalf<libp2p::Stream> {
+        self.0.into_inner().into_inner()
+    }
+}
+
+impl<In: DeserializeOwned + Send + 'static> Stream for RecvStream<In> {
+    type Item = io::Result<In>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_next_unpin(cx)
+    }
+}
@@ -7,7 +7,7 @@ use crate::{
 use futures::{future, stream, Sink};
 use std::convert::Infallible;
 
-use super::ConnectionCommon;
+use super::{Capabilities, ConnectionCommon};
 
 /// A dummy server endpoint that does nothing
 ///
@@ -25,6 +25,8 @@ impl ConnectionErrors for DummyServerEndpoint {
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for DummyServerEndpoint {
     type RecvStream = stream::Pending<Result<In, Self::RecvError>>;
     type SendSink = Box<dyn Sink<Out, Error = Self::SendError> + Unpin + Send>;
+    // there are never any channels, so this does not weaken a combined endpoint
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for DummyServerEndpoint {
@@ -1,23 +1,146 @@
 //! Transports for quic-rpc
-use crate::RpcError;
+use crate::{extensions::Extensions, RpcError};
 use futures::{Future, Sink, Stream};
 use std::{
     fmt::{self, Debug, Display},
     net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
 };
+#[cfg(feature = "flume-transport")]
+pub mod bridge;
+#[cfg(feature = "bus-transport")]
+pub mod bus;
 #[cfg(feature = "combined-transport")]
 pub mod combined;
+#[cfg(feature = "zstd-compression")]
+pub mod compression;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
+#[cfg(feature = "fair-sharing")]
+pub mod fair;
 #[cfg(feature = "flume-transport")]
 pub mod flume;
+#[cfg(feature = "http1-transport")]
+pub mod http1;
+#[cfg(feature = "http3-transport")]
+pub mod http3;
 #[cfg(feature = "hyper-transport")]
 pub mod hyper;
+#[cfg(feature = "iroh-transport")]
+pub mod iroh;
+#[cfg(feature = "libp2p-transport")]
+pub mod libp2p;
+#[cfg(all(windows, feature = "named-pipe-transport"))]
+pub mod named_pipe;
+#[cfg(feature = "proxy")]
+pub mod proxy;
 #[cfg(feature = "quinn-transport")]
 pub mod quinn;
+#[cfg(feature = "s2n-quic-transport")]
+pub mod s2n_quic;
+#[cfg(all(target_os = "linux", feature = "shm-transport"))]
+pub mod shm;
+#[cfg(feature = "signing")]
+pub mod signed;
+#[cfg(feature = "stdio-transport")]
+pub mod stdio;
+#[cfg(feature = "stream-transport")]
+pub mod stream;
+#[cfg(feature = "tcp-transport")]
+pub mod tcp;
+#[cfg(feature = "tls-tcp-transport")]
+pub mod tls_tcp;
+#[cfg(all(unix, feature = "unix-transport"))]
+pub mod unix;
+#[cfg(all(target_os = "linux", feature = "vsock-transport"))]
+pub mod vsock;
+#[cfg(feature = "web-transport")]
+pub mod web_transport;
 
+pub mod admission;
+pub mod boxed;
+pub mod breaker;
+pub mod exposure;
+pub mod latency;
 pub mod misc;
+pub mod ordered;
+pub mod pool;
+pub mod priority;
+pub mod pushback;
 
-#[cfg(any(feature = "quinn-transport", feature = "hyper-transport"))]
+#[cfg(any(
+    feature = "quinn-transport",
+    feature = "hyper-transport",
+    feature = "iroh-transport",
+    feature = "libp2p-transport",
+    feature = "s2n-quic-transport",
+    feature = "bus-transport",
+    feature = "tcp-transport",
+    all(unix, feature = "unix-transport"),
+    all(windows, feature = "named-pipe-transport"),
+    feature = "stdio-transport",
+    feature = "stream-transport",
+    feature = "tls-tcp-transport",
+    all(target_os = "linux", feature = "vsock-transport"),
+    all(target_os = "linux", feature = "shm-transport")
+))]
+mod decode;
+#[cfg(any(
+    feature = "tcp-transport",
+    all(unix, feature = "unix-transport"),
+    all(windows, feature = "named-pipe-transport"),
+    feature = "stdio-transport",
+    feature = "stream-transport",
+    feature = "tls-tcp-transport",
+    all(target_os = "linux", feature = "vsock-transport"),
+    all(target_os = "linux", feature = "shm-transport")
+))]
+mod framed;
+#[cfg(any(
+    feature = "quinn-transport",
+    feature = "hyper-transport",
+    feature = "iroh-transport",
+    feature = "libp2p-transport",
+    feature = "s2n-quic-transport"
+))]
 mod util;
+#[cfg(any(
+    feature = "quinn-transport",
+    feature = "hyper-transport",
+    feature = "iroh-transport",
+    feature = "libp2p-transport",
+    feature = "s2n-quic-transport",
+    feature = "bus-transport",
+    feature = "tcp-transport",
+    all(unix, feature = "unix-transport"),
+    all(windows, feature = "named-pipe-transport"),
+    feature = "stdio-transport",
+    feature = "stream-transport",
+    feature = "tls-tcp-transport",
+    all(target_os = "linux", feature = "vsock-transport"),
+    all(target_os = "linux", feature = "shm-transport")
+))]
+pub use decode::{DecodeError, Direction};
+#[cfg(all(
+    feature = "prost-codec",
+    any(
+        feature = "quinn-transport",
+        feature = "hyper-transport",
+        feature = "iroh-transport",
+        feature = "libp2p-transport",
+        feature = "s2n-quic-transport"
+    )
+))]
+pub use util::ProstCodec;
+#[cfg(any(
+    feature = "quinn-transport",
+    feature = "hyper-transport",
+    feature = "iroh-transport",
+    feature = "libp2p-transport",
+    feature = "s2n-quic-transport"
+))]
+pub use util::{Codec, CustomCodec, MessageCodec};
 
 /// Errors that can happen when creating and using a [`Connection`] or [`ServerEndpoint`].
 pub trait ConnectionErrors: Debug + Clone + Send + Sync + 'static {
@@ -27,6 +150,15 @@ pub trait ConnectionErrors: Debug + Clone + Send + Sync + 'static {
     type SendError: RpcError;
     /// Error when receiving a message via a channel
     type RecvError: RpcError;
+
+    /// How long the server asked the client to wait before retrying, if the
+    /// error is a [pushback](pushback::Pushback)
+    ///
+    /// Defaults to `None`. Wrappers that keep the receive error of the inner
+    /// connection forward to it.
+    fn retry_after(_error: &Self::RecvError) -> Option<Duration> {
+        None
+    }
 }
 
 /// Types that are common to both [`Connection`] and [`ServerEndpoint`].
@@ -37,6 +169,111 @@ pub trait ConnectionCommon<In, Out>: ConnectionErrors {
     type RecvStream: Stream<Item = Result<In, Self::RecvError>> + Send + Unpin + 'static;
     /// Send side of a bidirectional typed channel
     type SendSink: Sink<Out, Error = Self::SendError> + Send + Unpin + 'static;
+
+    /// The guarantees the transport gives for the messages of a channel
+    ///
+    /// Defaults to [`Capabilities::NONE`], so transports have to opt in to
+    /// every guarantee they give.
+    const CAPABILITIES: Capabilities = Capabilities::NONE;
+
+    /// Whether frames sent on this connection are compressed
+    ///
+    /// Defaults to `false`. Used by [strict mode](crate::strict) to refuse
+    /// connections without compression.
+    fn compressed(&self) -> bool {
+        false
+    }
+
+    /// The address of the remote end of the channel of `recv`, if known
+    ///
+    /// Defaults to `None`. Used for the [audit records](crate::audit) of calls.
+    fn peer_addr(_recv: &Self::RecvStream) -> Option<SocketAddr> {
+        None
+    }
+
+    /// The [extensions](crate::extensions) of the connection of the channel of
+    /// `recv`, if the transport has connections
+    ///
+    /// Defaults to `None`. Handlers get them from their
+    /// [CallContext](crate::context::CallContext).
+    fn extensions(_recv: &Self::RecvStream) -> Option<Extensions> {
+        None
+    }
+}
+
+/// The guarantees a transport gives for the messages of a channel
+///
+/// Generic code and wrapping transports can check these at compile time, by
+/// asserting on [`ConnectionCommon::CAPABILITIES`] in an associated const:
+///
+/// ```ignore
+/// impl<C: Connection<In, Out>, In, Out> Checked<C, In, Out> {
+///     const CHECK: () = assert!(C::CAPABILITIES.encrypted, "needs an encrypted transport");
+///
+///     fn new(inner: C) -> Self {
+///         // fails to compile for transports without encryption
+///         let () = Self::CHECK;
+///         ...
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Messages of a channel arrive in the order they were sent
+    pub ordered: bool,
+    /// Messages of a channel are not lost or duplicated
+    pub reliable: bool,
+    /// Messages can not be read or modified by third parties on the way,
+    /// because they are encrypted or never leave the process
+    pub encrypted: bool,
+    /// Channels are independent, so a slow channel does not hold up the others
+    pub multiplexed: bool,
+}
+
+impl Capabilities {
+    /// No guarantees at all
+    pub const NONE: Self = Self {
+        ordered: false,
+        reliable: false,
+        encrypted: false,
+        multiplexed: false,
+    };
+
+    /// All guarantees
+    pub const ALL: Self = Self {
+        ordered: true,
+        reliable: true,
+        encrypted: true,
+        multiplexed: true,
+    };
+
+    /// The guarantees that both `self` and `other` give
+    pub const fn and(self, other: Self) -> Self {
+        Self {
+            ordered: self.ordered && other.ordered,
+            reliable: self.reliable && other.reliable,
+            encrypted: self.encrypted && other.encrypted,
+            multiplexed: self.multiplexed && other.multiplexed,
+        }
+    }
+
+    /// Whether `self` gives all guarantees of `required`
+    pub const fn contains(self, required: Self) -> bool {
+        (self.ordered || !required.ordered)
+            && (self.reliable || !required.reliable)
+            && (self.encrypted || !required.encrypted)
+            && (self.multiplexed || !required.multiplexed)
+    }
+
+    /// The guarantees of `required` that `self` does not give
+    pub const fn missing(self, required: Self) -> Self {
+        Self {
+            ordered: required.ordered && !self.ordered,
+            reliable: required.reliable && !self.reliable,
+            encrypted: required.encrypted && !self.encrypted,
+            multiplexed: required.multiplexed && !self.multiplexed,
+        }
+    }
 }
 
 /// A connection to a specific remote machine
@@ -67,6 +304,28 @@ pub trait ServerEndpoint<In, Out>: ConnectionCommon<In, Out> {
     fn local_addr(&self) -> &[LocalAddr];
 }
 
+/// A [Connection] that can also send messages as unreliable datagrams
+///
+/// Datagrams are not part of a channel, so there is no response. They can be
+/// lost, duplicated or reordered, and have to fit into a single packet. This
+/// is for fire-and-forget messages like telemetry or position updates, where
+/// an occasional loss is fine and opening a channel per message is too
+/// expensive.
+pub trait DatagramConnection<In, Out>: Connection<In, Out> {
+    /// Send `msg` as a datagram, without waiting for it to arrive
+    fn send_datagram(&self, msg: Out) -> Result<(), Self::SendError>;
+}
+
+/// A [ServerEndpoint] that can also receive the datagrams sent by a
+/// [DatagramConnection]
+pub trait DatagramEndpoint<In, Out>: ServerEndpoint<In, Out> {
+    /// The future that will resolve to the next datagram or an error
+    type RecvDatagramFut: Future<Output = Result<In, Self::RecvError>> + Send;
+
+    /// Receive the next datagram from any of the connections
+    fn recv_datagram(&self) -> Self::RecvDatagramFut;
+}
+
 /// The kinds of local addresses a [ServerEndpoint] can be bound to.
 ///
 /// Returned by [ServerEndpoint::local_addr].
@@ -79,6 +338,17 @@ pub enum LocalAddr {
     Socket(SocketAddr),
     /// An in-memory address.
     Mem,
+    /// A path in the file system, e.g. of a unix domain socket.
+    Path(PathBuf),
+    /// A vsock context id and port.
+    Vsock {
+        /// The context id of the machine
+        cid: u32,
+        /// The port
+        port: u32,
+    },
+    /// A protocol of a multiplexed connection, e.g. a libp2p protocol.
+    Protocol(String),
 }
 
 impl Display for LocalAddr {
@@ -86,6 +356,9 @@ impl Display for LocalAddr {
         match self {
             LocalAddr::Socket(sockaddr) => write!(f, "{sockaddr}"),
             LocalAddr::Mem => write!(f, "mem"),
+            LocalAddr::Path(path) => write!(f, "{}", path.display()),
+            LocalAddr::Vsock { cid, port } => write!(f, "vsock:{cid}:{port}"),
+            LocalAddr::Protocol(protocol) => write!(f, "{protocol}"),
         }
     }
 }
@@ -0,0 +1,403 @@
+//! Transport over windows named pipes, for communication between local processes
+//!
+//! This is the windows counterpart of the unix socket transport, for a
+//! daemon that is controlled by a command line tool without opening a network
+//! port. Remote clients are rejected, and the server can check which process
+//! is calling with [RecvStream::peer_process_id].
+//!
+//! A [NamedPipeConnection] uses a single pipe connection to the server, and
+//! every channel is a logical stream on it, so all interaction patterns work.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::named_pipe::{NamedPipeConnection, NamedPipeServerEndpoint};
+//!
+//! let server = NamedPipeServerEndpoint::<u64, u64>::bind(r"\\.\pipe\daemon")?;
+//! let client = NamedPipeConnection::<u64, u64>::connect(r"\\.\pipe\daemon").await?;
+//! # Ok(())
+//! # }
+//! ```
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, SendError, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, Sink, Stream,
+};
+use serde::Serialize;
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, io,
+    marker::PhantomData,
+    os::windows::io::{AsRawHandle, RawHandle},
+    path::PathBuf,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    net::windows::named_pipe::{ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions},
+    task::JoinHandle,
+};
+
+/// All instances of the pipe are connected to other clients
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How long to wait before trying again to connect to a busy pipe
+const BUSY_RETRY: Duration = Duration::from_millis(50);
+
+mod ffi {
+    use std::os::windows::raw::HANDLE;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub fn GetNamedPipeClientProcessId(pipe: HANDLE, client_process_id: *mut u32) -> i32;
+        pub fn GetNamedPipeServerProcessId(pipe: HANDLE, server_process_id: *mut u32) -> i32;
+    }
+}
+
+type ProcessIdFn = unsafe extern "system" fn(std::os::windows::raw::HANDLE, *mut u32) -> i32;
+
+/// The id of the process on the other side of `pipe`, if windows reports it
+fn process_id(pipe: RawHandle, f: ProcessIdFn) -> Option<u32> {
+    let mut pid = 0;
+    // SAFETY: the handle belongs to a pipe that is open for the duration of the
+    // call, and the pointer is valid for writing an u32
+    let ok = unsafe { f(pipe as _, &mut pid) };
+    if ok != 0 {
+        Some(pid)
+    } else {
+        tracing::debug!(
+            "named pipe: peer process id not available: {}",
+            io::Error::last_os_error()
+        );
+        None
+    }
+}
+
+/// Send side of a channel
+pub struct SendSink<Out> {
+    inner: framed::SendSink<Out>,
+    peer: Option<u32>,
+}
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("inner", &self.inner)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    /// The id of the process on the other side of the pipe
+    ///
+    /// On a server, this is the client that made the call.
+    pub fn peer_process_id(&self) -> Option<u32> {
+        self.peer
+    }
+}
+
+impl<Out: Serialize> Sink<Out> for SendSink<Out> {
+    type Error = SendError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> result::Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Receive side of a channel
+pub struct RecvStream<In> {
+    inner: framed::RecvStream<In>,
+    peer: Option<u32>,
+}
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// The id of the process on the other side of the pipe
+    ///
+    /// On a server, this is the client that made the call.
+    pub fn peer_process_id(&self) -> Option<u32> {
+        self.peer
+    }
+
+    /// The extensions of the connection the channel was accepted on
+    ///
+    /// This is `None` on the client side.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.inner.extensions()
+    }
+}
+
+impl<In: RpcMessage> Stream for RecvStream<In> {
+    type Item = result::Result<In, RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+}
+
+/// A connection to a [NamedPipeServerEndpoint]
+pub struct NamedPipeConnection<In, Out> {
+    client: Arc<Client>,
+    peer: Option<u32>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for NamedPipeConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            peer: self.peer,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for NamedPipeConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedPipeConnection")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> NamedPipeConnection<In, Out> {
+    /// Connect to the server listening on the pipe `name`, e.g. `\\.\pipe\daemon`
+    ///
+    /// If all instances of the pipe are busy, this waits until one is free.
+    pub async fn connect(name: impl AsRef<OsStr>) -> io::Result<Self> {
+        let name = name.as_ref().to_owned();
+        loop {
+            match ClientOptions::new().open(&name) {
+                Ok(pipe) => break Ok(Self::from_pipe(pipe)),
+                Err(cause) if cause.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+                Err(cause) => break Err(cause),
+            }
+            tokio::time::sleep(BUSY_RETRY).await;
+        }
+    }
+
+    /// Use an established pipe connection to the server
+    ///
+    /// Once the connection is closed, opening channels fails and all open
+    /// channels end.
+    pub fn from_pipe(pipe: NamedPipeClient) -> Self {
+        let peer = process_id(pipe.as_raw_handle(), ffi::GetNamedPipeServerProcessId);
+        let (read, write) = tokio::io::split(pipe);
+        Self {
+            client: Arc::new(Client::new(read, write)),
+            peer,
+            _p: PhantomData,
+        }
+    }
+
+    /// The id of the server process
+    pub fn peer_process_id(&self) -> Option<u32> {
+        self.peer
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for NamedPipeConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for NamedPipeConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        // remote clients are rejected, so messages never leave the host, but
+        // they are not encrypted
+        encrypted: false,
+        multiplexed: true,
+    };
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for NamedPipeConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let peer = self.peer;
+        future::ready(self.client.open_bi().map(|(send, recv)| {
+            (
+                SendSink { inner: send, peer },
+                RecvStream { inner: recv, peer },
+            )
+        }))
+    }
+}
+
+struct ServerInner {
+    listener: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// A server endpoint that serves calls on the connections of a named pipe
+pub struct NamedPipeServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<Option<u32>>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for NamedPipeServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for NamedPipeServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedPipeServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+/// A new instance of the pipe `name`, for the next client to connect to
+fn instance(name: &OsStr, first: bool) -> io::Result<NamedPipeServer> {
+    ServerOptions::new()
+        .first_pipe_instance(first)
+        .reject_remote_clients(true)
+        .create(name)
+}
+
+impl<In: RpcMessage, Out: RpcMessage> NamedPipeServerEndpoint<In, Out> {
+    /// Listen for connections on the pipe `name`, e.g. `\\.\pipe\daemon`
+    ///
+    /// This fails if another process already created a pipe with that name.
+    /// Dropping all clones of the endpoint stops accepting connections.
+    /// Connections that were already accepted are served until they close.
+    pub fn bind(name: impl AsRef<OsStr>) -> io::Result<Self> {
+        let name: OsString = name.as_ref().to_owned();
+        let mut next = instance(&name, true)?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let local_addr = LocalAddr::Path(PathBuf::from(&name));
+        let listener = tokio::spawn(async move {
+            loop {
+                let connected = next.connect().await;
+                // the next client connects to a new instance
+                let pipe = match instance(&name, false) {
+                    Ok(pipe) => std::mem::replace(&mut next, pipe),
+                    Err(cause) => {
+                        tracing::warn!("named pipe: error creating instance: {}", cause);
+                        break;
+                    }
+                };
+                if let Err(cause) = connected {
+                    tracing::warn!("named pipe: error accepting connection: {}", cause);
+                    continue;
+                }
+                let peer = process_id(pipe.as_raw_handle(), ffi::GetNamedPipeClientProcessId);
+                tracing::debug!("named pipe: accepted connection from {:?}", peer);
+                let (read, write) = tokio::io::split(pipe);
+                tokio::spawn(framed::serve(read, write, peer, accept_tx.clone()));
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener,
+                local_addr: [local_addr],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for NamedPipeServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for NamedPipeServerEndpoint<In, Out>
+{
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        // remote clients are rejected, so messages never leave the host, but
+        // they are not encrypted
+        encrypted: false,
+        multiplexed: true,
+    };
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for NamedPipeServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, peer) = accepted.into_parts();
+            Ok((
+                SendSink { inner: send, peer },
+                RecvStream { inner: recv, peer },
+            ))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
@@ -0,0 +1,539 @@
+//! Ordered delivery of calls across independent substreams
+//!
+//! Substreams are independent, so calls that are issued concurrently can be
+//! accepted by the server in any order. [OrderedConnection] puts all calls of a
+//! client on an ordering lane and tags the first message of every call with a
+//! sequence number. [OrderedServerEndpoint] holds back calls until all calls
+//! with lower sequence numbers of the same lane have been accepted.
+//!
+//! The order of calls is the order in which [Connection::open_bi] is called,
+//! which for [crate::RpcClient] is the order in which the calls are first polled.
+//!
+//! If a call never arrives, e.g. because the client dropped it before sending
+//! the first message, the server skips it after [OrderedConfig::gap_timeout].
+//! Lanes without waiting calls are forgotten once they have been idle for that
+//! long, so the next call on such a lane may be held back for up to the same
+//! time before the calls it waits for are skipped.
+//!
+//! Both sides must use the wrappers, since they change the wire format of requests.
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Sink, Stream, StreamExt};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Wire format of requests on an ordered connection
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Ordered<T> {
+    /// The first message of a call on an ordering lane
+    Start {
+        /// Id of the lane
+        lane: u64,
+        /// Sequence number of the call within the lane, starting at 0
+        seq: u64,
+        /// The message
+        msg: T,
+    },
+    /// Any other message
+    Msg(T),
+}
+
+impl<T> Ordered<T> {
+    /// Get the message, discarding ordering information
+    pub fn into_msg(self) -> T {
+        match self {
+            Ordered::Start { msg, .. } => msg,
+            Ordered::Msg(msg) => msg,
+        }
+    }
+}
+
+/// A random id for a new lane
+fn random_lane_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
+}
+
+#[derive(Debug)]
+struct Lane {
+    id: u64,
+    next: AtomicU64,
+}
+
+impl Lane {
+    fn new() -> Self {
+        Self {
+            id: random_lane_id(),
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A connection that puts all calls on an ordering lane
+///
+/// Clones share the lane. Use [OrderedConnection::new_lane] to get a connection
+/// for calls that do not need to be ordered relative to the calls on this one.
+pub struct OrderedConnection<C, In, Out> {
+    inner: C,
+    lane: Arc<Lane>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C, In, Out> OrderedConnection<C, In, Out>
+where
+    C: Connection<In, Ordered<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a connection, creating a new lane
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            lane: Arc::new(Lane::new()),
+            _p: PhantomData,
+        }
+    }
+
+    /// Create a connection that shares the underlying connection, with a new lane
+    pub fn new_lane(&self) -> Self {
+        Self::new(self.inner.clone())
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for OrderedConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            lane: self.lane.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for OrderedConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedConnection")
+            .field("inner", &self.inner)
+            .field("lane", &self.lane.id)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for OrderedConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C, In, Out> ConnectionCommon<In, Out> for OrderedConnection<C, In, Out>
+where
+    C: Connection<In, Ordered<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = C::RecvStream;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C, In, Out> Connection<In, Out> for OrderedConnection<C, In, Out>
+where
+    C: Connection<In, Ordered<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        // the sequence number is taken when the call is issued, not when it is opened
+        let start = (self.lane.id, self.lane.next.fetch_add(1, Ordering::SeqCst));
+        let inner = self.inner.clone();
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let send = SendSink {
+                inner: send,
+                start: Some(start),
+                _p: PhantomData,
+            };
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+/// Send sink for ordered connections
+///
+/// Tags the first message with the lane and sequence number.
+#[pin_project]
+pub struct SendSink<C: ConnectionCommon<In, Ordered<Out>>, In, Out> {
+    #[pin]
+    inner: C::SendSink,
+    start: Option<(u64, u64)>,
+    _p: PhantomData<Out>,
+}
+
+impl<C: ConnectionCommon<In, Ordered<Out>>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Ordered<Out>>, In, Out> Sink<Out> for SendSink<C, In, Out> {
+    type Error = C::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Out) -> Result<(), Self::Error> {
+        let this = self.project();
+        let item = match this.start.take() {
+            Some((lane, seq)) => Ordered::Start { lane, seq, msg },
+            None => Ordered::Msg(msg),
+        };
+        this.inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Configuration for an [OrderedServerEndpoint]
+#[derive(Debug, Clone)]
+pub struct OrderedConfig {
+    /// How long to hold back calls waiting for a missing call before skipping it
+    pub gap_timeout: Duration,
+    /// The maximum number of lanes that are tracked at the same time
+    ///
+    /// When the limit is reached, the least recently used lane without waiting
+    /// calls is forgotten. If all lanes have waiting calls, calls on new lanes
+    /// are accepted immediately.
+    pub max_lanes: usize,
+}
+
+impl Default for OrderedConfig {
+    fn default() -> Self {
+        Self {
+            gap_timeout: Duration::from_secs(1),
+            max_lanes: 4096,
+        }
+    }
+}
+
+type Accepted<E, In, Out> = (
+    <E as ConnectionCommon<Ordered<In>, Out>>::SendSink,
+    RecvStream<E, In, Out>,
+);
+
+/// State of a lane on the server side
+struct LaneState<T> {
+    /// The next sequence number to accept
+    next: u64,
+    /// Calls that arrived early, with the time they arrived
+    pending: BTreeMap<u64, (Instant, T)>,
+    /// The time the last call on this lane arrived
+    active: Instant,
+}
+
+impl<T> Default for LaneState<T> {
+    fn default() -> Self {
+        Self {
+            next: 0,
+            pending: BTreeMap::new(),
+            active: Instant::now(),
+        }
+    }
+}
+
+/// Reorders accepted calls
+struct Dispatcher<T> {
+    lanes: HashMap<u64, LaneState<T>>,
+    ready: VecDeque<T>,
+}
+
+impl<T> Dispatcher<T> {
+    fn insert(&mut self, lane: u64, seq: u64, item: T, config: &OrderedConfig) {
+        if !self.lanes.contains_key(&lane) {
+            self.forget_idle(config.gap_timeout);
+            if self.lanes.len() >= config.max_lanes {
+                let oldest = self
+                    .lanes
+                    .iter()
+                    .filter(|(_, state)| state.pending.is_empty())
+                    .min_by_key(|(_, state)| state.active)
+                    .map(|(id, _)| *id);
+                match oldest {
+                    Some(id) => {
+                        self.lanes.remove(&id);
+                    }
+                    None => {
+                        tracing::debug!("too many lanes, accepting call without ordering");
+                        self.ready.push_back(item);
+                        return;
+                    }
+                }
+            }
+        }
+        let state = self.lanes.entry(lane).or_default();
+        state.active = Instant::now();
+        if seq < state.next {
+            // we skipped this call, so just accept it now
+            self.ready.push_back(item);
+            return;
+        }
+        state.pending.insert(seq, (Instant::now(), item));
+        Self::release(state, &mut self.ready);
+    }
+
+    /// Move all calls that are next in line to the ready queue
+    fn release(state: &mut LaneState<T>, ready: &mut VecDeque<T>) {
+        while let Some((_, item)) = state.pending.remove(&state.next) {
+            ready.push_back(item);
+            state.next += 1;
+        }
+    }
+
+    /// The time at which the oldest waiting call exceeds the timeout
+    fn deadline(&self, gap_timeout: Duration) -> Option<Instant> {
+        self.lanes
+            .values()
+            .flat_map(|lane| lane.pending.values().map(|(t, _)| *t))
+            .min()
+            .map(|t| t + gap_timeout)
+    }
+
+    /// Skip missing calls on lanes where calls have waited for too long
+    fn skip_gaps(&mut self, gap_timeout: Duration) {
+        let now = Instant::now();
+        for state in self.lanes.values_mut() {
+            while state
+                .pending
+                .values()
+                .any(|(t, _)| now.duration_since(*t) >= gap_timeout)
+            {
+                if let Some(first) = state.pending.keys().next() {
+                    tracing::debug!("skipping calls {}..{} on lane", state.next, first);
+                    state.next = *first;
+                }
+                Self::release(state, &mut self.ready);
+            }
+        }
+        self.forget_idle(gap_timeout);
+    }
+
+    /// Forget lanes without waiting calls that have been idle for too long
+    fn forget_idle(&mut self, gap_timeout: Duration) {
+        let now = Instant::now();
+        self.lanes.retain(|_, state| {
+            !state.pending.is_empty() || now.duration_since(state.active) < gap_timeout
+        });
+    }
+}
+
+/// A server endpoint that accepts calls in lane order
+///
+/// Calls that are not on a lane are accepted immediately.
+pub struct OrderedServerEndpoint<E: ConnectionCommon<Ordered<In>, Out>, In, Out> {
+    inner: E,
+    config: OrderedConfig,
+    dispatcher: Arc<tokio::sync::Mutex<Dispatcher<Accepted<E, In, Out>>>>,
+}
+
+impl<E, In, Out> OrderedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Ordered<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a server endpoint
+    pub fn new(inner: E, config: OrderedConfig) -> Self {
+        Self {
+            inner,
+            config,
+            dispatcher: Arc::new(tokio::sync::Mutex::new(Dispatcher {
+                lanes: HashMap::new(),
+                ready: VecDeque::new(),
+            })),
+        }
+    }
+}
+
+impl<E: ConnectionCommon<Ordered<In>, Out>, In, Out> Clone for OrderedServerEndpoint<E, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            dispatcher: self.dispatcher.clone(),
+        }
+    }
+}
+
+impl<E: ConnectionCommon<Ordered<In>, Out>, In, Out> fmt::Debug
+    for OrderedServerEndpoint<E, In, Out>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OrderedServerEndpoint")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<E, In, Out> ConnectionErrors for OrderedServerEndpoint<E, In, Out>
+where
+    E: ConnectionCommon<Ordered<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenError = E::OpenError;
+    type SendError = E::SendError;
+    type RecvError = E::RecvError;
+}
+
+impl<E, In, Out> ConnectionCommon<In, Out> for OrderedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Ordered<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = E::SendSink;
+    type RecvStream = self::RecvStream<E, In, Out>;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+}
+
+impl<E, In, Out> ServerEndpoint<In, Out> for OrderedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Ordered<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        let gap_timeout = config.gap_timeout;
+        let dispatcher = self.dispatcher.clone();
+        async move {
+            let mut dispatcher = dispatcher.lock().await;
+            loop {
+                if let Some(item) = dispatcher.ready.pop_front() {
+                    return Ok(item);
+                }
+                let accepted = match dispatcher.deadline(gap_timeout) {
+                    Some(deadline) => tokio::select! {
+                        res = inner.accept_bi() => Some(res),
+                        _ = tokio::time::sleep_until(deadline.into()) => None,
+                    },
+                    None => Some(inner.accept_bi().await),
+                };
+                let (send, mut recv) = match accepted {
+                    Some(res) => res?,
+                    None => {
+                        dispatcher.skip_gaps(gap_timeout);
+                        continue;
+                    }
+                };
+                match recv.next().await {
+                    Some(Ok(Ordered::Start { lane, seq, msg })) => {
+                        let recv = RecvStream::new(msg, recv);
+                        dispatcher.insert(lane, seq, (send, recv), &config);
+                    }
+                    Some(Ok(Ordered::Msg(msg))) => {
+                        return Ok((send, RecvStream::new(msg, recv)));
+                    }
+                    Some(Err(cause)) => {
+                        tracing::debug!("error reading first message: {}", cause);
+                    }
+                    None => {
+                        tracing::debug!("substream closed before the first message");
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Receive stream for ordered server endpoints
+///
+/// Yields the first message, which was already read to get the sequence
+/// number, followed by the remaining messages.
+#[pin_project]
+pub struct RecvStream<E: ConnectionCommon<Ordered<In>, Out>, In, Out> {
+    first: Option<In>,
+    #[pin]
+    inner: E::RecvStream,
+}
+
+impl<E: ConnectionCommon<Ordered<In>, Out>, In, Out> RecvStream<E, In, Out> {
+    fn new(first: In, inner: E::RecvStream) -> Self {
+        Self {
+            first: Some(first),
+            inner,
+        }
+    }
+}
+
+impl<E: ConnectionCommon<Ordered<In>, Out>, In, Out> fmt::Debug for RecvStream<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<E: ConnectionCommon<Ordered<In>, Out>, In: Unpin, Out> Stream for RecvStream<E, In, Out> {
+    type Item = result::Result<In, E::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if let Some(first) = this.first.take() {
+            return Poll::Ready(Some(Ok(first)));
+        }
+        this.inner
+            .poll_next(cx)
+            .map(|item| item.map(|res| res.map(Ordered::into_msg)))
+    }
+}
@@ -0,0 +1,375 @@
+//! Transport wrapper that spreads calls over a pool of connections
+//!
+//! [PoolConnection] opens connections on demand with a connect function, up to
+//! [PoolConfig::max_connections]. A call uses the connection with the fewest
+//! calls in flight, and a new connection is only opened once every connection
+//! has [PoolConfig::max_calls] calls in flight.
+//!
+//! Connections without calls for [PoolConfig::idle_timeout] are closed, down
+//! to [PoolConfig::min_connections], so a long lived process does not keep
+//! idle connections to every endpoint it ever talked to. They are reopened
+//! when calls need them again.
+//!
+//! How well connections are reused can be checked with [PoolConnection::stats].
+use super::{Capabilities, Connection, ConnectionCommon, ConnectionErrors};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Sink, Stream};
+use pin_project::pin_project;
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Configuration for a [PoolConnection]
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// The maximum number of connections
+    pub max_connections: usize,
+    /// The number of calls in flight on every connection before another one is opened
+    ///
+    /// Once [PoolConfig::max_connections] are open, calls go to the least busy
+    /// connection, even if it has more calls in flight.
+    pub max_calls: usize,
+    /// The number of connections that are kept open, even when idle
+    pub min_connections: usize,
+    /// Connections without calls for this long are closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 4,
+            max_calls: 64,
+            min_connections: 1,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// How the connections of a [PoolConnection] were used
+///
+/// Counters are totals since the pool was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Calls that used a connection that was already open
+    pub hits: u64,
+    /// Calls that had to open a new connection
+    pub misses: u64,
+    /// Hits on a connection that had no calls in flight, but served calls before
+    pub reuses: u64,
+    /// Connections that were opened
+    pub opened: u64,
+    /// Connections that were closed because they were idle
+    pub closed: u64,
+    /// The number of open connections
+    pub connections: usize,
+    /// The number of calls in flight, over all connections
+    pub in_flight: usize,
+}
+
+/// The calls on a connection of the pool
+#[derive(Debug)]
+struct Activity {
+    in_flight: AtomicUsize,
+    last_used: Mutex<Instant>,
+}
+
+/// Keeps a call counted as in flight, shared by the send and receive side
+#[derive(Debug)]
+struct CallGuard(Arc<Activity>);
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        // idle time counts from the end of the last call
+        *self.0.last_used.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Member<C> {
+    connection: C,
+    activity: Arc<Activity>,
+}
+
+impl<C> Member<C> {
+    fn in_flight(&self) -> usize {
+        self.activity.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+type Connect<C> = Box<dyn Fn() -> C + Send + Sync>;
+
+struct Inner<C> {
+    config: PoolConfig,
+    connect: Connect<C>,
+    members: Mutex<Vec<Member<C>>>,
+    stats: Mutex<PoolStats>,
+}
+
+impl<C: Clone> Inner<C> {
+    /// Pick a connection for a call, opening one if needed
+    fn checkout(&self) -> (C, Arc<CallGuard>) {
+        let mut members = self.members.lock().unwrap();
+        let mut stats = self.stats.lock().unwrap();
+        let least_busy = members
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, member)| member.in_flight())
+            .map(|(i, member)| (i, member.in_flight()));
+        let index = match least_busy {
+            Some((i, in_flight))
+                if in_flight < self.config.max_calls
+                    || members.len() >= self.config.max_connections.max(1) =>
+            {
+                stats.hits += 1;
+                if in_flight == 0 {
+                    stats.reuses += 1;
+                }
+                i
+            }
+            _ => {
+                stats.misses += 1;
+                stats.opened += 1;
+                members.push(Member {
+                    connection: (self.connect)(),
+                    activity: Arc::new(Activity {
+                        in_flight: AtomicUsize::new(0),
+                        last_used: Mutex::new(Instant::now()),
+                    }),
+                });
+                members.len() - 1
+            }
+        };
+        let member = &members[index];
+        member.activity.in_flight.fetch_add(1, Ordering::SeqCst);
+        *member.activity.last_used.lock().unwrap() = Instant::now();
+        let guard = Arc::new(CallGuard(member.activity.clone()));
+        (member.connection.clone(), guard)
+    }
+
+    /// Close connections that are idle for longer than the idle timeout
+    fn shrink(&self) {
+        let mut members = self.members.lock().unwrap();
+        let now = Instant::now();
+        let mut closed = 0;
+        let mut i = 0;
+        while i < members.len() && members.len() > self.config.min_connections {
+            let member = &members[i];
+            let idle = now.saturating_duration_since(*member.activity.last_used.lock().unwrap());
+            if member.in_flight() == 0 && idle >= self.config.idle_timeout {
+                // dropping the connection closes it, once the last call is done
+                members.remove(i);
+                closed += 1;
+            } else {
+                i += 1;
+            }
+        }
+        drop(members);
+        if closed > 0 {
+            tracing::debug!("pool: closed {} idle connections", closed);
+            self.stats.lock().unwrap().closed += closed;
+        }
+    }
+}
+
+/// A connection that spreads calls over a pool of connections
+///
+/// Clones share the same pool.
+pub struct PoolConnection<C, In, Out> {
+    inner: Arc<Inner<C>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> PoolConnection<C, In, Out> {
+    /// Create a pool that opens connections with `connect`
+    ///
+    /// No connection is opened before the first call. Idle connections are
+    /// closed by a task that runs until all clones of the pool are dropped.
+    pub fn new(config: PoolConfig, connect: impl Fn() -> C + Send + Sync + 'static) -> Self {
+        let interval = (config.idle_timeout / 2).max(Duration::from_millis(10));
+        let inner = Arc::new(Inner {
+            config,
+            connect: Box::new(connect),
+            members: Default::default(),
+            stats: Default::default(),
+        });
+        tokio::spawn(Self::shrink_task(Arc::downgrade(&inner), interval));
+        Self {
+            inner,
+            _p: PhantomData,
+        }
+    }
+
+    async fn shrink_task(inner: Weak<Inner<C>>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            match inner.upgrade() {
+                Some(inner) => inner.shrink(),
+                None => break,
+            }
+        }
+    }
+
+    /// Close the connections that are idle for longer than the idle timeout now
+    pub fn shrink(&self) {
+        self.inner.shrink()
+    }
+
+    /// How the connections of the pool were used so far
+    pub fn stats(&self) -> PoolStats {
+        let members = self.inner.members.lock().unwrap();
+        let mut stats = *self.inner.stats.lock().unwrap();
+        stats.connections = members.len();
+        stats.in_flight = members.iter().map(Member::in_flight).sum();
+        stats
+    }
+}
+
+impl<C, In, Out> Clone for PoolConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C, In, Out> fmt::Debug for PoolConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolConnection")
+            .field("config", &self.inner.config)
+            .field("stats", &*self.inner.stats.lock().unwrap())
+            .finish()
+    }
+}
+
+/// Send sink for pool connections
+///
+/// Keeps the call counted as in flight until it is dropped.
+#[pin_project]
+pub struct SendSink<C: ConnectionCommon<In, Out>, In, Out> {
+    #[pin]
+    inner: C::SendSink,
+    _guard: Arc<CallGuard>,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Sink<Out> for SendSink<C, In, Out> {
+    type Error = C::SendError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> result::Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Receive stream for pool connections
+///
+/// Keeps the call counted as in flight until it is dropped.
+#[pin_project]
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out> {
+    #[pin]
+    inner: C::RecvStream,
+    _guard: Arc<CallGuard>,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, C::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+/// Future returned by open_bi
+pub type OpenBiFuture<C, In, Out> = BoxFuture<
+    'static,
+    result::Result<
+        (SendSink<C, In, Out>, RecvStream<C, In, Out>),
+        <C as ConnectionErrors>::OpenError,
+    >,
+>;
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for PoolConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<std::time::Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for PoolConnection<C, In, Out>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for PoolConnection<C, In, Out>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let (connection, guard) = self.inner.checkout();
+        async move {
+            let (send, recv) = connection.open_bi().await?;
+            let send = SendSink {
+                inner: send,
+                _guard: guard.clone(),
+            };
+            let recv = RecvStream {
+                inner: recv,
+                _guard: guard,
+            };
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
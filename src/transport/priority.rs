@@ -0,0 +1,404 @@
+//! Transport wrapper that schedules opening substreams by priority
+//!
+//! [PriorityConnection] limits the number of substreams that can be in flight at
+//! the same time. When the limit is reached, calls wait in a queue and are
+//! admitted according to their [Priority], so interactive calls can jump ahead
+//! of queued bulk calls.
+//!
+//! To protect low priority calls from starvation, waiting calls age: every
+//! [PriorityConfig::aging_step] a call waits counts as one priority level, so a
+//! low priority call that has waited long enough will be admitted before newly
+//! arriving high priority calls.
+use super::{Capabilities, Connection, ConnectionCommon, ConnectionErrors};
+use crate::RpcMessage;
+use futures::{channel::oneshot, future::BoxFuture, Future, FutureExt, Sink, Stream};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Priority of a call
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum Priority {
+    /// Bulk transfers that can wait
+    Low,
+    /// Normal calls
+    #[default]
+    Normal,
+    /// Interactive calls that should be served first
+    High,
+}
+
+impl Priority {
+    fn level(self) -> i128 {
+        match self {
+            Priority::Low => 0,
+            Priority::Normal => 1,
+            Priority::High => 2,
+        }
+    }
+}
+
+/// Configuration for a [PriorityConnection]
+#[derive(Debug, Clone)]
+pub struct PriorityConfig {
+    /// The maximum number of substreams that can be in flight at the same time
+    ///
+    /// Must be at least 1, since no substream could ever be opened otherwise.
+    pub max_concurrent: usize,
+    /// The time a waiting call needs to wait to gain one priority level
+    pub aging_step: Duration,
+}
+
+impl Default for PriorityConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 64,
+            aging_step: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Waiter {
+    priority: Priority,
+    enqueued: Instant,
+    seq: u64,
+    sender: oneshot::Sender<()>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_use: usize,
+    waiters: Vec<Waiter>,
+    next_seq: u64,
+}
+
+/// A semaphore that grants permits by priority, with aging
+#[derive(Debug)]
+struct Limiter {
+    config: PriorityConfig,
+    /// Base for computing the virtual time of waiters
+    base: Instant,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    fn new(config: PriorityConfig) -> Self {
+        Self {
+            config,
+            base: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// The virtual enqueue time of a waiter, lower is served first
+    fn key(&self, waiter: &Waiter) -> (i128, u64) {
+        let enqueued = waiter.enqueued.duration_since(self.base).as_nanos() as i128;
+        let boost = self.config.aging_step.as_nanos() as i128 * waiter.priority.level();
+        (enqueued - boost, waiter.seq)
+    }
+
+    async fn acquire(self: Arc<Self>, priority: Priority) -> Permit {
+        let receiver = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_use < self.config.max_concurrent && state.waiters.is_empty() {
+                state.in_use += 1;
+                return Permit(self.clone());
+            }
+            let (sender, receiver) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiters.push(Waiter {
+                priority,
+                enqueued: Instant::now(),
+                seq,
+                sender,
+            });
+            receiver
+        };
+        Wait {
+            limiter: self,
+            receiver: Some(receiver),
+        }
+        .await
+    }
+
+    /// Hand the permit to the best waiter, or return it if there is none
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        // waiters that gave up waiting can be removed right away
+        state.waiters.retain(|w| !w.sender.is_canceled());
+        while !state.waiters.is_empty() {
+            let (index, _) = state
+                .waiters
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, w)| self.key(w))
+                .expect("waiters is not empty");
+            let waiter = state.waiters.swap_remove(index);
+            if waiter.sender.send(()).is_ok() {
+                return;
+            }
+        }
+        state.in_use -= 1;
+    }
+}
+
+/// Future waiting for a permit to be handed over
+///
+/// If this is dropped after the permit was handed over, the permit is released.
+struct Wait {
+    limiter: Arc<Limiter>,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Future for Wait {
+    type Output = Permit;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let receiver = this.receiver.as_mut().expect("polled after completion");
+        // the sender is only dropped after sending, so this can not fail
+        let _ = futures::ready!(receiver.poll_unpin(cx));
+        this.receiver = None;
+        Poll::Ready(Permit(this.limiter.clone()))
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            if let Ok(Some(())) = receiver.try_recv() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+/// A permit for one substream, released when dropped
+#[derive(Debug)]
+struct Permit(Arc<Limiter>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A connection that admits substreams by priority
+///
+/// All clones of a connection share the same limit. Use
+/// [PriorityConnection::with_priority] to create clones that open substreams
+/// with a different priority, e.g. one for interactive and one for bulk calls.
+pub struct PriorityConnection<C, In, Out> {
+    inner: C,
+    limiter: Arc<Limiter>,
+    priority: Priority,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> PriorityConnection<C, In, Out> {
+    /// Wrap a connection, using normal priority for calls
+    ///
+    /// # Panics
+    ///
+    /// If [PriorityConfig::max_concurrent] is 0.
+    pub fn new(inner: C, config: PriorityConfig) -> Self {
+        assert!(
+            config.max_concurrent > 0,
+            "max_concurrent must be at least 1"
+        );
+        Self {
+            inner,
+            limiter: Arc::new(Limiter::new(config)),
+            priority: Priority::default(),
+            _p: PhantomData,
+        }
+    }
+
+    /// Create a clone of this connection that opens substreams with the given priority
+    ///
+    /// The clone shares the limit with this connection.
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            priority,
+            _p: PhantomData,
+        }
+    }
+
+    /// The priority used for substreams opened by this connection
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for PriorityConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            priority: self.priority,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for PriorityConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PriorityConnection")
+            .field("inner", &self.inner)
+            .field("priority", &self.priority)
+            .finish()
+    }
+}
+
+/// Send sink for priority connections
+///
+/// Keeps the permit alive until both sink and stream are dropped.
+#[pin_project]
+pub struct SendSink<C: ConnectionCommon<In, Out>, In, Out> {
+    #[pin]
+    inner: C::SendSink,
+    _permit: Arc<Permit>,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for SendSink<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> SendSink<C, In, Out> {
+    /// Get the underlying sink of the wrapped connection
+    ///
+    /// Note that this releases the permit once the matching stream is dropped.
+    pub fn into_inner(self) -> C::SendSink {
+        self.inner
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Sink<Out> for SendSink<C, In, Out> {
+    type Error = C::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        self.project().inner.start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Receive stream for priority connections
+///
+/// Keeps the permit alive until both sink and stream are dropped.
+#[pin_project]
+pub struct RecvStream<C: ConnectionCommon<In, Out>, In, Out> {
+    #[pin]
+    inner: C::RecvStream,
+    _permit: Arc<Permit>,
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> RecvStream<C, In, Out> {
+    /// Get the underlying stream of the wrapped connection
+    ///
+    /// Note that this releases the permit once the matching sink is dropped.
+    pub fn into_inner(self) -> C::RecvStream {
+        self.inner
+    }
+}
+
+impl<C: ConnectionCommon<In, Out>, In, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, C::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+/// Future returned by open_bi
+pub type OpenBiFuture<C, In, Out> = BoxFuture<
+    'static,
+    result::Result<
+        (SendSink<C, In, Out>, RecvStream<C, In, Out>),
+        <C as ConnectionErrors>::OpenError,
+    >,
+>;
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for PriorityConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = C::RecvError;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        C::retry_after(error)
+    }
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for PriorityConnection<C, In, Out>
+{
+    type SendSink = self::SendSink<C, In, Out>;
+    type RecvStream = self::RecvStream<C, In, Out>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C: Connection<In, Out>, In: RpcMessage, Out: RpcMessage> Connection<In, Out>
+    for PriorityConnection<C, In, Out>
+{
+    type OpenBiFut = OpenBiFuture<C, In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let limiter = self.limiter.clone();
+        let priority = self.priority;
+        async move {
+            let permit = Arc::new(limiter.acquire(priority).await);
+            let (send, recv) = inner.open_bi().await?;
+            Ok((
+                SendSink {
+                    inner: send,
+                    _permit: permit.clone(),
+                },
+                RecvStream {
+                    inner: recv,
+                    _permit: permit,
+                },
+            ))
+        }
+        .boxed()
+    }
+}
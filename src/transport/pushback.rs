@@ -0,0 +1,505 @@
+//! Typed "server busy, retry after" responses
+//!
+//! A server that sheds load or limits the rate of calls needs to tell clients
+//! when to come back. [PushbackServerEndpoint] answers calls it does not admit
+//! with a [Pushback] instead of a response, and handlers can push back calls
+//! themselves with [ServerSendSink::push_back]. On the client side,
+//! [PushbackConnection] turns the pushback into a [RecvError::Pushback], and
+//! [RpcClientError::retry_after](crate::client::RpcClientError::retry_after)
+//! gets the delay out of the error of a call, so retry loops can sleep for as
+//! long as the server asked for. [UploadClient](crate::upload::UploadClient)
+//! does this on its own.
+//!
+//! Both sides must use the wrappers, since they change the wire format of responses.
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// The server is busy, and asks the client to retry the call later
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pushback {
+    /// How long the client should wait before retrying the call
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Pushback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server busy, retry after {:?}", self.retry_after)
+    }
+}
+
+impl error::Error for Pushback {}
+
+/// Wire format of responses on a connection that supports pushback
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply<T> {
+    /// A response
+    Msg(T),
+    /// The call was pushed back, no further responses follow
+    Pushback(Pushback),
+}
+
+/// A connection that understands pushback responses
+pub struct PushbackConnection<C, In, Out> {
+    inner: C,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C, In, Out> PushbackConnection<C, In, Out>
+where
+    C: Connection<Reply<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a connection
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            _p: PhantomData,
+        }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for PushbackConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for PushbackConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushbackConnection")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for PushbackConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = C::SendError;
+    type RecvError = RecvError<C::RecvError>;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        match error {
+            RecvError::Pushback(pushback) => Some(pushback.retry_after),
+            RecvError::Inner(cause) => C::retry_after(cause),
+        }
+    }
+}
+
+impl<C, In, Out> ConnectionCommon<In, Out> for PushbackConnection<C, In, Out>
+where
+    C: Connection<Reply<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = C::SendSink;
+    type RecvStream = self::RecvStream<C, In, Out>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C, In, Out> Connection<In, Out> for PushbackConnection<C, In, Out>
+where
+    C: Connection<Reply<In>, Out>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let recv = RecvStream {
+                inner: recv,
+                done: false,
+                _p: PhantomData,
+            };
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+/// Receive stream for pushback connections
+///
+/// Yields a [RecvError::Pushback] if the server pushed back the call, and ends
+/// after it.
+#[pin_project]
+pub struct RecvStream<C: ConnectionCommon<Reply<In>, Out>, In, Out> {
+    #[pin]
+    inner: C::RecvStream,
+    done: bool,
+    _p: PhantomData<In>,
+}
+
+impl<C: ConnectionCommon<Reply<In>, Out>, In, Out> fmt::Debug for RecvStream<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").finish()
+    }
+}
+
+impl<C: ConnectionCommon<Reply<In>, Out>, In, Out> Stream for RecvStream<C, In, Out> {
+    type Item = result::Result<In, RecvError<C::RecvError>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        if *this.done {
+            return Poll::Ready(None);
+        }
+        let item = futures::ready!(this.inner.poll_next(cx));
+        Poll::Ready(item.map(|res| match res {
+            Ok(Reply::Msg(msg)) => Ok(msg),
+            Ok(Reply::Pushback(pushback)) => {
+                *this.done = true;
+                Err(RecvError::Pushback(pushback))
+            }
+            Err(cause) => Err(RecvError::Inner(cause)),
+        }))
+    }
+}
+
+/// Error when receiving from a [PushbackConnection]
+#[derive(Debug, Clone)]
+pub enum RecvError<E> {
+    /// The server pushed back the call
+    Pushback(Pushback),
+    /// Receiving from the inner connection failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for RecvError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for RecvError<E> {}
+
+/// A limit on the rate of calls
+///
+/// Allows bursts of up to `calls` calls, and refills at a rate of `calls` per
+/// `per`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of calls
+    pub calls: u32,
+    /// The period in which `calls` calls are allowed
+    pub per: Duration,
+}
+
+/// Configuration for a [PushbackServerEndpoint]
+#[derive(Debug, Clone)]
+pub struct PushbackConfig {
+    /// The maximum number of calls in flight, further calls are pushed back
+    pub max_concurrent: Option<usize>,
+    /// The maximum rate of calls, further calls are pushed back until the
+    /// rate allows them again
+    pub rate_limit: Option<RateLimit>,
+    /// The delay clients are asked to wait when too many calls are in flight
+    pub retry_after: Duration,
+}
+
+impl Default for PushbackConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: None,
+            rate_limit: None,
+            retry_after: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Token bucket for a [RateLimit]
+#[derive(Debug)]
+struct Bucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.calls as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return the time until the next one is available
+    fn take(&mut self) -> result::Result<(), Duration> {
+        let capacity = self.limit.calls.max(1) as f64;
+        let per_token = self.limit.per.as_secs_f64() / capacity;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        if per_token > 0.0 {
+            self.tokens = (self.tokens + elapsed / per_token).min(capacity);
+        } else {
+            self.tokens = capacity;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) * per_token))
+        }
+    }
+}
+
+/// A permit for one call, released when dropped
+#[derive(Debug)]
+struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A server endpoint that pushes back calls it is too busy for
+///
+/// All clones share the same limits.
+pub struct PushbackServerEndpoint<E, In, Out> {
+    inner: E,
+    config: PushbackConfig,
+    in_flight: Arc<AtomicUsize>,
+    bucket: Option<Arc<Mutex<Bucket>>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<E, In, Out> PushbackServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<In, Reply<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a server endpoint
+    pub fn new(inner: E, config: PushbackConfig) -> Self {
+        let bucket = config
+            .rate_limit
+            .map(|limit| Arc::new(Mutex::new(Bucket::new(limit))));
+        Self {
+            inner,
+            config,
+            in_flight: Default::default(),
+            bucket,
+            _p: PhantomData,
+        }
+    }
+
+    /// The number of calls that are currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Clone, In, Out> Clone for PushbackServerEndpoint<E, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            in_flight: self.in_flight.clone(),
+            bucket: self.bucket.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<E: fmt::Debug, In, Out> fmt::Debug for PushbackServerEndpoint<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushbackServerEndpoint")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+impl<E: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for PushbackServerEndpoint<E, In, Out>
+{
+    type OpenError = E::OpenError;
+    type SendError = E::SendError;
+    type RecvError = E::RecvError;
+}
+
+impl<E, In, Out> ConnectionCommon<In, Out> for PushbackServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<In, Reply<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::ServerSendSink<E, In, Out>;
+    type RecvStream = self::ServerRecvStream<E, In, Out>;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+}
+
+impl<E, In, Out> ServerEndpoint<In, Out> for PushbackServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<In, Reply<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        let in_flight = self.in_flight.clone();
+        let bucket = self.bucket.clone();
+        async move {
+            loop {
+                let (mut send, mut recv) = inner.accept_bi().await?;
+                let busy = config
+                    .max_concurrent
+                    .map_or(false, |max| in_flight.load(Ordering::SeqCst) >= max);
+                let retry_after = if busy {
+                    Some(config.retry_after)
+                } else {
+                    bucket
+                        .as_ref()
+                        .and_then(|bucket| bucket.lock().unwrap().take().err())
+                };
+                let retry_after = match retry_after {
+                    Some(retry_after) => retry_after,
+                    None => {
+                        in_flight.fetch_add(1, Ordering::SeqCst);
+                        let permit = Arc::new(Permit(in_flight));
+                        let send = ServerSendSink {
+                            inner: send,
+                            _permit: permit.clone(),
+                        };
+                        let recv = ServerRecvStream {
+                            inner: recv,
+                            _permit: permit,
+                        };
+                        return Ok((send, recv));
+                    }
+                };
+                // read the request first, so the client is done sending it
+                // before the substream goes away
+                if let Some(Err(cause)) = recv.next().await {
+                    tracing::debug!("error reading request of pushed back call: {}", cause);
+                    continue;
+                }
+                tracing::debug!("pushing back call, retry after {:?}", retry_after);
+                let pushback = Reply::Pushback(Pushback { retry_after });
+                if let Err(cause) = send.send(pushback).await {
+                    tracing::debug!("error sending pushback: {}", cause);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Send sink for pushback server endpoints
+///
+/// Keeps the permit of the call alive until both sink and stream are dropped.
+#[pin_project]
+pub struct ServerSendSink<E: ConnectionCommon<In, Reply<Out>>, In, Out> {
+    #[pin]
+    inner: E::SendSink,
+    _permit: Arc<Permit>,
+}
+
+impl<E: ConnectionCommon<In, Reply<Out>>, In, Out> ServerSendSink<E, In, Out> {
+    /// Push back the call, asking the client to retry after `retry_after`
+    ///
+    /// The client sees no further responses, so this should be the last
+    /// message sent on the sink.
+    pub async fn push_back(&mut self, retry_after: Duration) -> result::Result<(), E::SendError> {
+        self.inner
+            .send(Reply::Pushback(Pushback { retry_after }))
+            .await
+    }
+}
+
+impl<E: ConnectionCommon<In, Reply<Out>>, In, Out> fmt::Debug for ServerSendSink<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerSendSink").finish()
+    }
+}
+
+impl<E: ConnectionCommon<In, Reply<Out>>, In, Out> Sink<Out> for ServerSendSink<E, In, Out> {
+    type Error = E::SendError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
+        self.project().inner.start_send(Reply::Msg(item))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+/// Receive stream for pushback server endpoints
+///
+/// Keeps the permit of the call alive until both sink and stream are dropped.
+#[pin_project]
+pub struct ServerRecvStream<E: ConnectionCommon<In, Reply<Out>>, In, Out> {
+    #[pin]
+    inner: E::RecvStream,
+    _permit: Arc<Permit>,
+}
+
+impl<E: ConnectionCommon<In, Reply<Out>>, In, Out> fmt::Debug for ServerRecvStream<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerRecvStream").finish()
+    }
+}
+
+impl<E: ConnectionCommon<In, Reply<Out>>, In, Out> Stream for ServerRecvStream<E, In, Out> {
+    type Item = result::Result<In, E::RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
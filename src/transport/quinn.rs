@@ -1,34 +1,631 @@
 //! QUIC transport implementation based on [quinn](https://crates.io/crates/quinn)
 use crate::{
-    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    discovery::Discovery,
+    extensions::Extensions,
+    transport::{
+        Connection, ConnectionErrors, DatagramConnection, DatagramEndpoint, LocalAddr,
+        ServerEndpoint,
+    },
     RpcMessage,
 };
+use bytes::Bytes;
 use futures::channel::oneshot;
+use futures::future::BoxFuture;
 use futures::{Future, FutureExt, Sink, SinkExt, Stream, StreamExt};
 use pin_project::pin_project;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{fmt, io, marker::PhantomData, pin::Pin, result};
 use tracing::{debug_span, Instrument};
 
+#[cfg(feature = "zstd-compression")]
+use super::compression::ZstdDictionary;
 use super::{
-    util::{FramedBincodeRead, FramedBincodeWrite},
-    ConnectionCommon,
+    latency::LatencyCritical,
+    util::{Codec, FramedBincodeRead, FramedBincodeWrite, Framing},
+    Capabilities, ConnectionCommon, Direction,
 };
 
 type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
 
-const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+pub(crate) const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+/// Application error code used when closing a connection because it was idle
+pub const IDLE_CLOSE_CODE: u32 = 1;
+
+/// Reason used when closing a connection because it was idle
+pub const IDLE_CLOSE_REASON: &[u8] = b"idle timeout";
+
+/// Application error code used when rejecting a substream because the
+/// connection has too many calls in flight
+pub const OVERLOADED_CODE: u32 = 2;
+
+/// The server rejected a call because the connection has too many calls in flight
+///
+/// This is the inner error of the [io::Error] returned by [SendSink] and
+/// [RecvStream] when a substream is rejected, see [OverloadPolicy::Reject].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server overloaded")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+impl Overloaded {
+    /// Check if an error of a [SendSink] or [RecvStream] is caused by an overloaded server
+    pub fn is_overloaded(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .map_or(false, |e| e.downcast_ref::<Overloaded>().is_some())
+    }
+
+    /// Turn resets with [OVERLOADED_CODE] into an [Overloaded] error
+    fn map_io(error: io::Error) -> io::Error {
+        let code = quinn::VarInt::from_u32(OVERLOADED_CODE);
+        let overloaded = match error.get_ref() {
+            Some(e) => match (e.downcast_ref(), e.downcast_ref()) {
+                (Some(quinn::ReadError::Reset(c)), _) => *c == code,
+                (_, Some(quinn::WriteError::Stopped(c))) => *c == code,
+                _ => false,
+            },
+            None => false,
+        };
+        if overloaded {
+            io::Error::new(io::ErrorKind::ConnectionRefused, Overloaded)
+        } else {
+            error
+        }
+    }
+}
+
+/// What to do with calls that exceed [ServerEndpointConfig::max_concurrent_calls]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Stop accepting substreams from the connection until a call finishes
+    ///
+    /// This applies backpressure to the client via the QUIC stream limits.
+    #[default]
+    Queue,
+    /// Reject the substream with [OVERLOADED_CODE]
+    ///
+    /// The client will see an [Overloaded] error.
+    Reject,
+}
+
+/// A datagram the server received, but could not pass on
+///
+/// See [ServerEndpointConfig::dead_letter].
+#[derive(Debug)]
+pub struct DeadLetter {
+    /// The address of the client that sent the datagram
+    pub remote_address: SocketAddr,
+    /// The encoded message, as it was received
+    pub payload: Bytes,
+    /// Why the datagram was not passed on
+    pub reason: DeadLetterReason,
+}
+
+/// Why a [DeadLetter] was not passed on
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum DeadLetterReason {
+    /// Too many datagrams were already waiting to be received
+    Lagging,
+    /// The datagram could not be decoded with the codec of its connection
+    Undecodable(io::Error),
+}
+
+/// Handler for the datagrams the server could not pass on
+#[derive(Clone)]
+struct DeadLetterHandler(Arc<dyn Fn(DeadLetter) + Send + Sync>);
+
+impl fmt::Debug for DeadLetterHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeadLetterHandler").finish_non_exhaustive()
+    }
+}
+
+/// Configuration for a [QuinnServerEndpoint]
+#[derive(Debug, Clone, Default)]
+pub struct ServerEndpointConfig {
+    idle_timeout: Option<Duration>,
+    idle_warning: Option<Duration>,
+    max_concurrent_calls: Option<usize>,
+    overload_policy: OverloadPolicy,
+    stats_interval: Option<Duration>,
+    connection_metrics: bool,
+    codecs: Vec<(Vec<u8>, Codec)>,
+    #[cfg(feature = "zstd-compression")]
+    dictionary: Option<ZstdDictionary>,
+    offload_threshold: Option<usize>,
+    latency_critical: LatencyCritical,
+    dead_letter: Option<DeadLetterHandler>,
+}
+
+impl ServerEndpointConfig {
+    /// Close connections that had no calls and no stream activity for the given duration.
+    ///
+    /// Stream activity is any stream data sent or received on the connection,
+    /// including opening new substreams. A connection with calls in flight is
+    /// never idle, even if the calls are quiet, e.g. a subscription without
+    /// updates. The duration starts again when the last call finishes.
+    ///
+    /// Idle connections are closed with [IDLE_CLOSE_CODE] and [IDLE_CLOSE_REASON].
+    pub fn idle_timeout(mut self, value: Duration) -> Self {
+        self.idle_timeout = Some(value);
+        self
+    }
+
+    /// Notify clients the given duration before an idle connection is closed.
+    ///
+    /// The notification is sent on a unidirectional stream and contains the
+    /// remaining time until the connection is closed as big endian milliseconds.
+    /// Only has an effect when [ServerEndpointConfig::idle_timeout] is set.
+    pub fn idle_warning(mut self, value: Duration) -> Self {
+        self.idle_warning = Some(value);
+        self
+    }
+
+    /// Limit the number of calls that can be in flight per connection.
+    ///
+    /// A call is in flight from the moment its substream is accepted until both
+    /// the send and the receive side of it are dropped on the server. This is in
+    /// addition to the stream limits of the quinn transport config. What happens
+    /// to calls beyond the limit is decided by [ServerEndpointConfig::overload_policy].
+    pub fn max_concurrent_calls(mut self, value: usize) -> Self {
+        self.max_concurrent_calls = Some(value);
+        self
+    }
+
+    /// What to do with calls beyond [ServerEndpointConfig::max_concurrent_calls].
+    ///
+    /// The default is [OverloadPolicy::Queue].
+    pub fn overload_policy(mut self, value: OverloadPolicy) -> Self {
+        self.overload_policy = value;
+        self
+    }
+
+    /// Record the [ConnectionStats] of every connection at the given interval.
+    ///
+    /// Stats are recorded in the `quic.connection.rtt`, `quic.connection.cwnd`,
+    /// `quic.connection.lost_packets` and `quic.connection.congestion_events`
+    /// metrics, so this only has an effect with the `opentelemetry-metrics` feature.
+    /// Stats can always be queried using [QuinnServerEndpoint::connection_stats].
+    pub fn stats_interval(mut self, value: Duration) -> Self {
+        self.stats_interval = Some(value);
+        self
+    }
+
+    /// Also record the calls in flight of every connection, labelled with the
+    /// address of the client.
+    ///
+    /// The calls in flight on all connections are always recorded in the
+    /// `rpc.server.in_flight` metric. With this, they are also recorded per
+    /// connection in the `rpc.server.connection.in_flight` metric, which adds
+    /// a time series for every client, so it is off by default.
+    pub fn connection_metrics(mut self, value: bool) -> Self {
+        self.connection_metrics = value;
+        self
+    }
+
+    /// Compress frames using a zstd dictionary.
+    ///
+    /// Clients must be configured with the same dictionary using
+    /// [QuinnConnection::with_zstd_dictionary].
+    #[cfg(feature = "zstd-compression")]
+    pub fn zstd_dictionary(mut self, value: ZstdDictionary) -> Self {
+        self.dictionary = Some(value);
+        self
+    }
+
+    /// Select the [Codec] of a connection by the application protocol (ALPN)
+    /// it negotiated.
+    ///
+    /// This allows serving clients that use different codecs on the same
+    /// endpoint. The server config of the endpoint needs to offer all of the
+    /// protocols, and every client offers the protocol of the codec it was
+    /// configured with using [QuinnConnection::with_codec]. Connections that
+    /// negotiated another protocol or none at all use [Codec::Bincode].
+    pub fn codecs(mut self, value: impl IntoIterator<Item = (Vec<u8>, Codec)>) -> Self {
+        self.codecs = value.into_iter().collect();
+        self
+    }
+
+    /// Encode and decode messages of at least `bytes` on the blocking thread pool.
+    ///
+    /// Encoding and decoding multi-megabyte messages takes long enough to hold
+    /// up other tasks of the runtime. With this, large messages are handed to
+    /// [tokio::task::spawn_blocking], while small messages are still handled
+    /// inline, since handing them off would cost more than it saves. The size
+    /// of a message to send is computed before encoding it, which walks the
+    /// message once more.
+    pub fn offload_threshold(mut self, bytes: usize) -> Self {
+        self.offload_threshold = Some(bytes);
+        self
+    }
+
+    /// Send the given message types without compressing them or encoding them
+    /// on the blocking thread pool.
+    ///
+    /// See [LatencyCritical]. Clients can mark the messages they send using
+    /// [QuinnConnection::with_latency_critical].
+    pub fn latency_critical(mut self, value: LatencyCritical) -> Self {
+        self.latency_critical = value;
+        self
+    }
+
+    /// Pass the datagrams that were received but are not passed on to `f`.
+    ///
+    /// Messages sent with
+    /// [RpcClient::notify_unreliable](crate::RpcClient::notify_unreliable) are
+    /// dropped by the server if too many of them are waiting to be received,
+    /// or if they can not be decoded. Instead of dropping them silently, they
+    /// are handed to `f` with the reason, e.g. to log or count them, or to
+    /// store them for later inspection. `f` is called on the task that
+    /// receives the datagrams, so it should not block.
+    pub fn dead_letter(mut self, f: impl Fn(DeadLetter) + Send + Sync + 'static) -> Self {
+        self.dead_letter = Some(DeadLetterHandler(Arc::new(f)));
+        self
+    }
+
+    /// The codec for a connection that negotiated `alpn`
+    fn codec(&self, alpn: Option<&[u8]>) -> Codec {
+        self.codecs
+            .iter()
+            .find(|(protocol, _)| Some(protocol.as_slice()) == alpn)
+            .map(|(_, codec)| codec.clone())
+            .unwrap_or_default()
+    }
+
+    fn framing(&self) -> Framing {
+        let mut framing = Framing::new(MAX_FRAME_LENGTH);
+        #[cfg(feature = "zstd-compression")]
+        {
+            framing.dictionary = self.dictionary.clone();
+        }
+        framing.offload_threshold = self.offload_threshold;
+        framing.latency_critical = self.latency_critical.clone();
+        framing
+    }
+}
+
+/// Tracks calls and stream activity on a connection, to detect idle connections
+struct IdleTracker {
+    /// Stream frames sent and received when we last saw activity
+    frames: (u64, u64),
+    last_activity: Instant,
+    warned: bool,
+}
+
+impl IdleTracker {
+    fn new(connection: &quinn::Connection) -> Self {
+        Self {
+            frames: Self::stream_frames(connection),
+            last_activity: Instant::now(),
+            warned: false,
+        }
+    }
+
+    fn stream_frames(connection: &quinn::Connection) -> (u64, u64) {
+        let stats = connection.stats();
+        (stats.frame_rx.stream, stats.frame_tx.stream)
+    }
+
+    /// Wait until the connection has been idle for `timeout`
+    ///
+    /// If `warning` is set, the client is notified that much time before the timeout.
+    async fn idle(
+        &mut self,
+        connection: &quinn::Connection,
+        calls: &CallCounter,
+        timeout: Duration,
+        warning: Option<Duration>,
+    ) {
+        // check often enough to be reasonably precise, but not too often
+        let tick = (timeout / 8).clamp(Duration::from_millis(10), Duration::from_secs(1));
+        loop {
+            let released = calls.released.notified();
+            let released = tokio::select! {
+                _ = tokio::time::sleep(tick) => false,
+                _ = released => true,
+            };
+            let frames = Self::stream_frames(connection);
+            if released || calls.get() > 0 || frames != self.frames {
+                self.frames = frames;
+                self.last_activity = Instant::now();
+                self.warned = false;
+                continue;
+            }
+            let idle = self.last_activity.elapsed();
+            if idle >= timeout {
+                return;
+            }
+            if let Some(warning) = warning {
+                let remaining = timeout - idle;
+                if !self.warned && remaining <= warning {
+                    self.warned = true;
+                    if let Err(cause) = Self::warn(connection, remaining).await {
+                        tracing::debug!("unable to send idle warning: {}", cause);
+                    }
+                    // the warning itself is stream activity, so don't count it
+                    self.frames = Self::stream_frames(connection);
+                }
+            }
+        }
+    }
+
+    async fn warn(
+        connection: &quinn::Connection,
+        remaining: Duration,
+    ) -> result::Result<(), quinn::WriteError> {
+        let mut send = connection
+            .open_uni()
+            .await
+            .map_err(quinn::WriteError::ConnectionLost)?;
+        let millis = u64::try_from(remaining.as_millis()).unwrap_or(u64::MAX);
+        send.write_all(&millis.to_be_bytes()).await?;
+        send.finish().await?;
+        Ok(())
+    }
+}
+
+/// Number of calls in flight on a server connection
+#[derive(Debug)]
+struct CallCounter {
+    remote_address: SocketAddr,
+    info: Arc<ConnectionInfo>,
+    /// The codec of the calls, selected by the negotiated protocol
+    codec: Codec,
+    /// Shared by all calls of the connection
+    extensions: Extensions,
+    count: AtomicUsize,
+    released: tokio::sync::Notify,
+    /// Whether to record the calls in flight of this connection
+    metrics: bool,
+}
+
+impl CallCounter {
+    fn new(connection: &quinn::Connection, config: &ServerEndpointConfig) -> Self {
+        let info = ConnectionInfo::new(connection);
+        Self {
+            remote_address: connection.remote_address(),
+            codec: config.codec(info.alpn.as_deref()),
+            info: Arc::new(info),
+            extensions: Extensions::new(),
+            count: AtomicUsize::new(0),
+            released: tokio::sync::Notify::new(),
+            metrics: config.connection_metrics,
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// Count a call as in flight until the returned guard is dropped
+    fn track(self: &Arc<Self>) -> Arc<CallGuard> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        crate::telemetry::server_in_flight(1);
+        if self.metrics {
+            crate::telemetry::connection_in_flight(self.remote_address, 1);
+        }
+        Arc::new(CallGuard(self.clone()))
+    }
+
+    /// Wait until fewer than `max` calls are in flight
+    async fn below(&self, max: usize) {
+        loop {
+            let released = self.released.notified();
+            if self.get() < max {
+                return;
+            }
+            released.await;
+        }
+    }
+}
+
+/// Keeps a call counted as in flight, shared by the send and receive side
+#[derive(Debug)]
+struct CallGuard(Arc<CallCounter>);
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::SeqCst);
+        crate::telemetry::server_in_flight(-1);
+        if self.0.metrics {
+            crate::telemetry::connection_in_flight(self.0.remote_address, -1);
+        }
+        self.0.released.notify_waiters();
+    }
+}
+
+/// Calls in flight on a connection to a [QuinnServerEndpoint]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionCalls {
+    /// The address of the client
+    pub remote_address: SocketAddr,
+    /// The number of calls in flight
+    pub in_flight: usize,
+}
+
+/// The TLS parameters of a connection accepted by a [QuinnServerEndpoint]
+///
+/// This is available to handlers through [RecvStream::connection_info] and
+/// [SendSink::connection_info], e.g. to select a per-tenant configuration by
+/// server name, or to log the identity of the client.
+///
+/// QUIC always uses TLS 1.3. Quinn does not expose which of the cipher suites
+/// of the server config was negotiated, so it is not part of this info.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The address of the client
+    pub remote_address: SocketAddr,
+    /// The server name the client asked for (SNI), if it sent one
+    pub server_name: Option<String>,
+    /// The negotiated application protocol (ALPN), if any
+    pub alpn: Option<Vec<u8>>,
+    /// The DER encoded certificate chain of the client, leaf first, if the
+    /// client authenticated itself
+    pub peer_certificates: Option<Vec<Vec<u8>>>,
+}
+
+impl ConnectionInfo {
+    fn new(connection: &quinn::Connection) -> Self {
+        let handshake = get_handshake_data(connection);
+        let peer_certificates = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+            .map(|chain| chain.into_iter().map(|cert| cert.0).collect());
+        Self {
+            remote_address: connection.remote_address(),
+            server_name: handshake.as_ref().and_then(|h| h.server_name.clone()),
+            alpn: handshake.and_then(|h| h.protocol),
+            peer_certificates,
+        }
+    }
+}
+
+/// Path statistics of a quinn connection
+///
+/// Counters are totals since the connection was established. Note that quinn
+/// does not report ECN markings separately. Packets marked with ECN-CE by the
+/// network are counted in `congestion_events`, together with congestion events
+/// caused by packet loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The address of the remote side
+    pub remote_address: SocketAddr,
+    /// The current estimate of the round trip time
+    pub rtt: Duration,
+    /// The current congestion window in bytes
+    pub cwnd: u64,
+    /// The number of congestion events, due to loss or ECN-CE markings
+    pub congestion_events: u64,
+    /// The number of packets lost
+    pub lost_packets: u64,
+    /// The number of bytes lost
+    pub lost_bytes: u64,
+    /// The number of packets sent
+    pub sent_packets: u64,
+}
+
+impl ConnectionStats {
+    fn new(connection: &quinn::Connection) -> Self {
+        let path = connection.stats().path;
+        Self {
+            remote_address: connection.remote_address(),
+            rtt: path.rtt,
+            cwnd: path.cwnd,
+            congestion_events: path.congestion_events,
+            lost_packets: path.lost_packets,
+            lost_bytes: path.lost_bytes,
+            sent_packets: path.sent_packets,
+        }
+    }
+
+    /// The fraction of sent packets that were lost
+    pub fn loss_rate(&self) -> f64 {
+        if self.sent_packets == 0 {
+            0.0
+        } else {
+            self.lost_packets as f64 / self.sent_packets as f64
+        }
+    }
+
+    /// Record stats in the metrics, with counters relative to `previous`
+    fn record(&self, previous: &ConnectionStats) {
+        crate::telemetry::connection_stats(
+            self.remote_address,
+            self.rtt,
+            self.cwnd,
+            self.lost_packets.saturating_sub(previous.lost_packets),
+            self.congestion_events
+                .saturating_sub(previous.congestion_events),
+        );
+    }
+
+    /// Record the stats of a connection every `interval` until it is closed
+    async fn sample(connection: quinn::Connection, interval: Duration) {
+        let mut previous = ConnectionStats::new(&connection);
+        loop {
+            tokio::select! {
+                _ = connection.closed() => break,
+                _ = tokio::time::sleep(interval) => {}
+            }
+            let stats = ConnectionStats::new(&connection);
+            stats.record(&previous);
+            previous = stats;
+        }
+    }
+}
+
+/// An open connection of a server endpoint
+#[derive(Debug)]
+struct TrackedConnection {
+    connection: quinn::Connection,
+    calls: Arc<CallCounter>,
+}
+
+/// All open connections, by stable id
+type Connections = Arc<Mutex<HashMap<usize, TrackedConnection>>>;
+
+/// An accepted substream, with the guard counting it as in flight
+type Accepted = (SocketInner, Option<Arc<CallGuard>>);
+
+/// A received datagram, with the codec and the remote address of its connection
+type Datagram = (Bytes, Codec, SocketAddr);
+
+/// Number of received datagrams that are buffered before further ones are dropped
+const DATAGRAM_BUFFER: usize = 64;
+
+/// Passes received datagrams on to the endpoint, counting the ones it drops
+#[derive(Debug, Clone)]
+struct DatagramSender {
+    sender: flume::Sender<Datagram>,
+    dropped: Arc<AtomicU64>,
+    dead_letter: Option<DeadLetterHandler>,
+}
+
+impl DatagramSender {
+    fn new(config: &ServerEndpointConfig) -> (Self, flume::Receiver<Datagram>) {
+        let (sender, receiver) = flume::bounded(DATAGRAM_BUFFER);
+        let sender = Self {
+            sender,
+            dropped: Default::default(),
+            dead_letter: config.dead_letter.clone(),
+        };
+        (sender, receiver)
+    }
+}
+
+/// Hand a datagram that is not passed on to the dead letter handler, if any
+fn dead_letter(handler: &Option<DeadLetterHandler>, letter: DeadLetter) {
+    if let Some(DeadLetterHandler(f)) = handler {
+        f(letter);
+    }
+}
 
 #[derive(Debug)]
 struct ServerEndpointInner {
     endpoint: Option<quinn::Endpoint>,
     task: Option<tokio::task::JoinHandle<()>>,
     local_addr: [LocalAddr; 1],
-    receiver: flume::Receiver<SocketInner>,
+    receiver: flume::Receiver<Accepted>,
+    datagrams: flume::Receiver<Datagram>,
+    dropped_datagrams: Arc<AtomicU64>,
+    dead_letter: Option<DeadLetterHandler>,
+    framing: Framing,
+    connections: Connections,
 }
 
 impl Drop for ServerEndpointInner {
@@ -51,6 +648,42 @@ impl Drop for ServerEndpointInner {
     }
 }
 
+/// The application protocol (ALPN) of HTTP/3, which WebTransport sessions use
+pub const H3_ALPN: &[u8] = b"h3";
+
+/// The HTTP/3 connections of an endpoint that also accepts WebTransport
+///
+/// See [QuinnServerEndpoint::with_web_transport]. The WebTransport sessions on
+/// the connections from [WebTransportConnections::accept] are established by
+/// `web_transport::serve` with the `web-transport` feature, or by an external
+/// HTTP/3 stack. Once the header of a bidi stream of a session has been read,
+/// the stream is passed back with [WebTransportConnections::session_stream],
+/// and from then on it is handled by the server endpoint like a substream of a
+/// native client.
+#[derive(Debug, Clone)]
+pub struct WebTransportConnections {
+    connections: flume::Receiver<quinn::Connection>,
+    streams: flume::Sender<Accepted>,
+}
+
+impl WebTransportConnections {
+    /// Wait for the next connection that negotiated [H3_ALPN]
+    ///
+    /// Returns `None` once the endpoint no longer accepts connections.
+    pub async fn accept(&self) -> Option<quinn::Connection> {
+        self.connections.recv_async().await.ok()
+    }
+
+    /// Handle a bidi stream of a WebTransport session as a call
+    ///
+    /// Returns false if the server endpoint was dropped. Like substreams passed
+    /// in via [QuinnServerEndpoint::handle_substreams], these are not tracked per
+    /// connection.
+    pub async fn session_stream(&self, stream: SocketInner) -> bool {
+        self.streams.send_async((stream, None)).await.is_ok()
+    }
+}
+
 /// A server endpoint using a quinn connection
 #[derive(Debug)]
 pub struct QuinnServerEndpoint<In: RpcMessage, Out: RpcMessage> {
@@ -62,10 +695,95 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     /// handles RPC requests from a connection
     ///
     /// to cleanly shutdown the handler, drop the receiver side of the sender.
-    async fn connection_handler(connection: quinn::Connection, sender: flume::Sender<SocketInner>) {
+    async fn connection_handler(
+        connection: quinn::Connection,
+        sender: flume::Sender<Accepted>,
+        datagrams: DatagramSender,
+        config: Arc<ServerEndpointConfig>,
+        connections: Connections,
+    ) {
+        let id = connection.stable_id();
+        let calls = Arc::new(CallCounter::new(&connection, &config));
+        tokio::spawn(Self::datagram_handler(
+            connection.clone(),
+            calls.codec.clone(),
+            datagrams,
+        ));
+        let tracked = TrackedConnection {
+            connection: connection.clone(),
+            calls: calls.clone(),
+        };
+        connections.lock().unwrap().insert(id, tracked);
+        crate::telemetry::connection_opened(connection.remote_address());
+        if let Some(interval) = config.stats_interval {
+            tokio::spawn(ConnectionStats::sample(connection.clone(), interval));
+        }
+        let remote_address = connection.remote_address();
+        Self::connection_handler_inner(connection, sender, &config, &calls).await;
+        connections.lock().unwrap().remove(&id);
+        crate::telemetry::connection_closed(remote_address);
+    }
+
+    /// Passes on the datagrams of a connection until it is closed
+    ///
+    /// Datagrams that arrive while [DATAGRAM_BUFFER] datagrams are waiting to
+    /// be received are dropped, like the network would drop them.
+    async fn datagram_handler(
+        connection: quinn::Connection,
+        codec: Codec,
+        datagrams: DatagramSender,
+    ) {
+        let remote_address = connection.remote_address();
+        while let Ok(data) = connection.read_datagram().await {
+            match datagrams
+                .sender
+                .try_send((data, codec.clone(), remote_address))
+            {
+                Ok(()) => {}
+                Err(flume::TrySendError::Full((payload, _, _))) => {
+                    let dropped = datagrams.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    tracing::debug!(dropped, "Dropping datagram, receiver is lagging");
+                    let letter = DeadLetter {
+                        remote_address,
+                        payload,
+                        reason: DeadLetterReason::Lagging,
+                    };
+                    dead_letter(&datagrams.dead_letter, letter);
+                }
+                Err(flume::TrySendError::Disconnected(_)) => break,
+            }
+        }
+    }
+
+    async fn connection_handler_inner(
+        connection: quinn::Connection,
+        sender: flume::Sender<Accepted>,
+        config: &ServerEndpointConfig,
+        calls: &Arc<CallCounter>,
+    ) {
+        let mut idle = IdleTracker::new(&connection);
         loop {
             tracing::debug!("Awaiting incoming bidi substream on existing connection...");
-            let bidi_stream = match connection.accept_bi().await {
+            let accept = async {
+                if let (Some(max), OverloadPolicy::Queue) =
+                    (config.max_concurrent_calls, config.overload_policy)
+                {
+                    calls.below(max).await;
+                }
+                connection.accept_bi().await
+            };
+            let accept = match config.idle_timeout {
+                Some(timeout) => tokio::select! {
+                    res = accept => res,
+                    _ = idle.idle(&connection, calls, timeout, config.idle_warning) => {
+                        tracing::debug!("Closing idle connection {}", connection.remote_address());
+                        connection.close(IDLE_CLOSE_CODE.into(), IDLE_CLOSE_REASON);
+                        break;
+                    }
+                },
+                None => accept.await,
+            };
+            let mut bidi_stream = match accept {
                 Ok(bidi_stream) => bidi_stream,
                 Err(quinn::ConnectionError::ApplicationClosed(e)) => {
                     tracing::debug!("Peer closed the connection {:?}", e);
@@ -76,15 +794,32 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                     break;
                 }
             };
+            if let Some(max) = config.max_concurrent_calls {
+                if calls.get() >= max {
+                    tracing::debug!("Rejecting substream {}, overloaded", bidi_stream.0.id());
+                    let code = quinn::VarInt::from_u32(OVERLOADED_CODE);
+                    bidi_stream.0.reset(code).ok();
+                    bidi_stream.1.stop(code).ok();
+                    continue;
+                }
+            }
             tracing::debug!("Sending substream to be handled... {}", bidi_stream.0.id());
-            if sender.send_async(bidi_stream).await.is_err() {
+            let guard = calls.track();
+            if sender.send_async((bidi_stream, Some(guard))).await.is_err() {
                 tracing::debug!("Receiver dropped");
                 break;
             }
         }
     }
 
-    async fn endpoint_handler(endpoint: quinn::Endpoint, sender: flume::Sender<SocketInner>) {
+    async fn endpoint_handler(
+        endpoint: quinn::Endpoint,
+        sender: flume::Sender<Accepted>,
+        datagrams: DatagramSender,
+        config: Arc<ServerEndpointConfig>,
+        connections: Connections,
+        h3: Option<flume::Sender<quinn::Connection>>,
+    ) {
         loop {
             tracing::debug!("Waiting for incoming connection...");
             let connecting = match endpoint.accept().await {
@@ -103,8 +838,24 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                 "Connection established from {:?}",
                 conection.remote_address()
             );
+            if let Some(h3) = &h3 {
+                let protocol = get_handshake_data(&conection).and_then(|h| h.protocol);
+                if protocol.as_deref() == Some(H3_ALPN) {
+                    tracing::debug!("Passing on HTTP/3 connection...");
+                    if h3.send_async(conection).await.is_err() {
+                        tracing::debug!("HTTP/3 receiver dropped");
+                    }
+                    continue;
+                }
+            }
             tracing::debug!("Spawning connection handler...");
-            tokio::spawn(Self::connection_handler(conection, sender.clone()));
+            tokio::spawn(Self::connection_handler(
+                conection,
+                sender.clone(),
+                datagrams.clone(),
+                config.clone(),
+                connections.clone(),
+            ));
         }
     }
 
@@ -115,18 +866,78 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     /// The server channel will take care of listening on the endpoint and spawning
     /// handlers for new connections.
     pub fn new(endpoint: quinn::Endpoint) -> io::Result<Self> {
+        Self::with_config(endpoint, Default::default())
+    }
+
+    /// Create a new server channel, given a quinn endpoint and a custom configuration.
+    ///
+    /// See [QuinnServerEndpoint::new] for details.
+    pub fn with_config(
+        endpoint: quinn::Endpoint,
+        config: ServerEndpointConfig,
+    ) -> io::Result<Self> {
+        let (endpoint, _) = Self::spawn(endpoint, config, None)?;
+        Ok(endpoint)
+    }
+
+    /// Create a new server channel that shares a quinn endpoint with WebTransport
+    ///
+    /// Connections are routed by the application protocol they negotiated:
+    /// connections that negotiated [H3_ALPN] are passed on to the returned
+    /// [WebTransportConnections], all others are handled as quic-rpc
+    /// connections. The server config of the endpoint needs to offer both
+    /// protocols, e.g. `vec![b"rpc".to_vec(), H3_ALPN.to_vec()]`, so browsers and
+    /// native clients can use the same port and certificate.
+    ///
+    /// See [QuinnServerEndpoint::new] for details.
+    pub fn with_web_transport(
+        endpoint: quinn::Endpoint,
+        config: ServerEndpointConfig,
+    ) -> io::Result<(Self, WebTransportConnections)> {
+        let (h3, connections) = flume::bounded(16);
+        let (endpoint, streams) = Self::spawn(endpoint, config, Some(h3))?;
+        let web_transport = WebTransportConnections {
+            connections,
+            streams,
+        };
+        Ok((endpoint, web_transport))
+    }
+
+    fn spawn(
+        endpoint: quinn::Endpoint,
+        config: ServerEndpointConfig,
+        h3: Option<flume::Sender<quinn::Connection>>,
+    ) -> io::Result<(Self, flume::Sender<Accepted>)> {
         let local_addr = endpoint.local_addr()?;
         let (sender, receiver) = flume::bounded(16);
-        let task = tokio::spawn(Self::endpoint_handler(endpoint.clone(), sender));
-        Ok(Self {
+        let (datagram_sender, datagrams) = DatagramSender::new(&config);
+        let dropped_datagrams = datagram_sender.dropped.clone();
+        let dead_letter = datagram_sender.dead_letter.clone();
+        let framing = config.framing();
+        let connections = Connections::default();
+        let task = tokio::spawn(Self::endpoint_handler(
+            endpoint.clone(),
+            sender.clone(),
+            datagram_sender,
+            Arc::new(config),
+            connections.clone(),
+            h3,
+        ));
+        let endpoint = Self {
             inner: Arc::new(ServerEndpointInner {
                 endpoint: Some(endpoint),
                 task: Some(task),
                 local_addr: [LocalAddr::Socket(local_addr)],
                 receiver,
+                datagrams,
+                dropped_datagrams,
+                dead_letter,
+                framing,
+                connections,
             }),
             _phantom: PhantomData,
-        })
+        };
+        Ok((endpoint, sender))
     }
 
     /// Create a new server channel, given just a source of incoming connections
@@ -136,12 +947,37 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
     pub fn handle_connections(
         incoming: flume::Receiver<quinn::Connection>,
         local_addr: SocketAddr,
+    ) -> Self {
+        Self::handle_connections_with_config(incoming, local_addr, Default::default())
+    }
+
+    /// Create a new server channel, given just a source of incoming connections
+    /// and a custom configuration.
+    ///
+    /// See [QuinnServerEndpoint::handle_connections] for details.
+    pub fn handle_connections_with_config(
+        incoming: flume::Receiver<quinn::Connection>,
+        local_addr: SocketAddr,
+        config: ServerEndpointConfig,
     ) -> Self {
         let (sender, receiver) = flume::bounded(16);
+        let (datagram_sender, datagrams) = DatagramSender::new(&config);
+        let dropped_datagrams = datagram_sender.dropped.clone();
+        let dead_letter = datagram_sender.dead_letter.clone();
+        let framing = config.framing();
+        let config = Arc::new(config);
+        let connections = Connections::default();
+        let connections2 = connections.clone();
         let task = tokio::spawn(async move {
             // just grab all connections and spawn a handler for each one
             while let Ok(connection) = incoming.recv_async().await {
-                tokio::spawn(Self::connection_handler(connection, sender.clone()));
+                tokio::spawn(Self::connection_handler(
+                    connection,
+                    sender.clone(),
+                    datagram_sender.clone(),
+                    config.clone(),
+                    connections2.clone(),
+                ));
             }
         });
         Self {
@@ -150,11 +986,33 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
                 task: Some(task),
                 local_addr: [LocalAddr::Socket(local_addr)],
                 receiver,
+                datagrams,
+                dropped_datagrams,
+                dead_letter,
+                framing,
+                connections,
             }),
             _phantom: PhantomData,
         }
     }
 
+    /// Create a new server channel that serves calls on an established connection
+    ///
+    /// This is for connections that were set up elsewhere, e.g. by another
+    /// library or after a custom handshake, or where the side that connected
+    /// serves calls. `local_addr` is only reported by
+    /// [ServerEndpoint::local_addr]. Once the
+    /// connection is closed, accepting channels fails.
+    pub fn from_connection(
+        connection: quinn::Connection,
+        local_addr: SocketAddr,
+        config: ServerEndpointConfig,
+    ) -> Self {
+        let (sender, incoming) = flume::bounded(1);
+        sender.send(connection).ok();
+        Self::handle_connections_with_config(incoming, local_addr, config)
+    }
+
     /// Create a new server channel, given just a source of incoming substreams
     ///
     /// This is useful if you want to manage the quinn endpoint yourself,
@@ -163,16 +1021,65 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnServerEndpoint<In, Out> {
         receiver: flume::Receiver<SocketInner>,
         local_addr: SocketAddr,
     ) -> Self {
+        let (sender, accepted) = flume::bounded(16);
+        // substreams from outside are not counted as in flight
+        let task = tokio::spawn(async move {
+            while let Ok(substream) = receiver.recv_async().await {
+                if sender.send_async((substream, None)).await.is_err() {
+                    break;
+                }
+            }
+        });
         Self {
             inner: Arc::new(ServerEndpointInner {
                 endpoint: None,
-                task: None,
+                task: Some(task),
                 local_addr: [LocalAddr::Socket(local_addr)],
-                receiver,
+                receiver: accepted,
+                // substreams from outside have no connection to receive datagrams on
+                datagrams: flume::bounded(0).1,
+                dropped_datagrams: Default::default(),
+                dead_letter: None,
+                framing: Framing::new(MAX_FRAME_LENGTH),
+                connections: Default::default(),
             }),
             _phantom: PhantomData,
         }
     }
+
+    /// The number of calls in flight for every open connection
+    ///
+    /// Substreams passed in via [QuinnServerEndpoint::handle_substreams] are not tracked.
+    pub fn connection_calls(&self) -> Vec<ConnectionCalls> {
+        let connections = self.inner.connections.lock().unwrap();
+        connections
+            .values()
+            .map(|tracked| ConnectionCalls {
+                remote_address: tracked.calls.remote_address,
+                in_flight: tracked.calls.get(),
+            })
+            .collect()
+    }
+
+    /// The number of received datagrams that were dropped
+    ///
+    /// Datagrams are dropped while 64 of them are waiting for
+    /// [DatagramEndpoint::recv_datagram], so this grows if they are not
+    /// received quickly enough.
+    pub fn dropped_datagrams(&self) -> u64 {
+        self.inner.dropped_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// The path statistics of every open connection
+    ///
+    /// Connections passed in via [QuinnServerEndpoint::handle_substreams] are not tracked.
+    pub fn connection_stats(&self) -> Vec<ConnectionStats> {
+        let connections = self.inner.connections.lock().unwrap();
+        connections
+            .values()
+            .map(|tracked| ConnectionStats::new(&tracked.connection))
+            .collect()
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Clone for QuinnServerEndpoint<In, Out> {
@@ -195,13 +1102,26 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for QuinnServerEndpoint<I
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for QuinnServerEndpoint<In, Out> {
     type RecvStream = self::RecvStream<In>;
     type SendSink = self::SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        recv.connection_info().map(|info| info.remote_address)
+    }
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for QuinnServerEndpoint<In, Out> {
     type AcceptBiFut = AcceptBiFuture<In, Out>;
 
     fn accept_bi(&self) -> Self::AcceptBiFut {
-        AcceptBiFuture(self.inner.receiver.clone().into_recv_async(), PhantomData)
+        AcceptBiFuture(
+            self.inner.receiver.clone().into_recv_async(),
+            self.inner.framing.clone(),
+            PhantomData,
+        )
     }
 
     fn local_addr(&self) -> &[LocalAddr] {
@@ -209,6 +1129,43 @@ impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for QuinnServerEnd
     }
 }
 
+/// Datagrams are decoded with the codec of the connection they arrived on, and
+/// are never compressed.
+///
+/// Datagrams that can not be decoded are skipped, so a client sending garbage
+/// does not make receiving fail for all others. Receiving fails once no
+/// connections can be accepted anymore, and right away for a server endpoint
+/// created with [QuinnServerEndpoint::handle_substreams].
+impl<In: RpcMessage, Out: RpcMessage> DatagramEndpoint<In, Out> for QuinnServerEndpoint<In, Out> {
+    type RecvDatagramFut = BoxFuture<'static, io::Result<In>>;
+
+    fn recv_datagram(&self) -> Self::RecvDatagramFut {
+        let datagrams = self.inner.datagrams.clone();
+        let handler = self.inner.dead_letter.clone();
+        async move {
+            loop {
+                let (payload, codec, remote_address) =
+                    datagrams.recv_async().await.map_err(|_| {
+                        io::Error::new(io::ErrorKind::NotConnected, "no connections to receive on")
+                    })?;
+                match codec.decode(&payload, Direction::Request) {
+                    Ok(msg) => break Ok(msg),
+                    Err(cause) => {
+                        tracing::debug!("Skipping undecodable datagram: {}", cause);
+                        let letter = DeadLetter {
+                            remote_address,
+                            payload,
+                            reason: DeadLetterReason::Undecodable(cause),
+                        };
+                        dead_letter(&handler, letter);
+                    }
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
 type SocketInner = (quinn::SendStream, quinn::RecvStream);
 
 #[derive(Debug)]
@@ -219,8 +1176,18 @@ struct ClientConnectionInner {
     task: Option<tokio::task::JoinHandle<()>>,
     /// The channel to receive new connections
     sender: flume::Sender<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+    /// The connection currently used to open substreams
+    current: CurrentConnection,
+    /// The subscribers of [QuinnConnection::events]
+    events: EventSenders,
 }
 
+/// The connection a client currently uses, if any
+type CurrentConnection = Arc<Mutex<Option<quinn::Connection>>>;
+
+/// The subscribers to the connection events of a client
+type EventSenders = Arc<Mutex<Vec<flume::Sender<ConnectionEvent>>>>;
+
 impl Drop for ClientConnectionInner {
     fn drop(&mut self) {
         tracing::debug!("Dropping client connection");
@@ -244,17 +1211,234 @@ impl Drop for ClientConnectionInner {
     }
 }
 
+/// Delay before the first retry after connecting to the server failed
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+
+/// Upper bound of the delay between retries, which doubles after every failure
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+
+/// Number of events buffered for a subscriber of [QuinnConnection::events]
+/// before further events are dropped
+const EVENT_BUFFER: usize = 64;
+
+/// A change of the connection of a [QuinnConnection]
+///
+/// See [QuinnConnection::events].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Connecting to the server at `addr` started
+    Connecting {
+        /// The address of the server
+        addr: SocketAddr,
+    },
+    /// An attempt to connect failed
+    Failed {
+        /// The address of the server, or `None` if resolving it failed
+        addr: Option<SocketAddr>,
+        /// Why the attempt failed
+        reason: String,
+    },
+    /// All addresses failed, the next attempt is made after `delay`
+    Backoff {
+        /// The time until the next attempt
+        delay: Duration,
+    },
+    /// Connected to the server at `addr`
+    Connected {
+        /// The address of the server
+        addr: SocketAddr,
+    },
+    /// The connection was lost, and is recreated for the next call
+    Disconnected {
+        /// Why the connection was lost
+        reason: String,
+    },
+    /// The server will close the connection as idle after `remaining`,
+    /// unless a call is made before
+    ///
+    /// See [ServerEndpointConfig::idle_warning].
+    IdleWarning {
+        /// The time until the server closes the connection
+        remaining: Duration,
+    },
+}
+
+/// Send an event to all subscribers that are still there
+fn emit(events: &EventSenders, event: ConnectionEvent) {
+    events.lock().unwrap().retain(|sender| {
+        !matches!(
+            sender.try_send(event.clone()),
+            Err(flume::TrySendError::Disconnected(_))
+        )
+    });
+}
+
+/// The addresses a hostname resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// The addresses, in the order in which they should be tried
+    pub addrs: Vec<SocketAddr>,
+    /// How long the addresses may be reused for reconnects before resolving again
+    pub ttl: Duration,
+}
+
+/// Resolves the hostname of a server to socket addresses
+///
+/// Used by [QuinnConnection::resolving] when connecting and on every reconnect,
+/// so a server can be moved by changing its DNS records.
+pub trait Resolver: fmt::Debug + Send + Sync + 'static {
+    /// Resolve `host` to the addresses to connect to on `port`
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Resolved>>;
+}
+
+/// A [Resolver] using the resolver of the operating system
+///
+/// The system resolver does not report the TTLs of the records, so the
+/// addresses are used for a fixed time to live, by default zero, resolving
+/// again on every reconnect. The system resolver may cache on its own.
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver {
+    ttl: Duration,
+}
+
+impl SystemResolver {
+    /// Create a resolver whose addresses are reused for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self { ttl }
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Resolved>> {
+        let host = host.to_string();
+        let ttl = self.ttl;
+        async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), port)).await?;
+            Ok(Resolved {
+                addrs: addrs.collect(),
+                ttl,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Resolves to the current endpoints of a [Discovery], ignoring the hostname
+#[derive(Debug)]
+struct DiscoveryResolver<D>(D);
+
+impl<D: Discovery<Endpoint = SocketAddr>> Resolver for DiscoveryResolver<D> {
+    fn resolve(&self, _host: &str, _port: u16) -> BoxFuture<'static, io::Result<Resolved>> {
+        self.0
+            .endpoints()
+            .map(|addrs| {
+                Ok(Resolved {
+                    addrs: addrs?,
+                    ttl: Duration::ZERO,
+                })
+            })
+            .boxed()
+    }
+}
+
+/// The delay before the next attempt to connect, after `backoff`
+fn next_backoff(backoff: Option<Duration>) -> Duration {
+    backoff.map_or(RECONNECT_BACKOFF_MIN, |delay| {
+        (delay * 2).min(RECONNECT_BACKOFF_MAX)
+    })
+}
+
+/// The server a reconnecting client connects to
+#[derive(Debug)]
+enum Remote {
+    /// A fixed address
+    Addr(SocketAddr),
+    /// A hostname that is resolved when connecting
+    Host {
+        host: String,
+        port: u16,
+        resolver: Arc<dyn Resolver>,
+        /// The last resolved addresses, and until when they can be used
+        cached: Option<(Vec<SocketAddr>, Instant)>,
+    },
+}
+
+impl Remote {
+    /// The addresses to try when connecting, resolving again if they expired
+    async fn addrs(&mut self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Remote::Addr(addr) => Ok(vec![*addr]),
+            Remote::Host {
+                host,
+                port,
+                resolver,
+                cached,
+            } => {
+                if let Some((addrs, expires)) = cached {
+                    if *expires > Instant::now() {
+                        return Ok(addrs.clone());
+                    }
+                }
+                tracing::debug!("Resolving {}:{}", host, port);
+                let resolved = resolver.resolve(host, *port).await?;
+                if resolved.addrs.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{host} did not resolve to any address"),
+                    ));
+                }
+                *cached = Some((resolved.addrs.clone(), Instant::now() + resolved.ttl));
+                Ok(resolved.addrs)
+            }
+        }
+    }
+}
+
 /// A connection using a quinn connection
 pub struct QuinnConnection<In: RpcMessage, Out: RpcMessage> {
     inner: Arc<ClientConnectionInner>,
+    framing: Framing,
     _phantom: PhantomData<(In, Out)>,
 }
 
 impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
+    /// Handles notifications the server sends on unidirectional streams.
+    ///
+    /// Currently the only notification is the idle warning, see
+    /// [ServerEndpointConfig::idle_warning], which is passed on as
+    /// [ConnectionEvent::IdleWarning]. Terminates when the connection is closed.
+    ///
+    /// Does not keep the event streams open once the client is dropped.
+    async fn notification_handler(
+        connection: quinn::Connection,
+        events: Weak<Mutex<Vec<flume::Sender<ConnectionEvent>>>>,
+    ) {
+        while let Ok(recv) = connection.accept_uni().await {
+            match recv.read_to_end(8).await {
+                Ok(data) => match <[u8; 8]>::try_from(data.as_slice()) {
+                    Ok(millis) => {
+                        let remaining = Duration::from_millis(u64::from_be_bytes(millis));
+                        tracing::info!("Server will close idle connection in {:?}", remaining);
+                        if let Some(events) = events.upgrade() {
+                            emit(&events, ConnectionEvent::IdleWarning { remaining });
+                        }
+                    }
+                    Err(_) => tracing::debug!("Unexpected notification from server"),
+                },
+                Err(e) => tracing::debug!("Error reading notification: {}", e),
+            }
+        }
+    }
+
     async fn single_connection_handler_inner(
         connection: quinn::Connection,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        events: &EventSenders,
     ) -> result::Result<(), flume::RecvError> {
+        tokio::spawn(Self::notification_handler(
+            connection.clone(),
+            Arc::downgrade(events),
+        ));
         loop {
             tracing::debug!("Awaiting request for new bidi substream...");
             let request = requests.recv_async().await?;
@@ -279,8 +1463,11 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
     async fn single_connection_handler(
         connection: quinn::Connection,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        current: CurrentConnection,
+        events: EventSenders,
     ) {
-        if Self::single_connection_handler_inner(connection, requests)
+        *current.lock().unwrap() = Some(connection.clone());
+        if Self::single_connection_handler_inner(connection, requests, &events)
             .await
             .is_err()
         {
@@ -297,31 +1484,96 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
     /// It will try to keep a connection open at all times.
     async fn reconnect_handler_inner(
         endpoint: quinn::Endpoint,
-        addr: SocketAddr,
+        mut remote: Remote,
         name: String,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        current: CurrentConnection,
+        events: EventSenders,
     ) -> result::Result<(), flume::RecvError> {
+        let mut backoff = None;
+        // a request that came in after the connection was lost
+        let mut pending = None;
         'outer: loop {
-            tracing::debug!("Connecting to {} as {}", addr, name);
-            let connecting = match endpoint.connect(addr, &name) {
-                Ok(connecting) => connecting,
+            if let Some(delay) = backoff {
+                emit(&events, ConnectionEvent::Backoff { delay });
+                tokio::time::sleep(delay).await;
+            }
+            let addrs = match remote.addrs().await {
+                Ok(addrs) => addrs,
                 Err(e) => {
-                    tracing::warn!("error calling connect: {}", e);
-                    // try again. Maybe delay?
+                    tracing::warn!("error resolving server address: {}", e);
+                    emit(
+                        &events,
+                        ConnectionEvent::Failed {
+                            addr: None,
+                            reason: e.to_string(),
+                        },
+                    );
+                    backoff = Some(next_backoff(backoff));
                     continue;
                 }
             };
-            let connection = match connecting.await {
-                Ok(connection) => connection,
-                Err(e) => {
-                    tracing::warn!("error awaiting connect: {}", e);
-                    // try again. Maybe delay?
+            let mut connected = None;
+            for addr in addrs {
+                tracing::debug!("Connecting to {} as {}", addr, name);
+                emit(&events, ConnectionEvent::Connecting { addr });
+                let reason = match endpoint.connect(addr, &name) {
+                    Ok(connecting) => match connecting.await {
+                        Ok(connection) => {
+                            connected = Some((connection, addr));
+                            break;
+                        }
+                        Err(e) => {
+                            tracing::warn!("error awaiting connect: {}", e);
+                            e.to_string()
+                        }
+                    },
+                    Err(e) => {
+                        tracing::warn!("error calling connect: {}", e);
+                        e.to_string()
+                    }
+                };
+                emit(
+                    &events,
+                    ConnectionEvent::Failed {
+                        addr: Some(addr),
+                        reason,
+                    },
+                );
+            }
+            let connection = match connected {
+                Some((connection, addr)) => {
+                    emit(&events, ConnectionEvent::Connected { addr });
+                    backoff = None;
+                    connection
+                }
+                None => {
+                    backoff = Some(next_backoff(backoff));
                     continue;
                 }
             };
+            tokio::spawn(Self::notification_handler(
+                connection.clone(),
+                Arc::downgrade(&events),
+            ));
+            *current.lock().unwrap() = Some(connection.clone());
             loop {
-                tracing::debug!("Awaiting request for new bidi substream...");
-                let request = requests.recv_async().await?;
+                let request = match pending.take() {
+                    Some(request) => request,
+                    None => {
+                        tracing::debug!("Awaiting request for new bidi substream...");
+                        tokio::select! {
+                            request = requests.recv_async() => request?,
+                            e = connection.closed() => {
+                                tracing::warn!("connection lost: {}", e);
+                                emit(&events, ConnectionEvent::Disconnected { reason: e.to_string() });
+                                // recreate the connection once it is needed
+                                pending = Some(requests.recv_async().await?);
+                                continue 'outer;
+                            }
+                        }
+                    }
+                };
                 tracing::debug!("Got request for new bidi substream");
                 match connection.open_bi().await {
                     Ok(pair) => {
@@ -333,7 +1585,12 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
                     Err(e) => {
                         tracing::warn!("error opening bidi substream: {}", e);
                         tracing::warn!("recreating connection");
-                        // try again. Maybe delay?
+                        emit(
+                            &events,
+                            ConnectionEvent::Disconnected {
+                                reason: e.to_string(),
+                            },
+                        );
                         continue 'outer;
                     }
                 }
@@ -343,11 +1600,13 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
 
     async fn reconnect_handler(
         endpoint: quinn::Endpoint,
-        addr: SocketAddr,
+        remote: Remote,
         name: String,
         requests: flume::Receiver<oneshot::Sender<Result<SocketInner, quinn::ConnectionError>>>,
+        current: CurrentConnection,
+        events: EventSenders,
     ) {
-        if Self::reconnect_handler_inner(endpoint, addr, name, requests)
+        if Self::reconnect_handler_inner(endpoint, remote, name, requests, current, events)
             .await
             .is_err()
         {
@@ -360,35 +1619,168 @@ impl<In: RpcMessage, Out: RpcMessage> QuinnConnection<In, Out> {
     /// Create a new channel
     pub fn from_connection(connection: quinn::Connection) -> Self {
         let (sender, receiver) = flume::bounded(16);
-        let task = tokio::spawn(Self::single_connection_handler(connection, receiver));
+        let current = CurrentConnection::default();
+        let events = EventSenders::default();
+        let task = tokio::spawn(Self::single_connection_handler(
+            connection,
+            receiver,
+            current.clone(),
+            events.clone(),
+        ));
         Self {
             inner: Arc::new(ClientConnectionInner {
                 endpoint: None,
                 task: Some(task),
                 sender,
+                current,
+                events,
             }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
             _phantom: PhantomData,
         }
     }
 
     /// Create a new channel
     pub fn new(endpoint: quinn::Endpoint, addr: SocketAddr, name: String) -> Self {
+        Self::reconnecting(endpoint, Remote::Addr(addr), name)
+    }
+
+    /// Create a new channel to a server given by hostname
+    ///
+    /// The hostname is resolved with `resolver` when connecting and every time
+    /// the connection has to be recreated, reusing the addresses for as long as
+    /// their TTL allows. The resolved addresses are tried in order until a
+    /// connection succeeds. The hostname is also used as the server name.
+    pub fn resolving(
+        endpoint: quinn::Endpoint,
+        host: String,
+        port: u16,
+        resolver: impl Resolver,
+    ) -> Self {
+        let remote = Remote::Host {
+            host: host.clone(),
+            port,
+            resolver: Arc::new(resolver),
+            cached: None,
+        };
+        Self::reconnecting(endpoint, remote, host)
+    }
+
+    /// Create a new channel to one of the servers of a [Discovery]
+    ///
+    /// The endpoints are fetched from `discovery` when connecting and every
+    /// time the connection has to be recreated, and tried in order until a
+    /// connection succeeds. `name` is the server name of all endpoints.
+    pub fn discovering(
+        endpoint: quinn::Endpoint,
+        name: String,
+        discovery: impl Discovery<Endpoint = SocketAddr>,
+    ) -> Self {
+        let remote = Remote::Host {
+            host: name.clone(),
+            port: 0,
+            resolver: Arc::new(DiscoveryResolver(discovery)),
+            cached: None,
+        };
+        Self::reconnecting(endpoint, remote, name)
+    }
+
+    fn reconnecting(endpoint: quinn::Endpoint, remote: Remote, name: String) -> Self {
         let (sender, receiver) = flume::bounded(16);
+        let current = CurrentConnection::default();
+        let events = EventSenders::default();
         let task = tokio::spawn(Self::reconnect_handler(
             endpoint.clone(),
-            addr,
+            remote,
             name,
             receiver,
+            current.clone(),
+            events.clone(),
         ));
         Self {
             inner: Arc::new(ClientConnectionInner {
                 endpoint: Some(endpoint),
                 task: Some(task),
                 sender,
+                current,
+                events,
             }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
             _phantom: PhantomData,
         }
     }
+
+    /// The path statistics of the current connection to the server
+    ///
+    /// Returns `None` if no connection was established yet.
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        let current = self.inner.current.lock().unwrap();
+        current.as_ref().map(ConnectionStats::new)
+    }
+
+    /// The largest encoded message that can be sent as a datagram on the
+    /// current connection to the server
+    ///
+    /// Returns `None` if no connection was established yet, or the server does
+    /// not accept datagrams. The size can change during the lifetime of the
+    /// connection, as the path MTU is discovered.
+    pub fn max_datagram_size(&self) -> Option<usize> {
+        let current = self.inner.current.lock().unwrap();
+        current
+            .as_ref()
+            .and_then(|connection| connection.max_datagram_size())
+    }
+
+    /// The events of connecting and reconnecting to the server, and its idle warnings
+    ///
+    /// Every call returns a new stream that gets all events from then on, e.g.
+    /// to show the status of the connection. A stream that is not polled drops
+    /// events once 64 are buffered. The stream ends once all clones
+    /// of the connection are dropped. A connection created with
+    /// [QuinnConnection::from_connection] does not reconnect, so there are
+    /// only [ConnectionEvent::IdleWarning] events.
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> + Send + Unpin + 'static {
+        let (sender, receiver) = flume::bounded(EVENT_BUFFER);
+        self.inner.events.lock().unwrap().push(sender);
+        receiver.into_stream()
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// The client config of the endpoint needs to offer the application
+    /// protocol (ALPN) the server selects this codec for, see
+    /// [ServerEndpointConfig::codecs].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+
+    /// Encode and decode messages of at least `bytes` on the blocking thread pool.
+    ///
+    /// See [ServerEndpointConfig::offload_threshold].
+    pub fn with_offload_threshold(mut self, bytes: usize) -> Self {
+        self.framing.offload_threshold = Some(bytes);
+        self
+    }
+
+    /// Send the given message types without compressing them or encoding them
+    /// on the blocking thread pool.
+    ///
+    /// See [ServerEndpointConfig::latency_critical].
+    pub fn with_latency_critical(mut self, latency_critical: LatencyCritical) -> Self {
+        self.framing.latency_critical = latency_critical;
+        self
+    }
+
+    /// Compress frames using a zstd dictionary.
+    ///
+    /// The server must be configured with the same dictionary using
+    /// [ServerEndpointConfig::zstd_dictionary].
+    #[cfg(feature = "zstd-compression")]
+    pub fn with_zstd_dictionary(mut self, dictionary: ZstdDictionary) -> Self {
+        self.framing.dictionary = Some(dictionary);
+        self
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for QuinnConnection<In, Out> {
@@ -403,6 +1795,7 @@ impl<In: RpcMessage, Out: RpcMessage> Clone for QuinnConnection<In, Out> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            framing: self.framing.clone(),
             _phantom: PhantomData,
         }
     }
@@ -419,6 +1812,11 @@ impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for QuinnConnection<In, O
 impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for QuinnConnection<In, Out> {
     type SendSink = self::SendSink<Out>;
     type RecvStream = self::RecvStream<In>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+
+    fn compressed(&self) -> bool {
+        self.framing.compressed()
+    }
 }
 
 impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for QuinnConnection<In, Out> {
@@ -428,17 +1826,42 @@ impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for QuinnConnection<In
         let (sender, receiver) = oneshot::channel();
         OpenBiFuture(
             OpenBiFutureState::Sending(self.inner.sender.clone().into_send_async(sender), receiver),
+            self.framing.clone(),
             PhantomData,
         )
     }
 }
 
+/// Datagrams are encoded with the codec of the connection, but not compressed,
+/// and sent on the current connection to the server.
+///
+/// Sending fails with [io::ErrorKind::NotConnected] while there is no
+/// connection, and with [io::ErrorKind::InvalidInput] if the encoded message
+/// is larger than [QuinnConnection::max_datagram_size]. A datagram that was
+/// sent can still be lost.
+impl<In: RpcMessage, Out: RpcMessage> DatagramConnection<In, Out> for QuinnConnection<In, Out> {
+    fn send_datagram(&self, msg: Out) -> io::Result<()> {
+        let connection = self.inner.current.lock().unwrap().clone();
+        let connection = connection.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "not connected to the server")
+        })?;
+        let data = self.framing.codec.encode(&msg)?;
+        connection.send_datagram(data).map_err(|e| match e {
+            quinn::SendDatagramError::TooLarge => io::Error::new(io::ErrorKind::InvalidInput, e),
+            e => io::Error::new(io::ErrorKind::Other, e),
+        })
+    }
+}
+
 /// A sink that wraps a quinn SendStream with length delimiting and bincode
 ///
 /// If you want to send bytes directly, use [SendSink::into_inner] to get the
 /// underlying [quinn::SendStream].
 #[pin_project]
-pub struct SendSink<Out>(#[pin] FramedBincodeWrite<quinn::SendStream, Out>);
+pub struct SendSink<Out>(
+    #[pin] FramedBincodeWrite<quinn::SendStream, Out>,
+    Option<Arc<CallGuard>>,
+);
 
 impl<Out> fmt::Debug for SendSink<Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -447,9 +1870,9 @@ impl<Out> fmt::Debug for SendSink<Out> {
 }
 
 impl<Out: Serialize> SendSink<Out> {
-    fn new(inner: quinn::SendStream) -> Self {
-        let inner = FramedBincodeWrite::new(inner, MAX_FRAME_LENGTH);
-        Self(inner)
+    fn new(inner: quinn::SendStream, framing: Framing, guard: Option<Arc<CallGuard>>) -> Self {
+        let inner = FramedBincodeWrite::new(inner, framing);
+        Self(inner, guard)
     }
 }
 
@@ -459,34 +1882,61 @@ impl<Out> SendSink<Out> {
     pub fn into_inner(self) -> quinn::SendStream {
         self.0.into_inner()
     }
+
+    /// The TLS parameters of the connection the call was accepted on
+    ///
+    /// This is `None` on the client side, and for substreams passed to
+    /// [QuinnServerEndpoint::handle_substreams].
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.1.as_ref().map(|guard| &*guard.0.info)
+    }
+
+    /// The extensions of the connection the call was accepted on
+    ///
+    /// Like [Self::connection_info], this is `None` on the client side.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.1.as_ref().map(|guard| &guard.0.extensions)
+    }
 }
 
-impl<Out: Serialize> Sink<Out> for SendSink<Out> {
+impl<Out: Serialize + Send + 'static> Sink<Out> for SendSink<Out> {
     type Error = io::Error;
 
     fn poll_ready(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_ready_unpin(cx)
+        self.project()
+            .0
+            .poll_ready_unpin(cx)
+            .map_err(Overloaded::map_io)
     }
 
     fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
-        self.project().0.start_send_unpin(item)
+        self.project()
+            .0
+            .start_send_unpin(item)
+            .map_err(Overloaded::map_io)
     }
 
     fn poll_flush(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_flush_unpin(cx)
+        self.project()
+            .0
+            .poll_flush_unpin(cx)
+            .map_err(Overloaded::map_io)
     }
 
     fn poll_close(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
-        self.project().0.poll_close_unpin(cx)
+        self.project()
+            .0
+            .poll_close_unpin(cx)
+            .map_err(Overloaded::map_io)
     }
 }
 
@@ -495,7 +1945,10 @@ impl<Out: Serialize> Sink<Out> for SendSink<Out> {
 /// If you want to receive bytes directly, use [RecvStream::into_inner] to get
 /// the underlying [quinn::RecvStream].
 #[pin_project]
-pub struct RecvStream<In>(#[pin] FramedBincodeRead<quinn::RecvStream, In>);
+pub struct RecvStream<In>(
+    #[pin] FramedBincodeRead<quinn::RecvStream, In>,
+    Option<Arc<CallGuard>>,
+);
 
 impl<In> fmt::Debug for RecvStream<In> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -504,9 +1957,14 @@ impl<In> fmt::Debug for RecvStream<In> {
 }
 
 impl<In: DeserializeOwned> RecvStream<In> {
-    fn new(inner: quinn::RecvStream) -> Self {
-        let inner = FramedBincodeRead::new(inner, MAX_FRAME_LENGTH);
-        Self(inner)
+    fn new(
+        inner: quinn::RecvStream,
+        framing: Framing,
+        guard: Option<Arc<CallGuard>>,
+        direction: Direction,
+    ) -> Self {
+        let inner = FramedBincodeRead::new(inner, framing, direction);
+        Self(inner, guard)
     }
 }
 
@@ -516,19 +1974,48 @@ impl<In> RecvStream<In> {
     pub fn into_inner(self) -> quinn::RecvStream {
         self.0.into_inner()
     }
+
+    /// The TLS parameters of the connection the call was accepted on
+    ///
+    /// This is `None` on the client side, and for substreams passed to
+    /// [QuinnServerEndpoint::handle_substreams].
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.1.as_ref().map(|guard| &*guard.0.info)
+    }
+
+    /// The extensions of the connection the call was accepted on
+    ///
+    /// Like [Self::connection_info], this is `None` on the client side.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.1.as_ref().map(|guard| &guard.0.extensions)
+    }
 }
 
-impl<In: DeserializeOwned> Stream for RecvStream<In> {
+impl<In: DeserializeOwned + Send + 'static> Stream for RecvStream<In> {
     type Item = result::Result<In, io::Error>;
 
     fn poll_next(
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        self.project().0.poll_next_unpin(cx)
+        self.project()
+            .0
+            .poll_next_unpin(cx)
+            .map_err(Overloaded::map_io)
     }
 }
 
+/// Wrap a bidi stream opened by a client, like [QuinnConnection] does
+#[cfg(feature = "web-transport")]
+pub(crate) fn client_socket<In: RpcMessage, Out: RpcMessage>(
+    (send, recv): SocketInner,
+    framing: Framing,
+) -> Socket<In, Out> {
+    let send = SendSink::new(send, framing.clone(), None);
+    let recv = RecvStream::new(recv, framing, None, Direction::Response);
+    (send, recv)
+}
+
 /// Error for open_bi. Currently just a quinn::ConnectionError
 pub type OpenBiError = quinn::ConnectionError;
 
@@ -560,7 +2047,7 @@ impl OpenBiFutureState {
 
 /// Future returned by open_bi
 #[pin_project]
-pub struct OpenBiFuture<In, Out>(OpenBiFutureState, PhantomData<(In, Out)>);
+pub struct OpenBiFuture<In, Out>(OpenBiFutureState, Framing, PhantomData<(In, Out)>);
 
 impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for OpenBiFuture<In, Out> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -586,8 +2073,8 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
             },
             OpenBiFutureState::Receiving(mut fut) => match fut.poll_unpin(cx) {
                 Poll::Ready(Ok(Ok((send, recv)))) => {
-                    let send = SendSink::new(send);
-                    let recv = RecvStream::new(recv);
+                    let send = SendSink::new(send, self.1.clone(), None);
+                    let recv = RecvStream::new(recv, self.1.clone(), None, Direction::Response);
                     Poll::Ready(Ok((send, recv)))
                 }
                 Poll::Ready(Ok(Err(cause))) => Poll::Ready(Err(cause)),
@@ -605,7 +2092,8 @@ impl<In: RpcMessage, Out: RpcMessage> Future for OpenBiFuture<In, Out> {
 /// Future returned by accept_bi
 #[pin_project]
 pub struct AcceptBiFuture<In, Out>(
-    #[pin] flume::r#async::RecvFut<'static, SocketInner>,
+    #[pin] flume::r#async::RecvFut<'static, Accepted>,
+    Framing,
     PhantomData<(In, Out)>,
 );
 
@@ -622,13 +2110,19 @@ impl<In: RpcMessage, Out: RpcMessage> Future for AcceptBiFuture<In, Out> {
         self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        self.project().0.poll_unpin(cx).map(|conn| {
-            let (send, recv) = conn.map_err(|e| {
+        let this = self.project();
+        let framing = this.1;
+        this.0.poll(cx).map(|conn| {
+            let ((send, recv), guard) = conn.map_err(|e| {
                 tracing::warn!("accept_bi: error receiving connection: {}", e);
                 quinn::ConnectionError::LocallyClosed
             })?;
-            let send = SendSink::new(send);
-            let recv = RecvStream::new(recv);
+            let mut framing = framing.clone();
+            if let Some(guard) = &guard {
+                framing.codec = guard.0.codec.clone();
+            }
+            let send = SendSink::new(send, framing.clone(), guard.clone());
+            let recv = RecvStream::new(recv, framing, guard, Direction::Request);
             Ok((send, recv))
         })
     }
@@ -682,3 +2176,131 @@ pub fn get_handshake_data(
         server_name: tls_connection.server_name.clone(),
     })
 }
+
+/// Create a client endpoint that sends all packets through a SOCKS5 proxy
+///
+/// The proxy relays the UDP datagrams of the endpoint with the UDP ASSOCIATE
+/// command, so it must allow that. The relay lasts as long as the endpoint;
+/// if the proxy closes it, connecting and sending fail until a new endpoint
+/// is created. HTTP proxies can not relay UDP, so this fails with
+/// [io::ErrorKind::Unsupported] for them.
+///
+/// The endpoint can be used like any other, e.g. with [QuinnConnection::new]:
+///
+/// ```no_run
+/// # async fn example(config: quinn::ClientConfig, addr: std::net::SocketAddr) -> std::io::Result<()> {
+/// use quic_rpc::transport::{proxy::Proxy, quinn::{client_endpoint_via_proxy, QuinnConnection}};
+///
+/// let proxy = Proxy::socks5("proxy.example.com:1080");
+/// let endpoint = client_endpoint_via_proxy(&proxy, config).await?;
+/// let connection = QuinnConnection::<u64, u64>::new(endpoint, addr, "localhost".into());
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "proxy")]
+pub async fn client_endpoint_via_proxy(
+    proxy: &super::proxy::Proxy,
+    config: quinn::ClientConfig,
+) -> io::Result<quinn::Endpoint> {
+    let (control, relay) = proxy.udp_associate().await?;
+    // the relay only accepts datagrams from the host of the control connection
+    let bind = SocketAddr::new(control.local_addr()?.ip(), 0);
+    let io = tokio::net::UdpSocket::bind(bind).await?;
+    io.connect(relay).await?;
+    let socket = Socks5UdpSocket {
+        io,
+        _control: control,
+    };
+    let mut endpoint = quinn::Endpoint::new_with_abstract_socket(
+        quinn::EndpointConfig::default(),
+        None,
+        socket,
+        quinn::TokioRuntime,
+    )?;
+    endpoint.set_default_client_config(config);
+    Ok(endpoint)
+}
+
+/// A UDP socket whose datagrams are relayed by a SOCKS5 proxy
+///
+/// Every datagram to and from the relay starts with the address of the peer.
+#[cfg(feature = "proxy")]
+#[derive(Debug)]
+struct Socks5UdpSocket {
+    /// Connected to the relay
+    io: tokio::net::UdpSocket,
+    /// The proxy ends the relay once this is closed
+    _control: tokio::net::TcpStream,
+}
+
+#[cfg(feature = "proxy")]
+impl quinn::AsyncUdpSocket for Socks5UdpSocket {
+    fn poll_send(
+        &mut self,
+        _state: &quinn_udp::UdpState,
+        cx: &mut Context,
+        transmits: &[quinn::Transmit],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = Vec::new();
+        for (sent, transmit) in transmits.iter().enumerate() {
+            // split segmentation offloads into single datagrams
+            let size = transmit.segment_size.unwrap_or(transmit.contents.len());
+            for datagram in transmit.contents.chunks(size.max(1)) {
+                buf.clear();
+                // reserved and fragment number
+                buf.extend_from_slice(&[0, 0, 0]);
+                super::proxy::Target::Addr(transmit.destination).encode(&mut buf)?;
+                buf.extend_from_slice(datagram);
+                match self.io.poll_send(cx, &buf) {
+                    Poll::Ready(Ok(_)) => {}
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending if sent > 0 => return Poll::Ready(Ok(sent)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+        }
+        Poll::Ready(Ok(transmits.len()))
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [io::IoSliceMut<'_>],
+        meta: &mut [quinn_udp::RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let (buf, meta) = match (bufs.first_mut(), meta.first_mut()) {
+            (Some(buf), Some(meta)) => (buf, meta),
+            _ => return Poll::Ready(Ok(0)),
+        };
+        loop {
+            let mut read = tokio::io::ReadBuf::new(buf);
+            futures::ready!(self.io.poll_recv(cx, &mut read))?;
+            let len = read.filled().len();
+            // fragments are not reassembled, and only IP addresses are expected
+            let parsed = match buf.get(..3) {
+                Some([0, 0, 0]) => super::proxy::decode_addr(&buf[3..len]),
+                _ => None,
+            };
+            let (addr, header) = match parsed {
+                Some((addr, header)) => (addr, header + 3),
+                None => {
+                    tracing::debug!("dropping invalid datagram from the proxy");
+                    continue;
+                }
+            };
+            buf.copy_within(header..len, 0);
+            *meta = quinn_udp::RecvMeta {
+                addr,
+                len: len - header,
+                stride: len - header,
+                ecn: None,
+                dst_ip: None,
+            };
+            return Poll::Ready(Ok(1));
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+}
@@ -0,0 +1,406 @@
+//! QUIC transport implementation based on [s2n-quic](https://crates.io/crates/s2n-quic)
+//!
+//! [S2nQuicConnection] and [S2nQuicServerEndpoint] mirror the connection and
+//! server endpoint of the quinn transport: the client connects to a server by
+//! address and server name, and every channel is a new bidirectional stream of
+//! that connection. The server endpoint accepts connections in a loop and
+//! serves the streams of all of them.
+//!
+//! s2n-quic follows the latest stable Rust release, so this transport needs a
+//! newer compiler than the rest of the crate.
+//!
+//! ```ignore
+//! let server = s2n_quic::Server::builder()
+//!     .with_tls((CERT_PEM, KEY_PEM))?
+//!     .with_io("127.0.0.1:4433")?
+//!     .start()?;
+//! let server = S2nQuicServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?;
+//!
+//! let client = s2n_quic::Client::builder()
+//!     .with_tls(CERT_PEM)?
+//!     .with_io("0.0.0.0:0")?
+//!     .start()?;
+//! let client = S2nQuicConnection::<ComputeResponse, ComputeRequest>::new(client, addr, "localhost".into());
+//! ```
+//!
+//! The TLS provider is chosen when building the [s2n_quic::Client] and
+//! [s2n_quic::Server], e.g. the FIPS validated provider of the
+//! `provider-tls-fips` feature of s2n-quic.
+use super::{
+    util::{Codec, FramedBincodeRead, FramedBincodeWrite, Framing},
+    Capabilities, ConnectionCommon, Direction,
+};
+use crate::{
+    transport::{Connection, ConnectionErrors, LocalAddr, ServerEndpoint},
+    RpcMessage,
+};
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use pin_project::pin_project;
+use s2n_quic::{
+    client::Connect,
+    connection::Handle,
+    stream::{BidirectionalStream, ReceiveStream, SendStream},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Maximum length of a single frame
+pub const MAX_FRAME_LENGTH: usize = 1024 * 1024 * 16;
+
+type Socket<In, Out> = (SendSink<Out>, RecvStream<In>);
+
+/// Split a bidirectional stream into the two sides of a channel
+fn socket<In: RpcMessage, Out: RpcMessage>(
+    stream: BidirectionalStream,
+    peer: Option<SocketAddr>,
+    framing: Framing,
+    direction: Direction,
+) -> Socket<In, Out> {
+    let (recv, send) = stream.split();
+    let send = SendSink(FramedBincodeWrite::new(send, framing.clone()));
+    let recv = RecvStream(FramedBincodeRead::new(recv, framing, direction), peer);
+    (send, recv)
+}
+
+#[derive(Debug)]
+struct ClientInner {
+    client: s2n_quic::Client,
+    addr: SocketAddr,
+    name: String,
+    /// The current connection, established by the first call
+    connection: tokio::sync::Mutex<Option<Handle>>,
+}
+
+impl ClientInner {
+    /// A handle to the current connection, connecting if there is none
+    async fn handle(&self) -> io::Result<Handle> {
+        let mut current = self.connection.lock().await;
+        if let Some(handle) = current.as_ref() {
+            return Ok(handle.clone());
+        }
+        tracing::debug!("Connecting to {} as {}", self.addr, self.name);
+        let connect = Connect::new(self.addr).with_server_name(self.name.as_str());
+        let mut connection = self.client.connect(connect).await?;
+        connection.keep_alive(true)?;
+        // the server does not open streams, so only the handle is kept
+        let (handle, _) = connection.split();
+        *current = Some(handle.clone());
+        Ok(handle)
+    }
+
+    /// Forget the connection of `handle`, so the next call reconnects
+    async fn forget(&self, handle: &Handle) {
+        let mut current = self.connection.lock().await;
+        if current.as_ref().map(Handle::id) == Some(handle.id()) {
+            *current = None;
+        }
+    }
+}
+
+/// A connection to a server that serves calls with a [S2nQuicServerEndpoint]
+///
+/// The QUIC connection is established when the first channel is opened, and
+/// established again if opening a channel fails, e.g. because the server
+/// closed the connection.
+pub struct S2nQuicConnection<In, Out> {
+    inner: Arc<ClientInner>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for S2nQuicConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for S2nQuicConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S2nQuicConnection")
+            .field("addr", &self.inner.addr)
+            .field("name", &self.inner.name)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> S2nQuicConnection<In, Out> {
+    /// Call the server at `addr` with server name `name`, connecting with `client`
+    pub fn new(client: s2n_quic::Client, addr: SocketAddr, name: String) -> Self {
+        Self {
+            inner: Arc::new(ClientInner {
+                client,
+                addr,
+                name,
+                connection: Default::default(),
+            }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        }
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// The server endpoint must use the same codec, see
+    /// [S2nQuicServerEndpoint::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+
+    /// The address of the server
+    pub fn addr(&self) -> SocketAddr {
+        self.inner.addr
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for S2nQuicConnection<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for S2nQuicConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        recv.peer_addr()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for S2nQuicConnection<In, Out> {
+    type OpenBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let framing = self.framing.clone();
+        async move {
+            let mut handle = inner.handle().await?;
+            match handle.open_bidirectional_stream().await {
+                Ok(stream) => Ok(socket(
+                    stream,
+                    Some(inner.addr),
+                    framing,
+                    Direction::Response,
+                )),
+                Err(cause) => {
+                    inner.forget(&handle).await;
+                    Err(cause.into())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct ServerInner {
+    task: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A server endpoint that serves the calls on all connections of a [s2n_quic::Server]
+pub struct S2nQuicServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<(BidirectionalStream, Option<SocketAddr>)>,
+    framing: Framing,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for S2nQuicServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            framing: self.framing.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for S2nQuicServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S2nQuicServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> S2nQuicServerEndpoint<In, Out> {
+    /// Serve the calls on the connections accepted by `server`
+    ///
+    /// Connections are accepted until the endpoint and all its clones are
+    /// dropped, which also closes the accepted connections.
+    pub fn new(mut server: s2n_quic::Server) -> io::Result<Self> {
+        let local_addr = server.local_addr()?;
+        let (sender, accept) = flume::bounded(16);
+        let task = tokio::spawn(async move {
+            // dropped with the task, which aborts the tasks of all connections
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    connection = server.accept() => {
+                        let mut connection = match connection {
+                            Some(connection) => connection,
+                            None => break,
+                        };
+                        let sender = sender.clone();
+                        connections.spawn(async move {
+                            let peer = connection.remote_addr().ok();
+                            while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
+                                if sender.send_async((stream, peer)).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                task,
+                local_addr: [LocalAddr::Socket(local_addr)],
+            }),
+            accept,
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _p: PhantomData,
+        })
+    }
+
+    /// Encode messages using `codec` instead of [Codec::Bincode].
+    ///
+    /// Clients must use the same codec, see [S2nQuicConnection::with_codec].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.framing.codec = codec;
+        self
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for S2nQuicServerEndpoint<In, Out> {
+    type OpenError = io::Error;
+    type SendError = io::Error;
+    type RecvError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for S2nQuicServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+
+    fn peer_addr(recv: &Self::RecvStream) -> Option<SocketAddr> {
+        recv.peer_addr()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for S2nQuicServerEndpoint<In, Out> {
+    type AcceptBiFut = BoxFuture<'static, io::Result<Socket<In, Out>>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        let framing = self.framing.clone();
+        async move {
+            let (stream, peer) = accept.recv_async().await.map_err(|_| {
+                io::Error::new(io::ErrorKind::NotConnected, "no longer accepting streams")
+            })?;
+            Ok(socket(stream, peer, framing, Direction::Request))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
+
+/// A sink that wraps a s2n-quic [SendStream] with length delimiting and bincode
+#[pin_project]
+pub struct SendSink<Out>(#[pin] FramedBincodeWrite<SendStream, Out>);
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    /// Get the underlying [SendStream], which implements
+    /// [tokio::io::AsyncWrite] and can be used to send bytes directly.
+    pub fn into_inner(self) -> SendStream {
+        self.0.into_inner()
+    }
+}
+
+impl<Out: Serialize + Send + 'static> Sink<Out> for SendSink<Out> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_ready_unpin(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> io::Result<()> {
+        self.project().0.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().0.poll_close_unpin(cx)
+    }
+}
+
+/// A stream that wraps a s2n-quic [ReceiveStream] with length delimiting and bincode
+#[pin_project]
+pub struct RecvStream<In>(
+    #[pin] FramedBincodeRead<ReceiveStream, In>,
+    Option<SocketAddr>,
+);
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream").field("peer", &self.1).finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// The address of the other end of the channel
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.1
+    }
+
+    /// Get the underlying [ReceiveStream], which implements
+    /// [tokio::io::AsyncRead] and can be used to receive bytes directly.
+    pub fn into_inner(self) -> ReceiveStream {
+        self.0.into_inner()
+    }
+}
+
+impl<In: DeserializeOwned + Send + 'static> Stream for RecvStream<In> {
+    type Item = io::Result<In>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().0.poll_next_unpin(cx)
+    }
+}
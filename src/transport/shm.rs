@@ -0,0 +1,607 @@
+//! Transport over shared memory, for calls between processes on the same host
+//!
+//! A [ShmChannel] is a memory mapped file with a ring buffer for each
+//! direction. Messages are copied into the ring of their direction and read
+//! from it by the other side, without going through the kernel. The kernel is
+//! only involved to wake up a side that waits for data or for room in a ring,
+//! using an eventfd, and only when that side actually waits.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::shm::{ShmChannel, ShmConnection, ShmServerEndpoint};
+//!
+//! let channel = ShmChannel::new(1 << 20)?;
+//! // pass channel.fds() to the other process, e.g. over a unix socket
+//! let server = ShmServerEndpoint::<u64, u64>::new(&channel)?;
+//! let client = ShmConnection::<u64, u64>::new(&channel)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every channel has a client side and a server side, each of which must only
+//! be opened once, in either process. Like the tcp transport, every rpc
+//! channel is a logical stream on the shared memory, so all interaction
+//! patterns work. A process that exits without closing its side leaves the
+//! other side waiting, so this is meant for processes that are supervised
+//! together.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    ready, FutureExt,
+};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    mem,
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    ptr, result,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinHandle,
+};
+
+const CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    // messages stay on the host, but in memory that other processes can map
+    encrypted: false,
+    multiplexed: true,
+};
+
+/// The state of a ring, at the start of the mapping
+///
+/// Positions count the bytes written to and read from the ring so far, the
+/// byte at a position is at the position modulo the capacity of the ring. The
+/// other process might not follow the protocol, so they are never trusted to
+/// be within bounds.
+#[repr(C, align(64))]
+struct RingHeader {
+    write: AtomicU64,
+    _write_line: [u8; 56],
+    read: AtomicU64,
+    _read_line: [u8; 56],
+    reader_waiting: AtomicU32,
+    writer_waiting: AtomicU32,
+    reader_closed: AtomicU32,
+    writer_closed: AtomicU32,
+}
+
+/// The space for each ring header
+const RING_HEADER: usize = 256;
+
+/// The headers of both rings, followed by the data of both rings
+const HEADER: usize = 2 * RING_HEADER;
+
+const _: () = assert!(mem::size_of::<RingHeader>() <= RING_HEADER);
+
+// all accesses to the shared state are sequentially consistent, so a side that
+// announces that it waits and then checks the ring can not miss an update of
+// the other side that checks for waiters after updating the ring
+const ORDER: Ordering = Ordering::SeqCst;
+
+fn cvt(ret: i32) -> io::Result<i32> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn eventfd() -> io::Result<OwnedFd> {
+    // SAFETY: no pointers are involved
+    let fd = cvt(unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) })?;
+    // SAFETY: the eventfd was just created, so nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Wake up the side that waits on an eventfd
+fn signal(fd: &OwnedFd) {
+    let one = 1u64;
+    // SAFETY: one is valid for reads of 8 bytes
+    // this only fails if the counter overflows, which still wakes up the waiter
+    unsafe { libc::write(fd.as_raw_fd(), &one as *const u64 as *const libc::c_void, 8) };
+}
+
+/// Reset an eventfd after waking up
+fn drain(fd: &OwnedFd) -> io::Result<()> {
+    let mut count = 0u64;
+    // SAFETY: count is valid for writes of 8 bytes
+    let n = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            &mut count as *mut u64 as *mut libc::c_void,
+            8,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// The shared memory of a channel, mapped into this process
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is only accessed through atomics, and through ranges
+// that are owned by one side, as handed over by the atomics
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(fd: &OwnedFd, len: usize) -> io::Result<Self> {
+        // SAFETY: the mapping is not used before it is checked for failure
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping is no longer used
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+struct Shared {
+    map: Mapping,
+    capacity: usize,
+    memfd: OwnedFd,
+    /// The eventfd to wake up the reader, and the one to wake up the writer,
+    /// of each ring
+    events: [[OwnedFd; 2]; 2],
+}
+
+impl Shared {
+    fn header(&self, ring: usize) -> &RingHeader {
+        // SAFETY: the mapping is large enough for both headers, and the
+        // header only consists of atomics, which are valid for any contents
+        unsafe { &*(self.map.ptr.add(ring * RING_HEADER) as *const RingHeader) }
+    }
+
+    /// Copy the bytes from `pos` out of a ring
+    ///
+    /// # Safety
+    ///
+    /// The bytes must have been written and not been read yet.
+    unsafe fn copy_out(&self, ring: usize, pos: u64, dst: &mut [u8]) {
+        let data = self.map.ptr.add(HEADER + ring * self.capacity);
+        let start = (pos % self.capacity as u64) as usize;
+        let first = dst.len().min(self.capacity - start);
+        ptr::copy_nonoverlapping(data.add(start), dst.as_mut_ptr(), first);
+        ptr::copy_nonoverlapping(data, dst.as_mut_ptr().add(first), dst.len() - first);
+    }
+
+    /// Copy bytes into a ring, starting at `pos`
+    ///
+    /// # Safety
+    ///
+    /// There must be room for the bytes.
+    unsafe fn copy_in(&self, ring: usize, pos: u64, src: &[u8]) {
+        let data = self.map.ptr.add(HEADER + ring * self.capacity);
+        let start = (pos % self.capacity as u64) as usize;
+        let first = src.len().min(self.capacity - start);
+        ptr::copy_nonoverlapping(src.as_ptr(), data.add(start), first);
+        ptr::copy_nonoverlapping(src.as_ptr().add(first), data, src.len() - first);
+    }
+}
+
+/// Shared memory for calls between two processes
+///
+/// The client uses a [ShmConnection] and the server a [ShmServerEndpoint],
+/// both created from the same channel, or from the file descriptors of the
+/// channel in another process.
+pub struct ShmChannel {
+    shared: Arc<Shared>,
+}
+
+impl fmt::Debug for ShmChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShmChannel")
+            .field("capacity", &self.shared.capacity)
+            .finish()
+    }
+}
+
+impl ShmChannel {
+    /// Create a new channel, with rings of `capacity` bytes for each direction
+    ///
+    /// Messages that are larger than the capacity are passed through the ring
+    /// in parts, so the capacity only needs to be large enough for a few
+    /// messages in flight.
+    pub fn new(capacity: usize) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the capacity must not be zero",
+            ));
+        }
+        let len = HEADER + 2 * capacity;
+        let name = b"quic-rpc\0";
+        // SAFETY: name is nul terminated
+        let fd = cvt(unsafe { libc::memfd_create(name.as_ptr().cast(), libc::MFD_CLOEXEC) })?;
+        // SAFETY: the file was just created, so nothing else owns it
+        let memfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: no pointers are involved
+        cvt(unsafe { libc::ftruncate(memfd.as_raw_fd(), len as libc::off_t) })?;
+        let events = [[eventfd()?, eventfd()?], [eventfd()?, eventfd()?]];
+        Self::from_parts(memfd, events, len)
+    }
+
+    /// Open a channel in another process, from the file descriptors given by
+    /// [ShmChannel::fds]
+    ///
+    /// # Safety
+    ///
+    /// The file descriptors must be those of a channel, in the same order.
+    pub unsafe fn from_fds(fds: [OwnedFd; 5]) -> io::Result<Self> {
+        let [memfd, a, b, c, d] = fds;
+        let mut stat: libc::stat = mem::zeroed();
+        cvt(libc::fstat(memfd.as_raw_fd(), &mut stat))?;
+        let len = stat.st_size as usize;
+        if len <= HEADER || (len - HEADER) % 2 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not the memory of a channel",
+            ));
+        }
+        Self::from_parts(memfd, [[a, b], [c, d]], len)
+    }
+
+    fn from_parts(memfd: OwnedFd, events: [[OwnedFd; 2]; 2], len: usize) -> io::Result<Self> {
+        let map = Mapping::new(&memfd, len)?;
+        Ok(Self {
+            shared: Arc::new(Shared {
+                map,
+                capacity: (len - HEADER) / 2,
+                memfd,
+                events,
+            }),
+        })
+    }
+
+    /// The file descriptors of the channel, to open it in another process
+    ///
+    /// These are closed on exec, so they need to be passed to the other
+    /// process over a unix socket, or inherited without the close on exec
+    /// flag.
+    pub fn fds(&self) -> [BorrowedFd<'_>; 5] {
+        let [[a, b], [c, d]] = &self.shared.events;
+        [
+            self.shared.memfd.as_fd(),
+            a.as_fd(),
+            b.as_fd(),
+            c.as_fd(),
+            d.as_fd(),
+        ]
+    }
+
+    /// The size of the ring of each direction, in bytes
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity
+    }
+
+    /// Open one side of the channel, writing to ring `side` and reading the other one
+    fn open(&self, side: usize) -> io::Result<(ShmReader, ShmWriter)> {
+        let events = &self.shared.events;
+        let reader = ShmReader {
+            shared: self.shared.clone(),
+            ring: 1 - side,
+            wake: AsyncFd::new(events[1 - side][0].try_clone()?)?,
+        };
+        let writer = ShmWriter {
+            shared: self.shared.clone(),
+            ring: side,
+            wake: AsyncFd::new(events[side][1].try_clone()?)?,
+        };
+        Ok((reader, writer))
+    }
+}
+
+/// Reads from a ring of a [ShmChannel]
+struct ShmReader {
+    shared: Arc<Shared>,
+    ring: usize,
+    wake: AsyncFd<OwnedFd>,
+}
+
+impl AsyncRead for ShmReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let shared = &*this.shared;
+        let header = shared.header(this.ring);
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let read = header.read.load(ORDER);
+            let available = header.write.load(ORDER).wrapping_sub(read);
+            if available > 0 {
+                let n = (available.min(shared.capacity as u64) as usize).min(buf.remaining());
+                // SAFETY: the bytes were written, and are only overwritten once read
+                unsafe { shared.copy_out(this.ring, read, buf.initialize_unfilled_to(n)) };
+                buf.advance(n);
+                header.read.store(read.wrapping_add(n as u64), ORDER);
+                if header.writer_waiting.swap(0, ORDER) != 0 {
+                    signal(&shared.events[this.ring][1]);
+                }
+                return Poll::Ready(Ok(()));
+            }
+            // the writer is done once everything it wrote is read
+            if header.writer_closed.load(ORDER) != 0 {
+                if header.write.load(ORDER) == read {
+                    return Poll::Ready(Ok(()));
+                }
+                continue;
+            }
+            // check again after announcing that we wait
+            if header.reader_waiting.swap(1, ORDER) == 0 {
+                continue;
+            }
+            let mut guard = ready!(this.wake.poll_read_ready(cx))?;
+            if let Ok(res) = guard.try_io(|fd| drain(fd.get_ref())) {
+                res?;
+            }
+        }
+    }
+}
+
+impl Drop for ShmReader {
+    fn drop(&mut self) {
+        self.shared.header(self.ring).reader_closed.store(1, ORDER);
+        signal(&self.shared.events[self.ring][1]);
+    }
+}
+
+/// Writes to a ring of a [ShmChannel]
+struct ShmWriter {
+    shared: Arc<Shared>,
+    ring: usize,
+    wake: AsyncFd<OwnedFd>,
+}
+
+impl ShmWriter {
+    fn close(&self) {
+        self.shared.header(self.ring).writer_closed.store(1, ORDER);
+        signal(&self.shared.events[self.ring][0]);
+    }
+}
+
+impl AsyncWrite for ShmWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let shared = &*this.shared;
+        let header = shared.header(this.ring);
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if header.reader_closed.load(ORDER) != 0 {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            let write = header.write.load(ORDER);
+            let used = write.wrapping_sub(header.read.load(ORDER));
+            let free = (shared.capacity as u64).saturating_sub(used) as usize;
+            if free > 0 {
+                let n = free.min(buf.len());
+                // SAFETY: the reader is done with the free part of the ring
+                unsafe { shared.copy_in(this.ring, write, &buf[..n]) };
+                header.write.store(write.wrapping_add(n as u64), ORDER);
+                if header.reader_waiting.swap(0, ORDER) != 0 {
+                    signal(&shared.events[this.ring][0]);
+                }
+                return Poll::Ready(Ok(n));
+            }
+            // check again after announcing that we wait
+            if header.writer_waiting.swap(1, ORDER) == 0 {
+                continue;
+            }
+            let mut guard = ready!(this.wake.poll_read_ready(cx))?;
+            if let Ok(res) = guard.try_io(|fd| drain(fd.get_ref())) {
+                res?;
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // written bytes are visible to the reader right away
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ShmWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A connection to a [ShmServerEndpoint] on the other side of a [ShmChannel]
+pub struct ShmConnection<In, Out> {
+    client: Arc<Client>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for ShmConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for ShmConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShmConnection").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ShmConnection<In, Out> {
+    /// Open the client side of `channel`
+    ///
+    /// Once the server closes its side, opening channels fails and all open
+    /// channels end.
+    pub fn new(channel: &ShmChannel) -> io::Result<Self> {
+        let (read, write) = channel.open(0)?;
+        Ok(Self {
+            client: Arc::new(Client::new(read, write)),
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for ShmConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for ShmConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for ShmConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    serve: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.serve.abort();
+    }
+}
+
+/// A server endpoint that serves the calls of the client on the other side
+/// of a [ShmChannel]
+///
+/// Once the client closes its side, accepting channels fails with
+/// [OpenError::Closed].
+pub struct ShmServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for ShmServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for ShmServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShmServerEndpoint").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ShmServerEndpoint<In, Out> {
+    /// Open the server side of `channel`
+    pub fn new(channel: &ShmChannel) -> io::Result<Self> {
+        let (read, write) = channel.open(1)?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let serve = tokio::spawn(framed::serve(read, write, (), accept_tx));
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                serve,
+                local_addr: [LocalAddr::Mem],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for ShmServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for ShmServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for ShmServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
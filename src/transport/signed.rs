@@ -0,0 +1,526 @@
+//! Ed25519 signatures for every message
+//!
+//! [SignedConnection] and [SignedServerEndpoint] wrap a transport so that every
+//! message is serialized, signed with the key of the sender and verified by the
+//! receiver, independent of any encryption provided by the transport. This is
+//! useful when messages cross hops that terminate the transport encryption, or
+//! when the peer identity on the transport level is not meaningful.
+//!
+//! The receive stream of a channel remembers the key that signed its first
+//! message, and rejects messages signed by any other key. Handlers can get the
+//! identity of the client from the receive stream of their channel:
+//!
+//! ```ignore
+//! let (req, chan) = server.accept().await?;
+//! let signer = chan.recv.signer();
+//! ```
+//!
+//! By default all valid signatures are accepted. Use [SigningConfig::trust] to
+//! only accept messages from a set of known keys.
+//!
+//! Signatures cover the direction of the message, so a signed request can not
+//! be reflected as a response. They do not protect against replays of whole
+//! messages. Both sides must use the wrappers, since they change the wire format.
+use super::{
+    Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+};
+use crate::RpcMessage;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use futures::{future::BoxFuture, FutureExt, Sink, Stream};
+use pin_project::pin_project;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    error, fmt,
+    marker::PhantomData,
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Signature context of requests
+const REQUEST_CONTEXT: &[u8] = b"quic-rpc signed request";
+/// Signature context of responses
+const RESPONSE_CONTEXT: &[u8] = b"quic-rpc signed response";
+
+/// Wire format of messages on a signed connection
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Signed<T> {
+    payload: Vec<u8>,
+    signer: VerifyingKey,
+    signature: Signature,
+    #[serde(skip)]
+    _p: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for Signed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signed")
+            .field("len", &self.payload.len())
+            .field("signer", &self.signer)
+            .finish()
+    }
+}
+
+impl<T> Signed<T> {
+    /// The key that signed this message, which is not verified yet
+    pub fn signer(&self) -> VerifyingKey {
+        self.signer
+    }
+}
+
+impl<T: Serialize> Signed<T> {
+    fn sign(msg: &T, key: &SigningKey, context: &[u8]) -> bincode::Result<Self> {
+        let payload = bincode::serialize(msg)?;
+        let signature = key.sign(&signed_bytes(context, &payload));
+        Ok(Self {
+            payload,
+            signer: key.verifying_key(),
+            signature,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Signed<T> {
+    fn verify(&self, context: &[u8]) -> result::Result<T, VerifyError> {
+        self.signer
+            .verify_strict(&signed_bytes(context, &self.payload), &self.signature)
+            .map_err(|_| VerifyError::BadSignature)?;
+        bincode::deserialize(&self.payload).map_err(VerifyError::Deserialize)
+    }
+}
+
+fn signed_bytes(context: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(context.len() + payload.len());
+    bytes.extend_from_slice(context);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Keys used by a [SignedConnection] or [SignedServerEndpoint]
+#[derive(Clone)]
+pub struct SigningConfig {
+    key: Arc<SigningKey>,
+    trusted: Option<Arc<HashSet<[u8; 32]>>>,
+}
+
+impl fmt::Debug for SigningConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigningConfig")
+            .field("key", &self.key.verifying_key())
+            .field("trusted", &self.trusted.as_ref().map(|keys| keys.len()))
+            .finish()
+    }
+}
+
+impl SigningConfig {
+    /// Sign messages with `key`, accepting messages signed by any key
+    pub fn new(key: SigningKey) -> Self {
+        Self {
+            key: Arc::new(key),
+            trusted: None,
+        }
+    }
+
+    /// Accept messages signed by `key`
+    ///
+    /// Once a key is trusted, messages signed by keys that are not trusted
+    /// are rejected.
+    pub fn trust(mut self, key: VerifyingKey) -> Self {
+        let trusted = self.trusted.get_or_insert_with(Default::default);
+        Arc::make_mut(trusted).insert(key.to_bytes());
+        self
+    }
+
+    /// The public key that messages are signed with
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.key.verifying_key()
+    }
+
+    fn is_trusted(&self, key: &VerifyingKey) -> bool {
+        match &self.trusted {
+            Some(trusted) => trusted.contains(key.as_bytes()),
+            None => true,
+        }
+    }
+}
+
+/// A connection that signs requests and verifies responses
+pub struct SignedConnection<C, In, Out> {
+    inner: C,
+    config: SigningConfig,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<C, In, Out> SignedConnection<C, In, Out>
+where
+    C: Connection<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a connection
+    pub fn new(inner: C, config: SigningConfig) -> Self {
+        Self {
+            inner,
+            config,
+            _p: PhantomData,
+        }
+    }
+
+    /// Get back the inner connection
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clone, In, Out> Clone for SignedConnection<C, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<C: fmt::Debug, In, Out> fmt::Debug for SignedConnection<C, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedConnection")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<C: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for SignedConnection<C, In, Out>
+{
+    type OpenError = C::OpenError;
+    type SendError = SendError<C::SendError>;
+    type RecvError = RecvError<C::RecvError>;
+
+    fn retry_after(error: &Self::RecvError) -> Option<Duration> {
+        match error {
+            RecvError::Inner(cause) => C::retry_after(cause),
+            _ => None,
+        }
+    }
+}
+
+impl<C, In, Out> ConnectionCommon<In, Out> for SignedConnection<C, In, Out>
+where
+    C: Connection<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<C::SendSink, Out>;
+    type RecvStream = self::RecvStream<C::RecvStream, In>;
+    const CAPABILITIES: Capabilities = C::CAPABILITIES;
+}
+
+impl<C, In, Out> Connection<In, Out> for SignedConnection<C, In, Out>
+where
+    C: Connection<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type OpenBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        async move {
+            let (send, recv) = inner.open_bi().await?;
+            let send = SendSink::new(send, config.clone(), REQUEST_CONTEXT);
+            let recv = RecvStream::new(recv, config, RESPONSE_CONTEXT);
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+}
+
+/// A server endpoint that verifies requests and signs responses
+pub struct SignedServerEndpoint<E, In, Out> {
+    inner: E,
+    config: SigningConfig,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<E, In, Out> SignedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    /// Wrap a server endpoint
+    pub fn new(inner: E, config: SigningConfig) -> Self {
+        Self {
+            inner,
+            config,
+            _p: PhantomData,
+        }
+    }
+
+    /// Get back the inner server endpoint
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: Clone, In, Out> Clone for SignedServerEndpoint<E, In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<E: fmt::Debug, In, Out> fmt::Debug for SignedServerEndpoint<E, In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignedServerEndpoint")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl<E: ConnectionErrors, In: RpcMessage, Out: RpcMessage> ConnectionErrors
+    for SignedServerEndpoint<E, In, Out>
+{
+    type OpenError = E::OpenError;
+    type SendError = SendError<E::SendError>;
+    type RecvError = RecvError<E::RecvError>;
+}
+
+impl<E, In, Out> ConnectionCommon<In, Out> for SignedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type SendSink = self::SendSink<E::SendSink, Out>;
+    type RecvStream = self::RecvStream<E::RecvStream, In>;
+    const CAPABILITIES: Capabilities = E::CAPABILITIES;
+}
+
+impl<E, In, Out> ServerEndpoint<In, Out> for SignedServerEndpoint<E, In, Out>
+where
+    E: ServerEndpoint<Signed<In>, Signed<Out>>,
+    In: RpcMessage,
+    Out: RpcMessage,
+{
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), Self::OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let inner = self.inner.clone();
+        let config = self.config.clone();
+        async move {
+            let (send, recv) = inner.accept_bi().await?;
+            let send = SendSink::new(send, config.clone(), RESPONSE_CONTEXT);
+            let recv = RecvStream::new(recv, config, REQUEST_CONTEXT);
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        self.inner.local_addr()
+    }
+}
+
+/// Send sink for signed channels
+///
+/// Serializes and signs every message before passing it to the inner sink.
+#[pin_project]
+pub struct SendSink<S, T> {
+    #[pin]
+    inner: S,
+    config: SigningConfig,
+    context: &'static [u8],
+    _p: PhantomData<fn(T)>,
+}
+
+impl<S, T> SendSink<S, T> {
+    fn new(inner: S, config: SigningConfig, context: &'static [u8]) -> Self {
+        Self {
+            inner,
+            config,
+            context,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<S, T> fmt::Debug for SendSink<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink").finish()
+    }
+}
+
+impl<S: Sink<Signed<T>>, T: Serialize> Sink<T> for SendSink<S, T> {
+    type Error = SendError<S::Error>;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_ready(cx)
+            .map_err(SendError::Inner)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: T) -> Result<(), Self::Error> {
+        let this = self.project();
+        let item =
+            Signed::sign(&msg, &this.config.key, this.context).map_err(SendError::Serialize)?;
+        this.inner.start_send(item).map_err(SendError::Inner)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_flush(cx)
+            .map_err(SendError::Inner)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.project()
+            .inner
+            .poll_close(cx)
+            .map_err(SendError::Inner)
+    }
+}
+
+/// Receive stream for signed channels
+///
+/// Verifies every message, and that all messages are signed by the same key.
+#[pin_project]
+pub struct RecvStream<R, T> {
+    #[pin]
+    inner: R,
+    config: SigningConfig,
+    context: &'static [u8],
+    signer: Option<VerifyingKey>,
+    _p: PhantomData<fn() -> T>,
+}
+
+impl<R, T> RecvStream<R, T> {
+    fn new(inner: R, config: SigningConfig, context: &'static [u8]) -> Self {
+        Self {
+            inner,
+            config,
+            context,
+            signer: None,
+            _p: PhantomData,
+        }
+    }
+
+    /// The key that signed the messages on this channel
+    ///
+    /// This is `None` until the first message has been received. On the server
+    /// side, the first message is the request, so this is always set for
+    /// channels returned by [RpcServer::accept](crate::RpcServer::accept).
+    pub fn signer(&self) -> Option<VerifyingKey> {
+        self.signer
+    }
+}
+
+impl<R, T> fmt::Debug for RecvStream<R, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("signer", &self.signer)
+            .finish()
+    }
+}
+
+impl<R, T, E> Stream for RecvStream<R, T>
+where
+    R: Stream<Item = result::Result<Signed<T>, E>>,
+    T: DeserializeOwned,
+{
+    type Item = result::Result<T, RecvError<E>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let item = match this.inner.poll_next(cx) {
+            Poll::Ready(Some(Ok(item))) => item,
+            Poll::Ready(Some(Err(cause))) => {
+                return Poll::Ready(Some(Err(RecvError::Inner(cause))))
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => return Poll::Pending,
+        };
+        let signer = item.signer;
+        match this.signer {
+            Some(expected) if *expected != signer => {
+                return Poll::Ready(Some(Err(RecvError::SignerChanged(signer))))
+            }
+            _ if !this.config.is_trusted(&signer) => {
+                return Poll::Ready(Some(Err(RecvError::Untrusted(signer))))
+            }
+            _ => {}
+        }
+        let msg = match item.verify(this.context) {
+            Ok(msg) => msg,
+            Err(VerifyError::BadSignature) => {
+                return Poll::Ready(Some(Err(RecvError::BadSignature(signer))))
+            }
+            Err(VerifyError::Deserialize(cause)) => {
+                return Poll::Ready(Some(Err(RecvError::Deserialize(cause))))
+            }
+        };
+        *this.signer = Some(signer);
+        Poll::Ready(Some(Ok(msg)))
+    }
+}
+
+enum VerifyError {
+    BadSignature,
+    Deserialize(bincode::Error),
+}
+
+/// Send error for signed channels
+#[derive(Debug)]
+pub enum SendError<E> {
+    /// The message could not be serialized
+    Serialize(bincode::Error),
+    /// The inner sink failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for SendError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for SendError<E> {}
+
+/// Receive error for signed channels
+#[derive(Debug)]
+pub enum RecvError<E> {
+    /// The signature of a message signed by this key is invalid
+    BadSignature(VerifyingKey),
+    /// The message is signed by a key that is not trusted
+    Untrusted(VerifyingKey),
+    /// The message is signed by a different key than the first message of the channel
+    SignerChanged(VerifyingKey),
+    /// The message could not be deserialized
+    Deserialize(bincode::Error),
+    /// The inner stream failed
+    Inner(E),
+}
+
+impl<E: fmt::Debug> fmt::Display for RecvError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<E: fmt::Debug> error::Error for RecvError<E> {}
@@ -0,0 +1,229 @@
+//! Transport over the standard streams of a child process, for plugins
+//!
+//! A host spawns a plugin as a child process with piped stdin and stdout, and
+//! calls it with a [StdioConnection] made with [StdioConnection::from_child].
+//! The plugin serves the calls with a [StdioServerEndpoint::stdio] on its own
+//! stdin and stdout:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::stdio::StdioConnection;
+//! use std::process::Stdio;
+//! use tokio::process::Command;
+//!
+//! let mut child = Command::new("my-plugin")
+//!     .stdin(Stdio::piped())
+//!     .stdout(Stdio::piped())
+//!     .spawn()?;
+//! let client = StdioConnection::<u64, u64>::from_child(&mut child)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Like the tcp transport, every channel is a logical stream on the pipes, so
+//! all interaction patterns work. The pipes carry nothing but frames, so the
+//! plugin must not write anything else to its stdout, e.g. logs have to go to
+//! stderr. Once the host drops the connection, the stdin of the plugin is
+//! closed and its endpoint stops accepting channels, so it can exit.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use std::{fmt, io, marker::PhantomData, result, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    process::Child,
+    task::JoinHandle,
+};
+
+/// Pipes between local processes never leave the host
+const CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    // the streams can be anything, e.g. pipes or a socket
+    encrypted: false,
+    multiplexed: true,
+};
+
+/// A connection to a [StdioServerEndpoint] on the other end of a pair of pipes
+pub struct StdioConnection<In, Out> {
+    client: Arc<Client>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for StdioConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for StdioConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StdioConnection").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> StdioConnection<In, Out> {
+    /// Call the server that reads from `write` and writes to `read`
+    ///
+    /// These are meant to be pipes to a local process. Once `read` ends,
+    /// opening channels fails and all open channels end.
+    pub fn new<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            client: Arc::new(Client::new(read, write)),
+            _p: PhantomData,
+        }
+    }
+
+    /// Call the server on the stdin and stdout of `child`
+    ///
+    /// This takes the stdin and stdout of `child`, which fails if they were
+    /// not spawned as [piped](std::process::Stdio::piped). The child itself
+    /// is left to the caller, e.g. to wait for it to exit.
+    pub fn from_child(child: &mut Child) -> io::Result<Self> {
+        if child.stdin.is_none() || child.stdout.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stdin and stdout of the child process must be piped",
+            ));
+        }
+        let stdin = child.stdin.take().expect("checked above");
+        let stdout = child.stdout.take().expect("checked above");
+        Ok(Self::new(stdout, stdin))
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for StdioConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for StdioConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for StdioConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    serve: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.serve.abort();
+    }
+}
+
+/// A server endpoint that serves the calls of a single client on a pair of pipes
+///
+/// Once the client closes its side, accepting channels fails with
+/// [OpenError::Closed].
+pub struct StdioServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for StdioServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for StdioServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StdioServerEndpoint").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> StdioServerEndpoint<In, Out> {
+    /// Serve the calls of the client that writes to `read` and reads from `write`
+    ///
+    /// These are meant to be pipes to a local process.
+    pub fn new<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (accept_tx, accept) = flume::bounded(32);
+        let serve = tokio::spawn(framed::serve(read, write, (), accept_tx));
+        Self {
+            inner: Arc::new(ServerInner {
+                serve,
+                local_addr: [LocalAddr::Mem],
+            }),
+            accept,
+            _p: PhantomData,
+        }
+    }
+
+    /// Serve the calls of the parent process on the stdin and stdout of this process
+    pub fn stdio() -> Self {
+        Self::new(tokio::io::stdin(), tokio::io::stdout())
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for StdioServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for StdioServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for StdioServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
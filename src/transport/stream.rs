@@ -0,0 +1,213 @@
+//! Transport over any byte stream, e.g. an SSH tunnel or a serial link
+//!
+//! [StreamConnection] and [StreamServerEndpoint] take an established duplex
+//! byte stream, anything that implements [AsyncRead] and [AsyncWrite], and use
+//! it like the tcp transport uses a socket: every channel is a logical stream
+//! with its own frames, so all interaction patterns work.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::stream::{StreamConnection, StreamServerEndpoint};
+//!
+//! let (client, server) = tokio::io::duplex(64 * 1024);
+//! let client = StreamConnection::<u64, u64>::new(client);
+//! let server = StreamServerEndpoint::<u64, u64>::new(server);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The stream only carries frames, it must not be used for anything else at
+//! the same time. Nothing is known about the link behind the stream, so the
+//! capabilities do not claim encryption, even if it is an encrypted tunnel.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use std::{fmt, marker::PhantomData, result, sync::Arc};
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite},
+    task::JoinHandle,
+};
+
+const CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    encrypted: false,
+    multiplexed: true,
+};
+
+/// A connection to a [StreamServerEndpoint] on the other end of a byte stream
+pub struct StreamConnection<In, Out> {
+    client: Arc<Client>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for StreamConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for StreamConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamConnection").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> StreamConnection<In, Out> {
+    /// Call the server on the other end of `stream`
+    ///
+    /// Once the stream ends, opening channels fails and all open channels end.
+    pub fn new<T>(stream: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = split(stream);
+        Self::from_split(read, write)
+    }
+
+    /// Call the server that reads from `write` and writes to `read`
+    pub fn from_split<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            client: Arc::new(Client::new(read, write)),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for StreamConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for StreamConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for StreamConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    serve: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.serve.abort();
+    }
+}
+
+/// A server endpoint that serves the calls of a single client on a byte stream
+///
+/// Once the client closes its side, accepting channels fails with
+/// [OpenError::Closed].
+pub struct StreamServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for StreamServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for StreamServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamServerEndpoint").finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> StreamServerEndpoint<In, Out> {
+    /// Serve the calls of the client on the other end of `stream`
+    pub fn new<T>(stream: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = split(stream);
+        Self::from_split(read, write)
+    }
+
+    /// Serve the calls of the client that writes to `read` and reads from `write`
+    pub fn from_split<R, W>(read: R, write: W) -> Self
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (accept_tx, accept) = flume::bounded(32);
+        let serve = tokio::spawn(framed::serve(read, write, (), accept_tx));
+        Self {
+            inner: Arc::new(ServerInner {
+                serve,
+                local_addr: [LocalAddr::Mem],
+            }),
+            accept,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for StreamServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for StreamServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for StreamServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
@@ -0,0 +1,256 @@
+//! Transport over plain TCP, for deployments where QUIC is not needed
+//!
+//! A [TcpConnection] uses a single TCP connection to the server, and every
+//! channel is a logical stream on it. Messages are encoded with bincode and
+//! sent as length delimited frames, tagged with the id of their channel. The
+//! first message of a channel opens it on the server, and closing a send sink
+//! sends an end marker, so all interaction patterns work, including calls
+//! without a response.
+//!
+//! All channels share the TCP connection, so a large message delays the
+//! messages of all other channels until it is sent. Messages that were
+//! received but not yet read are buffered per channel.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use std::{fmt, io, marker::PhantomData, net::SocketAddr, result, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinHandle,
+};
+
+/// A connection to a [TcpServerEndpoint]
+pub struct TcpConnection<In, Out> {
+    client: Arc<Client>,
+    remote_addr: SocketAddr,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for TcpConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            remote_addr: self.remote_addr,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for TcpConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpConnection")
+            .field("remote_addr", &self.remote_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> TcpConnection<In, Out> {
+    /// Connect to the server at `addr`
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr).await?)
+    }
+
+    /// Connect to the server at `host` and `port` through `proxy`
+    #[cfg(feature = "proxy")]
+    pub async fn connect_via_proxy(
+        proxy: &super::proxy::Proxy,
+        host: &str,
+        port: u16,
+    ) -> io::Result<Self> {
+        Self::from_stream(proxy.connect(host, port).await?)
+    }
+
+    /// Use an established TCP connection to the server
+    ///
+    /// Once the connection is closed, opening channels fails and all open
+    /// channels end.
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let remote_addr = stream.peer_addr()?;
+        let (read, write) = stream.into_split();
+        Ok(Self {
+            client: Arc::new(Client::new(read, write)),
+            remote_addr,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for TcpConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for TcpConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        encrypted: false,
+        multiplexed: true,
+    };
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for TcpConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    /// The task accepting connections, if the endpoint has a listener
+    listener: Option<JoinHandle<()>>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+    }
+}
+
+/// A server endpoint that serves calls on the TCP connections of a listener,
+/// or on a single established connection
+pub struct TcpServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for TcpServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for TcpServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TcpServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> TcpServerEndpoint<In, Out> {
+    /// Listen for connections on `addr`
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::serve(TcpListener::bind(addr).await?)
+    }
+
+    /// Serve calls on the connections accepted by `listener`
+    ///
+    /// Dropping all clones of the endpoint stops accepting connections.
+    /// Connections that were already accepted are served until they close.
+    pub fn serve(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let listener = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        tracing::debug!("tcp: accepted connection from {}", addr);
+                        stream
+                    }
+                    Err(cause) => {
+                        tracing::warn!("tcp: error accepting connection: {}", cause);
+                        continue;
+                    }
+                };
+                if stream.set_nodelay(true).is_err() {
+                    continue;
+                }
+                let (read, write) = stream.into_split();
+                tokio::spawn(framed::serve(read, write, (), accept_tx.clone()));
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener: Some(listener),
+                local_addr: [LocalAddr::Socket(local_addr)],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+
+    /// Serve calls on an established TCP connection
+    ///
+    /// This is for connections that were accepted or set up elsewhere, e.g.
+    /// after a custom handshake. Once the connection is closed, accepting
+    /// channels fails.
+    pub fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let local_addr = stream.local_addr()?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let (read, write) = stream.into_split();
+        tokio::spawn(framed::serve(read, write, (), accept_tx));
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener: None,
+                local_addr: [LocalAddr::Socket(local_addr)],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for TcpServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for TcpServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        encrypted: false,
+        multiplexed: true,
+    };
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for TcpServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
@@ -0,0 +1,407 @@
+//! Transport over TLS on TCP, for networks that only let TLS on TCP through
+//!
+//! This works like the tcp transport: a [TlsTcpConnection] uses a single
+//! connection to the server, and every channel is a logical stream on it.
+//! The connection is encrypted with rustls, configured with the
+//! [rustls::ClientConfig] and [rustls::ServerConfig] passed to the
+//! constructors, so client authentication and certificate pinning are up to
+//! those configs.
+//!
+//! ```no_run
+//! # async fn example(
+//! #     client_config: std::sync::Arc<rustls::ClientConfig>,
+//! #     server_config: std::sync::Arc<rustls::ServerConfig>,
+//! # ) -> std::io::Result<()> {
+//! use quic_rpc::transport::tls_tcp::{TlsTcpConnection, TlsTcpServerEndpoint};
+//!
+//! let server =
+//!     TlsTcpServerEndpoint::<u64, u64>::bind("0.0.0.0:443", server_config).await?;
+//! let name = "example.com".try_into().unwrap();
+//! let client =
+//!     TlsTcpConnection::<u64, u64>::connect("example.com:443", client_config, name).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The TLS session of a connection runs on a task of its own, which passes
+//! the plaintext to the multiplexing of the channels through an in-memory
+//! pipe.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, ServerName};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    marker::PhantomData,
+    net::SocketAddr,
+    result,
+    sync::Arc,
+};
+use tokio::{
+    io::{duplex, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    task::JoinHandle,
+};
+
+/// Size of the buffers for reading from the socket and the plaintext pipe
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Capacity of the in-memory pipe between the TLS session and the channels
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+const CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    encrypted: true,
+    multiplexed: true,
+};
+
+fn tls_error(error: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Write the TLS records the session wants to send
+async fn write_tls<W: AsyncWrite + Unpin>(
+    tcp: &mut W,
+    tls: &mut rustls::Connection,
+) -> io::Result<()> {
+    while tls.wants_write() {
+        let mut records = Vec::new();
+        tls.write_tls(&mut records)?;
+        tcp.write_all(&records).await?;
+    }
+    Ok(())
+}
+
+/// Pass TLS records read from the socket to the session
+fn read_tls(tls: &mut rustls::Connection, mut records: &[u8]) -> io::Result<()> {
+    while !records.is_empty() {
+        tls.read_tls(&mut records)?;
+        tls.process_new_packets().map_err(tls_error)?;
+    }
+    Ok(())
+}
+
+/// Complete the handshake of `tls` on `tcp`
+async fn handshake(tcp: &mut TcpStream, tls: &mut rustls::Connection) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    while tls.is_handshaking() {
+        write_tls(tcp, tls).await?;
+        if !tls.is_handshaking() {
+            break;
+        }
+        let n = tcp.read(&mut buffer).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during the TLS handshake",
+            ));
+        }
+        read_tls(tls, &buffer[..n])?;
+    }
+    write_tls(tcp, tls).await
+}
+
+/// Run the TLS session `tls` on `tcp`, with its plaintext on `plain`
+///
+/// Runs until the socket or the session is closed. Once the plaintext side
+/// is closed, the session is closed with a close notification.
+async fn bridge(mut tcp: TcpStream, mut tls: rustls::Connection, plain: DuplexStream) {
+    if let Err(cause) = bridge_io(&mut tcp, &mut tls, plain).await {
+        tracing::debug!("tls: connection failed: {}", cause);
+    }
+}
+
+async fn bridge_io(
+    tcp: &mut TcpStream,
+    tls: &mut rustls::Connection,
+    plain: DuplexStream,
+) -> io::Result<()> {
+    let (mut tcp_read, mut tcp_write) = tcp.split();
+    let (mut plain_read, mut plain_write) = split(plain);
+    let mut records = vec![0; BUFFER_SIZE];
+    let mut outgoing = vec![0; BUFFER_SIZE];
+    let mut incoming = vec![0; BUFFER_SIZE];
+    let mut sending = true;
+    loop {
+        write_tls(&mut tcp_write, tls).await?;
+        loop {
+            match tls.reader().read(&mut incoming) {
+                // the peer closed the session
+                Ok(0) => {
+                    plain_write.shutdown().await.ok();
+                    return Ok(());
+                }
+                Ok(n) => plain_write.write_all(&incoming[..n]).await?,
+                Err(cause) if cause.kind() == io::ErrorKind::WouldBlock => break,
+                Err(cause) => return Err(cause),
+            }
+        }
+        tokio::select! {
+            n = tcp_read.read(&mut records), if tls.wants_read() => {
+                let n = n?;
+                if n == 0 {
+                    plain_write.shutdown().await.ok();
+                    return Ok(());
+                }
+                read_tls(tls, &records[..n])?;
+            }
+            n = plain_read.read(&mut outgoing), if sending => {
+                let n = n?;
+                if n == 0 {
+                    tls.send_close_notify();
+                    sending = false;
+                } else {
+                    tls.writer().write_all(&outgoing[..n])?;
+                }
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+/// Run `tls` on `tcp` on a task, and return the read and write half of its plaintext
+fn spawn_bridge(
+    tcp: TcpStream,
+    tls: rustls::Connection,
+) -> (
+    impl AsyncRead + Send + Unpin,
+    impl AsyncWrite + Send + Unpin,
+) {
+    let (plain, session) = duplex(PIPE_CAPACITY);
+    tokio::spawn(bridge(tcp, tls, session));
+    split(plain)
+}
+
+/// A connection to a [TlsTcpServerEndpoint]
+pub struct TlsTcpConnection<In, Out> {
+    client: Arc<Client>,
+    remote_addr: SocketAddr,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for TlsTcpConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            remote_addr: self.remote_addr,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for TlsTcpConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTcpConnection")
+            .field("remote_addr", &self.remote_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> TlsTcpConnection<In, Out> {
+    /// Connect to the server at `addr`, whose certificate is valid for `server_name`
+    pub async fn connect(
+        addr: impl ToSocketAddrs,
+        config: Arc<ClientConfig>,
+        server_name: ServerName,
+    ) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr).await?, config, server_name).await
+    }
+
+    /// Connect to the server at `host` and `port` through `proxy`, whose
+    /// certificate is valid for `server_name`
+    ///
+    /// The proxy only sees the encrypted connection.
+    #[cfg(feature = "proxy")]
+    pub async fn connect_via_proxy(
+        proxy: &super::proxy::Proxy,
+        host: &str,
+        port: u16,
+        config: Arc<ClientConfig>,
+        server_name: ServerName,
+    ) -> io::Result<Self> {
+        Self::from_stream(proxy.connect(host, port).await?, config, server_name).await
+    }
+
+    /// Use an established TCP connection to the server
+    ///
+    /// This completes the TLS handshake before returning. Once the connection
+    /// is closed, opening channels fails and all open channels end.
+    pub async fn from_stream(
+        mut stream: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: ServerName,
+    ) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let remote_addr = stream.peer_addr()?;
+        let tls = ClientConnection::new(config, server_name).map_err(tls_error)?;
+        let mut tls = rustls::Connection::Client(tls);
+        handshake(&mut stream, &mut tls).await?;
+        let (read, write) = spawn_bridge(stream, tls);
+        Ok(Self {
+            client: Arc::new(Client::new(read, write)),
+            remote_addr,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for TlsTcpConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for TlsTcpConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for TlsTcpConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    listener: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// A server endpoint that serves calls on the TLS connections of a TCP listener
+pub struct TlsTcpServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for TlsTcpServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for TlsTcpServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTcpServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> TlsTcpServerEndpoint<In, Out> {
+    /// Listen for connections on `addr`
+    pub async fn bind(addr: impl ToSocketAddrs, config: Arc<ServerConfig>) -> io::Result<Self> {
+        Self::serve(TcpListener::bind(addr).await?, config)
+    }
+
+    /// Serve calls on the connections accepted by `listener`
+    ///
+    /// Connections whose TLS handshake fails are closed. Dropping all clones
+    /// of the endpoint stops accepting connections. Connections that were
+    /// already accepted are served until they close.
+    pub fn serve(listener: TcpListener, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let listener = tokio::spawn(async move {
+            loop {
+                let (mut stream, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(cause) => {
+                        tracing::warn!("tls: error accepting connection: {}", cause);
+                        continue;
+                    }
+                };
+                if stream.set_nodelay(true).is_err() {
+                    continue;
+                }
+                let config = config.clone();
+                let accept_tx = accept_tx.clone();
+                tokio::spawn(async move {
+                    let tls = match ServerConnection::new(config) {
+                        Ok(tls) => tls,
+                        Err(cause) => {
+                            tracing::warn!("tls: error creating session: {}", cause);
+                            return;
+                        }
+                    };
+                    let mut tls = rustls::Connection::Server(tls);
+                    if let Err(cause) = handshake(&mut stream, &mut tls).await {
+                        tracing::debug!("tls: handshake with {} failed: {}", addr, cause);
+                        return;
+                    }
+                    tracing::debug!("tls: accepted connection from {}", addr);
+                    let (read, write) = spawn_bridge(stream, tls);
+                    framed::serve(read, write, (), accept_tx).await;
+                });
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener,
+                local_addr: [LocalAddr::Socket(local_addr)],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for TlsTcpServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for TlsTcpServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for TlsTcpServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
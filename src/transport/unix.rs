@@ -0,0 +1,419 @@
+//! Transport over unix domain sockets, for communication between local processes
+//!
+//! This is useful for a daemon that is controlled by a command line tool,
+//! without opening a network port. Access can be restricted with the file
+//! permissions of the socket, and the server can check who is calling with
+//! [RecvStream::peer_credentials].
+//!
+//! Like the tcp transport, a [UnixConnection] uses a single
+//! socket connection to the server, and every channel is a logical stream on
+//! it, so all interaction patterns work.
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::unix::{UnixConnection, UnixServerEndpoint};
+//!
+//! let server = UnixServerEndpoint::<u64, u64>::bind("/run/daemon.sock")?;
+//! let client = UnixConnection::<u64, u64>::connect("/run/daemon.sock").await?;
+//! # Ok(())
+//! # }
+//! ```
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, SendError, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt, Sink, Stream,
+};
+use serde::Serialize;
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+
+/// The credentials of the process on the other side of a unix socket
+///
+/// These are the credentials at the time the connection was established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// The effective user id of the process
+    pub uid: u32,
+    /// The effective group id of the process
+    pub gid: u32,
+    /// The process id, if the platform reports it
+    pub pid: Option<i32>,
+}
+
+impl PeerCredentials {
+    /// The credentials of the peer of `stream`, if the platform reports them
+    fn new(stream: &UnixStream) -> Option<Self> {
+        match stream.peer_cred() {
+            Ok(cred) => Some(Self {
+                uid: cred.uid(),
+                gid: cred.gid(),
+                pid: cred.pid(),
+            }),
+            Err(cause) => {
+                tracing::debug!("unix: peer credentials not available: {}", cause);
+                None
+            }
+        }
+    }
+}
+
+/// Send side of a channel
+pub struct SendSink<Out> {
+    inner: framed::SendSink<Out>,
+    peer: Option<PeerCredentials>,
+}
+
+impl<Out> fmt::Debug for SendSink<Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SendSink")
+            .field("inner", &self.inner)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<Out> SendSink<Out> {
+    /// The credentials of the process on the other side of the socket
+    ///
+    /// On a server, this is the client that made the call.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+}
+
+impl<Out: Serialize> Sink<Out> for SendSink<Out> {
+    type Error = SendError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Out) -> result::Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Receive side of a channel
+pub struct RecvStream<In> {
+    inner: framed::RecvStream<In>,
+    peer: Option<PeerCredentials>,
+}
+
+impl<In> fmt::Debug for RecvStream<In> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvStream")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<In> RecvStream<In> {
+    /// The credentials of the process on the other side of the socket
+    ///
+    /// On a server, this is the client that made the call.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+
+    /// The extensions of the connection the channel was accepted on
+    ///
+    /// This is `None` on the client side.
+    pub fn extensions(&self) -> Option<&Extensions> {
+        self.inner.extensions()
+    }
+}
+
+impl<In: RpcMessage> Stream for RecvStream<In> {
+    type Item = result::Result<In, RecvError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().inner).poll_next(cx)
+    }
+}
+
+/// A connection to a [UnixServerEndpoint]
+pub struct UnixConnection<In, Out> {
+    client: Arc<Client>,
+    peer: Option<PeerCredentials>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for UnixConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            peer: self.peer,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for UnixConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixConnection")
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> UnixConnection<In, Out> {
+    /// Connect to the server listening on the socket at `path`
+    pub async fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_stream(UnixStream::connect(path).await?))
+    }
+
+    /// Use an established socket connection to the server
+    ///
+    /// Once the connection is closed, opening channels fails and all open
+    /// channels end.
+    pub fn from_stream(stream: UnixStream) -> Self {
+        let peer = PeerCredentials::new(&stream);
+        let (read, write) = stream.into_split();
+        Self {
+            client: Arc::new(Client::new(read, write)),
+            peer,
+            _p: PhantomData,
+        }
+    }
+
+    /// The credentials of the server process
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for UnixConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for UnixConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        // messages never leave the host, but are not encrypted
+        encrypted: false,
+        multiplexed: true,
+    };
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for UnixConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let peer = self.peer;
+        future::ready(self.client.open_bi().map(|(send, recv)| {
+            (
+                SendSink { inner: send, peer },
+                RecvStream { inner: recv, peer },
+            )
+        }))
+    }
+}
+
+struct ServerInner {
+    /// The task accepting connections, if the endpoint has a listener
+    listener: Option<JoinHandle<()>>,
+    local_addr: [LocalAddr; 1],
+    /// The socket file to remove once the endpoint is dropped
+    remove: Option<PathBuf>,
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+        if let Some(path) = &self.remove {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// A server endpoint that serves calls on the connections of a unix socket
+/// listener, or on a single established connection
+pub struct UnixServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<Option<PeerCredentials>>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for UnixServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for UnixServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> UnixServerEndpoint<In, Out> {
+    /// Listen for connections on a new socket at `path`
+    ///
+    /// This fails if a file exists at `path`, which may be left over from a
+    /// process that did not shut down cleanly. The socket file is removed
+    /// once all clones of the endpoint are dropped.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        Self::new(UnixListener::bind(path)?, Some(path.to_path_buf()))
+    }
+
+    /// Serve calls on the connections accepted by `listener`
+    ///
+    /// Dropping all clones of the endpoint stops accepting connections.
+    /// Connections that were already accepted are served until they close.
+    pub fn serve(listener: UnixListener) -> io::Result<Self> {
+        Self::new(listener, None)
+    }
+
+    /// Serve calls on an established socket connection
+    ///
+    /// This is for connections that were accepted or set up elsewhere, e.g.
+    /// one end of [UnixStream::pair] or a socket inherited from the parent
+    /// process. Once the connection is closed, accepting channels fails.
+    pub fn from_stream(stream: UnixStream) -> io::Result<Self> {
+        let local_addr = stream
+            .local_addr()?
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let peer = PeerCredentials::new(&stream);
+        let (accept_tx, accept) = flume::bounded(32);
+        let (read, write) = stream.into_split();
+        tokio::spawn(framed::serve(read, write, peer, accept_tx));
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener: None,
+                local_addr: [LocalAddr::Path(local_addr)],
+                remove: None,
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+
+    fn new(listener: UnixListener, remove: Option<PathBuf>) -> io::Result<Self> {
+        let local_addr = listener
+            .local_addr()?
+            .as_pathname()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let (accept_tx, accept) = flume::bounded(32);
+        let listener = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(cause) => {
+                        tracing::warn!("unix: error accepting connection: {}", cause);
+                        continue;
+                    }
+                };
+                let peer = PeerCredentials::new(&stream);
+                tracing::debug!("unix: accepted connection from {:?}", peer);
+                let (read, write) = stream.into_split();
+                tokio::spawn(framed::serve(read, write, peer, accept_tx.clone()));
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener: Some(listener),
+                local_addr: [LocalAddr::Path(local_addr)],
+                remove,
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for UnixServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for UnixServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = Capabilities {
+        ordered: true,
+        reliable: true,
+        // messages never leave the host, but are not encrypted
+        encrypted: false,
+        multiplexed: true,
+    };
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for UnixServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, peer) = accepted.into_parts();
+            Ok((
+                SendSink { inner: send, peer },
+                RecvStream { inner: recv, peer },
+            ))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
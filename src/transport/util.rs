@@ -1,45 +1,614 @@
 use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    fmt,
+    future::Future,
+    io,
+    marker::PhantomData,
+    panic,
     pin::Pin,
+    sync::Arc,
     task::{self, Poll},
 };
 
 use bincode::Options;
-use futures::{Sink, SinkExt, Stream, StreamExt};
+use bytes::{Bytes, BytesMut};
+use futures::{Sink, Stream};
 use pin_project::pin_project;
 use serde::{de::DeserializeOwned, Serialize};
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_util::codec::LengthDelimitedCodec;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::{JoinError, JoinHandle},
+};
+use tokio_util::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+#[cfg(feature = "zstd-compression")]
+use super::compression::ZstdDictionary;
+use super::{
+    decode::{decode, Direction},
+    latency::LatencyCritical,
+};
+use crate::Service;
+
+/// How messages are encoded into frames
+///
+/// The codec is a runtime value, so a server can use a different codec for
+/// every peer, e.g. depending on what the peer negotiated in its handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Codec {
+    /// bincode with fixint encoding
+    #[default]
+    Bincode,
+    /// bincode with varint encoding, which is more compact for small integers
+    BincodeVarint,
+    /// postcard, for peers on constrained devices that use it in their firmware
+    #[cfg(feature = "postcard-codec")]
+    Postcard,
+    /// JSON, for clients in other languages and for debugging with text tools
+    ///
+    /// Enums are encoded as objects with the name of the variant as the only key,
+    /// so a request of a service looks like `{"Sqr":3}`.
+    #[cfg(feature = "json-codec")]
+    Json,
+    /// MessagePack, for peers in other languages that already speak it
+    ///
+    /// Structs are encoded as maps with the names of their fields, and enums
+    /// like [Codec::Json] as maps with the name of the variant as the only key.
+    #[cfg(feature = "msgpack-codec")]
+    MessagePack,
+    /// CBOR, for peers in other languages that speak it
+    #[cfg(feature = "cbor-codec")]
+    Cbor,
+    /// CBOR with the deterministic encoding of RFC 8949, so equal messages are
+    /// always encoded to the same bytes, e.g. to hash or sign them
+    ///
+    /// Map keys are sorted by their encoding, and lengths are always definite.
+    /// Messages are decoded like [Codec::Cbor], without checking that the peer
+    /// encoded them deterministically.
+    #[cfg(feature = "cbor-codec")]
+    CborDeterministic,
+    /// Codecs of the application, see [CustomCodec]
+    Custom(CustomCodec),
+}
+
+impl Codec {
+    /// Whether encoded messages never contain a newline, so frames can be separated by them
+    #[cfg(feature = "hyper-transport")]
+    pub(super) fn is_line_delimited(&self) -> bool {
+        #[cfg(feature = "json-codec")]
+        if let Codec::Json = self {
+            return true;
+        }
+        false
+    }
+
+    /// Encode `item` into the payload of a frame
+    ///
+    /// This is how messages are sent on the wire, e.g. to hash a request for
+    /// an [AuditRecord](crate::audit::AuditRecord) with [Codec::CborDeterministic].
+    pub fn encode<T: Serialize + 'static>(&self, item: &T) -> io::Result<Bytes> {
+        let data = match self {
+            Codec::Bincode => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .serialize(item),
+            Codec::BincodeVarint => bincode::DefaultOptions::new().serialize(item),
+            #[cfg(feature = "postcard-codec")]
+            Codec::Postcard => {
+                return postcard::to_allocvec(item)
+                    .map(Bytes::from)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            }
+            #[cfg(feature = "json-codec")]
+            Codec::Json => {
+                return serde_json::to_vec(item)
+                    .map(Bytes::from)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            }
+            #[cfg(feature = "msgpack-codec")]
+            Codec::MessagePack => {
+                return rmp_serde::to_vec_named(item)
+                    .map(Bytes::from)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            }
+            #[cfg(feature = "cbor-codec")]
+            Codec::Cbor => {
+                let mut data = Vec::new();
+                return ciborium::ser::into_writer(item, &mut data)
+                    .map(|()| Bytes::from(data))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            #[cfg(feature = "cbor-codec")]
+            Codec::CborDeterministic => return encode_cbor_deterministic(item).map(Bytes::from),
+            Codec::Custom(codec) => return codec.get::<T>()?.encode(item).map(Bytes::from),
+        };
+        data.map(Bytes::from)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// The length of `item` once it is encoded, without encoding it
+    pub(super) fn encoded_len<T: Serialize + 'static>(&self, item: &T) -> io::Result<u64> {
+        let len = match self {
+            Codec::Bincode => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .serialized_size(item),
+            Codec::BincodeVarint => bincode::DefaultOptions::new().serialized_size(item),
+            #[cfg(feature = "postcard-codec")]
+            Codec::Postcard => {
+                return postcard::experimental::serialized_size(item)
+                    .map(|len| len as u64)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+            }
+            #[cfg(feature = "json-codec")]
+            Codec::Json => {
+                let mut counter = Counter(0);
+                return serde_json::to_writer(&mut counter, item)
+                    .map(|()| counter.0)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            #[cfg(feature = "cbor-codec")]
+            Codec::Cbor => {
+                let mut counter = Counter(0);
+                return ciborium::ser::into_writer(item, &mut counter)
+                    .map(|()| counter.0)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            #[cfg(feature = "cbor-codec")]
+            Codec::CborDeterministic => {
+                return encode_cbor_deterministic(item).map(|data| data.len() as u64)
+            }
+            #[cfg(feature = "msgpack-codec")]
+            Codec::MessagePack => {
+                let mut counter = Counter(0);
+                return rmp_serde::encode::write_named(&mut counter, item)
+                    .map(|()| counter.0)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+            }
+            Codec::Custom(codec) => return codec.get::<T>()?.encoded_len(item),
+        };
+        len.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
 
-type BincodeEncoding =
-    bincode::config::WithOtherIntEncoding<bincode::DefaultOptions, bincode::config::FixintEncoding>;
-/// Wrapper that wraps a bidirectional binary stream in a length delimited codec and bincode with fast fixint encoding
+    pub(super) fn decode<T: DeserializeOwned + 'static>(
+        &self,
+        bytes: &[u8],
+        direction: Direction,
+    ) -> io::Result<T> {
+        let res = match self {
+            Codec::Bincode => decode(
+                bincode::DefaultOptions::new().with_fixint_encoding(),
+                bytes,
+                direction,
+            ),
+            Codec::BincodeVarint => decode(bincode::DefaultOptions::new(), bytes, direction),
+            #[cfg(feature = "postcard-codec")]
+            Codec::Postcard => return decode_postcard(bytes),
+            #[cfg(feature = "json-codec")]
+            Codec::Json => {
+                return serde_json::from_slice(bytes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            #[cfg(feature = "msgpack-codec")]
+            Codec::MessagePack => return decode_msgpack(bytes),
+            #[cfg(feature = "cbor-codec")]
+            Codec::Cbor | Codec::CborDeterministic => return decode_cbor(bytes),
+            Codec::Custom(codec) => return codec.get::<T>()?.decode(bytes),
+        };
+        Ok(res?)
+    }
+}
+
+/// Decode a postcard message, which has to fill the whole frame
+#[cfg(feature = "postcard-codec")]
+fn decode_postcard<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    match postcard::take_from_bytes(bytes) {
+        Ok((item, [])) => Ok(item),
+        Ok((_, rest)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} trailing bytes after {}", rest.len(), type_name::<T>()),
+        )),
+        Err(cause) => Err(io::Error::new(io::ErrorKind::InvalidData, cause)),
+    }
+}
+
+/// Decode a MessagePack message, which has to fill the whole frame
+#[cfg(feature = "msgpack-codec")]
+fn decode_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    let mut de = rmp_serde::Deserializer::new(io::Cursor::new(bytes));
+    let item = serde::Deserialize::deserialize(&mut de)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match bytes.len() as u64 - de.position() {
+        0 => Ok(item),
+        rest => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} trailing bytes after {}", rest, type_name::<T>()),
+        )),
+    }
+}
+
+/// Encode `item` as CBOR, with the core deterministic encoding of RFC 8949
+///
+/// The serializer already encodes integers, floats and lengths in their
+/// shortest form, so only the keys of maps need to be sorted, after going
+/// through a [ciborium::Value] to find all maps.
+#[cfg(feature = "cbor-codec")]
+fn encode_cbor_deterministic<T: Serialize>(item: &T) -> io::Result<Vec<u8>> {
+    let value = ciborium::Value::serialized(item)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut data = Vec::new();
+    ciborium::ser::into_writer(&sort_cbor_maps(value)?, &mut data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(data)
+}
+
+/// Sort the keys of all maps in `value` by their encoding
+#[cfg(feature = "cbor-codec")]
+fn sort_cbor_maps(value: ciborium::Value) -> io::Result<ciborium::Value> {
+    use ciborium::Value;
+    Ok(match value {
+        Value::Map(entries) => {
+            let mut entries = entries
+                .into_iter()
+                .map(|(key, value)| {
+                    let key = sort_cbor_maps(key)?;
+                    let mut encoded = Vec::new();
+                    ciborium::ser::into_writer(&key, &mut encoded)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    Ok((encoded, key, sort_cbor_maps(value)?))
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Map(
+                entries
+                    .into_iter()
+                    .map(|(_, key, value)| (key, value))
+                    .collect(),
+            )
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(sort_cbor_maps)
+                .collect::<io::Result<_>>()?,
+        ),
+        Value::Tag(tag, inner) => Value::Tag(tag, Box::new(sort_cbor_maps(*inner)?)),
+        value => value,
+    })
+}
+
+/// Decode a CBOR message, which has to fill the whole frame
+#[cfg(feature = "cbor-codec")]
+fn decode_cbor<T: DeserializeOwned>(mut bytes: &[u8]) -> io::Result<T> {
+    let item = ciborium::de::from_reader(&mut bytes)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match bytes.len() {
+        0 => Ok(item),
+        rest => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} trailing bytes after {}", rest, type_name::<T>()),
+        )),
+    }
+}
+
+/// A writer that only counts the bytes written to it
+#[cfg(any(
+    feature = "json-codec",
+    feature = "msgpack-codec",
+    feature = "cbor-codec"
+))]
+struct Counter(u64);
+
+#[cfg(any(
+    feature = "json-codec",
+    feature = "msgpack-codec",
+    feature = "cbor-codec"
+))]
+impl std::io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encoding of the messages of type `T`, for a [CustomCodec]
+///
+/// A codec for any serde format can implement this for all
+/// `T: Serialize + DeserializeOwned` at once.
+pub trait MessageCodec<T>: Send + Sync + 'static {
+    /// Encode `item` into the payload of a frame
+    fn encode(&self, item: &T) -> io::Result<Vec<u8>>;
+
+    /// Decode the payload of a frame
+    ///
+    /// Errors are passed on to the receiver of the channel as they are, so
+    /// they should have the kind [io::ErrorKind::InvalidData].
+    fn decode(&self, bytes: &[u8]) -> io::Result<T>;
+
+    /// The length of `item` once it is encoded
+    ///
+    /// This decides whether a message is encoded on the blocking thread pool,
+    /// if the transport has an offload threshold. The default encodes the
+    /// message, so codecs that can compute it cheaper should do so.
+    fn encoded_len(&self, item: &T) -> io::Result<u64> {
+        self.encode(item).map(|data| data.len() as u64)
+    }
+}
+
+/// Codecs of the application, by message type
+///
+/// A channel sends messages of one type and receives messages of another, so
+/// a custom codec needs a [MessageCodec] for both. Messages of any other type
+/// fail to encode or decode.
+///
+/// ```ignore
+/// let codec = CustomCodec::new().with_service::<ComputeService, _>(Json);
+/// let connection = QuinnConnection::new(endpoint, addr, name).with_codec(Codec::Custom(codec));
+/// ```
+///
+/// Codecs are compared by identity: clones of a custom codec are equal,
+/// two codecs that were built the same way are not.
+#[derive(Clone, Default)]
+pub struct CustomCodec {
+    /// `Arc<dyn MessageCodec<T>>` by the type id of `T`
+    codecs: Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl CustomCodec {
+    /// A codec without any message types
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode messages of type `T` with `codec`
+    pub fn with<T: 'static>(mut self, codec: impl MessageCodec<T>) -> Self {
+        let codec: Arc<dyn MessageCodec<T>> = Arc::new(codec);
+        Arc::make_mut(&mut self.codecs).insert(TypeId::of::<T>(), Arc::new(codec));
+        self
+    }
+
+    /// Encode the requests and responses of `S` with `codec`
+    pub fn with_service<S, C>(self, codec: C) -> Self
+    where
+        S: Service,
+        C: MessageCodec<S::Req> + MessageCodec<S::Res> + Clone,
+    {
+        self.with::<S::Req>(codec.clone()).with::<S::Res>(codec)
+    }
+
+    fn get<T: 'static>(&self) -> io::Result<Arc<dyn MessageCodec<T>>> {
+        self.codecs
+            .get(&TypeId::of::<T>())
+            .and_then(|codec| codec.downcast_ref::<Arc<dyn MessageCodec<T>>>())
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("no codec for {}", type_name::<T>()),
+                )
+            })
+    }
+}
+
+impl PartialEq for CustomCodec {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.codecs, &other.codecs)
+    }
+}
+
+impl Eq for CustomCodec {}
+
+impl fmt::Debug for CustomCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomCodec")
+            .field("types", &self.codecs.len())
+            .finish()
+    }
+}
+
+/// Protobuf encoding of prost messages, for a [CustomCodec]
+///
+/// This puts types generated from .proto files on the wire as protobuf, so
+/// peers that only have the .proto files can talk to the service. The request
+/// and response of the service are prost messages with a `oneof` of the
+/// messages of the methods.
+///
+/// Messages still need serde for the rest of the crate, e.g. for method names.
+/// prost-build derives it for all generated types with
+/// `type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")`.
+///
+/// ```ignore
+/// let codec = CustomCodec::new().with_service::<ComputeService, _>(ProstCodec);
+/// let connection = QuinnConnection::new(endpoint, addr, name).with_codec(Codec::Custom(codec));
+/// ```
+#[cfg(feature = "prost-codec")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProstCodec;
+
+#[cfg(feature = "prost-codec")]
+impl<T: prost::Message + Default> MessageCodec<T> for ProstCodec {
+    fn encode(&self, item: &T) -> io::Result<Vec<u8>> {
+        Ok(item.encode_to_vec())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+        T::decode(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn encoded_len(&self, item: &T) -> io::Result<u64> {
+        Ok(item.encoded_len() as u64)
+    }
+}
+
+/// How frames are delimited and encoded on a binary stream
+#[derive(Debug, Clone)]
+pub struct Framing {
+    /// Maximum length of a single frame, before compression
+    pub max_frame_length: usize,
+    /// The codec of the messages in the frames
+    pub codec: Codec,
+    /// Dictionary to compress frames with
+    #[cfg(feature = "zstd-compression")]
+    pub dictionary: Option<ZstdDictionary>,
+    /// Messages of at least this many bytes are encoded and decoded on the
+    /// blocking thread pool of tokio instead of the task that sends or
+    /// receives them
+    pub offload_threshold: Option<usize>,
+    /// Messages that are sent without compressing or offloading them
+    pub latency_critical: LatencyCritical,
+}
+
+impl Framing {
+    /// Plain length delimited frames
+    pub fn new(max_frame_length: usize) -> Self {
+        Self {
+            max_frame_length,
+            codec: Codec::default(),
+            #[cfg(feature = "zstd-compression")]
+            dictionary: None,
+            offload_threshold: None,
+            latency_critical: LatencyCritical::default(),
+        }
+    }
+
+    /// Whether a message of `len` bytes is encoded or decoded on the blocking thread pool
+    fn offload(&self, len: u64) -> bool {
+        self.offload_threshold
+            .map_or(false, |threshold| len >= threshold as u64)
+    }
+
+    /// Whether frames are compressed
+    #[cfg(feature = "zstd-compression")]
+    pub fn compressed(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    /// Whether frames are compressed
+    #[cfg(not(feature = "zstd-compression"))]
+    pub fn compressed(&self) -> bool {
+        false
+    }
+
+    /// The message type of `item` for the compression statistics, if frames are compressed
+    fn message_kind<T: Serialize>(&self, item: &T) -> Option<&'static str> {
+        if !self.compressed() {
+            return None;
+        }
+        Some(super::exposure::variant(item).unwrap_or_else(type_name::<T>))
+    }
+}
+
+/// Length delimited codec that optionally compresses frames
+pub struct FrameCodec {
+    inner: LengthDelimitedCodec,
+    #[cfg(feature = "zstd-compression")]
+    framing: Framing,
+    /// The message type of the next frame that is encoded
+    #[cfg(feature = "zstd-compression")]
+    message: Option<&'static str>,
+    /// Whether the next frame that is encoded is sent uncompressed
+    #[cfg(feature = "zstd-compression")]
+    latency_critical: bool,
+}
+
+impl FrameCodec {
+    fn new(framing: Framing) -> Self {
+        // compressed frames have a flag byte in front
+        #[cfg(feature = "zstd-compression")]
+        let max_frame_length = framing.max_frame_length + framing.dictionary.is_some() as usize;
+        #[cfg(not(feature = "zstd-compression"))]
+        let max_frame_length = framing.max_frame_length;
+        let inner = LengthDelimitedCodec::builder()
+            .max_frame_length(max_frame_length)
+            .new_codec();
+        Self {
+            inner,
+            #[cfg(feature = "zstd-compression")]
+            framing,
+            #[cfg(feature = "zstd-compression")]
+            message: None,
+            #[cfg(feature = "zstd-compression")]
+            latency_critical: false,
+        }
+    }
+
+    /// Set the message type of the next frame that is encoded, and whether it is latency critical
+    #[cfg(feature = "zstd-compression")]
+    fn set_message(&mut self, message: Option<&'static str>, latency_critical: bool) {
+        self.message = message;
+        self.latency_critical = latency_critical;
+    }
+
+    /// Set the message type of the next frame that is encoded, and whether it is latency critical
+    #[cfg(not(feature = "zstd-compression"))]
+    fn set_message(&mut self, _message: Option<&'static str>, _latency_critical: bool) {}
+}
+
+impl Decoder for FrameCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let frame = self.inner.decode(src)?;
+        #[cfg(feature = "zstd-compression")]
+        if let (Some(frame), Some(dictionary)) = (&frame, &self.framing.dictionary) {
+            let frame = dictionary.decompress(frame, self.framing.max_frame_length)?;
+            return Ok(Some(BytesMut::from(frame.as_slice())));
+        }
+        Ok(frame)
+    }
+}
+
+impl Encoder<Bytes> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        #[cfg(feature = "zstd-compression")]
+        if let Some(dictionary) = &self.framing.dictionary {
+            if item.len() > self.framing.max_frame_length {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "frame too large",
+                ));
+            }
+            let message = self.message.take();
+            let frame = if std::mem::take(&mut self.latency_critical) {
+                dictionary.uncompressed(&item)
+            } else {
+                dictionary.compress(&item, message)?
+            };
+            return self.inner.encode(Bytes::from(frame), dst);
+        }
+        self.inner.encode(item, dst)
+    }
+}
+
+/// Wrapper that wraps a bidirectional binary stream in a length delimited codec and the [Codec] of the framing
 /// to get a bidirectional stream of rpc Messages
+///
+/// Messages that bincode can not decode are reported as a [DecodeError](super::DecodeError)
+/// wrapped in an [io::Error], the errors of a [CustomCodec] are reported as they are.
 #[pin_project]
 pub struct FramedBincodeRead<T, In>(
-    #[pin]
-    tokio_serde::SymmetricallyFramed<
-        tokio_util::codec::FramedRead<T, tokio_util::codec::LengthDelimitedCodec>,
-        In,
-        tokio_serde::formats::SymmetricalBincode<In, BincodeEncoding>,
-    >,
+    #[pin] tokio_util::codec::FramedRead<T, FrameCodec>,
+    Direction,
+    Framing,
+    /// A large frame that is being decoded on the blocking thread pool
+    Option<JoinHandle<io::Result<In>>>,
 );
 
 impl<T: AsyncRead, In: DeserializeOwned> FramedBincodeRead<T, In> {
-    /// Wrap a socket in a length delimited codec and bincode with fast fixint encoding
-    pub fn new(inner: T, max_frame_length: usize) -> Self {
-        // configure length delimited codec with max frame length
-        let framing = LengthDelimitedCodec::builder()
-            .max_frame_length(max_frame_length)
-            .new_codec();
-        // create the actual framing. This turns the AsyncRead/AsyncWrite into a Stream/Sink of Bytes/BytesMut
-        let framed = tokio_util::codec::FramedRead::new(inner, framing);
-        // configure bincode with fixint encoding
-        let bincode_options: BincodeEncoding =
-            bincode::DefaultOptions::new().with_fixint_encoding();
-        let bincode = tokio_serde::formats::Bincode::from(bincode_options);
-        // create the actual framing. This turns the Stream/Sink of Bytes/BytesMut into a Stream/Sink of In/Out
-        let framed = tokio_serde::Framed::new(framed, bincode);
-        Self(framed)
+    /// Wrap a socket in a length delimited codec and the [Codec] of the framing
+    ///
+    /// `direction` is the direction of the messages that are read, for error reporting.
+    pub fn new(inner: T, framing: Framing, direction: Direction) -> Self {
+        let codec = FrameCodec::new(framing.clone());
+        // create the actual framing. This turns the AsyncRead into a Stream of BytesMut
+        let framed = tokio_util::codec::FramedRead::new(inner, codec);
+        Self(framed, direction, framing, None)
     }
 }
 
@@ -49,46 +618,105 @@ impl<T, In> FramedBincodeRead<T, In> {
     /// This can be useful if you want to drop the framing and use the underlying stream directly
     /// after exchanging some messages.
     pub fn into_inner(self) -> T {
-        self.0.into_inner().into_inner()
+        self.0.into_inner()
     }
 }
 
-impl<T: AsyncRead, In: DeserializeOwned> Stream for FramedBincodeRead<T, In> {
+impl<T: AsyncRead, In: DeserializeOwned + Send + 'static> Stream for FramedBincodeRead<T, In> {
     type Item = Result<In, std::io::Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
-        self.project().0.poll_next_unpin(cx)
+        let mut this = self.project();
+        let direction = *this.1;
+        loop {
+            if let Some(task) = this.3.as_mut() {
+                let res = futures::ready!(Pin::new(task).poll(cx));
+                *this.3 = None;
+                return Poll::Ready(Some(offloaded(res).and_then(|res| res)));
+            }
+            let frame = match futures::ready!(this.0.as_mut().poll_next(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(cause)) => return Poll::Ready(Some(Err(cause))),
+                None => return Poll::Ready(None),
+            };
+            if !this.2.offload(frame.len() as u64) {
+                return Poll::Ready(Some(this.2.codec.decode(&frame, direction)));
+            }
+            let codec = this.2.codec.clone();
+            *this.3 = Some(tokio::task::spawn_blocking(move || {
+                codec.decode(&frame, direction)
+            }));
+        }
+    }
+}
+
+/// The result of a task on the blocking thread pool, with its panic resumed
+fn offloaded<T>(res: Result<T, JoinError>) -> io::Result<T> {
+    match res {
+        Ok(res) => Ok(res),
+        Err(cause) if cause.is_panic() => panic::resume_unwind(cause.into_panic()),
+        Err(cause) => Err(io::Error::new(io::ErrorKind::Other, cause)),
     }
 }
 
-/// Wrapper that wraps a bidirectional binary stream in a length delimited codec and bincode with fast fixint encoding
+/// Wrapper that wraps a bidirectional binary stream in a length delimited codec and the [Codec] of the framing
 /// to get a bidirectional stream of rpc Messages
 #[pin_project]
 pub struct FramedBincodeWrite<T, Out>(
-    #[pin]
-    tokio_serde::SymmetricallyFramed<
-        tokio_util::codec::FramedWrite<T, tokio_util::codec::LengthDelimitedCodec>,
-        Out,
-        tokio_serde::formats::SymmetricalBincode<Out, BincodeEncoding>,
-    >,
+    #[pin] tokio_util::codec::FramedWrite<T, FrameCodec>,
+    Framing,
+    /// A large message that is being encoded on the blocking thread pool
+    Encoding,
+    PhantomData<Out>,
 );
 
+/// The state of a message that is encoded on the blocking thread pool, with its message type
+enum Encoding {
+    Idle,
+    Running(JoinHandle<io::Result<Bytes>>, Option<&'static str>),
+    /// Encoded, but not yet passed on to the frame codec
+    Done(Bytes, Option<&'static str>),
+}
+
 impl<T: AsyncWrite, Out: Serialize> FramedBincodeWrite<T, Out> {
-    /// Wrap a socket in a length delimited codec and bincode with fast fixint encoding
-    pub fn new(inner: T, max_frame_length: usize) -> Self {
-        // configure length delimited codec with max frame length
-        let framing = LengthDelimitedCodec::builder()
-            .max_frame_length(max_frame_length)
-            .new_codec();
-        // create the actual framing. This turns the AsyncRead/AsyncWrite into a Stream/Sink of Bytes/BytesMut
-        let framed = tokio_util::codec::FramedWrite::new(inner, framing);
-        // configure bincode with fixint encoding
-        let bincode_options: BincodeEncoding =
-            bincode::DefaultOptions::new().with_fixint_encoding();
-        let bincode = tokio_serde::formats::SymmetricalBincode::from(bincode_options);
-        // create the actual framing. This turns the Stream/Sink of Bytes/BytesMut into a Stream/Sink of In/Out
-        let framed = tokio_serde::SymmetricallyFramed::new(framed, bincode);
-        Self(framed)
+    /// Wrap a socket in a length delimited codec and the [Codec] of the framing
+    pub fn new(inner: T, framing: Framing) -> Self {
+        let codec = FrameCodec::new(framing.clone());
+        // create the actual framing. This turns the AsyncWrite into a Sink of Bytes
+        let framed = tokio_util::codec::FramedWrite::new(inner, codec);
+        Self(framed, framing, Encoding::Idle, PhantomData)
+    }
+
+    /// Pass an offloaded message on to the frame codec once it is encoded
+    fn poll_encoded(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let mut this = self.project();
+        loop {
+            match this.2 {
+                Encoding::Idle => return Poll::Ready(Ok(())),
+                Encoding::Running(task, message) => {
+                    let message = *message;
+                    match offloaded(futures::ready!(Pin::new(task).poll(cx))).and_then(|res| res) {
+                        Ok(frame) => *this.2 = Encoding::Done(frame, message),
+                        Err(cause) => {
+                            *this.2 = Encoding::Idle;
+                            return Poll::Ready(Err(cause));
+                        }
+                    }
+                }
+                Encoding::Done(..) => {
+                    futures::ready!(this.0.as_mut().poll_ready(cx))?;
+                    if let Encoding::Done(frame, message) =
+                        std::mem::replace(this.2, Encoding::Idle)
+                    {
+                        this.0
+                            .as_mut()
+                            .encoder_pin_mut()
+                            .set_message(message, false);
+                        this.0.as_mut().start_send(frame)?;
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -98,36 +726,57 @@ impl<T, Out> FramedBincodeWrite<T, Out> {
     /// This can be useful if you want to drop the framing and use the underlying stream directly
     /// after exchanging some messages.
     pub fn into_inner(self) -> T {
-        self.0.into_inner().into_inner()
+        self.0.into_inner()
     }
 }
 
-impl<T: AsyncWrite, Out: Serialize> Sink<Out> for FramedBincodeWrite<T, Out> {
+impl<T: AsyncWrite, Out: Serialize + Send + 'static> Sink<Out> for FramedBincodeWrite<T, Out> {
     type Error = std::io::Error;
 
     fn poll_ready(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_ready_unpin(cx)
+        futures::ready!(self.as_mut().poll_encoded(cx))?;
+        self.project().0.poll_ready(cx)
     }
 
     fn start_send(self: Pin<&mut Self>, item: Out) -> Result<(), Self::Error> {
-        self.project().0.start_send_unpin(item)
+        let mut this = self.project();
+        let codec = &this.1.codec;
+        let message = this.1.message_kind(&item);
+        let latency_critical = this.1.latency_critical.contains(&item);
+        if !latency_critical
+            && this.1.offload_threshold.is_some()
+            && this.1.offload(codec.encoded_len(&item)?)
+        {
+            let codec = codec.clone();
+            let task = tokio::task::spawn_blocking(move || codec.encode(&item));
+            *this.2 = Encoding::Running(task, message);
+            return Ok(());
+        }
+        let frame = codec.encode(&item)?;
+        this.0
+            .as_mut()
+            .encoder_pin_mut()
+            .set_message(message, latency_critical);
+        this.0.start_send(frame)
     }
 
     fn poll_flush(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_flush_unpin(cx)
+        futures::ready!(self.as_mut().poll_encoded(cx))?;
+        self.project().0.poll_flush(cx)
     }
 
     fn poll_close(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         cx: &mut task::Context<'_>,
     ) -> Poll<Result<(), Self::Error>> {
-        self.project().0.poll_close_unpin(cx)
+        futures::ready!(self.as_mut().poll_encoded(cx))?;
+        self.project().0.poll_close(cx)
     }
 }
 
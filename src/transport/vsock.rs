@@ -0,0 +1,453 @@
+//! Transport over vsock, for calls between virtual machines and their host
+//!
+//! Services in Firecracker or QEMU guests can be called from the host, and
+//! the other way around, without a network between them. An address is a
+//! context id (CID), which identifies the host or a guest, and a port:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use quic_rpc::transport::vsock::{VsockConnection, VsockServerEndpoint, CID_ANY, CID_HOST};
+//!
+//! // in the guest
+//! let server = VsockServerEndpoint::<u64, u64>::bind(CID_ANY, 5000)?;
+//! // on the host, calling the guest with CID 3
+//! let client = VsockConnection::<u64, u64>::connect(3, 5000).await?;
+//! // in the guest, calling the host
+//! let client = VsockConnection::<u64, u64>::connect(CID_HOST, 5001).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Like the tcp transport, a [VsockConnection] uses a single connection to
+//! the server, and every channel is a logical stream on it, so all
+//! interaction patterns work.
+use super::framed::{self, Accepted, Client};
+pub use super::framed::{OpenError, RecvError, RecvStream, SendError, SendSink, MAX_FRAME_LENGTH};
+use crate::{
+    extensions::Extensions,
+    transport::{
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors, LocalAddr, ServerEndpoint,
+    },
+    RpcMessage,
+};
+use futures::{
+    future::{self, BoxFuture},
+    ready, FutureExt,
+};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    result,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinHandle,
+};
+
+/// Listen on all context ids of the local machine
+pub const CID_ANY: u32 = libc::VMADDR_CID_ANY;
+
+/// The context id of the host, as seen from a guest
+pub const CID_HOST: u32 = libc::VMADDR_CID_HOST;
+
+/// The context id of the local machine, for connections that do not leave it
+///
+/// This needs loopback support in the kernel, e.g. the `vsock_loopback` module.
+pub const CID_LOCAL: u32 = libc::VMADDR_CID_LOCAL;
+
+/// Let the kernel pick a free port when binding
+pub const PORT_ANY: u32 = libc::VMADDR_PORT_ANY;
+
+/// Messages only pass between a guest and its hypervisor
+const CAPABILITIES: Capabilities = Capabilities {
+    ordered: true,
+    reliable: true,
+    encrypted: true,
+    multiplexed: true,
+};
+
+fn cvt(ret: i32) -> io::Result<i32> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn socket() -> io::Result<OwnedFd> {
+    let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+    // SAFETY: no pointers are involved
+    let fd = cvt(unsafe { libc::socket(libc::AF_VSOCK, flags, 0) })?;
+    // SAFETY: the socket was just created, so nothing else owns it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn sockaddr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    // SAFETY: all zeroes is a valid sockaddr_vm
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+const SOCKADDR_LEN: libc::socklen_t = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
+
+/// The context id and port a socket is bound to
+fn local_addr(fd: &OwnedFd) -> io::Result<(u32, u32)> {
+    let mut addr = sockaddr(0, 0);
+    let mut len = SOCKADDR_LEN;
+    // SAFETY: addr and len are valid for writes of a sockaddr_vm
+    cvt(unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut len,
+        )
+    })?;
+    Ok((addr.svm_cid, addr.svm_port))
+}
+
+/// A connected vsock socket
+struct VsockStream(AsyncFd<OwnedFd>);
+
+impl VsockStream {
+    async fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let addr = sockaddr(cid, port);
+        // SAFETY: addr is a valid sockaddr_vm
+        let res = cvt(unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                SOCKADDR_LEN,
+            )
+        });
+        match res {
+            Err(cause) if cause.raw_os_error() != Some(libc::EINPROGRESS) => return Err(cause),
+            _ => {}
+        }
+        let fd = AsyncFd::new(fd)?;
+        // the socket becomes writable once connecting is done, successfully or not
+        drop(fd.writable().await?);
+        let mut error: i32 = 0;
+        let mut len = mem::size_of::<i32>() as libc::socklen_t;
+        // SAFETY: error and len are valid for writes of a c_int
+        cvt(unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut error as *mut i32 as *mut libc::c_void,
+                &mut len,
+            )
+        })?;
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            let res = guard.try_io(|fd| {
+                // SAFETY: unfilled is valid for writes of its length
+                let n = unsafe {
+                    libc::recv(
+                        fd.as_raw_fd(),
+                        unfilled.as_mut_ptr() as *mut libc::c_void,
+                        unfilled.len(),
+                        0,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            if let Ok(res) = res {
+                buf.advance(res?);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            let res = guard.try_io(|fd| {
+                // SAFETY: buf is valid for reads of its length
+                let n = unsafe {
+                    libc::send(
+                        fd.as_raw_fd(),
+                        buf.as_ptr() as *const libc::c_void,
+                        buf.len(),
+                        libc::MSG_NOSIGNAL,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            if let Ok(res) = res {
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // SAFETY: no pointers are involved
+        let res = cvt(unsafe { libc::shutdown(self.0.as_raw_fd(), libc::SHUT_WR) });
+        Poll::Ready(res.map(|_| ()))
+    }
+}
+
+/// A listening vsock socket
+struct VsockListener(AsyncFd<OwnedFd>);
+
+impl VsockListener {
+    fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let addr = sockaddr(cid, port);
+        // SAFETY: addr is a valid sockaddr_vm
+        cvt(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                SOCKADDR_LEN,
+            )
+        })?;
+        // SAFETY: no pointers are involved
+        cvt(unsafe { libc::listen(fd.as_raw_fd(), 128) })?;
+        Ok(Self(AsyncFd::new(fd)?))
+    }
+
+    /// Accept a connection, and return it with the context id and port of the peer
+    async fn accept(&self) -> io::Result<(VsockStream, u32, u32)> {
+        loop {
+            let mut guard = self.0.readable().await?;
+            let res = guard.try_io(|fd| {
+                let mut addr = sockaddr(0, 0);
+                let mut len = SOCKADDR_LEN;
+                let flags = libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+                // SAFETY: addr and len are valid for writes of a sockaddr_vm
+                let fd = cvt(unsafe {
+                    libc::accept4(
+                        fd.as_raw_fd(),
+                        &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+                        &mut len,
+                        flags,
+                    )
+                })?;
+                // SAFETY: the socket was just accepted, so nothing else owns it
+                Ok((unsafe { OwnedFd::from_raw_fd(fd) }, addr))
+            });
+            if let Ok(res) = res {
+                let (fd, addr) = res?;
+                let stream = VsockStream(AsyncFd::new(fd)?);
+                return Ok((stream, addr.svm_cid, addr.svm_port));
+            }
+        }
+    }
+}
+
+/// A connection to a [VsockServerEndpoint]
+pub struct VsockConnection<In, Out> {
+    client: Arc<Client>,
+    cid: u32,
+    port: u32,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for VsockConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            cid: self.cid,
+            port: self.port,
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for VsockConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VsockConnection")
+            .field("cid", &self.cid)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> VsockConnection<In, Out> {
+    /// Connect to the server on `port` of the machine with the context id `cid`
+    ///
+    /// Once the connection is closed, opening channels fails and all open
+    /// channels end.
+    pub async fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let stream = VsockStream::connect(cid, port).await?;
+        let (read, write) = tokio::io::split(stream);
+        Ok(Self {
+            client: Arc::new(Client::new(read, write)),
+            cid,
+            port,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for VsockConnection<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for VsockConnection<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for VsockConnection<In, Out> {
+    type OpenBiFut = future::Ready<result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        future::ready(self.client.open_bi())
+    }
+}
+
+struct ServerInner {
+    listener: JoinHandle<()>,
+    local_addr: [LocalAddr; 1],
+}
+
+impl Drop for ServerInner {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+/// A server endpoint that serves calls on the connections of a vsock port
+pub struct VsockServerEndpoint<In, Out> {
+    inner: Arc<ServerInner>,
+    accept: flume::Receiver<Accepted<()>>,
+    _p: PhantomData<(In, Out)>,
+}
+
+impl<In, Out> Clone for VsockServerEndpoint<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            accept: self.accept.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
+impl<In, Out> fmt::Debug for VsockServerEndpoint<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VsockServerEndpoint")
+            .field("local_addr", &self.inner.local_addr)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> VsockServerEndpoint<In, Out> {
+    /// Listen for connections on `port` of the context id `cid`
+    ///
+    /// Use [CID_ANY] to accept connections to any context id of this machine,
+    /// and [PORT_ANY] to let the kernel pick a port, which is then part of the
+    /// [local address](ServerEndpoint::local_addr). Dropping all clones of the
+    /// endpoint stops accepting connections. Connections that were already
+    /// accepted are served until they close.
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let listener = VsockListener::bind(cid, port)?;
+        let (cid, port) = local_addr(listener.0.get_ref())?;
+        let (accept_tx, accept) = flume::bounded(32);
+        let listener = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, cid, port)) => {
+                        tracing::debug!("vsock: accepted connection from {}:{}", cid, port);
+                        stream
+                    }
+                    Err(cause) => {
+                        tracing::warn!("vsock: error accepting connection: {}", cause);
+                        continue;
+                    }
+                };
+                let (read, write) = tokio::io::split(stream);
+                tokio::spawn(framed::serve(read, write, (), accept_tx.clone()));
+            }
+        });
+        Ok(Self {
+            inner: Arc::new(ServerInner {
+                listener,
+                local_addr: [LocalAddr::Vsock { cid, port }],
+            }),
+            accept,
+            _p: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for VsockServerEndpoint<In, Out> {
+    type OpenError = OpenError;
+    type SendError = SendError;
+    type RecvError = RecvError;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out> for VsockServerEndpoint<In, Out> {
+    type RecvStream = RecvStream<In>;
+    type SendSink = SendSink<Out>;
+    const CAPABILITIES: Capabilities = CAPABILITIES;
+
+    fn extensions(recv: &Self::RecvStream) -> Option<Extensions> {
+        recv.extensions().cloned()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ServerEndpoint<In, Out> for VsockServerEndpoint<In, Out> {
+    type AcceptBiFut =
+        BoxFuture<'static, result::Result<(Self::SendSink, Self::RecvStream), OpenError>>;
+
+    fn accept_bi(&self) -> Self::AcceptBiFut {
+        let accept = self.accept.clone();
+        async move {
+            let accepted = accept.recv_async().await.map_err(|_| OpenError::Closed)?;
+            let (send, recv, ()) = accepted.into_parts();
+            Ok((send, recv))
+        }
+        .boxed()
+    }
+
+    fn local_addr(&self) -> &[LocalAddr] {
+        &self.inner.local_addr
+    }
+}
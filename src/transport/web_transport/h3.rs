@@ -0,0 +1,741 @@
+//! The parts of HTTP/3 and QPACK that are needed to establish WebTransport sessions
+//!
+//! Field sections only use the static QPACK table. The dynamic table capacity
+//! stays at its default of 0, so the QPACK encoder and decoder streams of the
+//! peer never carry anything that needs to be read.
+use std::io;
+use tokio::io::AsyncReadExt;
+
+/// Type of the HTTP/3 control stream
+pub(super) const CONTROL_STREAM: u64 = 0x00;
+/// Type of the QPACK encoder stream
+pub(super) const QPACK_ENCODER_STREAM: u64 = 0x02;
+/// Type of the QPACK decoder stream
+pub(super) const QPACK_DECODER_STREAM: u64 = 0x03;
+/// Signal value at the start of a bidi stream of a WebTransport session,
+/// followed by the session id
+pub(super) const WEBTRANSPORT_STREAM: u64 = 0x41;
+
+const DATA_FRAME: u64 = 0x00;
+const HEADERS_FRAME: u64 = 0x01;
+const SETTINGS_FRAME: u64 = 0x04;
+
+const SETTINGS_ENABLE_CONNECT_PROTOCOL: u64 = 0x08;
+const SETTINGS_H3_DATAGRAM: u64 = 0x33;
+const SETTINGS_ENABLE_WEBTRANSPORT: u64 = 0x2b60_3742;
+const SETTINGS_WEBTRANSPORT_MAX_SESSIONS: u64 = 0xc671_706a;
+
+/// Error code for streams of an unknown type
+pub(super) const H3_STREAM_CREATION_ERROR: u32 = 0x0103;
+/// Error code for bidi streams of a session that does not exist
+pub(super) const WEBTRANSPORT_BUFFERED_STREAM_REJECTED: u32 = 0x3994_bd84;
+
+/// The number of WebTransport sessions a server accepts per connection
+pub(super) const MAX_SESSIONS: usize = 16;
+
+/// The longest frame that is read while establishing a session
+const MAX_FRAME_LENGTH: u64 = 16 * 1024;
+
+/// Header a server sends for clients that implement draft 02 of WebTransport over HTTP/3
+pub(super) const DRAFT02_RESPONSE: (&str, &str) = ("sec-webtransport-http3-draft", "draft02");
+/// Header a client sends for servers that implement draft 02 of WebTransport over HTTP/3
+pub(super) const DRAFT02_REQUEST: (&str, &str) = ("sec-webtransport-http3-draft02", "1");
+
+/// The fields of a decoded field section, in order
+pub(super) type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Append a QUIC variable length integer
+pub(super) fn put_varint(buf: &mut Vec<u8>, value: u64) {
+    if value < 1 << 6 {
+        buf.push(value as u8);
+    } else if value < 1 << 14 {
+        buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes());
+    } else if value < 1 << 30 {
+        buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes());
+    } else {
+        debug_assert!(value < 1 << 62);
+        buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes());
+    }
+}
+
+fn get_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let first = *buf.first().ok_or_else(|| invalid("truncated varint"))?;
+    let len = 1 << (first >> 6);
+    if buf.len() < len {
+        return Err(invalid("truncated varint"));
+    }
+    let mut value = u64::from(first & 0x3f);
+    for byte in &buf[1..len] {
+        value = value << 8 | u64::from(*byte);
+    }
+    *buf = &buf[len..];
+    Ok(value)
+}
+
+/// Read a QUIC variable length integer from a stream
+pub(super) async fn read_varint(recv: &mut quinn::RecvStream) -> io::Result<u64> {
+    let first = recv.read_u8().await?;
+    let mut value = u64::from(first & 0x3f);
+    for _ in 1..1 << (first >> 6) {
+        value = value << 8 | u64::from(recv.read_u8().await?);
+    }
+    Ok(value)
+}
+
+/// Read the payload of a frame whose type was already read
+async fn read_payload(recv: &mut quinn::RecvStream) -> io::Result<Vec<u8>> {
+    let len = read_varint(recv).await?;
+    if len > MAX_FRAME_LENGTH {
+        return Err(invalid("frame too long"));
+    }
+    let mut payload = vec![0; len as usize];
+    AsyncReadExt::read_exact(recv, &mut payload).await?;
+    Ok(payload)
+}
+
+/// Read until the stream ends, discarding everything
+pub(super) async fn drain(mut recv: quinn::RecvStream) {
+    while let Ok(Some(_)) = recv.read_chunk(usize::MAX, true).await {}
+}
+
+/// The start of a control stream: its type and the settings enabling WebTransport
+pub(super) fn control_stream() -> Vec<u8> {
+    let mut settings = Vec::new();
+    for (id, value) in [
+        (SETTINGS_ENABLE_CONNECT_PROTOCOL, 1),
+        (SETTINGS_H3_DATAGRAM, 1),
+        (SETTINGS_ENABLE_WEBTRANSPORT, 1),
+        (SETTINGS_WEBTRANSPORT_MAX_SESSIONS, MAX_SESSIONS as u64),
+    ] {
+        put_varint(&mut settings, id);
+        put_varint(&mut settings, value);
+    }
+    let mut buf = Vec::new();
+    put_varint(&mut buf, CONTROL_STREAM);
+    put_varint(&mut buf, SETTINGS_FRAME);
+    put_varint(&mut buf, settings.len() as u64);
+    buf.extend_from_slice(&settings);
+    buf
+}
+
+/// Read the settings at the start of a control stream whose type was already
+/// read, returning whether the peer supports WebTransport
+pub(super) async fn read_settings(recv: &mut quinn::RecvStream) -> io::Result<bool> {
+    if read_varint(recv).await? != SETTINGS_FRAME {
+        return Err(invalid("control stream does not start with SETTINGS"));
+    }
+    let payload = read_payload(recv).await?;
+    let mut rest = payload.as_slice();
+    let mut supported = false;
+    while !rest.is_empty() {
+        let id = get_varint(&mut rest)?;
+        let value = get_varint(&mut rest)?;
+        if (id == SETTINGS_ENABLE_WEBTRANSPORT || id == SETTINGS_WEBTRANSPORT_MAX_SESSIONS)
+            && value > 0
+        {
+            supported = true;
+        }
+    }
+    Ok(supported)
+}
+
+/// A HEADERS frame with the given fields
+pub(super) fn headers(fields: &[(&str, &str)]) -> Vec<u8> {
+    let block = encode_fields(fields);
+    let mut buf = Vec::new();
+    put_varint(&mut buf, HEADERS_FRAME);
+    put_varint(&mut buf, block.len() as u64);
+    buf.extend_from_slice(&block);
+    buf
+}
+
+/// Read the HEADERS frame at the start of a request or response
+///
+/// `first` is the type of the first frame, if it was already read. Frames of
+/// unknown types before the HEADERS frame are skipped.
+pub(super) async fn read_headers(
+    recv: &mut quinn::RecvStream,
+    mut first: Option<u64>,
+) -> io::Result<Fields> {
+    loop {
+        let ty = match first.take() {
+            Some(ty) => ty,
+            None => read_varint(recv).await?,
+        };
+        let payload = read_payload(recv).await?;
+        match ty {
+            HEADERS_FRAME => return decode_fields(&payload),
+            DATA_FRAME | SETTINGS_FRAME => return Err(invalid("unexpected frame before HEADERS")),
+            _ => continue,
+        }
+    }
+}
+
+/// The value of the first field named `name`
+pub(super) fn field<'a>(fields: &'a Fields, name: &str) -> Option<&'a [u8]> {
+    fields
+        .iter()
+        .find(|(n, _)| n == name.as_bytes())
+        .map(|(_, value)| value.as_slice())
+}
+
+/// Append an integer with a prefix of `prefix` bits, the bits above it are `flags`
+fn put_int(buf: &mut Vec<u8>, flags: u8, prefix: u8, value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        buf.push(flags | value as u8);
+        return;
+    }
+    buf.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        buf.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    buf.push(rest as u8);
+}
+
+fn get_int(buf: &mut &[u8], prefix: u8) -> io::Result<usize> {
+    let (&first, rest) = buf
+        .split_first()
+        .ok_or_else(|| invalid("truncated integer"))?;
+    *buf = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid("truncated integer"))?;
+        *buf = rest;
+        if shift > 28 {
+            return Err(invalid("integer too large"));
+        }
+        value += usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// Append a string literal with a length prefix of `prefix` bits, Huffman
+/// coded if that is shorter
+fn put_string(buf: &mut Vec<u8>, flags: u8, prefix: u8, value: &[u8]) {
+    let coded = huffman_encode(value);
+    if coded.len() < value.len() {
+        put_int(buf, flags | 1 << prefix, prefix, coded.len());
+        buf.extend_from_slice(&coded);
+    } else {
+        put_int(buf, flags, prefix, value.len());
+        buf.extend_from_slice(value);
+    }
+}
+
+fn get_string(buf: &mut &[u8], prefix: u8) -> io::Result<Vec<u8>> {
+    let huffman = buf.first().map_or(false, |first| first & 1 << prefix != 0);
+    let len = get_int(buf, prefix)?;
+    if buf.len() < len {
+        return Err(invalid("truncated string"));
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+    if huffman {
+        huffman_decode(value)
+    } else {
+        Ok(value.to_vec())
+    }
+}
+
+fn encode_fields(fields: &[(&str, &str)]) -> Vec<u8> {
+    // required insert count and base are 0, nothing refers to the dynamic table
+    let mut block = vec![0, 0];
+    for &(name, value) in fields {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            // indexed field line
+            put_int(&mut block, 0b1100_0000, 6, index);
+        } else if let Some(index) = STATIC_TABLE.iter().position(|&(n, _)| n == name) {
+            // literal field line with name reference
+            put_int(&mut block, 0b0101_0000, 4, index);
+            put_string(&mut block, 0, 7, value.as_bytes());
+        } else {
+            // literal field line with literal name
+            put_string(&mut block, 0b0010_0000, 3, name.as_bytes());
+            put_string(&mut block, 0, 7, value.as_bytes());
+        }
+    }
+    block
+}
+
+fn decode_fields(block: &[u8]) -> io::Result<Fields> {
+    let dynamic = || invalid("field section refers to the dynamic table");
+    let static_entry = |index: usize| {
+        STATIC_TABLE
+            .get(index)
+            .copied()
+            .ok_or_else(|| invalid("invalid static table index"))
+    };
+    let mut rest = block;
+    let required_insert_count = get_int(&mut rest, 8)?;
+    get_int(&mut rest, 7)?;
+    if required_insert_count != 0 {
+        return Err(dynamic());
+    }
+    let mut fields = Vec::new();
+    while let Some(&first) = rest.first() {
+        let field = if first & 0b1000_0000 != 0 {
+            // indexed field line
+            if first & 0b0100_0000 == 0 {
+                return Err(dynamic());
+            }
+            let (name, value) = static_entry(get_int(&mut rest, 6)?)?;
+            (name.as_bytes().to_vec(), value.as_bytes().to_vec())
+        } else if first & 0b0100_0000 != 0 {
+            // literal field line with name reference
+            if first & 0b0001_0000 == 0 {
+                return Err(dynamic());
+            }
+            let (name, _) = static_entry(get_int(&mut rest, 4)?)?;
+            (name.as_bytes().to_vec(), get_string(&mut rest, 7)?)
+        } else if first & 0b0010_0000 != 0 {
+            // literal field line with literal name
+            let name = get_string(&mut rest, 3)?;
+            (name, get_string(&mut rest, 7)?)
+        } else {
+            // the post-base forms only refer to the dynamic table
+            return Err(dynamic());
+        };
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+fn huffman_encode(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bits = 0u64;
+    let mut len = 0;
+    for &byte in value {
+        let (n, code) = HUFFMAN_CODES[usize::from(byte)];
+        bits = bits << n | u64::from(code);
+        len += n;
+        while len >= 8 {
+            len -= 8;
+            out.push((bits >> len) as u8);
+        }
+        bits &= (1 << len) - 1;
+    }
+    if len > 0 {
+        // pad with the most significant bits of EOS, which are all ones
+        out.push((bits << (8 - len)) as u8 | 0xff >> len);
+    }
+    out
+}
+
+fn huffman_decode(value: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut len = 0;
+    for &byte in value {
+        for shift in (0..8).rev() {
+            bits = bits << 1 | u32::from(byte >> shift & 1);
+            len += 1;
+            let symbol = HUFFMAN_CODES
+                .iter()
+                .position(|&(n, code)| n == len && code == bits);
+            if let Some(symbol) = symbol {
+                out.push(symbol as u8);
+                bits = 0;
+                len = 0;
+            } else if len >= 30 {
+                return Err(invalid("invalid huffman code"));
+            }
+        }
+    }
+    // the padding is shorter than a byte and all ones
+    if len > 7 || bits != (1 << len) - 1 {
+        return Err(invalid("invalid huffman padding"));
+    }
+    Ok(out)
+}
+
+/// The QPACK static table, RFC 9204 appendix A
+const STATIC_TABLE: [(&str, &str); 99] = [
+    (":authority", ""),
+    (":path", "/"),
+    ("age", "0"),
+    ("content-disposition", ""),
+    ("content-length", "0"),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("referer", ""),
+    ("set-cookie", ""),
+    (":method", "CONNECT"),
+    (":method", "DELETE"),
+    (":method", "GET"),
+    (":method", "HEAD"),
+    (":method", "OPTIONS"),
+    (":method", "POST"),
+    (":method", "PUT"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "103"),
+    (":status", "200"),
+    (":status", "304"),
+    (":status", "404"),
+    (":status", "503"),
+    ("accept", "*/*"),
+    ("accept", "application/dns-message"),
+    ("accept-encoding", "gzip, deflate, br"),
+    ("accept-ranges", "bytes"),
+    ("access-control-allow-headers", "cache-control"),
+    ("access-control-allow-headers", "content-type"),
+    ("access-control-allow-origin", "*"),
+    ("cache-control", "max-age=0"),
+    ("cache-control", "max-age=2592000"),
+    ("cache-control", "max-age=604800"),
+    ("cache-control", "no-cache"),
+    ("cache-control", "no-store"),
+    ("cache-control", "public, max-age=31536000"),
+    ("content-encoding", "br"),
+    ("content-encoding", "gzip"),
+    ("content-type", "application/dns-message"),
+    ("content-type", "application/javascript"),
+    ("content-type", "application/json"),
+    ("content-type", "application/x-www-form-urlencoded"),
+    ("content-type", "image/gif"),
+    ("content-type", "image/jpeg"),
+    ("content-type", "image/png"),
+    ("content-type", "text/css"),
+    ("content-type", "text/html; charset=utf-8"),
+    ("content-type", "text/plain"),
+    ("content-type", "text/plain;charset=utf-8"),
+    ("range", "bytes=0-"),
+    ("strict-transport-security", "max-age=31536000"),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains",
+    ),
+    (
+        "strict-transport-security",
+        "max-age=31536000; includesubdomains; preload",
+    ),
+    ("vary", "accept-encoding"),
+    ("vary", "origin"),
+    ("x-content-type-options", "nosniff"),
+    ("x-xss-protection", "1; mode=block"),
+    (":status", "100"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "302"),
+    (":status", "400"),
+    (":status", "403"),
+    (":status", "421"),
+    (":status", "425"),
+    (":status", "500"),
+    ("accept-language", ""),
+    ("access-control-allow-credentials", "FALSE"),
+    ("access-control-allow-credentials", "TRUE"),
+    ("access-control-allow-headers", "*"),
+    ("access-control-allow-methods", "get"),
+    ("access-control-allow-methods", "get, post, options"),
+    ("access-control-allow-methods", "options"),
+    ("access-control-expose-headers", "content-length"),
+    ("access-control-request-headers", "content-type"),
+    ("access-control-request-method", "get"),
+    ("access-control-request-method", "post"),
+    ("alt-svc", "clear"),
+    ("authorization", ""),
+    (
+        "content-security-policy",
+        "script-src 'none'; object-src 'none'; base-uri 'none'",
+    ),
+    ("early-data", "1"),
+    ("expect-ct", ""),
+    ("forwarded", ""),
+    ("if-range", ""),
+    ("origin", ""),
+    ("purpose", "prefetch"),
+    ("server", ""),
+    ("timing-allow-origin", "*"),
+    ("upgrade-insecure-requests", "1"),
+    ("user-agent", ""),
+    ("x-forwarded-for", ""),
+    ("x-frame-options", "deny"),
+    ("x-frame-options", "sameorigin"),
+];
+
+/// The Huffman code (length in bits, code) of every byte, RFC 7541 appendix B
+const HUFFMAN_CODES: [(u8, u32); 256] = [
+    (13, 0x1ff8),
+    (23, 0x7fffd8),
+    (28, 0xfffffe2),
+    (28, 0xfffffe3),
+    (28, 0xfffffe4),
+    (28, 0xfffffe5),
+    (28, 0xfffffe6),
+    (28, 0xfffffe7),
+    (28, 0xfffffe8),
+    (24, 0xffffea),
+    (30, 0x3ffffffc),
+    (28, 0xfffffe9),
+    (28, 0xfffffea),
+    (30, 0x3ffffffd),
+    (28, 0xfffffeb),
+    (28, 0xfffffec),
+    (28, 0xfffffed),
+    (28, 0xfffffee),
+    (28, 0xfffffef),
+    (28, 0xffffff0),
+    (28, 0xffffff1),
+    (28, 0xffffff2),
+    (30, 0x3ffffffe),
+    (28, 0xffffff3),
+    (28, 0xffffff4),
+    (28, 0xffffff5),
+    (28, 0xffffff6),
+    (28, 0xffffff7),
+    (28, 0xffffff8),
+    (28, 0xffffff9),
+    (28, 0xffffffa),
+    (28, 0xffffffb),
+    (6, 0x14),
+    (10, 0x3f8),
+    (10, 0x3f9),
+    (12, 0xffa),
+    (13, 0x1ff9),
+    (6, 0x15),
+    (8, 0xf8),
+    (11, 0x7fa),
+    (10, 0x3fa),
+    (10, 0x3fb),
+    (8, 0xf9),
+    (11, 0x7fb),
+    (8, 0xfa),
+    (6, 0x16),
+    (6, 0x17),
+    (6, 0x18),
+    (5, 0x0),
+    (5, 0x1),
+    (5, 0x2),
+    (6, 0x19),
+    (6, 0x1a),
+    (6, 0x1b),
+    (6, 0x1c),
+    (6, 0x1d),
+    (6, 0x1e),
+    (6, 0x1f),
+    (7, 0x5c),
+    (8, 0xfb),
+    (15, 0x7ffc),
+    (6, 0x20),
+    (12, 0xffb),
+    (10, 0x3fc),
+    (13, 0x1ffa),
+    (6, 0x21),
+    (7, 0x5d),
+    (7, 0x5e),
+    (7, 0x5f),
+    (7, 0x60),
+    (7, 0x61),
+    (7, 0x62),
+    (7, 0x63),
+    (7, 0x64),
+    (7, 0x65),
+    (7, 0x66),
+    (7, 0x67),
+    (7, 0x68),
+    (7, 0x69),
+    (7, 0x6a),
+    (7, 0x6b),
+    (7, 0x6c),
+    (7, 0x6d),
+    (7, 0x6e),
+    (7, 0x6f),
+    (7, 0x70),
+    (7, 0x71),
+    (7, 0x72),
+    (8, 0xfc),
+    (7, 0x73),
+    (8, 0xfd),
+    (13, 0x1ffb),
+    (19, 0x7fff0),
+    (13, 0x1ffc),
+    (14, 0x3ffc),
+    (6, 0x22),
+    (15, 0x7ffd),
+    (5, 0x3),
+    (6, 0x23),
+    (5, 0x4),
+    (6, 0x24),
+    (5, 0x5),
+    (6, 0x25),
+    (6, 0x26),
+    (6, 0x27),
+    (5, 0x6),
+    (7, 0x74),
+    (7, 0x75),
+    (6, 0x28),
+    (6, 0x29),
+    (6, 0x2a),
+    (5, 0x7),
+    (6, 0x2b),
+    (7, 0x76),
+    (6, 0x2c),
+    (5, 0x8),
+    (5, 0x9),
+    (6, 0x2d),
+    (7, 0x77),
+    (7, 0x78),
+    (7, 0x79),
+    (7, 0x7a),
+    (7, 0x7b),
+    (15, 0x7ffe),
+    (11, 0x7fc),
+    (14, 0x3ffd),
+    (13, 0x1ffd),
+    (28, 0xffffffc),
+    (20, 0xfffe6),
+    (22, 0x3fffd2),
+    (20, 0xfffe7),
+    (20, 0xfffe8),
+    (22, 0x3fffd3),
+    (22, 0x3fffd4),
+    (22, 0x3fffd5),
+    (23, 0x7fffd9),
+    (22, 0x3fffd6),
+    (23, 0x7fffda),
+    (23, 0x7fffdb),
+    (23, 0x7fffdc),
+    (23, 0x7fffdd),
+    (23, 0x7fffde),
+    (24, 0xffffeb),
+    (23, 0x7fffdf),
+    (24, 0xffffec),
+    (24, 0xffffed),
+    (22, 0x3fffd7),
+    (23, 0x7fffe0),
+    (24, 0xffffee),
+    (23, 0x7fffe1),
+    (23, 0x7fffe2),
+    (23, 0x7fffe3),
+    (23, 0x7fffe4),
+    (21, 0x1fffdc),
+    (22, 0x3fffd8),
+    (23, 0x7fffe5),
+    (22, 0x3fffd9),
+    (23, 0x7fffe6),
+    (23, 0x7fffe7),
+    (24, 0xffffef),
+    (22, 0x3fffda),
+    (21, 0x1fffdd),
+    (20, 0xfffe9),
+    (22, 0x3fffdb),
+    (22, 0x3fffdc),
+    (23, 0x7fffe8),
+    (23, 0x7fffe9),
+    (21, 0x1fffde),
+    (23, 0x7fffea),
+    (22, 0x3fffdd),
+    (22, 0x3fffde),
+    (24, 0xfffff0),
+    (21, 0x1fffdf),
+    (22, 0x3fffdf),
+    (23, 0x7fffeb),
+    (23, 0x7fffec),
+    (21, 0x1fffe0),
+    (21, 0x1fffe1),
+    (22, 0x3fffe0),
+    (21, 0x1fffe2),
+    (23, 0x7fffed),
+    (22, 0x3fffe1),
+    (23, 0x7fffee),
+    (23, 0x7fffef),
+    (20, 0xfffea),
+    (22, 0x3fffe2),
+    (22, 0x3fffe3),
+    (22, 0x3fffe4),
+    (23, 0x7ffff0),
+    (22, 0x3fffe5),
+    (22, 0x3fffe6),
+    (23, 0x7ffff1),
+    (26, 0x3ffffe0),
+    (26, 0x3ffffe1),
+    (20, 0xfffeb),
+    (19, 0x7fff1),
+    (22, 0x3fffe7),
+    (23, 0x7ffff2),
+    (22, 0x3fffe8),
+    (25, 0x1ffffec),
+    (26, 0x3ffffe2),
+    (26, 0x3ffffe3),
+    (26, 0x3ffffe4),
+    (27, 0x7ffffde),
+    (27, 0x7ffffdf),
+    (26, 0x3ffffe5),
+    (24, 0xfffff1),
+    (25, 0x1ffffed),
+    (19, 0x7fff2),
+    (21, 0x1fffe3),
+    (26, 0x3ffffe6),
+    (27, 0x7ffffe0),
+    (27, 0x7ffffe1),
+    (26, 0x3ffffe7),
+    (27, 0x7ffffe2),
+    (24, 0xfffff2),
+    (21, 0x1fffe4),
+    (21, 0x1fffe5),
+    (26, 0x3ffffe8),
+    (26, 0x3ffffe9),
+    (28, 0xffffffd),
+    (27, 0x7ffffe3),
+    (27, 0x7ffffe4),
+    (27, 0x7ffffe5),
+    (20, 0xfffec),
+    (24, 0xfffff3),
+    (20, 0xfffed),
+    (21, 0x1fffe6),
+    (22, 0x3fffe9),
+    (21, 0x1fffe7),
+    (21, 0x1fffe8),
+    (23, 0x7ffff3),
+    (22, 0x3fffea),
+    (22, 0x3fffeb),
+    (25, 0x1ffffee),
+    (25, 0x1ffffef),
+    (24, 0xfffff4),
+    (24, 0xfffff5),
+    (26, 0x3ffffea),
+    (23, 0x7ffff4),
+    (26, 0x3ffffeb),
+    (27, 0x7ffffe6),
+    (26, 0x3ffffec),
+    (26, 0x3ffffed),
+    (27, 0x7ffffe7),
+    (27, 0x7ffffe8),
+    (27, 0x7ffffe9),
+    (27, 0x7ffffea),
+    (27, 0x7ffffeb),
+    (28, 0xffffffe),
+    (27, 0x7ffffec),
+    (27, 0x7ffffed),
+    (27, 0x7ffffee),
+    (27, 0x7ffffef),
+    (27, 0x7fffff0),
+    (26, 0x3ffffee),
+];
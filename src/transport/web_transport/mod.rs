@@ -0,0 +1,316 @@
+//! WebTransport sessions over HTTP/3, so browsers can call quic-rpc services
+//!
+//! A browser opens a WebTransport session with an extended CONNECT request
+//! over HTTP/3, and every bidi stream it then opens in the session is handled
+//! like a substream of a native quinn connection. The HTTP/3 connections come
+//! from a [QuinnServerEndpoint] that shares its quinn endpoint with native
+//! clients, see [QuinnServerEndpoint::with_web_transport]:
+//!
+//! ```ignore
+//! let (server, connections) = QuinnServerEndpoint::with_web_transport(endpoint, config)?;
+//! tokio::spawn(web_transport::serve(connections));
+//! let server = RpcServer::<ComputeService, _>::new(server);
+//! ```
+//!
+//! Only what WebTransport needs of HTTP/3 is implemented. Other requests are
+//! answered with status 404, every session is accepted regardless of its path,
+//! and datagrams and unidirectional streams of sessions are not supported.
+//! Messages are framed like on a native connection, so a browser client has to
+//! prefix every bincode encoded message with its length as a big endian u32.
+//!
+//! [WebTransportConnection] is the client side of a session, for native
+//! clients that have to go through the same port and protocol as browsers.
+use crate::{
+    transport::{
+        quinn::{client_socket, RecvStream, SendSink, WebTransportConnections, MAX_FRAME_LENGTH},
+        util::Framing,
+        Capabilities, Connection, ConnectionCommon, ConnectionErrors,
+    },
+    RpcMessage,
+};
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+use quinn::VarInt;
+use std::{
+    collections::HashSet,
+    fmt, io,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+mod h3;
+
+#[cfg(doc)]
+use crate::transport::quinn::{QuinnServerEndpoint, H3_ALPN};
+
+/// The sessions of a connection, by the id of their request stream
+type Sessions = Arc<Mutex<HashSet<u64>>>;
+
+fn connection_error(error: quinn::ConnectionError) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, error)
+}
+
+/// Establish the WebTransport sessions on the connections of `connections`
+///
+/// Runs until the server endpoint no longer accepts connections.
+pub async fn serve(connections: WebTransportConnections) {
+    while let Some(connection) = connections.accept().await {
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let remote_address = connection.remote_address();
+            if let Err(e) = serve_connection(connection, connections).await {
+                tracing::debug!("HTTP/3 connection from {} failed: {}", remote_address, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    connection: quinn::Connection,
+    connections: WebTransportConnections,
+) -> io::Result<()> {
+    let (settings, _) = oneshot::channel();
+    tokio::spawn(accept_uni(connection.clone(), settings));
+    // closing the control stream would close the connection
+    let mut control = connection.open_uni().await.map_err(connection_error)?;
+    control.write_all(&h3::control_stream()).await?;
+    let sessions = Sessions::default();
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::debug!("Error accepting HTTP/3 stream: {}", e);
+                return Ok(());
+            }
+        };
+        let sessions = sessions.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(send, recv, sessions, connections).await {
+                tracing::debug!("HTTP/3 stream failed: {}", e);
+            }
+        });
+    }
+}
+
+/// Handle a bidi stream, which is either a request or a stream of a session
+async fn serve_stream(
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    sessions: Sessions,
+    connections: WebTransportConnections,
+) -> io::Result<()> {
+    let first = h3::read_varint(&mut recv).await?;
+    if first == h3::WEBTRANSPORT_STREAM {
+        let session = h3::read_varint(&mut recv).await?;
+        if sessions.lock().unwrap().contains(&session) {
+            connections.session_stream((send, recv)).await;
+        } else {
+            tracing::debug!("Rejecting stream of unknown session {}", session);
+            let code = VarInt::from_u32(h3::WEBTRANSPORT_BUFFERED_STREAM_REJECTED);
+            send.reset(code).ok();
+            recv.stop(code).ok();
+        }
+        return Ok(());
+    }
+    let fields = h3::read_headers(&mut recv, Some(first)).await?;
+    let is_session = h3::field(&fields, ":method") == Some(b"CONNECT")
+        && h3::field(&fields, ":protocol") == Some(b"webtransport");
+    if !is_session {
+        send.write_all(&h3::headers(&[(":status", "404")])).await?;
+        send.finish().await?;
+        return Ok(());
+    }
+    let id = VarInt::from(recv.id()).into_inner();
+    let accepted = {
+        let mut sessions = sessions.lock().unwrap();
+        sessions.len() < h3::MAX_SESSIONS && sessions.insert(id)
+    };
+    if !accepted {
+        send.write_all(&h3::headers(&[(":status", "429")])).await?;
+        send.finish().await?;
+        return Ok(());
+    }
+    tracing::debug!("WebTransport session {} established", id);
+    send.write_all(&h3::headers(&[(":status", "200"), h3::DRAFT02_RESPONSE]))
+        .await?;
+    // the session lasts until the client closes the request stream
+    h3::drain(recv).await;
+    tracing::debug!("WebTransport session {} closed", id);
+    sessions.lock().unwrap().remove(&id);
+    send.finish().await.ok();
+    Ok(())
+}
+
+/// Read the unidirectional streams of the peer
+///
+/// The settings at the start of the control stream are sent to `settings`,
+/// everything else is discarded.
+async fn accept_uni(connection: quinn::Connection, settings: oneshot::Sender<io::Result<bool>>) {
+    let mut settings = Some(settings);
+    while let Ok(mut recv) = connection.accept_uni().await {
+        let ty = match h3::read_varint(&mut recv).await {
+            Ok(ty) => ty,
+            Err(_) => continue,
+        };
+        match ty {
+            h3::CONTROL_STREAM => {
+                let settings = settings.take();
+                tokio::spawn(async move {
+                    let supported = h3::read_settings(&mut recv).await;
+                    if let Some(settings) = settings {
+                        settings.send(supported).ok();
+                    }
+                    h3::drain(recv).await;
+                });
+            }
+            h3::QPACK_ENCODER_STREAM | h3::QPACK_DECODER_STREAM => {
+                tokio::spawn(h3::drain(recv));
+            }
+            _ => {
+                recv.stop(VarInt::from_u32(h3::H3_STREAM_CREATION_ERROR))
+                    .ok();
+            }
+        }
+    }
+}
+
+/// The streams that keep a session open
+#[derive(Debug)]
+struct Session {
+    connection: quinn::Connection,
+    id: u64,
+    _control: quinn::SendStream,
+    _request: (quinn::SendStream, quinn::RecvStream),
+}
+
+/// A connection that calls a server through a WebTransport session
+///
+/// The session is closed once all clones are dropped.
+pub struct WebTransportConnection<In: RpcMessage, Out: RpcMessage> {
+    session: Arc<Session>,
+    framing: Framing,
+    _phantom: PhantomData<(In, Out)>,
+}
+
+impl<In: RpcMessage, Out: RpcMessage> WebTransportConnection<In, Out> {
+    /// Establish a WebTransport session on an HTTP/3 connection
+    ///
+    /// The connection must have negotiated [H3_ALPN]. `authority` and `path`
+    /// are sent in the CONNECT request that opens the session.
+    pub async fn connect(
+        connection: quinn::Connection,
+        authority: &str,
+        path: &str,
+    ) -> io::Result<Self> {
+        let (settings, server_settings) = oneshot::channel();
+        tokio::spawn(accept_uni(connection.clone(), settings));
+        let mut control = connection.open_uni().await.map_err(connection_error)?;
+        control.write_all(&h3::control_stream()).await?;
+        // sessions must not be requested before the server announced support
+        let supported = server_settings.await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the server sent its settings",
+            )
+        })??;
+        if !supported {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the server does not support WebTransport",
+            ));
+        }
+
+        let (mut send, mut recv) = connection.open_bi().await.map_err(connection_error)?;
+        let request = h3::headers(&[
+            (":method", "CONNECT"),
+            (":protocol", "webtransport"),
+            (":scheme", "https"),
+            (":authority", authority),
+            (":path", path),
+            h3::DRAFT02_REQUEST,
+        ]);
+        send.write_all(&request).await?;
+        let fields = h3::read_headers(&mut recv, None).await?;
+        match h3::field(&fields, ":status") {
+            Some(b"200") => {}
+            status => {
+                let status = String::from_utf8_lossy(status.unwrap_or_default());
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("the server refused the session with status {status}"),
+                ));
+            }
+        }
+        let id = VarInt::from(send.id()).into_inner();
+        tracing::debug!("WebTransport session {} established", id);
+        Ok(Self {
+            session: Arc::new(Session {
+                connection,
+                id,
+                _control: control,
+                _request: (send, recv),
+            }),
+            framing: Framing::new(MAX_FRAME_LENGTH),
+            _phantom: PhantomData,
+        })
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> fmt::Debug for WebTransportConnection<In, Out> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebTransportConnection")
+            .field("session", &self.session.id)
+            .finish()
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> Clone for WebTransportConnection<In, Out> {
+    fn clone(&self) -> Self {
+        Self {
+            session: self.session.clone(),
+            framing: self.framing.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionErrors for WebTransportConnection<In, Out> {
+    type SendError = io::Error;
+
+    type RecvError = io::Error;
+
+    type OpenError = io::Error;
+}
+
+impl<In: RpcMessage, Out: RpcMessage> ConnectionCommon<In, Out>
+    for WebTransportConnection<In, Out>
+{
+    type SendSink = SendSink<Out>;
+    type RecvStream = RecvStream<In>;
+    const CAPABILITIES: Capabilities = Capabilities::ALL;
+}
+
+/// Future returned by [WebTransportConnection::open_bi]
+pub type OpenBiFuture<In, Out> = BoxFuture<'static, io::Result<(SendSink<Out>, RecvStream<In>)>>;
+
+impl<In: RpcMessage, Out: RpcMessage> Connection<In, Out> for WebTransportConnection<In, Out> {
+    type OpenBiFut = OpenBiFuture<In, Out>;
+
+    fn open_bi(&self) -> Self::OpenBiFut {
+        let session = self.session.clone();
+        let framing = self.framing.clone();
+        async move {
+            let (mut send, recv) = session
+                .connection
+                .open_bi()
+                .await
+                .map_err(connection_error)?;
+            let mut header = Vec::new();
+            h3::put_varint(&mut header, h3::WEBTRANSPORT_STREAM);
+            h3::put_varint(&mut header, session.id);
+            send.write_all(&header).await?;
+            Ok(client_socket((send, recv), framing))
+        }
+        .boxed()
+    }
+}
@@ -0,0 +1,617 @@
+//! Client streaming uploads that resume where they left off
+//!
+//! A large upload over a flaky link can fail after most of the data was
+//! received. [UploadClient::upload] retries failed uploads, and before every
+//! attempt asks the server how much of the upload it durably received, using
+//! an [OffsetRequest] that is answered with an [UploadOffset]. The attempt then
+//! only sends the updates after that offset.
+//!
+//! Both messages need to be part of the service, as an rpc call:
+//!
+//! ```ignore
+//! declare_rpc!(UploadService, OffsetRequest, UploadOffset);
+//! ```
+//!
+//! The offset is opaque to the library. It can count updates or bytes, as long
+//! as the server and the source of the updates agree. Servers can keep track
+//! of offsets with [UploadOffsets], or answer [OffsetRequest]s from their own
+//! storage.
+//!
+//! # Journaling
+//!
+//! Sources that can not produce their updates again, e.g. readings of a sensor,
+//! can use [UploadClient::upload_journaled] instead. It keeps every update it
+//! sent in a [Journal] until the server acknowledges it with its offset, and
+//! replays the updates the server did not durably receive after a reconnect.
+//! Offsets count updates in this case. The journal is bounded: once it is full,
+//! the client asks the server for its offset to drop acknowledged updates, so
+//! the server needs to [commit](UploadOffsets::commit) offsets while it
+//! receives an upload, not only at the end.
+//!
+//! [MemoryJournal] keeps the updates in memory, `FileJournal` in a file, if the
+//! `bincode` feature is enabled.
+use crate::{
+    client::{ClientStreamingError, ClientStreamingItemError, RpcClientError},
+    message::{ClientStreamingMsg, RpcMsg},
+    transport::ConnectionErrors,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    error, fmt, io,
+    pin::Pin,
+    result,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(feature = "bincode")]
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    path::Path,
+};
+
+/// Default number of attempts of an upload
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// Default delay between attempts of an upload
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Request for the offset up to which the server durably received an upload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OffsetRequest {
+    /// The id of the upload
+    pub id: String,
+}
+
+/// The offset up to which the server durably received an upload
+///
+/// This is 0 for uploads the server does not know about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UploadOffset(pub u64);
+
+/// A client streaming request that can be resumed at an offset
+pub trait ResumableUpload<S: Service>: ClientStreamingMsg<S> + Clone {
+    /// The id of the upload, which must be the same for all attempts
+    fn upload_id(&self) -> String;
+
+    /// Set the offset at which the updates of this attempt start
+    fn resume_at(&mut self, offset: u64);
+}
+
+/// Offsets of the uploads a server is receiving, by upload id
+///
+/// Clones share the same offsets. The offsets are kept in memory, so uploads
+/// can only be resumed as long as the server keeps running.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOffsets(Arc<Mutex<HashMap<String, u64>>>);
+
+impl UploadOffsets {
+    /// Create a new, empty set of offsets
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The offset up to which an upload was durably received
+    pub fn offset(&self, id: &str) -> u64 {
+        self.0.lock().unwrap().get(id).copied().unwrap_or_default()
+    }
+
+    /// Record that an upload was durably received up to `offset`
+    ///
+    /// Call this only once the data before `offset` is stored, since the next
+    /// attempt of the upload will not send it again.
+    pub fn commit(&self, id: &str, offset: u64) {
+        self.0.lock().unwrap().insert(id.to_string(), offset);
+    }
+
+    /// Forget a completed upload
+    pub fn finish(&self, id: &str) {
+        self.0.lock().unwrap().remove(id);
+    }
+
+    /// Handler for [OffsetRequest]s, to be used with [RpcChannel::rpc](crate::server::RpcChannel::rpc)
+    pub async fn handle(self, req: OffsetRequest) -> UploadOffset {
+        UploadOffset(self.offset(&req.id))
+    }
+}
+
+/// Updates of a journaled upload that were sent, but not yet acknowledged by
+/// the server, see [UploadClient::upload_journaled]
+pub trait Journal<T> {
+    /// The number of updates in the journal
+    fn len(&self) -> usize;
+
+    /// Whether the journal has no updates
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the journal can not take another update
+    fn is_full(&self) -> bool;
+
+    /// Append an update that is about to be sent
+    fn push(&mut self, update: &T) -> io::Result<()>;
+
+    /// Drop the first `n` updates, which the server acknowledged
+    fn acknowledge(&mut self, n: usize) -> io::Result<()>;
+
+    /// The updates in the journal, in the order they were pushed
+    fn replay(&mut self) -> io::Result<Vec<T>>;
+}
+
+/// A [Journal] that keeps up to a number of updates in memory
+#[derive(Debug, Clone)]
+pub struct MemoryJournal<T> {
+    updates: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> MemoryJournal<T> {
+    /// Create a journal for up to `capacity` updates
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            updates: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+}
+
+impl<T: Clone> Journal<T> for MemoryJournal<T> {
+    fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    fn is_full(&self) -> bool {
+        self.updates.len() >= self.capacity
+    }
+
+    fn push(&mut self, update: &T) -> io::Result<()> {
+        if self.is_full() {
+            return Err(io::Error::new(io::ErrorKind::Other, "journal is full"));
+        }
+        self.updates.push_back(update.clone());
+        Ok(())
+    }
+
+    fn acknowledge(&mut self, n: usize) -> io::Result<()> {
+        self.updates.drain(..n.min(self.updates.len()));
+        Ok(())
+    }
+
+    fn replay(&mut self) -> io::Result<Vec<T>> {
+        Ok(self.updates.iter().cloned().collect())
+    }
+}
+
+/// A [Journal] that keeps up to a number of updates in a file, encoded with bincode
+///
+/// Only the positions of the updates are kept in memory. The file is created
+/// or truncated when the journal is created, and rewritten without the
+/// acknowledged updates once they take up more than half of it.
+#[cfg(feature = "bincode")]
+#[derive(Debug)]
+pub struct FileJournal<T> {
+    file: File,
+    /// The position of every update in the file
+    positions: VecDeque<u64>,
+    /// The length of the file
+    end: u64,
+    capacity: usize,
+    _p: PhantomData<T>,
+}
+
+#[cfg(feature = "bincode")]
+impl<T> FileJournal<T> {
+    /// Create a journal for up to `capacity` updates in the file at `path`
+    pub fn create(path: impl AsRef<Path>, capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            positions: VecDeque::new(),
+            end: 0,
+            capacity: capacity.max(1),
+            _p: PhantomData,
+        })
+    }
+
+    /// Move the updates that were not acknowledged to the start of the file
+    fn compact(&mut self) -> io::Result<()> {
+        let start = self.positions.front().copied().unwrap_or(self.end);
+        let mut rest = Vec::new();
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_to_end(&mut rest)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&rest)?;
+        self.file.set_len(rest.len() as u64)?;
+        for position in self.positions.iter_mut() {
+            *position -= start;
+        }
+        self.end = rest.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T: Serialize + serde::de::DeserializeOwned> Journal<T> for FileJournal<T> {
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    fn is_full(&self) -> bool {
+        self.positions.len() >= self.capacity
+    }
+
+    fn push(&mut self, update: &T) -> io::Result<()> {
+        if self.is_full() {
+            return Err(io::Error::new(io::ErrorKind::Other, "journal is full"));
+        }
+        let data = bincode::serialize(update)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.file.write_all(&data)?;
+        self.positions.push_back(self.end);
+        self.end += 4 + data.len() as u64;
+        Ok(())
+    }
+
+    fn acknowledge(&mut self, n: usize) -> io::Result<()> {
+        self.positions.drain(..n.min(self.positions.len()));
+        match self.positions.front() {
+            Some(start) if *start > self.end / 2 => self.compact(),
+            Some(_) => Ok(()),
+            None => {
+                self.end = 0;
+                self.file.set_len(0)
+            }
+        }
+    }
+
+    fn replay(&mut self) -> io::Result<Vec<T>> {
+        let start = match self.positions.front() {
+            Some(start) => *start,
+            None => return Ok(Vec::new()),
+        };
+        let mut data = Vec::new();
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_to_end(&mut data)?;
+        let mut rest = data.as_slice();
+        let mut updates = Vec::with_capacity(self.positions.len());
+        while rest.len() >= 4 {
+            let (len, tail) = rest.split_at(4);
+            let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if tail.len() < len {
+                break;
+            }
+            let (update, tail) = tail.split_at(len);
+            updates.push(
+                bincode::deserialize(update)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            );
+            rest = tail;
+        }
+        if updates.len() != self.positions.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "journal file is truncated",
+            ));
+        }
+        Ok(updates)
+    }
+}
+
+/// A client that retries client streaming uploads, resuming them at the
+/// offset the server durably received
+pub struct UploadClient<S, C> {
+    client: RpcClient<S, C>,
+    max_attempts: usize,
+    retry_delay: Duration,
+}
+
+impl<S, C: Clone> Clone for UploadClient<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            max_attempts: self.max_attempts,
+            retry_delay: self.retry_delay,
+        }
+    }
+}
+
+impl<S, C> fmt::Debug for UploadClient<S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UploadClient")
+            .field("max_attempts", &self.max_attempts)
+            .field("retry_delay", &self.retry_delay)
+            .finish()
+    }
+}
+
+impl<S, C> UploadClient<S, C>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    OffsetRequest: RpcMsg<S, Response = UploadOffset>,
+{
+    /// Create a new upload client
+    pub fn new(client: RpcClient<S, C>) -> Self {
+        Self {
+            client,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    /// Set the number of attempts of an upload, including the first one
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the delay between attempts of an upload
+    ///
+    /// Attempts the server pushed back are retried after the delay the
+    /// server asked for instead.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Upload the updates produced by `source`, retrying failed attempts
+    ///
+    /// `source` is called for every attempt with the offset the server
+    /// durably received, and must produce the updates after that offset.
+    /// Fails with the error of the last attempt.
+    pub async fn upload<M, F, St>(
+        &self,
+        msg: M,
+        mut source: F,
+    ) -> result::Result<M::Response, UploadError<C>>
+    where
+        M: ResumableUpload<S>,
+        F: FnMut(u64) -> St,
+        St: Stream<Item = M::Update>,
+    {
+        let mut attempt = 1;
+        loop {
+            match self.attempt(msg.clone(), &mut source).await {
+                Ok(res) => return Ok(res),
+                Err(cause) if attempt >= self.max_attempts => return Err(cause),
+                Err(cause) => {
+                    tracing::debug!("upload attempt {} failed: {}", attempt, cause);
+                    attempt += 1;
+                    let delay = cause.retry_after().unwrap_or(self.retry_delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn attempt<M, F, St>(
+        &self,
+        mut msg: M,
+        source: &mut F,
+    ) -> result::Result<M::Response, UploadError<C>>
+    where
+        M: ResumableUpload<S>,
+        F: FnMut(u64) -> St,
+        St: Stream<Item = M::Update>,
+    {
+        let id = msg.upload_id();
+        let UploadOffset(offset) = self
+            .client
+            .rpc(OffsetRequest { id })
+            .await
+            .map_err(UploadError::Offset)?;
+        msg.resume_at(offset);
+        let (mut send, recv) = self
+            .client
+            .client_streaming(msg)
+            .await
+            .map_err(UploadError::Open)?;
+        let updates = source(offset);
+        tokio::pin!(updates);
+        while let Some(update) = updates.next().await {
+            send.send(update).await.map_err(UploadError::Send)?;
+        }
+        send.close().await.map_err(UploadError::Send)?;
+        // some transports only end the update stream once the sink is dropped
+        drop(send);
+        recv.await.map_err(UploadError::Recv)
+    }
+
+    /// Upload the updates of `updates`, retrying failed attempts and replaying
+    /// the updates the server did not acknowledge from `journal`
+    ///
+    /// `updates` are the updates after the offset the server has for the
+    /// upload when this is called, and are read only once. Offsets count
+    /// updates. Fails with the error of the last attempt, the journal then
+    /// holds the updates the server may not have received.
+    pub async fn upload_journaled<M, St, J>(
+        &self,
+        msg: M,
+        updates: St,
+        journal: &mut J,
+    ) -> result::Result<M::Response, UploadError<C>>
+    where
+        M: ResumableUpload<S>,
+        St: Stream<Item = M::Update>,
+        J: Journal<M::Update>,
+    {
+        tokio::pin!(updates);
+        let mut state = Journaled {
+            journal,
+            base: None,
+            next: None,
+        };
+        let mut attempt = 1;
+        loop {
+            match self
+                .journaled_attempt(msg.clone(), &mut updates, &mut state)
+                .await
+            {
+                Ok(res) => {
+                    state.journal.acknowledge(state.journal.len()).ok();
+                    return Ok(res);
+                }
+                Err(cause) if attempt >= self.max_attempts => return Err(cause),
+                Err(cause) => {
+                    tracing::debug!("upload attempt {} failed: {}", attempt, cause);
+                    attempt += 1;
+                    let delay = cause.retry_after().unwrap_or(self.retry_delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn journaled_attempt<M, St, J>(
+        &self,
+        mut msg: M,
+        updates: &mut Pin<&mut St>,
+        state: &mut Journaled<'_, M::Update, J>,
+    ) -> result::Result<M::Response, UploadError<C>>
+    where
+        M: ResumableUpload<S>,
+        St: Stream<Item = M::Update>,
+        J: Journal<M::Update>,
+    {
+        let id = msg.upload_id();
+        let offset = self.acknowledged(&id, state).await?;
+        msg.resume_at(offset);
+        let (mut send, recv) = self
+            .client
+            .client_streaming(msg)
+            .await
+            .map_err(UploadError::Open)?;
+        for update in state.journal.replay().map_err(UploadError::Journal)? {
+            send.send(update).await.map_err(UploadError::Send)?;
+        }
+        loop {
+            let update = match state.next.take() {
+                Some(update) => update,
+                None => match updates.next().await {
+                    Some(update) => update,
+                    None => break,
+                },
+            };
+            if state.journal.is_full() {
+                // keep the update for the next attempt, in case this one fails
+                state.next = Some(update);
+                self.make_room(&id, state).await?;
+                continue;
+            }
+            state.journal.push(&update).map_err(UploadError::Journal)?;
+            send.send(update).await.map_err(UploadError::Send)?;
+        }
+        send.close().await.map_err(UploadError::Send)?;
+        // some transports only end the update stream once the sink is dropped
+        drop(send);
+        recv.await.map_err(UploadError::Recv)
+    }
+
+    /// Ask the server for the offset of an upload, and drop the updates it
+    /// acknowledges from the journal
+    async fn acknowledged<T, J: Journal<T>>(
+        &self,
+        id: &str,
+        state: &mut Journaled<'_, T, J>,
+    ) -> result::Result<u64, UploadError<C>> {
+        let UploadOffset(offset) = self
+            .client
+            .rpc(OffsetRequest { id: id.to_string() })
+            .await
+            .map_err(UploadError::Offset)?;
+        let base = *state.base.get_or_insert(offset);
+        let journaled = state.journal.len() as u64;
+        if offset < base || offset > base + journaled {
+            return Err(UploadError::Journal(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "server is at offset {offset}, the journal has {journaled} updates after {base}"
+                ),
+            )));
+        }
+        state
+            .journal
+            .acknowledge((offset - base) as usize)
+            .map_err(UploadError::Journal)?;
+        state.base = Some(offset);
+        Ok(offset)
+    }
+
+    /// Wait until the server acknowledges enough updates to make room in the journal
+    async fn make_room<T, J: Journal<T>>(
+        &self,
+        id: &str,
+        state: &mut Journaled<'_, T, J>,
+    ) -> result::Result<(), UploadError<C>> {
+        for _ in 0..self.max_attempts {
+            self.acknowledged(id, state).await?;
+            if !state.journal.is_full() {
+                return Ok(());
+            }
+            tokio::time::sleep(self.retry_delay).await;
+        }
+        Err(UploadError::JournalFull)
+    }
+}
+
+/// The state of a journaled upload across attempts
+struct Journaled<'a, T, J> {
+    journal: &'a mut J,
+    /// The offset of the first update in the journal, once it is known
+    base: Option<u64>,
+    /// An update that was taken from the source, but is not yet journaled
+    next: Option<T>,
+}
+
+/// Error for an [UploadClient]
+#[derive(Debug)]
+pub enum UploadError<C: ConnectionErrors> {
+    /// Unable to get the offset of the upload
+    Offset(RpcClientError<C>),
+    /// Unable to start the upload
+    Open(ClientStreamingError<C>),
+    /// Unable to send an update
+    Send(C::SendError),
+    /// Unable to receive the response
+    Recv(ClientStreamingItemError<C>),
+    /// Unable to journal or replay updates, or the journal does not match the
+    /// offset of the server
+    Journal(io::Error),
+    /// The journal stayed full, since the server did not acknowledge any updates
+    JournalFull,
+}
+
+impl<C: ConnectionErrors> fmt::Display for UploadError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for UploadError<C> {}
+
+impl<C: ConnectionErrors> UploadError<C> {
+    /// How long the server asked to wait before retrying, if it pushed back
+    /// the attempt
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            UploadError::Offset(cause) => cause.retry_after(),
+            UploadError::Recv(cause) => cause.retry_after(),
+            UploadError::Open(_)
+            | UploadError::Send(_)
+            | UploadError::Journal(_)
+            | UploadError::JournalFull => None,
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Validation of requests before they reach a handler
+//!
+//! A server configured with [RpcServer::with_validation](crate::RpcServer::with_validation)
+//! checks every request of a message type before the handler for it runs. A
+//! request that fails the check gets a response with an [InvalidArgument],
+//! which lists a message for every field that is wrong, and the call ends with
+//! [RpcServerError::InvalidArgument](crate::server::RpcServerError::InvalidArgument).
+//! So handlers only ever see valid requests:
+//!
+//! ```ignore
+//! impl Validate for CreateUser {
+//!     fn validate(&self) -> Result<(), InvalidArgument> {
+//!         InvalidArgument::default()
+//!             .check(!self.name.is_empty(), "name", "must not be empty")
+//!             .check(self.age < 200, "age", "must be below 200")
+//!             .into_result()
+//!     }
+//! }
+//!
+//! let server = RpcServer::new(endpoint).with_validation::<CreateUser>();
+//! ```
+//!
+//! The response has to be able to carry the error, so the response type of
+//! the message needs to implement [FromInvalidArgument]. This is the case for
+//! responses that are a [Result] with an error type that implements
+//! `From<InvalidArgument>`. The client sees the error like any other error of
+//! the call.
+//!
+//! Checks of other libraries, such as the derived validations of the
+//! `validator` crate, can be used with [RpcServer::with_validator](crate::RpcServer::with_validator),
+//! by converting their errors to an [InvalidArgument]:
+//!
+//! ```ignore
+//! let server = RpcServer::new(endpoint).with_validator(|req: &CreateUser| {
+//!     validator::Validate::validate(req).map_err(|errors| {
+//!         errors.field_errors().into_iter().fold(InvalidArgument::default(), |invalid, (field, errors)| {
+//!             errors.iter().fold(invalid, |invalid, error| invalid.with_field(field, error.to_string()))
+//!         })
+//!     })
+//! });
+//! ```
+//!
+//! Only the first request of a call is checked, updates of streaming calls are
+//! passed to the handler unchecked.
+use crate::{
+    message::{
+        BidiStreaming, BidiStreamingMsg, ClientStreaming, ClientStreamingMsg, Msg, Rpc, RpcMsg,
+        ServerStreaming, ServerStreamingMsg,
+    },
+    Service,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    error, fmt,
+    sync::Arc,
+};
+
+/// A message about a field of a request that is not valid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldViolation {
+    /// The path of the field, e.g. `address.zip`
+    pub field: String,
+    /// What is wrong with the value of the field
+    pub message: String,
+}
+
+/// A request was rejected because some of its fields are not valid
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvalidArgument {
+    /// The fields that are not valid, in the order they were checked
+    pub violations: Vec<FieldViolation>,
+}
+
+impl InvalidArgument {
+    /// Add a violation for `field`
+    pub fn with_field(mut self, field: impl Into<String>, message: impl Into<String>) -> Self {
+        self.violations.push(FieldViolation {
+            field: field.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    /// Add a violation for `field` unless `valid` holds
+    pub fn check(self, valid: bool, field: impl Into<String>, message: impl Into<String>) -> Self {
+        if valid {
+            self
+        } else {
+            self.with_field(field, message)
+        }
+    }
+
+    /// Whether no violations were added
+    pub fn is_empty(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// `Ok` if no violations were added, or this as the error otherwise
+    pub fn into_result(self) -> Result<(), Self> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl fmt::Display for InvalidArgument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid argument")?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { ", " };
+            write!(f, "{}{}: {}", sep, violation.field, violation.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for InvalidArgument {}
+
+/// A request that can check its own fields
+pub trait Validate {
+    /// Check the request, returning all fields that are not valid
+    fn validate(&self) -> Result<(), InvalidArgument>;
+}
+
+/// A response that can carry an [InvalidArgument] to the client
+pub trait FromInvalidArgument {
+    /// The response for a request that is not valid
+    fn from_invalid_argument(invalid: InvalidArgument) -> Self;
+}
+
+impl<T, E: From<InvalidArgument>> FromInvalidArgument for Result<T, E> {
+    fn from_invalid_argument(invalid: InvalidArgument) -> Self {
+        Err(invalid.into())
+    }
+}
+
+/// The response type of a message with the interaction pattern `P`
+///
+/// This is implemented for all messages, so validations can be configured
+/// for messages of every pattern alike.
+pub trait PatternResponse<S: Service, P> {
+    /// The response, or the type of the items of the response stream
+    type Response: Into<S::Res>;
+}
+
+impl<S: Service, M: RpcMsg<S>> PatternResponse<S, Rpc> for M {
+    type Response = M::Response;
+}
+
+impl<S: Service, M: ServerStreamingMsg<S>> PatternResponse<S, ServerStreaming> for M {
+    type Response = M::Response;
+}
+
+impl<S: Service, M: ClientStreamingMsg<S>> PatternResponse<S, ClientStreaming> for M {
+    type Response = M::Response;
+}
+
+impl<S: Service, M: BidiStreamingMsg<S>> PatternResponse<S, BidiStreaming> for M {
+    type Response = M::Response;
+}
+
+/// The response of a message `M` to a request that is not valid
+pub(crate) type ResponseOf<S, M> = <M as PatternResponse<S, <M as Msg<S>>::Pattern>>::Response;
+
+type CheckFn = dyn Fn(&dyn Any) -> Result<(), InvalidArgument> + Send + Sync;
+
+/// The check and the response of a message type
+struct Check<S: Service> {
+    check: Arc<CheckFn>,
+    respond: fn(InvalidArgument) -> S::Res,
+}
+
+impl<S: Service> Clone for Check<S> {
+    fn clone(&self) -> Self {
+        Self {
+            check: self.check.clone(),
+            respond: self.respond,
+        }
+    }
+}
+
+/// Checks of the requests of a server, by message type
+pub(crate) struct Validations<S: Service>(HashMap<TypeId, Check<S>>);
+
+impl<S: Service> Validations<S> {
+    pub(crate) fn insert<M, F>(&mut self, f: F)
+    where
+        M: Msg<S> + PatternResponse<S, M::Pattern>,
+        ResponseOf<S, M>: FromInvalidArgument,
+        F: Fn(&M) -> Result<(), InvalidArgument> + Send + Sync + 'static,
+    {
+        let check = move |req: &dyn Any| match req.downcast_ref::<M>() {
+            Some(req) => f(req),
+            None => Ok(()),
+        };
+        self.0.insert(
+            TypeId::of::<M>(),
+            Check {
+                check: Arc::new(check),
+                respond: |invalid| ResponseOf::<S, M>::from_invalid_argument(invalid).into(),
+            },
+        );
+    }
+
+    /// Check a request, returning the error and the response if it is not valid
+    pub(crate) fn check<M: Msg<S>>(&self, req: &M) -> Option<(InvalidArgument, S::Res)> {
+        let check = self.0.get(&TypeId::of::<M>())?;
+        let invalid = (check.check)(req).err()?;
+        let response = (check.respond)(invalid.clone());
+        Some((invalid, response))
+    }
+}
+
+impl<S: Service> Default for Validations<S> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<S: Service> Clone for Validations<S> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<S: Service> fmt::Debug for Validations<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Validations")
+            .field("methods", &self.0.len())
+            .finish()
+    }
+}
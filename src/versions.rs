@@ -0,0 +1,323 @@
+//! Multiple versions of the same logical rpc message
+//!
+//! Changing a message in an incompatible way breaks every client and server
+//! that is still deployed with the old definition. Instead, the new definition
+//! can be added to the service as a new message, next to the old one, with both
+//! being versions of the same logical message. Handlers and callers only deal
+//! with the canonical version, usually the newest one, and [Versioned]
+//! converts between it and the other versions.
+//!
+//! Servers handle every version with
+//! [RpcChannel::rpc_versioned](crate::server::RpcChannel::rpc_versioned), and
+//! tell clients which versions they support by answering [VersionsRequest]s
+//! with [Versions::handle]. [VersionedClient] asks the server once, and then
+//! sends every call as the newest version that both sides support.
+//!
+//! The negotiation message needs to be part of the service, as an rpc call:
+//!
+//! ```ignore
+//! declare_rpc!(StoreService, VersionsRequest, SupportedVersions);
+//! declare_rpc!(StoreService, GetV1, GetResponseV1);
+//! declare_rpc!(StoreService, GetV2, GetResponseV2);
+//!
+//! let versions = Versions::new()
+//!     .with::<StoreService, GetV1>()
+//!     .with::<StoreService, GetV2>();
+//! ```
+//!
+//! Only rpc calls can be versioned.
+//!
+//! To refuse sending calls as versions that are too old, e.g. because they
+//! lack fields that are required for compliance, see
+//! [VersionedClient::with_requirements].
+use crate::{
+    client::RpcClientError,
+    message::RpcMsg,
+    strict::{Downgrade, Requirements},
+    telemetry::method_name,
+    transport::ConnectionErrors,
+    RpcClient, Service, ServiceConnection,
+};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::{Any, TypeId},
+    collections::{BTreeMap, HashMap},
+    error, fmt, result,
+    sync::Arc,
+};
+
+/// Request for the versions of all logical messages a server supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionsRequest;
+
+/// The versions a server supports, by the name of the canonical message
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SupportedVersions(pub BTreeMap<String, Vec<u32>>);
+
+/// The response of the canonical version of a versioned message
+pub type CanonicalResponse<S, V> = <<V as Versioned<S>>::Canonical as RpcMsg<S>>::Response;
+
+/// A version of a logical rpc message
+///
+/// The canonical message is a version of itself, with conversions that do
+/// nothing.
+pub trait Versioned<S: Service>: RpcMsg<S> {
+    /// The canonical version of the message
+    type Canonical: RpcMsg<S>;
+    /// The version number, higher is newer
+    const VERSION: u32;
+
+    /// Convert a request of this version to the canonical version
+    fn upgrade(self) -> Self::Canonical;
+
+    /// Convert a canonical request to this version
+    ///
+    /// Fields that this version does not have are lost.
+    fn downgrade(req: Self::Canonical) -> Self;
+
+    /// Convert a response of this version to the canonical version
+    fn upgrade_response(res: Self::Response) -> CanonicalResponse<S, Self>;
+
+    /// Convert a canonical response to this version
+    fn downgrade_response(res: CanonicalResponse<S, Self>) -> Self::Response;
+}
+
+/// The versions of logical messages a server supports
+#[derive(Debug, Clone, Default)]
+pub struct Versions(SupportedVersions);
+
+impl Versions {
+    /// Create an empty set of versions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add version `V` of its logical message
+    pub fn with<S: Service, V: Versioned<S>>(mut self) -> Self {
+        let versions = (self.0)
+            .0
+            .entry(method_name::<V::Canonical>().to_string())
+            .or_default();
+        if let Err(index) = versions.binary_search(&V::VERSION) {
+            versions.insert(index, V::VERSION);
+        }
+        self
+    }
+
+    /// The supported versions
+    pub fn supported(&self) -> &SupportedVersions {
+        &self.0
+    }
+
+    /// Handler for [VersionsRequest]s, to be used with [RpcChannel::rpc](crate::server::RpcChannel::rpc)
+    pub async fn handle(self, _req: VersionsRequest) -> SupportedVersions {
+        self.0
+    }
+}
+
+type Caller<S, C> = Arc<
+    dyn Fn(
+            RpcClient<S, C>,
+            Box<dyn Any + Send>,
+        ) -> BoxFuture<'static, result::Result<Box<dyn Any + Send>, RpcClientError<C>>>
+        + Send
+        + Sync,
+>;
+
+/// The versions of a logical message the client can send
+struct Method<S, C: ConnectionErrors> {
+    name: &'static str,
+    callers: BTreeMap<u32, Caller<S, C>>,
+}
+
+impl<S, C: ConnectionErrors> Clone for Method<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            callers: self.callers.clone(),
+        }
+    }
+}
+
+/// A client that sends calls as the newest version the server supports
+///
+/// The versions of the server are requested with the first call, and kept
+/// until [VersionedClient::forget_versions] is called, e.g. after reconnecting
+/// to a server that might have been upgraded. Clones share the versions.
+pub struct VersionedClient<S, C: ConnectionErrors> {
+    client: RpcClient<S, C>,
+    methods: HashMap<TypeId, Method<S, C>>,
+    server: Arc<tokio::sync::Mutex<Option<SupportedVersions>>>,
+    requirements: Option<Arc<Requirements>>,
+}
+
+impl<S, C: ConnectionErrors> Clone for VersionedClient<S, C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            methods: self.methods.clone(),
+            server: self.server.clone(),
+            requirements: self.requirements.clone(),
+        }
+    }
+}
+
+impl<S, C: ConnectionErrors> fmt::Debug for VersionedClient<S, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let methods = self
+            .methods
+            .values()
+            .map(|method| (method.name, method.callers.keys().collect::<Vec<_>>()))
+            .collect::<BTreeMap<_, _>>();
+        f.debug_struct("VersionedClient")
+            .field("methods", &methods)
+            .field("requirements", &self.requirements)
+            .finish()
+    }
+}
+
+impl<S, C> VersionedClient<S, C>
+where
+    S: Service,
+    C: ServiceConnection<S>,
+    VersionsRequest: RpcMsg<S, Response = SupportedVersions>,
+{
+    /// Create a new versioned client, without any versions
+    pub fn new(client: RpcClient<S, C>) -> Self {
+        Self {
+            client,
+            methods: HashMap::new(),
+            server: Default::default(),
+            requirements: None,
+        }
+    }
+
+    /// Refuse to send calls over a connection or as a version that is weaker
+    /// than `requirements`
+    ///
+    /// The connection is checked before every call, and the version after it
+    /// is negotiated. Calls that do not meet the requirements fail with
+    /// [VersionedError::Downgrade], without sending anything.
+    pub fn with_requirements(mut self, requirements: Requirements) -> Self {
+        self.requirements = Some(Arc::new(requirements));
+        self
+    }
+
+    /// Allow sending calls as version `V` of its logical message
+    pub fn with_version<V: Versioned<S>>(mut self) -> Self {
+        let caller: Caller<S, C> = Arc::new(|client: RpcClient<S, C>, req: Box<dyn Any + Send>| {
+            let req = *req
+                .downcast::<V::Canonical>()
+                .expect("callers are registered by canonical type");
+            async move {
+                let res = client.rpc(V::downgrade(req)).await?;
+                let res: Box<dyn Any + Send> = Box::new(V::upgrade_response(res));
+                Ok(res)
+            }
+            .boxed()
+        });
+        self.methods
+            .entry(TypeId::of::<V::Canonical>())
+            .or_insert_with(|| Method {
+                name: method_name::<V::Canonical>(),
+                callers: BTreeMap::new(),
+            })
+            .callers
+            .insert(V::VERSION, caller);
+        self
+    }
+
+    /// Call the logical message of the canonical message `M`, using the newest
+    /// version both sides support
+    pub async fn rpc<M: RpcMsg<S>>(
+        &self,
+        msg: M,
+    ) -> result::Result<M::Response, VersionedError<C>> {
+        let (version, caller) = self.negotiate::<M>().await?;
+        tracing::trace!("calling {} as version {}", method_name::<M>(), version);
+        let res = caller(self.client.clone(), Box::new(msg))
+            .await
+            .map_err(VersionedError::Call)?;
+        Ok(*res
+            .downcast::<M::Response>()
+            .expect("callers return the canonical response"))
+    }
+
+    /// The version calls of the canonical message `M` are sent as
+    pub async fn version<M: RpcMsg<S>>(&self) -> result::Result<u32, VersionedError<C>> {
+        let (version, _) = self.negotiate::<M>().await?;
+        Ok(version)
+    }
+
+    /// Forget the versions of the server, so they are requested again with the
+    /// next call
+    pub async fn forget_versions(&self) {
+        *self.server.lock().await = None;
+    }
+
+    async fn negotiate<M: RpcMsg<S>>(
+        &self,
+    ) -> result::Result<(u32, Caller<S, C>), VersionedError<C>> {
+        let name = method_name::<M>();
+        if let Some(requirements) = &self.requirements {
+            requirements
+                .check(self.client.inner())
+                .map_err(VersionedError::Downgrade)?;
+        }
+        let method = self
+            .methods
+            .get(&TypeId::of::<M>())
+            .ok_or(VersionedError::NoCommonVersion(name))?;
+        let mut server = self.server.lock().await;
+        if server.is_none() {
+            let versions = self
+                .client
+                .rpc(VersionsRequest)
+                .await
+                .map_err(VersionedError::Negotiate)?;
+            *server = Some(versions);
+        }
+        let supported = server
+            .as_ref()
+            .and_then(|versions| versions.0.get(name))
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let (version, caller) = method
+            .callers
+            .iter()
+            .rev()
+            .find(|(version, _)| supported.contains(version))
+            .map(|(version, caller)| (*version, caller.clone()))
+            .ok_or(VersionedError::NoCommonVersion(name))?;
+        if let Some(requirements) = &self.requirements {
+            requirements
+                .check_version(name, version)
+                .map_err(VersionedError::Downgrade)?;
+        }
+        Ok((version, caller))
+    }
+}
+
+/// Error for a [VersionedClient]
+#[derive(Debug)]
+pub enum VersionedError<C: ConnectionErrors> {
+    /// Unable to get the versions the server supports
+    Negotiate(RpcClientError<C>),
+    /// Client and server have no version of the logical message with this
+    /// canonical message in common
+    NoCommonVersion(&'static str),
+    /// The connection or the negotiated version is weaker than required, see
+    /// [VersionedClient::with_requirements]
+    Downgrade(Downgrade),
+    /// The call failed
+    Call(RpcClientError<C>),
+}
+
+impl<C: ConnectionErrors> fmt::Display for VersionedError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl<C: ConnectionErrors> error::Error for VersionedError<C> {}
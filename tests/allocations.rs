@@ -0,0 +1,90 @@
+#![cfg(all(
+    feature = "alloc-counters",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+mod math;
+use futures::{SinkExt, StreamExt};
+use math::*;
+use quic_rpc::{
+    allocations::{AllocationStats, CountingAllocator},
+    transport::flume,
+    RpcClient, RpcServer,
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator::system();
+
+/// Messages sent before measuring, so buffers have grown to their final size
+const WARM_UP: u64 = 100;
+
+/// Messages sent while measuring
+const MEASURED: u64 = 1000;
+
+/// Allocations of an rpc call on the flume transport, for the client, the
+/// server and the channel between them, with some slack for optional features
+/// like metrics and stream limits
+const RPC_CALL_ALLOCATIONS: u64 = 15;
+
+/// The tests run on a current thread runtime, so the allocations of the
+/// client, the server and the transport are all counted on the test thread.
+fn client() -> RpcClient<ComputeService, flume::FlumeConnection<ComputeResponse, ComputeRequest>> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(ComputeService::server(server));
+    RpcClient::new(client)
+}
+
+/// updates and responses of a bidi call do not allocate
+#[tokio::test]
+async fn allocations_bidi_steady_state() -> anyhow::Result<()> {
+    let client = client();
+    let (mut send, mut recv) = client.bidi(Multiply(2)).await?;
+    let mut before = AllocationStats::thread();
+    for n in 0..WARM_UP + MEASURED {
+        if n == WARM_UP {
+            before = AllocationStats::thread();
+        }
+        send.send(MultiplyUpdate(n)).await?;
+        assert_eq!(recv.next().await.unwrap()?.0, n as u128 * 2);
+    }
+    let allocated = AllocationStats::thread() - before;
+    assert_eq!(allocated, AllocationStats::default());
+    Ok(())
+}
+
+/// rpc calls allocate when opening their channel, but not more with every call
+#[tokio::test]
+async fn allocations_rpc_per_call() -> anyhow::Result<()> {
+    let client = client();
+    let mut before = AllocationStats::thread();
+    for n in 0..WARM_UP + MEASURED {
+        if n == WARM_UP {
+            before = AllocationStats::thread();
+        }
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    let allocated = AllocationStats::thread() - before;
+    assert!(
+        allocated.allocations <= RPC_CALL_ALLOCATIONS * MEASURED,
+        "{:?} for {} calls",
+        allocated,
+        MEASURED
+    );
+    Ok(())
+}
+
+/// the totals of the process are part of the metrics
+#[cfg(feature = "openmetrics")]
+#[tokio::test]
+async fn allocations_metrics() -> anyhow::Result<()> {
+    client().rpc(Sqr(2)).await?;
+    let metrics = quic_rpc::metrics::encode();
+    assert!(metrics.contains("# TYPE process_allocations counter"));
+    assert!(metrics.contains("process_allocations_total "));
+    assert!(metrics.contains("process_allocated_bytes_total "));
+    Ok(())
+}
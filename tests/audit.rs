@@ -0,0 +1,148 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+use std::time::Duration;
+
+use quic_rpc::{
+    audit::{AuditConfig, AuditRecord, Auditor, Outcome},
+    server::StreamingTimeouts,
+    transport::flume,
+    RpcClient, RpcServer,
+};
+use tokio::sync::mpsc;
+
+mod math;
+use math::*;
+
+/// An auditor that sends every batch to the returned receiver
+fn collecting(
+    config: AuditConfig,
+) -> (
+    Auditor<ComputeService>,
+    mpsc::UnboundedReceiver<Vec<AuditRecord>>,
+) {
+    let (batches, receiver) = mpsc::unbounded_channel();
+    let auditor = Auditor::new(config, move |batch| {
+        batches.send(batch).ok();
+        async {}
+    });
+    (auditor, receiver)
+}
+
+/// Read batches until there are `n` records
+async fn records(
+    batches: &mut mpsc::UnboundedReceiver<Vec<AuditRecord>>,
+    n: usize,
+) -> Vec<AuditRecord> {
+    let mut records = Vec::new();
+    while records.len() < n {
+        let batch = tokio::time::timeout(Duration::from_secs(5), batches.recv())
+            .await
+            .expect("no audit records")
+            .unwrap();
+        assert!(!batch.is_empty());
+        records.extend(batch);
+    }
+    records
+}
+
+/// every finished call is audited, with the summary of its request
+#[tokio::test]
+async fn audit_finished_calls() -> anyhow::Result<()> {
+    let config = AuditConfig {
+        max_batch: 2,
+        max_delay: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let (auditor, mut batches) = collecting(config);
+    let auditor = auditor.with_summary(|req: &ComputeRequest| format!("{req:?}"));
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_streaming_timeouts(StreamingTimeouts {
+            max_duration: Some(Duration::from_millis(50)),
+            max_update_gap: None,
+        })
+        .with_auditor(auditor);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+    for n in 0..3 {
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    let records = records(&mut batches, 3).await;
+    for (n, record) in records.iter().enumerate() {
+        assert_eq!(record.service, "audit::math::ComputeService");
+        assert_eq!(record.method, "Sqr");
+        assert_eq!(record.request, Some(format!("Sqr(Sqr({n}))")));
+        assert_eq!(record.outcome, Outcome::Completed);
+        assert_eq!(record.peer, None);
+    }
+
+    // the client never finishes its updates
+    let (_updates, response) = client.client_streaming(Sum).await?;
+    assert!(response.await.is_err());
+    let records = self::records(&mut batches, 1).await;
+    assert_eq!(records[0].method, "Sum");
+    match &records[0].outcome {
+        Outcome::Failed(cause) => assert!(cause.starts_with("Timeout"), "{cause}"),
+        outcome => panic!("unexpected outcome {outcome:?}"),
+    }
+    server_handle.abort();
+    Ok(())
+}
+
+/// calls that are dropped before they finish are audited as aborted
+#[tokio::test]
+async fn audit_aborted_call() -> anyhow::Result<()> {
+    let (auditor, mut batches) = collecting(AuditConfig::default());
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_auditor(auditor);
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let call = tokio::spawn(async move { client.rpc(Sqr(2)).await });
+    let (req, chan) = server.accept().await?;
+    let req = match req {
+        ComputeRequest::Sqr(req) => req,
+        req => panic!("unexpected request {req:?}"),
+    };
+    let handler = tokio::spawn(chan.rpc(req, (), |_, _| futures::future::pending::<SqrResponse>()));
+    // let the handler start
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    handler.abort();
+    let records = records(&mut batches, 1).await;
+    assert_eq!(records[0].outcome, Outcome::Aborted);
+    // without a summary, the request is not recorded
+    assert_eq!(records[0].request, None);
+    assert!(call.await?.is_err());
+    Ok(())
+}
+
+/// records are dropped instead of holding up calls when the writer is stuck
+#[tokio::test]
+async fn audit_full_buffer() -> anyhow::Result<()> {
+    let config = AuditConfig {
+        capacity: 1,
+        max_batch: 1,
+        ..Default::default()
+    };
+    let auditor = Auditor::new(config, |_| futures::future::pending());
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_auditor(auditor.clone());
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+    for n in 0..5 {
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    // at most one record is being written and one is buffered
+    for _ in 0..100 {
+        if auditor.dropped() >= 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(auditor.dropped() >= 3);
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,54 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+mod math;
+use math::*;
+use quic_rpc::{blocking::BlockingClient, transport::flume, RpcClient, RpcServer};
+
+fn client() -> RpcClient<ComputeService, flume::FlumeConnection<ComputeResponse, ComputeRequest>> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(ComputeService::server(server));
+    RpcClient::new(client)
+}
+
+/// calls from a blocking task of the runtime do not panic
+#[tokio::test]
+async fn blocking_spawn_blocking() -> anyhow::Result<()> {
+    let client = BlockingClient::new(client());
+    let (sqr, fibonacci) = tokio::task::spawn_blocking(move || {
+        let sqr = client.rpc(Sqr(12))?;
+        let fibonacci = client
+            .server_streaming(Fibonacci(5))?
+            .into_iter()
+            .map(|res| res.map(|res| res.0))
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::Ok((sqr, fibonacci))
+    })
+    .await??;
+    assert_eq!(sqr, SqrResponse(144));
+    assert_eq!(fibonacci, vec![0, 1, 1, 2, 3]);
+    Ok(())
+}
+
+/// calls from a thread outside of the runtime
+#[tokio::test(flavor = "multi_thread")]
+async fn blocking_std_thread() -> anyhow::Result<()> {
+    let client = BlockingClient::new(client());
+    let thread = std::thread::spawn(move || client.rpc(Sqr(3)));
+    let res = tokio::task::spawn_blocking(move || thread.join().unwrap()).await??;
+    assert_eq!(res, SqrResponse(9));
+    Ok(())
+}
+
+/// a panic of the call is forwarded to the caller
+#[tokio::test]
+async fn blocking_panic() -> anyhow::Result<()> {
+    let client = BlockingClient::new(client());
+    let res = tokio::task::spawn_blocking(move || {
+        client.block_on(async {
+            panic!("call failed");
+        })
+    })
+    .await;
+    assert!(res.unwrap_err().is_panic());
+    Ok(())
+}
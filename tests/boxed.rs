@@ -0,0 +1,78 @@
+#![cfg(all(
+    feature = "flume-transport",
+    feature = "tcp-transport",
+    feature = "macros"
+))]
+use quic_rpc::{
+    transport::{
+        boxed::{BoxedClient, BoxedConnection, BoxedServer, BoxedServerEndpoint},
+        flume::{self, FlumeConnection},
+        tcp::{TcpConnection, TcpServerEndpoint},
+        Connection, ConnectionCommon, LocalAddr, ServerEndpoint,
+    },
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// A server and a client of the transport named `kind`
+async fn pair(
+    kind: &str,
+) -> anyhow::Result<(BoxedServer<ComputeService>, BoxedClient<ComputeService>)> {
+    let (endpoint, connection) = match kind {
+        "mem" => {
+            let (endpoint, connection) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+            (
+                BoxedServerEndpoint::new(endpoint),
+                BoxedConnection::new(connection),
+            )
+        }
+        "tcp" => {
+            let endpoint =
+                TcpServerEndpoint::<ComputeRequest, ComputeResponse>::bind("127.0.0.1:0").await?;
+            let addr = match endpoint.local_addr() {
+                [LocalAddr::Socket(addr)] => *addr,
+                other => anyhow::bail!("unexpected local addr {:?}", other),
+            };
+            let connection =
+                TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+            (
+                BoxedServerEndpoint::new(endpoint),
+                BoxedConnection::new(connection),
+            )
+        }
+        other => anyhow::bail!("unknown transport {other}"),
+    };
+    Ok((RpcServer::new(endpoint), RpcClient::new(connection)))
+}
+
+/// the transport is chosen at runtime, without changing the types
+#[tokio::test]
+async fn boxed_transports() -> anyhow::Result<()> {
+    for kind in ["mem", "tcp"] {
+        let (server, client) = pair(kind).await?;
+        let server_handle = tokio::spawn(ComputeService::server(server));
+        smoke_test(client.into_inner()).await?;
+        server_handle.abort();
+    }
+    Ok(())
+}
+
+/// the capabilities and errors of the transport inside are kept
+#[tokio::test]
+async fn boxed_capabilities_and_errors() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    drop(endpoint);
+    let connection = BoxedConnection::new(connection);
+    assert_eq!(
+        connection.capabilities(),
+        <FlumeConnection<ComputeResponse, ComputeRequest> as ConnectionCommon<_, _>>::CAPABILITIES
+    );
+    let err = connection.open_bi().await.err().expect("no server");
+    assert!(
+        format!("{}", err.cause()).contains("RemoteDropped"),
+        "unexpected error {err}"
+    );
+    Ok(())
+}
@@ -0,0 +1,125 @@
+#![cfg(feature = "flume-transport")]
+use quic_rpc::{
+    client::RpcClientError,
+    sharded::ShardedClient,
+    transport::{
+        breaker::{BreakerConfig, BreakerConnection, BreakerState, CircuitBreaker, OpenError},
+        flume,
+    },
+    RpcClient, RpcServer,
+};
+use std::time::Duration;
+
+mod math;
+use math::*;
+
+type Conn = BreakerConnection<
+    flume::FlumeConnection<ComputeResponse, ComputeRequest>,
+    ComputeResponse,
+    ComputeRequest,
+>;
+
+fn config() -> BreakerConfig {
+    BreakerConfig {
+        window: 4,
+        min_calls: 3,
+        failure_rate: 0.5,
+        slow_call: None,
+        open_for: Duration::from_millis(50),
+    }
+}
+
+/// A connection to a server that is running
+fn healthy(breaker: &CircuitBreaker) -> Conn {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(ComputeService::server(server));
+    BreakerConnection::with_breaker(client, breaker.clone())
+}
+
+/// A connection to a server that is gone, so opening substreams fails
+fn broken(breaker: &CircuitBreaker) -> Conn {
+    let (_, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    BreakerConnection::with_breaker(client, breaker.clone())
+}
+
+#[tokio::test]
+async fn breaker_opens_and_recovers() -> anyhow::Result<()> {
+    let breaker = CircuitBreaker::new(config());
+    let broken = RpcClient::<ComputeService, _>::new(broken(&breaker));
+    let healthy = RpcClient::<ComputeService, _>::new(healthy(&breaker));
+
+    assert_eq!(healthy.rpc(Sqr(2)).await?.0, 4);
+    for _ in 0..2 {
+        let res = broken.rpc(Sqr(2)).await;
+        assert!(matches!(
+            res,
+            Err(RpcClientError::Open(OpenError::Inner(_)))
+        ));
+    }
+    // 2 of the 3 calls failed, so the breaker is open and calls fail fast
+    assert_eq!(breaker.state(), BreakerState::Open);
+    let res = healthy.rpc(Sqr(2)).await;
+    assert!(matches!(res, Err(RpcClientError::Open(OpenError::Open))));
+
+    // a failed probe opens the breaker again
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(breaker.state(), BreakerState::HalfOpen);
+    assert!(broken.rpc(Sqr(2)).await.is_err());
+    assert_eq!(breaker.state(), BreakerState::Open);
+
+    // a successful probe closes it
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert_eq!(healthy.rpc(Sqr(3)).await?.0, 9);
+    assert_eq!(breaker.state(), BreakerState::Closed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn breaker_slow_calls() -> anyhow::Result<()> {
+    // the server is alive, but never accepts, so calls never get a response
+    let (_server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let config = BreakerConfig {
+        min_calls: 1,
+        slow_call: Some(Duration::from_millis(10)),
+        ..config()
+    };
+    let client = RpcClient::<ComputeService, _>::new(BreakerConnection::new(client, config));
+    let res = tokio::time::timeout(Duration::from_millis(50), client.rpc(Sqr(2))).await;
+    assert!(res.is_err());
+    // the abandoned call took too long, so it counts as failed
+    assert_eq!(client.inner().breaker().state(), BreakerState::Open);
+    Ok(())
+}
+
+/// shards with an open breaker are ejected until they recover
+#[tokio::test]
+async fn breaker_sharded_client() -> anyhow::Result<()> {
+    let good = CircuitBreaker::new(config());
+    let bad = CircuitBreaker::new(config());
+    let sharded = ShardedClient::new(|req: &ComputeRequest| match req {
+        ComputeRequest::Sqr(Sqr(x)) => *x,
+        _ => 0,
+    })
+    .with_shard("good", RpcClient::new(healthy(&good)))
+    .with_shard("bad", RpcClient::new(broken(&bad)))
+    .with_circuit_breakers();
+
+    // find keys owned by the broken shard
+    let keys = (0..100)
+        .filter(|key| sharded.owner(key) == Some("bad"))
+        .take(3)
+        .collect::<Vec<_>>();
+    for key in &keys {
+        assert!(sharded.rpc(Sqr(*key)).await.is_err());
+    }
+    assert_eq!(bad.state(), BreakerState::Open);
+
+    // the keys of the broken shard are now served by the remaining shard
+    for key in keys {
+        let res = sharded.rpc(Sqr(key)).await?;
+        assert_eq!(res.0, key as u128 * key as u128);
+    }
+    assert_eq!(sharded.shards().len(), 2);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+#![cfg(all(
+    feature = "flume-transport",
+    feature = "tcp-transport",
+    feature = "macros"
+))]
+use quic_rpc::{
+    transport::{
+        bridge::{self, ForwardError},
+        tcp::{TcpConnection, TcpServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcClient, RpcServer,
+};
+use std::net::SocketAddr;
+
+mod math;
+use math::*;
+
+/// A tcp server endpoint on a free port, and its address
+async fn tcp_endpoint() -> anyhow::Result<(
+    TcpServerEndpoint<ComputeRequest, ComputeResponse>,
+    SocketAddr,
+)> {
+    let endpoint =
+        TcpServerEndpoint::<ComputeRequest, ComputeResponse>::bind("127.0.0.1:0").await?;
+    let addr = match endpoint.local_addr() {
+        [LocalAddr::Socket(addr)] => *addr,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    Ok((endpoint, addr))
+}
+
+/// calls on the flume connection are made on the remote connection
+#[tokio::test]
+async fn bridge_connection() -> anyhow::Result<()> {
+    let (endpoint, addr) = tcp_endpoint().await?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(endpoint)));
+    let remote = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+    let (connection, bridge) = bridge::connection(remote, 1);
+    smoke_test(connection.clone()).await?;
+
+    // the bridge ends with the last clone of the connection
+    let client = RpcClient::<ComputeService, _>::new(connection);
+    assert_eq!(client.rpc(Sqr(5)).await?, SqrResponse(25));
+    drop(client);
+    assert!(matches!(bridge.await?, ForwardError::Accept(_)));
+    server_handle.abort();
+    Ok(())
+}
+
+/// calls of remote clients arrive on the flume server endpoint
+#[tokio::test]
+async fn bridge_server_endpoint() -> anyhow::Result<()> {
+    let (remote, addr) = tcp_endpoint().await?;
+    let (endpoint, _bridge) = bridge::server_endpoint(remote, 1);
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(endpoint)));
+    let client = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
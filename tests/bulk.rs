@@ -0,0 +1,192 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{SinkExt, StreamExt};
+use quic_rpc::{
+    bulk::{
+        self, BulkError, BulkOptions, BulkReceiver, BulkTransfers, FileRegion, Segment, Stripe,
+        StripeReceived,
+    },
+    declare_client_streaming,
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Stripe(Stripe),
+    Segment(Segment),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    StripeReceived(StripeReceived),
+}
+
+#[derive(Debug, Clone)]
+struct BulkService;
+
+impl Service for BulkService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_client_streaming!(BulkService, Stripe, Segment, StripeReceived);
+
+async fn server<C: ServiceEndpoint<BulkService>>(
+    server: RpcServer<BulkService, C>,
+    receiver: BulkReceiver,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        let receiver = receiver.clone();
+        // the stripes of a transfer need to be handled concurrently
+        tokio::spawn(async move {
+            match req {
+                Request::Stripe(msg) => {
+                    chan.client_streaming(msg, receiver, BulkReceiver::handle)
+                        .await
+                }
+                Request::Segment(_) => Err(RpcServerError::UnexpectedStartMessage),
+            }
+        });
+    }
+}
+
+fn spawn_server(
+    max_pending: usize,
+) -> (
+    RpcClient<BulkService, flume::FlumeConnection<Response, Request>>,
+    BulkTransfers,
+) {
+    let (endpoint, client) = flume::connection::<Request, Response>(1);
+    let (receiver, transfers) = BulkReceiver::new(max_pending);
+    tokio::spawn(server(RpcServer::new(endpoint), receiver));
+    (RpcClient::new(client), transfers)
+}
+
+/// data striped across any number of streams arrives in order
+#[tokio::test]
+async fn bulk_roundtrip() -> anyhow::Result<()> {
+    let (client, mut transfers) = spawn_server(2);
+    let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    for stripes in [1, 3, 8] {
+        let options = BulkOptions {
+            stripes,
+            segment_size: 1000,
+        };
+        // the source produces chunks that do not line up with the segments
+        let chunks = data.chunks(777).map(<[u8]>::to_vec).collect::<Vec<_>>();
+        let id = format!("transfer-{stripes}");
+        let sent = bulk::send(&client, id.clone(), futures::stream::iter(chunks), options);
+        let received = async {
+            let mut transfer = transfers.accept().await.expect("transfer");
+            assert_eq!(transfer.id(), id);
+            let mut received = Vec::new();
+            while let Some(segment) = transfer.recv().await {
+                received.extend(segment?);
+            }
+            anyhow::Ok(received)
+        };
+        let (sent, received) = tokio::join!(sent, received);
+        assert_eq!(sent?, data.len() as u64);
+        assert_eq!(received?, data);
+    }
+    Ok(())
+}
+
+/// stripes that do not fit the transfer are rejected
+#[tokio::test]
+async fn bulk_rejected_stripe() -> anyhow::Result<()> {
+    let (client, mut transfers) = spawn_server(4);
+    let stripe = |index| Stripe {
+        id: "transfer".into(),
+        index,
+        stripes: 2,
+    };
+    let (mut first, first_res) = client.client_streaming(stripe(0)).await?;
+    let transfer = transfers.accept().await.expect("transfer");
+
+    // the index is already used
+    let (mut duplicate, duplicate_res) = client.client_streaming(stripe(0)).await?;
+    duplicate
+        .send(Segment {
+            seq: 0,
+            data: vec![1],
+        })
+        .await?;
+    drop(duplicate);
+    assert_eq!(duplicate_res.await?, StripeReceived(0));
+
+    // the index is out of range
+    let (out_of_range, out_of_range_res) = client.client_streaming(stripe(2)).await?;
+    drop(out_of_range);
+    assert_eq!(out_of_range_res.await?, StripeReceived(0));
+
+    // the transfer goes on with the valid stripes
+    let (mut second, second_res) = client.client_streaming(stripe(1)).await?;
+    second
+        .send(Segment {
+            seq: 1,
+            data: vec![2],
+        })
+        .await?;
+    first
+        .send(Segment {
+            seq: 0,
+            data: vec![1],
+        })
+        .await?;
+    drop((first, second));
+    assert_eq!(first_res.await?, StripeReceived(1));
+    assert_eq!(second_res.await?, StripeReceived(1));
+    let received = transfer.into_stream().collect::<Vec<_>>().await;
+    assert_eq!(received, vec![Ok(vec![1]), Ok(vec![2])]);
+    Ok(())
+}
+
+/// a region of a file is sent as it is in the file
+#[tokio::test]
+async fn bulk_file_region() -> anyhow::Result<()> {
+    let (client, mut transfers) = spawn_server(4);
+    let path = std::env::temp_dir().join(format!("quic-rpc-bulk-{}", std::process::id()));
+    let data = (0..50_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&path, &data)?;
+    let options = BulkOptions {
+        stripes: 3,
+        segment_size: 4096,
+    };
+    let file = std::fs::File::open(&path)?;
+    let cases = [
+        (FileRegion::whole(file.try_clone()?)?, 0..data.len()),
+        (
+            FileRegion::new(file.try_clone()?, 1000, 20_001),
+            1000..21_001,
+        ),
+        (FileRegion::new(file.try_clone()?, 0, 0), 0..0),
+    ];
+    for (i, (region, range)) in cases.into_iter().enumerate() {
+        let id = format!("file-{i}");
+        let sent = bulk::send_file(&client, id.clone(), region, options);
+        let received = async {
+            let transfer = transfers.accept().await.expect("transfer");
+            assert_eq!(transfer.id(), id);
+            let segments = transfer.into_stream().collect::<Vec<_>>().await;
+            segments.into_iter().collect::<Result<Vec<_>, _>>()
+        };
+        let (sent, received) = tokio::join!(sent, received);
+        assert_eq!(sent?, range.len() as u64);
+        assert_eq!(received?.concat(), data[range]);
+    }
+
+    // a region past the end of the file fails to read
+    let region = FileRegion::new(file, 49_000, 2000);
+    let res = tokio::join!(
+        bulk::send_file(&client, "short", region, options),
+        transfers.accept()
+    );
+    assert!(matches!(res.0, Err(BulkError::Read(_))));
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
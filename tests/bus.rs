@@ -0,0 +1,57 @@
+#![cfg(feature = "bus-transport")]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::bus::{BusConnection, BusServerEndpoint, MemoryBroker},
+    RpcClient, RpcServer,
+};
+
+/// all 4 patterns work over a broker
+#[tokio::test]
+async fn bus_channel_smoke() -> anyhow::Result<()> {
+    let broker = MemoryBroker::new();
+    let server =
+        BusServerEndpoint::<_, ComputeRequest, ComputeResponse>::serve(broker.clone(), "compute")
+            .await?;
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+
+    let client =
+        BusConnection::<_, ComputeResponse, ComputeRequest>::connect(broker, "compute").await?;
+    smoke_test(client).await?;
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// responses are routed back to the connection that made the call
+#[tokio::test]
+async fn bus_channel_many_clients() -> anyhow::Result<()> {
+    let broker = MemoryBroker::new();
+    let server =
+        BusServerEndpoint::<_, ComputeRequest, ComputeResponse>::serve(broker.clone(), "compute")
+            .await?;
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+
+    let mut calls = Vec::new();
+    for i in 0..4u64 {
+        let client =
+            BusConnection::<_, ComputeResponse, ComputeRequest>::connect(broker.clone(), "compute")
+                .await?;
+        let client = RpcClient::<ComputeService, _>::new(client);
+        calls.push(tokio::spawn(async move {
+            for j in 0..10u64 {
+                let n = i * 100 + j;
+                assert_eq!(client.rpc(Sqr(n)).await?, SqrResponse((n * n) as u128));
+            }
+            anyhow::Ok(())
+        }));
+    }
+    for call in calls {
+        call.await??;
+    }
+
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,198 @@
+#![cfg(all(feature = "flume-transport", feature = "response-cache"))]
+use derive_more::{From, TryInto};
+use quic_rpc::{
+    cache::ResponseCache, declare_rpc, message::RpcMsg, server::RpcServerError, transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Get(String);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Put(String, u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum StoreRequest {
+    Get(Get),
+    Put(Put),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum StoreResponse {
+    Value(Option<u64>),
+    Unit(()),
+}
+
+#[derive(Debug, Clone)]
+struct StoreService;
+
+impl Service for StoreService {
+    type Req = StoreRequest;
+    type Res = StoreResponse;
+}
+
+impl RpcMsg<StoreService> for Get {
+    type Response = Option<u64>;
+    const CACHEABLE: bool = true;
+}
+
+declare_rpc!(StoreService, Put, ());
+
+#[derive(Debug, Clone, Default)]
+struct Store {
+    values: Arc<Mutex<HashMap<String, u64>>>,
+    gets: Arc<AtomicUsize>,
+}
+
+impl Store {
+    async fn get(self, req: Get) -> Option<u64> {
+        self.gets.fetch_add(1, Ordering::SeqCst);
+        self.values.lock().unwrap().get(&req.0).copied()
+    }
+
+    async fn put(self, req: Put) {
+        self.values.lock().unwrap().insert(req.0, req.1);
+    }
+
+    async fn server<C: ServiceEndpoint<StoreService>>(
+        self,
+        server: RpcServer<StoreService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            let store = self.clone();
+            match req {
+                StoreRequest::Get(msg) => chan.rpc(msg, store, Store::get).await,
+                StoreRequest::Put(msg) => chan.rpc(msg, store, Store::put).await,
+            }?;
+        }
+    }
+}
+
+#[tokio::test]
+async fn cache_rpc_responses() -> anyhow::Result<()> {
+    let cache = ResponseCache::<StoreService>::new(Duration::from_secs(60)).with_invalidation(
+        |req, cache| {
+            if let StoreRequest::Put(_) = req {
+                cache.invalidate::<Get>();
+            }
+        },
+    );
+    let (server, client) = flume::connection::<StoreRequest, StoreResponse>(1);
+    let server = RpcServer::<StoreService, _>::new(server).with_response_cache(cache.clone());
+    let store = Store::default();
+    let gets = store.gets.clone();
+    let server_handle = tokio::spawn(store.server(server));
+    let client = RpcClient::<StoreService, _>::new(client);
+
+    // repeated identical requests are answered from the cache
+    assert_eq!(client.rpc(Get("a".into())).await?, None);
+    assert_eq!(client.rpc(Get("a".into())).await?, None);
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+    // different requests are not
+    assert_eq!(client.rpc(Get("b".into())).await?, None);
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+    assert_eq!(cache.len(), 2);
+
+    // the invalidation hook removes stale responses
+    client.rpc(Put("a".into(), 1)).await?;
+    assert!(cache.is_empty());
+    assert_eq!(client.rpc(Get("a".into())).await?, Some(1));
+    assert_eq!(client.rpc(Get("a".into())).await?, Some(1));
+    assert_eq!(gets.load(Ordering::SeqCst), 3);
+
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn cache_expires_entries() -> anyhow::Result<()> {
+    let cache = ResponseCache::<StoreService>::new(Duration::from_secs(60))
+        .with_method_ttl::<Get>(Duration::from_millis(50))
+        .with_capacity(1);
+    let (server, client) = flume::connection::<StoreRequest, StoreResponse>(1);
+    let server = RpcServer::<StoreService, _>::new(server).with_response_cache(cache.clone());
+    let store = Store::default();
+    let gets = store.gets.clone();
+    let server_handle = tokio::spawn(store.server(server));
+    let client = RpcClient::<StoreService, _>::new(client);
+
+    client.rpc(Get("a".into())).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.rpc(Get("a".into())).await?;
+    assert_eq!(gets.load(Ordering::SeqCst), 2);
+
+    // the capacity is never exceeded
+    client.rpc(Get("b".into())).await?;
+    assert_eq!(cache.len(), 1);
+    client.rpc(Get("b".into())).await?;
+    assert_eq!(gets.load(Ordering::SeqCst), 3);
+
+    server_handle.abort();
+    Ok(())
+}
+
+/// Names of the timings recorded on spans
+#[derive(Debug, Clone, Default)]
+struct Timings(Arc<Mutex<Vec<&'static str>>>);
+
+impl Visit for Timings {
+    fn record_u64(&mut self, field: &Field, _value: u64) {
+        if field.name().ends_with("_us") {
+            self.0.lock().unwrap().push(field.name());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Timings {
+    fn on_record(&self, _id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        values.record(&mut self.clone());
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn cache_hit_timings() -> anyhow::Result<()> {
+    let timings = Timings::default();
+    let _guard = tracing_subscriber::registry()
+        .with(timings.clone())
+        .set_default();
+
+    let cache = ResponseCache::<StoreService>::new(Duration::from_secs(60));
+    let (server, client) = flume::connection::<StoreRequest, StoreResponse>(1);
+    let server = RpcServer::<StoreService, _>::new(server).with_response_cache(cache);
+    let store = Store::default();
+    let gets = store.gets.clone();
+    let server_handle = tokio::spawn(store.server(server));
+    let client = RpcClient::<StoreService, _>::new(client);
+
+    client.rpc(Get("a".into())).await?;
+    timings.0.lock().unwrap().clear();
+    client.rpc(Get("a".into())).await?;
+    assert_eq!(gets.load(Ordering::SeqCst), 1);
+
+    // responses from the cache are encoded and sent in timed phases too
+    let recorded = timings.0.lock().unwrap();
+    for name in ["rpc.encode_us", "rpc.send_us"] {
+        assert!(recorded.contains(&name), "missing timing {name}");
+    }
+    drop(recorded);
+
+    server_handle.abort();
+    Ok(())
+}
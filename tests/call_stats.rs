@@ -0,0 +1,86 @@
+#![cfg(all(
+    feature = "call-stats",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+use quic_rpc::{transport::flume, RpcClient, RpcServer};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Get(pub u64);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Done(pub u64);
+
+quic_rpc::rpc_service! {
+    Request = StatsRequest;
+    Response = StatsResponse;
+    Service = StatsService;
+    CreateDispatch = _;
+
+    #[rpc(idempotent)]
+    Rpc get = Get, _ -> Done;
+}
+
+/// a server that drops the first `fail` attempts, and answers after `delay`
+fn server(
+    fail: usize,
+    delay: Duration,
+) -> RpcClient<StatsService, flume::FlumeConnection<StatsResponse, StatsRequest>> {
+    let (server, client) = flume::connection::<StatsRequest, StatsResponse>(1);
+    let server = RpcServer::<StatsService, _>::new(server);
+    let attempts = Arc::new(AtomicUsize::new(0));
+    tokio::spawn(async move {
+        while let Ok((StatsRequest::Get(req), chan)) = server.accept().await {
+            if attempts.fetch_add(1, Ordering::SeqCst) < fail {
+                drop(chan);
+                continue;
+            }
+            chan.rpc(req, (), |(), req| async move {
+                tokio::time::sleep(delay).await;
+                Done(req.0)
+            })
+            .await?;
+        }
+        anyhow::Ok(())
+    });
+    RpcClient::new(client).with_retries(3, Duration::from_millis(1))
+}
+
+/// a single attempt reports the sizes of request and response and its round trip
+#[tokio::test]
+async fn call_stats_rpc() -> anyhow::Result<()> {
+    let client = server(0, Duration::from_millis(20));
+    let (res, stats) = client.rpc_detailed(Get(7)).await?;
+    assert_eq!(res, Done(7));
+    // 4 bytes of enum variant and 8 bytes of u64
+    assert_eq!(stats.bytes_sent, 12);
+    assert_eq!(stats.bytes_received, 12);
+    assert_eq!(stats.retries, 0);
+    assert!(stats.rtt >= Duration::from_millis(20), "{stats:?}");
+    assert!(stats.queue_time < Duration::from_millis(20), "{stats:?}");
+    Ok(())
+}
+
+/// retried calls count the requests of every attempt and the number of retries
+#[tokio::test]
+async fn call_stats_retries() -> anyhow::Result<()> {
+    let client = server(2, Duration::ZERO);
+    let (res, stats) = client.rpc_retry_detailed(Get(3)).await?;
+    assert_eq!(res, Done(3));
+    assert_eq!(stats.bytes_sent, 36);
+    assert_eq!(stats.bytes_received, 12);
+    assert_eq!(stats.retries, 2);
+
+    // the single attempt of rpc_detailed is not retried
+    let client = server(1, Duration::ZERO);
+    assert!(client.rpc_detailed(Get(3)).await.is_err());
+    Ok(())
+}
@@ -0,0 +1,172 @@
+#![cfg(feature = "flume-transport")]
+mod math;
+use futures::StreamExt;
+use math::*;
+use quic_rpc::{
+    channels::{self, LagPolicy, Pausable, TeeError},
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, ServiceEndpoint,
+};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// a multiply handler written around channels
+async fn multiply_server<C: ServiceEndpoint<ComputeService>>(
+    server: RpcServer<ComputeService, C>,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        let req = match req {
+            ComputeRequest::Multiply(req) => req,
+            _ => return Err(RpcServerError::UnexpectedStartMessage),
+        };
+        tokio::spawn(chan.bidi_streaming(req, (), |(), req, updates| {
+            let mut updates = channels::tokio_receiver(updates, 4);
+            let (tx, rx) = mpsc::channel(4);
+            tokio::spawn(async move {
+                while let Some(MultiplyUpdate(n)) = updates.recv().await {
+                    let product = req.0 as u128 * n as u128;
+                    if tx.send(MultiplyResponse(product)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            channels::tokio_stream(rx)
+        }));
+    }
+}
+
+#[tokio::test]
+async fn channels_tokio() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(multiply_server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let (updates, responses) = client.bidi(Multiply(3)).await?;
+    let (tx, forward) = channels::tokio_sender(updates, 4);
+    let mut rx = channels::tokio_receiver(responses, 4);
+    for i in 1..=3 {
+        tx.send(MultiplyUpdate(i)).await?;
+        assert_eq!(rx.recv().await.unwrap()?.0, 3 * i as u128);
+    }
+    // closing the sender ends the updates, and then the responses
+    drop(tx);
+    forward.await??;
+    assert!(rx.recv().await.is_none());
+    server_handle.abort();
+    Ok(())
+}
+
+/// std channels can be used from threads outside of the runtime
+#[tokio::test]
+async fn channels_std() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let (updates, responses) = client.bidi(Multiply(2)).await?;
+    let (tx, forward) = channels::std_sender(updates);
+    let rx = channels::std_receiver(responses);
+    let products = std::thread::spawn(move || {
+        let mut products = Vec::new();
+        for i in 1..=3 {
+            tx.send(MultiplyUpdate(i)).unwrap();
+            products.push(rx.recv().unwrap().unwrap().0);
+        }
+        products
+    });
+    let products = tokio::task::spawn_blocking(move || products.join().unwrap()).await?;
+    assert_eq!(products, vec![2, 4, 6]);
+    forward.await??;
+    server_handle.abort();
+    Ok(())
+}
+
+/// every consumer of a tee gets every response, at the pace of the slowest one
+#[tokio::test]
+async fn channels_tee_wait() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let responses = client.server_streaming(Fibonacci(10)).await?;
+    let mut consumers = channels::tee(responses, 2, 1, LagPolicy::Wait);
+    let b = consumers.pop().unwrap();
+    let a = consumers.pop().unwrap();
+    let (a, b) = tokio::join!(a.collect::<Vec<_>>(), b.collect::<Vec<_>>());
+    let a = a.into_iter().map(|res| res.unwrap().0).collect::<Vec<_>>();
+    let b = b.into_iter().map(|res| res.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(a, vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    assert_eq!(a, b);
+    server_handle.abort();
+    Ok(())
+}
+
+/// a consumer that falls behind is told how many responses it missed
+#[tokio::test]
+async fn channels_tee_skip() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let responses = client.server_streaming(Fibonacci(10)).await?;
+    let mut consumers = channels::tee(responses, 2, 2, LagPolicy::Skip);
+    let slow = consumers.pop().unwrap();
+    let fast = consumers.pop().unwrap();
+    // the fast consumer is not held up by the slow one
+    let mut received = 0;
+    for item in fast.collect::<Vec<_>>().await {
+        match item {
+            Ok(_) => received += 1,
+            Err(TeeError::Lagged(n)) => received += n,
+            Err(TeeError::Inner(cause)) => panic!("{}", cause),
+        }
+    }
+    assert_eq!(received, 10);
+
+    // the slow consumer only got what fit into its buffer
+    let slow = slow.collect::<Vec<_>>().await;
+    assert_eq!(slow.len(), 3);
+    assert_eq!(slow[0].as_ref().unwrap().0, 0);
+    assert_eq!(slow[1].as_ref().unwrap().0, 1);
+    assert!(matches!(slow[2], Err(TeeError::Lagged(8))));
+    server_handle.abort();
+    Ok(())
+}
+
+/// a paused response stream yields nothing until it is resumed, and then
+/// continues where it left off
+#[tokio::test]
+async fn channels_pausable() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let mut responses = Pausable::new(client.server_streaming(Fibonacci(10)).await?);
+    assert_eq!(responses.next().await.unwrap()?.0, 0);
+    let handle = responses.handle();
+    handle.pause();
+    assert!(responses.is_paused());
+    let next = tokio::time::timeout(Duration::from_millis(50), responses.next()).await;
+    assert!(next.is_err());
+
+    // resuming from elsewhere wakes up the consumer
+    let consumer = tokio::spawn(responses.collect::<Vec<_>>());
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(!consumer.is_finished());
+    handle.resume();
+    let rest = consumer
+        .await?
+        .into_iter()
+        .map(|res| res.unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(rest, vec![1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    server_handle.abort();
+    Ok(())
+}
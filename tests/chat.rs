@@ -0,0 +1,37 @@
+#![cfg(all(
+    feature = "flume-transport",
+    feature = "quinn-transport",
+    feature = "macros"
+))]
+use quic_rpc::{
+    transport::{
+        flume,
+        quinn::{QuinnConnection, QuinnServerEndpoint},
+    },
+    RpcClient, RpcServer,
+};
+
+#[path = "../examples/chat/chat.rs"]
+mod chat;
+use chat::*;
+
+#[tokio::test]
+async fn chat_flume() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ChatRequest, ChatResponse>(1);
+    let server = tokio::spawn(Chat::default().serve(RpcServer::new(server)));
+    conversation(RpcClient::<ChatService, _>::new(client)).await?;
+    server.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn chat_quinn() -> anyhow::Result<()> {
+    let (server, client, addr) = quinn_endpoints()?;
+    let server = QuinnServerEndpoint::<ChatRequest, ChatResponse>::new(server)?;
+    let server = tokio::spawn(Chat::default().serve(RpcServer::new(server)));
+    let client =
+        QuinnConnection::<ChatResponse, ChatRequest>::new(client, addr, "localhost".into());
+    conversation(RpcClient::<ChatService, _>::new(client)).await?;
+    server.abort();
+    Ok(())
+}
@@ -0,0 +1,268 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, SinkExt, Stream, StreamExt};
+use quic_rpc::{
+    client::{RpcClientError, StreamingResponseItemError},
+    context::{CallCancelled, CallContext},
+    declare_client_streaming, declare_rpc, declare_server_streaming,
+    server::{RpcServerError, StreamingTimeouts},
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// sleep for the given number of milliseconds
+#[derive(Debug, Serialize, Deserialize)]
+struct Sleep(u64);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Slept;
+
+/// call [Sleep] on the downstream service, from a spawned task
+#[derive(Debug, Serialize, Deserialize)]
+struct Nested(u64);
+
+/// an endless stream of ticks, optionally sleeping in a background task
+#[derive(Debug, Serialize, Deserialize)]
+struct Ticks(Option<u64>);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Tick;
+
+/// report the remaining time of the call context of the handler
+#[derive(Debug, Serialize, Deserialize)]
+struct Remaining;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RemainingUpdate;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RemainingResponse(Option<Duration>);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum SlowRequest {
+    Sleep(Sleep),
+    Nested(Nested),
+    Ticks(Ticks),
+    Remaining(Remaining),
+    RemainingUpdate(RemainingUpdate),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum SlowResponse {
+    Slept(Slept),
+    Tick(Tick),
+    RemainingResponse(RemainingResponse),
+}
+
+#[derive(Debug, Clone)]
+struct SlowService;
+
+impl Service for SlowService {
+    type Req = SlowRequest;
+    type Res = SlowResponse;
+}
+
+declare_rpc!(SlowService, Sleep, Slept);
+declare_rpc!(SlowService, Nested, Slept);
+declare_server_streaming!(SlowService, Ticks, Tick);
+declare_client_streaming!(SlowService, Remaining, RemainingUpdate, RemainingResponse);
+
+type Downstream = RpcClient<SlowService, flume::FlumeConnection<SlowResponse, SlowRequest>>;
+
+/// Sets the flag when dropped before it is defused
+struct DropFlag(Option<Arc<AtomicBool>>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        if let Some(flag) = self.0.take() {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Handler {
+    downstream: Option<Downstream>,
+    sleep_dropped: Arc<AtomicBool>,
+}
+
+impl Handler {
+    async fn sleep(self, req: Sleep) -> Slept {
+        let mut flag = DropFlag(Some(self.sleep_dropped));
+        tokio::time::sleep(Duration::from_millis(req.0)).await;
+        flag.0 = None;
+        Slept
+    }
+
+    async fn nested(self, req: Nested) -> Slept {
+        let downstream = self.downstream.expect("no downstream");
+        let ctx = CallContext::current().expect("handlers run in a context");
+        // the spawned call outlives the handler, unless it is bounded by the context
+        let task = tokio::spawn(ctx.scope(async move { downstream.rpc(Sleep(req.0)).await }));
+        task.await.unwrap().unwrap_or(Slept)
+    }
+
+    fn ticks(self, req: Ticks) -> impl Stream<Item = Tick> {
+        if let Some(background) = req.0 {
+            let ctx = CallContext::current().expect("handlers run in a context");
+            ctx.spawn(self.sleep(Sleep(background)));
+        }
+        stream::repeat_with(|| Tick).then(|tick| async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tick
+        })
+    }
+
+    async fn remaining(
+        self,
+        _req: Remaining,
+        updates: impl Stream<Item = RemainingUpdate>,
+    ) -> RemainingResponse {
+        updates.for_each(|_| async {}).await;
+        RemainingResponse(CallContext::current().and_then(|ctx| ctx.remaining()))
+    }
+
+    async fn server<C: ServiceEndpoint<SlowService>>(
+        self,
+        server: RpcServer<SlowService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            let handler = self.clone();
+            tokio::spawn(async move {
+                match req {
+                    SlowRequest::Sleep(msg) => chan.rpc(msg, handler, Handler::sleep).await,
+                    SlowRequest::Nested(msg) => chan.rpc(msg, handler, Handler::nested).await,
+                    SlowRequest::Ticks(msg) => {
+                        chan.server_streaming(msg, handler, Handler::ticks).await
+                    }
+                    SlowRequest::Remaining(msg) => {
+                        chan.client_streaming(msg, handler, Handler::remaining)
+                            .await
+                    }
+                    SlowRequest::RemainingUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+                }
+            });
+        }
+    }
+}
+
+fn spawn_server(downstream: Option<Downstream>) -> (Downstream, Arc<AtomicBool>) {
+    let (server, client) = flume::connection::<SlowRequest, SlowResponse>(1);
+    let server =
+        RpcServer::<SlowService, _>::new(server).with_streaming_timeouts(StreamingTimeouts {
+            max_duration: Some(Duration::from_secs(10)),
+            max_update_gap: None,
+        });
+    let sleep_dropped = Arc::new(AtomicBool::new(false));
+    let handler = Handler {
+        downstream,
+        sleep_dropped: sleep_dropped.clone(),
+    };
+    tokio::spawn(handler.server(server));
+    (RpcClient::new(client), sleep_dropped)
+}
+
+/// downstream work started from a handler is cancelled once the inbound call ends
+#[tokio::test]
+async fn context_cancels_nested_calls() -> anyhow::Result<()> {
+    let (backend, sleep_dropped) = spawn_server(None);
+    let (frontend, _) = spawn_server(Some(backend.with_context_propagation(true)));
+
+    // the client gives up on the call, which ends the call on the frontend
+    let res = tokio::time::timeout(Duration::from_millis(50), frontend.rpc(Nested(10_000))).await;
+    assert!(res.is_err());
+    for _ in 0..100 {
+        if sleep_dropped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("downstream call was not cancelled");
+}
+
+/// calls fail once the deadline of a context started by hand passes
+#[tokio::test]
+async fn context_deadline() -> anyhow::Result<()> {
+    let (client, _) = spawn_server(None);
+    let bounded = client.clone().with_context_propagation(true);
+    let ctx = CallContext::new().with_timeout(Duration::from_millis(20));
+    let res = ctx.clone().scope(bounded.rpc(Sleep(10_000))).await;
+    assert!(matches!(
+        res,
+        Err(RpcClientError::Cancelled(CallCancelled::DeadlineExceeded))
+    ));
+    assert_eq!(ctx.remaining(), Some(Duration::ZERO));
+
+    // without propagation, calls are not bounded by the context
+    let res = ctx.scope(client.rpc(Sleep(30))).await;
+    assert!(res.is_ok());
+    Ok(())
+}
+
+/// response streams end with an error once the context is cancelled
+#[tokio::test]
+async fn context_cancels_streams() -> anyhow::Result<()> {
+    let (client, _) = spawn_server(None);
+    let client = client.with_context_propagation(true);
+    let ctx = CallContext::new();
+    let mut ticks = ctx
+        .clone()
+        .scope(client.server_streaming(Ticks(None)))
+        .await?;
+    assert!(ticks.next().await.unwrap().is_ok());
+    ctx.cancel();
+    assert!(matches!(
+        ticks.next().await,
+        Some(Err(StreamingResponseItemError::Cancelled(
+            CallCancelled::Cancelled
+        )))
+    ));
+    assert!(ticks.next().await.is_none());
+    Ok(())
+}
+
+/// the context of a client streaming call has the deadline of its timeout
+#[tokio::test]
+async fn context_server_deadline() -> anyhow::Result<()> {
+    let (client, _) = spawn_server(None);
+    let (mut send, recv) = client.client_streaming(Remaining).await?;
+    send.send(RemainingUpdate).await?;
+    drop(send);
+    let remaining = recv.await?.0.expect("deadline");
+    assert!(remaining > Duration::ZERO && remaining <= Duration::from_secs(10));
+    Ok(())
+}
+
+/// tasks spawned by a handler are cancelled once the call ends
+#[tokio::test]
+async fn context_spawn_scoped() -> anyhow::Result<()> {
+    let (client, sleep_dropped) = spawn_server(None);
+    let mut ticks = client.server_streaming(Ticks(Some(10_000))).await?;
+    assert!(ticks.next().await.unwrap().is_ok());
+    assert!(!sleep_dropped.load(Ordering::SeqCst));
+    // the client goes away, which ends the call
+    drop(ticks);
+    for _ in 0..100 {
+        if sleep_dropped.load(Ordering::SeqCst) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(sleep_dropped.load(Ordering::SeqCst));
+
+    // tasks spawned in a context that is already done do not run
+    let ctx = CallContext::new();
+    ctx.cancel();
+    let res = ctx.spawn(async { 42 }).await?;
+    assert_eq!(res, Err(CallCancelled::Cancelled));
+    Ok(())
+}
@@ -0,0 +1,247 @@
+#![cfg(all(
+    feature = "contract-testing",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+use derive_more::{From, TryInto};
+use futures::{stream, SinkExt, StreamExt, TryStreamExt};
+use quic_rpc::{
+    client::RpcClientError,
+    contract::{Contract, ContractMock, Diff, Interaction, Pattern, Violation},
+    declare_client_streaming, declare_rpc, declare_server_streaming,
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Get(String);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Value(Option<u64>);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Count(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Counted(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Add;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AddUpdate(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Total(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Get(Get),
+    Count(Count),
+    Add(Add),
+    AddUpdate(AddUpdate),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    Value(Value),
+    Counted(Counted),
+    Total(Total),
+}
+
+#[derive(Debug, Clone)]
+struct StoreService;
+
+impl Service for StoreService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_rpc!(StoreService, Get, Value);
+declare_server_streaming!(StoreService, Count, Counted);
+declare_client_streaming!(StoreService, Add, AddUpdate, Total);
+
+async fn server<C: ServiceEndpoint<StoreService>>(
+    server: RpcServer<StoreService, C>,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        match req {
+            Request::Get(msg) => {
+                chan.rpc(msg, (), |(), Get(key)| async move {
+                    Value(Some(key.len() as u64))
+                })
+                .await
+            }
+            Request::Count(msg) => {
+                chan.server_streaming(msg, (), |(), Count(n)| stream::iter((0..n).map(Counted)))
+                    .await
+            }
+            Request::Add(msg) => {
+                chan.client_streaming(msg, (), |(), _, updates| async move {
+                    Total(
+                        updates
+                            .fold(0, |total, AddUpdate(x)| async move { total + x })
+                            .await,
+                    )
+                })
+                .await
+            }
+            Request::AddUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+        }?;
+    }
+}
+
+/// the contract the server and the client below keep
+fn contract() -> Contract<StoreService> {
+    Contract::default()
+        .interaction(
+            Interaction::new("get", Pattern::Rpc, Get("abc".into())).response(Value(Some(3))),
+        )
+        .interaction(
+            Interaction::new("count", Pattern::ServerStreaming, Count(2))
+                .response(Counted(0))
+                .response(Counted(1)),
+        )
+        .interaction(
+            Interaction::new("add", Pattern::ClientStreaming, Add)
+                .update(AddUpdate(1))
+                .update(AddUpdate(2))
+                .response(Total(3)),
+        )
+}
+
+/// a client that makes the calls of the contract
+async fn client<C>(client: &RpcClient<StoreService, C>, add: &[u64]) -> anyhow::Result<()>
+where
+    C: ServiceConnection<StoreService>,
+    C::SendError: std::error::Error,
+{
+    assert_eq!(client.rpc(Get("abc".into())).await?, Value(Some(3)));
+    let counted = client
+        .server_streaming(Count(2))
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(counted, [Counted(0), Counted(1)]);
+    let (mut send, recv) = client.client_streaming(Add).await?;
+    for x in add {
+        send.send(AddUpdate(*x)).await?;
+    }
+    drop(send);
+    assert_eq!(recv.await?, Total(3));
+    Ok(())
+}
+
+/// a server that keeps the contract passes, and one that does not is reported
+#[tokio::test]
+async fn contract_verify_server() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<Request, Response>(1);
+    tokio::spawn(server(RpcServer::new(endpoint)));
+    contract().verify(&connection).await?;
+
+    let contract = Contract::<StoreService>::default()
+        .interaction(
+            Interaction::new("get", Pattern::Rpc, Get("abc".into())).response(Value(Some(4))),
+        )
+        .interaction(
+            Interaction::new("count", Pattern::ServerStreaming, Count(3))
+                .response(Counted(0))
+                .response(Counted(1)),
+        )
+        .interaction(Interaction::new("add", Pattern::ClientStreaming, Add).response(Total(0)));
+    let violations = contract.verify(&connection).await.unwrap_err().0;
+    assert_eq!(violations.len(), 2);
+    match &violations[0] {
+        Violation::Responses { interaction, diff } => {
+            assert_eq!(interaction, "get");
+            assert_eq!(
+                diff,
+                &Diff {
+                    index: 0,
+                    expected: Some(Value(Some(4)).into()),
+                    actual: Some(Value(Some(3)).into()),
+                }
+            );
+        }
+        other => panic!("unexpected violation {other:?}"),
+    }
+    match &violations[1] {
+        Violation::Responses { interaction, diff } => {
+            assert_eq!(interaction, "count");
+            assert_eq!(
+                diff,
+                &Diff {
+                    index: 2,
+                    expected: None,
+                    actual: Some(Counted(2).into()),
+                }
+            );
+        }
+        other => panic!("unexpected violation {other:?}"),
+    }
+    Ok(())
+}
+
+/// a client that keeps the contract passes against the mock
+#[tokio::test]
+async fn contract_mock_client() -> anyhow::Result<()> {
+    let mock = ContractMock::new(contract());
+    let rpc = RpcClient::<StoreService, _>::new(mock.connection());
+    client(&rpc, &[1, 2]).await?;
+    mock.verify().await?;
+    // the calls are forgotten once verified
+    let violations = mock.verify().await.unwrap_err().0;
+    assert_eq!(violations.len(), 3);
+    assert!(violations
+        .iter()
+        .all(|violation| matches!(violation, Violation::NotCalled { .. })));
+    Ok(())
+}
+
+/// a client that does not keep the contract is reported
+#[tokio::test]
+async fn contract_mock_violations() -> anyhow::Result<()> {
+    let mock = ContractMock::new(contract());
+    let rpc = RpcClient::<StoreService, _>::new(mock.connection());
+    // the calls are still answered as scripted
+    client(&rpc, &[1, 5]).await?;
+    assert!(matches!(
+        rpc.rpc(Get("other".into())).await,
+        Err(RpcClientError::EarlyClose)
+    ));
+    let violations = mock.verify().await.unwrap_err();
+    assert_eq!(violations.0.len(), 2);
+    assert!(matches!(
+        &violations.0[0],
+        Violation::Updates { interaction, diff }
+            if interaction == "add" && diff.index == 1
+    ));
+    assert!(matches!(
+        &violations.0[1],
+        Violation::UnknownRequest(Request::Get(Get(key))) if key == "other"
+    ));
+    assert_eq!(
+        violations.to_string(),
+        "2 contract violations\n  \
+         add: update message 1 is AddUpdate(AddUpdate(5)), expected AddUpdate(AddUpdate(2))\n  \
+         unknown request Get(Get(\"other\"))"
+    );
+
+    // only the calls that were made are checked
+    let connection = mock.connection();
+    let rpc = RpcClient::<StoreService, _>::new(connection);
+    rpc.rpc(Get("abc".into())).await?;
+    let violations = mock.verify().await.unwrap_err().0;
+    let not_called = violations
+        .iter()
+        .map(|violation| match violation {
+            Violation::NotCalled { interaction } => interaction.as_str(),
+            other => panic!("unexpected violation {other:?}"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(not_called, ["count", "add"]);
+    Ok(())
+}
@@ -0,0 +1,96 @@
+#![cfg(feature = "flume-transport")]
+use futures::StreamExt;
+use quic_rpc::{
+    discovery::{Discovery, DnsDiscovery, StaticDiscovery},
+    sharded::{Rebalance, ShardedClient},
+    transport::flume,
+    RpcClient, RpcServer,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod math;
+use math::*;
+
+/// Wait until `f` returns true, failing after a second
+async fn eventually(f: impl Fn() -> bool) {
+    for _ in 0..100 {
+        if f() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met");
+}
+
+#[tokio::test]
+async fn discovery_static_watch() -> anyhow::Result<()> {
+    let discovery = StaticDiscovery::new(vec![1, 2]);
+    let mut changes = discovery.watch();
+    assert_eq!(changes.next().await.unwrap()?, vec![1, 2]);
+    discovery.set(vec![2, 3]);
+    assert_eq!(changes.next().await.unwrap()?, vec![2, 3]);
+    assert_eq!(discovery.endpoints().await?, vec![2, 3]);
+
+    // the watch ends once the discovery is gone
+    drop(discovery);
+    assert!(changes.next().await.is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn discovery_dns() -> anyhow::Result<()> {
+    let discovery = DnsDiscovery::new("127.0.0.1", 4433);
+    let addr: SocketAddr = "127.0.0.1:4433".parse()?;
+    assert_eq!(discovery.endpoints().await?, vec![addr]);
+    let mut changes = discovery.with_interval(Duration::from_millis(10)).watch();
+    assert_eq!(changes.next().await.unwrap()?, vec![addr]);
+    // unchanged addresses are not reported again
+    let next = tokio::time::timeout(Duration::from_millis(100), changes.next()).await;
+    assert!(next.is_err());
+    Ok(())
+}
+
+/// shards follow the endpoints of the discovery
+#[tokio::test]
+async fn discovery_sharded_client() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let sharded = ShardedClient::new(|req: &ComputeRequest| match req {
+        ComputeRequest::Sqr(Sqr(x)) => *x,
+        _ => 0,
+    })
+    .with_shard("manual", client.clone());
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    sharded.on_rebalance(move |e| events2.lock().unwrap().push(e.clone()));
+
+    let discovery = StaticDiscovery::new(vec!["a", "b"]);
+    let task = sharded.discover(&discovery, move |_| client.clone());
+    eventually(|| sharded.shards().len() == 3).await;
+    assert_eq!(sharded.rpc(Sqr(3)).await?.0, 9);
+
+    discovery.set(vec!["b", "c"]);
+    eventually(|| !sharded.shards().contains(&"a")).await;
+    let mut shards = sharded.shards();
+    shards.sort();
+    assert_eq!(shards, vec!["b", "c", "manual"]);
+    {
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(events.contains(&Rebalance::Removed("a")));
+        assert!(events.contains(&Rebalance::Added("c")));
+    }
+
+    // the task ends with the discovery, and leaves the shards in place
+    drop(discovery);
+    task.await?;
+    assert_eq!(sharded.shards().len(), 3);
+    Ok(())
+}
@@ -0,0 +1,98 @@
+#![cfg(all(feature = "flume-transport", feature = "encryption"))]
+mod math;
+use futures::{SinkExt, StreamExt};
+use math::*;
+use quic_rpc::{
+    server::RpcServerError,
+    transport::{
+        encrypted::{
+            Encrypted, EncryptedConnection, EncryptedServerEndpoint, EncryptionConfig, RecvError,
+        },
+        flume, Connection, ServerEndpoint,
+    },
+    RpcClient, RpcServer,
+};
+
+type Server = EncryptedServerEndpoint<
+    flume::FlumeServerEndpoint<Encrypted<ComputeRequest>, Encrypted<ComputeResponse>>,
+    ComputeRequest,
+    ComputeResponse,
+>;
+
+type Client = EncryptedConnection<
+    flume::FlumeConnection<Encrypted<ComputeResponse>, Encrypted<ComputeRequest>>,
+    ComputeResponse,
+    ComputeRequest,
+>;
+
+fn encrypted_connection(server: EncryptionConfig, client: EncryptionConfig) -> (Server, Client) {
+    let (server_conn, client_conn) =
+        flume::connection::<Encrypted<ComputeRequest>, Encrypted<ComputeResponse>>(1);
+    (
+        EncryptedServerEndpoint::new(server_conn, server),
+        EncryptedConnection::new(client_conn, client),
+    )
+}
+
+/// all 4 patterns work over an encrypted connection with key rotation
+#[tokio::test]
+async fn encrypted_channel_smoke() -> anyhow::Result<()> {
+    let config = EncryptionConfig::new([1; 32]).rotate_after_messages(2);
+    let (server, client) = encrypted_connection(config.clone(), config);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(client).await?;
+
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// the receiver follows the key rotations of the sender without configuration
+#[tokio::test]
+async fn encrypted_channel_rotation() -> anyhow::Result<()> {
+    let (server, client) = encrypted_connection(
+        EncryptionConfig::new([1; 32]),
+        EncryptionConfig::new([1; 32]).rotate_after_bytes(16),
+    );
+
+    let (mut send, _recv) = client.open_bi().await?;
+    send.send(ComputeRequest::Sqr(Sqr(1))).await?;
+    let (_send, mut recv) = server.accept_bi().await?;
+    assert!(matches!(
+        recv.next().await,
+        Some(Ok(ComputeRequest::Sqr(Sqr(1))))
+    ));
+    assert_eq!(recv.epoch(), 0);
+
+    // each request is 12 bytes, so every other one is sent with the next key
+    for i in 2..=8 {
+        send.send(ComputeRequest::Sqr(Sqr(i))).await?;
+        assert!(matches!(recv.next().await, Some(Ok(ComputeRequest::Sqr(Sqr(n)))) if n == i));
+    }
+    assert_eq!(send.epoch(), 3);
+    assert_eq!(recv.epoch(), 3);
+    Ok(())
+}
+
+/// messages encrypted with a different key are rejected
+#[tokio::test]
+async fn encrypted_channel_wrong_key() -> anyhow::Result<()> {
+    let (server, client) = encrypted_connection(
+        EncryptionConfig::new([1; 32]),
+        EncryptionConfig::new([2; 32]),
+    );
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move { server.accept().await.map(|_| ()) });
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert!(client.rpc(Sqr(4)).await.is_err());
+    match server_handle.await? {
+        Err(RpcServerError::RecvError(RecvError::Decrypt)) => {}
+        e => panic!("unexpected result {e:?}"),
+    }
+    Ok(())
+}
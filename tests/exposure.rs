@@ -0,0 +1,59 @@
+#![cfg(all(
+    feature = "combined-transport",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+use futures::StreamExt;
+use quic_rpc::{
+    transport::{
+        combined::MultiServerEndpoint,
+        exposure::{ExposedServerEndpoint, Exposure},
+        flume,
+    },
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// an exposure serves the methods it lists, or all but the ones it lists
+#[test]
+fn exposure_allows() {
+    let public = Exposure::<ComputeService>::none().with::<Sqr>();
+    assert!(public.allows(&Sqr(2).into()));
+    assert!(!public.allows(&Fibonacci(2).into()));
+
+    let internal = Exposure::<ComputeService>::all().without::<Sqr>();
+    assert!(!internal.allows(&Sqr(2).into()));
+    assert!(internal.allows(&Fibonacci(2).into()));
+    assert!(internal.allows(&Multiply(2).into()));
+}
+
+/// one server serves different methods on different listeners
+#[tokio::test]
+async fn exposure_per_listener() -> anyhow::Result<()> {
+    let (admin, admin_client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let (public, public_client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let public =
+        ExposedServerEndpoint::new(public, Exposure::<ComputeService>::none().with::<Sqr>());
+    let server = MultiServerEndpoint::new()
+        .with_endpoint(admin)
+        .with_endpoint(public);
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let admin = RpcClient::<ComputeService, _>::new(admin_client);
+    assert_eq!(admin.rpc(Sqr(3)).await?, SqrResponse(9));
+    let fib = admin.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(fib.count().await, 5);
+
+    let public = RpcClient::<ComputeService, _>::new(public_client);
+    assert_eq!(public.rpc(Sqr(4)).await?, SqrResponse(16));
+    let fib = public.server_streaming(Fibonacci(5)).await?;
+    let items = fib.collect::<Vec<_>>().await;
+    assert!(items.iter().all(|item| item.is_err()), "{items:?}");
+
+    // the rejected call does not affect the other calls of the listener
+    assert_eq!(public.rpc(Sqr(5)).await?, SqrResponse(25));
+    server_handle.abort();
+    Ok(())
+}
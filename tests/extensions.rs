@@ -0,0 +1,179 @@
+#![cfg(all(
+    feature = "tcp-transport",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+use derive_more::{From, TryInto};
+use quic_rpc::{
+    context::CallContext,
+    declare_rpc,
+    extensions::Extensions,
+    server::RpcServerError,
+    transport::{
+        flume,
+        tcp::{TcpConnection, TcpServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// remember the name of the caller for later calls on the connection
+#[derive(Debug, Serialize, Deserialize)]
+struct Login(String);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct LoggedIn;
+
+/// the name the caller logged in with, and the number of calls so far
+#[derive(Debug, Serialize, Deserialize)]
+struct WhoAmI;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Me(Option<String>, u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Login(Login),
+    WhoAmI(WhoAmI),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    LoggedIn(LoggedIn),
+    Me(Me),
+}
+
+#[derive(Debug, Clone)]
+struct LoginService;
+
+impl Service for LoginService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_rpc!(LoginService, Login, LoggedIn);
+declare_rpc!(LoginService, WhoAmI, Me);
+
+#[derive(Debug, Clone)]
+struct Principal(String);
+
+#[derive(Debug, Clone, Default)]
+struct Calls(Arc<AtomicU64>);
+
+/// a server that records logins in the extensions of the connection while a
+/// call is still pending, and reads them in the handlers
+async fn server<C: ServiceEndpoint<LoginService>>(
+    server: RpcServer<LoginService, C>,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let pending = server.accept_pending().await?;
+        if let Some(extensions) = pending.extensions() {
+            let calls = extensions.get_or_insert_with(Calls::default);
+            calls.0.fetch_add(1, Ordering::SeqCst);
+            if let Request::Login(Login(name)) = pending.request() {
+                extensions.insert(Principal(name.clone()));
+            }
+        }
+        let (req, chan) = pending.accept();
+        match req {
+            Request::Login(msg) => chan.rpc(msg, (), |(), _| async move { LoggedIn }).await,
+            Request::WhoAmI(msg) => {
+                chan.rpc(msg, (), |(), _| async move {
+                    let extensions =
+                        CallContext::current().and_then(|ctx| ctx.extensions().cloned());
+                    let name = extensions
+                        .as_ref()
+                        .and_then(|extensions| extensions.get::<Principal>())
+                        .map(|principal| principal.0);
+                    let calls = extensions
+                        .and_then(|extensions| extensions.get::<Calls>())
+                        .map_or(0, |calls| calls.0.load(Ordering::SeqCst));
+                    Me(name, calls)
+                })
+                .await
+            }
+        }?;
+    }
+}
+
+/// every connection has its own extensions, shared by all of its calls
+#[tokio::test]
+async fn extensions_per_connection() -> anyhow::Result<()> {
+    let endpoint = TcpServerEndpoint::<Request, Response>::bind("127.0.0.1:0").await?;
+    let addr = match endpoint.local_addr() {
+        [LocalAddr::Socket(addr)] => *addr,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    let server_handle = tokio::spawn(server(RpcServer::new(endpoint)));
+
+    let alice =
+        RpcClient::<LoginService, _>::new(TcpConnection::<Response, Request>::connect(addr).await?);
+    let other =
+        RpcClient::<LoginService, _>::new(TcpConnection::<Response, Request>::connect(addr).await?);
+    assert_eq!(alice.rpc(WhoAmI).await?, Me(None, 1));
+    alice.rpc(Login("alice".into())).await?;
+    assert_eq!(alice.rpc(WhoAmI).await?, Me(Some("alice".into()), 3));
+    assert_eq!(other.rpc(WhoAmI).await?, Me(None, 1));
+    server_handle.abort();
+    Ok(())
+}
+
+/// transports without connections have no extensions, unless they are set on
+/// the channel by hand
+#[tokio::test]
+async fn extensions_without_connections() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<Request, Response>(1);
+    let server_handle = tokio::spawn(server(RpcServer::new(endpoint.clone())));
+    let client = RpcClient::<LoginService, _>::new(connection);
+    client.rpc(Login("alice".into())).await?;
+    assert_eq!(client.rpc(WhoAmI).await?, Me(None, 0));
+    server_handle.abort();
+
+    let extensions = Extensions::new();
+    extensions.insert(Principal("bob".into()));
+    let server = RpcServer::<LoginService, _>::new(endpoint);
+    let server_handle = tokio::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        let chan = chan.with_extensions(extensions);
+        match req {
+            Request::WhoAmI(msg) => {
+                chan.rpc(msg, (), |(), _| async move {
+                    let name = CallContext::current()
+                        .and_then(|ctx| ctx.extensions()?.get::<Principal>())
+                        .map(|principal| principal.0);
+                    Me(name, 0)
+                })
+                .await
+            }
+            _ => Err(RpcServerError::UnexpectedStartMessage),
+        }
+    });
+    assert_eq!(client.rpc(WhoAmI).await?, Me(Some("bob".into()), 0));
+    server_handle.await??;
+    Ok(())
+}
+
+/// values are keyed by their type, and clones of the map share them
+#[test]
+fn extensions_typed_map() {
+    let extensions = Extensions::new();
+    let clone = extensions.clone();
+    assert!(extensions.insert(Principal("alice".into())).is_none());
+    assert_eq!(clone.get::<Principal>().map(|p| p.0), Some("alice".into()));
+    assert!(!clone.contains::<Calls>());
+    assert_eq!(clone.insert(7u64), None);
+    assert_eq!(
+        extensions.update(|x: &mut u64| std::mem::replace(x, 8)),
+        Some(7)
+    );
+    assert_eq!(extensions.update(|x: &mut u32| *x), None);
+    assert_eq!(extensions.remove::<u64>(), Some(8));
+    assert!(!clone.contains::<u64>());
+    let old = clone.insert(Principal("bob".into()));
+    assert_eq!(old.map(|p| p.0), Some("alice".into()));
+}
@@ -0,0 +1,116 @@
+#![cfg(all(feature = "fair-sharing", feature = "flume-transport"))]
+use futures::{SinkExt, StreamExt};
+use quic_rpc::transport::{
+    fair::{FairConfig, FairConnection, FairServerEndpoint, FairShare},
+    flume, Connection, ServerEndpoint,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// a message with a bincode encoding of 1008 bytes
+fn message() -> Vec<u8> {
+    vec![0; 1000]
+}
+
+/// a share of 200 messages per second, without bursts
+fn share() -> FairShare {
+    FairShare::new(FairConfig {
+        rate: 201_600,
+        burst: 1008,
+    })
+}
+
+type Bytes = FairConnection<flume::FlumeConnection<Vec<u8>, Vec<u8>>, Vec<u8>, Vec<u8>>;
+
+/// a connection of `share` whose server discards all messages
+fn connection(share: &FairShare, weight: u32) -> Bytes {
+    let (server, client) = flume::connection::<Vec<u8>, Vec<u8>>(1);
+    tokio::spawn(async move {
+        while let Ok((_send, mut recv)) = server.accept_bi().await {
+            tokio::spawn(async move { while recv.next().await.is_some() {} });
+        }
+    });
+    FairConnection::new(client, share, weight)
+}
+
+/// while both services send, they get bandwidth by their weights
+#[tokio::test]
+async fn fair_weights() -> anyhow::Result<()> {
+    let share = share();
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let mut tasks = Vec::new();
+    for (name, weight) in [("control", 4), ("bulk", 1)] {
+        let connection = connection(&share, weight);
+        let sent = sent.clone();
+        tasks.push(tokio::spawn(async move {
+            let (mut send, _recv) = connection.open_bi().await?;
+            for _ in 0..50 {
+                send.send(message()).await?;
+                sent.lock().unwrap().push(name);
+            }
+            anyhow::Ok(())
+        }));
+    }
+    for task in tasks {
+        task.await??;
+    }
+    let sent = sent.lock().unwrap();
+    let control = sent[..25].iter().filter(|name| **name == "control").count();
+    assert!((17..=22).contains(&control), "order {sent:?}");
+    Ok(())
+}
+
+/// a service that is alone gets all of the bandwidth, but not more
+#[tokio::test]
+async fn fair_alone() -> anyhow::Result<()> {
+    let share = share();
+    let _control = connection(&share, 4);
+    let bulk = connection(&share, 1);
+    let (mut send, _recv) = bulk.open_bi().await?;
+    let started = Instant::now();
+    for _ in 0..21 {
+        send.send(message()).await?;
+    }
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(95), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(400), "{elapsed:?}");
+    Ok(())
+}
+
+/// responses of server endpoints are scheduled as well, and messages that
+/// stop waiting do not hold up the others
+#[tokio::test]
+async fn fair_server_endpoint() -> anyhow::Result<()> {
+    let share = share();
+    let (server, client) = flume::connection::<Vec<u8>, Vec<u8>>(1);
+    let server = FairServerEndpoint::new(server, &share, 1);
+    assert_eq!(server.weight(), 1);
+    let control = connection(&share, 0);
+    assert_eq!(control.weight(), 1);
+
+    let (_send, mut recv) = client.open_bi().await?;
+    let (mut response, _request) = server.accept_bi().await?;
+    let started = Instant::now();
+    let reader = tokio::spawn(async move {
+        let mut received = 0;
+        while let Some(Ok(_)) = recv.next().await {
+            received += 1;
+        }
+        received
+    });
+    for _ in 0..11 {
+        response.send(message()).await?;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(45));
+
+    // the burst is used up, so this waits until it is cancelled
+    let waiting = tokio::time::timeout(Duration::from_millis(1), response.send(message())).await;
+    assert!(waiting.is_err());
+    drop(response);
+    assert_eq!(reader.await?, 11);
+    let (mut send, _recv) = control.open_bi().await?;
+    tokio::time::timeout(Duration::from_millis(100), send.send(message())).await??;
+    Ok(())
+}
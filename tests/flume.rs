@@ -37,3 +37,725 @@ async fn flume_channel_smoke() -> anyhow::Result<()> {
     }
     Ok(())
 }
+
+/// a server driven by a stream of accepted requests ends when the client is dropped
+#[tokio::test]
+async fn flume_channel_into_stream() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let errors = AtomicUsize::new(0);
+        server
+            .into_stream()
+            .for_each_concurrent(4, |item| async {
+                match item {
+                    Ok((req, chan)) => {
+                        ComputeService::dispatch(chan, req, ComputeService)
+                            .await
+                            .ok();
+                    }
+                    Err(_) => {
+                        errors.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+            .await;
+        errors.into_inner()
+    });
+    smoke_test(client).await?;
+
+    // the stream ends after the accept error instead of yielding it forever
+    assert_eq!(server_handle.await?, 1);
+    Ok(())
+}
+
+/// a saturating bidi stream must not starve other tasks on a single threaded runtime
+#[tokio::test]
+async fn flume_channel_fairness() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server).with_yield_budget(16);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client).with_yield_budget(16);
+
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticker = tokio::task::spawn({
+        let ticks = ticks.clone();
+        async move {
+            loop {
+                ticks.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+
+    let n = 10000u64;
+    let (mut send, mut recv) = client.bidi(Multiply(2)).await?;
+    let updates = tokio::task::spawn(async move {
+        for i in 0..n {
+            send.send(MultiplyUpdate(i)).await?;
+        }
+        anyhow::Ok(())
+    });
+    let mut count = 0;
+    while let Some(item) = recv.next().await {
+        item?;
+        count += 1;
+    }
+    updates.await??;
+    assert_eq!(count, n);
+    // the ticker must have been able to run repeatedly while the stream was busy
+    assert!(ticks.load(Ordering::SeqCst) > (n as usize) / 16);
+    ticker.abort();
+
+    drop(client);
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// middlewares are applied to every item of the response stream of their message type
+#[tokio::test]
+async fn flume_stream_middleware() -> anyhow::Result<()> {
+    use futures::TryStreamExt;
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client)
+        .with_server_streaming_middleware::<Fibonacci, _, _>(|| {
+            let mut index = 0;
+            move |FibonacciResponse(x)| {
+                index += 1;
+                FibonacciResponse(x * 1000 + index)
+            }
+        });
+
+    for _ in 0..2 {
+        let res = client
+            .server_streaming(Fibonacci(5))
+            .await?
+            .map_ok(|x| x.0)
+            .try_collect::<Vec<_>>()
+            .await?;
+        // state is per call, so the index starts at 1 for every call
+        assert_eq!(res, vec![1, 1002, 1003, 2004, 3005]);
+    }
+
+    drop(client);
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// high priority calls are admitted before queued low priority calls, unless
+/// the low priority calls have waited for long enough
+#[tokio::test]
+async fn flume_priority_connection() -> anyhow::Result<()> {
+    use quic_rpc::transport::priority::{Priority, PriorityConfig, PriorityConnection};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+
+    /// occupy the only slot, queue a low and then a high priority call, then free the slot
+    async fn admission_order(
+        client: &flume::FlumeConnection<ComputeResponse, ComputeRequest>,
+        aging_step: Duration,
+    ) -> anyhow::Result<Vec<Priority>> {
+        let config = PriorityConfig {
+            max_concurrent: 1,
+            aging_step,
+        };
+        let conn = PriorityConnection::new(client.clone(), config);
+        let blocker = RpcClient::<ComputeService, _>::new(conn.clone());
+        let (send, recv) = blocker.bidi(Multiply(2)).await?;
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for priority in [Priority::Low, Priority::High] {
+            let client = RpcClient::<ComputeService, _>::new(conn.with_priority(priority));
+            let order = order.clone();
+            tasks.push(tokio::spawn(async move {
+                let res = client.rpc(Sqr(2)).await;
+                order.lock().unwrap().push(priority);
+                res
+            }));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        drop((send, recv));
+        for task in tasks {
+            task.await??;
+        }
+        let order = order.lock().unwrap().clone();
+        Ok(order)
+    }
+
+    let order = admission_order(&client, Duration::from_secs(1)).await?;
+    assert_eq!(order, vec![Priority::High, Priority::Low]);
+    // with a short aging step the low priority call has waited long enough to go first
+    let order = admission_order(&client, Duration::from_millis(1)).await?;
+    assert_eq!(order, vec![Priority::Low, Priority::High]);
+
+    drop(client);
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// a priority connection that could never open a substream is rejected
+#[test]
+#[should_panic(expected = "max_concurrent must be at least 1")]
+fn flume_priority_connection_zero() {
+    use quic_rpc::transport::priority::{PriorityConfig, PriorityConnection};
+    let (_, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let config = PriorityConfig {
+        max_concurrent: 0,
+        ..Default::default()
+    };
+    PriorityConnection::new(client, config);
+}
+
+/// calls are routed to the shard owning the key, and only keys of a removed shard move
+#[tokio::test]
+async fn flume_sharded_client() -> anyhow::Result<()> {
+    use quic_rpc::sharded::{Rebalance, ShardError, ShardedClient};
+    use std::sync::{Arc, Mutex};
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, live) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    // the server for this shard is gone, so every call routed to it fails
+    let (_, dead) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let sharded = ShardedClient::new(|req: &ComputeRequest| match req {
+        ComputeRequest::Sqr(Sqr(x)) => *x,
+        _ => 0,
+    });
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+    sharded.on_rebalance(move |e| events2.lock().unwrap().push(e.clone()));
+    assert!(matches!(
+        sharded.rpc(Sqr(1)).await,
+        Err(ShardError::NoShards)
+    ));
+
+    let sharded = sharded
+        .with_shard("live", RpcClient::new(live))
+        .with_shard("dead", RpcClient::new(dead))
+        .with_shard("other", RpcClient::new(flume::connection(1).1));
+    let owners = (0..100u64)
+        .map(|x| (x, sharded.owner(&x).unwrap()))
+        .collect::<Vec<_>>();
+    assert!(owners.iter().any(|(_, o)| *o == "live"));
+    for (x, owner) in &owners {
+        let res = sharded.rpc(Sqr(*x)).await;
+        assert_eq!(res.is_ok(), *owner == "live");
+        if let Ok(SqrResponse(y)) = res {
+            assert_eq!(y, (*x as u128) * (*x as u128));
+        }
+    }
+
+    sharded.remove_shard(&"other");
+    for (x, owner) in &owners {
+        if *owner != "other" {
+            assert_eq!(sharded.owner(x).as_ref(), Some(owner));
+        }
+    }
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            Rebalance::Added("live"),
+            Rebalance::Added("dead"),
+            Rebalance::Added("other"),
+            Rebalance::Removed("other"),
+        ]
+    );
+
+    drop(sharded);
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// key ownership does not depend on the toolchain or platform the client was built with
+#[test]
+fn sharded_owner_is_stable() {
+    use quic_rpc::{sharded::ShardedClient, transport::flume::FlumeConnection};
+    fn client() -> RpcClient<ComputeService, FlumeConnection<ComputeResponse, ComputeRequest>> {
+        RpcClient::new(flume::connection(1).1)
+    }
+    let sharded = ShardedClient::new(|req: &ComputeRequest| match req {
+        ComputeRequest::Sqr(Sqr(x)) => *x,
+        _ => 0,
+    })
+    .with_shard("a", client())
+    .with_shard("b", client())
+    .with_shard("c", client());
+    let owners = (0..8u64)
+        .map(|x| sharded.owner(&x).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(owners, ["c", "a", "c", "b", "a", "a", "c", "b"]);
+}
+
+/// generic code can name the server type without requiring a service
+#[test]
+fn flume_server_type_unbounded() {
+    struct Holder<S, C>(Option<RpcServer<S, C>>);
+    assert!(Holder::<(), ()>(None).0.is_none());
+}
+
+/// when a handler is dropped before responding, the client gets the configured response
+#[tokio::test]
+async fn flume_handler_dropped() -> anyhow::Result<()> {
+    use quic_rpc::server::HandlerDropped;
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_handler_dropped_response(
+        |info: &HandlerDropped| match info.method {
+            "Sqr" => Some(SqrResponse(u128::MAX).into()),
+            _ => None,
+        },
+    );
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let call = tokio::spawn(async move { client.rpc(Sqr(2)).await });
+
+    let (req, chan) = server.accept().await?;
+    let req = match req {
+        ComputeRequest::Sqr(req) => req,
+        req => panic!("unexpected request {req:?}"),
+    };
+    // a handler that never responds, in a task that gets aborted
+    let handler = tokio::spawn(chan.rpc(req, (), |_, _| futures::future::pending()));
+    tokio::task::yield_now().await;
+    handler.abort();
+
+    let res = call.await??;
+    assert_eq!(res.0, u128::MAX);
+    Ok(())
+}
+
+/// calls on an ordering lane are accepted in the order they were issued
+#[tokio::test]
+async fn flume_ordered_lane() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        ordered::{Ordered, OrderedConfig, OrderedConnection, OrderedServerEndpoint},
+        Connection,
+    };
+    use std::time::Duration;
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<Ordered<ComputeRequest>, ComputeResponse>(16);
+    let config = OrderedConfig {
+        gap_timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let server = RpcServer::<ComputeService, _>::new(OrderedServerEndpoint::new(server, config));
+    let accepted = tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let (req, _chan) = server.accept().await?;
+            match req {
+                ComputeRequest::Sqr(Sqr(x)) => accepted.push(x),
+                req => panic!("unexpected request {req:?}"),
+            }
+        }
+        anyhow::Ok(accepted)
+    });
+
+    let conn = OrderedConnection::new(client);
+    // issue the calls in order, but open and send them in reverse order
+    let calls = vec![conn.open_bi(), conn.open_bi()];
+    let mut channels = Vec::new();
+    for (i, call) in calls.into_iter().enumerate().rev() {
+        let (mut send, recv) = call.await?;
+        send.send(Sqr(i as u64).into()).await?;
+        channels.push((send, recv));
+    }
+    // a call that is issued but never sent is skipped after the gap timeout
+    drop(conn.open_bi());
+    let (mut send, recv) = conn.open_bi().await?;
+    send.send(Sqr(3).into()).await?;
+    channels.push((send, recv));
+
+    assert_eq!(accepted.await??, vec![0, 1, 3]);
+    Ok(())
+}
+
+/// calls on new lanes are not held back when the lane limit is reached
+#[tokio::test]
+async fn flume_ordered_max_lanes() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        ordered::{Ordered, OrderedConfig, OrderedConnection, OrderedServerEndpoint},
+        Connection,
+    };
+    use std::time::Duration;
+    let (server, client) = flume::connection::<Ordered<ComputeRequest>, ComputeResponse>(16);
+    let config = OrderedConfig {
+        gap_timeout: Duration::from_secs(10),
+        max_lanes: 1,
+    };
+    let server = RpcServer::<ComputeService, _>::new(OrderedServerEndpoint::new(server, config));
+    let accepted = tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        for _ in 0..4 {
+            let (req, _chan) = server.accept().await?;
+            match req {
+                ComputeRequest::Sqr(Sqr(x)) => accepted.push(x),
+                req => panic!("unexpected request {req:?}"),
+            }
+        }
+        anyhow::Ok(accepted)
+    });
+
+    let a = OrderedConnection::new(client);
+    let b = a.new_lane();
+    let c = a.new_lane();
+    let mut channels = Vec::new();
+    let first = a.open_bi();
+    // waits for the first call on the only lane that is tracked
+    let (mut send, recv) = a.open_bi().await?;
+    send.send(Sqr(1).into()).await?;
+    channels.push((send, recv));
+    // the only lane has a waiting call, so this one is accepted right away
+    let (mut send, recv) = b.open_bi().await?;
+    send.send(Sqr(2).into()).await?;
+    channels.push((send, recv));
+    let (mut send, recv) = first.await?;
+    send.send(Sqr(0).into()).await?;
+    channels.push((send, recv));
+    // the first lane has no waiting calls anymore, so it makes room for this one
+    let (mut send, recv) = c.open_bi().await?;
+    send.send(Sqr(3).into()).await?;
+    channels.push((send, recv));
+
+    assert_eq!(accepted.await??, vec![2, 0, 1, 3]);
+    Ok(())
+}
+
+/// client streaming calls that exceed their time limits are aborted on both sides
+#[tokio::test]
+async fn flume_client_streaming_timeouts() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::server::{CallTimeout, StreamingTimeouts, TimeoutKind};
+    use std::time::Duration;
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_streaming_timeouts(StreamingTimeouts {
+            max_duration: Some(Duration::from_millis(200)),
+            max_update_gap: None,
+        })
+        .with_method_timeouts::<Sum>(StreamingTimeouts {
+            max_duration: None,
+            max_update_gap: Some(Duration::from_millis(50)),
+        })
+        .with_timeout_response(|info: &CallTimeout| match info.method {
+            "Sum" => Some(SumResponse(u128::MAX).into()),
+            _ => None,
+        });
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    // the update gap of Sum overrides the default duration
+    let (mut send, res) = client.client_streaming(Sum).await?;
+    let (req, chan) = server.accept().await?;
+    send.send(SumUpdate(1)).await?;
+    match ComputeService::dispatch(chan, req, ComputeService).await {
+        Err(RpcServerError::Timeout(CallTimeout {
+            method: "Sum",
+            kind: TimeoutKind::UpdateGap,
+            ..
+        })) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+    assert_eq!(res.await?, SumResponse(u128::MAX));
+
+    // a client that keeps sending updates still hits the maximum duration
+    let (mut send, res) = client.client_streaming(Sum).await?;
+    let (req, chan) = server.accept().await?;
+    let chan = chan.with_method_timeouts::<Sum>(StreamingTimeouts {
+        max_duration: Some(Duration::from_millis(100)),
+        max_update_gap: Some(Duration::from_millis(50)),
+    });
+    let sender = tokio::spawn(async move {
+        while send.send(SumUpdate(1)).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    });
+    match ComputeService::dispatch(chan, req, ComputeService).await {
+        Err(RpcServerError::Timeout(CallTimeout {
+            kind: TimeoutKind::Duration,
+            ..
+        })) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+    assert_eq!(res.await?, SumResponse(u128::MAX));
+    sender.abort();
+    Ok(())
+}
+
+/// requests can be rejected before a handler is set up
+#[tokio::test]
+async fn flume_channel_two_phase_accept() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use quic_rpc::client::RpcClientError;
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let mut rejected = 0;
+        loop {
+            let pending = server.accept_pending().await?;
+            match pending.request() {
+                // refuse large squares with a response the client understands
+                ComputeRequest::Sqr(Sqr(n)) if *n > 1000 => {
+                    pending.reject_with(SqrResponse(0)).await?;
+                    rejected += 1;
+                }
+                ComputeRequest::Fibonacci(_) => {
+                    pending.reject();
+                    rejected += 1;
+                }
+                _ => {
+                    let (req, chan) = pending.accept();
+                    ComputeService::dispatch(chan, req, ComputeService).await?;
+                }
+            }
+            if rejected == 2 {
+                break anyhow::Ok(());
+            }
+        }
+    });
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    assert_eq!(client.rpc(Sqr(1001)).await?.0, 0);
+    let items = client.server_streaming(Fibonacci(10)).await?;
+    assert_eq!(items.count().await, 0);
+    server_handle.await??;
+    // the server is gone
+    assert!(matches!(
+        client.rpc(Sqr(3)).await,
+        Err(RpcClientError::Open(_))
+    ));
+    Ok(())
+}
+
+/// Compile time check that a transport is encrypted
+struct Encrypted<C>(C);
+
+impl<C: quic_rpc::transport::ConnectionCommon<ComputeResponse, ComputeRequest>> Encrypted<C> {
+    const CHECK: () = assert!(C::CAPABILITIES.encrypted, "transport must be encrypted");
+
+    fn new(inner: C) -> Self {
+        let () = Self::CHECK;
+        Self(inner)
+    }
+}
+
+#[test]
+fn flume_capabilities() {
+    use quic_rpc::transport::{priority::PriorityConnection, Capabilities, ConnectionCommon};
+    type Conn = flume::FlumeConnection<ComputeResponse, ComputeRequest>;
+    type Prio = PriorityConnection<Conn, ComputeResponse, ComputeRequest>;
+    assert_eq!(
+        <Conn as ConnectionCommon<ComputeResponse, ComputeRequest>>::CAPABILITIES,
+        Capabilities::ALL
+    );
+    // wrappers keep the capabilities of the inner transport
+    assert_eq!(
+        <Prio as ConnectionCommon<ComputeResponse, ComputeRequest>>::CAPABILITIES,
+        Capabilities::ALL
+    );
+    let (_server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let Encrypted(_client) = Encrypted::new(client);
+
+    let unordered = Capabilities {
+        ordered: false,
+        ..Capabilities::ALL
+    };
+    assert!(Capabilities::ALL.contains(unordered));
+    assert!(!unordered.contains(Capabilities::ALL));
+    assert_eq!(unordered.and(Capabilities::NONE), Capabilities::NONE);
+}
+
+/// Admission order of calls that wait while another call is in flight
+async fn admission_order(
+    policy: quic_rpc::transport::admission::AdmissionPolicy,
+    calls: Vec<(quic_rpc::transport::priority::Priority, u64)>,
+) -> anyhow::Result<Vec<u64>> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        admission::{AdmissionConfig, AdmissionServerEndpoint, Prioritized, PrioritizedConnection},
+        priority::Priority,
+        Connection,
+    };
+    use std::time::Duration;
+    let (server, client) = flume::connection::<Prioritized<ComputeRequest>, ComputeResponse>(16);
+    let config = AdmissionConfig {
+        max_concurrent: 1,
+        policy,
+    };
+    let server = RpcServer::<ComputeService, _>::new(AdmissionServerEndpoint::new(server, config));
+    let conn = PrioritizedConnection::new(client);
+
+    // a bulk call that keeps the server busy
+    let (mut send, _recv) = conn.with_priority(Priority::Low).open_bi().await?;
+    send.send(Sqr(0).into()).await?;
+    let (_, busy) = server.accept().await?;
+
+    let n = calls.len();
+    let mut channels = Vec::new();
+    for (priority, x) in calls {
+        let (mut send, recv) = conn.with_priority(priority).open_bi().await?;
+        send.send(Sqr(x).into()).await?;
+        channels.push((send, recv));
+    }
+    let accepted = tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        for _ in 0..n {
+            // dropping the channel right away admits the next call
+            match server.accept().await?.0 {
+                ComputeRequest::Sqr(Sqr(x)) => accepted.push(x),
+                req => panic!("unexpected request {req:?}"),
+            }
+        }
+        anyhow::Ok(accepted)
+    });
+    // give the server time to queue all calls, then finish the busy call
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(busy);
+    accepted.await?
+}
+
+/// waiting calls are admitted by priority
+#[tokio::test]
+async fn flume_admission_priority() -> anyhow::Result<()> {
+    use quic_rpc::transport::{admission::AdmissionPolicy, priority::Priority};
+    let calls = vec![
+        (Priority::Low, 1),
+        (Priority::Low, 2),
+        (Priority::Normal, 3),
+        (Priority::High, 4),
+    ];
+    let order = admission_order(AdmissionPolicy::Strict, calls).await?;
+    assert_eq!(order, vec![4, 3, 1, 2]);
+
+    let calls = vec![
+        (Priority::Low, 1),
+        (Priority::Low, 2),
+        (Priority::Low, 3),
+        (Priority::High, 4),
+        (Priority::High, 5),
+        (Priority::High, 6),
+    ];
+    let policy = AdmissionPolicy::Weighted {
+        high: 2,
+        normal: 1,
+        low: 1,
+    };
+    let order = admission_order(policy, calls).await?;
+    assert_eq!(order, vec![4, 1, 5, 6, 2, 3]);
+    Ok(())
+}
+
+/// latency critical calls are admitted while other calls wait for admission
+#[tokio::test]
+async fn flume_admission_latency_critical() -> anyhow::Result<()> {
+    use futures::SinkExt;
+    use quic_rpc::transport::{
+        admission::{AdmissionConfig, AdmissionServerEndpoint, Prioritized, PrioritizedConnection},
+        latency::LatencyCritical,
+        priority::Priority,
+        Connection,
+    };
+    use std::time::Duration;
+    let (server, client) = flume::connection::<Prioritized<ComputeRequest>, ComputeResponse>(16);
+    let config = AdmissionConfig {
+        max_concurrent: 1,
+        ..Default::default()
+    };
+    let endpoint = AdmissionServerEndpoint::new(server, config)
+        .with_latency_critical(LatencyCritical::new().with::<Fibonacci>());
+    let server = RpcServer::<ComputeService, _>::new(endpoint.clone());
+    let conn = PrioritizedConnection::new(client);
+
+    // a call that keeps the server busy, and one that waits behind it
+    let (mut send, _recv) = conn.open_bi().await?;
+    send.send(Sqr(0).into()).await?;
+    let (_, busy) = server.accept().await?;
+    let (mut waiting, _recv) = conn.with_priority(Priority::High).open_bi().await?;
+    waiting.send(Sqr(1).into()).await?;
+
+    let (mut send, _recv) = conn.with_priority(Priority::Low).open_bi().await?;
+    send.send(Fibonacci(3).into()).await?;
+    let (req, critical) = tokio::time::timeout(Duration::from_secs(1), server.accept()).await??;
+    assert!(matches!(req, ComputeRequest::Fibonacci(Fibonacci(3))));
+    assert_eq!(endpoint.in_flight(), 1);
+    drop(critical);
+
+    // the waiting call is still admitted once the busy call is done
+    drop(busy);
+    let (req, _) = tokio::time::timeout(Duration::from_secs(1), server.accept()).await??;
+    assert!(matches!(req, ComputeRequest::Sqr(Sqr(1))));
+    Ok(())
+}
+
+/// connections with latency delay requests and responses, without reordering them
+#[tokio::test]
+async fn flume_latency() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use quic_rpc::transport::flume::Latency;
+    use std::time::{Duration, Instant};
+
+    let latency = Latency {
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        seed: 42,
+    };
+    let (server, client) =
+        flume::connection_with_latency::<ComputeRequest, ComputeResponse>(1, latency);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let t0 = Instant::now();
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    let elapsed = t0.elapsed();
+    assert!(elapsed >= Duration::from_millis(40), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+
+    let items = client
+        .server_streaming(Fibonacci(10))
+        .await?
+        .map(|res| res.unwrap().0)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(items, vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,106 @@
+#![cfg(all(feature = "http1-transport", feature = "macros"))]
+use std::{net::SocketAddr, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Client, Request, StatusCode, Uri};
+use quic_rpc::{
+    transport::http1::{Http1Connection, Http1ServerEndpoint, LongPollConfig},
+    transport::{Connection, LocalAddr, ServerEndpoint},
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// polls that return after 100ms, and sessions that are dropped after 300ms
+fn config() -> LongPollConfig {
+    LongPollConfig {
+        poll_timeout: Duration::from_millis(100),
+        idle_timeout: Duration::from_millis(300),
+        ..Default::default()
+    }
+}
+
+/// A server endpoint on a free port, and the uri to reach it
+fn server<In: quic_rpc::RpcMessage, Out: quic_rpc::RpcMessage>(
+) -> anyhow::Result<(Http1ServerEndpoint<In, Out>, Uri)> {
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let server = Http1ServerEndpoint::serve_with_config(&addr, config())?;
+    let uri = match server.local_addr() {
+        [LocalAddr::Socket(addr)] => format!("http://{addr}/rpc").parse()?,
+        other => anyhow::bail!("unexpected local address {other:?}"),
+    };
+    Ok((server, uri))
+}
+
+/// all 4 patterns work over long polls
+#[tokio::test]
+async fn http1_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, uri) = server::<ComputeRequest, ComputeResponse>()?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let client = Http1Connection::<ComputeResponse, ComputeRequest>::with_config(uri, config());
+    smoke_test(client.clone()).await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    server_handle.abort();
+    Ok(())
+}
+
+/// messages arrive after polls that timed out, and either side can end its side
+#[tokio::test]
+async fn http1_long_poll() -> anyhow::Result<()> {
+    let (server, uri) = server::<u64, u64>()?;
+    let client = Http1Connection::<u64, u64>::with_config(uri, config());
+    let (mut client_send, mut client_recv) = client.open_bi().await?;
+    let (mut server_send, mut server_recv) = server.accept_bi().await?;
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    server_send.send(1).await?;
+    assert_eq!(client_recv.next().await.transpose()?, Some(1));
+    client_send.send(2).await?;
+    client_send.send(3).await?;
+    assert_eq!(server_recv.next().await.transpose()?, Some(2));
+    assert_eq!(server_recv.next().await.transpose()?, Some(3));
+
+    drop(client_send);
+    assert!(server_recv.next().await.is_none());
+    server_send.send(4).await?;
+    drop(server_send);
+    assert_eq!(client_recv.next().await.transpose()?, Some(4));
+    assert!(client_recv.next().await.is_none());
+    Ok(())
+}
+
+/// sessions of clients that went away are dropped
+#[tokio::test]
+async fn http1_idle_session() -> anyhow::Result<()> {
+    let (server, uri) = server::<u64, u64>()?;
+    // a client that opens a session, and never comes back
+    let res = Client::new()
+        .request(Request::post(uri).body(Body::empty())?)
+        .await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let (_send, mut recv) = server.accept_bi().await?;
+    let ended = tokio::time::timeout(Duration::from_secs(2), recv.next()).await?;
+    assert!(ended.is_none());
+    Ok(())
+}
+
+/// a combined connection falls back to long polls when the first connection fails
+#[cfg(all(feature = "combined-transport", feature = "flume-transport"))]
+#[tokio::test]
+async fn http1_fallback() -> anyhow::Result<()> {
+    use quic_rpc::transport::{combined::CombinedConnection, flume};
+
+    let (server, uri) = server::<ComputeRequest, ComputeResponse>()?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let (unreachable, first) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    drop(unreachable);
+    let second = Http1Connection::<ComputeResponse, ComputeRequest>::with_config(uri, config());
+    let client = CombinedConnection::new(Some(first), Some(second)).with_fallback(true);
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(4)).await?.0, 16);
+    server_handle.abort();
+    Ok(())
+}
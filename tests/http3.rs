@@ -0,0 +1,147 @@
+#![cfg(all(feature = "http3-transport", feature = "macros"))]
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use quic_rpc::{
+    transport::{
+        http3::{Http3Connection, Http3ServerEndpoint},
+        quinn::H3_ALPN,
+    },
+    RpcClient, RpcServer,
+};
+use quinn::{ClientConfig, Endpoint, ServerConfig};
+
+mod math;
+use math::*;
+
+/// An HTTP/3 server endpoint on a free port, and its certificate
+fn server_endpoint() -> anyhow::Result<(Endpoint, Vec<u8>)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+    Ok((server, cert_der))
+}
+
+/// An HTTP/3 connection to `addr`, trusting `cert`
+async fn connect(addr: SocketAddr, cert: &[u8]) -> anyhow::Result<quinn::Connection> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(cert.to_vec()))?;
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![H3_ALPN.to_vec()];
+    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+    Ok(endpoint.connect(addr, "localhost")?.await?)
+}
+
+/// all 4 patterns work with calls made as HTTP/3 requests
+#[tokio::test]
+async fn http3_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, cert) = server_endpoint()?;
+    let addr = server.local_addr()?;
+    let server = Http3ServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let connection = connect(addr, &cert).await?;
+    let client = Http3Connection::<ComputeResponse, ComputeRequest>::connect(
+        connection,
+        "localhost",
+        "/rpc",
+    )
+    .await?;
+    smoke_test(client.clone()).await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    server_handle.abort();
+    Ok(())
+}
+
+/// requests that are not calls are answered without reaching the server
+#[tokio::test]
+async fn http3_other_requests() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, cert) = server_endpoint()?;
+    let addr = server.local_addr()?;
+    let server = Http3ServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let connection = connect(addr, &cert).await?;
+    let (mut driver, mut requests) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+    tokio::spawn(async move { futures::future::poll_fn(|cx| driver.poll_close(cx)).await });
+    let request = http::Request::get("https://localhost/rpc").body(())?;
+    let mut stream = requests.send_request(request).await?;
+    stream.finish().await?;
+    let response = stream.recv_response().await?;
+    assert_eq!(response.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+    let request = http::Request::post("https://localhost/rpc")
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(())?;
+    let mut stream = requests.send_request(request).await?;
+    stream.finish().await?;
+    let response = stream.recv_response().await?;
+    assert_eq!(response.status(), http::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    // the server keeps accepting calls
+    let client = Http3Connection::<ComputeResponse, ComputeRequest>::connect(
+        connect(addr, &cert).await?,
+        "localhost",
+        "/rpc",
+    )
+    .await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(4)).await?.0, 16);
+    server_handle.abort();
+    Ok(())
+}
+
+/// responses with a status other than 200 fail the call
+#[tokio::test]
+async fn http3_refused() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, cert) = server_endpoint()?;
+    let addr = server.local_addr()?;
+    // a proxy that can not reach the server
+    tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await?;
+        let mut connection =
+            h3::server::Connection::<_, bytes::Bytes>::new(h3_quinn::Connection::new(connection))
+                .await?;
+        while let Some((_, mut stream)) = connection.accept().await? {
+            let response = http::Response::builder()
+                .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                .body(())?;
+            stream.send_response(response).await?;
+            stream.finish().await?;
+        }
+        anyhow::Ok(())
+    });
+
+    let connection = connect(addr, &cert).await?;
+    let client = Http3Connection::<ComputeResponse, ComputeRequest>::connect(
+        connection,
+        "localhost",
+        "/rpc",
+    )
+    .await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let err = client.rpc(Sqr(4)).await.unwrap_err();
+    assert!(
+        err.to_string().contains("status 503"),
+        "unexpected error {err}"
+    );
+    Ok(())
+}
@@ -8,7 +8,10 @@ use quic_rpc::{
     client::RpcClientError,
     declare_rpc,
     server::RpcServerError,
-    transport::hyper::{self, HyperConnection, HyperServerEndpoint, RecvError},
+    transport::{
+        hyper::{self, HyperConnection, HyperServerEndpoint, RecvError},
+        Direction,
+    },
     RpcClient, RpcServer, Service,
 };
 use serde::{Deserialize, Serialize};
@@ -217,9 +220,9 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
     let client = RpcClient::<TestService, _>::new(client);
 
     macro_rules! assert_matches {
-        ($e:expr, $p:pat) => {
+        ($e:expr, $p:pat $(if $guard:expr)?) => {
             assert!(
-                matches!($e, $p),
+                matches!($e, $p $(if $guard)?),
                 "expected {} to match {}",
                 stringify!($e),
                 stringify!($p)
@@ -227,10 +230,10 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
         };
     }
     macro_rules! assert_server_result {
-        ($p:pat) => {
+        ($p:pat $(if $guard:expr)?) => {
             let server_result = server_results.recv_async().await.unwrap();
             assert!(
-                matches!(server_result, $p),
+                matches!(server_result, $p $(if $guard)?),
                 "expected server result to match {}",
                 stringify!($p)
             );
@@ -263,8 +266,10 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
     let res = client.rpc(NoDeserRequest(NoDeser)).await;
     assert_matches!(res, Err(RpcClientError::EarlyClose));
     assert_server_result!(Err(RpcServerError::RecvError(
-        hyper::RecvError::DeserializeError(_)
-    )));
+        hyper::RecvError::DeserializeError(e)
+    )) if e.direction == Direction::Request
+        && e.variant == Some("NoDeserRequest")
+        && e.offset == 4);
 
     // response not serializable - should fail on the server side
     let res = client.rpc(NoSerResponseRequest).await;
@@ -277,7 +282,8 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
     let res = client.rpc(NoDeserResponseRequest).await;
     assert_matches!(
         res,
-        Err(RpcClientError::RecvError(RecvError::DeserializeError(_)))
+        Err(RpcClientError::RecvError(RecvError::DeserializeError(e)))
+            if e.direction == Direction::Response && e.variant == Some("NoDeser")
     );
     assert_server_result!(Ok(()));
 
@@ -298,3 +304,125 @@ async fn hyper_channel_errors() -> anyhow::Result<()> {
     let _ = server_handle.await;
     Ok(())
 }
+
+#[cfg(feature = "openmetrics")]
+#[tokio::test]
+async fn hyper_channel_metrics() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3003".parse()?;
+    let uri: Uri = "http://127.0.0.1:3003".parse()?;
+    let config = hyper::ChannelConfig::default().serve_metrics(true);
+    let channel =
+        HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve_with_config(&addr, config)?;
+    let server = RpcServer::<ComputeService, _>::new(channel);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+
+    // rpc calls still work over http2
+    let client = RpcClient::<ComputeService, _>::new(HyperConnection::new(uri.clone()));
+    assert_eq!(client.rpc(Sqr(3)).await?, SqrResponse(9));
+
+    // metrics are served over http1 on the same listener
+    let scraper = ::hyper::Client::new();
+    let res = scraper.get(format!("{uri}metrics").parse()?).await?;
+    assert_eq!(res.status(), ::hyper::StatusCode::OK);
+    assert_eq!(
+        res.headers()[::hyper::header::CONTENT_TYPE],
+        quic_rpc::metrics::CONTENT_TYPE
+    );
+    let body = ::hyper::body::to_bytes(res.into_body()).await?;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body.contains("# TYPE rpc_server_duration_seconds histogram"));
+    // the client records the call before it returns, the server only after responding
+    assert!(body.lines().any(
+        |line| line.starts_with("rpc_client_duration_seconds_count{")
+            && line.contains("rpc_method=\"Sqr\"")
+    ));
+    assert!(body.ends_with("# EOF\n"));
+
+    // but rpc calls are not
+    let res = scraper.get(uri).await?;
+    assert_eq!(
+        res.status(),
+        ::hyper::StatusCode::HTTP_VERSION_NOT_SUPPORTED
+    );
+
+    server_handle.abort();
+    let _ = server_handle.await;
+    Ok(())
+}
+
+#[cfg(feature = "payload-sampling")]
+#[tokio::test]
+async fn hyper_channel_samples() -> anyhow::Result<()> {
+    let addr: SocketAddr = "127.0.0.1:3004".parse()?;
+    let uri: Uri = "http://127.0.0.1:3004".parse()?;
+    let config = hyper::ChannelConfig::default().serve_samples(true);
+    let channel =
+        HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve_with_config(&addr, config)?;
+    let server = RpcServer::<ComputeService, _>::new(channel);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+
+    quic_rpc::sampling::configure(1.0, 16);
+    let client = RpcClient::<ComputeService, _>::new(HyperConnection::new(uri.clone()));
+    assert_eq!(client.rpc(Sqr(3)).await?, SqrResponse(9));
+
+    // samples are served over http1 on the same listener
+    let res = ::hyper::Client::new()
+        .get(format!("{uri}debug/samples").parse()?)
+        .await?;
+    assert_eq!(res.status(), ::hyper::StatusCode::OK);
+    let body = ::hyper::body::to_bytes(res.into_body()).await?;
+    let body = std::str::from_utf8(&body)?;
+    assert!(body
+        .lines()
+        .any(|line| line.starts_with("sample ") && line.contains(" Sqr ")));
+
+    // don't sample the calls of other tests
+    quic_rpc::sampling::configure(0.0, 16);
+    server_handle.abort();
+    let _ = server_handle.await;
+    Ok(())
+}
+
+/// with the json codec, the messages are lines of json that can be written by hand
+#[cfg(feature = "json-codec")]
+#[tokio::test]
+async fn hyper_channel_json() -> anyhow::Result<()> {
+    use quic_rpc::transport::Codec;
+
+    let addr: SocketAddr = "127.0.0.1:3005".parse()?;
+    let uri: Uri = "http://127.0.0.1:3005".parse()?;
+    let config = hyper::ChannelConfig::default().codec(Codec::Json);
+    let channel = HyperServerEndpoint::<ComputeRequest, ComputeResponse>::serve_with_config(
+        &addr,
+        config.clone(),
+    )?;
+    let server = RpcServer::<ComputeService, _>::new(channel);
+    let server_handle = tokio::spawn(async move {
+        loop {
+            ComputeService::server(server.clone()).await?;
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+
+    let client = HyperConnection::with_config(uri.clone(), config);
+    smoke_test(client).await?;
+
+    // a request written by hand, on a body that stays open until the response
+    // arrived, since the server takes the end of the requests as a cancellation
+    let raw = ::hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<::hyper::Body>();
+    let (mut body_tx, body) = ::hyper::Body::channel();
+    let res = tokio::spawn(raw.request(::hyper::Request::post(uri).body(body)?));
+    body_tx.send_data("\n{\"Sqr\":4}\n".into()).await?;
+    let res = res.await??;
+    assert_eq!(res.status(), ::hyper::StatusCode::OK);
+    let body = ::hyper::body::to_bytes(res.into_body()).await?;
+    assert_eq!(std::str::from_utf8(&body)?, "{\"SqrResponse\":16}\n");
+    drop(body_tx);
+
+    server_handle.abort();
+    let _ = server_handle.await;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+#![cfg(all(feature = "stream-transport", feature = "macros"))]
+use derive_more::{From, TryInto};
+use futures::{stream, Stream, StreamExt};
+use quic_rpc::{
+    client::{InterruptedCause, StreamingResponseItemError},
+    declare_server_streaming,
+    server::RpcServerError,
+    transport::stream::{RecvError, StreamConnection, StreamServerEndpoint},
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{io::duplex, sync::Notify, task::JoinHandle};
+
+/// count from `from` up to `to`, excluding `to`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Count {
+    from: u64,
+    to: u64,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Counted(u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum CountRequest {
+    Count(Count),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum CountResponse {
+    Counted(Counted),
+}
+
+#[derive(Debug, Clone)]
+struct CountService;
+
+impl Service for CountService {
+    type Req = CountRequest;
+    type Res = CountResponse;
+}
+
+declare_server_streaming!(CountService, Count, Counted);
+
+/// The first call stops after [STALL_AT] responses, until its connection is cut
+#[derive(Debug, Clone)]
+struct Handler {
+    first: Arc<AtomicBool>,
+    stalled: Arc<Notify>,
+}
+
+const STALL_AT: u64 = 3;
+
+impl Handler {
+    fn count(self, req: Count) -> impl Stream<Item = Counted> {
+        let stall = self.first.swap(false, Ordering::SeqCst);
+        stream::iter(req.from..req.to).then(move |n| {
+            let stalled = self.stalled.clone();
+            async move {
+                if stall && n == STALL_AT {
+                    stalled.notify_one();
+                    futures::future::pending::<()>().await;
+                }
+                Counted(n)
+            }
+        })
+    }
+
+    async fn server<C: ServiceEndpoint<CountService>>(
+        self,
+        server: RpcServer<CountService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            let handler = self.clone();
+            tokio::spawn(async move {
+                match req {
+                    CountRequest::Count(msg) => {
+                        chan.server_streaming(msg, handler, Handler::count).await
+                    }
+                }
+            });
+        }
+    }
+}
+
+type Client = RpcClient<CountService, StreamConnection<CountResponse, CountRequest>>;
+
+/// A client of a server, through a link that is cut by aborting the returned task
+fn connect(handler: &Handler) -> (Client, JoinHandle<()>) {
+    let (client, mut client_link) = duplex(64 * 1024);
+    let (mut server_link, server) = duplex(64 * 1024);
+    let link = tokio::spawn(async move {
+        tokio::io::copy_bidirectional(&mut client_link, &mut server_link)
+            .await
+            .ok();
+    });
+    let server = RpcServer::<CountService, _>::new(StreamServerEndpoint::new(server));
+    tokio::spawn(handler.clone().server(server));
+    (RpcClient::new(StreamConnection::new(client)), link)
+}
+
+fn handler() -> Handler {
+    Handler {
+        first: Arc::new(AtomicBool::new(true)),
+        stalled: Arc::new(Notify::new()),
+    }
+}
+
+fn advance(req: &mut Count, res: &Counted) {
+    req.from = res.0 + 1;
+}
+
+/// a call that is not interrupted returns all responses
+#[tokio::test]
+async fn interrupted_complete() -> anyhow::Result<()> {
+    let handler = handler();
+    handler.first.store(false, Ordering::SeqCst);
+    let (client, _link) = connect(&handler);
+    let res = client
+        .server_streaming_collect_resumable(Count { from: 0, to: 10 }, advance)
+        .await?;
+    assert_eq!(res, (0..10).map(Counted).collect::<Vec<_>>());
+    Ok(())
+}
+
+/// a call that loses its connection part way can be resumed after the
+/// responses it received
+#[tokio::test]
+async fn interrupted_resume() -> anyhow::Result<()> {
+    let handler = handler();
+    let (client, link) = connect(&handler);
+    let call = tokio::spawn(async move {
+        client
+            .server_streaming_collect_resumable(Count { from: 0, to: 10 }, advance)
+            .await
+    });
+    handler.stalled.notified().await;
+    link.abort();
+    let interrupted = call.await?.unwrap_err();
+    assert!(interrupted.received.len() as u64 <= STALL_AT);
+    assert!(matches!(
+        interrupted.cause,
+        InterruptedCause::Item(StreamingResponseItemError::RecvError(RecvError::Closed))
+    ));
+    let resume = interrupted.resumable.expect("resumable");
+    assert_eq!(
+        resume,
+        Count {
+            from: interrupted.received.len() as u64,
+            to: 10
+        }
+    );
+
+    let (client, _link) = connect(&handler);
+    let rest = client
+        .server_streaming_collect_resumable(resume, advance)
+        .await?;
+    let all = interrupted
+        .received
+        .into_iter()
+        .chain(rest)
+        .collect::<Vec<_>>();
+    assert_eq!(all, (0..10).map(Counted).collect::<Vec<_>>());
+    Ok(())
+}
+
+/// without a way to advance the request, the call has to be restarted
+#[tokio::test]
+async fn interrupted_not_resumable() -> anyhow::Result<()> {
+    let handler = handler();
+    let (client, link) = connect(&handler);
+    let call = tokio::spawn(async move {
+        client
+            .server_streaming_collect(Count { from: 0, to: 10 })
+            .await
+    });
+    handler.stalled.notified().await;
+    link.abort();
+    let interrupted = call.await?.unwrap_err();
+    assert!(interrupted.cause.is_transient());
+    assert!(interrupted.resumable.is_none());
+    Ok(())
+}
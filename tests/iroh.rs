@@ -0,0 +1,46 @@
+#![cfg(feature = "iroh-transport")]
+// iroh-net 0.28.2 deprecates everything in favor of the renamed iroh crate
+#![allow(deprecated)]
+mod math;
+use iroh_net::{relay::RelayMode, Endpoint};
+use math::*;
+use quic_rpc::{
+    transport::{
+        iroh::{IrohConnection, IrohServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+const ALPN: &[u8] = b"quic-rpc/compute/1";
+
+/// An endpoint that only connects directly, without relays or discovery
+async fn endpoint(alpns: Vec<Vec<u8>>) -> anyhow::Result<Endpoint> {
+    let endpoint = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .alpns(alpns)
+        .bind()
+        .await?;
+    Ok(endpoint)
+}
+
+/// all 4 patterns work on the streams of an iroh connection to a NodeAddr
+#[tokio::test]
+async fn iroh_channel_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let server_endpoint = endpoint(vec![ALPN.to_vec()]).await?;
+    let addr = server_endpoint.node_addr().await?;
+    let server = IrohServerEndpoint::<ComputeRequest, ComputeResponse>::new(server_endpoint);
+    assert!(matches!(server.local_addr(), [LocalAddr::Socket(_), ..]));
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let client_endpoint = endpoint(vec![]).await?;
+    let client = IrohConnection::<ComputeResponse, ComputeRequest>::new(
+        client_endpoint,
+        addr,
+        ALPN.to_vec(),
+    );
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "libp2p-transport")]
+mod math;
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{
+    noise, swarm::SwarmEvent, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder,
+};
+use math::*;
+use quic_rpc::{
+    transport::{
+        libp2p::{Libp2pConnection, Libp2pServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new("/quic-rpc/compute/1");
+
+fn swarm() -> anyhow::Result<Swarm<libp2p_stream::Behaviour>> {
+    let swarm = SwarmBuilder::with_new_identity()
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(|_| libp2p_stream::Behaviour::new())?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    Ok(swarm)
+}
+
+/// A listening server swarm, polled in the background, with its peer id and address
+async fn spawn_server_swarm() -> anyhow::Result<(
+    libp2p_stream::Control,
+    PeerId,
+    Multiaddr,
+    tokio::task::JoinHandle<()>,
+)> {
+    let mut swarm = swarm()?;
+    swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)?;
+    let addr = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            break address;
+        }
+    };
+    let control = swarm.behaviour().new_control();
+    let peer = *swarm.local_peer_id();
+    let handle = tokio::spawn(async move {
+        loop {
+            swarm.select_next_some().await;
+        }
+    });
+    Ok((control, peer, addr, handle))
+}
+
+/// A client swarm connected to `addr`, polled in the background
+async fn spawn_client_swarm(
+    addr: Multiaddr,
+) -> anyhow::Result<(libp2p_stream::Control, tokio::task::JoinHandle<()>)> {
+    let mut swarm = swarm()?;
+    swarm.dial(addr)?;
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::ConnectionEstablished { .. } => break,
+            SwarmEvent::OutgoingConnectionError { error, .. } => return Err(error.into()),
+            _ => {}
+        }
+    }
+    let control = swarm.behaviour().new_control();
+    let handle = tokio::spawn(async move {
+        loop {
+            swarm.select_next_some().await;
+        }
+    });
+    Ok((control, handle))
+}
+
+/// all 4 patterns work on streams of a protocol of an existing swarm
+#[tokio::test]
+async fn libp2p_channel_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server_control, server_peer, addr, server_swarm) = spawn_server_swarm().await?;
+    let server = Libp2pServerEndpoint::<ComputeRequest, ComputeResponse>::new(
+        server_control.clone(),
+        PROTOCOL,
+    )?;
+    assert!(matches!(
+        server.local_addr(),
+        [LocalAddr::Protocol(protocol)] if protocol == "/quic-rpc/compute/1"
+    ));
+    // a protocol can only be served once per behaviour
+    assert!(
+        Libp2pServerEndpoint::<ComputeRequest, ComputeResponse>::new(server_control, PROTOCOL)
+            .is_err()
+    );
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let (client_control, client_swarm) = spawn_client_swarm(addr).await?;
+    let client = Libp2pConnection::<ComputeResponse, ComputeRequest>::new(
+        client_control,
+        server_peer,
+        PROTOCOL,
+    );
+    smoke_test(client).await?;
+    server_handle.abort();
+    server_swarm.abort();
+    client_swarm.abort();
+    Ok(())
+}
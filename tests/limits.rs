@@ -0,0 +1,109 @@
+#![cfg(all(feature = "flume-transport", feature = "stream-limits"))]
+mod math;
+use futures::{SinkExt, StreamExt};
+use math::*;
+use quic_rpc::{
+    client::{BidiItemError, StreamingResponseItemError},
+    limits::{LimitKind, StreamDirection, StreamLimits},
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer,
+};
+use std::time::Duration;
+
+fn max_items(n: u64) -> StreamLimits {
+    StreamLimits {
+        max_items: Some(n),
+        ..Default::default()
+    }
+}
+
+/// the server stops runaway response streams and update streams
+#[tokio::test]
+async fn server_stream_limits() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server)
+        .with_stream_limits(max_items(3))
+        .with_method_stream_limits::<Fibonacci>(max_items(10));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    let server_handle = tokio::spawn(async move {
+        let mut errors = Vec::new();
+        for _ in 0..2 {
+            let (req, chan) = server.accept().await?;
+            match ComputeService::dispatch(chan, req, ComputeService).await {
+                Err(RpcServerError::StreamLimit(cause)) => errors.push(cause),
+                res => anyhow::bail!("unexpected result {res:?}"),
+            }
+        }
+        anyhow::Ok(errors)
+    });
+
+    // the response stream ends after the limit
+    let items = client.server_streaming(Fibonacci(100)).await?;
+    let items = items.collect::<Vec<_>>().await;
+    assert_eq!(items.len(), 10);
+
+    // the call is aborted once there are too many updates
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    for i in 0..5 {
+        if send.send(SumUpdate(i)).await.is_err() {
+            break;
+        }
+    }
+    assert!(recv.await.is_err());
+
+    let errors = server_handle.await??;
+    assert_eq!(errors[0].method, "Fibonacci");
+    assert_eq!(errors[0].direction, StreamDirection::Responses);
+    assert_eq!(errors[0].kind, LimitKind::Items);
+    assert_eq!(errors[1].method, "Sum");
+    assert_eq!(errors[1].direction, StreamDirection::Updates);
+    assert_eq!(errors[1].kind, LimitKind::Items);
+    Ok(())
+}
+
+/// the client ends response streams that exceed the limits with an error
+#[tokio::test]
+async fn client_stream_limits() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let response_size = bincode::serialized_size(&ComputeResponse::from(FibonacciResponse(0)))?;
+    let client = RpcClient::<ComputeService, _>::new(client)
+        .with_method_stream_limits::<Fibonacci>(StreamLimits {
+            max_bytes: Some(response_size * 5),
+            ..Default::default()
+        })
+        .with_method_stream_limits::<Multiply>(StreamLimits {
+            max_duration: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+    let mut items = client.server_streaming(Fibonacci(100)).await?;
+    for _ in 0..5 {
+        items.next().await.unwrap()?;
+    }
+    match items.next().await {
+        Some(Err(StreamingResponseItemError::LimitExceeded(cause))) => {
+            assert_eq!(cause.kind, LimitKind::Bytes);
+            assert_eq!(cause.direction, StreamDirection::Responses);
+        }
+        item => panic!("unexpected item {item:?}"),
+    }
+    assert!(items.next().await.is_none());
+
+    let (mut send, mut recv) = client.bidi(Multiply(2)).await?;
+    send.send(MultiplyUpdate(1)).await?;
+    assert_eq!(recv.next().await.unwrap()?.0, 2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    send.send(MultiplyUpdate(2)).await?;
+    match recv.next().await {
+        Some(Err(BidiItemError::LimitExceeded(cause))) => {
+            assert_eq!(cause.kind, LimitKind::Duration);
+        }
+        item => panic!("unexpected item {item:?}"),
+    }
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,419 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+use futures::{SinkExt, Stream, StreamExt};
+use quic_rpc::{rpc_service, server::run_server_loop, transport::flume, RpcClient};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, mem::size_of};
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Small(pub u64);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Big(pub [u64; 32]);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Upload;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadUpdate(pub Big);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadResponse(pub u64);
+
+rpc_service! {
+    Request = BigRequest;
+    Response = BigResponse;
+    Service = BigService;
+    CreateDispatch = create_big_dispatch;
+
+    Rpc small = Small, _ -> Small;
+    Rpc big = Big, _ -> Big;
+    ClientStreaming upload = Upload, UploadUpdate -> UploadResponse;
+}
+
+mod unboxed {
+    use super::{Big, Small};
+
+    quic_rpc::rpc_service! {
+        Request = UnboxedRequest;
+        Response = UnboxedResponse;
+        Service = UnboxedService;
+        CreateDispatch = _;
+        MaxVariantSize = usize::MAX;
+
+        Rpc small = Small, _ -> Small;
+        Rpc big = Big, _ -> Big;
+    }
+}
+
+/// The messages of [BigService] in a different order, with a pinned tag
+mod reordered {
+    use super::{Big, Small, Upload, UploadResponse, UploadUpdate};
+
+    quic_rpc::rpc_service! {
+        Request = ReorderedRequest;
+        Response = ReorderedResponse;
+        Service = ReorderedService;
+        CreateDispatch = _;
+
+        ClientStreaming upload = Upload, UploadUpdate -> UploadResponse;
+        Rpc big = Big, _ -> Big;
+        #[tags(Small = 7)]
+        Rpc small = Small, _ -> Small;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BigServer;
+
+impl BigServer {
+    async fn small(self, req: Small) -> Small {
+        Small(req.0 + 1)
+    }
+
+    async fn big(self, mut req: Big) -> Big {
+        req.0.reverse();
+        req
+    }
+
+    async fn upload(
+        self,
+        _req: Upload,
+        updates: impl Stream<Item = UploadUpdate>,
+    ) -> UploadResponse {
+        tokio::pin!(updates);
+        let mut sum = 0;
+        while let Some(update) = updates.next().await {
+            sum += update.0 .0.iter().sum::<u64>();
+        }
+        UploadResponse(sum)
+    }
+}
+
+create_big_dispatch!(BigServer, dispatch_big_request);
+
+fn big() -> Big {
+    let mut data = [0; 32];
+    for (i, x) in data.iter_mut().enumerate() {
+        *x = i as u64;
+    }
+    Big(data)
+}
+
+#[test]
+fn macros_box_large_variants() {
+    assert!(size_of::<BigRequest>() <= 2 * size_of::<usize>());
+    assert!(size_of::<BigResponse>() <= 2 * size_of::<usize>());
+    assert!(size_of::<unboxed::UnboxedRequest>() > size_of::<Big>());
+
+    // conversions work with the unboxed types
+    let req = BigRequest::from(big());
+    assert!(matches!(&req, BigRequest::Big(b) if **b == big()));
+    assert_eq!(Big::try_from(req), Ok(big()));
+    assert!(Big::try_from(BigRequest::from(Small(1))).is_err());
+    assert_eq!(
+        Small::try_from(unboxed::UnboxedRequest::from(Small(1))),
+        Ok(Small(1))
+    );
+}
+
+#[tokio::test]
+async fn macros_boxed_dispatch() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<BigRequest, BigResponse>(1);
+    let server_handle = tokio::spawn(run_server_loop(
+        BigService,
+        server,
+        BigServer,
+        dispatch_big_request,
+    ));
+    let client = RpcClient::<BigService, _>::new(client);
+
+    assert_eq!(client.rpc(Small(1)).await?, Small(2));
+    let mut expected = big();
+    expected.0.reverse();
+    assert_eq!(client.rpc(big()).await?, expected);
+
+    let (mut send, recv) = client.client_streaming(Upload).await?;
+    send.send(UploadUpdate(big())).await?;
+    send.send(UploadUpdate(big())).await?;
+    drop(send);
+    assert_eq!(recv.await?.0, 2 * (0..32).sum::<u64>());
+
+    // the server loop stops once the client is gone
+    drop(client);
+    assert!(server_handle.await?.is_err());
+    Ok(())
+}
+
+#[test]
+fn macros_manifest() {
+    use quic_rpc::__private::tag;
+
+    let mut expected = vec![
+        ("Small", tag("Small", &[])),
+        ("Big", tag("Big", &[])),
+        ("Upload", tag("Upload", &[])),
+        ("UploadUpdate", tag("UploadUpdate", &[])),
+    ];
+    expected.sort_by_key(|(_, tag)| *tag);
+    assert_eq!(BigRequest::MANIFEST, expected.as_slice());
+
+    // tags are independent of the declaration order, unless pinned
+    let mut reordered = expected.clone();
+    for entry in reordered.iter_mut().filter(|(name, _)| *name == "Small") {
+        entry.1 = 7;
+    }
+    assert_eq!(reordered::ReorderedRequest::MANIFEST.len(), reordered.len());
+    assert!(reordered
+        .iter()
+        .all(|entry| reordered::ReorderedRequest::MANIFEST.contains(entry)));
+    assert!(reordered::ReorderedRequest::MANIFEST.contains(&("Small", 7)));
+    assert_eq!(tag("Small", &[("Small", 7)]), 7);
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn macros_stable_tags() -> anyhow::Result<()> {
+    use reordered::{ReorderedRequest, ReorderedResponse};
+
+    // the wire format is the same for differently ordered declarations
+    let bytes = bincode::serialize(&BigRequest::from(big()))?;
+    assert_eq!(bytes, bincode::serialize(&ReorderedRequest::from(big()))?);
+    assert_eq!(
+        &bytes[..4],
+        &BigRequest::MANIFEST
+            .iter()
+            .find(|(name, _)| *name == "Big")
+            .unwrap()
+            .1
+            .to_le_bytes()
+    );
+    let req: ReorderedRequest = bincode::deserialize(&bytes)?;
+    assert_eq!(Big::try_from(req), Ok(big()));
+
+    let bytes = bincode::serialize(&BigResponse::from(UploadResponse(3)))?;
+    let res: ReorderedResponse = bincode::deserialize(&bytes)?;
+    assert!(matches!(
+        res,
+        ReorderedResponse::UploadResponse(UploadResponse(3))
+    ));
+
+    // the pinned tag is used instead of the default one
+    let bytes = bincode::serialize(&ReorderedRequest::from(Small(1)))?;
+    assert_eq!(&bytes[..4], &7u32.to_le_bytes());
+    assert!(bincode::deserialize::<BigRequest>(&bytes).is_err());
+    Ok(())
+}
+
+/// Messages with a declared policy
+mod policy {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Get(pub u64);
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Put(pub u64);
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct Done(pub u64);
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub struct Stored;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Changed;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Watch;
+
+    quic_rpc::rpc_service! {
+        Request = PolicyRequest;
+        Response = PolicyResponse;
+        Service = PolicyService;
+        CreateDispatch = _;
+
+        /// Get the value of a key
+        ///
+        /// Served from the cache if possible.
+        #[tags(Get = 1)]
+        #[rpc(timeout = "100ms", idempotent, max_size = "1KiB", cacheable)]
+        Rpc get = Get, _ -> Done;
+        /// Store a value
+        #[rpc(timeout = "2m")]
+        Rpc put = Put, _ -> Stored;
+        #[rpc(max_size = "64KB")]
+        ServerStreaming watch = Watch, _ -> Changed;
+    }
+}
+
+#[test]
+fn macros_method_policy() {
+    use policy::*;
+    use quic_rpc::message::{MethodPolicy, Msg, RpcMsg};
+    use std::time::Duration;
+
+    assert_eq!(
+        <Get as Msg<PolicyService>>::POLICY,
+        MethodPolicy {
+            timeout: Some(Duration::from_millis(100)),
+            idempotent: true,
+            max_size: Some(1024),
+        }
+    );
+    assert_eq!(
+        <Put as Msg<PolicyService>>::POLICY,
+        MethodPolicy::DEFAULT.with_timeout(Duration::from_secs(120))
+    );
+    assert_eq!(
+        <Watch as Msg<PolicyService>>::POLICY,
+        MethodPolicy::DEFAULT.with_max_size(64_000)
+    );
+    let cacheable = [
+        <Get as RpcMsg<PolicyService>>::CACHEABLE,
+        <Put as RpcMsg<PolicyService>>::CACHEABLE,
+    ];
+    assert_eq!(cacheable, [true, false]);
+    // methods without an rpc attribute have the default policy
+    assert_eq!(<Small as Msg<BigService>>::POLICY, MethodPolicy::DEFAULT);
+    assert_eq!(PolicyRequest::MANIFEST[0], ("Get", 1));
+}
+
+/// the doc comments of the method lines are kept, for every method
+#[test]
+fn macros_method_docs() {
+    use policy::*;
+
+    let docs = PolicyRequest::DOCS;
+    assert_eq!(
+        docs.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        ["Get", "Put", "Watch"]
+    );
+    assert_eq!(
+        PolicyRequest::doc("Get").as_deref(),
+        Some("Get the value of a key\n\nServed from the cache if possible.")
+    );
+    assert_eq!(PolicyRequest::doc("Put").as_deref(), Some("Store a value"));
+    assert_eq!(PolicyRequest::doc("Watch").as_deref(), Some(""));
+    assert!(docs[2].1.is_empty());
+    // only messages that start a call have docs
+    assert_eq!(PolicyRequest::doc("Done"), None);
+}
+
+/// clients apply the timeout of the policy, and only retry idempotent calls
+#[tokio::test]
+async fn macros_method_policy_calls() -> anyhow::Result<()> {
+    use policy::*;
+    use quic_rpc::{client::RpcClientError, context::CallCancelled, RpcServer};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    let (server, client) = flume::connection::<PolicyRequest, PolicyResponse>(1);
+    let server = RpcServer::<PolicyService, _>::new(server);
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let counter = attempts.clone();
+    tokio::spawn(async move {
+        loop {
+            let (req, chan) = server.accept().await?;
+            // the first attempt of every call fails
+            if counter.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+                drop(chan);
+                continue;
+            }
+            match req {
+                // longer than the timeout of Get
+                PolicyRequest::Get(req) if req.0 == 0 => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    chan.rpc(req, (), |(), req| async move { Done(req.0) })
+                        .await?
+                }
+                PolicyRequest::Get(req) => {
+                    chan.rpc(req, (), |(), req| async move { Done(req.0) })
+                        .await?
+                }
+                PolicyRequest::Put(req) => chan.rpc(req, (), |(), _| async move { Stored }).await?,
+                PolicyRequest::Watch(_) => {}
+            }
+        }
+        #[allow(unreachable_code)]
+        anyhow::Ok(())
+    });
+    let client =
+        RpcClient::<PolicyService, _>::new(client).with_retries(3, Duration::from_millis(1));
+
+    // the failed attempt of the idempotent call is retried
+    assert_eq!(client.rpc_retry(Get(1)).await?, Done(1));
+    assert_eq!(attempts.swap(0, Ordering::SeqCst), 2);
+
+    // the failed attempt of the other call is not
+    assert!(matches!(
+        client.rpc_retry(Put(1)).await,
+        Err(RpcClientError::EarlyClose)
+    ));
+    assert_eq!(attempts.swap(1, Ordering::SeqCst), 1);
+
+    // the call takes longer than the timeout of the policy
+    assert!(matches!(
+        client.rpc(Get(0)).await,
+        Err(RpcClientError::Cancelled(CallCancelled::DeadlineExceeded))
+    ));
+    Ok(())
+}
+
+/// Messages whose responses can carry errors
+mod fallible {
+    use quic_rpc::server::HandlerDropped;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Get(pub u64);
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+    pub enum ApiError {
+        HandlerDropped(String),
+    }
+
+    impl From<HandlerDropped> for ApiError {
+        fn from(info: HandlerDropped) -> Self {
+            Self::HandlerDropped(info.method.to_string())
+        }
+    }
+
+    pub type GetResult = Result<u64, ApiError>;
+
+    quic_rpc::rpc_service! {
+        Request = FallibleRequest;
+        Response = FallibleResponse;
+        Service = FallibleService;
+        CreateDispatch = _;
+
+        Rpc get = Get, _ -> GetResult;
+    }
+}
+
+/// the client gets a typed error by default when a handler is dropped
+#[tokio::test]
+async fn macros_handler_dropped() -> anyhow::Result<()> {
+    use fallible::*;
+    use quic_rpc::RpcServer;
+
+    let (server, client) = flume::connection::<FallibleRequest, FallibleResponse>(1);
+    let server = RpcServer::<FallibleService, _>::new(server);
+    let client = RpcClient::<FallibleService, _>::new(client);
+    let call = tokio::spawn(async move { client.rpc(Get(1)).await });
+
+    let (req, chan) = server.accept().await?;
+    let FallibleRequest::Get(req) = req;
+    // a handler that never responds, in a task that gets aborted
+    let handler = tokio::spawn(chan.rpc(req, (), |_, _| futures::future::pending()));
+    tokio::task::yield_now().await;
+    handler.abort();
+
+    let res = call.await??;
+    assert_eq!(res, Err(ApiError::HandlerDropped("Get".to_string())));
+    Ok(())
+}
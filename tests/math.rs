@@ -1,7 +1,19 @@
 #![cfg(any(
+    feature = "bus-transport",
     feature = "flume-transport",
     feature = "hyper-transport",
-    feature = "quinn-transport"
+    feature = "iroh-transport",
+    feature = "libp2p-transport",
+    feature = "quinn-transport",
+    feature = "s2n-quic-transport",
+    feature = "tcp-transport",
+    feature = "unix-transport",
+    feature = "named-pipe-transport",
+    feature = "stdio-transport",
+    feature = "stream-transport",
+    feature = "tls-tcp-transport",
+    feature = "vsock-transport",
+    feature = "shm-transport"
 ))]
 #![allow(dead_code)]
 use async_stream::stream;
@@ -9,7 +21,8 @@ use derive_more::{From, TryInto};
 use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
 use quic_rpc::{
     declare_bidi_streaming, declare_client_streaming, declare_rpc, declare_server_streaming,
-    server::RpcServerError, RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
+    server::{RpcChannel, RpcServerError},
+    RpcClient, RpcServer, Service, ServiceConnection, ServiceEndpoint,
 };
 use serde::{Deserialize, Serialize};
 use std::{
@@ -39,7 +52,7 @@ pub struct SumResponse(pub u128);
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Fibonacci(pub u64);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct FibonacciResponse(pub u128);
 
 /// multiply a stream of numbers, returning a stream
@@ -49,7 +62,7 @@ pub struct Multiply(pub u64);
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MultiplyUpdate(pub u64);
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct MultiplyResponse(pub u128);
 
 /// request enum
@@ -129,6 +142,25 @@ impl ComputeService {
         }
     }
 
+    /// dispatch a single request, without spawning
+    pub async fn dispatch<C: ServiceEndpoint<ComputeService>>(
+        chan: RpcChannel<ComputeService, C>,
+        req: ComputeRequest,
+        service: ComputeService,
+    ) -> result::Result<(), RpcServerError<C>> {
+        use ComputeRequest::*;
+        #[rustfmt::skip]
+        let res = match req {
+            Sqr(msg) => chan.rpc(msg, service, ComputeService::sqr).await,
+            Sum(msg) => chan.client_streaming(msg, service, ComputeService::sum).await,
+            Fibonacci(msg) => chan.server_streaming(msg, service, ComputeService::fibonacci).await,
+            Multiply(msg) => chan.bidi_streaming(msg, service, ComputeService::multiply).await,
+            SumUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+            MultiplyUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+        };
+        res
+    }
+
     pub async fn server<C: ServiceEndpoint<ComputeService>>(
         server: RpcServer<ComputeService, C>,
     ) -> result::Result<(), RpcServerError<C>> {
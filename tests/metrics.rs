@@ -0,0 +1,83 @@
+#![cfg(all(
+    feature = "openmetrics",
+    feature = "flume-transport",
+    feature = "macros"
+))]
+use std::time::Duration;
+
+use quic_rpc::{
+    metrics::{self, MetricLabels, OVERFLOW_VALUE},
+    transport::flume,
+    RpcClient, RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// Wait until the registry has a server duration count for `labels`
+async fn server_count(labels: &str) -> u64 {
+    let prefix = format!("rpc_server_duration_seconds_count{{{labels}}} ");
+    for _ in 0..100 {
+        let encoded = metrics::encode();
+        let count = encoded
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .map(|count| count.parse().unwrap());
+        if let Some(count) = count {
+            return count;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("no series with labels {labels} in\n{}", metrics::encode());
+}
+
+/// server call durations are labelled with values from the request, up to a limit
+#[tokio::test]
+async fn metrics_extracted_labels() -> anyhow::Result<()> {
+    let labels = MetricLabels::new().with_label("operand", 2, |req: &ComputeRequest| match req {
+        ComputeRequest::Sqr(Sqr(n)) => Some(n.to_string()),
+        _ => None,
+    });
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server).with_metric_labels(labels);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+    for n in [1, 2, 1, 3, 4] {
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    futures::SinkExt::send(&mut send, SumUpdate(1))
+        .await
+        .map_err(anyhow::Error::msg)?;
+    drop(send);
+    assert_eq!(recv.await?, SumResponse(1));
+
+    let sqr =
+        "rpc_system=\"quic-rpc\",rpc_service=\"metrics::math::ComputeService\",rpc_method=\"Sqr\"";
+    assert_eq!(server_count(&format!("{sqr},operand=\"1\"")).await, 2);
+    assert_eq!(server_count(&format!("{sqr},operand=\"2\"")).await, 1);
+    // 3 and 4 are over the limit of distinct values
+    assert_eq!(
+        server_count(&format!("{sqr},operand=\"{OVERFLOW_VALUE}\"")).await,
+        2
+    );
+    // without a value, the label is left out
+    let sum =
+        "rpc_system=\"quic-rpc\",rpc_service=\"metrics::math::ComputeService\",rpc_method=\"Sum\"";
+    assert_eq!(server_count(sum).await, 1);
+    // client calls are not labelled
+    assert!(metrics::encode()
+        .lines()
+        .any(|line| line.starts_with(&format!("rpc_client_duration_seconds_count{{{sqr}}} "))));
+    server_handle.abort();
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "label \"rpc_method\" is already set")]
+fn metrics_reserved_label() {
+    MetricLabels::<ComputeService>::new().with_label("rpc_method", 1, |_| None);
+}
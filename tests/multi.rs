@@ -0,0 +1,54 @@
+#![cfg(all(
+    feature = "combined-transport",
+    feature = "flume-transport",
+    feature = "tcp-transport",
+    feature = "macros"
+))]
+use std::time::Duration;
+
+use quic_rpc::{
+    transport::{
+        combined::MultiServerEndpoint,
+        flume::{self, FlumeServerEndpoint},
+        tcp::{TcpConnection, TcpServerEndpoint},
+        ConnectionCommon, LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+mod math;
+use math::*;
+
+/// one server loop serves the calls of all endpoints
+#[tokio::test]
+async fn multi_endpoint_smoke() -> anyhow::Result<()> {
+    let (mem, mem_client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let tcp = TcpServerEndpoint::<ComputeRequest, ComputeResponse>::bind("127.0.0.1:0").await?;
+    let server = MultiServerEndpoint::new()
+        .with_endpoint(mem)
+        .with_endpoint(tcp);
+    let addr = match server.local_addr() {
+        [LocalAddr::Mem, LocalAddr::Socket(addr)] => *addr,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    assert_eq!(
+        server.capabilities(),
+        <FlumeServerEndpoint<ComputeRequest, ComputeResponse> as ConnectionCommon<_, _>>::CAPABILITIES
+            .and(<TcpServerEndpoint<ComputeRequest, ComputeResponse> as ConnectionCommon<_, _>>::CAPABILITIES)
+    );
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    smoke_test(mem_client.clone()).await?;
+    smoke_test(TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// without endpoints, accepting waits forever
+#[tokio::test]
+async fn multi_endpoint_empty() {
+    let server = MultiServerEndpoint::<ComputeRequest, ComputeResponse>::default();
+    assert!(server.local_addr().is_empty());
+    let accept = tokio::time::timeout(Duration::from_millis(10), server.accept_bi()).await;
+    assert!(accept.is_err());
+}
@@ -0,0 +1,51 @@
+#![cfg(all(windows, feature = "named-pipe-transport"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        named_pipe::{NamedPipeConnection, NamedPipeServerEndpoint},
+        Connection, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+/// A pipe name that is unique per test
+fn pipe_name(name: &str) -> String {
+    format!(r"\\.\pipe\quic-rpc-{}-{}", name, std::process::id())
+}
+
+/// all 4 patterns work over a named pipe
+#[tokio::test]
+async fn named_pipe_channel_smoke() -> anyhow::Result<()> {
+    let name = pipe_name("smoke");
+    let server = NamedPipeServerEndpoint::<ComputeRequest, ComputeResponse>::bind(&name)?;
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    for _ in 0..3 {
+        // every connection gets its own instance of the pipe
+        let client = NamedPipeConnection::<ComputeResponse, ComputeRequest>::connect(&name).await?;
+        smoke_test(client).await?;
+    }
+    server_handle.abort();
+    Ok(())
+}
+
+/// both sides see the process id of the other side
+#[tokio::test]
+async fn named_pipe_peer_process_id() -> anyhow::Result<()> {
+    let name = pipe_name("peer");
+    let server = NamedPipeServerEndpoint::<ComputeRequest, ComputeResponse>::bind(&name)?;
+    let client = NamedPipeConnection::<ComputeResponse, ComputeRequest>::connect(&name).await?;
+    let (mut send, _recv) = client.open_bi().await?;
+    futures::SinkExt::send(&mut send, ComputeRequest::Sqr(Sqr(2))).await?;
+    let (_send, recv) = server.accept_bi().await?;
+
+    // the client and the server are the same process here
+    let pid = Some(std::process::id());
+    assert_eq!(recv.peer_process_id(), pid);
+    assert_eq!(client.peer_process_id(), pid);
+    assert_eq!(send.peer_process_id(), pid);
+    Ok(())
+}
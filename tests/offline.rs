@@ -0,0 +1,201 @@
+#![cfg(all(feature = "offline", feature = "flume-transport"))]
+use derive_more::{From, TryInto};
+use futures::{stream, FutureExt, SinkExt, StreamExt, TryStreamExt};
+use quic_rpc::{
+    client::RpcClientError,
+    declare_client_streaming, declare_rpc, declare_server_streaming,
+    offline::{self, OfflineConnection, RecordingConnection},
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Get(String);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Value(Option<u64>);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Count(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Counted(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Add;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AddUpdate(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Total(u64);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Get(Get),
+    Count(Count),
+    Add(Add),
+    AddUpdate(AddUpdate),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    Value(Value),
+    Counted(Counted),
+    Total(Total),
+}
+
+#[derive(Debug, Clone)]
+struct StoreService;
+
+impl Service for StoreService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_rpc!(StoreService, Get, Value);
+declare_server_streaming!(StoreService, Count, Counted);
+declare_client_streaming!(StoreService, Add, AddUpdate, Total);
+
+/// fixtures that compute the responses from the requests
+fn fixtures(
+    req: Request,
+    updates: offline::Updates<StoreService>,
+) -> offline::Responses<StoreService> {
+    match req {
+        Request::Get(Get(key)) => offline::respond(Value(Some(key.len() as u64))),
+        Request::Count(Count(n)) => offline::respond_all((0..n).map(Counted)),
+        Request::Add(_) => updates
+            .fold(0, |total, update| async move {
+                match update {
+                    Request::AddUpdate(AddUpdate(x)) => total + x,
+                    _ => total,
+                }
+            })
+            .map(|total| Response::from(Total(total)))
+            .into_stream()
+            .boxed(),
+        Request::AddUpdate(_) => stream::empty().boxed(),
+    }
+}
+
+async fn add<C>(client: &RpcClient<StoreService, C>, values: &[u64]) -> anyhow::Result<Total>
+where
+    C: quic_rpc::ServiceConnection<StoreService>,
+    C::SendError: std::error::Error,
+{
+    let (mut send, recv) = client.client_streaming(Add).await?;
+    for x in values {
+        send.send(AddUpdate(*x)).await?;
+    }
+    drop(send);
+    Ok(recv.await?)
+}
+
+/// all interaction patterns work with closures as fixtures
+#[tokio::test]
+async fn offline_closures() -> anyhow::Result<()> {
+    let client =
+        RpcClient::<StoreService, _>::new(OfflineConnection::<StoreService>::new(fixtures));
+    assert_eq!(client.rpc(Get("abc".into())).await?, Value(Some(3)));
+    let counted = client
+        .server_streaming(Count(3))
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(counted, [Counted(0), Counted(1), Counted(2)]);
+    assert_eq!(add(&client, &[1, 2, 3]).await?, Total(6));
+
+    // a fixture without responses closes the call early
+    let connection = OfflineConnection::<StoreService>::new(|req: Request, _| match req {
+        Request::Get(Get(key)) if key == "known" => offline::respond(Value(None)),
+        _ => stream::empty().boxed(),
+    });
+    let client = RpcClient::<StoreService, _>::new(connection);
+    assert_eq!(client.rpc(Get("known".into())).await?, Value(None));
+    assert!(matches!(
+        client.rpc(Get("abc".into())).await,
+        Err(RpcClientError::EarlyClose)
+    ));
+    Ok(())
+}
+
+async fn server<C: ServiceEndpoint<StoreService>>(
+    server: RpcServer<StoreService, C>,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        match req {
+            Request::Get(msg) => {
+                chan.rpc(msg, (), |(), Get(key)| async move {
+                    Value(Some(key.len() as u64 * 10))
+                })
+                .await
+            }
+            Request::Count(msg) => {
+                chan.server_streaming(msg, (), |(), Count(n)| {
+                    stream::iter((0..n).map(|i| Counted(i * 10)))
+                })
+                .await
+            }
+            Request::Add(msg) => {
+                chan.client_streaming(msg, (), |(), _, updates| async move {
+                    Total(
+                        updates
+                            .fold(0, |total, AddUpdate(x)| async move { total + x })
+                            .await,
+                    )
+                })
+                .await
+            }
+            Request::AddUpdate(_) => Err(RpcServerError::UnexpectedStartMessage),
+        }?;
+    }
+}
+
+/// calls to a server are recorded, and can be replayed without it
+#[tokio::test]
+async fn offline_recorded_session() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<Request, Response>(1);
+    tokio::spawn(server(RpcServer::new(endpoint)));
+    let recording = RecordingConnection::<StoreService, _>::new(connection);
+    let client = RpcClient::<StoreService, _>::new(recording.clone());
+    assert_eq!(client.rpc(Get("abc".into())).await?, Value(Some(30)));
+    let counted = client
+        .server_streaming(Count(2))
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(counted, [Counted(0), Counted(10)]);
+    assert_eq!(add(&client, &[1, 2]).await?, Total(3));
+
+    let session = recording.take_session();
+    assert_eq!(session.calls.len(), 3);
+    let add_call = session
+        .calls
+        .iter()
+        .find(|call| call.request == Request::Add(Add))
+        .expect("recorded add call");
+    assert_eq!(add_call.updates, [AddUpdate(1).into(), AddUpdate(2).into()]);
+    assert_eq!(add_call.responses, [Total(3).into()]);
+    assert!(recording.take_session().calls.is_empty());
+
+    // the replayed responses are the recorded ones
+    let client = RpcClient::<StoreService, _>::new(OfflineConnection::<StoreService>::new(session));
+    assert_eq!(client.rpc(Get("abc".into())).await?, Value(Some(30)));
+    let counted = client
+        .server_streaming(Count(2))
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(counted, [Counted(0), Counted(10)]);
+    assert_eq!(add(&client, &[5]).await?, Total(3));
+    // calls that were not recorded are closed early
+    assert!(matches!(
+        client.rpc(Get("other".into())).await,
+        Err(RpcClientError::EarlyClose)
+    ));
+    Ok(())
+}
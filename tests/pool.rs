@@ -0,0 +1,73 @@
+#![cfg(feature = "flume-transport")]
+use quic_rpc::{
+    transport::{
+        flume,
+        pool::{PoolConfig, PoolConnection, PoolStats},
+    },
+    RpcClient, RpcServer,
+};
+use std::time::Duration;
+
+mod math;
+use math::*;
+
+/// A connection to a new server, like a new quinn connection to the same endpoint
+fn connect() -> flume::FlumeConnection<ComputeResponse, ComputeRequest> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(ComputeService::server(server));
+    client
+}
+
+#[tokio::test]
+async fn pool_reuse_and_shrink() -> anyhow::Result<()> {
+    let config = PoolConfig {
+        max_connections: 3,
+        max_calls: 1,
+        min_connections: 1,
+        idle_timeout: Duration::from_millis(100),
+    };
+    let pool = PoolConnection::new(config, connect);
+    let client = RpcClient::<ComputeService, _>::new(pool.clone());
+    assert_eq!(pool.stats(), PoolStats::default());
+
+    // the first call opens a connection, the second one reuses it
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    let stats = pool.stats();
+    assert_eq!((stats.misses, stats.hits, stats.reuses), (1, 1, 1));
+    assert_eq!(
+        (stats.opened, stats.connections, stats.in_flight),
+        (1, 1, 0)
+    );
+
+    // calls in flight need more connections, up to the maximum
+    let mut calls = Vec::new();
+    for _ in 0..4 {
+        calls.push(client.bidi(Multiply(2)).await?);
+    }
+    let stats = pool.stats();
+    assert_eq!(
+        (stats.opened, stats.connections, stats.in_flight),
+        (3, 3, 4)
+    );
+    assert_eq!((stats.misses, stats.hits, stats.reuses), (3, 3, 2));
+    drop(calls);
+    assert_eq!(pool.stats().in_flight, 0);
+
+    // idle connections are closed, down to the minimum
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let stats = pool.stats();
+    assert_eq!((stats.closed, stats.connections), (2, 1));
+
+    // and opened again when needed
+    let first = client.bidi(Multiply(2)).await?;
+    let second = client.bidi(Multiply(3)).await?;
+    let stats = pool.stats();
+    assert_eq!(
+        (stats.opened, stats.connections, stats.in_flight),
+        (4, 2, 2)
+    );
+    drop((first, second));
+    Ok(())
+}
@@ -0,0 +1,143 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{SinkExt, StreamExt};
+use quic_rpc::{
+    declare_server_initiated,
+    push::{self, PushControl, Pushed, Pusher},
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// starts the push call
+#[derive(Debug, Serialize, Deserialize)]
+struct Listen;
+
+/// header of a pushed stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncStart(String);
+
+/// item of a pushed stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncItem(u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Listen(Listen),
+    PushControl(PushControl),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    Pushed(Pushed<SyncStart, SyncItem>),
+}
+
+#[derive(Debug, Clone)]
+struct SyncService;
+
+impl Service for SyncService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_server_initiated!(SyncService, Listen, SyncStart, SyncItem);
+
+/// serves push calls, handing out the pusher of every call
+async fn server<C: ServiceEndpoint<SyncService>>(
+    server: RpcServer<SyncService, C>,
+    clients: mpsc::UnboundedSender<Pusher<SyncStart, SyncItem>>,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            match req {
+                Request::Listen(msg) => {
+                    chan.bidi_streaming(msg, clients, |clients, _req, updates| {
+                        let (pusher, responses) = push::serve(updates);
+                        clients.send(pusher).ok();
+                        responses
+                    })
+                    .await
+                }
+                Request::PushControl(_) => Err(RpcServerError::UnexpectedStartMessage),
+            }
+        });
+    }
+}
+
+type Client = RpcClient<SyncService, flume::FlumeConnection<Response, Request>>;
+
+fn spawn_server() -> (Client, mpsc::UnboundedReceiver<Pusher<SyncStart, SyncItem>>) {
+    let (endpoint, client) = flume::connection::<Request, Response>(1);
+    let (clients_tx, clients) = mpsc::unbounded_channel();
+    tokio::spawn(server(RpcServer::new(endpoint), clients_tx));
+    (RpcClient::new(client), clients)
+}
+
+/// the server opens typed streams toward the client, which end when it drops them
+#[tokio::test]
+async fn push_streams() -> anyhow::Result<()> {
+    let (client, mut clients) = spawn_server();
+    let mut incoming = push::listen(&client, Listen).await?;
+    let pusher = clients.recv().await.expect("pusher");
+
+    let mut first = pusher.open(SyncStart("first".into()))?;
+    let mut second = pusher.open(SyncStart("second".into()))?;
+    first.send(SyncItem(1)).await?;
+    second.send(SyncItem(2)).await?;
+    first.send(SyncItem(3)).await?;
+    drop(first);
+
+    let (header, first_items) = incoming.accept().await.expect("first stream")?;
+    assert_eq!(header, SyncStart("first".into()));
+    let (header, mut second_items) = incoming.accept().await.expect("second stream")?;
+    assert_eq!(header, SyncStart("second".into()));
+    assert_ne!(first_items.id(), second_items.id());
+    assert_eq!(
+        first_items.collect::<Vec<_>>().await,
+        [SyncItem(1), SyncItem(3)]
+    );
+    assert_eq!(second_items.next().await, Some(SyncItem(2)));
+
+    // the call ends once the server is done with it
+    drop((pusher, second));
+    assert_eq!(second_items.next().await, None);
+    assert!(incoming.accept().await.is_none());
+    Ok(())
+}
+
+/// streams and the whole call can be closed by the client
+#[tokio::test]
+async fn push_closed_by_client() -> anyhow::Result<()> {
+    let (client, mut clients) = spawn_server();
+    let mut incoming = push::listen(&client, Listen).await?;
+    let pusher = clients.recv().await.expect("pusher");
+
+    let mut sink = pusher.open(SyncStart("dropped".into()))?;
+    let (_, items) = incoming.accept().await.expect("stream")?;
+    drop(items);
+    // the server learns about the dropped stream with the next items
+    tokio::time::timeout(Duration::from_secs(5), async {
+        let mut i = 0;
+        while sink.send(SyncItem(i)).await.is_ok() {
+            i += 1;
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await?;
+
+    // without anybody listening, the call ends
+    drop(incoming);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !pusher.is_closed() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    })
+    .await?;
+    assert!(pusher.open(SyncStart("late".into())).is_err());
+    Ok(())
+}
@@ -0,0 +1,103 @@
+#![cfg(feature = "flume-transport")]
+use futures::SinkExt;
+use quic_rpc::{
+    client::RpcClientError,
+    transport::{
+        flume,
+        pushback::{
+            Pushback, PushbackConfig, PushbackConnection, PushbackServerEndpoint, RateLimit,
+            RecvError, Reply,
+        },
+    },
+    RpcClient, RpcServer,
+};
+use std::time::Duration;
+
+mod math;
+use math::*;
+
+type Client = RpcClient<
+    ComputeService,
+    PushbackConnection<
+        flume::FlumeConnection<Reply<ComputeResponse>, ComputeRequest>,
+        ComputeResponse,
+        ComputeRequest,
+    >,
+>;
+
+fn spawn_server(config: PushbackConfig) -> Client {
+    let (server, client) = flume::connection::<ComputeRequest, Reply<ComputeResponse>>(1);
+    let server = PushbackServerEndpoint::new(server, config);
+    tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    RpcClient::new(PushbackConnection::new(client))
+}
+
+/// calls beyond the concurrency limit are pushed back until a call finishes
+#[tokio::test]
+async fn pushback_max_concurrent() -> anyhow::Result<()> {
+    let client = spawn_server(PushbackConfig {
+        max_concurrent: Some(1),
+        retry_after: Duration::from_millis(20),
+        ..Default::default()
+    });
+    // a client streaming call is in flight until the updates end
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    send.send(SumUpdate(1)).await?;
+
+    let res = client.rpc(Sqr(2)).await;
+    let err = res.expect_err("over the limit");
+    assert_eq!(err.retry_after(), Some(Duration::from_millis(20)));
+    assert!(matches!(
+        err,
+        RpcClientError::RecvError(RecvError::Pushback(Pushback { .. }))
+    ));
+
+    drop(send);
+    assert_eq!(recv.await?.0, 1);
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    Ok(())
+}
+
+/// calls beyond the rate limit are asked to wait for the next free slot
+#[tokio::test]
+async fn pushback_rate_limit() -> anyhow::Result<()> {
+    let client = spawn_server(PushbackConfig {
+        rate_limit: Some(RateLimit {
+            calls: 2,
+            per: Duration::from_millis(100),
+        }),
+        ..Default::default()
+    });
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+    let retry_after = client
+        .rpc(Sqr(4))
+        .await
+        .expect_err("over the rate")
+        .retry_after()
+        .expect("pushed back");
+    assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(50));
+
+    tokio::time::sleep(retry_after).await;
+    assert_eq!(client.rpc(Sqr(4)).await?.0, 16);
+    Ok(())
+}
+
+/// handlers can push back calls themselves
+#[tokio::test]
+async fn pushback_from_handler() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, Reply<ComputeResponse>>(1);
+    let server = RpcServer::<ComputeService, _>::new(PushbackServerEndpoint::new(
+        server,
+        Default::default(),
+    ));
+    tokio::spawn(async move {
+        let (_, mut chan) = server.accept().await?;
+        chan.send.push_back(Duration::from_secs(3)).await?;
+        anyhow::Ok(())
+    });
+    let client = RpcClient::<ComputeService, _>::new(PushbackConnection::new(client));
+    let err = client.rpc(Sqr(2)).await.expect_err("pushed back");
+    assert_eq!(err.retry_after(), Some(Duration::from_secs(3)));
+    Ok(())
+}
@@ -0,0 +1,184 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, Stream, TryStreamExt};
+use quic_rpc::{
+    declare_server_streaming,
+    query::{self, field, Fields, Filter, QueryMsg, Value},
+    server::RpcServerError,
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/// search the tickets, with the filter applied by the server
+#[derive(Debug, Serialize, Deserialize)]
+struct Search {
+    filter: Filter,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Ticket {
+    project: String,
+    title: String,
+    priority: u32,
+}
+
+impl Fields for Ticket {
+    fn field(&self, name: &str) -> Option<Value> {
+        match name {
+            "project" => Some(self.project.as_str().into()),
+            "title" => Some(self.title.as_str().into()),
+            "priority" => Some(self.priority.into()),
+            _ => None,
+        }
+    }
+}
+
+impl QueryMsg for Search {
+    fn take_filter(&mut self) -> Filter {
+        std::mem::take(&mut self.filter)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    Search(Search),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    Ticket(Ticket),
+}
+
+#[derive(Debug, Clone)]
+struct TicketService;
+
+impl Service for TicketService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_server_streaming!(TicketService, Search, Ticket);
+
+/// Tickets indexed by project, remembering which projects were scanned
+#[derive(Debug, Clone)]
+struct Tickets {
+    by_project: Arc<HashMap<String, Vec<Ticket>>>,
+    scanned: Arc<Mutex<Vec<String>>>,
+}
+
+impl Tickets {
+    fn search(self, req: Search, filter: Filter) -> impl Stream<Item = Ticket> {
+        // the filter was taken out of the request
+        assert_eq!(req.filter, Filter::All);
+        // push the project down to the index, and filter the rest
+        let projects = match filter.required("project") {
+            Some(Value::Str(project)) => vec![project.clone()],
+            _ => self.by_project.keys().cloned().collect(),
+        };
+        let mut tickets = Vec::new();
+        for project in projects {
+            self.scanned.lock().unwrap().push(project.clone());
+            tickets.extend(self.by_project.get(&project).cloned().unwrap_or_default());
+        }
+        query::filtered(stream::iter(tickets), filter)
+    }
+
+    async fn server<C: ServiceEndpoint<TicketService>>(
+        self,
+        server: RpcServer<TicketService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            match req {
+                Request::Search(msg) => {
+                    chan.server_streaming(msg, self.clone(), query::handler(Tickets::search))
+                        .await
+                }
+            }?;
+        }
+    }
+}
+
+fn ticket(project: &str, title: &str, priority: u32) -> Ticket {
+    Ticket {
+        project: project.into(),
+        title: title.into(),
+        priority,
+    }
+}
+
+/// the server gets the filter separately, and pushes it down
+#[tokio::test]
+async fn query_filter_pushdown() -> anyhow::Result<()> {
+    let mut by_project = HashMap::new();
+    by_project.insert(
+        "rpc".to_string(),
+        vec![
+            ticket("rpc", "fix reconnect", 3),
+            ticket("rpc", "docs", 1),
+            ticket("rpc", "fix tests", 2),
+        ],
+    );
+    by_project.insert("web".to_string(), vec![ticket("web", "fix css", 3)]);
+    let tickets = Tickets {
+        by_project: Arc::new(by_project),
+        scanned: Default::default(),
+    };
+    let (server, client) = flume::connection::<Request, Response>(1);
+    tokio::spawn(tickets.clone().server(RpcServer::new(server)));
+    let client = RpcClient::<TicketService, _>::new(client);
+
+    let filter = field("project")
+        .eq("rpc")
+        .and(field("priority").ge(2))
+        .and(field("title").starts_with("fix"));
+    let found = client
+        .server_streaming(Search { filter })
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(
+        found,
+        [
+            ticket("rpc", "fix reconnect", 3),
+            ticket("rpc", "fix tests", 2)
+        ]
+    );
+    assert_eq!(*tickets.scanned.lock().unwrap(), ["rpc"]);
+
+    // without a required project, all projects are scanned
+    let filter = field("priority").one_of([3u32]);
+    let found = client
+        .server_streaming(Search { filter })
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(found.len(), 2);
+    assert_eq!(tickets.scanned.lock().unwrap().len(), 3);
+    Ok(())
+}
+
+/// conditions compare values of different numeric kinds, and missing fields
+#[test]
+fn query_filter_matches() {
+    let item = ticket("rpc", "docs", 2);
+    assert!(field("priority").gt(-1).matches(&item));
+    assert!(field("priority").lt(2.5).matches(&item));
+    assert!(!field("priority").eq("2").matches(&item));
+    assert!(!field("missing").eq(1).matches(&item));
+    assert!(field("missing").eq(1).not().matches(&item));
+    assert!(field("title")
+        .eq("other")
+        .or(field("project").ne("web"))
+        .matches(&item));
+    let filter = field("project").eq("rpc").and(field("priority").le(2));
+    assert_eq!(filter.fields(), ["priority", "project"]);
+    assert_eq!(filter.required("project"), Some(&Value::from("rpc")));
+    assert_eq!(filter.required("priority"), None);
+    assert!(Filter::All.matches(&item));
+}
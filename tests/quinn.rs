@@ -2,9 +2,13 @@
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use quic_rpc::{RpcClient, RpcServer};
+use quic_rpc::{
+    transport::quinn::{QuinnServerEndpoint, ServerEndpointConfig, IDLE_CLOSE_CODE},
+    RpcClient, RpcServer,
+};
 use quinn::{ClientConfig, Endpoint, ServerConfig};
 use tokio::task::JoinHandle;
 
@@ -90,8 +94,15 @@ pub fn make_endpoints(port: u16) -> anyhow::Result<Endpoints> {
 }
 
 fn run_server(server: quinn::Endpoint) -> JoinHandle<anyhow::Result<()>> {
+    run_server_with_config(server, Default::default())
+}
+
+fn run_server_with_config(
+    server: quinn::Endpoint,
+    config: ServerEndpointConfig,
+) -> JoinHandle<anyhow::Result<()>> {
     tokio::task::spawn(async move {
-        let connection = quic_rpc::transport::quinn::QuinnServerEndpoint::new(server)?;
+        let connection = QuinnServerEndpoint::with_config(server, config)?;
         let server = RpcServer::<ComputeService, _>::new(connection);
         ComputeService::server(server).await?;
         anyhow::Ok(())
@@ -134,3 +145,1046 @@ async fn quinn_channel_smoke() -> anyhow::Result<()> {
     server_handle.abort();
     Ok(())
 }
+
+#[tokio::test]
+async fn quinn_idle_connection_reaping() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12347)?;
+    let config = ServerEndpointConfig::default()
+        .idle_timeout(Duration::from_millis(500))
+        .idle_warning(Duration::from_millis(300));
+    let server_handle = run_server_with_config(server, config);
+    // use a raw connection, so we can observe the warning ourselves
+    let t0 = Instant::now();
+    let connection = client.connect(server_addr, "localhost")?.await?;
+    // we get a warning first
+    let warning = connection.accept_uni().await?.read_to_end(8).await?;
+    let remaining = u64::from_be_bytes(warning.as_slice().try_into()?);
+    assert!(remaining <= 300);
+    // and then the connection gets closed
+    match connection.closed().await {
+        quinn::ConnectionError::ApplicationClosed(close) => {
+            assert_eq!(close.error_code, IDLE_CLOSE_CODE.into());
+        }
+        e => panic!("unexpected close reason {e:?}"),
+    }
+    assert!(t0.elapsed() >= Duration::from_millis(400));
+    server_handle.abort();
+    Ok(())
+}
+
+/// a quiet call keeps the connection open past the idle timeout, and the
+/// client is warned once the connection is idle after the call
+#[tokio::test]
+async fn quinn_idle_quiet_call() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use quic_rpc::transport::quinn::{ConnectionEvent, QuinnConnection};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12370)?;
+    let config = ServerEndpointConfig::default()
+        .idle_timeout(Duration::from_millis(300))
+        .idle_warning(Duration::from_millis(200));
+    let server_handle = run_server_with_config(server, config);
+    let connection = QuinnConnection::new(client, server_addr, "localhost".into());
+    let mut events = connection.events();
+    let client = RpcClient::<ComputeService, _>::new(connection);
+    let (mut send, recv) = client.client_streaming(Sum).await?;
+    // nothing is sent for more than twice the idle timeout
+    tokio::time::sleep(Duration::from_millis(800)).await;
+    send.send(SumUpdate(4)).await?;
+    send.close().await?;
+    assert_eq!(recv.await?, SumResponse(4));
+    // the idle timeout starts once the call is finished
+    let t0 = Instant::now();
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.next()).await?;
+        match event {
+            Some(ConnectionEvent::IdleWarning { remaining }) => {
+                assert!(remaining <= Duration::from_millis(200));
+                break;
+            }
+            Some(ConnectionEvent::Connecting { .. }) | Some(ConnectionEvent::Connected { .. }) => {}
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+    assert!(t0.elapsed() >= Duration::from_millis(50));
+    server_handle.abort();
+    Ok(())
+}
+
+/// all interaction patterns work with frames compressed using a zstd dictionary
+#[cfg(feature = "zstd-compression")]
+#[tokio::test]
+async fn quinn_zstd_dictionary_smoke() -> anyhow::Result<()> {
+    use quic_rpc::transport::compression::ZstdDictionary;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12348)?;
+    // a raw content dictionary, a trained one would work the same
+    let dictionary = ZstdDictionary::new(&[0u8; 64]);
+    let config = ServerEndpointConfig::default().zstd_dictionary(dictionary.clone());
+    let server_handle = run_server_with_config(server, config);
+    let client_connection =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .with_zstd_dictionary(dictionary);
+    smoke_test(client_connection).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// message types that do not compress are sent uncompressed, except for probes
+#[cfg(feature = "zstd-compression")]
+#[tokio::test]
+async fn quinn_zstd_adaptive() -> anyhow::Result<()> {
+    use quic_rpc::transport::compression::{Adaptive, ZstdDictionary};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12363)?;
+    let adaptive = Adaptive {
+        min_saving: 0.05,
+        samples: 4,
+        probe_interval: 8,
+    };
+    let dictionary = ZstdDictionary::new(&[0u8; 64]).with_adaptive(Some(adaptive));
+    let config = ServerEndpointConfig::default().zstd_dictionary(dictionary.clone());
+    let server_handle = run_server_with_config(server, config);
+    let client_connection =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .with_zstd_dictionary(dictionary.clone());
+    let client = RpcClient::<ComputeService, _>::new(client_connection);
+    for i in 0..40u64 {
+        // frames of a few random looking bytes never get smaller
+        let n = (i + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    let stats = dictionary.stats();
+    let sqr = stats["Sqr"];
+    assert_eq!(sqr.compressed_frames + sqr.skipped_frames, 40);
+    // 4 samples, and then one probe every 8 frames
+    assert_eq!(sqr.compressed_frames, 4 + 36 / 8);
+    assert_eq!(sqr.ratio(), 1.0);
+    assert_eq!(
+        stats["SqrResponse"].compressed_frames,
+        sqr.compressed_frames
+    );
+    server_handle.abort();
+    Ok(())
+}
+
+/// latency critical messages are sent without compressing them, all others are compressed
+#[cfg(feature = "zstd-compression")]
+#[tokio::test]
+async fn quinn_latency_critical() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use quic_rpc::transport::{compression::ZstdDictionary, latency::LatencyCritical};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12366)?;
+    let dictionary = ZstdDictionary::new(&[0u8; 64]).with_adaptive(None);
+    let config = ServerEndpointConfig::default()
+        .zstd_dictionary(dictionary.clone())
+        .offload_threshold(0)
+        .latency_critical(LatencyCritical::new().with::<SqrResponse>());
+    let server_handle = run_server_with_config(server, config);
+    let client_connection =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .with_zstd_dictionary(dictionary.clone())
+            .with_offload_threshold(0)
+            .with_latency_critical(LatencyCritical::new().with::<Sqr>());
+    let client = RpcClient::<ComputeService, _>::new(client_connection);
+    for n in 0..3 {
+        assert_eq!(
+            client.rpc(Sqr(n)).await?,
+            SqrResponse(n as u128 * n as u128)
+        );
+    }
+    let items = client.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(items.count().await, 5);
+    let stats = dictionary.stats();
+    assert!(!stats.contains_key("Sqr"), "{stats:?}");
+    assert!(!stats.contains_key("SqrResponse"), "{stats:?}");
+    assert_eq!(stats["Fibonacci"].compressed_frames, 1);
+    assert_eq!(stats["FibonacciResponse"].compressed_frames, 5);
+    server_handle.abort();
+    Ok(())
+}
+
+/// both sides can use a quinn connection that was established elsewhere
+#[tokio::test]
+async fn quinn_from_connection() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::QuinnConnection;
+    use std::time::Duration;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12367)?;
+    let (client, server) = tokio::join!(client.connect(server_addr, "localhost")?, async {
+        server.accept().await.expect("endpoint is open").await
+    });
+    let server = QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::from_connection(
+        server?,
+        server_addr,
+        ServerEndpointConfig::default(),
+    );
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let client = client?;
+    smoke_test(QuinnConnection::from_connection(client.clone())).await?;
+    // the server stops accepting channels once the connection is closed
+    client.close(0u32.into(), b"done");
+    let res = tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    assert!(res.is_err());
+    Ok(())
+}
+
+/// messages sent as datagrams arrive at the server next to the calls, and
+/// datagrams that can not be decoded are passed to the dead letter handler
+#[tokio::test]
+async fn quinn_unreliable() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{DeadLetter, DeadLetterReason, QuinnConnection};
+    use std::sync::Mutex;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12369)?;
+    let dead_letters = Arc::new(Mutex::new(Vec::new()));
+    let config = ServerEndpointConfig::default().dead_letter({
+        let dead_letters = dead_letters.clone();
+        move |letter: DeadLetter| dead_letters.lock().unwrap().push(letter)
+    });
+    let endpoint = QuinnServerEndpoint::with_config(server, config)?;
+    let server = RpcServer::<ComputeService, _>::new(endpoint.clone());
+    let server_handle = tokio::spawn(ComputeService::server(server.clone()));
+    let quinn_connection = client.connect(server_addr, "localhost")?.await?;
+    let connection = QuinnConnection::from_connection(quinn_connection.clone());
+    let client = RpcClient::<ComputeService, _>::new(connection.clone());
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    assert!(connection.max_datagram_size().is_some());
+    // datagrams can get lost, even on localhost, so keep sending until both arrive
+    let received = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            quinn_connection.send_datagram(vec![0xff; 3].into())?;
+            client.notify_unreliable(Sqr(3))?;
+            tokio::select! {
+                req = server.accept_unreliable() => {
+                    if !dead_letters.lock().unwrap().is_empty() {
+                        break anyhow::Ok(req?);
+                    }
+                }
+                _ = tokio::time::sleep(Duration::from_millis(50)) => {}
+            }
+        }
+    })
+    .await??;
+    assert!(matches!(received, ComputeRequest::Sqr(Sqr(3))));
+    let letter = dead_letters.lock().unwrap().remove(0);
+    assert_eq!(&letter.payload[..], &[0xff; 3]);
+    assert!(matches!(letter.reason, DeadLetterReason::Undecodable(_)));
+    assert_eq!(endpoint.dropped_datagrams(), 0);
+    server_handle.abort();
+    Ok(())
+}
+
+/// all interaction patterns work when messages are encoded and decoded on the blocking thread pool
+#[tokio::test]
+async fn quinn_offload_threshold() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12359)?;
+    // every message is offloaded, so ordering across offloaded messages is covered
+    let config = ServerEndpointConfig::default().offload_threshold(1);
+    let server_handle = run_server_with_config(server, config);
+    let client_connection =
+        quic_rpc::transport::quinn::QuinnConnection::new(client, server_addr, "localhost".into())
+            .with_offload_threshold(1);
+    smoke_test(client_connection).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// calls beyond the per connection limit are rejected, and in flight calls are observable
+#[tokio::test]
+async fn quinn_overloaded_connection() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use quic_rpc::{
+        client::RpcClientError,
+        transport::quinn::{OverloadPolicy, Overloaded, QuinnConnection},
+    };
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12349)?;
+    let config = ServerEndpointConfig::default()
+        .max_concurrent_calls(1)
+        .overload_policy(OverloadPolicy::Reject);
+    let endpoint =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_config(server, config)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(endpoint.clone())));
+    let client = RpcClient::<ComputeService, _>::new(QuinnConnection::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    ));
+
+    // occupy the only slot
+    let (mut send, mut recv) = client.bidi(Multiply(2)).await?;
+    send.send(MultiplyUpdate(3)).await?;
+    assert_eq!(recv.next().await.unwrap()?.0, 6);
+    let calls = endpoint.connection_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].in_flight, 1);
+
+    match client.rpc(Sqr(2)).await {
+        Err(RpcClientError::Send(e)) | Err(RpcClientError::RecvError(e)) => {
+            assert!(Overloaded::is_overloaded(&e), "unexpected error {e:?}");
+        }
+        res => panic!("unexpected result {res:?}"),
+    }
+
+    // once the call is done, the slot is free again
+    drop((send, recv));
+    let t0 = Instant::now();
+    while endpoint.connection_calls()[0].in_flight > 0 {
+        assert!(t0.elapsed() < Duration::from_secs(5));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    server_handle.abort();
+    Ok(())
+}
+
+/// the calls in flight of a connection are recorded if the endpoint opted in
+#[cfg(feature = "openmetrics")]
+#[tokio::test]
+async fn quinn_connection_metrics() -> anyhow::Result<()> {
+    use futures::{SinkExt, StreamExt};
+    use quic_rpc::{metrics, transport::quinn::QuinnConnection};
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12371)?;
+    let config = ServerEndpointConfig::default().connection_metrics(true);
+    let server_handle = run_server_with_config(server, config);
+    let client = RpcClient::<ComputeService, _>::new(QuinnConnection::new(
+        client,
+        server_addr,
+        "localhost".into(),
+    ));
+    let (mut send, mut recv) = client.bidi(Multiply(2)).await?;
+    send.send(MultiplyUpdate(3)).await?;
+    assert_eq!(recv.next().await.unwrap()?.0, 6);
+    let in_flight = metrics::encode()
+        .lines()
+        .filter(|line| line.starts_with("rpc_server_connection_in_flight{"))
+        .map(|line| line.rsplit(' ').next().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(in_flight, ["1"]);
+    drop((send, recv));
+    server_handle.abort();
+    Ok(())
+}
+
+#[tokio::test]
+async fn quinn_connection_stats() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::QuinnConnection;
+    tracing_subscriber::fmt::try_init().ok();
+    let Endpoints {
+        client,
+        server,
+        server_addr,
+    } = make_endpoints(12350)?;
+    let config = ServerEndpointConfig::default().stats_interval(Duration::from_millis(10));
+    let endpoint =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_config(server, config)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(endpoint.clone())));
+    let connection = QuinnConnection::new(client, server_addr, "localhost".into());
+    assert!(connection.stats().is_none());
+    let client = RpcClient::<ComputeService, _>::new(connection.clone());
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+
+    let stats = connection.stats().expect("connected");
+    assert_eq!(stats.remote_address, server_addr);
+    assert!(stats.sent_packets > 0);
+    assert!(stats.cwnd > 0);
+    assert!(stats.loss_rate() <= 1.0);
+    let stats = endpoint.connection_stats();
+    assert_eq!(stats.len(), 1);
+    assert!(stats[0].sent_packets > 0);
+    // give the sampler a chance to run
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    server_handle.abort();
+    Ok(())
+}
+
+/// a client given a hostname connects to the address it currently resolves to
+#[tokio::test]
+async fn quinn_resolving_failover() -> anyhow::Result<()> {
+    use futures::{future::BoxFuture, FutureExt};
+    use quic_rpc::transport::quinn::{QuinnConnection, Resolved, Resolver};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    #[derive(Debug, Clone)]
+    struct TestResolver {
+        addr: Arc<Mutex<SocketAddr>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Resolver for TestResolver {
+        fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, std::io::Result<Resolved>> {
+            assert_eq!((host, port), ("localhost", 4433));
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let addrs = vec![*self.addr.lock().unwrap()];
+            async move {
+                Ok(Resolved {
+                    addrs,
+                    ttl: Duration::ZERO,
+                })
+            }
+            .boxed()
+        }
+    }
+
+    tracing_subscriber::fmt::try_init().ok();
+    let addr_a: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12351));
+    let addr_b: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12352));
+    let (server_a, cert_a) = make_server_endpoint(addr_a)?;
+    let (server_b, cert_b) = make_server_endpoint(addr_b)?;
+    let client = make_client_endpoint("0.0.0.0:0".parse()?, &[&cert_a, &cert_b])?;
+    let handle_a = run_server(server_a.clone());
+    let handle_b = run_server(server_b);
+
+    let resolver = TestResolver {
+        addr: Arc::new(Mutex::new(addr_a)),
+        calls: Default::default(),
+    };
+    let connection = QuinnConnection::resolving(client, "localhost".into(), 4433, resolver.clone());
+    let client = RpcClient::<ComputeService, _>::new(connection.clone());
+    assert_eq!(client.rpc(Sqr(2)).await?.0, 4);
+    assert_eq!(
+        connection.stats().expect("connected").remote_address,
+        addr_a
+    );
+    assert_eq!(resolver.calls.load(Ordering::SeqCst), 1);
+
+    // move the server, the next reconnect resolves the new address
+    *resolver.addr.lock().unwrap() = addr_b;
+    server_a.close(0u32.into(), b"moved");
+    handle_a.abort();
+    let t0 = Instant::now();
+    loop {
+        assert!(t0.elapsed() < Duration::from_secs(5));
+        match client.rpc(Sqr(3)).await {
+            Ok(res) => {
+                assert_eq!(res.0, 9);
+                break;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+    assert_eq!(
+        connection.stats().expect("connected").remote_address,
+        addr_b
+    );
+    assert!(resolver.calls.load(Ordering::SeqCst) >= 2);
+    handle_b.abort();
+    Ok(())
+}
+
+/// a client given a discovery connects to one of its endpoints
+#[tokio::test]
+async fn quinn_discovering() -> anyhow::Result<()> {
+    use quic_rpc::{discovery::StaticDiscovery, transport::quinn::QuinnConnection};
+
+    tracing_subscriber::fmt::try_init().ok();
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12353));
+    let (server, cert) = make_server_endpoint(addr)?;
+    let client = make_client_endpoint("0.0.0.0:0".parse()?, &[&cert])?;
+    let handle = run_server(server);
+
+    let discovery = StaticDiscovery::new(vec![addr]);
+    let connection = QuinnConnection::discovering(client, "localhost".into(), discovery);
+    let client = RpcClient::<ComputeService, _>::new(connection.clone());
+    assert_eq!(client.rpc(Sqr(4)).await?.0, 16);
+    assert_eq!(connection.stats().expect("connected").remote_address, addr);
+    handle.abort();
+    Ok(())
+}
+
+/// handlers see the server name, application protocol and client certificate
+#[tokio::test]
+async fn quinn_connection_info() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::QuinnConnection;
+
+    tracing_subscriber::fmt::try_init().ok();
+    let server_cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let client_cert = rcgen::generate_simple_self_signed(vec!["tenant-a".into()])?;
+    let server_der = server_cert.serialize_der()?;
+    let client_der = client_cert.serialize_der()?;
+
+    // the server requires a client certificate, the self-signed one is its only root
+    let mut client_roots = rustls::RootCertStore::empty();
+    client_roots.add(&rustls::Certificate(client_der.clone()))?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(
+            client_roots,
+        ))
+        .with_single_cert(
+            vec![rustls::Certificate(server_der.clone())],
+            rustls::PrivateKey(server_cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![b"rpc/1".to_vec()];
+
+    let mut server_roots = rustls::RootCertStore::empty();
+    server_roots.add(&rustls::Certificate(server_der))?;
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(server_roots)
+        .with_single_cert(
+            vec![rustls::Certificate(client_der.clone())],
+            rustls::PrivateKey(client_cert.serialize_private_key_der()),
+        )?;
+    client_crypto.alpn_protocols = vec![b"rpc/1".to_vec()];
+
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12354));
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+    let mut client = Endpoint::client("0.0.0.0:0".parse()?)?;
+    client.set_default_client_config(ClientConfig::new(Arc::new(client_crypto)));
+
+    let handle = tokio::spawn(async move {
+        let server = RpcServer::<ComputeService, _>::new(QuinnServerEndpoint::new(server)?);
+        let (_, chan) = server.accept().await?;
+        anyhow::Ok(chan.recv.connection_info().cloned())
+    });
+    let client =
+        RpcClient::<ComputeService, _>::new(QuinnConnection::new(client, addr, "localhost".into()));
+    // the server does not answer, it only looks at the call
+    let _ = tokio::time::timeout(Duration::from_millis(500), client.rpc(Sqr(2))).await;
+    let info = handle.await??.expect("accepted on a quinn endpoint");
+    assert_eq!(info.server_name.as_deref(), Some("localhost"));
+    assert_eq!(info.alpn.as_deref(), Some(&b"rpc/1"[..]));
+    assert_eq!(info.peer_certificates, Some(vec![client_der]));
+    Ok(())
+}
+
+/// native clients and WebTransport sessions share one endpoint, routed by ALPN
+#[tokio::test]
+async fn quinn_web_transport_routing() -> anyhow::Result<()> {
+    use quic_rpc::transport::quinn::{get_handshake_data, QuinnConnection, H3_ALPN};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    tracing_subscriber::fmt::try_init().ok();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![b"rpc".to_vec(), H3_ALPN.to_vec()];
+    let client = |alpn: &[u8]| -> anyhow::Result<Endpoint> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert_der.clone()))?;
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![alpn.to_vec()];
+        let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        Ok(endpoint)
+    };
+
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12355));
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+    let (server, web_transport) =
+        QuinnServerEndpoint::with_web_transport(server, Default::default())?;
+    tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    // stands in for an HTTP/3 stack that establishes the sessions
+    let sessions = Arc::new(AtomicUsize::new(0));
+    tokio::spawn({
+        let sessions = sessions.clone();
+        async move {
+            while let Some(connection) = web_transport.accept().await {
+                let protocol = get_handshake_data(&connection).and_then(|h| h.protocol);
+                assert_eq!(protocol.as_deref(), Some(H3_ALPN));
+                while let Ok(stream) = connection.accept_bi().await {
+                    sessions.fetch_add(1, Ordering::SeqCst);
+                    web_transport.session_stream(stream).await;
+                }
+            }
+        }
+    });
+
+    let native = RpcClient::<ComputeService, _>::new(QuinnConnection::new(
+        client(b"rpc")?,
+        addr,
+        "localhost".into(),
+    ));
+    assert_eq!(native.rpc(Sqr(2)).await?.0, 4);
+    assert_eq!(sessions.load(Ordering::SeqCst), 0);
+
+    let browser = RpcClient::<ComputeService, _>::new(QuinnConnection::new(
+        client(H3_ALPN)?,
+        addr,
+        "localhost".into(),
+    ));
+    assert_eq!(browser.rpc(Sqr(3)).await?.0, 9);
+    assert_eq!(sessions.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+/// clients with different codecs are served by one endpoint, selected by ALPN
+#[tokio::test]
+async fn quinn_codec_per_connection() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Codec};
+
+    tracing_subscriber::fmt::try_init().ok();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![b"rpc/varint".to_vec(), b"rpc".to_vec()];
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12356));
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+    let config =
+        ServerEndpointConfig::default().codecs([(b"rpc/varint".to_vec(), Codec::BincodeVarint)]);
+    let server = QuinnServerEndpoint::with_config(server, config)?;
+    tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let client = |alpn: &[u8], codec: Codec| -> anyhow::Result<_> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&rustls::Certificate(cert_der.clone()))?;
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![alpn.to_vec()];
+        let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        let connection = QuinnConnection::new(endpoint, addr, "localhost".into());
+        Ok(RpcClient::<ComputeService, _>::new(
+            connection.with_codec(codec),
+        ))
+    };
+    let old = client(b"rpc", Codec::Bincode)?;
+    let new = client(b"rpc/varint", Codec::BincodeVarint)?;
+    assert_eq!(old.rpc(Sqr(2)).await?.0, 4);
+    assert_eq!(new.rpc(Sqr(3)).await?.0, 9);
+    assert_eq!(old.rpc(Sqr(4)).await?.0, 16);
+
+    // the server decodes with the codec of the protocol, not what the client sends
+    let mismatched = client(b"rpc/varint", Codec::Bincode)?;
+    assert!(mismatched.rpc(Sqr(5)).await.is_err());
+    Ok(())
+}
+
+/// A server endpoint at `addr` and a client endpoint that trusts it, both
+/// offering only the application protocol `alpn`
+fn alpn_endpoints(addr: SocketAddr, alpn: &[u8]) -> anyhow::Result<(Endpoint, Endpoint)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![alpn.to_vec()];
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(cert_der))?;
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![alpn.to_vec()];
+    let mut client = Endpoint::client("0.0.0.0:0".parse()?)?;
+    client.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+    Ok((server, client))
+}
+
+/// messages are encoded with the codec of the application, and a server
+/// without it can not decode them
+#[tokio::test]
+async fn quinn_custom_codec() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Codec, CustomCodec, MessageCodec};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::io;
+
+    /// bincode behind a version byte
+    #[derive(Debug, Clone)]
+    struct Versioned;
+
+    impl<T: Serialize + DeserializeOwned> MessageCodec<T> for Versioned {
+        fn encode(&self, item: &T) -> io::Result<Vec<u8>> {
+            let mut data = vec![1];
+            bincode::serialize_into(&mut data, item)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Ok(data)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> io::Result<T> {
+            match bytes.split_first() {
+                Some((1, rest)) => bincode::deserialize(rest)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown version",
+                )),
+            }
+        }
+    }
+
+    tracing_subscriber::fmt::try_init().ok();
+    let codec = Codec::Custom(CustomCodec::new().with_service::<ComputeService, _>(Versioned));
+    assert_eq!(codec, codec.clone());
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12361));
+    let (server, endpoint) = alpn_endpoints(addr, b"rpc/versioned")?;
+    let config =
+        ServerEndpointConfig::default().codecs([(b"rpc/versioned".to_vec(), codec.clone())]);
+    let server = QuinnServerEndpoint::with_config(server, config)?;
+    tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let client = |codec: Codec| {
+        let connection = QuinnConnection::new(endpoint.clone(), addr, "localhost".into());
+        RpcClient::<ComputeService, _>::new(connection.with_codec(codec))
+    };
+    let custom = client(codec);
+    assert_eq!(custom.rpc(Sqr(3)).await?.0, 9);
+    let bincode = client(Codec::Bincode);
+    assert!(bincode.rpc(Sqr(4)).await.is_err());
+    Ok(())
+}
+
+/// the math service works with postcard on both sides
+#[cfg(feature = "postcard-codec")]
+#[tokio::test]
+async fn quinn_postcard_smoke() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Codec};
+
+    tracing_subscriber::fmt::try_init().ok();
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12362));
+    let (server, client) = alpn_endpoints(addr, b"rpc/postcard")?;
+    let config =
+        ServerEndpointConfig::default().codecs([(b"rpc/postcard".to_vec(), Codec::Postcard)]);
+    let server = QuinnServerEndpoint::with_config(server, config)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let connection =
+        QuinnConnection::new(client, addr, "localhost".into()).with_codec(Codec::Postcard);
+    smoke_test(connection).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// the math service works with msgpack on both sides, and its messages are
+/// what other msgpack implementations produce
+#[cfg(feature = "msgpack-codec")]
+#[tokio::test]
+async fn quinn_msgpack_smoke() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Codec};
+
+    tracing_subscriber::fmt::try_init().ok();
+    // {"Sqr": 3}, as encoded by other implementations
+    let request: ComputeRequest = rmp_serde::from_slice(&[0x81, 0xa3, b'S', b'q', b'r', 0x03])?;
+    assert!(matches!(request, ComputeRequest::Sqr(Sqr(3))));
+
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12364));
+    let (server, client) = alpn_endpoints(addr, b"rpc/msgpack")?;
+    let config =
+        ServerEndpointConfig::default().codecs([(b"rpc/msgpack".to_vec(), Codec::MessagePack)]);
+    let server = QuinnServerEndpoint::with_config(server, config)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let connection =
+        QuinnConnection::new(client, addr, "localhost".into()).with_codec(Codec::MessagePack);
+    smoke_test(connection).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// the math service works with cbor on both sides
+#[cfg(feature = "cbor-codec")]
+#[tokio::test]
+async fn quinn_cbor_smoke() -> anyhow::Result<()> {
+    use quic_rpc::transport::{quinn::QuinnConnection, Codec};
+
+    tracing_subscriber::fmt::try_init().ok();
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12365));
+    let (server, client) = alpn_endpoints(addr, b"rpc/cbor")?;
+    let config = ServerEndpointConfig::default().codecs([(b"rpc/cbor".to_vec(), Codec::Cbor)]);
+    let server = QuinnServerEndpoint::with_config(server, config)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let connection = QuinnConnection::new(client, addr, "localhost".into()).with_codec(Codec::Cbor);
+    smoke_test(connection).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// deterministic cbor encodes equal maps to the same bytes, whatever the
+/// order of their entries
+#[cfg(feature = "cbor-codec")]
+#[test]
+fn cbor_deterministic() -> anyhow::Result<()> {
+    use quic_rpc::transport::Codec;
+    use std::collections::HashMap;
+
+    let forward = (0..100).map(|i| (format!("key{i}"), i)).collect::<Vec<_>>();
+    let a: HashMap<String, u32> = forward.iter().cloned().collect();
+    let b: HashMap<String, u32> = forward.into_iter().rev().collect();
+    let codec = Codec::CborDeterministic;
+    assert_eq!(codec.encode(&a)?, codec.encode(&b)?);
+
+    // shorter keys first, since their encoding starts with the length
+    let map: HashMap<&str, u8> = [("aa", 2), ("b", 1)].into_iter().collect();
+    assert_eq!(
+        &codec.encode(&vec![map])?[..],
+        [0x81, 0xa2, 0x61, b'b', 0x01, 0x62, b'a', b'a', 0x02]
+    );
+    Ok(())
+}
+
+/// prost messages go over the wire as protobuf, as defined in their .proto
+#[cfg(feature = "prost-codec")]
+#[tokio::test]
+async fn quinn_prost() -> anyhow::Result<()> {
+    use prost::Message;
+    use quic_rpc::{
+        declare_rpc,
+        transport::{quinn::QuinnConnection, Codec, CustomCodec, ProstCodec},
+        Service,
+    };
+    use serde::{Deserialize, Serialize};
+
+    // what prost-build generates for
+    //
+    // message Ping { uint64 value = 1; }
+    // message Pong { uint64 value = 1; }
+    // message Request { oneof kind { Ping ping = 1; } }
+    // message Response { oneof kind { Pong pong = 1; } }
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Ping {
+        #[prost(uint64, tag = "1")]
+        value: u64,
+    }
+
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Pong {
+        #[prost(uint64, tag = "1")]
+        value: u64,
+    }
+
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Request {
+        #[prost(oneof = "RequestKind", tags = "1")]
+        kind: Option<RequestKind>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof, Serialize, Deserialize)]
+    enum RequestKind {
+        #[prost(message, tag = "1")]
+        Ping(Ping),
+    }
+
+    #[derive(Clone, PartialEq, Message, Serialize, Deserialize)]
+    struct Response {
+        #[prost(oneof = "ResponseKind", tags = "1")]
+        kind: Option<ResponseKind>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Oneof, Serialize, Deserialize)]
+    enum ResponseKind {
+        #[prost(message, tag = "1")]
+        Pong(Pong),
+    }
+
+    impl From<Ping> for Request {
+        fn from(ping: Ping) -> Self {
+            Request {
+                kind: Some(RequestKind::Ping(ping)),
+            }
+        }
+    }
+
+    impl TryFrom<Request> for Ping {
+        type Error = Request;
+
+        fn try_from(req: Request) -> Result<Self, Request> {
+            match req.kind {
+                Some(RequestKind::Ping(ping)) => Ok(ping),
+                None => Err(req),
+            }
+        }
+    }
+
+    impl From<Pong> for Response {
+        fn from(pong: Pong) -> Self {
+            Response {
+                kind: Some(ResponseKind::Pong(pong)),
+            }
+        }
+    }
+
+    impl TryFrom<Response> for Pong {
+        type Error = Response;
+
+        fn try_from(res: Response) -> Result<Self, Response> {
+            match res.kind {
+                Some(ResponseKind::Pong(pong)) => Ok(pong),
+                None => Err(res),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct PingService;
+
+    impl Service for PingService {
+        type Req = Request;
+        type Res = Response;
+    }
+
+    declare_rpc!(PingService, Ping, Pong);
+
+    tracing_subscriber::fmt::try_init().ok();
+    let codec = Codec::Custom(CustomCodec::new().with_service::<PingService, _>(ProstCodec));
+    let request = Request::from(Ping { value: 3 });
+    assert_eq!(codec.encode(&request)?, request.encode_to_vec());
+
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12368));
+    let (server, client) = alpn_endpoints(addr, b"rpc/proto")?;
+    let config = ServerEndpointConfig::default().codecs([(b"rpc/proto".to_vec(), codec.clone())]);
+    let server =
+        RpcServer::<PingService, _>::new(QuinnServerEndpoint::with_config(server, config)?);
+    let server_handle = tokio::spawn(async move {
+        while let Ok((req, chan)) = server.accept().await {
+            let ping = Ping::try_from(req).map_err(|req| anyhow::anyhow!("{req:?}"))?;
+            chan.rpc(ping, (), |(), ping| async move {
+                Pong {
+                    value: ping.value + 1,
+                }
+            })
+            .await?;
+        }
+        anyhow::Ok(())
+    });
+    let connection = QuinnConnection::new(client, addr, "localhost".into()).with_codec(codec);
+    let client = RpcClient::<PingService, _>::new(connection);
+    assert_eq!(client.rpc(Ping { value: 3 }).await?, Pong { value: 4 });
+    server_handle.abort();
+    Ok(())
+}
+
+/// a reconnecting client reports its attempts, the delays between them and
+/// the state of its connection
+#[tokio::test]
+async fn quinn_connection_events() -> anyhow::Result<()> {
+    use futures::{Stream, StreamExt};
+    use quic_rpc::{
+        discovery::StaticDiscovery,
+        transport::quinn::{ConnectionEvent, QuinnConnection},
+    };
+
+    async fn next(
+        events: &mut (impl Stream<Item = ConnectionEvent> + Unpin),
+    ) -> anyhow::Result<Option<ConnectionEvent>> {
+        Ok(tokio::time::timeout(Duration::from_secs(5), events.next()).await?)
+    }
+
+    tracing_subscriber::fmt::try_init().ok();
+    let addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 12360));
+    let (server, cert) = make_server_endpoint(addr)?;
+    let client = make_client_endpoint("0.0.0.0:0".parse()?, &[&cert])?;
+    let handle = run_server(server.clone());
+
+    // no server is known at first, so resolving fails and is retried
+    let discovery = StaticDiscovery::new(vec![]);
+    let connection = QuinnConnection::discovering(client, "localhost".into(), discovery.clone());
+    let mut events = connection.events();
+    for delay in [100, 200] {
+        assert!(matches!(
+            next(&mut events).await?,
+            Some(ConnectionEvent::Failed { addr: None, .. })
+        ));
+        assert_eq!(
+            next(&mut events).await?,
+            Some(ConnectionEvent::Backoff {
+                delay: Duration::from_millis(delay)
+            })
+        );
+    }
+    discovery.set(vec![addr]);
+    loop {
+        match next(&mut events).await? {
+            Some(ConnectionEvent::Connecting { addr: a }) => {
+                assert_eq!(a, addr);
+                break;
+            }
+            Some(ConnectionEvent::Failed { addr: None, .. })
+            | Some(ConnectionEvent::Backoff { .. }) => {}
+            other => panic!("unexpected event {other:?}"),
+        }
+    }
+    assert_eq!(
+        next(&mut events).await?,
+        Some(ConnectionEvent::Connected { addr })
+    );
+    let rpc = RpcClient::<ComputeService, _>::new(connection.clone());
+    assert_eq!(rpc.rpc(Sqr(5)).await?.0, 25);
+
+    // the connection is lost without any call being made
+    server.close(0u32.into(), b"going away");
+    handle.abort();
+    assert!(matches!(
+        next(&mut events).await?,
+        Some(ConnectionEvent::Disconnected { .. })
+    ));
+
+    // the stream ends with the connection
+    drop(rpc);
+    drop(connection);
+    assert_eq!(next(&mut events).await?, None);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+#![cfg(feature = "s2n-quic-transport")]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        s2n_quic::{S2nQuicConnection, S2nQuicServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+/// A server listening on localhost with a self signed certificate, and a
+/// client that trusts it
+fn make_endpoints() -> anyhow::Result<(s2n_quic::Server, s2n_quic::Client)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_pem = cert.serialize_pem()?;
+    let key_pem = cert.serialize_private_key_pem();
+    let server = s2n_quic::Server::builder()
+        .with_tls((cert_pem.as_str(), key_pem.as_str()))?
+        .with_io("127.0.0.1:0")?
+        .start()?;
+    let client = s2n_quic::Client::builder()
+        .with_tls(cert_pem.as_str())?
+        .with_io("0.0.0.0:0")?
+        .start()?;
+    Ok((server, client))
+}
+
+/// all 4 patterns work on the streams of a s2n-quic connection
+#[tokio::test]
+async fn s2n_quic_channel_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, client) = make_endpoints()?;
+    let server = S2nQuicServerEndpoint::<ComputeRequest, ComputeResponse>::new(server)?;
+    let addr = match server.local_addr() {
+        [LocalAddr::Socket(addr)] => *addr,
+        other => panic!("unexpected local addr {other:?}"),
+    };
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let client =
+        S2nQuicConnection::<ComputeResponse, ComputeRequest>::new(client, addr, "localhost".into());
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,66 @@
+#![cfg(all(feature = "flume-transport", feature = "payload-sampling"))]
+mod math;
+use futures::StreamExt;
+use math::*;
+use quic_rpc::{sampling, transport::flume, RpcClient, RpcServer};
+
+/// a fraction of the client calls is recorded, with their serialized payloads
+#[tokio::test]
+async fn sampling_client_calls() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+
+    // off by default
+    client.rpc(Sqr(2)).await?;
+    assert!(sampling::samples().is_empty());
+
+    // every other call
+    sampling::configure(0.5, 8);
+    for i in 0..4 {
+        client.rpc(Sqr(i)).await?;
+    }
+    let samples = sampling::samples();
+    assert_eq!(samples.len(), 2);
+    for (sample, i) in samples.iter().zip([1, 3]) {
+        assert_eq!(sample.method, "Sqr");
+        assert!(sample.service.ends_with("ComputeService"));
+        let request: ComputeRequest = bincode::deserialize(&sample.request)?;
+        assert!(matches!(request, ComputeRequest::Sqr(Sqr(n)) if n == i));
+        assert_eq!(sample.responses.len(), 1);
+        let response: ComputeResponse = bincode::deserialize(&sample.responses[0])?;
+        assert!(
+            matches!(response, ComputeResponse::SqrResponse(SqrResponse(n)) if n == (i * i) as u128)
+        );
+    }
+
+    // all responses of a stream are recorded once the stream is dropped
+    sampling::clear();
+    sampling::configure(1.0, 8);
+    let items = client.server_streaming(Fibonacci(5)).await?;
+    assert_eq!(items.collect::<Vec<_>>().await.len(), 5);
+    let samples = sampling::samples();
+    assert_eq!(samples.len(), 1);
+    assert_eq!(samples[0].method, "Fibonacci");
+    assert_eq!(samples[0].responses.len(), 5);
+    let text = sampling::encode();
+    assert!(text.starts_with("sample "));
+    assert_eq!(
+        text.lines().filter(|l| l.starts_with("response ")).count(),
+        5
+    );
+
+    // only the most recent samples are kept
+    sampling::configure(1.0, 2);
+    for i in 0..3 {
+        client.rpc(Sqr(i)).await?;
+    }
+    let samples = sampling::samples();
+    assert_eq!(samples.len(), 2);
+    let last: ComputeRequest = bincode::deserialize(&samples[1].request)?;
+    assert!(matches!(last, ComputeRequest::Sqr(Sqr(2))));
+
+    server_handle.abort();
+    Ok(())
+}
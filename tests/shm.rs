@@ -0,0 +1,83 @@
+#![cfg(all(target_os = "linux", feature = "shm-transport", feature = "macros"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    server::RpcServerError,
+    transport::shm::{OpenError, ShmChannel, ShmConnection, ShmServerEndpoint},
+    RpcClient, RpcServer,
+};
+use std::os::unix::io::OwnedFd;
+use tokio::task::JoinHandle;
+
+type Client = ShmConnection<ComputeResponse, ComputeRequest>;
+type Server = ShmServerEndpoint<ComputeRequest, ComputeResponse>;
+
+fn spawn_server(channel: &ShmChannel) -> anyhow::Result<JoinHandle<()>> {
+    let server = RpcServer::<ComputeService, _>::new(Server::new(channel)?);
+    Ok(tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    }))
+}
+
+/// open the channel again from its file descriptors, like another process would
+fn reopen(channel: &ShmChannel) -> anyhow::Result<ShmChannel> {
+    let fds = channel
+        .fds()
+        .map(|fd| fd.try_clone_to_owned())
+        .into_iter()
+        .collect::<std::io::Result<Vec<OwnedFd>>>()?;
+    let fds: [OwnedFd; 5] = fds.try_into().expect("5 fds");
+    // SAFETY: these are the fds of a channel, in order
+    Ok(unsafe { ShmChannel::from_fds(fds)? })
+}
+
+/// all 4 patterns work over shared memory
+#[tokio::test]
+async fn shm_channel_smoke() -> anyhow::Result<()> {
+    let channel = ShmChannel::new(64 * 1024)?;
+    let server_handle = spawn_server(&channel)?;
+    smoke_test(Client::new(&channel)?).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// both sides can use their own mapping, and messages larger than the rings
+/// are passed through them in parts
+#[tokio::test]
+async fn shm_channel_separate_mappings() -> anyhow::Result<()> {
+    let channel = ShmChannel::new(7)?;
+    let other = reopen(&channel)?;
+    assert_eq!(other.capacity(), 7);
+    let server_handle = spawn_server(&other)?;
+    drop(other);
+    smoke_test(Client::new(&channel)?).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// the server stops accepting once the client is gone
+#[tokio::test]
+async fn shm_channel_client_closed() -> anyhow::Result<()> {
+    let channel = ShmChannel::new(1024)?;
+    let server = RpcServer::<ComputeService, _>::new(Server::new(&channel)?);
+    let server_handle = tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(Client::new(&channel)?);
+    assert_eq!(client.rpc(Sqr(3)).await?, SqrResponse(9));
+    drop(client);
+    match server_handle.await? {
+        Err(RpcServerError::Accept(OpenError::Closed)) => {}
+        other => panic!("unexpected termination result {other:?}"),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn shm_channel_bench() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let channel = ShmChannel::new(1 << 20)?;
+    let server_handle = spawn_server(&channel)?;
+    let client = RpcClient::<ComputeService, _>::new(Client::new(&channel)?);
+    bench(client, 50000).await?;
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,97 @@
+#![cfg(all(feature = "flume-transport", feature = "signing"))]
+mod math;
+use ed25519_dalek::SigningKey;
+use math::*;
+use quic_rpc::{
+    server::RpcServerError,
+    transport::{
+        flume,
+        signed::{RecvError, Signed, SignedConnection, SignedServerEndpoint, SigningConfig},
+    },
+    RpcClient, RpcServer,
+};
+
+type Server = SignedServerEndpoint<
+    flume::FlumeServerEndpoint<Signed<ComputeRequest>, Signed<ComputeResponse>>,
+    ComputeRequest,
+    ComputeResponse,
+>;
+
+type Client = SignedConnection<
+    flume::FlumeConnection<Signed<ComputeResponse>, Signed<ComputeRequest>>,
+    ComputeResponse,
+    ComputeRequest,
+>;
+
+fn signed_connection(server: SigningConfig, client: SigningConfig) -> (Server, Client) {
+    let (server_conn, client_conn) =
+        flume::connection::<Signed<ComputeRequest>, Signed<ComputeResponse>>(1);
+    (
+        SignedServerEndpoint::new(server_conn, server),
+        SignedConnection::new(client_conn, client),
+    )
+}
+
+/// all 4 patterns work over a signed connection
+#[tokio::test]
+async fn signed_channel_smoke() -> anyhow::Result<()> {
+    let server_key = SigningKey::from_bytes(&[1; 32]);
+    let client_key = SigningKey::from_bytes(&[2; 32]);
+    let server_config = SigningConfig::new(server_key.clone()).trust(client_key.verifying_key());
+    let client_config = SigningConfig::new(client_key).trust(server_key.verifying_key());
+    let (server, client) = signed_connection(server_config, client_config);
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(ComputeService::server(server));
+    smoke_test(client).await?;
+
+    match server_handle.await? {
+        Err(RpcServerError::Accept(_)) => {}
+        e => panic!("unexpected termination result {e:?}"),
+    }
+    Ok(())
+}
+
+/// handlers see the key of the client
+#[tokio::test]
+async fn signed_channel_signer() -> anyhow::Result<()> {
+    let client_key = SigningKey::from_bytes(&[2; 32]);
+    let expected = client_key.verifying_key();
+    let (server, client) = signed_connection(
+        SigningConfig::new(SigningKey::from_bytes(&[1; 32])),
+        SigningConfig::new(client_key),
+    );
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move {
+        let (req, chan) = server.accept().await?;
+        assert_eq!(chan.recv.signer(), Some(expected));
+        ComputeService::dispatch(chan, req, ComputeService).await
+    });
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(4)).await?, SqrResponse(16));
+    server_handle.await??;
+    Ok(())
+}
+
+/// requests signed by keys that are not trusted are rejected
+#[tokio::test]
+async fn signed_channel_untrusted() -> anyhow::Result<()> {
+    let trusted = SigningKey::from_bytes(&[3; 32]).verifying_key();
+    let client_key = SigningKey::from_bytes(&[2; 32]);
+    let untrusted = client_key.verifying_key();
+    let (server, client) = signed_connection(
+        SigningConfig::new(SigningKey::from_bytes(&[1; 32])).trust(trusted),
+        SigningConfig::new(client_key),
+    );
+
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::task::spawn(async move { server.accept().await.map(|_| ()) });
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert!(client.rpc(Sqr(4)).await.is_err());
+    match server_handle.await? {
+        Err(RpcServerError::RecvError(RecvError::Untrusted(key))) => assert_eq!(key, untrusted),
+        e => panic!("unexpected result {e:?}"),
+    }
+    Ok(())
+}
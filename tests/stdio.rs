@@ -0,0 +1,58 @@
+#![cfg(all(feature = "stdio-transport", feature = "macros"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        stdio::{OpenError, StdioConnection, StdioServerEndpoint},
+        ServerEndpoint,
+    },
+    RpcServer,
+};
+use tokio::io::{duplex, split};
+
+/// A connection and a server endpoint on the two ends of in-memory pipes
+fn pipes() -> (
+    StdioConnection<ComputeResponse, ComputeRequest>,
+    StdioServerEndpoint<ComputeRequest, ComputeResponse>,
+) {
+    let (client, server) = duplex(64 * 1024);
+    let (read, write) = split(client);
+    let client = StdioConnection::new(read, write);
+    let (read, write) = split(server);
+    let server = StdioServerEndpoint::new(read, write);
+    (client, server)
+}
+
+/// all 4 patterns work over a pair of pipes
+#[tokio::test]
+async fn stdio_channel_smoke() -> anyhow::Result<()> {
+    let (client, server) = pipes();
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// the server stops accepting once the client is gone
+#[tokio::test]
+async fn stdio_client_closed() -> anyhow::Result<()> {
+    let (client, server) = pipes();
+    drop(client);
+    let res = server.accept_bi().await;
+    assert!(matches!(res, Err(OpenError::Closed)));
+    Ok(())
+}
+
+/// a child process without piped stdio can not be called
+#[cfg(unix)]
+#[tokio::test]
+async fn stdio_child_not_piped() -> anyhow::Result<()> {
+    let mut child = tokio::process::Command::new("true").spawn()?;
+    let res = StdioConnection::<ComputeResponse, ComputeRequest>::from_child(&mut child);
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    child.wait().await?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+#![cfg(all(feature = "stream-transport", feature = "macros"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        stream::{OpenError, StreamConnection, StreamServerEndpoint},
+        ServerEndpoint,
+    },
+    RpcServer,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+fn serve(
+    server: StreamServerEndpoint<ComputeRequest, ComputeResponse>,
+) -> tokio::task::JoinHandle<()> {
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    })
+}
+
+/// all 4 patterns work over an in-memory duplex stream
+#[tokio::test]
+async fn stream_channel_smoke() -> anyhow::Result<()> {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let server_handle = serve(StreamServerEndpoint::new(server));
+    smoke_test(StreamConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+    ))
+    .await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// any byte stream works, here a tcp connection the caller established
+#[tokio::test]
+async fn stream_channel_tcp() -> anyhow::Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let (client, server) = tokio::try_join!(TcpStream::connect(listener.local_addr()?), async {
+        let (stream, _) = listener.accept().await?;
+        Ok(stream)
+    })?;
+    let server_handle = serve(StreamServerEndpoint::new(server));
+    smoke_test(StreamConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+    ))
+    .await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// the server stops accepting once the client is gone
+#[tokio::test]
+async fn stream_client_closed() -> anyhow::Result<()> {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let server = StreamServerEndpoint::<ComputeRequest, ComputeResponse>::new(server);
+    drop(StreamConnection::<ComputeResponse, ComputeRequest>::new(
+        client,
+    ));
+    let res = server.accept_bi().await;
+    assert!(matches!(res, Err(OpenError::Closed)));
+    Ok(())
+}
@@ -0,0 +1,306 @@
+#![cfg(all(feature = "flume-transport", feature = "session-persistence"))]
+use derive_more::{From, TryInto};
+use futures::{stream, Stream, StreamExt};
+use quic_rpc::{
+    declare_server_streaming,
+    server::RpcServerError,
+    subscription::{
+        Drain, EncryptedStore, FileStore, MemStore, Redirect, Resumable, SessionStore,
+        SubscriptionClient,
+    },
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+/// subscribe to a range of events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Subscribe {
+    from: u64,
+    to: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Event(u64);
+
+/// follow events, on whatever instance has them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Follow {
+    from: u64,
+    token: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum FollowUpdate {
+    Event(String, u64),
+    Moved(Redirect),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum EventRequest {
+    Subscribe(Subscribe),
+    Follow(Follow),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum EventResponse {
+    Event(Event),
+    FollowUpdate(FollowUpdate),
+}
+
+#[derive(Debug, Clone)]
+struct EventService;
+
+impl Service for EventService {
+    type Req = EventRequest;
+    type Res = EventResponse;
+}
+
+declare_server_streaming!(EventService, Subscribe, Event);
+declare_server_streaming!(EventService, Follow, FollowUpdate);
+
+impl Resumable<EventService> for Subscribe {
+    fn advance(&mut self, response: &Event) {
+        self.from = response.0 + 1;
+    }
+}
+
+impl Resumable<EventService> for Follow {
+    fn advance(&mut self, response: &FollowUpdate) {
+        if let FollowUpdate::Event(_, n) = response {
+            self.from = n + 1;
+        }
+    }
+
+    fn redirect(response: &FollowUpdate) -> Option<Redirect> {
+        match response {
+            FollowUpdate::Moved(redirect) => Some(redirect.clone()),
+            FollowUpdate::Event(..) => None,
+        }
+    }
+
+    fn redirected(&mut self, token: &[u8]) {
+        self.token = token.to_vec();
+    }
+}
+
+/// A server instance for follow calls, with the events it has
+#[derive(Debug, Clone)]
+struct Instance {
+    name: &'static str,
+    events: u64,
+    /// keep follow calls open after the last event
+    hold: bool,
+    drain: Drain,
+}
+
+impl Instance {
+    fn follow(self, req: Follow) -> impl Stream<Item = FollowUpdate> {
+        let name = self.name;
+        // only accept redirected calls that were handed over properly
+        let events = if req.from == 0 || req.token == name.as_bytes() {
+            req.from..req.from + self.events
+        } else {
+            0..0
+        };
+        let events = stream::iter(events).map(move |n| FollowUpdate::Event(name.into(), n));
+        let events = if self.hold {
+            events.chain(stream::pending()).boxed()
+        } else {
+            events.boxed()
+        };
+        self.drain.wrap(events, move |endpoint| {
+            FollowUpdate::Moved(Redirect {
+                token: endpoint.clone().into_bytes(),
+                endpoint,
+            })
+        })
+    }
+
+    async fn server<C: ServiceEndpoint<EventService>>(
+        self,
+        server: RpcServer<EventService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            let instance = self.clone();
+            tokio::spawn(async move {
+                match req {
+                    EventRequest::Follow(msg) => {
+                        chan.server_streaming(msg, instance, Instance::follow).await
+                    }
+                    EventRequest::Subscribe(_) => Err(RpcServerError::UnexpectedStartMessage),
+                }
+            });
+        }
+    }
+}
+
+impl EventService {
+    fn subscribe(self, req: Subscribe) -> impl Stream<Item = Event> {
+        stream::iter(req.from..req.to).map(Event)
+    }
+
+    async fn server<C: ServiceEndpoint<EventService>>(
+        server: RpcServer<EventService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            tokio::spawn(async move {
+                match req {
+                    EventRequest::Subscribe(msg) => {
+                        chan.server_streaming(msg, EventService, EventService::subscribe)
+                            .await
+                    }
+                    EventRequest::Follow(_) => Err(RpcServerError::UnexpectedStartMessage),
+                }
+            });
+        }
+    }
+}
+
+const KEY: [u8; 32] = [7; 32];
+
+#[tokio::test]
+async fn subscription_resume_after_restart() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<EventRequest, EventResponse>(1);
+    let server = RpcServer::<EventService, _>::new(server);
+    tokio::spawn(EventService::server(server));
+    let mem = MemStore::new();
+
+    let client = RpcClient::<EventService, _>::new(client);
+    {
+        let subs = SubscriptionClient::new(client.clone(), EncryptedStore::new(mem.clone(), KEY));
+        let mut events = subs.subscribe("a", Subscribe { from: 0, to: 5 }).await?;
+        assert_eq!(events.next().await.transpose()?, Some(Event(0)));
+        assert_eq!(events.next().await.transpose()?, Some(Event(1)));
+        // simulate a restart by dropping the stream and the client
+    }
+
+    // the state is encrypted at rest
+    assert!(EncryptedStore::new(mem.clone(), [8; 32])
+        .load()
+        .await
+        .is_err());
+
+    let subs = SubscriptionClient::new(client, EncryptedStore::new(mem.clone(), KEY));
+    assert_eq!(subs.subscriptions().await?, vec!["a".to_string()]);
+    let mut resumed = subs.resume::<Subscribe>().await?;
+    assert_eq!(resumed.len(), 1);
+    let (id, events) = resumed.remove(0);
+    assert_eq!(id, "a");
+    let events = events.collect::<Vec<_>>().await;
+    let events = events.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(events, vec![Event(2), Event(3), Event(4)]);
+    // the server ended the subscription, so it is no longer persisted
+    assert!(subs.subscriptions().await?.is_empty());
+    Ok(())
+}
+
+/// an entry that can not be decoded does not keep the others from resuming
+#[tokio::test]
+async fn subscription_resume_skips_undecodable() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<EventRequest, EventResponse>(1);
+    let server = RpcServer::<EventService, _>::new(server);
+    tokio::spawn(EventService::server(server));
+    let mem = MemStore::new();
+    mem.save("broken", vec![0xff; 3]).await?;
+
+    let subs = SubscriptionClient::new(RpcClient::<EventService, _>::new(client), mem);
+    drop(subs.subscribe("a", Subscribe { from: 0, to: 0 }).await?);
+    let mut resumed = subs.resume::<Subscribe>().await?;
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed.remove(0).0, "a");
+    // the broken entry is left in the store
+    assert!(subs.subscriptions().await?.contains(&"broken".to_string()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscription_file_store() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("quic-rpc-file-store-{}", std::process::id()));
+    let store = FileStore::new(&dir);
+    assert!(store.load().await?.is_empty());
+    store.save("a/b", vec![1, 2, 3]).await?;
+    store.save("c", vec![4]).await?;
+    store.save("c", vec![5]).await?;
+    assert_eq!(
+        store.load().await?,
+        vec![
+            ("a/b".to_string(), vec![1, 2, 3]),
+            ("c".to_string(), vec![5])
+        ]
+    );
+    store.remove("a/b").await?;
+    store.remove("missing").await?;
+    assert_eq!(store.load().await?, vec![("c".to_string(), vec![5])]);
+    std::fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+/// subscriptions of a draining instance move to the instance it redirects to
+#[tokio::test]
+async fn subscription_redirect() -> anyhow::Result<()> {
+    let spawn = |instance: Instance| {
+        let (server, client) = flume::connection::<EventRequest, EventResponse>(1);
+        tokio::spawn(instance.server(RpcServer::new(server)));
+        RpcClient::<EventService, _>::new(client)
+    };
+    let drain = Drain::new();
+    let a = spawn(Instance {
+        name: "a",
+        events: 2,
+        hold: true,
+        drain: drain.clone(),
+    });
+    let b = spawn(Instance {
+        name: "b",
+        events: 3,
+        hold: false,
+        drain: Drain::new(),
+    });
+    let subs = SubscriptionClient::new(a, MemStore::new()).with_redirects(move |endpoint| {
+        let b = b.clone();
+        async move {
+            assert_eq!(endpoint, "b");
+            Ok(b)
+        }
+    });
+
+    let mut events = subs
+        .subscribe(
+            "f",
+            Follow {
+                from: 0,
+                token: vec![],
+            },
+        )
+        .await?;
+    let event = |name: &str, n| Some(FollowUpdate::Event(name.into(), n));
+    assert_eq!(events.next().await.transpose()?, event("a", 0));
+    assert_eq!(events.next().await.transpose()?, event("a", 1));
+
+    // the subscription continues on b, without seeing the redirect
+    drain.start("b");
+    assert_eq!(drain.endpoint().as_deref(), Some("b"));
+    let rest = events.collect::<Vec<_>>().await;
+    let rest = rest.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        rest.into_iter().map(Some).collect::<Vec<_>>(),
+        [event("b", 2), event("b", 3), event("b", 4)]
+    );
+    assert!(subs.subscriptions().await?.is_empty());
+
+    // new subscriptions go to b right away
+    let mut events = subs
+        .subscribe(
+            "g",
+            Follow {
+                from: 0,
+                token: vec![],
+            },
+        )
+        .await?;
+    assert_eq!(events.next().await.transpose()?, event("b", 0));
+    Ok(())
+}
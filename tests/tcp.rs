@@ -0,0 +1,129 @@
+#![cfg(feature = "tcp-transport")]
+mod math;
+use math::*;
+use quic_rpc::{
+    strict::{Downgrade, Requirements},
+    transport::{
+        tcp::{TcpConnection, TcpServerEndpoint},
+        Capabilities, LocalAddr,
+    },
+    RpcClient, RpcServer,
+};
+
+async fn spawn_server() -> anyhow::Result<(std::net::SocketAddr, tokio::task::JoinHandle<()>)> {
+    let server = TcpServerEndpoint::<ComputeRequest, ComputeResponse>::bind("127.0.0.1:0").await?;
+    let addr = match quic_rpc::transport::ServerEndpoint::local_addr(&server) {
+        [LocalAddr::Socket(addr)] => *addr,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    Ok((addr, handle))
+}
+
+/// all 4 patterns work over plain tcp
+#[tokio::test]
+async fn tcp_channel_smoke() -> anyhow::Result<()> {
+    let (addr, server_handle) = spawn_server().await?;
+    let client = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// both sides can use a tcp connection that was established elsewhere, and
+/// the server stops accepting channels once it closes
+#[tokio::test]
+async fn tcp_from_stream() -> anyhow::Result<()> {
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let (client, server) = tokio::join!(
+        TcpStream::connect(listener.local_addr()?),
+        listener.accept()
+    );
+    let server = TcpServerEndpoint::<ComputeRequest, ComputeResponse>::from_stream(server?.0)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let client = TcpConnection::<ComputeResponse, ComputeRequest>::from_stream(client?)?;
+    smoke_test(client).await?;
+    let res = tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    assert!(res.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn tcp_channel_bench() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (addr, server_handle) = spawn_server().await?;
+    let client = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    bench(client, 50000).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// concurrent calls share a connection, and calls of many connections are served
+#[tokio::test]
+async fn tcp_channel_many_calls() -> anyhow::Result<()> {
+    let (addr, server_handle) = spawn_server().await?;
+    let mut calls = Vec::new();
+    for i in 0..4u64 {
+        let client = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+        let client = RpcClient::<ComputeService, _>::new(client);
+        for j in 0..4u64 {
+            let client = client.clone();
+            calls.push(tokio::spawn(async move {
+                for k in 0..10u64 {
+                    let n = i * 100 + j * 10 + k;
+                    assert_eq!(client.rpc(Sqr(n)).await?, SqrResponse((n * n) as u128));
+                }
+                anyhow::Ok(())
+            }));
+        }
+    }
+    for call in calls {
+        call.await??;
+    }
+    server_handle.abort();
+    Ok(())
+}
+
+/// strict mode refuses plain tcp when encryption is required
+#[tokio::test]
+async fn tcp_strict_encryption() -> anyhow::Result<()> {
+    let (addr, server_handle) = spawn_server().await?;
+    let client = TcpConnection::<ComputeResponse, ComputeRequest>::connect(addr).await?;
+    let ordered = Capabilities {
+        ordered: true,
+        ..Capabilities::NONE
+    };
+    Requirements::new()
+        .with_capabilities(ordered)
+        .check(&client)?;
+
+    let encrypted = Capabilities {
+        encrypted: true,
+        ..Capabilities::ALL
+    };
+    let err = Requirements::new()
+        .with_capabilities(encrypted)
+        .check(&client)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        Downgrade::Capabilities {
+            missing: Capabilities {
+                encrypted: true,
+                ..Capabilities::NONE
+            }
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "the transport is not encrypted, which is required"
+    );
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,53 @@
+#![cfg(any(
+    feature = "flume-transport",
+    feature = "hyper-transport",
+    feature = "quinn-transport"
+))]
+mod math;
+use math::*;
+use quic_rpc::{
+    server::RpcServerError,
+    testing::{HarnessError, TestHarness},
+};
+
+/// call all 4 patterns directly, without a transport
+#[tokio::test]
+async fn harness_smoke() -> anyhow::Result<()> {
+    let harness = TestHarness::new(ComputeService, ComputeService::dispatch);
+
+    assert_eq!(harness.rpc(Sqr(1234)).await?, SqrResponse(1522756));
+
+    let res = harness
+        .client_streaming(Sum, (1..=3).map(SumUpdate))
+        .await?;
+    assert_eq!(res, SumResponse(6));
+
+    let mut call = harness.server_streaming(Fibonacci(4));
+    call.assert_next(FibonacciResponse(0)).await;
+    call.assert_next(FibonacciResponse(1)).await;
+    assert_eq!(
+        call.collect().await?,
+        vec![FibonacciResponse(1), FibonacciResponse(2)]
+    );
+
+    let mut call = harness.bidi(Multiply(2));
+    call.send(MultiplyUpdate(3))?;
+    call.assert_next(MultiplyResponse(6)).await;
+    call.send(MultiplyUpdate(5))?;
+    call.assert_next(MultiplyResponse(10)).await;
+    call.close_updates();
+    call.assert_done().await;
+    Ok(())
+}
+
+/// untyped calls surface handler errors
+#[tokio::test]
+async fn harness_unexpected_start_message() -> anyhow::Result<()> {
+    let harness = TestHarness::new(ComputeService, ComputeService::dispatch);
+    let mut call = harness.start(SumUpdate(1).into());
+    match call.next().await {
+        Err(HarnessError::Handler(RpcServerError::UnexpectedStartMessage)) => {}
+        res => panic!("unexpected result {res:?}"),
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+#![cfg(feature = "flume-transport")]
+use quic_rpc::{transport::flume, RpcClient, RpcServer};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+mod math;
+use math::*;
+
+/// Names of all spans, and the timings recorded on server call spans
+#[derive(Debug, Default)]
+struct Recorded {
+    spans: Vec<&'static str>,
+    timings: BTreeMap<&'static str, u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Recorder(Arc<Mutex<Recorded>>);
+
+impl Visit for Recorder {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name().ends_with("_us") {
+            self.0.lock().unwrap().timings.insert(field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, _id: &span::Id, _ctx: Context<'_, S>) {
+        self.0.lock().unwrap().spans.push(attrs.metadata().name());
+    }
+
+    fn on_record(&self, _id: &span::Id, values: &span::Record<'_>, _ctx: Context<'_, S>) {
+        values.record(&mut self.clone());
+    }
+}
+
+/// rpc calls record the timing of each phase, and run them in child spans
+#[tokio::test(flavor = "current_thread")]
+async fn timings_rpc() -> anyhow::Result<()> {
+    let recorder = Recorder::default();
+    let _guard = tracing_subscriber::registry()
+        .with(recorder.clone())
+        .set_default();
+
+    let (server, client) = flume::connection::<ComputeRequest, ComputeResponse>(1);
+    let server = RpcServer::<ComputeService, _>::new(server);
+    tokio::spawn(ComputeService::server(server));
+    let client = RpcClient::<ComputeService, _>::new(client);
+    assert_eq!(client.rpc(Sqr(3)).await?.0, 9);
+
+    let recorded = recorder.0.lock().unwrap();
+    for name in ["rpc.handler", "rpc.encode", "rpc.send"] {
+        assert!(recorded.spans.contains(&name), "missing span {name}");
+    }
+    let fields = recorded.timings.keys().copied().collect::<Vec<_>>();
+    assert_eq!(
+        fields,
+        [
+            "rpc.decode_us",
+            "rpc.encode_us",
+            "rpc.handler_us",
+            "rpc.queue_us",
+            "rpc.send_us"
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,109 @@
+#![cfg(all(feature = "tls-tcp-transport", feature = "macros"))]
+mod math;
+use futures::{StreamExt, TryStreamExt};
+use math::*;
+use quic_rpc::{
+    transport::{
+        tls_tcp::{TlsTcpConnection, TlsTcpServerEndpoint},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcClient, RpcServer,
+};
+use std::{net::SocketAddr, sync::Arc};
+
+type Client = TlsTcpConnection<ComputeResponse, ComputeRequest>;
+
+/// A server config with a self signed certificate for localhost, and its certificate
+fn configure_server() -> anyhow::Result<(rustls::ServerConfig, Vec<u8>)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    Ok((config, cert_der))
+}
+
+fn configure_client(server_cert: &[u8]) -> anyhow::Result<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(server_cert.to_vec()))?;
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+async fn spawn_server() -> anyhow::Result<(SocketAddr, Vec<u8>, tokio::task::JoinHandle<()>)> {
+    let (config, cert) = configure_server()?;
+    let server = TlsTcpServerEndpoint::<ComputeRequest, ComputeResponse>::bind(
+        "127.0.0.1:0",
+        Arc::new(config),
+    )
+    .await?;
+    let addr = match server.local_addr() {
+        [LocalAddr::Socket(addr)] => *addr,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    Ok((addr, cert, handle))
+}
+
+/// all 4 patterns work over tls
+#[tokio::test]
+async fn tls_tcp_channel_smoke() -> anyhow::Result<()> {
+    let (addr, cert, server_handle) = spawn_server().await?;
+    let config = Arc::new(configure_client(&cert)?);
+    let client = Client::connect(addr, config, "localhost".try_into()?).await?;
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
+
+/// concurrent calls on one connection, with messages larger than a tls record
+#[tokio::test]
+async fn tls_tcp_concurrent_calls() -> anyhow::Result<()> {
+    let (addr, cert, server_handle) = spawn_server().await?;
+    let config = Arc::new(configure_client(&cert)?);
+    let client = Client::connect(addr, config, "localhost".try_into()?).await?;
+    let client = RpcClient::<ComputeService, _>::new(client);
+    let mut calls = Vec::new();
+    for i in 0..8u64 {
+        let client = client.clone();
+        calls.push(tokio::spawn(async move {
+            let n = 10_000 + i;
+            let (send, recv) = client.bidi(Multiply(2)).await?;
+            let updates = tokio::spawn(async move {
+                let updates = futures::stream::iter((0..n).map(MultiplyUpdate));
+                updates.map(Ok).forward(send).await
+            });
+            let res = recv.map_ok(|x| x.0).try_collect::<Vec<_>>().await?;
+            updates.await??;
+            assert_eq!(res.len() as u64, n);
+            assert_eq!(res.last(), Some(&((n - 1) as u128 * 2)));
+            anyhow::Ok(())
+        }));
+    }
+    for call in calls {
+        call.await??;
+    }
+    server_handle.abort();
+    Ok(())
+}
+
+/// the handshake fails if the server certificate is not trusted
+#[tokio::test]
+async fn tls_tcp_untrusted_certificate() -> anyhow::Result<()> {
+    let (addr, _cert, server_handle) = spawn_server().await?;
+    let (_, other_cert) = configure_server()?;
+    let config = Arc::new(configure_client(&other_cert)?);
+    let res = Client::connect(addr, config, "localhost".try_into()?).await;
+    assert_eq!(res.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    server_handle.abort();
+    Ok(())
+}
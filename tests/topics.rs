@@ -0,0 +1,126 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, Stream, StreamExt};
+use quic_rpc::{
+    declare_bidi_streaming,
+    server::RpcServerError,
+    topics::{self, Control, TopicEvent},
+    transport::flume,
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+/// watch topics, with numbered events
+#[derive(Debug, Serialize, Deserialize)]
+struct Watch;
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum WatchRequest {
+    Watch(Watch),
+    Control(Control<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum WatchResponse {
+    Event(TopicEvent<String, u64>),
+}
+
+#[derive(Debug, Clone)]
+struct WatchService;
+
+impl Service for WatchService {
+    type Req = WatchRequest;
+    type Res = WatchResponse;
+}
+
+type WatchControl = Control<String>;
+type WatchEvent = TopicEvent<String, u64>;
+declare_bidi_streaming!(WatchService, Watch, WatchControl, WatchEvent);
+
+impl WatchService {
+    /// topics named "forever" never end, all others have 3 events
+    fn events(topic: &str) -> stream::BoxStream<'static, u64> {
+        if topic == "forever" {
+            stream::pending().boxed()
+        } else {
+            stream::iter(0..3).boxed()
+        }
+    }
+
+    fn watch(
+        self,
+        _req: Watch,
+        updates: impl Stream<Item = Control<String>> + Send + 'static,
+    ) -> impl Stream<Item = TopicEvent<String, u64>> + Send + 'static {
+        topics::multiplex(updates, |topic: &String| Self::events(topic))
+    }
+
+    async fn server<C: ServiceEndpoint<WatchService>>(
+        server: RpcServer<WatchService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, chan) = server.accept().await?;
+            tokio::spawn(async move {
+                match req {
+                    WatchRequest::Watch(msg) => {
+                        chan.bidi_streaming(msg, WatchService, WatchService::watch)
+                            .await
+                    }
+                    WatchRequest::Control(_) => Err(RpcServerError::UnexpectedStartMessage),
+                }
+            });
+        }
+    }
+}
+
+fn item(topic: &str, n: u64) -> TopicEvent<String, u64> {
+    TopicEvent::Item(topic.to_string(), n)
+}
+
+fn ended(topic: &str) -> TopicEvent<String, u64> {
+    TopicEvent::Ended(topic.to_string())
+}
+
+#[tokio::test]
+async fn topics_attach_detach() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<WatchRequest, WatchResponse>(1);
+    let server = RpcServer::<WatchService, _>::new(server);
+    tokio::spawn(WatchService::server(server));
+    let client = RpcClient::<WatchService, _>::new(client);
+
+    let (mut topics, mut events) = topics::open(&client, Watch).await?;
+    topics.attach("forever".to_string()).await?;
+    // attaching twice has no effect
+    topics.attach("forever".to_string()).await?;
+    topics.attach("a".to_string()).await?;
+    for i in 0..3 {
+        assert_eq!(events.next().await.transpose()?, Some(item("a", i)));
+    }
+    assert_eq!(events.next().await.transpose()?, Some(ended("a")));
+
+    // a topic can be attached again after it ended
+    topics.attach("a".to_string()).await?;
+    assert_eq!(events.next().await.transpose()?, Some(item("a", 0)));
+    topics.detach("forever".to_string()).await?;
+    // events of different topics can be interleaved in any order
+    let mut rest = Vec::new();
+    while !(rest.contains(&ended("a")) && rest.contains(&ended("forever"))) {
+        rest.push(events.next().await.transpose()?.unwrap());
+    }
+    let a = rest
+        .iter()
+        .filter(|e| !matches!(e, TopicEvent::Ended(t) if t == "forever"));
+    assert_eq!(
+        a.cloned().collect::<Vec<_>>(),
+        vec![item("a", 1), item("a", 2), ended("a")]
+    );
+    assert_eq!(rest.len(), 4);
+
+    // closing the updates detaches all topics and ends the call
+    topics.attach("forever".to_string()).await?;
+    drop(topics);
+    let rest = events.collect::<Vec<_>>().await;
+    let rest = rest.into_iter().collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(rest, vec![ended("forever")]);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+#![cfg(all(unix, feature = "unix-transport"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        unix::{UnixConnection, UnixServerEndpoint},
+        Connection, LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+use std::path::PathBuf;
+
+/// A socket path that is unique per test
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("quic-rpc-{}-{}.sock", name, std::process::id()))
+}
+
+/// all 4 patterns work over a unix socket
+#[tokio::test]
+async fn unix_channel_smoke() -> anyhow::Result<()> {
+    let path = socket_path("smoke");
+    let server = UnixServerEndpoint::<ComputeRequest, ComputeResponse>::bind(&path)?;
+    assert!(matches!(server.local_addr(), [LocalAddr::Path(p)] if *p == path));
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    let client = UnixConnection::<ComputeResponse, ComputeRequest>::connect(&path).await?;
+    smoke_test(client).await?;
+    server_handle.abort();
+    // removing the socket file once the endpoint is gone
+    server_handle.await.ok();
+    assert!(!path.exists());
+    Ok(())
+}
+
+/// both ends of a socket pair can be used as connection and server endpoint,
+/// and the server stops accepting channels once the connection closes
+#[tokio::test]
+async fn unix_from_stream() -> anyhow::Result<()> {
+    use std::time::Duration;
+    let (client, server) = tokio::net::UnixStream::pair()?;
+    let server = UnixServerEndpoint::<ComputeRequest, ComputeResponse>::from_stream(server)?;
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+    let client = UnixConnection::<ComputeResponse, ComputeRequest>::from_stream(client);
+    smoke_test(client).await?;
+    let res = tokio::time::timeout(Duration::from_secs(5), server_handle).await??;
+    assert!(res.is_err());
+    Ok(())
+}
+
+/// both sides see the credentials of the other process
+#[tokio::test]
+async fn unix_peer_credentials() -> anyhow::Result<()> {
+    let path = socket_path("credentials");
+    let server = UnixServerEndpoint::<ComputeRequest, ComputeResponse>::bind(&path)?;
+    let client = UnixConnection::<ComputeResponse, ComputeRequest>::connect(&path).await?;
+    let (mut send, _recv) = client.open_bi().await?;
+    futures::SinkExt::send(&mut send, ComputeRequest::Sqr(Sqr(2))).await?;
+    let (_send, recv) = server.accept_bi().await?;
+
+    let server_side = recv.peer_credentials().expect("client credentials");
+    let client_side = client.peer_credentials().expect("server credentials");
+    // the client and the server are the same process here
+    assert_eq!(server_side, client_side);
+    assert_eq!(send.peer_credentials(), Some(client_side));
+    #[cfg(target_os = "linux")]
+    assert_eq!(server_side.pid, Some(std::process::id() as i32));
+    Ok(())
+}
@@ -0,0 +1,227 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use futures::{stream, Stream, StreamExt};
+use quic_rpc::{
+    declare_client_streaming, declare_rpc,
+    server::RpcServerError,
+    transport::flume,
+    upload::{
+        Journal, MemoryJournal, OffsetRequest, ResumableUpload, UploadClient, UploadOffset,
+        UploadOffsets,
+    },
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// upload a sequence of numbers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Upload {
+    id: String,
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Item(u64);
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct Done(u64);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum UploadRequest {
+    Offset(OffsetRequest),
+    Upload(Upload),
+    Item(Item),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum UploadResponse {
+    Offset(UploadOffset),
+    Done(Done),
+}
+
+#[derive(Debug, Clone)]
+struct UploadService;
+
+impl Service for UploadService {
+    type Req = UploadRequest;
+    type Res = UploadResponse;
+}
+
+declare_rpc!(UploadService, OffsetRequest, UploadOffset);
+declare_client_streaming!(UploadService, Upload, Item, Done);
+
+impl ResumableUpload<UploadService> for Upload {
+    fn upload_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn resume_at(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+}
+
+/// A server that stores uploaded items, and drops the first upload after 3 items
+#[derive(Debug, Clone, Default)]
+struct Store {
+    offsets: UploadOffsets,
+    items: Arc<Mutex<Vec<u64>>>,
+    uploads: Arc<AtomicUsize>,
+}
+
+impl Store {
+    async fn upload(self, req: Upload, updates: impl Stream<Item = Item>) -> Done {
+        assert_eq!(req.offset, self.offsets.offset(&req.id));
+        tokio::pin!(updates);
+        while let Some(Item(x)) = updates.next().await {
+            let len = {
+                let mut items = self.items.lock().unwrap();
+                items.push(x);
+                items.len()
+            };
+            self.offsets.commit(&req.id, len as u64);
+        }
+        self.offsets.finish(&req.id);
+        Done(self.items.lock().unwrap().iter().sum())
+    }
+
+    async fn server<C: ServiceEndpoint<UploadService>>(
+        self,
+        server: RpcServer<UploadService, C>,
+    ) -> Result<(), RpcServerError<C>> {
+        loop {
+            let (req, mut chan) = server.accept().await?;
+            let store = self.clone();
+            tokio::spawn(async move {
+                match req {
+                    UploadRequest::Offset(msg) => {
+                        chan.rpc(msg, store.offsets.clone(), UploadOffsets::handle)
+                            .await
+                    }
+                    UploadRequest::Upload(msg)
+                        if store.uploads.fetch_add(1, Ordering::SeqCst) == 0 =>
+                    {
+                        // simulate a connection failure after 3 items
+                        for _ in 0..3 {
+                            if let Some(Ok(UploadRequest::Item(Item(x)))) = chan.recv.next().await {
+                                store.items.lock().unwrap().push(x);
+                            }
+                        }
+                        store.offsets.commit(&msg.id, 3);
+                        Ok(())
+                    }
+                    UploadRequest::Upload(msg) => {
+                        chan.client_streaming(msg, store, Store::upload).await
+                    }
+                    UploadRequest::Item(_) => Err(RpcServerError::UnexpectedStartMessage),
+                }
+            });
+        }
+    }
+}
+
+#[tokio::test]
+async fn upload_resumes_at_offset() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<UploadRequest, UploadResponse>(1);
+    let server = RpcServer::<UploadService, _>::new(server);
+    let store = Store::default();
+    tokio::spawn(store.clone().server(server));
+    let client = UploadClient::new(RpcClient::<UploadService, _>::new(client))
+        .with_retry_delay(Duration::from_millis(10));
+
+    let starts = Arc::new(Mutex::new(Vec::new()));
+    let req = Upload {
+        id: "numbers".to_string(),
+        offset: 0,
+    };
+    let res = client
+        .upload(req, |offset| {
+            starts.lock().unwrap().push(offset);
+            stream::iter(offset..10).map(Item)
+        })
+        .await?;
+
+    // every item was received exactly once
+    assert_eq!(res, Done((0..10).sum()));
+    assert_eq!(*store.items.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    assert_eq!(*starts.lock().unwrap(), vec![0, 3]);
+    assert_eq!(store.offsets.offset("numbers"), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upload_gives_up_after_max_attempts() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<UploadRequest, UploadResponse>(1);
+    let server = RpcServer::<UploadService, _>::new(server);
+    let store = Store::default();
+    tokio::spawn(store.clone().server(server));
+    let client = UploadClient::new(RpcClient::<UploadService, _>::new(client)).with_max_attempts(1);
+
+    let req = Upload {
+        id: "numbers".to_string(),
+        offset: 0,
+    };
+    let res = client
+        .upload(req, |offset| stream::iter(offset..10).map(Item))
+        .await;
+    assert!(res.is_err());
+    assert_eq!(store.offsets.offset("numbers"), 3);
+    Ok(())
+}
+
+/// updates that can not be produced again are replayed from the journal, and
+/// a full journal makes room with the offset of the server
+#[tokio::test]
+async fn upload_journaled_replays_unacknowledged() -> anyhow::Result<()> {
+    let (server, client) = flume::connection::<UploadRequest, UploadResponse>(1);
+    let server = RpcServer::<UploadService, _>::new(server);
+    let store = Store::default();
+    tokio::spawn(store.clone().server(server));
+    let client = UploadClient::new(RpcClient::<UploadService, _>::new(client))
+        .with_retry_delay(Duration::from_millis(10));
+
+    let req = Upload {
+        id: "numbers".to_string(),
+        offset: 0,
+    };
+    let mut journal = MemoryJournal::new(4);
+    let res = client
+        .upload_journaled(req, stream::iter(0..10).map(Item), &mut journal)
+        .await?;
+
+    assert_eq!(res, Done((0..10).sum()));
+    assert_eq!(*store.items.lock().unwrap(), (0..10).collect::<Vec<_>>());
+    assert!(journal.is_empty());
+    Ok(())
+}
+
+/// a file journal keeps the updates that were not acknowledged, in order
+#[cfg(feature = "bincode")]
+#[test]
+fn file_journal() -> anyhow::Result<()> {
+    use quic_rpc::upload::FileJournal;
+
+    let path = std::env::temp_dir().join(format!("quic-rpc-journal-{}", std::process::id()));
+    let mut journal = FileJournal::create(&path, 3)?;
+    for x in 0..3 {
+        journal.push(&Item(x))?;
+    }
+    assert!(journal.is_full());
+    assert!(journal.push(&Item(3)).is_err());
+    journal.acknowledge(2)?;
+    journal.push(&Item(3))?;
+    journal.push(&Item(4))?;
+    let replayed: Vec<u64> = journal.replay()?.into_iter().map(|Item(x)| x).collect();
+    assert_eq!(replayed, vec![2, 3, 4]);
+    journal.acknowledge(3)?;
+    assert!(journal.replay()?.is_empty());
+    assert_eq!(std::fs::metadata(&path)?.len(), 0);
+    std::fs::remove_file(path)?;
+    Ok(())
+}
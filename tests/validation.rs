@@ -0,0 +1,207 @@
+#![cfg(all(feature = "flume-transport", feature = "macros"))]
+use derive_more::{From, TryInto};
+use futures::{stream, StreamExt, TryStreamExt};
+use quic_rpc::{
+    declare_rpc, declare_server_streaming,
+    server::RpcServerError,
+    transport::flume,
+    validation::{FieldViolation, InvalidArgument, Validate},
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CreateUser {
+    name: String,
+    age: u32,
+}
+
+impl Validate for CreateUser {
+    fn validate(&self) -> Result<(), InvalidArgument> {
+        InvalidArgument::default()
+            .check(!self.name.is_empty(), "name", "must not be empty")
+            .check(self.age < 200, "age", "must be below 200")
+            .into_result()
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct UserId(u64);
+
+/// the errors of the service, one of which is a request that is not valid
+#[derive(Debug, PartialEq, Serialize, Deserialize, From)]
+enum ApiError {
+    Invalid(InvalidArgument),
+    Exists,
+}
+
+/// list the users whose name starts with a prefix
+#[derive(Debug, Serialize, Deserialize)]
+struct ListUsers(String);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct User(String);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    CreateUser(CreateUser),
+    ListUsers(ListUsers),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    Created(Created),
+    Listed(Listed),
+}
+
+#[derive(Debug, Clone)]
+struct UserService;
+
+impl Service for UserService {
+    type Req = Request;
+    type Res = Response;
+}
+
+type Created = Result<UserId, ApiError>;
+type Listed = Result<User, InvalidArgument>;
+
+declare_rpc!(UserService, CreateUser, Created);
+declare_server_streaming!(UserService, ListUsers, Listed);
+
+/// handle calls, counting the calls that reached a handler
+async fn handle_calls<C: ServiceEndpoint<UserService>>(
+    server: RpcServer<UserService, C>,
+    handled: Arc<AtomicUsize>,
+) -> Vec<RpcServerError<C>> {
+    let mut errors = Vec::new();
+    while let Ok((req, chan)) = server.accept().await {
+        let handled = handled.clone();
+        let res = match req {
+            Request::CreateUser(msg) => {
+                chan.rpc(msg, handled, |handled, req| async move {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                    if req.name == "root" {
+                        Err(ApiError::Exists)
+                    } else {
+                        Ok(UserId(1))
+                    }
+                })
+                .await
+            }
+            Request::ListUsers(msg) => {
+                chan.server_streaming(msg, handled, |handled, ListUsers(prefix)| {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                    stream::iter(
+                        ["ada", "alan"].map(move |name| Ok(User(format!("{prefix}{name}")))),
+                    )
+                })
+                .await
+            }
+        };
+        if let Err(cause) = res {
+            errors.push(cause);
+        }
+    }
+    errors
+}
+
+/// requests that are not valid are answered without calling the handler
+#[tokio::test]
+async fn validation_rejects_invalid_requests() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<Request, Response>(1);
+    let handled = Arc::new(AtomicUsize::new(0));
+    let server = RpcServer::new(endpoint)
+        .with_validation::<CreateUser>()
+        .with_validator(|ListUsers(prefix): &ListUsers| {
+            InvalidArgument::default()
+                .check(prefix.len() <= 3, "prefix", "must be at most 3 characters")
+                .into_result()
+        });
+    let server_handle = tokio::spawn(handle_calls(server, handled.clone()));
+    let client = RpcClient::<UserService, _>::new(connection);
+
+    let created = client
+        .rpc(CreateUser {
+            name: "ada".into(),
+            age: 36,
+        })
+        .await?;
+    assert_eq!(created, Ok(UserId(1)));
+    let invalid = client
+        .rpc(CreateUser {
+            name: "".into(),
+            age: 400,
+        })
+        .await?;
+    let expected = InvalidArgument::default()
+        .with_field("name", "must not be empty")
+        .with_field("age", "must be below 200");
+    assert_eq!(invalid, Err(ApiError::Invalid(expected.clone())));
+    assert_eq!(
+        expected.to_string(),
+        "invalid argument: name: must not be empty, age: must be below 200"
+    );
+    // errors of the handler are unaffected
+    let exists = client
+        .rpc(CreateUser {
+            name: "root".into(),
+            age: 0,
+        })
+        .await?;
+    assert_eq!(exists, Err(ApiError::Exists));
+
+    let users = client
+        .server_streaming(ListUsers("x".into()))
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    assert_eq!(users, [Ok(User("xada".into())), Ok(User("xalan".into()))]);
+    let users = client
+        .server_streaming(ListUsers("long".into()))
+        .await?
+        .collect::<Vec<_>>()
+        .await;
+    match &users[..] {
+        [Ok(Err(invalid))] => assert_eq!(
+            invalid.violations,
+            [FieldViolation {
+                field: "prefix".into(),
+                message: "must be at most 3 characters".into(),
+            }]
+        ),
+        other => panic!("unexpected responses {other:?}"),
+    }
+
+    drop(client);
+    let errors = server_handle.await?;
+    assert_eq!(handled.load(Ordering::SeqCst), 3);
+    assert_eq!(errors.len(), 2);
+    assert!(errors
+        .iter()
+        .all(|error| matches!(error, RpcServerError::InvalidArgument(_))));
+    Ok(())
+}
+
+/// without validation, every request reaches its handler
+#[tokio::test]
+async fn validation_not_configured() -> anyhow::Result<()> {
+    let (endpoint, connection) = flume::connection::<Request, Response>(1);
+    let handled = Arc::new(AtomicUsize::new(0));
+    let server_handle = tokio::spawn(handle_calls(RpcServer::new(endpoint), handled.clone()));
+    let client = RpcClient::<UserService, _>::new(connection);
+    let created = client
+        .rpc(CreateUser {
+            name: "".into(),
+            age: 400,
+        })
+        .await?;
+    assert_eq!(created, Ok(UserId(1)));
+    drop(client);
+    assert!(server_handle.await?.is_empty());
+    assert_eq!(handled.load(Ordering::SeqCst), 1);
+    Ok(())
+}
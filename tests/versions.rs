@@ -0,0 +1,241 @@
+#![cfg(feature = "flume-transport")]
+use derive_more::{From, TryInto};
+use quic_rpc::{
+    declare_rpc,
+    server::RpcServerError,
+    strict::{Downgrade, Requirements},
+    transport::flume,
+    versions::{
+        SupportedVersions, Versioned, VersionedClient, VersionedError, Versions, VersionsRequest,
+    },
+    RpcClient, RpcServer, Service, ServiceEndpoint,
+};
+use serde::{Deserialize, Serialize};
+
+/// the first version of the square call, limited to small numbers
+#[derive(Debug, Serialize, Deserialize)]
+struct SqrV1(u32);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SqrResponseV1(u64);
+
+/// the current version of the square call, which can return a negative result
+#[derive(Debug, Serialize, Deserialize)]
+struct Sqr {
+    value: u64,
+    negate: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SqrResponse(i128);
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Request {
+    VersionsRequest(VersionsRequest),
+    SqrV1(SqrV1),
+    Sqr(Sqr),
+}
+
+#[derive(Debug, Serialize, Deserialize, From, TryInto)]
+enum Response {
+    SupportedVersions(SupportedVersions),
+    SqrResponseV1(SqrResponseV1),
+    SqrResponse(SqrResponse),
+}
+
+#[derive(Debug, Clone)]
+struct SqrService;
+
+impl Service for SqrService {
+    type Req = Request;
+    type Res = Response;
+}
+
+declare_rpc!(SqrService, VersionsRequest, SupportedVersions);
+declare_rpc!(SqrService, SqrV1, SqrResponseV1);
+declare_rpc!(SqrService, Sqr, SqrResponse);
+
+impl Versioned<SqrService> for SqrV1 {
+    type Canonical = Sqr;
+    const VERSION: u32 = 1;
+
+    fn upgrade(self) -> Sqr {
+        Sqr {
+            value: self.0 as u64,
+            negate: false,
+        }
+    }
+
+    fn downgrade(req: Sqr) -> Self {
+        SqrV1(req.value as u32)
+    }
+
+    fn upgrade_response(res: SqrResponseV1) -> SqrResponse {
+        SqrResponse(res.0 as i128)
+    }
+
+    fn downgrade_response(res: SqrResponse) -> SqrResponseV1 {
+        SqrResponseV1(res.0 as u64)
+    }
+}
+
+impl Versioned<SqrService> for Sqr {
+    type Canonical = Sqr;
+    const VERSION: u32 = 2;
+
+    fn upgrade(self) -> Sqr {
+        self
+    }
+
+    fn downgrade(req: Sqr) -> Self {
+        req
+    }
+
+    fn upgrade_response(res: SqrResponse) -> SqrResponse {
+        res
+    }
+
+    fn downgrade_response(res: SqrResponse) -> SqrResponse {
+        res
+    }
+}
+
+async fn sqr((): (), req: Sqr) -> SqrResponse {
+    let res = req.value as i128 * req.value as i128;
+    SqrResponse(if req.negate { -res } else { res })
+}
+
+async fn server<C: ServiceEndpoint<SqrService>>(
+    server: RpcServer<SqrService, C>,
+    versions: Versions,
+) -> Result<(), RpcServerError<C>> {
+    loop {
+        let (req, chan) = server.accept().await?;
+        match req {
+            Request::VersionsRequest(msg) => {
+                chan.rpc(msg, versions.clone(), Versions::handle).await
+            }
+            Request::SqrV1(msg) => chan.rpc_versioned(msg, (), sqr).await,
+            Request::Sqr(msg) => chan.rpc_versioned(msg, (), sqr).await,
+        }?;
+    }
+}
+
+type Client = VersionedClient<SqrService, flume::FlumeConnection<Response, Request>>;
+
+fn spawn_server(
+    versions: Versions,
+) -> RpcClient<SqrService, flume::FlumeConnection<Response, Request>> {
+    let (endpoint, client) = flume::connection::<Request, Response>(1);
+    tokio::spawn(server(RpcServer::new(endpoint), versions));
+    RpcClient::new(client)
+}
+
+fn all_versions() -> Versions {
+    Versions::new()
+        .with::<SqrService, SqrV1>()
+        .with::<SqrService, Sqr>()
+}
+
+/// the client uses the newest version the server supports
+#[tokio::test]
+async fn versions_negotiated() -> anyhow::Result<()> {
+    let new = Client::new(spawn_server(all_versions()))
+        .with_version::<SqrV1>()
+        .with_version::<Sqr>();
+    assert_eq!(new.version::<Sqr>().await?, 2);
+    let req = Sqr {
+        value: 3,
+        negate: true,
+    };
+    assert_eq!(new.rpc(req).await?.0, -9);
+
+    // an old server only knows the first version, so the negation is lost
+    let old = spawn_server(Versions::new().with::<SqrService, SqrV1>());
+    let old = Client::new(old)
+        .with_version::<SqrV1>()
+        .with_version::<Sqr>();
+    assert_eq!(old.version::<Sqr>().await?, 1);
+    let req = Sqr {
+        value: 3,
+        negate: true,
+    };
+    assert_eq!(old.rpc(req).await?.0, 9);
+    Ok(())
+}
+
+/// an old client talks to a new server using the old version
+#[tokio::test]
+async fn versions_old_client() -> anyhow::Result<()> {
+    let client = spawn_server(all_versions());
+    let versioned = Client::new(client.clone()).with_version::<SqrV1>();
+    assert_eq!(versioned.version::<Sqr>().await?, 1);
+    assert_eq!(client.rpc(SqrV1(4)).await?.0, 16);
+
+    // without a common version, nothing is sent
+    let none = Client::new(spawn_server(Versions::new().with::<SqrService, Sqr>()))
+        .with_version::<SqrV1>();
+    let req = Sqr {
+        value: 1,
+        negate: false,
+    };
+    assert!(matches!(
+        none.rpc(req).await,
+        Err(VersionedError::NoCommonVersion("Sqr"))
+    ));
+    Ok(())
+}
+
+/// a strict client refuses to fall back to an old version or a weaker connection
+#[tokio::test]
+async fn versions_strict() -> anyhow::Result<()> {
+    let strict = || Requirements::new().with_min_version::<SqrService, Sqr>();
+    let new = Client::new(spawn_server(all_versions()))
+        .with_version::<SqrV1>()
+        .with_version::<Sqr>()
+        .with_requirements(strict());
+    let req = Sqr {
+        value: 3,
+        negate: true,
+    };
+    assert_eq!(new.rpc(req).await?.0, -9);
+
+    // the old server would lose the negation, so nothing is sent
+    let old = Client::new(spawn_server(Versions::new().with::<SqrService, SqrV1>()))
+        .with_version::<SqrV1>()
+        .with_version::<Sqr>()
+        .with_requirements(strict());
+    let req = Sqr {
+        value: 3,
+        negate: true,
+    };
+    let err = old.rpc(req).await.unwrap_err();
+    assert!(matches!(
+        &err,
+        VersionedError::Downgrade(Downgrade::Version {
+            method: "Sqr",
+            required: 2,
+            negotiated: 1
+        })
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Downgrade(Version { method: \"Sqr\", required: 2, negotiated: 1 })"
+    );
+    if let VersionedError::Downgrade(downgrade) = err {
+        assert_eq!(
+            downgrade.to_string(),
+            "Sqr would be sent as version 1, but at least version 2 is required"
+        );
+    }
+
+    // the mem transport does not compress
+    let uncompressed = Client::new(spawn_server(all_versions()))
+        .with_version::<Sqr>()
+        .with_requirements(Requirements::new().with_compression());
+    assert!(matches!(
+        uncompressed.version::<Sqr>().await,
+        Err(VersionedError::Downgrade(Downgrade::Compression))
+    ));
+    Ok(())
+}
@@ -0,0 +1,61 @@
+#![cfg(all(target_os = "linux", feature = "vsock-transport", feature = "macros"))]
+mod math;
+use math::*;
+use quic_rpc::{
+    transport::{
+        vsock::{VsockConnection, VsockServerEndpoint, CID_ANY, CID_LOCAL, PORT_ANY},
+        LocalAddr, ServerEndpoint,
+    },
+    RpcServer,
+};
+
+type Server = VsockServerEndpoint<ComputeRequest, ComputeResponse>;
+
+/// Bind to a free port, or `None` if the kernel does not support vsock
+fn bind() -> anyhow::Result<Option<Server>> {
+    match Server::bind(CID_ANY, PORT_ANY) {
+        Ok(server) => Ok(Some(server)),
+        Err(cause) if cause.raw_os_error() == Some(EAFNOSUPPORT) => Ok(None),
+        Err(cause) => Err(cause.into()),
+    }
+}
+
+/// The error of creating a socket of an unsupported address family on linux
+const EAFNOSUPPORT: i32 = 97;
+
+/// binding to any port reports the port the kernel picked
+#[tokio::test]
+async fn vsock_bind_any_port() -> anyhow::Result<()> {
+    let server = match bind()? {
+        Some(server) => server,
+        None => return Ok(()),
+    };
+    match server.local_addr() {
+        [LocalAddr::Vsock { cid, port }] => {
+            assert_eq!(*cid, CID_ANY);
+            assert_ne!(*port, PORT_ANY);
+        }
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    }
+    Ok(())
+}
+
+/// all 4 patterns work over vsock
+#[tokio::test]
+#[ignore = "needs vsock loopback, e.g. the vsock_loopback kernel module"]
+async fn vsock_channel_smoke() -> anyhow::Result<()> {
+    let server = bind()?.expect("vsock is supported");
+    let port = match server.local_addr() {
+        [LocalAddr::Vsock { port, .. }] => *port,
+        other => anyhow::bail!("unexpected local addr {:?}", other),
+    };
+    let server = RpcServer::<ComputeService, _>::new(server);
+    let server_handle = tokio::spawn(async move {
+        ComputeService::server(server).await.ok();
+    });
+    let client =
+        VsockConnection::<ComputeResponse, ComputeRequest>::connect(CID_LOCAL, port).await?;
+    smoke_test(client).await?;
+    server_handle.abort();
+    Ok(())
+}
@@ -0,0 +1,118 @@
+#![cfg(feature = "web-transport")]
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
+};
+
+use quic_rpc::{
+    transport::{
+        quinn::{QuinnConnection, QuinnServerEndpoint, H3_ALPN},
+        web_transport::{self, WebTransportConnection},
+    },
+    RpcClient, RpcServer,
+};
+use quinn::{ClientConfig, Endpoint, ServerConfig};
+
+mod math;
+use math::*;
+
+/// A server endpoint that offers both rpc and HTTP/3, and its certificate
+fn server_endpoint(port: u16) -> anyhow::Result<(Endpoint, Vec<u8>)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    let cert_der = cert.serialize_der()?;
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![rustls::Certificate(cert_der.clone())],
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+        )?;
+    server_crypto.alpn_protocols = vec![b"rpc".to_vec(), H3_ALPN.to_vec()];
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+    let server = Endpoint::server(ServerConfig::with_crypto(Arc::new(server_crypto)), addr)?;
+    Ok((server, cert_der))
+}
+
+/// A client endpoint that trusts `cert` and offers `alpn`
+fn client_endpoint(cert: &[u8], alpn: &[u8]) -> anyhow::Result<Endpoint> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(&rustls::Certificate(cert.to_vec()))?;
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![alpn.to_vec()];
+    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+    Ok(endpoint)
+}
+
+/// all 4 patterns work through a WebTransport session, next to a native client
+#[tokio::test]
+async fn web_transport_session_smoke() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, cert) = server_endpoint(12357)?;
+    let addr = server.local_addr()?;
+    let (server, connections) =
+        QuinnServerEndpoint::with_web_transport(server, Default::default())?;
+    tokio::spawn(web_transport::serve(connections));
+    let server_handle = tokio::spawn(ComputeService::server(RpcServer::new(server)));
+
+    let connection = client_endpoint(&cert, H3_ALPN)?
+        .connect(addr, "localhost")?
+        .await?;
+    let browser = WebTransportConnection::<ComputeResponse, ComputeRequest>::connect(
+        connection,
+        "localhost",
+        "/rpc",
+    )
+    .await?;
+    smoke_test(browser.clone()).await?;
+
+    let native = RpcClient::<ComputeService, _>::new(QuinnConnection::new(
+        client_endpoint(&cert, b"rpc")?,
+        addr,
+        "localhost".into(),
+    ));
+    assert_eq!(native.rpc(Sqr(2)).await?.0, 4);
+    let browser = RpcClient::<ComputeService, _>::new(browser);
+    assert_eq!(browser.rpc(Sqr(3)).await?.0, 9);
+    server_handle.abort();
+    Ok(())
+}
+
+/// sessions are not requested from HTTP/3 servers without WebTransport support
+#[tokio::test]
+async fn web_transport_unsupported() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::try_init().ok();
+    let (server, cert) = server_endpoint(12358)?;
+    let addr = server.local_addr()?;
+    let (_server, connections) =
+        QuinnServerEndpoint::<ComputeRequest, ComputeResponse>::with_web_transport(
+            server,
+            Default::default(),
+        )?;
+    // a plain HTTP/3 server, whose settings do not enable WebTransport
+    tokio::spawn(async move {
+        while let Some(connection) = connections.accept().await {
+            let mut control = connection.open_uni().await?;
+            // control stream type, then an empty SETTINGS frame
+            control.write_all(&[0x00, 0x04, 0x00]).await?;
+            connection.closed().await;
+        }
+        anyhow::Ok(())
+    });
+
+    let connection = client_endpoint(&cert, H3_ALPN)?
+        .connect(addr, "localhost")?
+        .await?;
+    let err = WebTransportConnection::<ComputeResponse, ComputeRequest>::connect(
+        connection,
+        "localhost",
+        "/rpc",
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    Ok(())
+}